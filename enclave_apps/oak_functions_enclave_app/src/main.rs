@@ -21,14 +21,15 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, sync::Arc};
+use core::sync::atomic::Ordering;
 
 use oak_functions_service::wasm::WasmHandler;
 use oak_restricted_kernel_sdk::{
     attestation::InstanceEvidenceProvider,
-    channel::{start_blocking_server, FileDescriptorChannel},
-    crypto::InstanceEncryptionKeyHandle,
+    channel::{start_blocking_server_until, FileDescriptorChannel},
+    crypto::{InstanceEncryptionKeyHandle, InstanceSealer},
     entrypoint,
-    utils::samplestore::StaticSampleStore,
+    utils::{exit, samplestore::StaticSampleStore},
 };
 
 #[entrypoint]
@@ -48,9 +49,18 @@ fn main() -> ! {
         evidencer,
         Arc::new(encryption_key_handle),
         None,
+        Some(Arc::new(InstanceSealer)),
     );
+    let terminate_requested = service.terminate_requested();
     let server =
         oak_functions_enclave_service::proto::oak::functions::OakFunctionsServer::new(service);
-    start_blocking_server(Box::<FileDescriptorChannel>::default(), server, &mut invocation_stats)
-        .expect("server encountered an unrecoverable error");
+    start_blocking_server_until(
+        Box::<FileDescriptorChannel>::default(),
+        server,
+        &mut invocation_stats,
+        || terminate_requested.load(Ordering::Acquire),
+    )
+    .expect("server encountered an unrecoverable error");
+    log::info!("terminating on request of the host");
+    exit(0);
 }
@@ -135,6 +135,7 @@
                 cargo-udeps
                 cargo-vet
                 protobuf
+                rust-cbindgen
                 systemd
                 qemu_kvm
                 python312
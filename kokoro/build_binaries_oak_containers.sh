@@ -42,6 +42,7 @@ readonly generated_binaries=(
     # not reproducibly buildable yet.
     ./oak_containers_system_image/target/oak_containers_orchestrator
     ./oak_containers_system_image/target/oak_containers_syslogd
+    ./oak_containers_system_image/target/oak_containers_agent
 )
 readonly binary_names=(
     oak_containers_stage1
@@ -53,6 +54,7 @@ readonly binary_names=(
 
     oak_containers_orchestrator
     oak_containers_syslogd
+    oak_containers_agent
 )
 for i in "${!binary_names[@]}"; do
     cp --preserve=timestamps \
@@ -85,6 +85,11 @@ bitflags! {
         const XLF_EFI_HANDOVER_64 = 1 << 3;
         /// The kernel supports kexec EFI boot with EFI runtime support.
         const XLF_EFI_KEXEC = 1 << 4;
+        /// The kernel supports being entered via the 64-bit entry point with 5-level paging
+        /// enabled.
+        const XLF_5LEVEL = 1 << 5;
+        /// The kernel was built with 5-level paging enabled.
+        const XLF_5LEVEL_ENABLED = 1 << 6;
     }
 }
 
@@ -101,6 +106,22 @@ pub enum SetupDataType {
     CCBlob = 7,
     IMA = 8,
     RngSeed = 9,
+
+    // Oak-specific types. The Linux kernel skips over setup data entries with a type it doesn't
+    // recognize, so we use a range that is unlikely to clash with future upstream types.
+    /// Information about the application processors started by the firmware.
+    OakApInfo = 0x4F41_4B01,
+    /// Location of the measured boot event log created by the firmware.
+    OakEventLog = 0x4F41_4B02,
+    /// Location of the DICE data generated by the firmware for the next boot
+    /// stage.
+    OakDiceData = 0x4F41_4B03,
+    /// Location of the AMD SEV-SNP attestation report requested by the
+    /// firmware.
+    OakAttestationReport = 0x4F41_4B04,
+    /// Location of the RAM ranges that the firmware did not validate under AMD
+    /// SEV-SNP.
+    OakUnvalidatedMemory = 0x4F41_4B05,
 }
 
 #[repr(C, packed)]
@@ -132,6 +153,156 @@ impl CCSetupData {
     }
 }
 
+/// Setup data describing the application processors (APs) started by the
+/// firmware.
+///
+/// The APs are parked in a wakeup mailbox, which uses the same layout as the
+/// ACPI Multiprocessor Wakeup Mailbox Structure (see Section 5.2.12.19 in the
+/// ACPI specification, Version 6.5), with the difference that the wakeup
+/// vector is entered in real mode.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct ApInfoSetupData {
+    pub header: SetupData,
+    /// Number of APs that have checked in and are waiting in the mailbox.
+    pub ap_count: u32,
+    /// Physical address of the wakeup mailbox.
+    pub mailbox_address: u64,
+}
+
+impl ApInfoSetupData {
+    pub fn new(ap_count: u32, mailbox_address: u64) -> Self {
+        Self {
+            header: SetupData {
+                next: core::ptr::null(),
+                type_: SetupDataType::OakApInfo,
+                len: (size_of::<ApInfoSetupData>() - size_of::<SetupData>()) as u32,
+            },
+            ap_count,
+            mailbox_address,
+        }
+    }
+}
+
+/// Setup data pointing to the measured boot event log.
+///
+/// The event log itself lives in memory that is marked as reserved in the
+/// E820 table, so that the kernel does not overwrite it before it has had a
+/// chance to read the measurements.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct EventLogSetupData {
+    pub header: SetupData,
+    /// Physical address of the event log.
+    pub event_log_address: u64,
+    /// Size of the event log, in bytes.
+    pub event_log_size: u32,
+}
+
+impl EventLogSetupData {
+    pub fn new(event_log_address: u64, event_log_size: u32) -> Self {
+        Self {
+            header: SetupData {
+                next: core::ptr::null(),
+                type_: SetupDataType::OakEventLog,
+                len: (size_of::<EventLogSetupData>() - size_of::<SetupData>()) as u32,
+            },
+            event_log_address,
+            event_log_size,
+        }
+    }
+}
+
+/// Setup data pointing to the DICE data (the compound device identifier and
+/// certificates for the next boot stage) generated by the firmware.
+///
+/// As with the event log, the DICE data lives in memory that is marked as
+/// reserved in the E820 table.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct DiceDataSetupData {
+    pub header: SetupData,
+    /// Physical address of the DICE data.
+    pub dice_data_address: u64,
+    /// Size of the DICE data, in bytes.
+    pub dice_data_size: u32,
+}
+
+impl DiceDataSetupData {
+    pub fn new(dice_data_address: u64, dice_data_size: u32) -> Self {
+        Self {
+            header: SetupData {
+                next: core::ptr::null(),
+                type_: SetupDataType::OakDiceData,
+                len: (size_of::<DiceDataSetupData>() - size_of::<SetupData>()) as u32,
+            },
+            dice_data_address,
+            dice_data_size,
+        }
+    }
+}
+
+/// Setup data pointing to an AMD SEV-SNP attestation report that the firmware
+/// requested on behalf of later boot stages.
+///
+/// The report data contains the SHA2-384 digest of the measured boot event
+/// log. As with the event log, the report lives in memory that is marked as
+/// reserved in the E820 table.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct AttestationReportSetupData {
+    pub header: SetupData,
+    /// Physical address of the attestation report.
+    pub report_address: u64,
+    /// Size of the attestation report, in bytes.
+    pub report_size: u32,
+}
+
+impl AttestationReportSetupData {
+    pub fn new(report_address: u64, report_size: u32) -> Self {
+        Self {
+            header: SetupData {
+                next: core::ptr::null(),
+                type_: SetupDataType::OakAttestationReport,
+                len: (size_of::<AttestationReportSetupData>() - size_of::<SetupData>()) as u32,
+            },
+            report_address,
+            report_size,
+        }
+    }
+}
+
+/// Setup data pointing to the RAM ranges that the firmware left unvalidated
+/// under AMD SEV-SNP.
+///
+/// The ranges are stored as an array of `BootE820Entry` structures (with type
+/// `RAM`). They are listed as RAM in the E820 table as well, but the kernel has
+/// to validate them before use. As with the event log, the array lives in
+/// memory that is marked as reserved in the E820 table.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct UnvalidatedMemorySetupData {
+    pub header: SetupData,
+    /// Physical address of the array of ranges.
+    pub ranges_address: u64,
+    /// Number of entries in the array.
+    pub range_count: u32,
+}
+
+impl UnvalidatedMemorySetupData {
+    pub fn new(ranges_address: u64, range_count: u32) -> Self {
+        Self {
+            header: SetupData {
+                next: core::ptr::null(),
+                type_: SetupDataType::OakUnvalidatedMemory,
+                len: (size_of::<UnvalidatedMemorySetupData>() - size_of::<SetupData>()) as u32,
+            },
+            ranges_address,
+            range_count,
+        }
+    }
+}
+
 /// Real-mode Kernel Header.
 ///
 /// For each field, some are information from the kernel to the bootloader
@@ -35,6 +35,7 @@ pub use core::result::{
     Result,
     Result::{Err, Ok},
 };
+use core::time::Duration;
 
 use prost::Message;
 pub use proto::{response_wrapper, RequestWrapper, ResponseWrapper};
@@ -60,6 +61,19 @@ pub trait AsyncTransport {
     async fn invoke(&mut self, request_bytes: &[u8]) -> Result<Vec<u8>, Self::Error>;
 }
 
+/// An [`AsyncTransport`] that can bound how long an invocation may take.
+#[async_trait::async_trait]
+pub trait AsyncDeadlineTransport: AsyncTransport {
+    /// Same as [`AsyncTransport::invoke`], but gives up waiting for the
+    /// response once `timeout` has elapsed, returning a transport-specific
+    /// error and cancelling the invocation.
+    async fn invoke_with_timeout(
+        &mut self,
+        request_bytes: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Self::Error>;
+}
+
 impl From<Status> for proto::Status {
     fn from(value: Status) -> Self {
         proto::Status { code: value.code as i32, message: value.message }
@@ -153,30 +167,54 @@ pub async fn async_client_invoke<
     method_id: u32,
     request: &Req,
 ) -> Result<Result<Res, Status>, T::Error> {
-    let request_body = request.encode_to_vec();
-    let request = RequestWrapper { method_id, body: request_body };
-    let request_bytes = request.encode_to_vec();
+    let request_bytes = encode_async_request(method_id, request);
     // This may result in tranport errors, corresponding to the outer Result layer.
     let response_bytes = transport.invoke(&request_bytes).await?;
-    let result: Result<Res, Status> = try {
-        let response = ResponseWrapper::decode(response_bytes.as_ref()).map_err(|err| {
+    Ok(decode_async_response(&response_bytes))
+}
+
+/// Same as [`async_client_invoke`], but gives up once `timeout` has elapsed;
+/// see [`AsyncDeadlineTransport::invoke_with_timeout`].
+pub async fn async_client_invoke_with_deadline<
+    T: AsyncDeadlineTransport,
+    Req: prost::Message,
+    Res: prost::Message + Default,
+>(
+    transport: &mut T,
+    method_id: u32,
+    request: &Req,
+    timeout: Duration,
+) -> Result<Result<Res, Status>, T::Error> {
+    let request_bytes = encode_async_request(method_id, request);
+    // This may result in tranport errors, including timeouts, corresponding to the
+    // outer Result layer.
+    let response_bytes = transport.invoke_with_timeout(&request_bytes, timeout).await?;
+    Ok(decode_async_response(&response_bytes))
+}
+
+fn encode_async_request<Req: prost::Message>(method_id: u32, request: &Req) -> Vec<u8> {
+    let request_body = request.encode_to_vec();
+    let request = RequestWrapper { method_id, body: request_body };
+    request.encode_to_vec()
+}
+
+fn decode_async_response<Res: prost::Message + Default>(
+    response_bytes: &[u8],
+) -> Result<Res, Status> {
+    let response = ResponseWrapper::decode(response_bytes).map_err(|err| {
+        Status::new_with_message(
+            StatusCode::Internal,
+            format!("Client failed to deserialize response wrapper: {}", err),
+        )
+    })?;
+    match response.response {
+        Some(response_wrapper::Response::Error(err)) => Err(err.into()),
+        Some(response_wrapper::Response::Body(body)) => Res::decode(body.as_ref()).map_err(|err| {
             Status::new_with_message(
                 StatusCode::Internal,
-                format!("Client failed to deserialize response wrapper: {}", err),
+                format!("Client failed to deserialize response body: {}", err),
             )
-        })?;
-        match response.response {
-            Some(response_wrapper::Response::Error(err)) => Err(err.into()),
-            Some(response_wrapper::Response::Body(body)) => {
-                Res::decode(body.as_ref()).map_err(|err| {
-                    Status::new_with_message(
-                        StatusCode::Internal,
-                        format!("Client failed to deserialize response body: {}", err),
-                    )
-                })
-            }
-            None => Err(Status::new(StatusCode::Internal)),
-        }?
-    };
-    Ok(result)
+        }),
+        None => Err(Status::new(StatusCode::Internal)),
+    }
 }
@@ -87,7 +87,9 @@ impl ExternPath {
 ///   underlying handler in order to indirectly invoke methods on the
 ///   corresponding `Server` object on the other side of the handler.
 /// - a struct named `TestNameAsyncClient`, similar to `TestNameClient` but with
-///   async support.
+///   async support. If its transport implements
+///   `micro_rpc::AsyncDeadlineTransport`, it also exposes a `_with_deadline`
+///   variant of each method, which gives up after a timeout.
 pub fn compile(
     protos: &[impl AsRef<Path>],
     includes: &[impl AsRef<Path>],
@@ -218,6 +220,20 @@ fn generate_service_client(service: &Service, asynchronous: bool) -> anyhow::Res
             .flatten(),
     );
     lines.extend(vec![format!("}}"), format!("")]);
+    if asynchronous {
+        lines.push(format!("impl <T: ::micro_rpc::AsyncDeadlineTransport> {client_name}<T> {{"));
+        lines.extend(
+            service
+                .methods
+                .iter()
+                .map(generate_client_deadline_method)
+                .collect::<Result<Vec<_>, _>>()
+                .context("couldn't generate client deadline method")?
+                .into_iter()
+                .flatten(),
+        );
+        lines.extend(vec![format!("}}"), format!("")]);
+    }
     Ok(lines.into_iter().intersperse("\n".to_string()).collect())
 }
 
@@ -242,6 +258,22 @@ fn generate_client_method(method: &Method, asynchronous: bool) -> anyhow::Result
     ])
 }
 
+fn generate_client_deadline_method(method: &Method) -> anyhow::Result<Vec<String>> {
+    let method_id = method_id(method)?;
+    let request_type = request_type(method);
+    let response_type = response_type(method);
+    let method_name = method_name(method);
+    Ok(vec![
+        format!(
+            "    pub async fn {method_name}_with_deadline(&mut self, request: &{request_type}, timeout: ::core::time::Duration) -> Result<Result<{response_type}, ::micro_rpc::Status>, T::Error> {{"
+        ),
+        format!(
+            "        ::micro_rpc::async_client_invoke_with_deadline(&mut self.transport, {method_id}, request, timeout).await"
+        ),
+        format!("    }}"),
+    ])
+}
+
 fn generate_server_handler(method: &Method) -> anyhow::Result<Vec<String>> {
     // This handler appears inside a `match` block in the server implementation. Its
     // purpose is to parse the incoming request buffer as an object of the
@@ -41,6 +41,9 @@ pub fn verify_binary_digest(
 }
 
 /// Verifies the binary endorsement against log entry and public keys.
+///
+/// Returns the index of the endorsement in Rekor if its inclusion in the log
+/// was verified, i.e. if a log entry was given.
 pub fn verify_binary_endorsement(
     now_utc_millis: i64,
     endorsement: &[u8],
@@ -48,21 +51,21 @@ pub fn verify_binary_endorsement(
     log_entry: &[u8],
     endorser_public_key: &[u8],
     rekor_public_key: &[u8],
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Option<u64>> {
     let statement = parse_endorsement_statement(endorsement)?;
 
     if !log_entry.is_empty() {
         verify_endorsement_statement(now_utc_millis, &statement)?;
-        verify_rekor_log_entry(log_entry, rekor_public_key, endorsement)?;
+        let log_index = verify_rekor_log_entry(log_entry, rekor_public_key, endorsement)?;
         verify_endorser_public_key(log_entry, endorser_public_key)?;
+        Ok(Some(log_index))
     } else {
         if !rekor_public_key.is_empty() {
             anyhow::bail!("log entry unavailable but verification was requested");
         }
         verify_signature_raw(signature, endorsement, endorser_public_key)?;
+        Ok(None)
     }
-
-    Ok(())
 }
 
 /// Verifies endorsement against the given reference values.
@@ -0,0 +1,839 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Contains code related to Intel TDX quotes, and the Intel DCAP collateral
+//! they are verified with.
+//!
+//! The format of the quotes is described in
+//! <https://download.01.org/intel-sgx/latest/dcap-latest/linux/docs/Intel_TDX_DCAP_Quoting_Library_API.pdf>,
+//! and the format of the collateral in
+//! <https://api.portal.trustedservices.intel.com/content/documentation.html>.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+
+use anyhow::Context;
+use oak_proto_rust::oak::attestation::v1::{IntelTdxCollateral, IntelTdxReferenceValues};
+use p256::{
+    ecdsa::{signature::Verifier, Signature, VerifyingKey},
+    pkcs8::ObjectIdentifier,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::value::RawValue;
+use time::OffsetDateTime;
+use x509_cert::{
+    der::{
+        asn1::{PrintableStringRef, Utf8StringRef},
+        Decode, DecodePem, Encode, Tag, Tagged,
+    },
+    ext::pkix::{BasicConstraints, KeyUsage},
+    Certificate,
+};
+
+use crate::util::hash_sha2_256;
+
+const QUOTE_VERSION: u16 = 4;
+const ATTESTATION_KEY_TYPE_ECDSA_P256: u16 = 2;
+const TEE_TYPE_TDX: u32 = 0x81;
+
+const QUOTE_HEADER_SIZE: usize = 48;
+const TD_REPORT_BODY_SIZE: usize = 584;
+const QE_REPORT_SIZE: usize = 384;
+const ECDSA_SIGNATURE_SIZE: usize = 64;
+const ECDSA_PUBLIC_KEY_SIZE: usize = 64;
+const MEASUREMENT_SIZE: usize = 48;
+
+const QE_REPORT_CERTIFICATION_DATA_TYPE: u16 = 6;
+const PCK_CERT_CHAIN_CERTIFICATION_DATA_TYPE: u16 = 5;
+
+/// Bit of the TD attributes that is set for TDs launched in debug mode.
+const TD_ATTRIBUTES_DEBUG: u8 = 1;
+
+/// Identity of the quoting enclave for TDX in the collateral.
+const TD_QE_ID: &str = "TD_QE";
+/// Status of TCB levels without known vulnerabilities, which is always
+/// accepted.
+const UP_TO_DATE: &str = "UpToDate";
+
+const PEM_CERTIFICATE_FOOTER: &str = "-----END CERTIFICATE-----";
+/// Common name of the certificate Intel signs TCB info and QE identities with.
+const TCB_SIGNING_COMMON_NAME: &str = "Intel SGX TCB Signing";
+
+const ECDSA_WITH_SHA256_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const COMMON_NAME_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.4.3");
+// The OIDs of the SGX extensions of PCK certificates are taken from
+// https://api.trustedservices.intel.com/documents/Intel_SGX_PCK_Certificate_CRL_Spec-1.4.pdf
+const SGX_EXTENSIONS_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113741.1.13.1");
+const SGX_TCB_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113741.1.13.1.2");
+const SGX_FMSPC_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113741.1.13.1.4");
+/// The last arc of the PCE SVN in the TCB of the SGX extensions. The arcs 1 to
+/// 16 are the CPU SVN components.
+const SGX_TCB_PCESVN_ARC: u32 = 17;
+
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+
+/// The body of a TD report, which contains the measurements of the TD and of
+/// the TDX module.
+pub struct TdReportBody<'a> {
+    pub tee_tcb_svn: &'a [u8],
+    pub mr_seam: &'a [u8],
+    pub mr_signer_seam: &'a [u8],
+    pub seam_attributes: &'a [u8],
+    pub td_attributes: &'a [u8],
+    pub xfam: &'a [u8],
+    pub mr_td: &'a [u8],
+    pub mr_config_id: &'a [u8],
+    pub mr_owner: &'a [u8],
+    pub mr_owner_config: &'a [u8],
+    pub rtmrs: [&'a [u8]; 4],
+    pub report_data: &'a [u8],
+}
+
+impl<'a> TdReportBody<'a> {
+    fn parse(body: &'a [u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader(body);
+        Ok(Self {
+            tee_tcb_svn: reader.bytes(16)?,
+            mr_seam: reader.bytes(MEASUREMENT_SIZE)?,
+            mr_signer_seam: reader.bytes(MEASUREMENT_SIZE)?,
+            seam_attributes: reader.bytes(8)?,
+            td_attributes: reader.bytes(8)?,
+            xfam: reader.bytes(8)?,
+            mr_td: reader.bytes(MEASUREMENT_SIZE)?,
+            mr_config_id: reader.bytes(MEASUREMENT_SIZE)?,
+            mr_owner: reader.bytes(MEASUREMENT_SIZE)?,
+            mr_owner_config: reader.bytes(MEASUREMENT_SIZE)?,
+            rtmrs: [
+                reader.bytes(MEASUREMENT_SIZE)?,
+                reader.bytes(MEASUREMENT_SIZE)?,
+                reader.bytes(MEASUREMENT_SIZE)?,
+                reader.bytes(MEASUREMENT_SIZE)?,
+            ],
+            report_data: reader.bytes(64)?,
+        })
+    }
+
+    /// Whether the TD was launched in debug mode.
+    pub fn is_debug(&self) -> bool {
+        self.td_attributes[0] & TD_ATTRIBUTES_DEBUG != 0
+    }
+}
+
+/// The report of the quoting enclave, which certifies the attestation key that
+/// signs quotes.
+struct QeReport<'a> {
+    misc_select: u32,
+    attributes: &'a [u8],
+    mr_signer: &'a [u8],
+    isv_prod_id: u16,
+    isv_svn: u16,
+    report_data: &'a [u8],
+}
+
+impl<'a> QeReport<'a> {
+    fn parse(report: &'a [u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader(report);
+        let _cpu_svn = reader.bytes(16)?;
+        let misc_select = reader.u32()?;
+        let _reserved = reader.bytes(28)?;
+        let attributes = reader.bytes(16)?;
+        let _mr_enclave = reader.bytes(32)?;
+        let _reserved = reader.bytes(32)?;
+        let mr_signer = reader.bytes(32)?;
+        let _reserved = reader.bytes(96)?;
+        let isv_prod_id = reader.u16()?;
+        let isv_svn = reader.u16()?;
+        let _reserved = reader.bytes(60)?;
+        let report_data = reader.bytes(64)?;
+        Ok(Self { misc_select, attributes, mr_signer, isv_prod_id, isv_svn, report_data })
+    }
+}
+
+/// A version 4 TDX quote, signed with an ECDSA P-256 attestation key.
+pub struct TdQuote<'a> {
+    /// The header and the TD report body, which the quote signature is over.
+    signed_data: &'a [u8],
+    pub body: TdReportBody<'a>,
+    signature: &'a [u8],
+    attestation_key: &'a [u8],
+    qe_report: &'a [u8],
+    qe_report_signature: &'a [u8],
+    qe_auth_data: &'a [u8],
+    pck_cert_chain: &'a [u8],
+}
+
+impl<'a> TdQuote<'a> {
+    pub fn parse(quote: &'a [u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader(quote);
+        let mut header = Reader(reader.bytes(QUOTE_HEADER_SIZE)?);
+        let version = header.u16()?;
+        anyhow::ensure!(version == QUOTE_VERSION, "unsupported quote version: {}", version);
+        let attestation_key_type = header.u16()?;
+        anyhow::ensure!(
+            attestation_key_type == ATTESTATION_KEY_TYPE_ECDSA_P256,
+            "unsupported attestation key type: {}",
+            attestation_key_type
+        );
+        let tee_type = header.u32()?;
+        anyhow::ensure!(tee_type == TEE_TYPE_TDX, "unsupported TEE type: {:#x}", tee_type);
+        let body = TdReportBody::parse(reader.bytes(TD_REPORT_BODY_SIZE)?)?;
+        let signed_data = &quote[..QUOTE_HEADER_SIZE + TD_REPORT_BODY_SIZE];
+
+        let signature_data_size = reader.u32()? as usize;
+        let mut signature_data = Reader(reader.bytes(signature_data_size)?);
+        let signature = signature_data.bytes(ECDSA_SIGNATURE_SIZE)?;
+        let attestation_key = signature_data.bytes(ECDSA_PUBLIC_KEY_SIZE)?;
+        let mut qe_certification_data =
+            signature_data.certification_data(QE_REPORT_CERTIFICATION_DATA_TYPE)?;
+        let qe_report = qe_certification_data.bytes(QE_REPORT_SIZE)?;
+        let qe_report_signature = qe_certification_data.bytes(ECDSA_SIGNATURE_SIZE)?;
+        let qe_auth_data_size = qe_certification_data.u16()? as usize;
+        let qe_auth_data = qe_certification_data.bytes(qe_auth_data_size)?;
+        let pck_cert_chain =
+            qe_certification_data.certification_data(PCK_CERT_CHAIN_CERTIFICATION_DATA_TYPE)?.0;
+
+        Ok(Self {
+            signed_data,
+            body,
+            signature,
+            attestation_key,
+            qe_report,
+            qe_report_signature,
+            qe_auth_data,
+            pck_cert_chain,
+        })
+    }
+}
+
+/// Returns the FMSPC (family, model, stepping, platform type and customized
+/// SKU) of the platform that generated `quote`, which identifies the TCB info
+/// to verify it with.
+pub fn get_fmspc(quote: &TdQuote) -> anyhow::Result<Vec<u8>> {
+    let pck = parse_pem_chain(quote.pck_cert_chain)?
+        .into_iter()
+        .next()
+        .context("empty PCK certificate chain")?;
+    Ok(PckExtensions::parse(&pck)?.fmspc)
+}
+
+/// Verifies a TDX quote against the collateral of the platform:
+///
+/// 1. the PCK certificate chain in the quote must lead to the root CA
+///    certificate of the reference values,
+/// 1. the report of the quoting enclave must be signed with the PCK key, and
+///    bind the attestation key,
+/// 1. the quote must be signed with the attestation key,
+/// 1. the collateral must be signed with keys whose certificate chains lead to
+///    the same root CA certificate, and must not be outdated,
+/// 1. the TCB level of the platform and of the TDX module, and the identity and
+///    TCB level of the quoting enclave, must match the collateral with an
+///    accepted status.
+///
+/// Certificate revocation lists aren't checked yet.
+pub fn verify_quote(
+    now_utc_millis: i64,
+    quote: &TdQuote,
+    collateral: &IntelTdxCollateral,
+    reference_values: &IntelTdxReferenceValues,
+) -> anyhow::Result<()> {
+    let root = Certificate::from_der(&reference_values.root_ca_certificate)
+        .map_err(|_err| anyhow::anyhow!("could not parse Intel root CA cert"))?;
+
+    let pck = verify_cert_chain(now_utc_millis, quote.pck_cert_chain, &root)
+        .context("invalid PCK certificate chain")?;
+    verify_raw_signature(
+        &certificate_public_key(&pck)?,
+        quote.qe_report,
+        quote.qe_report_signature,
+    )
+    .context("invalid QE report signature")?;
+    let qe_report = QeReport::parse(quote.qe_report)?;
+    let attestation_key_hash = hash_sha2_256(&[quote.attestation_key, quote.qe_auth_data].concat());
+    anyhow::ensure!(
+        qe_report.report_data[..attestation_key_hash.len()] == attestation_key_hash,
+        "the attestation key is not bound to the QE report"
+    );
+    let attestation_key = VerifyingKey::from_sec1_bytes(&[&[0x04], quote.attestation_key].concat())
+        .map_err(|_err| anyhow::anyhow!("invalid attestation key"))?;
+    verify_raw_signature(&attestation_key, quote.signed_data, quote.signature)
+        .context("invalid quote signature")?;
+
+    let pck_extensions = PckExtensions::parse(&pck)?;
+    let tcb_info: TcbInfo = verify_signed_json(
+        now_utc_millis,
+        &collateral.tcb_info,
+        "tcbInfo",
+        &collateral.tcb_info_issuer_chain,
+        &root,
+    )
+    .context("invalid TCB info")?;
+    verify_next_update(now_utc_millis, &tcb_info.next_update).context("outdated TCB info")?;
+    anyhow::ensure!(
+        tcb_info.fmspc.eq_ignore_ascii_case(&hex::encode(&pck_extensions.fmspc)),
+        "the TCB info is for another platform"
+    );
+    let platform_status = tcb_info.platform_tcb_status(&pck_extensions, quote.body.tee_tcb_svn)?;
+    verify_tcb_status("platform", platform_status, reference_values)?;
+    if let Some(module_status) = tcb_info.tdx_module_tcb_status(&quote.body)? {
+        verify_tcb_status("TDX module", module_status, reference_values)?;
+    }
+
+    let qe_identity: EnclaveIdentity = verify_signed_json(
+        now_utc_millis,
+        &collateral.qe_identity,
+        "enclaveIdentity",
+        &collateral.qe_identity_issuer_chain,
+        &root,
+    )
+    .context("invalid QE identity")?;
+    verify_next_update(now_utc_millis, &qe_identity.next_update).context("outdated QE identity")?;
+    let qe_status = qe_identity.tcb_status(&qe_report)?;
+    verify_tcb_status("quoting enclave", qe_status, reference_values)
+}
+
+/// The FMSPC of the platform and the TCB it was certified with, as found in
+/// the SGX extensions of its PCK certificate.
+struct PckExtensions {
+    fmspc: Vec<u8>,
+    cpu_svn_components: [u8; 16],
+    pce_svn: u16,
+}
+
+impl PckExtensions {
+    fn parse(pck: &Certificate) -> anyhow::Result<Self> {
+        let extension = pck
+            .tbs_certificate
+            .extensions
+            .as_ref()
+            .and_then(|extensions| {
+                extensions.iter().find(|extension| extension.extn_id == SGX_EXTENSIONS_OID)
+            })
+            .context("no SGX extensions in the PCK certificate")?;
+        let entries = match der_elements(extension.extn_value.as_bytes())?.as_slice() {
+            [(DER_SEQUENCE, entries)] => parse_sgx_entries(entries)?,
+            _ => anyhow::bail!("invalid SGX extensions"),
+        };
+
+        let mut fmspc = None;
+        let mut tcb = None;
+        for (oid, tag, value) in entries {
+            match tag {
+                DER_OCTET_STRING if oid == SGX_FMSPC_OID => fmspc = Some(value.to_vec()),
+                DER_SEQUENCE if oid == SGX_TCB_OID => tcb = Some(value),
+                _ => {}
+            }
+        }
+
+        let mut cpu_svn_components = [0; 16];
+        let mut pce_svn = None;
+        for (oid, tag, value) in parse_sgx_entries(tcb.context("no TCB in the SGX extensions")?)? {
+            let arc = oid.arcs().last().unwrap_or_default();
+            if tag != DER_INTEGER || oid.parent() != Some(SGX_TCB_OID) {
+                continue;
+            }
+            match arc {
+                1..=16 => {
+                    cpu_svn_components[arc as usize - 1] = parse_der_unsigned(value)?
+                        .try_into()
+                        .map_err(|_| anyhow::anyhow!("invalid CPU SVN component"))?
+                }
+                SGX_TCB_PCESVN_ARC => {
+                    pce_svn = Some(
+                        parse_der_unsigned(value)?
+                            .try_into()
+                            .map_err(|_| anyhow::anyhow!("invalid PCE SVN"))?,
+                    )
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            fmspc: fmspc.context("no FMSPC in the SGX extensions")?,
+            cpu_svn_components,
+            pce_svn: pce_svn.context("no PCE SVN in the SGX extensions")?,
+        })
+    }
+}
+
+/// The TCB info of a platform family, which lists the TCB levels of the
+/// platform and of the TDX module with their status.
+#[derive(Deserialize)]
+struct TcbInfo {
+    #[serde(with = "time::serde::rfc3339")]
+    #[serde(rename = "nextUpdate")]
+    next_update: OffsetDateTime,
+    fmspc: String,
+    #[serde(default)]
+    #[serde(rename = "tdxModuleIdentities")]
+    tdx_module_identities: Vec<TdxModuleIdentity>,
+    #[serde(rename = "tcbLevels")]
+    tcb_levels: Vec<TcbLevel>,
+}
+
+#[derive(Deserialize)]
+struct TcbLevel {
+    tcb: Tcb,
+    #[serde(rename = "tcbStatus")]
+    tcb_status: String,
+}
+
+#[derive(Deserialize)]
+struct Tcb {
+    #[serde(rename = "sgxtcbcomponents")]
+    sgx_tcb_components: Vec<TcbComponent>,
+    pcesvn: u16,
+    #[serde(rename = "tdxtcbcomponents")]
+    tdx_tcb_components: Vec<TcbComponent>,
+}
+
+#[derive(Deserialize)]
+struct TcbComponent {
+    svn: u8,
+}
+
+#[derive(Deserialize)]
+struct TdxModuleIdentity {
+    id: String,
+    mrsigner: String,
+    attributes: String,
+    #[serde(rename = "attributesMask")]
+    attributes_mask: String,
+    #[serde(rename = "tcbLevels")]
+    tcb_levels: Vec<IsvTcbLevel>,
+}
+
+/// A TCB level of an enclave or of the TDX module, identified by its SVN.
+#[derive(Deserialize)]
+struct IsvTcbLevel {
+    tcb: IsvTcb,
+    #[serde(rename = "tcbStatus")]
+    tcb_status: String,
+}
+
+#[derive(Deserialize)]
+struct IsvTcb {
+    isvsvn: u16,
+}
+
+impl TcbInfo {
+    /// Returns the status of the highest TCB level that the platform's TCB is
+    /// at or above.
+    fn platform_tcb_status(
+        &self,
+        pck_extensions: &PckExtensions,
+        tee_tcb_svn: &[u8],
+    ) -> anyhow::Result<&str> {
+        // The first two components of the TEE TCB SVN of TDX modules with a
+        // major version above 0 identify the module, which is checked against
+        // the TDX module identities instead.
+        let first_tdx_component = if tee_tcb_svn[1] > 0 { 2 } else { 0 };
+        self.tcb_levels
+            .iter()
+            .find(|level| {
+                level.tcb.sgx_tcb_components.len() == pck_extensions.cpu_svn_components.len()
+                    && level.tcb.tdx_tcb_components.len() == tee_tcb_svn.len()
+                    && level
+                        .tcb
+                        .sgx_tcb_components
+                        .iter()
+                        .zip(pck_extensions.cpu_svn_components)
+                        .all(|(component, svn)| svn >= component.svn)
+                    && pck_extensions.pce_svn >= level.tcb.pcesvn
+                    && level
+                        .tcb
+                        .tdx_tcb_components
+                        .iter()
+                        .zip(tee_tcb_svn)
+                        .skip(first_tdx_component)
+                        .all(|(component, svn)| *svn >= component.svn)
+            })
+            .map(|level| level.tcb_status.as_str())
+            .context("the TCB of the platform is below all TCB levels")
+    }
+
+    /// Returns the status of the TCB level of the TDX module, if the module is
+    /// identified separately from the platform.
+    fn tdx_module_tcb_status(&self, body: &TdReportBody) -> anyhow::Result<Option<&str>> {
+        let major_version = body.tee_tcb_svn[1];
+        if major_version == 0 {
+            return Ok(None);
+        }
+        let id = format!("TDX_{:02X}", major_version);
+        let identity = self
+            .tdx_module_identities
+            .iter()
+            .find(|identity| identity.id == id)
+            .context("unknown TDX module")?;
+        anyhow::ensure!(
+            identity.mrsigner.eq_ignore_ascii_case(&hex::encode(body.mr_signer_seam)),
+            "unexpected TDX module signer"
+        );
+        anyhow::ensure!(
+            masked_equal(body.seam_attributes, &identity.attributes, &identity.attributes_mask)?,
+            "unexpected TDX module attributes"
+        );
+        identity
+            .tcb_levels
+            .iter()
+            .find(|level| u16::from(body.tee_tcb_svn[0]) >= level.tcb.isvsvn)
+            .map(|level| Some(level.tcb_status.as_str()))
+            .context("the TCB of the TDX module is below all TCB levels")
+    }
+}
+
+/// The identity of the quoting enclave, and its TCB levels.
+#[derive(Deserialize)]
+struct EnclaveIdentity {
+    id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    #[serde(rename = "nextUpdate")]
+    next_update: OffsetDateTime,
+    miscselect: String,
+    #[serde(rename = "miscselectMask")]
+    miscselect_mask: String,
+    attributes: String,
+    #[serde(rename = "attributesMask")]
+    attributes_mask: String,
+    mrsigner: String,
+    isvprodid: u16,
+    #[serde(rename = "tcbLevels")]
+    tcb_levels: Vec<IsvTcbLevel>,
+}
+
+impl EnclaveIdentity {
+    /// Checks that `qe_report` is from the quoting enclave with this identity,
+    /// and returns the status of its TCB level.
+    fn tcb_status(&self, qe_report: &QeReport) -> anyhow::Result<&str> {
+        anyhow::ensure!(self.id == TD_QE_ID, "unexpected enclave identity: {}", self.id);
+        anyhow::ensure!(
+            self.mrsigner.eq_ignore_ascii_case(&hex::encode(qe_report.mr_signer)),
+            "unexpected quoting enclave signer"
+        );
+        anyhow::ensure!(
+            self.isvprodid == qe_report.isv_prod_id,
+            "unexpected quoting enclave product ID"
+        );
+        anyhow::ensure!(
+            masked_equal(
+                &qe_report.misc_select.to_be_bytes(),
+                &self.miscselect,
+                &self.miscselect_mask
+            )?,
+            "unexpected quoting enclave MISCSELECT"
+        );
+        anyhow::ensure!(
+            masked_equal(qe_report.attributes, &self.attributes, &self.attributes_mask)?,
+            "unexpected quoting enclave attributes"
+        );
+        self.tcb_levels
+            .iter()
+            .find(|level| qe_report.isv_svn >= level.tcb.isvsvn)
+            .map(|level| level.tcb_status.as_str())
+            .context("the TCB of the quoting enclave is below all TCB levels")
+    }
+}
+
+fn verify_tcb_status(
+    component: &str,
+    status: &str,
+    reference_values: &IntelTdxReferenceValues,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        status == UP_TO_DATE
+            || reference_values.accepted_tcb_statuses.iter().any(|accepted| accepted == status),
+        "unaccepted TCB status of the {}: {}",
+        component,
+        status
+    );
+    Ok(())
+}
+
+fn verify_next_update(now_utc_millis: i64, next_update: &OffsetDateTime) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        next_update.unix_timestamp_nanos() / 1000000 >= now_utc_millis.into(),
+        "the collateral should have been updated by {}",
+        next_update
+    );
+    Ok(())
+}
+
+/// Parses a JSON object containing a signed field and its `signature`, as
+/// returned by the Intel PCS, verifies the signature over the field with the
+/// key certified by `issuer_chain`, and returns the parsed field. The leaf of
+/// `issuer_chain` must be the Intel SGX TCB Signing certificate, so that
+/// other certificates chaining to the root, such as PCK certificates, can't
+/// sign collateral.
+fn verify_signed_json<T: DeserializeOwned>(
+    now_utc_millis: i64,
+    json: &[u8],
+    field: &str,
+    issuer_chain: &[u8],
+    root: &Certificate,
+) -> anyhow::Result<T> {
+    let signed: BTreeMap<String, &RawValue> = serde_json::from_slice(json)
+        .map_err(|error| anyhow::anyhow!("couldn't parse signed JSON: {}", error))?;
+    let contents = signed.get(field).with_context(|| format!("no {} in the JSON", field))?;
+    let signature: String =
+        serde_json::from_str(signed.get("signature").context("no signature in the JSON")?.get())
+            .map_err(|error| anyhow::anyhow!("couldn't parse signature: {}", error))?;
+    let signature = hex::decode(signature)
+        .map_err(|error| anyhow::anyhow!("couldn't decode hex signature: {}", error))?;
+
+    let signer = verify_cert_chain(now_utc_millis, issuer_chain, root)
+        .context("invalid issuer certificate chain")?;
+    anyhow::ensure!(
+        common_name(&signer) == Some(TCB_SIGNING_COMMON_NAME),
+        "the collateral is not signed by the TCB signing certificate: {}",
+        signer.tbs_certificate.subject
+    );
+    verify_raw_signature(&certificate_public_key(&signer)?, contents.get().as_bytes(), &signature)?;
+
+    serde_json::from_str(contents.get())
+        .map_err(|error| anyhow::anyhow!("couldn't parse {}: {}", field, error))
+}
+
+/// Verifies a PEM-encoded certificate chain, leaf first, that must end with
+/// `root` or a certificate signed by it, and returns the leaf certificate.
+/// Every certificate that issues another one must be a CA allowed to sign
+/// certificates.
+fn verify_cert_chain(
+    now_utc_millis: i64,
+    chain: &[u8],
+    root: &Certificate,
+) -> anyhow::Result<Certificate> {
+    let certs = parse_pem_chain(chain)?;
+    let last = certs.last().context("empty certificate chain")?;
+    for pair in certs.windows(2) {
+        verify_cert_issuer(&pair[1])?;
+        verify_cert_signature(&pair[1], &pair[0])?;
+    }
+    let root_der = root.to_der().map_err(|_err| anyhow::anyhow!("could not encode root cert"))?;
+    if last.to_der().map_err(|_err| anyhow::anyhow!("could not encode cert"))? != root_der {
+        verify_cert_issuer(root)?;
+        verify_cert_signature(root, last)?;
+    }
+    for cert in certs.iter().chain([root]) {
+        verify_cert_validity(now_utc_millis, cert)?;
+    }
+    Ok(certs.into_iter().next().expect("empty certificate chain"))
+}
+
+fn parse_pem_chain(chain: &[u8]) -> anyhow::Result<Vec<Certificate>> {
+    core::str::from_utf8(chain)
+        .map_err(|error| anyhow::anyhow!(error))?
+        .split_inclusive(PEM_CERTIFICATE_FOOTER)
+        .map(|pem| pem.trim_matches(|c: char| c.is_whitespace() || c == '\0'))
+        .filter(|pem| !pem.is_empty())
+        .map(|pem| {
+            Certificate::from_pem(pem).map_err(|_err| anyhow::anyhow!("could not parse PEM cert"))
+        })
+        .collect()
+}
+
+/// Checks that the basic constraints of `issuer` mark it as a CA, and that its
+/// key usage allows signing certificates.
+fn verify_cert_issuer(issuer: &Certificate) -> anyhow::Result<()> {
+    let subject = &issuer.tbs_certificate.subject;
+    let basic_constraints = issuer
+        .tbs_certificate
+        .get::<BasicConstraints>()
+        .map_err(|_err| anyhow::anyhow!("could not parse basic constraints of {}", subject))?;
+    anyhow::ensure!(
+        matches!(basic_constraints, Some((_, BasicConstraints { ca: true, .. }))),
+        "certificate is not a CA: {}",
+        subject
+    );
+    let key_usage = issuer
+        .tbs_certificate
+        .get::<KeyUsage>()
+        .map_err(|_err| anyhow::anyhow!("could not parse key usage of {}", subject))?;
+    anyhow::ensure!(
+        key_usage.is_some_and(|(_, key_usage)| key_usage.key_cert_sign()),
+        "certificate is not allowed to sign certificates: {}",
+        subject
+    );
+    Ok(())
+}
+
+/// Returns the common name in the subject of `cert`, if it has exactly one.
+fn common_name(cert: &Certificate) -> Option<&str> {
+    let mut names = cert
+        .tbs_certificate
+        .subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .filter(|attribute| attribute.oid == COMMON_NAME_OID);
+    let name = names.next()?;
+    if names.next().is_some() {
+        return None;
+    }
+    match name.value.tag() {
+        Tag::PrintableString => PrintableStringRef::try_from(&name.value).ok().map(|s| s.as_str()),
+        Tag::Utf8String => Utf8StringRef::try_from(&name.value).ok().map(|s| s.as_str()),
+        _ => None,
+    }
+}
+
+fn verify_cert_signature(signer: &Certificate, signee: &Certificate) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        signee.signature_algorithm.oid == ECDSA_WITH_SHA256_OID,
+        "unsupported signature algorithm: {:?}",
+        signee.signature_algorithm
+    );
+    let message = signee
+        .tbs_certificate
+        .to_der()
+        .map_err(|_err| anyhow::anyhow!("could not extract message to verify ECDSA signature"))?;
+    let signature = Signature::from_der(signee.signature.raw_bytes())
+        .map_err(|_err| anyhow::anyhow!("could not extract ECDSA signature"))?;
+    certificate_public_key(signer)?
+        .verify(&message, &signature)
+        .map_err(|_err| anyhow::anyhow!("invalid certificate signature"))
+}
+
+fn verify_cert_validity(now_utc_millis: i64, cert: &Certificate) -> anyhow::Result<()> {
+    let validity = &cert.tbs_certificate.validity;
+    let now = i128::from(now_utc_millis);
+    anyhow::ensure!(
+        validity.not_before.to_unix_duration().as_millis() as i128 <= now
+            && now <= validity.not_after.to_unix_duration().as_millis() as i128,
+        "certificate is not valid at this time: {}",
+        cert.tbs_certificate.subject
+    );
+    Ok(())
+}
+
+fn certificate_public_key(cert: &Certificate) -> anyhow::Result<VerifyingKey> {
+    VerifyingKey::from_sec1_bytes(
+        cert.tbs_certificate.subject_public_key_info.subject_public_key.raw_bytes(),
+    )
+    .map_err(|_err| anyhow::anyhow!("could not parse ECDSA public key"))
+}
+
+/// Verifies an ECDSA signature encoded as the concatenation of `r` and `s`.
+fn verify_raw_signature(
+    key: &VerifyingKey,
+    message: &[u8],
+    signature: &[u8],
+) -> anyhow::Result<()> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|_err| anyhow::anyhow!("invalid ECDSA signature"))?;
+    key.verify(message, &signature).map_err(|_err| anyhow::anyhow!("couldn't verify signature"))
+}
+
+/// Compares `actual` with the hex-encoded `expected` value, only considering
+/// the bits set in the hex-encoded `mask`.
+fn masked_equal(actual: &[u8], expected: &str, mask: &str) -> anyhow::Result<bool> {
+    let expected =
+        hex::decode(expected).map_err(|error| anyhow::anyhow!("invalid hex value: {}", error))?;
+    let mask = hex::decode(mask).map_err(|error| anyhow::anyhow!("invalid hex mask: {}", error))?;
+    anyhow::ensure!(
+        expected.len() == actual.len() && mask.len() == actual.len(),
+        "mismatched value sizes"
+    );
+    Ok(actual
+        .iter()
+        .zip(expected)
+        .zip(mask)
+        .all(|((actual, expected), mask)| actual & mask == expected & mask))
+}
+
+/// Parses the contents of a DER SEQUENCE of `SEQUENCE { OID, value }`
+/// entries, as used in the SGX extensions, into the OIDs and the tags and
+/// contents of the values.
+fn parse_sgx_entries(contents: &[u8]) -> anyhow::Result<Vec<(ObjectIdentifier, u8, &[u8])>> {
+    der_elements(contents)?
+        .into_iter()
+        .map(|(tag, entry)| match (tag, der_elements(entry)?.as_slice()) {
+            (DER_SEQUENCE, [(DER_OID, oid), (value_tag, value)]) => Ok((
+                ObjectIdentifier::from_bytes(oid)
+                    .map_err(|_err| anyhow::anyhow!("invalid OID in the SGX extensions"))?,
+                *value_tag,
+                *value,
+            )),
+            _ => Err(anyhow::anyhow!("invalid entry in the SGX extensions")),
+        })
+        .collect()
+}
+
+/// Splits DER-encoded `contents` into the tags and contents of the elements
+/// they consist of.
+fn der_elements(contents: &[u8]) -> anyhow::Result<Vec<(u8, &[u8])>> {
+    let mut reader = Reader(contents);
+    let mut elements = Vec::new();
+    while !reader.0.is_empty() {
+        let header = reader.bytes(2)?;
+        let length = match header[1] {
+            length @ 0..=0x7f => length as usize,
+            length_size @ 0x81..=0x84 => reader
+                .bytes((length_size & 0x7f) as usize)?
+                .iter()
+                .fold(0, |length, byte| length << 8 | *byte as usize),
+            _ => anyhow::bail!("unsupported DER length"),
+        };
+        elements.push((header[0], reader.bytes(length)?));
+    }
+    Ok(elements)
+}
+
+/// Parses the contents of a DER INTEGER that must be non-negative and fit in
+/// 32 bits.
+fn parse_der_unsigned(contents: &[u8]) -> anyhow::Result<u32> {
+    anyhow::ensure!(
+        !contents.is_empty() && contents[0] & 0x80 == 0,
+        "invalid unsigned DER integer"
+    );
+    let contents = contents.strip_prefix(&[0]).unwrap_or(contents);
+    anyhow::ensure!(contents.len() <= 4, "DER integer too large");
+    Ok(contents.iter().fold(0, |value, byte| value << 8 | u32::from(*byte)))
+}
+
+/// Reads little-endian values from a byte string.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, size: usize) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(self.0.len() >= size, "truncated data");
+        let (bytes, rest) = self.0.split_at(size);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().expect("invalid size")))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().expect("invalid size")))
+    }
+
+    /// Reads certification data of the given type, and returns a reader of
+    /// its contents.
+    fn certification_data(&mut self, expected_type: u16) -> anyhow::Result<Reader<'a>> {
+        let certification_data_type = self.u16()?;
+        anyhow::ensure!(
+            certification_data_type == expected_type,
+            "unexpected certification data type: {}",
+            certification_data_type
+        );
+        let size = self.u32()? as usize;
+        Ok(Reader(self.bytes(size)?))
+    }
+}
@@ -22,6 +22,9 @@ extern crate alloc;
 pub mod amd;
 pub mod claims;
 pub mod endorsement;
+pub mod intel;
+pub mod peer;
+pub mod policy;
 pub mod rekor;
 pub mod util;
 pub mod verifier;
@@ -0,0 +1,84 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Verification of the attestation of a peer that sends requests to an
+//! enclave, for enclaves that only serve other attested enclaves or services.
+
+use anyhow::Context;
+use ecdsa::signature::Verifier;
+use oak_proto_rust::oak::attestation::v1::{
+    AppraisalPolicy, Endorsements, ExtractedEvidence, IntelTdxReferenceValues, PeerAttestation,
+    RootLayerReferenceValues,
+};
+use p256::ecdsa::{Signature, VerifyingKey};
+
+use crate::{
+    policy::appraise,
+    verifier::{verify_dice_chain, verify_root_attestation_signature},
+};
+
+/// Verifies that `attestation` proves that the peer that created the
+/// encapsulated key `serialized_encapsulated_public_key` of a request runs in
+/// an environment that satisfies `policy`.
+///
+/// As with [`crate::verifier::verify`], the attestation report of the peer's
+/// TEE must be signed by the vendor of the TEE and bind the root key of the
+/// DICE chain, which is then verified and appraised against the policy. The
+/// signature over the encapsulated key must have been created with the signing
+/// key certified by the evidence. Since the endorsements of the peer's binaries
+/// aren't available, policies that require endorsement claims are rejected.
+pub fn verify_peer_attestation(
+    now_utc_millis: i64,
+    policy: &AppraisalPolicy,
+    attestation: &PeerAttestation,
+    serialized_encapsulated_public_key: &[u8],
+) -> anyhow::Result<ExtractedEvidence> {
+    anyhow::ensure!(
+        policy.required_endorsement_claim_types.is_empty(),
+        "endorsement claims can't be required of peers"
+    );
+    let evidence = attestation.evidence.as_ref().context("no peer evidence")?;
+    let root_layer = evidence.root_layer.as_ref().context("no peer root layer evidence")?;
+    // Intel TDX quotes lead to the root CA certificate of the policy, if any.
+    let reference_values =
+        (!policy.intel_root_ca_certificate.is_empty()).then(|| RootLayerReferenceValues {
+            intel_tdx: Some(IntelTdxReferenceValues {
+                root_ca_certificate: policy.intel_root_ca_certificate.clone(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    verify_root_attestation_signature(
+        now_utc_millis,
+        root_layer,
+        &attestation.root_layer_endorsements.clone().unwrap_or_default(),
+        reference_values.as_ref(),
+    )
+    .context("invalid peer attestation report")?;
+    let extracted_evidence = verify_dice_chain(evidence).context("invalid peer DICE chain")?;
+
+    let signing_key = VerifyingKey::from_sec1_bytes(&extracted_evidence.signing_public_key)
+        .map_err(|error| anyhow::anyhow!("invalid peer signing key: {}", error))?;
+    let signature = Signature::from_slice(&attestation.encapsulated_public_key_signature)
+        .map_err(|error| anyhow::anyhow!("invalid encapsulated key signature: {}", error))?;
+    signing_key.verify(serialized_encapsulated_public_key, &signature).map_err(|error| {
+        anyhow::anyhow!("couldn't verify encapsulated key signature: {}", error)
+    })?;
+
+    appraise(policy, &extracted_evidence, &Endorsements::default())
+        .context("peer evidence doesn't satisfy the policy")?;
+    Ok(extracted_evidence)
+}
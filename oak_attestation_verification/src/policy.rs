@@ -0,0 +1,286 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Appraisal of extracted evidence against an [`AppraisalPolicy`], which covers
+//! the common checks (accepted kernels, minimum TCB versions, debug mode and
+//! endorsement claims) without requiring full reference values.
+//!
+//! Policies can be written as JSON documents, with the field names of the
+//! proto message, e.g.:
+//!
+//! ```json
+//! {
+//!   "allowed_kernel_digests": [{ "sha2_256": "ec752c66..." }],
+//!   "min_amd_sev_snp_tcb": { "boot_loader": 3, "tee": 0, "snp": 20, "microcode": 209 },
+//!   "required_endorsement_claim_types": [
+//!     "https://github.com/project-oak/transparent-release/endorsement/v2"
+//!   ]
+//! }
+//! ```
+//!
+//! As in the JSON mapping of protos, `intel_root_ca_certificate` is base64
+//! encoded.
+
+use alloc::{string::String, vec, vec::Vec};
+
+use anyhow::Context;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use oak_proto_rust::oak::{
+    attestation::v1::{
+        endorsements, extracted_evidence::EvidenceValues, root_layer_data::Report, AppraisalPolicy,
+        Endorsements, ExtractedEvidence, KernelLayerData, KernelLayerEndorsements, RootLayerData,
+        TcbVersion, TransparentReleaseEndorsement,
+    },
+    HexDigest,
+};
+use serde::Deserialize;
+
+use crate::{
+    claims::parse_endorsement_statement,
+    util::{is_hex_digest_match, raw_to_hex_digest, MatchResult},
+};
+
+/// Checks that the evidence values extracted by the verifier and the
+/// endorsements of the evidence satisfy `policy`.
+///
+/// The endorsements are only inspected, so they must have been verified
+/// before, e.g. with [`crate::verifier::verify`].
+pub fn appraise(
+    policy: &AppraisalPolicy,
+    extracted_evidence: &ExtractedEvidence,
+    endorsements: &Endorsements,
+) -> anyhow::Result<()> {
+    let (root_layer, kernel_layer) =
+        match extracted_evidence.evidence_values.as_ref().context("no evidence values")? {
+            EvidenceValues::OakRestrictedKernel(values) => {
+                (values.root_layer.as_ref(), values.kernel_layer.as_ref())
+            }
+            EvidenceValues::OakContainers(values) => {
+                (values.root_layer.as_ref(), values.kernel_layer.as_ref())
+            }
+            EvidenceValues::Cb(values) => (values.root_layer.as_ref(), None),
+        };
+    appraise_root_layer(policy, root_layer.context("no root layer evidence values")?)
+        .context("root layer appraisal failed")?;
+    appraise_kernel_layer(policy, kernel_layer).context("kernel layer appraisal failed")?;
+    appraise_endorsements(policy, endorsements).context("endorsement appraisal failed")
+}
+
+/// Parses a policy from a JSON document.
+pub fn parse_policy_json(json: &[u8]) -> anyhow::Result<AppraisalPolicy> {
+    let policy: JsonPolicy = serde_json::from_slice(json)
+        .map_err(|err| anyhow::anyhow!("couldn't parse appraisal policy: {}", err))?;
+    Ok(AppraisalPolicy {
+        allowed_kernel_digests: policy
+            .allowed_kernel_digests
+            .into_iter()
+            .map(|digest| HexDigest {
+                psha2: digest.psha2,
+                sha1: digest.sha1,
+                sha2_256: digest.sha2_256,
+                sha2_512: digest.sha2_512,
+                sha3_512: digest.sha3_512,
+                sha3_384: digest.sha3_384,
+                sha3_256: digest.sha3_256,
+                sha3_224: digest.sha3_224,
+                sha2_384: digest.sha2_384,
+            })
+            .collect(),
+        min_amd_sev_snp_tcb: policy.min_amd_sev_snp_tcb.map(|tcb| TcbVersion {
+            boot_loader: tcb.boot_loader,
+            tee: tcb.tee,
+            snp: tcb.snp,
+            microcode: tcb.microcode,
+        }),
+        allow_debug: policy.allow_debug,
+        required_endorsement_claim_types: policy.required_endorsement_claim_types,
+        allow_insecure: policy.allow_insecure,
+        intel_root_ca_certificate: BASE64_STANDARD
+            .decode(policy.intel_root_ca_certificate)
+            .map_err(|err| anyhow::anyhow!("invalid Intel root CA certificate: {}", err))?,
+    })
+}
+
+fn appraise_root_layer(policy: &AppraisalPolicy, values: &RootLayerData) -> anyhow::Result<()> {
+    match values.report.as_ref().context("no attestation report values")? {
+        Report::SevSnp(report) => {
+            anyhow::ensure!(policy.allow_debug || !report.debug, "debug mode not allowed");
+            if let Some(min_tcb) = policy.min_amd_sev_snp_tcb.as_ref() {
+                let reported_tcb = report.reported_tcb.as_ref().context("no reported TCB")?;
+                for (name, reported, min) in [
+                    ("boot loader", reported_tcb.boot_loader, min_tcb.boot_loader),
+                    ("tee", reported_tcb.tee, min_tcb.tee),
+                    ("snp", reported_tcb.snp, min_tcb.snp),
+                    ("microcode", reported_tcb.microcode, min_tcb.microcode),
+                ] {
+                    anyhow::ensure!(
+                        reported >= min,
+                        "{} version {} in the reported TCB is lower than {}",
+                        name,
+                        reported,
+                        min
+                    );
+                }
+            }
+            Ok(())
+        }
+        Report::Tdx(report) => {
+            anyhow::ensure!(policy.allow_debug || !report.debug, "debug mode not allowed");
+            Ok(())
+        }
+        Report::Fake(_) => {
+            anyhow::ensure!(policy.allow_insecure, "evidence wasn't generated in a TEE");
+            Ok(())
+        }
+    }
+}
+
+fn appraise_kernel_layer(
+    policy: &AppraisalPolicy,
+    values: Option<&KernelLayerData>,
+) -> anyhow::Result<()> {
+    if policy.allowed_kernel_digests.is_empty() {
+        return Ok(());
+    }
+    let kernel_image = values
+        .context("no kernel layer evidence values")?
+        .kernel_image
+        .as_ref()
+        .context("no kernel image digest")?;
+    let actual = raw_to_hex_digest(kernel_image);
+    anyhow::ensure!(
+        policy
+            .allowed_kernel_digests
+            .iter()
+            .any(|allowed| is_hex_digest_match(&actual, allowed) == MatchResult::SAME),
+        "kernel image with SHA2-256 digest {} isn't allowed",
+        actual.sha2_256
+    );
+    Ok(())
+}
+
+fn appraise_endorsements(
+    policy: &AppraisalPolicy,
+    endorsements: &Endorsements,
+) -> anyhow::Result<()> {
+    if policy.required_endorsement_claim_types.is_empty() {
+        return Ok(());
+    }
+    let claim_types = transparent_release_endorsements(endorsements)
+        .into_iter()
+        .filter(|endorsement| !endorsement.endorsement.is_empty())
+        .map(|endorsement| {
+            parse_endorsement_statement(&endorsement.endorsement)
+                .map(|statement| statement.predicate.claim_type)
+        })
+        .collect::<anyhow::Result<Vec<String>>>()?;
+    for required in policy.required_endorsement_claim_types.iter() {
+        anyhow::ensure!(
+            claim_types.contains(required),
+            "no endorsement with claim type {}",
+            required
+        );
+    }
+    Ok(())
+}
+
+/// Returns the endorsements of the binaries in all layers.
+fn transparent_release_endorsements(
+    endorsements: &Endorsements,
+) -> Vec<&TransparentReleaseEndorsement> {
+    #[allow(deprecated)]
+    fn kernel_layer(
+        endorsements: Option<&KernelLayerEndorsements>,
+    ) -> Vec<Option<&TransparentReleaseEndorsement>> {
+        endorsements
+            .map(|ends| {
+                vec![
+                    ends.kernel.as_ref(),
+                    ends.kernel_cmd_line.as_ref(),
+                    ends.init_ram_fs.as_ref(),
+                    ends.memory_map.as_ref(),
+                    ends.acpi.as_ref(),
+                    ends.kernel_image.as_ref(),
+                ]
+            })
+            .unwrap_or_default()
+    }
+
+    let mut all = Vec::new();
+    match endorsements.r#type.as_ref() {
+        Some(endorsements::Type::OakRestrictedKernel(ends)) => {
+            all.push(ends.root_layer.as_ref().and_then(|root| root.stage0.as_ref()));
+            all.extend(kernel_layer(ends.kernel_layer.as_ref()));
+            if let Some(application_layer) = ends.application_layer.as_ref() {
+                all.push(application_layer.binary.as_ref());
+                all.push(application_layer.configuration.as_ref());
+            }
+        }
+        Some(endorsements::Type::OakContainers(ends)) => {
+            all.push(ends.root_layer.as_ref().and_then(|root| root.stage0.as_ref()));
+            all.extend(kernel_layer(ends.kernel_layer.as_ref()));
+            if let Some(system_layer) = ends.system_layer.as_ref() {
+                all.push(system_layer.system_image.as_ref());
+            }
+            if let Some(container_layer) = ends.container_layer.as_ref() {
+                all.push(container_layer.binary.as_ref());
+                all.push(container_layer.configuration.as_ref());
+            }
+        }
+        Some(endorsements::Type::Cb(ends)) => {
+            all.push(ends.root_layer.as_ref().and_then(|root| root.stage0.as_ref()));
+        }
+        None => {}
+    }
+    all.into_iter().flatten().collect()
+}
+
+// Mirrors of the proto messages for parsing policies from JSON, since the
+// generated types don't implement `Deserialize`.
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JsonPolicy {
+    allowed_kernel_digests: Vec<JsonHexDigest>,
+    min_amd_sev_snp_tcb: Option<JsonTcbVersion>,
+    allow_debug: bool,
+    required_endorsement_claim_types: Vec<String>,
+    allow_insecure: bool,
+    intel_root_ca_certificate: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JsonHexDigest {
+    psha2: String,
+    sha1: String,
+    sha2_256: String,
+    sha2_512: String,
+    sha3_512: String,
+    sha3_384: String,
+    sha3_256: String,
+    sha3_224: String,
+    sha2_384: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JsonTcbVersion {
+    boot_loader: u32,
+    tee: u32,
+    snp: u32,
+    microcode: u32,
+}
@@ -113,18 +113,51 @@ pub struct PublicKey {
     pub content: String,
 }
 
-/// Struct representing a verification object in a Rekor LogEntry. The
-/// verification object in Rekor also contains an inclusion proof. Since we
-/// currently don't verify the inclusion proof in the client, it is omitted from
-/// this struct.
+/// Struct representing a verification object in a Rekor LogEntry.
 #[derive(Debug, Deserialize, PartialEq)]
 #[cfg_attr(feature = "std", derive(Serialize))]
 pub struct LogEntryVerification {
+    /// Proof that the entry is included in the log, for log entries that have
+    /// been integrated in the Merkle tree of the log.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "inclusionProof")]
+    pub inclusion_proof: Option<InclusionProof>,
+
     // Base64-encoded signature over the body, integratedTime, logID, and logIndex.
     #[serde(rename = "signedEntryTimestamp")]
     pub signed_entry_timestamp: String,
 }
 
+/// Struct representing the proof of inclusion of an entry in the Merkle tree
+/// of the log, as specified in RFC 6962.
+/// Based on <https://github.com/sigstore/rekor/blob/2978cdc26fdf8f5bfede8459afd9735f0f231a2a/pkg/generated/models/inclusion_proof.go#L35.>
+#[derive(Debug, Deserialize, PartialEq)]
+#[cfg_attr(feature = "std", derive(Serialize))]
+pub struct InclusionProof {
+    /// Signed checkpoint (in the signed note format) committing to the root
+    /// hash and the size of the tree.
+    pub checkpoint: String,
+
+    /// Hex-encoded hashes of the audit path, from the leaf to the root.
+    pub hashes: Vec<String>,
+
+    /// Index of the entry in the tree of the shard it belongs to. Unlike the
+    /// index in the LogEntry, this doesn't count the entries of previous
+    /// shards.
+    /// Minimum: 0
+    #[serde(rename = "logIndex")]
+    pub log_index: u64,
+
+    /// Hex-encoded root hash of the tree.
+    /// Pattern: ^[0-9a-fA-F]{64}$
+    #[serde(rename = "rootHash")]
+    pub root_hash: String,
+
+    /// Minimum: 1
+    #[serde(rename = "treeSize")]
+    pub tree_size: u64,
+}
+
 /// Convenient struct for verifying the `signedEntryTimestamp` in a Rekor
 /// LogEntry.
 ///
@@ -183,32 +216,42 @@ impl TryFrom<&LogEntry> for RekorSignatureBundle {
 /// Verifies a Rekor LogEntry. This includes verifying:
 ///
 /// 1. the signature in `signedEntryTimestamp` using Rekor's public key,
+/// 1. the inclusion proof of the entry, against a checkpoint signed with
+///    Rekor's public key,
 /// 1. the signature in `body.RekordObj.signature` using the endorser's public
 ///    key,
 /// 1. that the content of the body equals `endorsement`.
+///
+/// Returns the index of the entry in the log.
 pub fn verify_rekor_log_entry(
     log_entry: &[u8],
     rekor_public_key: &[u8],
     endorsement: &[u8],
-) -> anyhow::Result<()> {
+) -> anyhow::Result<u64> {
     verify_rekor_signature(log_entry, rekor_public_key)?;
+    verify_rekor_inclusion_proof(log_entry, rekor_public_key)?;
 
     let body = get_rekor_log_entry_body(log_entry)?;
 
     // Verify the body in the Rekor LogEntry
     verify_rekor_body(&body, endorsement)?;
 
-    Ok(())
+    Ok(get_rekor_log_entry(log_entry)?.log_index)
 }
 
-/// Parses the given bytes into a Rekor `LogEntry` object, and returns its
-/// `body` parsed into an instance of `Body`.
-pub fn get_rekor_log_entry_body(log_entry: &[u8]) -> anyhow::Result<Body> {
+/// Parses the given bytes into a Rekor `LogEntry` object.
+pub fn get_rekor_log_entry(log_entry: &[u8]) -> anyhow::Result<LogEntry> {
     let parsed: BTreeMap<String, LogEntry> =
         serde_json::from_slice(log_entry).map_err(|error| {
             anyhow::anyhow!("couldn't parse bytes into a LogEntry object: {}", error)
         })?;
-    let entry = parsed.values().next().context("no entry in the map")?;
+    parsed.into_values().next().context("no entry in the map")
+}
+
+/// Parses the given bytes into a Rekor `LogEntry` object, and returns its
+/// `body` parsed into an instance of `Body`.
+pub fn get_rekor_log_entry_body(log_entry: &[u8]) -> anyhow::Result<Body> {
+    let entry = get_rekor_log_entry(log_entry)?;
 
     // Parse base64-encoded entry.body into an instance of Body.
     let body_bytes: Vec<u8> = BASE64_STANDARD
@@ -232,6 +275,43 @@ pub fn verify_rekor_signature(log_entry: &[u8], rekor_public_key: &[u8]) -> anyh
     .context("couldn't verify signedEntryTimestamp of the Rekor LogEntry")
 }
 
+/// Parses a blob into a Rekor log entry and verifies that the entry is included
+/// in the log: the inclusion proof must lead from the entry to the root hash of
+/// a checkpoint signed with Rekor's public key.
+pub fn verify_rekor_inclusion_proof(
+    log_entry: &[u8],
+    rekor_public_key: &[u8],
+) -> anyhow::Result<()> {
+    let entry = get_rekor_log_entry(log_entry)?;
+    let proof = entry
+        .verification
+        .as_ref()
+        .and_then(|verification| verification.inclusion_proof.as_ref())
+        .context("no inclusion proof in the log entry")?;
+
+    let checkpoint = verify_checkpoint(&proof.checkpoint, rekor_public_key)
+        .context("couldn't verify the checkpoint of the inclusion proof")?;
+    let root_hash = decode_hash(&proof.root_hash)?;
+    anyhow::ensure!(
+        checkpoint.tree_size == proof.tree_size && checkpoint.root_hash == root_hash,
+        "the inclusion proof doesn't match its checkpoint"
+    );
+
+    let leaf = BASE64_STANDARD
+        .decode(&entry.body)
+        .map_err(|error| anyhow::anyhow!("couldn't decode Base64 body: {}", error))?;
+    let audit_path =
+        proof.hashes.iter().map(|hash| decode_hash(hash)).collect::<anyhow::Result<Vec<_>>>()?;
+    let computed_root_hash =
+        root_from_inclusion_proof(proof.log_index, proof.tree_size, &leaf, &audit_path)?;
+    anyhow::ensure!(
+        computed_root_hash == root_hash,
+        "the inclusion proof doesn't lead to the root hash of the log"
+    );
+
+    Ok(())
+}
+
 /// Verifies the signature in the body over the contents.
 pub fn verify_rekor_body(body: &Body, contents_bytes: &[u8]) -> anyhow::Result<()> {
     if body.spec.signature.format != "x509" {
@@ -277,11 +357,109 @@ pub fn verify_rekor_body(body: &Body, contents_bytes: &[u8]) -> anyhow::Result<(
 }
 
 fn rekor_signature_bundle(log_entry: &[u8]) -> anyhow::Result<RekorSignatureBundle> {
-    let parsed: BTreeMap<String, LogEntry> =
-        serde_json::from_slice(log_entry).map_err(|error| {
-            anyhow::anyhow!("couldn't parse bytes into a LogEntry object: {}", error)
-        })?;
-    let entry = parsed.values().next().context("no entry in the map")?;
+    let entry = get_rekor_log_entry(log_entry)?;
+
+    RekorSignatureBundle::try_from(&entry)
+}
+
+/// The tree size and root hash that a checkpoint commits to.
+struct Checkpoint {
+    tree_size: u64,
+    root_hash: [u8; 32],
+}
+
+/// Verifies a checkpoint in the signed note format used by Rekor, returning
+/// the tree it commits to. See <https://github.com/transparency-dev/formats/blob/main/log/README.md>.
+///
+/// The note consists of the origin, the tree size and the Base64-encoded root
+/// hash (each on its own line, optionally followed by extension lines), an
+/// empty line, and signature lines of the form `— <name> <Base64 signature>`.
+/// Each signature starts with a 4-byte hint identifying the key, which for
+/// Rekor is the prefix of the SHA-256 hash of its DER-encoded public key.
+fn verify_checkpoint(checkpoint: &str, rekor_public_key: &[u8]) -> anyhow::Result<Checkpoint> {
+    let (text, signatures) =
+        checkpoint.split_once("\n\n").context("no signatures in the checkpoint")?;
+    // The signed text includes the newline ending its last line.
+    let signed = format!("{text}\n");
+
+    let key_hint = &hash_sha2_256(rekor_public_key)[..4];
+    let signature = signatures
+        .lines()
+        .filter_map(|line| line.strip_prefix("\u{2014} "))
+        .filter_map(|line| line.rsplit_once(' '))
+        .filter_map(|(_name, signature)| BASE64_STANDARD.decode(signature).ok())
+        .find(|signature| signature.len() > 4 && &signature[..4] == key_hint)
+        .context("no signature from the Rekor public key in the checkpoint")?;
+    verify_signature_raw(&signature[4..], signed.as_bytes(), rekor_public_key)?;
+
+    let mut lines = text.lines().skip(1);
+    let tree_size = lines
+        .next()
+        .context("no tree size in the checkpoint")?
+        .parse()
+        .map_err(|error| anyhow::anyhow!("invalid tree size in the checkpoint: {}", error))?;
+    let root_hash = BASE64_STANDARD
+        .decode(lines.next().context("no root hash in the checkpoint")?)
+        .map_err(|error| anyhow::anyhow!("couldn't decode Base64 root hash: {}", error))?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid root hash size in the checkpoint"))?;
+
+    Ok(Checkpoint { tree_size, root_hash })
+}
+
+/// Computes the root hash of a tree of `tree_size` leaves from the leaf at
+/// `leaf_index` and its audit path, as specified in RFC 9162 section 2.1.3.2.
+fn root_from_inclusion_proof(
+    leaf_index: u64,
+    tree_size: u64,
+    leaf: &[u8],
+    audit_path: &[[u8; 32]],
+) -> anyhow::Result<[u8; 32]> {
+    anyhow::ensure!(leaf_index < tree_size, "leaf index is outside of the tree");
+
+    let mut index = leaf_index;
+    let mut last_index = tree_size - 1;
+    let mut hash = hash_leaf(leaf);
+    for sibling in audit_path {
+        anyhow::ensure!(last_index != 0, "the inclusion proof is too long");
+        if index & 1 == 1 || index == last_index {
+            hash = hash_children(sibling, &hash);
+            if index & 1 == 0 {
+                // Skip the levels where the node is the last one and has no sibling.
+                while index & 1 == 0 && index != 0 {
+                    index >>= 1;
+                    last_index >>= 1;
+                }
+            }
+        } else {
+            hash = hash_children(&hash, sibling);
+        }
+        index >>= 1;
+        last_index >>= 1;
+    }
+    anyhow::ensure!(last_index == 0, "the inclusion proof is too short");
+
+    Ok(hash)
+}
+
+fn hash_leaf(leaf: &[u8]) -> [u8; 32] {
+    let mut input = Vec::with_capacity(leaf.len() + 1);
+    input.push(0);
+    input.extend_from_slice(leaf);
+    hash_sha2_256(&input)
+}
+
+fn hash_children(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut input = Vec::with_capacity(65);
+    input.push(1);
+    input.extend_from_slice(left);
+    input.extend_from_slice(right);
+    hash_sha2_256(&input)
+}
 
-    RekorSignatureBundle::try_from(entry)
+fn decode_hash(hash_hex: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(hash_hex)
+        .map_err(|error| anyhow::anyhow!("couldn't decode hex hash: {}", error))?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid hash size"))
 }
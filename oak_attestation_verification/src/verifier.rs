@@ -35,17 +35,18 @@ use oak_proto_rust::oak::{
         extracted_evidence::EvidenceValues, kernel_binary_reference_value, reference_values,
         root_layer_data::Report, text_reference_value, AmdAttestationReport, AmdSevReferenceValues,
         ApplicationKeys, ApplicationLayerData, ApplicationLayerEndorsements,
-        ApplicationLayerReferenceValues, AttestationResults, BinaryReferenceValue, CbData,
-        CbEndorsements, CbReferenceValues, ContainerLayerData, ContainerLayerEndorsements,
-        ContainerLayerReferenceValues, Endorsements, Evidence, ExtractedEvidence,
-        FakeAttestationReport, InsecureReferenceValues, IntelTdxAttestationReport,
-        IntelTdxReferenceValues, KernelAttachment, KernelBinaryReferenceValue, KernelLayerData,
-        KernelLayerEndorsements, KernelLayerReferenceValues, OakContainersData,
-        OakContainersEndorsements, OakContainersReferenceValues, OakRestrictedKernelData,
-        OakRestrictedKernelEndorsements, OakRestrictedKernelReferenceValues, ReferenceValues,
-        RootLayerData, RootLayerEndorsements, RootLayerEvidence, RootLayerReferenceValues,
-        SystemLayerData, SystemLayerEndorsements, SystemLayerReferenceValues, TcbVersion,
-        TeePlatform, TextReferenceValue, TransparentReleaseEndorsement,
+        ApplicationLayerReferenceValues, AttestationBundle, AttestationResults,
+        BinaryReferenceValue, CbData, CbEndorsements, CbReferenceValues, ContainerLayerData,
+        ContainerLayerEndorsements, ContainerLayerReferenceValues, Endorsements, Evidence,
+        ExtractedEvidence, FakeAttestationReport, InsecureReferenceValues,
+        IntelTdxAttestationReport, IntelTdxReferenceValues, KernelAttachment,
+        KernelBinaryReferenceValue, KernelLayerData, KernelLayerEndorsements,
+        KernelLayerReferenceValues, OakContainersData, OakContainersEndorsements,
+        OakContainersReferenceValues, OakRestrictedKernelData, OakRestrictedKernelEndorsements,
+        OakRestrictedKernelReferenceValues, ReferenceValues, RootLayerData, RootLayerEndorsements,
+        RootLayerEvidence, RootLayerReferenceValues, SystemLayerData, SystemLayerEndorsements,
+        SystemLayerReferenceValues, TcbVersion, TeePlatform, TextReferenceValue,
+        TransparencyLogEntry, TransparentReleaseEndorsement, VerificationCheck, VerificationReport,
     },
     HexDigest, RawDigest,
 };
@@ -63,6 +64,7 @@ use crate::{
     amd::{verify_attestation_report_signature, verify_cert_signature},
     claims::{get_digest, parse_endorsement_statement},
     endorsement::verify_binary_endorsement,
+    intel::{verify_quote, TdQuote},
     util::{
         hash_sha2_256, is_hex_digest_match, raw_digest_from_contents, raw_to_hex_digest,
         MatchResult,
@@ -94,6 +96,44 @@ pub fn to_attestation_results(
     }
 }
 
+/// Verifies entire setup and returns a report of the checks that were made,
+/// instead of only the outcome.
+pub fn verify_report(
+    now_utc_millis: i64,
+    evidence: &Evidence,
+    endorsements: &Endorsements,
+    reference_values: &ReferenceValues,
+) -> VerificationReport {
+    let mut report =
+        VerificationReport { verification_time_utc_millis: now_utc_millis, ..Default::default() };
+    match verify_recording(now_utc_millis, evidence, endorsements, reference_values, &mut report) {
+        Ok(_) => report.status = Status::Success.into(),
+        Err(err) => {
+            report.status = Status::GenericFailure.into();
+            report.reason = format!("{:#?}", err);
+        }
+    }
+    report
+}
+
+/// Verifies the evidence captured in `bundle` again, as if at
+/// `now_utc_millis`: e.g. at the time it was captured, to audit a past session,
+/// or at the current time, to check whether it would still be accepted.
+pub fn verify_bundle(now_utc_millis: i64, bundle: &AttestationBundle) -> VerificationReport {
+    match (bundle.evidence.as_ref(), bundle.endorsements.as_ref(), bundle.reference_values.as_ref())
+    {
+        (Some(evidence), Some(endorsements), Some(reference_values)) => {
+            verify_report(now_utc_millis, evidence, endorsements, reference_values)
+        }
+        _ => VerificationReport {
+            status: Status::GenericFailure.into(),
+            reason: "incomplete attestation bundle".into(),
+            verification_time_utc_millis: now_utc_millis,
+            ..Default::default()
+        },
+    }
+}
+
 /// Verifies entire setup by forwarding to individual setup types.
 /// The `now_utc_millis` argument will be changed to a time type as work
 /// progresses.
@@ -102,40 +142,67 @@ pub fn verify(
     evidence: &Evidence,
     endorsements: &Endorsements,
     reference_values: &ReferenceValues,
+) -> anyhow::Result<ExtractedEvidence> {
+    verify_recording(
+        now_utc_millis,
+        evidence,
+        endorsements,
+        reference_values,
+        &mut VerificationReport::default(),
+    )
+}
+
+/// Verifies entire setup like [`verify`], recording the checks that were made
+/// and the extracted evidence values in `report`.
+fn verify_recording(
+    now_utc_millis: i64,
+    evidence: &Evidence,
+    endorsements: &Endorsements,
+    reference_values: &ReferenceValues,
+    report: &mut VerificationReport,
 ) -> anyhow::Result<ExtractedEvidence> {
     // Ensure the Attestation report is properly signed by the platform and that it
     // includes the root public key used in the DICE chain.
     {
-        let tee_certificate = match endorsements.r#type.as_ref().context("no endorsements")? {
-            endorsements::Type::OakRestrictedKernel(endorsements) => endorsements
-                .root_layer
-                .as_ref()
-                .context("no root layer endorsements")?
-                .tee_certificate
-                .as_ref(),
-            endorsements::Type::OakContainers(endorsements) => endorsements
-                .root_layer
-                .as_ref()
-                .context("no root layer endorsements")?
-                .tee_certificate
-                .as_ref(),
-            endorsements::Type::Cb(endorsements) => endorsements
-                .root_layer
-                .as_ref()
-                .context("no root layer endorsements")?
-                .tee_certificate
-                .as_ref(),
+        let root_layer_endorsements =
+            match endorsements.r#type.as_ref().context("no endorsements")? {
+                endorsements::Type::OakRestrictedKernel(endorsements) => {
+                    endorsements.root_layer.as_ref()
+                }
+                endorsements::Type::OakContainers(endorsements) => endorsements.root_layer.as_ref(),
+                endorsements::Type::Cb(endorsements) => endorsements.root_layer.as_ref(),
+            }
+            .context("no root layer endorsements")?;
+        let root_layer_reference_values = match reference_values.r#type.as_ref() {
+            Some(reference_values::Type::OakRestrictedKernel(rvs)) => rvs.root_layer.as_ref(),
+            Some(reference_values::Type::OakContainers(rvs)) => rvs.root_layer.as_ref(),
+            Some(reference_values::Type::Cb(rvs)) => rvs.root_layer.as_ref(),
+            None => None,
         };
         let root_layer = evidence.root_layer.as_ref().context("no root layer evidence")?;
-        verify_root_attestation_signature(now_utc_millis, root_layer, tee_certificate)?;
+        record_check(
+            &mut report.checks,
+            "root attestation signature",
+            verify_root_attestation_signature(
+                now_utc_millis,
+                root_layer,
+                root_layer_endorsements,
+                root_layer_reference_values,
+            ),
+        )?;
     };
 
     // Ensure the DICE chain signatures are valid and extract the measurements,
     // public keys and other attestation-related data from the DICE chain.
-    let extracted_evidence = verify_dice_chain(evidence).context("invalid DICE chain")?;
-
-    // Ensure the extracted measurements match the endorsements.
-    match (
+    let mut extracted_evidence =
+        record_check(&mut report.checks, "DICE chain", verify_dice_chain(evidence))
+            .context("invalid DICE chain")?;
+
+    // Ensure the extracted measurements match the endorsements, collecting the
+    // transparency log entries of the endorsements along the way.
+    let mut log_entries = Vec::new();
+    let checks = &mut report.checks;
+    let result = match (
         endorsements.r#type.as_ref(),
         reference_values.r#type.as_ref(),
         extracted_evidence.evidence_values.as_ref(),
@@ -144,29 +211,53 @@ pub fn verify(
             Some(endorsements::Type::OakRestrictedKernel(ends)),
             Some(reference_values::Type::OakRestrictedKernel(rvs)),
             Some(EvidenceValues::OakRestrictedKernel(values)),
-        ) => verify_oak_restricted_kernel(now_utc_millis, values, ends, rvs),
+        ) => verify_oak_restricted_kernel(
+            now_utc_millis,
+            values,
+            ends,
+            rvs,
+            &mut log_entries,
+            checks,
+        ),
         (
             Some(endorsements::Type::OakContainers(ends)),
             Some(reference_values::Type::OakContainers(rvs)),
             Some(EvidenceValues::OakContainers(values)),
-        ) => verify_oak_containers(now_utc_millis, values, ends, rvs),
+        ) => verify_oak_containers(now_utc_millis, values, ends, rvs, &mut log_entries, checks),
         (
             Some(endorsements::Type::Cb(ends)),
             Some(reference_values::Type::Cb(rvs)),
             Some(EvidenceValues::Cb(values)),
-        ) => verify_cb(now_utc_millis, values, ends, rvs),
+        ) => verify_cb(now_utc_millis, values, ends, rvs, &mut log_entries, checks),
         // Evidence, endorsements and reference values must exist and reflect the same chain type.
-        (None, _, _) => anyhow::bail!("Endorsements are empty"),
-        (_, None, _) => anyhow::bail!("Reference values are empty"),
-        (_, _, None) => anyhow::bail!("Extracted evidence values are empty"),
+        (None, _, _) => Err(anyhow::anyhow!("Endorsements are empty")),
+        (_, None, _) => Err(anyhow::anyhow!("Reference values are empty")),
+        (_, _, None) => Err(anyhow::anyhow!("Extracted evidence values are empty")),
         (Some(_), Some(_), Some(_)) => {
-            anyhow::bail!("Mismatch between evidence, endorsements and reference values")
+            Err(anyhow::anyhow!("Mismatch between evidence, endorsements and reference values"))
         }
-    }?;
+    };
+    extracted_evidence.transparency_log_entries = log_entries;
+    report.extracted_evidence = Some(extracted_evidence.clone());
+    result?;
 
     Ok(extracted_evidence)
 }
 
+/// Records the outcome of a check in `checks`, and returns it.
+fn record_check<T>(
+    checks: &mut Vec<VerificationCheck>,
+    name: &str,
+    result: anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    checks.push(VerificationCheck {
+        name: name.into(),
+        passed: result.is_ok(),
+        reason: result.as_ref().err().map(|err| format!("{:#}", err)).unwrap_or_default(),
+    });
+    result
+}
+
 /// Verifies signatures of the certificates in the DICE chain and extracts the
 /// evidence values from the certificates if the verification is successful.
 pub fn verify_dice_chain(evidence: &Evidence) -> anyhow::Result<ExtractedEvidence> {
@@ -244,31 +335,48 @@ fn verify_oak_restricted_kernel(
     values: &OakRestrictedKernelData,
     endorsements: &OakRestrictedKernelEndorsements,
     reference_values: &OakRestrictedKernelReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
+    checks: &mut Vec<VerificationCheck>,
 ) -> anyhow::Result<()> {
-    verify_root_layer(
-        now_utc_millis,
-        values.root_layer.as_ref().context("no root layer evidence values")?,
-        endorsements.root_layer.as_ref(),
-        reference_values.root_layer.as_ref().context("no root layer reference values")?,
+    record_check(
+        checks,
+        "root layer",
+        verify_root_layer(
+            now_utc_millis,
+            values.root_layer.as_ref().context("no root layer evidence values")?,
+            endorsements.root_layer.as_ref(),
+            reference_values.root_layer.as_ref().context("no root layer reference values")?,
+            log_entries,
+        ),
     )
     .context("root layer verification failed")?;
 
-    verify_kernel_layer(
-        now_utc_millis,
-        values.kernel_layer.as_ref().context("no kernel layer evidence values")?,
-        endorsements.kernel_layer.as_ref(),
-        reference_values.kernel_layer.as_ref().context("no kernel layer reference values")?,
+    record_check(
+        checks,
+        "kernel layer",
+        verify_kernel_layer(
+            now_utc_millis,
+            values.kernel_layer.as_ref().context("no kernel layer evidence values")?,
+            endorsements.kernel_layer.as_ref(),
+            reference_values.kernel_layer.as_ref().context("no kernel layer reference values")?,
+            log_entries,
+        ),
     )
     .context("kernel layer verification failed")?;
 
-    verify_application_layer(
-        now_utc_millis,
-        values.application_layer.as_ref().context("no applications layer evidence values")?,
-        endorsements.application_layer.as_ref(),
-        reference_values
-            .application_layer
-            .as_ref()
-            .context("no application layer reference values")?,
+    record_check(
+        checks,
+        "application layer",
+        verify_application_layer(
+            now_utc_millis,
+            values.application_layer.as_ref().context("no applications layer evidence values")?,
+            endorsements.application_layer.as_ref(),
+            reference_values
+                .application_layer
+                .as_ref()
+                .context("no application layer reference values")?,
+            log_entries,
+        ),
     )
     .context("application layer verification failed")
 }
@@ -280,36 +388,61 @@ fn verify_oak_containers(
     values: &OakContainersData,
     endorsements: &OakContainersEndorsements,
     reference_values: &OakContainersReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
+    checks: &mut Vec<VerificationCheck>,
 ) -> anyhow::Result<()> {
-    verify_root_layer(
-        now_utc_millis,
-        values.root_layer.as_ref().context("no root layer evidence values")?,
-        endorsements.root_layer.as_ref(),
-        reference_values.root_layer.as_ref().context("no root layer reference values")?,
+    record_check(
+        checks,
+        "root layer",
+        verify_root_layer(
+            now_utc_millis,
+            values.root_layer.as_ref().context("no root layer evidence values")?,
+            endorsements.root_layer.as_ref(),
+            reference_values.root_layer.as_ref().context("no root layer reference values")?,
+            log_entries,
+        ),
     )
     .context("root layer verification failed")?;
 
-    verify_kernel_layer(
-        now_utc_millis,
-        values.kernel_layer.as_ref().context("no kernel layer evidence values")?,
-        endorsements.kernel_layer.as_ref(),
-        reference_values.kernel_layer.as_ref().context("no kernel layer reference values")?,
+    record_check(
+        checks,
+        "kernel layer",
+        verify_kernel_layer(
+            now_utc_millis,
+            values.kernel_layer.as_ref().context("no kernel layer evidence values")?,
+            endorsements.kernel_layer.as_ref(),
+            reference_values.kernel_layer.as_ref().context("no kernel layer reference values")?,
+            log_entries,
+        ),
     )
     .context("kernel layer verification failed")?;
 
-    verify_system_layer(
-        now_utc_millis,
-        values.system_layer.as_ref().context("no system layer evidence values")?,
-        endorsements.system_layer.as_ref(),
-        reference_values.system_layer.as_ref().context("no system layer reference values")?,
+    record_check(
+        checks,
+        "system layer",
+        verify_system_layer(
+            now_utc_millis,
+            values.system_layer.as_ref().context("no system layer evidence values")?,
+            endorsements.system_layer.as_ref(),
+            reference_values.system_layer.as_ref().context("no system layer reference values")?,
+            log_entries,
+        ),
     )
     .context("system layer verification failed")?;
 
-    verify_container_layer(
-        now_utc_millis,
-        values.container_layer.as_ref().context("no container layer evidence values")?,
-        endorsements.container_layer.as_ref(),
-        reference_values.container_layer.as_ref().context("no container layer reference values")?,
+    record_check(
+        checks,
+        "container layer",
+        verify_container_layer(
+            now_utc_millis,
+            values.container_layer.as_ref().context("no container layer evidence values")?,
+            endorsements.container_layer.as_ref(),
+            reference_values
+                .container_layer
+                .as_ref()
+                .context("no container layer reference values")?,
+            log_entries,
+        ),
     )
     .context("container layer verification failed")
 }
@@ -321,12 +454,19 @@ fn verify_cb(
     values: &CbData,
     endorsements: &CbEndorsements,
     reference_values: &CbReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
+    checks: &mut Vec<VerificationCheck>,
 ) -> anyhow::Result<()> {
-    verify_root_layer(
-        now_utc_millis,
-        values.root_layer.as_ref().context("no root layer evidence values")?,
-        endorsements.root_layer.as_ref(),
-        reference_values.root_layer.as_ref().context("no root layer reference values")?,
+    record_check(
+        checks,
+        "root layer",
+        verify_root_layer(
+            now_utc_millis,
+            values.root_layer.as_ref().context("no root layer evidence values")?,
+            endorsements.root_layer.as_ref(),
+            reference_values.root_layer.as_ref().context("no root layer reference values")?,
+            log_entries,
+        ),
     )
     .context("root layer verification failed")?;
 
@@ -394,10 +534,14 @@ fn verify_amd_sev_attestation_report(
 
 /// Verifies the Intel TDX attestation report.
 fn verify_intel_tdx_attestation_report(
-    _attestation_report_values: &IntelTdxAttestationReport,
-    _reference_values: &IntelTdxReferenceValues,
+    attestation_report_values: &IntelTdxAttestationReport,
+    reference_values: &IntelTdxReferenceValues,
 ) -> anyhow::Result<()> {
-    anyhow::bail!("needs implementation")
+    if !reference_values.allow_debug && attestation_report_values.debug {
+        anyhow::bail!("debug mode not allowed");
+    }
+
+    Ok(())
 }
 
 /// Verifies insecure attestation.
@@ -407,16 +551,17 @@ fn verify_insecure(_reference_values: &InsecureReferenceValues) -> anyhow::Resul
 
 /// Verifies the signature chain for the attestation report included in the
 /// root.
-fn verify_root_attestation_signature(
-    _now_utc_millis: i64,
+pub(crate) fn verify_root_attestation_signature(
+    now_utc_millis: i64,
     root_layer: &RootLayerEvidence,
-    serialized_certificate: &[u8],
+    endorsements: &RootLayerEndorsements,
+    reference_values: Option<&RootLayerReferenceValues>,
 ) -> anyhow::Result<()> {
     match root_layer.platform() {
         TeePlatform::Unspecified => anyhow::bail!("unspecified TEE platform"),
         TeePlatform::AmdSevSnp => {
             // We demand that product-specific ASK signs the VCEK.
-            let vcek = Certificate::from_der(serialized_certificate)
+            let vcek = Certificate::from_der(&endorsements.tee_certificate)
                 .map_err(|_err| anyhow::anyhow!("could not parse VCEK cert"))?;
             // Right now there are only Milan CPUs, so it is not urgent to code the
             // decision between Milan and Genoa which would appear here.
@@ -447,7 +592,33 @@ fn verify_root_attestation_signature(
 
             Ok(())
         }
-        TeePlatform::IntelTdx => anyhow::bail!("not supported"),
+        TeePlatform::IntelTdx => {
+            // Unlike the AMD root key, the Intel root CA certificate is provided by the
+            // client, so TDX quotes can only be verified when there are reference values
+            // for them.
+            let intel_tdx_values = match reference_values {
+                Some(RootLayerReferenceValues { intel_tdx: Some(intel_tdx_values), .. }) => {
+                    intel_tdx_values
+                }
+                Some(RootLayerReferenceValues { insecure: Some(_), .. }) => return Ok(()),
+                _ => anyhow::bail!("no Intel TDX reference values"),
+            };
+            let collateral =
+                endorsements.tdx_collateral.as_ref().context("no Intel TDX collateral")?;
+            let quote = TdQuote::parse(&root_layer.remote_attestation_report)
+                .context("invalid Intel TDX quote")?;
+            verify_quote(now_utc_millis, &quote, collateral, intel_tdx_values)?;
+
+            // Check that the root ECA public key for the DICE chain is bound to the
+            // quote to ensure that the entire chain is valid.
+            let expected = &hash_sha2_256(&root_layer.eca_public_key[..])[..];
+            anyhow::ensure!(
+                expected == &quote.body.report_data[..expected.len()],
+                "The root layer's ECA public key is not bound to the TDX quote"
+            );
+
+            Ok(())
+        }
         TeePlatform::None => Ok(()),
     }
 }
@@ -459,6 +630,7 @@ fn verify_root_layer(
     values: &RootLayerData,
     endorsements: Option<&RootLayerEndorsements>,
     reference_values: &RootLayerReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     match (
         values.report.as_ref(),
@@ -482,10 +654,24 @@ fn verify_root_layer(
                     .stage0
                     .as_ref()
                     .context("stage0 binary reference values not found")?,
+                log_entries,
             )?;
             verify_amd_sev_attestation_report(report_values, amd_sev_values)
         }
         (Some(Report::Tdx(report_values)), _, Some(intel_tdx_values), _) => {
+            // MRTD covers the initial memory of the TD, which contains stage0.
+            let measurement =
+                RawDigest { sha2_384: report_values.mr_td.to_vec(), ..Default::default() };
+            verify_measurement_digest(
+                &measurement,
+                now_utc_millis,
+                endorsements.and_then(|value| value.stage0.as_ref()),
+                intel_tdx_values
+                    .stage0
+                    .as_ref()
+                    .context("stage0 binary reference values not found")?,
+                log_entries,
+            )?;
             verify_intel_tdx_attestation_report(report_values, intel_tdx_values)
         }
         (_, _, _, Some(insecure_values)) => {
@@ -508,6 +694,7 @@ fn verify_kernel_layer(
     values: &KernelLayerData,
     endorsements: Option<&KernelLayerEndorsements>,
     reference_values: &KernelLayerReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     verify_kernel_measurement_digest(
         values.kernel_image.as_ref().context("no kernel evidence value")?,
@@ -515,6 +702,7 @@ fn verify_kernel_layer(
         now_utc_millis,
         endorsements.and_then(|value| value.kernel.as_ref()),
         reference_values.kernel.as_ref().context("no kernel reference value")?,
+        log_entries,
     )
     .context("kernel failed verification")?;
 
@@ -550,6 +738,7 @@ fn verify_kernel_layer(
         now_utc_millis,
         endorsements.and_then(|value| value.init_ram_fs.as_ref()),
         reference_values.init_ram_fs.as_ref().context("no initial RAM disk reference value")?,
+        log_entries,
     )
     .context("initial RAM disk failed verification")?;
 
@@ -558,6 +747,7 @@ fn verify_kernel_layer(
         now_utc_millis,
         endorsements.and_then(|value| value.memory_map.as_ref()),
         reference_values.memory_map.as_ref().context("no memory map reference value")?,
+        log_entries,
     )
     .context("memory map failed verification")?;
 
@@ -566,6 +756,7 @@ fn verify_kernel_layer(
         now_utc_millis,
         endorsements.and_then(|value| value.acpi.as_ref()),
         reference_values.acpi.as_ref().context("no ACPI reference value")?,
+        log_entries,
     )
     .context("ACPI table building commands failed verification")
 }
@@ -577,12 +768,14 @@ fn verify_system_layer(
     values: &SystemLayerData,
     endorsements: Option<&SystemLayerEndorsements>,
     reference_values: &SystemLayerReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     verify_measurement_digest(
         values.system_image.as_ref().context("no system image evidence value")?,
         now_utc_millis,
         endorsements.and_then(|value| value.system_image.as_ref()),
         reference_values.system_image.as_ref().context("no system image reference value")?,
+        log_entries,
     )
     .context("system image failed verification")
 }
@@ -594,12 +787,14 @@ fn verify_application_layer(
     values: &ApplicationLayerData,
     endorsements: Option<&ApplicationLayerEndorsements>,
     reference_values: &ApplicationLayerReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     verify_measurement_digest(
         values.binary.as_ref().context("no binary evidence value")?,
         now_utc_millis,
         endorsements.and_then(|value| value.binary.as_ref()),
         reference_values.binary.as_ref().context("application binary reference value")?,
+        log_entries,
     )
     .context("application binary failed verification")?;
 
@@ -608,6 +803,7 @@ fn verify_application_layer(
         now_utc_millis,
         endorsements.and_then(|value| value.configuration.as_ref()),
         reference_values.configuration.as_ref().context("no configuration reference value")?,
+        log_entries,
     )
     .context("configuration failed verification")
 }
@@ -618,12 +814,14 @@ fn verify_container_layer(
     values: &ContainerLayerData,
     endorsements: Option<&ContainerLayerEndorsements>,
     reference_values: &ContainerLayerReferenceValues,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     verify_measurement_digest(
         values.bundle.as_ref().context("no bundle evidence value")?,
         now_utc_millis,
         endorsements.and_then(|value| value.binary.as_ref()),
         reference_values.binary.as_ref().context("container bundle reference value")?,
+        log_entries,
     )
     .context("container bundle failed verification")?;
 
@@ -632,6 +830,7 @@ fn verify_container_layer(
         now_utc_millis,
         endorsements.and_then(|value| value.configuration.as_ref()),
         reference_values.configuration.as_ref().context("no configuration reference value")?,
+        log_entries,
     )
     .context("configuration failed verification")
 }
@@ -643,6 +842,7 @@ fn verify_measurement_digest(
     now_utc_millis: i64,
     endorsement: Option<&TransparentReleaseEndorsement>,
     reference_value: &BinaryReferenceValue,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     let actual = raw_to_hex_digest(measurement);
     match reference_value.r#type.as_ref() {
@@ -650,7 +850,7 @@ fn verify_measurement_digest(
         Some(binary_reference_value::Type::Endorsement(public_keys)) => {
             let endorsement =
                 endorsement.context("matching endorsement not found for reference value")?;
-            verify_binary_endorsement(
+            let log_index = verify_binary_endorsement(
                 now_utc_millis,
                 &endorsement.endorsement,
                 &endorsement.endorsement_signature,
@@ -659,7 +859,11 @@ fn verify_measurement_digest(
                 &public_keys.rekor_public_key,
             )?;
             let expected = get_digest(&parse_endorsement_statement(&endorsement.endorsement)?)?;
-            verify_hex_digests(&actual, &expected)
+            verify_hex_digests(&actual, &expected)?;
+            if let Some(log_index) = log_index {
+                log_entries.push(TransparencyLogEntry { binary_digest: Some(expected), log_index });
+            }
+            Ok(())
         }
         Some(binary_reference_value::Type::Digests(expected_digests)) => {
             if expected_digests.digests.iter().any(|expected_digest| {
@@ -682,6 +886,7 @@ fn verify_kernel_measurement_digest(
     now_utc_millis: i64,
     endorsement: Option<&TransparentReleaseEndorsement>,
     reference_value: &KernelBinaryReferenceValue,
+    log_entries: &mut Vec<TransparencyLogEntry>,
 ) -> anyhow::Result<()> {
     let actual_image = raw_to_hex_digest(image_measurement);
     let actual_setup_data = raw_to_hex_digest(setup_data_measurement);
@@ -690,7 +895,7 @@ fn verify_kernel_measurement_digest(
         Some(kernel_binary_reference_value::Type::Endorsement(public_keys)) => {
             let endorsement =
                 endorsement.context("matching endorsement not found for reference value")?;
-            verify_binary_endorsement(
+            let log_index = verify_binary_endorsement(
                 now_utc_millis,
                 &endorsement.endorsement,
                 &endorsement.endorsement_signature,
@@ -717,7 +922,12 @@ fn verify_kernel_measurement_digest(
                 .setup_data
                 .ok_or_else(|| anyhow::anyhow!("no setup data digest in kernel attachment"))?;
             verify_hex_digests(&actual_image, &expected_image)?;
-            verify_hex_digests(&actual_setup_data, &expected_setup_data)
+            verify_hex_digests(&actual_setup_data, &expected_setup_data)?;
+            if let Some(log_index) = log_index {
+                log_entries
+                    .push(TransparencyLogEntry { binary_digest: Some(expected_digest), log_index });
+            }
+            Ok(())
         }
         Some(kernel_binary_reference_value::Type::Digests(expected_digests)) => {
             if !expected_digests
@@ -817,7 +1027,12 @@ fn extract_evidence(evidence: &Evidence) -> anyhow::Result<ExtractedEvidence> {
         )
         .context("couldn't extract application key values")?;
 
-    Ok(ExtractedEvidence { evidence_values, encryption_public_key, signing_public_key })
+    Ok(ExtractedEvidence {
+        evidence_values,
+        encryption_public_key,
+        signing_public_key,
+        ..Default::default()
+    })
 }
 
 /// Extracts the measurements and other attestation-related values from the
@@ -931,7 +1146,22 @@ fn extract_root_values(root_layer: &RootLayerEvidence) -> anyhow::Result<RootLay
                 })),
             })
         }
-        TeePlatform::IntelTdx => Err(anyhow::anyhow!("not supported")),
+        TeePlatform::IntelTdx => {
+            let quote = TdQuote::parse(&root_layer.remote_attestation_report)
+                .context("invalid Intel TDX quote")?;
+            let body = &quote.body;
+
+            Ok(RootLayerData {
+                report: Some(Report::Tdx(IntelTdxAttestationReport {
+                    report_data: body.report_data.to_vec(),
+                    mr_td: body.mr_td.to_vec(),
+                    rtmrs: body.rtmrs.iter().map(|rtmr| rtmr.to_vec()).collect(),
+                    mr_seam: body.mr_seam.to_vec(),
+                    tee_tcb_svn: body.tee_tcb_svn.to_vec(),
+                    debug: body.is_debug(),
+                })),
+            })
+        }
         TeePlatform::None => {
             // We use an unsigned, mostly empty AMD SEV-SNP attestation report as a fake
             // when not running in a TEE.
@@ -25,7 +25,7 @@ use oak_attestation_verification::{
         verify_binary_digest, verify_binary_endorsement, verify_endorsement_statement,
         verify_endorser_public_key,
     },
-    rekor::{verify_rekor_log_entry, verify_rekor_signature},
+    rekor::{verify_rekor_inclusion_proof, verify_rekor_log_entry, verify_rekor_signature},
     util::{convert_pem_to_raw, MatchResult},
 };
 use oak_proto_rust::oak::HexDigest;
@@ -42,6 +42,9 @@ const LOG_ENTRY_PATH: &str = "testdata/logentry.json";
 // from https://rekor.sigstore.dev/api/v1/log/publicKey.
 const REKOR_PUBLIC_KEY_PATH: &str = "testdata/rekor_public_key.pem";
 
+// The index of the entry under LOG_ENTRY_PATH in Rekor.
+const LOG_INDEX: u64 = 74497915;
+
 // Pretend the tests run at this time: 1 March 2024, 12:00 UTC
 const NOW_UTC_MILLIS: i64 = 1709294400000;
 
@@ -115,6 +118,32 @@ fn test_verify_rekor_signature() {
     assert!(result.is_ok());
 }
 
+#[test]
+fn test_verify_rekor_inclusion_proof() {
+    let testdata = load_testdata();
+    let result = verify_rekor_inclusion_proof(&testdata.log_entry, &testdata.rekor_public_key);
+    assert!(result.is_ok(), "{:?}", result);
+}
+
+#[test]
+fn test_verify_rekor_inclusion_proof_fails_with_modified_audit_path() {
+    let testdata = load_testdata();
+    let log_entry = String::from_utf8(testdata.log_entry)
+        .expect("log entry isn't UTF-8")
+        .replace("bbf175fe7b34de22", "bbf175fe7b34de23");
+
+    let result = verify_rekor_inclusion_proof(log_entry.as_bytes(), &testdata.rekor_public_key);
+    assert!(result.is_err(), "{:?}", result);
+}
+
+#[test]
+fn test_verify_rekor_inclusion_proof_fails_with_invalid_rekor_public_key() {
+    let testdata = load_testdata();
+    // NB: We use the wrong key deliberately.
+    let result = verify_rekor_inclusion_proof(&testdata.log_entry, &testdata.endorser_public_key);
+    assert!(result.is_err(), "{:?}", result);
+}
+
 #[test]
 fn test_verify_rekor_log_entry() {
     let testdata = load_testdata();
@@ -124,7 +153,7 @@ fn test_verify_rekor_log_entry() {
         &testdata.rekor_public_key,
         &testdata.endorsement,
     );
-    assert!(result.is_ok(), "{:?}", result);
+    assert!(result.as_ref().is_ok_and(|log_index| *log_index == LOG_INDEX), "{:?}", result);
 }
 
 #[test]
@@ -156,7 +185,7 @@ fn test_verify_binary_endorsement() {
         &testdata.endorser_public_key,
         &testdata.rekor_public_key,
     );
-    assert!(result.is_ok(), "{:?}", result);
+    assert!(result.as_ref().is_ok_and(|log_index| *log_index == Some(LOG_INDEX)), "{:?}", result);
 }
 
 #[test]
@@ -0,0 +1,175 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::fs;
+
+use oak_attestation_verification::intel::{get_fmspc, verify_quote, TdQuote};
+use oak_proto_rust::oak::attestation::v1::{IntelTdxCollateral, IntelTdxReferenceValues};
+
+// The quote and the collateral are synthetic: all certificates chain to a test
+// root CA rather than to the Intel SGX root CA, and the platform is at a TCB
+// level with the "OutOfDate" status.
+const QUOTE_PATH: &str = "testdata/tdx_quote.bin";
+const ROOT_CA_PATH: &str = "testdata/tdx_root_ca.der";
+const TCB_INFO_PATH: &str = "testdata/tdx_tcb_info.json";
+const QE_IDENTITY_PATH: &str = "testdata/tdx_qe_identity.json";
+const TCB_SIGNING_CHAIN_PATH: &str = "testdata/tdx_tcb_signing_chain.pem";
+// TCB info signed by the PCK certificate of the quote, and its chain.
+const PCK_SIGNED_TCB_INFO_PATH: &str = "testdata/tdx_tcb_info_pck_signed.json";
+const PCK_CHAIN_PATH: &str = "testdata/tdx_pck_chain.pem";
+// TCB info signed by a certificate named like the TCB signing certificate, but
+// issued by the PCK certificate, which is not a CA.
+const LEAF_ISSUED_TCB_INFO_PATH: &str = "testdata/tdx_tcb_info_leaf_issued.json";
+const LEAF_ISSUED_CHAIN_PATH: &str = "testdata/tdx_leaf_issued_chain.pem";
+
+// The FMSPC in the PCK certificate of the quote.
+const FMSPC: &str = "90c06f000000";
+
+// Pretend the tests run at this time: 1 March 2024, 12:00 UTC
+const NOW_UTC_MILLIS: i64 = 1709294400000;
+
+// The collateral must be updated by 1 April 2024, 00:00 UTC.
+const NEXT_UPDATE_UTC_MILLIS: i64 = 1711929600000;
+
+fn create_collateral() -> IntelTdxCollateral {
+    let tcb_signing_chain =
+        fs::read(TCB_SIGNING_CHAIN_PATH).expect("couldn't read TCB signing chain");
+    IntelTdxCollateral {
+        tcb_info: fs::read(TCB_INFO_PATH).expect("couldn't read TCB info"),
+        tcb_info_issuer_chain: tcb_signing_chain.clone(),
+        qe_identity: fs::read(QE_IDENTITY_PATH).expect("couldn't read QE identity"),
+        qe_identity_issuer_chain: tcb_signing_chain,
+    }
+}
+
+fn create_reference_values() -> IntelTdxReferenceValues {
+    IntelTdxReferenceValues {
+        root_ca_certificate: fs::read(ROOT_CA_PATH).expect("couldn't read root CA cert"),
+        accepted_tcb_statuses: vec!["OutOfDate".to_owned()],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_parse_quote() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+
+    assert_eq!(quote.body.mr_td, [0x7d; 48]);
+    assert_eq!(quote.body.rtmrs, [[1; 48], [2; 48], [3; 48], [4; 48]]);
+    assert!(!quote.body.is_debug());
+    assert_eq!(hex::encode(get_fmspc(&quote).expect("couldn't get FMSPC")), FMSPC);
+}
+
+#[test]
+fn test_parse_truncated_quote_fails() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    assert!(TdQuote::parse(&quote[..quote.len() - 1]).is_err());
+}
+
+#[test]
+fn test_verify_quote_succeeds() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+
+    let result =
+        verify_quote(NOW_UTC_MILLIS, &quote, &create_collateral(), &create_reference_values());
+    assert!(result.is_ok(), "{:?}", result);
+}
+
+#[test]
+fn test_verify_modified_quote_fails() {
+    let mut quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    // Modify the first byte of MRTD.
+    quote[48 + 136] ^= 1;
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+
+    let result =
+        verify_quote(NOW_UTC_MILLIS, &quote, &create_collateral(), &create_reference_values());
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_verify_unaccepted_tcb_status_fails() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+    let reference_values =
+        IntelTdxReferenceValues { accepted_tcb_statuses: vec![], ..create_reference_values() };
+
+    let result = verify_quote(NOW_UTC_MILLIS, &quote, &create_collateral(), &reference_values);
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_verify_modified_tcb_info_fails() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+    let mut collateral = create_collateral();
+    collateral.tcb_info = String::from_utf8(collateral.tcb_info)
+        .expect("invalid TCB info")
+        .replace("OutOfDate", "UpToDate")
+        .into_bytes();
+
+    let result = verify_quote(NOW_UTC_MILLIS, &quote, &collateral, &create_reference_values());
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_verify_outdated_collateral_fails() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+
+    let result = verify_quote(
+        NEXT_UPDATE_UTC_MILLIS + 1,
+        &quote,
+        &create_collateral(),
+        &create_reference_values(),
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_verify_pck_signed_tcb_info_fails() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+    let collateral = IntelTdxCollateral {
+        tcb_info: fs::read(PCK_SIGNED_TCB_INFO_PATH).expect("couldn't read TCB info"),
+        tcb_info_issuer_chain: fs::read(PCK_CHAIN_PATH).expect("couldn't read PCK chain"),
+        ..create_collateral()
+    };
+
+    let result = verify_quote(NOW_UTC_MILLIS, &quote, &collateral, &create_reference_values());
+    assert!(result.is_err());
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.contains("not signed by the TCB signing certificate"), "{}", error);
+}
+
+#[test]
+fn test_verify_tcb_info_signed_by_leaf_issued_cert_fails() {
+    let quote = fs::read(QUOTE_PATH).expect("couldn't read quote");
+    let quote = TdQuote::parse(&quote).expect("couldn't parse quote");
+    let collateral = IntelTdxCollateral {
+        tcb_info: fs::read(LEAF_ISSUED_TCB_INFO_PATH).expect("couldn't read TCB info"),
+        tcb_info_issuer_chain: fs::read(LEAF_ISSUED_CHAIN_PATH)
+            .expect("couldn't read issuer chain"),
+        ..create_collateral()
+    };
+
+    let result = verify_quote(NOW_UTC_MILLIS, &quote, &collateral, &create_reference_values());
+    assert!(result.is_err());
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.contains("certificate is not a CA"), "{}", error);
+}
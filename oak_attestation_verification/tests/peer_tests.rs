@@ -0,0 +1,149 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::fs;
+
+use oak_attestation_verification::peer::verify_peer_attestation;
+use oak_proto_rust::oak::attestation::v1::{
+    AppraisalPolicy, Evidence, PeerAttestation, RootLayerEndorsements, TeePlatform,
+};
+use prost::Message;
+
+const CONTAINERS_EVIDENCE_PATH: &str = "testdata/oc_evidence.binarypb";
+const CONTAINERS_VCEK_MILAN_CERT_DER: &str = "testdata/oc_vcek_milan.der";
+const FAKE_EVIDENCE_PATH: &str = "testdata/fake_evidence.binarypb";
+
+// Pretend this is the current time.
+const NOW_UTC_MILLIS: i64 = 1698829200000;
+
+const ENCAPSULATED_PUBLIC_KEY: &[u8] = b"encapsulated public key";
+
+fn read_evidence(path: &str) -> Evidence {
+    let serialized = fs::read(path).expect("could not read evidence");
+    Evidence::decode(serialized.as_slice()).expect("could not decode evidence")
+}
+
+fn create_attestation(signature: Vec<u8>) -> PeerAttestation {
+    let tee_certificate = fs::read(CONTAINERS_VCEK_MILAN_CERT_DER).expect("could not read VCEK");
+    PeerAttestation {
+        evidence: Some(read_evidence(CONTAINERS_EVIDENCE_PATH)),
+        encapsulated_public_key_signature: signature,
+        root_layer_endorsements: Some(RootLayerEndorsements {
+            tee_certificate,
+            ..Default::default()
+        }),
+    }
+}
+
+#[test]
+fn verify_peer_attestation_fails_without_evidence() {
+    let attestation = PeerAttestation { evidence: None, ..Default::default() };
+
+    let result = verify_peer_attestation(
+        NOW_UTC_MILLIS,
+        &AppraisalPolicy::default(),
+        &attestation,
+        ENCAPSULATED_PUBLIC_KEY,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn verify_peer_attestation_fails_with_invalid_signature() {
+    // A well-formed signature that wasn't created with the evidence's signing key.
+    let attestation = create_attestation(vec![1; 64]);
+
+    let result = verify_peer_attestation(
+        NOW_UTC_MILLIS,
+        &AppraisalPolicy::default(),
+        &attestation,
+        ENCAPSULATED_PUBLIC_KEY,
+    );
+    assert!(result.is_err());
+    assert!(format!("{:#}", result.unwrap_err()).contains("encapsulated key signature"));
+}
+
+#[test]
+fn verify_peer_attestation_fails_with_required_claim_types() {
+    let policy = AppraisalPolicy {
+        required_endorsement_claim_types: vec!["https://example.com/reviewed".to_owned()],
+        ..Default::default()
+    };
+
+    let result = verify_peer_attestation(
+        NOW_UTC_MILLIS,
+        &policy,
+        &create_attestation(vec![1; 64]),
+        ENCAPSULATED_PUBLIC_KEY,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn verify_peer_attestation_fails_without_tee_certificate() {
+    let attestation =
+        PeerAttestation { root_layer_endorsements: None, ..create_attestation(vec![1; 64]) };
+
+    let result = verify_peer_attestation(
+        NOW_UTC_MILLIS,
+        &AppraisalPolicy::default(),
+        &attestation,
+        ENCAPSULATED_PUBLIC_KEY,
+    );
+    assert!(format!("{:#}", result.unwrap_err()).contains("attestation report"));
+}
+
+#[test]
+fn verify_peer_attestation_fails_with_forged_eca_key() {
+    // A DICE chain rooted in an ECA key the peer minted itself, presented with a
+    // genuine attestation report that doesn't bind that key.
+    let mut attestation = create_attestation(vec![1; 64]);
+    let forged_eca_public_key =
+        read_evidence(FAKE_EVIDENCE_PATH).root_layer.expect("no root layer").eca_public_key;
+    attestation
+        .evidence
+        .as_mut()
+        .and_then(|evidence| evidence.root_layer.as_mut())
+        .expect("no root layer")
+        .eca_public_key = forged_eca_public_key;
+
+    let result = verify_peer_attestation(
+        NOW_UTC_MILLIS,
+        &AppraisalPolicy::default(),
+        &attestation,
+        ENCAPSULATED_PUBLIC_KEY,
+    );
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.contains("not bound to the attestation report"), "{}", error);
+}
+
+#[test]
+fn verify_peer_attestation_fails_with_self_signed_chain() {
+    // A DICE chain signed by keys the peer chose, claiming to come from an AMD
+    // SEV-SNP platform without a report signed by AMD.
+    let mut evidence = read_evidence(FAKE_EVIDENCE_PATH);
+    evidence.root_layer.as_mut().expect("no root layer").platform = TeePlatform::AmdSevSnp.into();
+    let attestation = PeerAttestation { evidence: Some(evidence), ..create_attestation(vec![]) };
+
+    let result = verify_peer_attestation(
+        NOW_UTC_MILLIS,
+        &AppraisalPolicy { allow_insecure: true, ..Default::default() },
+        &attestation,
+        ENCAPSULATED_PUBLIC_KEY,
+    );
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.contains("invalid peer attestation report"), "{}", error);
+}
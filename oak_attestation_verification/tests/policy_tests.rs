@@ -0,0 +1,189 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::fs;
+
+use oak_attestation_verification::{
+    policy::{appraise, parse_policy_json},
+    util::raw_to_hex_digest,
+    verifier::verify_dice_chain,
+};
+use oak_proto_rust::oak::{
+    attestation::v1::{
+        endorsements, extracted_evidence::EvidenceValues, AppraisalPolicy, Endorsements, Evidence,
+        ExtractedEvidence, OakContainersEndorsements, RootLayerEndorsements, TcbVersion,
+        TransparentReleaseEndorsement,
+    },
+    HexDigest,
+};
+use prost::Message;
+
+const ENDORSEMENT_PATH: &str = "testdata/endorsement.json";
+const CONTAINERS_EVIDENCE_PATH: &str = "testdata/oc_evidence.binarypb";
+const FAKE_EVIDENCE_PATH: &str = "testdata/fake_evidence.binarypb";
+
+// The claim type of the endorsement in `ENDORSEMENT_PATH`.
+const ENDORSEMENT_CLAIM_TYPE: &str =
+    "https://github.com/project-oak/transparent-release/endorsement/v2";
+
+fn extract_evidence(path: &str) -> ExtractedEvidence {
+    let serialized = fs::read(path).expect("could not read evidence");
+    let evidence = Evidence::decode(serialized.as_slice()).expect("could not decode evidence");
+    verify_dice_chain(&evidence).expect("invalid DICE chain")
+}
+
+// Endorsements with only an endorsement of stage0, which isn't verified by the
+// appraisal.
+fn create_endorsements() -> Endorsements {
+    let endorsement = fs::read(ENDORSEMENT_PATH).expect("couldn't read endorsement");
+    let root_layer = RootLayerEndorsements {
+        stage0: Some(TransparentReleaseEndorsement { endorsement, ..Default::default() }),
+        ..Default::default()
+    };
+    Endorsements {
+        r#type: Some(endorsements::Type::OakContainers(OakContainersEndorsements {
+            root_layer: Some(root_layer),
+            ..Default::default()
+        })),
+    }
+}
+
+fn kernel_digest(extracted_evidence: &ExtractedEvidence) -> HexDigest {
+    match extracted_evidence.evidence_values.as_ref() {
+        Some(EvidenceValues::OakContainers(values)) => raw_to_hex_digest(
+            values
+                .kernel_layer
+                .as_ref()
+                .and_then(|kernel_layer| kernel_layer.kernel_image.as_ref())
+                .expect("no kernel image digest"),
+        ),
+        _ => panic!("unexpected evidence values"),
+    }
+}
+
+#[test]
+fn appraise_succeeds_with_default_policy() {
+    let extracted_evidence = extract_evidence(CONTAINERS_EVIDENCE_PATH);
+
+    let result = appraise(&AppraisalPolicy::default(), &extracted_evidence, &create_endorsements());
+    assert!(result.is_ok(), "{:?}", result);
+}
+
+#[test]
+fn appraise_succeeds_with_matching_policy() {
+    let extracted_evidence = extract_evidence(CONTAINERS_EVIDENCE_PATH);
+    let policy = AppraisalPolicy {
+        allowed_kernel_digests: vec![
+            HexDigest { sha2_256: "00".repeat(32), ..Default::default() },
+            kernel_digest(&extracted_evidence),
+        ],
+        min_amd_sev_snp_tcb: Some(TcbVersion { boot_loader: 0, tee: 0, snp: 0, microcode: 0 }),
+        required_endorsement_claim_types: vec![ENDORSEMENT_CLAIM_TYPE.to_owned()],
+        ..Default::default()
+    };
+
+    let result = appraise(&policy, &extracted_evidence, &create_endorsements());
+    assert!(result.is_ok(), "{:?}", result);
+}
+
+#[test]
+fn appraise_fails_with_disallowed_kernel() {
+    let extracted_evidence = extract_evidence(CONTAINERS_EVIDENCE_PATH);
+    let policy = AppraisalPolicy {
+        allowed_kernel_digests: vec![HexDigest { sha2_256: "00".repeat(32), ..Default::default() }],
+        ..Default::default()
+    };
+
+    let result = appraise(&policy, &extracted_evidence, &create_endorsements());
+    assert!(result.is_err());
+}
+
+#[test]
+fn appraise_fails_with_unsupported_tcb_version() {
+    let extracted_evidence = extract_evidence(CONTAINERS_EVIDENCE_PATH);
+    let policy = AppraisalPolicy {
+        min_amd_sev_snp_tcb: Some(TcbVersion {
+            boot_loader: 0,
+            tee: 0,
+            snp: u32::MAX,
+            microcode: 0,
+        }),
+        ..Default::default()
+    };
+
+    let result = appraise(&policy, &extracted_evidence, &create_endorsements());
+    assert!(result.is_err());
+    assert!(format!("{:#}", result.unwrap_err()).contains("snp version"));
+}
+
+#[test]
+fn appraise_fails_with_missing_claim_type() {
+    let extracted_evidence = extract_evidence(CONTAINERS_EVIDENCE_PATH);
+    let policy = AppraisalPolicy {
+        required_endorsement_claim_types: vec![
+            ENDORSEMENT_CLAIM_TYPE.to_owned(),
+            "https://example.com/reviewed".to_owned(),
+        ],
+        ..Default::default()
+    };
+
+    let result = appraise(&policy, &extracted_evidence, &create_endorsements());
+    assert!(result.is_err());
+}
+
+#[test]
+fn appraise_fake_evidence() {
+    let extracted_evidence = extract_evidence(FAKE_EVIDENCE_PATH);
+    let endorsements = create_endorsements();
+
+    assert!(appraise(&AppraisalPolicy::default(), &extracted_evidence, &endorsements).is_err());
+    let policy = AppraisalPolicy { allow_insecure: true, ..Default::default() };
+    assert!(appraise(&policy, &extracted_evidence, &endorsements).is_ok());
+}
+
+#[test]
+fn parse_policy_json_succeeds() {
+    let policy = parse_policy_json(
+        br#"{
+            "allowed_kernel_digests": [{ "sha2_256": "ec752c66" }],
+            "min_amd_sev_snp_tcb": { "boot_loader": 3, "snp": 20 },
+            "allow_debug": true,
+            "required_endorsement_claim_types": ["https://example.com/reviewed"],
+            "intel_root_ca_certificate": "AQID"
+        }"#,
+    )
+    .expect("couldn't parse policy");
+
+    assert_eq!(
+        policy,
+        AppraisalPolicy {
+            allowed_kernel_digests: vec![HexDigest {
+                sha2_256: "ec752c66".to_owned(),
+                ..Default::default()
+            }],
+            min_amd_sev_snp_tcb: Some(TcbVersion { boot_loader: 3, tee: 0, snp: 20, microcode: 0 }),
+            allow_debug: true,
+            required_endorsement_claim_types: vec!["https://example.com/reviewed".to_owned()],
+            allow_insecure: false,
+            intel_root_ca_certificate: vec![1, 2, 3],
+        }
+    );
+}
+
+#[test]
+fn parse_policy_json_fails_with_unknown_field() {
+    assert!(parse_policy_json(br#"{ "allow_debugging": true }"#).is_err());
+}
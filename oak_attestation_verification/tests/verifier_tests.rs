@@ -18,21 +18,21 @@ use std::{fs, string::String};
 
 use oak_attestation_verification::{
     util::convert_pem_to_raw,
-    verifier::{to_attestation_results, verify, verify_dice_chain},
+    verifier::{to_attestation_results, verify, verify_bundle, verify_dice_chain, verify_report},
 };
 use oak_proto_rust::oak::{
     attestation::v1::{
         attestation_results::Status, binary_reference_value, extracted_evidence::EvidenceValues,
         kernel_binary_reference_value, reference_values, root_layer_data::Report,
         text_reference_value, AmdSevReferenceValues, ApplicationLayerEndorsements,
-        ApplicationLayerReferenceValues, BinaryReferenceValue, ContainerLayerEndorsements,
-        ContainerLayerReferenceValues, Digests, EndorsementReferenceValue, Endorsements, Evidence,
-        InsecureReferenceValues, KernelBinaryReferenceValue, KernelLayerEndorsements,
-        KernelLayerReferenceValues, OakContainersEndorsements, OakContainersReferenceValues,
-        OakRestrictedKernelEndorsements, OakRestrictedKernelReferenceValues, ReferenceValues,
-        Regex, RootLayerEndorsements, RootLayerReferenceValues, SkipVerification, StringLiterals,
-        SystemLayerEndorsements, SystemLayerReferenceValues, TcbVersion, TextReferenceValue,
-        TransparentReleaseEndorsement,
+        ApplicationLayerReferenceValues, AttestationBundle, BinaryReferenceValue,
+        ContainerLayerEndorsements, ContainerLayerReferenceValues, Digests,
+        EndorsementReferenceValue, Endorsements, Evidence, InsecureReferenceValues,
+        KernelBinaryReferenceValue, KernelLayerEndorsements, KernelLayerReferenceValues,
+        OakContainersEndorsements, OakContainersReferenceValues, OakRestrictedKernelEndorsements,
+        OakRestrictedKernelReferenceValues, ReferenceValues, Regex, RootLayerEndorsements,
+        RootLayerReferenceValues, SkipVerification, StringLiterals, SystemLayerEndorsements,
+        SystemLayerReferenceValues, TcbVersion, TextReferenceValue, TransparentReleaseEndorsement,
     },
     RawDigest,
 };
@@ -94,8 +94,11 @@ fn create_containers_endorsements() -> Endorsements {
         rekor_log_entry: log_entry,
     };
 
-    let root_layer =
-        RootLayerEndorsements { tee_certificate: vcek_milan_cert, stage0: Some(tre.clone()) };
+    let root_layer = RootLayerEndorsements {
+        tee_certificate: vcek_milan_cert,
+        stage0: Some(tre.clone()),
+        tdx_collateral: None,
+    };
     #[allow(deprecated)]
     let kernel_layer = KernelLayerEndorsements {
         kernel: Some(tre.clone()),
@@ -124,7 +127,11 @@ fn create_containers_endorsements() -> Endorsements {
 fn create_rk_endorsements() -> Endorsements {
     let vcek_milan_cert = fs::read(RK_VCEK_MILAN_CERT_DER).expect("couldn't read TEE cert");
 
-    let root_layer = RootLayerEndorsements { tee_certificate: vcek_milan_cert, stage0: None };
+    let root_layer = RootLayerEndorsements {
+        tee_certificate: vcek_milan_cert,
+        stage0: None,
+        tdx_collateral: None,
+    };
     #[allow(deprecated)]
     let kernel_layer = KernelLayerEndorsements {
         kernel: None,
@@ -256,6 +263,65 @@ fn verify_containers_succeeds() {
     assert!(p.status() == Status::Success);
 }
 
+#[test]
+fn verify_report_succeeds() {
+    let evidence = create_containers_evidence();
+    let endorsements = create_containers_endorsements();
+    let reference_values = create_containers_reference_values();
+
+    let report = verify_report(NOW_UTC_MILLIS, &evidence, &endorsements, &reference_values);
+
+    assert!(report.status() == Status::Success, "{}", report.reason);
+    assert_eq!(report.verification_time_utc_millis, NOW_UTC_MILLIS);
+    assert!(report.extracted_evidence.is_some());
+    let checks: Vec<(&str, bool)> =
+        report.checks.iter().map(|check| (check.name.as_str(), check.passed)).collect();
+    assert_eq!(
+        checks,
+        vec![
+            ("root attestation signature", true),
+            ("DICE chain", true),
+            ("root layer", true),
+            ("kernel layer", true),
+            ("system layer", true),
+            ("container layer", true),
+        ]
+    );
+}
+
+#[test]
+fn verify_bundle_succeeds() {
+    let bundle = AttestationBundle {
+        evidence: Some(create_containers_evidence()),
+        endorsements: Some(create_containers_endorsements()),
+        reference_values: Some(create_containers_reference_values()),
+        captured_at_utc_millis: NOW_UTC_MILLIS,
+    };
+    // The bundle is verified after being stored, as it would be for an audit.
+    let bundle = AttestationBundle::decode(bundle.encode_to_vec().as_slice())
+        .expect("couldn't decode bundle");
+
+    let report = verify_bundle(bundle.captured_at_utc_millis, &bundle);
+
+    assert!(report.status() == Status::Success, "{}", report.reason);
+    assert_eq!(report.verification_time_utc_millis, NOW_UTC_MILLIS);
+}
+
+#[test]
+fn verify_bundle_fails_with_incomplete_bundle() {
+    let bundle = AttestationBundle {
+        evidence: Some(create_containers_evidence()),
+        endorsements: Some(create_containers_endorsements()),
+        reference_values: None,
+        captured_at_utc_millis: NOW_UTC_MILLIS,
+    };
+
+    let report = verify_bundle(NOW_UTC_MILLIS, &bundle);
+
+    assert!(report.status() == Status::GenericFailure);
+    assert!(report.checks.is_empty());
+}
+
 #[test]
 fn verify_rk_succeeds() {
     let evidence = create_rk_evidence();
@@ -379,6 +445,16 @@ fn verify_fails_with_unsupported_tcb_version() {
     eprintln!("======================================");
     assert!(r.is_err());
     assert!(p.status() == Status::GenericFailure);
+
+    // The report records which check failed, and still contains the values
+    // extracted from the evidence.
+    let report = verify_report(NOW_UTC_MILLIS, &evidence, &endorsements, &reference_values);
+    assert!(report.status() == Status::GenericFailure);
+    assert!(report.extracted_evidence.is_some());
+    let failed = report.checks.last().expect("no checks in the report");
+    assert_eq!(failed.name, "root layer");
+    assert!(!failed.passed);
+    assert!(failed.reason.contains("unsupported snp version"), "{}", failed.reason);
 }
 
 #[test]
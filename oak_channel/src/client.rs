@@ -16,29 +16,99 @@
 
 extern crate alloc;
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt;
 
 use oak_core::timer::Timer;
 
 use crate::{
+    compression,
+    frame::Flags,
     message::{InvocationId, RequestMessage, ResponseMessage},
     Channel, InvocationChannel,
 };
 
+/// The protocol extensions that a service supports.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// Whether the service accepts compressed requests.
+    pub compression: bool,
+    /// Whether the service understands cancellations.
+    pub cancellation: bool,
+}
+
 pub struct ClientChannelHandle {
     inner: InvocationChannel,
+    extensions: Extensions,
 }
 
 impl ClientChannelHandle {
     pub fn new(socket: Box<dyn Channel>) -> Self {
-        Self { inner: InvocationChannel::new(socket) }
+        Self { inner: InvocationChannel::new(socket), extensions: Extensions::default() }
+    }
+
+    /// Asks the service which protocol extensions it supports, and reads its
+    /// response from `reader`, which may be another handle to the same
+    /// channel. The supported extensions are used for the requests written
+    /// through this handle from now on. Must be called before any other
+    /// request is sent, with an invocation id that isn't used for any other
+    /// request.
+    pub fn negotiate(
+        &mut self,
+        reader: &mut ClientChannelHandle,
+        invocation_id: InvocationId,
+    ) -> anyhow::Result<Extensions> {
+        self.inner.write_message_with_flags(
+            RequestMessage { invocation_id, body: Vec::new() },
+            Flags::NEGOTIATE,
+        )?;
+        let (response, flags, _): (ResponseMessage, _, _) = reader.inner.read_message()?;
+        if response.invocation_id != invocation_id {
+            anyhow::bail!(
+                "expected response with invocation id {}, got {}",
+                invocation_id,
+                response.invocation_id
+            );
+        }
+        // Services that don't support any extensions ignore the flag, and respond to
+        // the empty request with an error instead.
+        let negotiated = flags.contains(Flags::NEGOTIATE);
+        self.extensions = Extensions {
+            compression: negotiated && flags.contains(Flags::COMPRESSED),
+            cancellation: negotiated && flags.contains(Flags::CANCEL),
+        };
+        Ok(self.extensions.clone())
     }
+
+    /// Tells the service that the response to the request with the given
+    /// invocation id is no longer awaited, so that it may skip handling it.
+    /// Does nothing if the service doesn't understand cancellations.
+    pub fn write_cancellation(&mut self, invocation_id: InvocationId) -> anyhow::Result<()> {
+        if !self.extensions.cancellation {
+            return Ok(());
+        }
+        self.inner.write_message_with_flags(
+            RequestMessage { invocation_id, body: Vec::new() },
+            Flags::CANCEL,
+        )
+    }
+
     pub fn write_request(&mut self, request: RequestMessage) -> anyhow::Result<()> {
+        if self.extensions.compression && request.body.len() >= compression::MIN_COMPRESSED_SIZE {
+            let body = compression::compress(&request.body)?;
+            if body.len() < request.body.len() {
+                return self.inner.write_message_with_flags(
+                    RequestMessage { invocation_id: request.invocation_id, body },
+                    Flags::COMPRESSED,
+                );
+            }
+        }
         self.inner.write_message(request)
     }
 
     pub fn read_response(&mut self) -> anyhow::Result<(ResponseMessage, Timer)> {
-        self.inner.read_message()
+        let (response, _, timer) = self.inner.read_message()?;
+        Ok((response, timer))
     }
 }
 
@@ -66,3 +136,81 @@ impl InvocationIdCounter {
         next_invocation_id
     }
 }
+
+/// Limits of the requests that may be in flight on a channel at the same time,
+/// i.e. written but not responded to yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowLimits {
+    pub max_requests: usize,
+    pub max_bytes: usize,
+}
+
+impl WindowLimits {
+    pub const DEFAULT_MAX_REQUESTS: usize = 64;
+    pub const DEFAULT_MAX_BYTES: usize = 64 << 20;
+}
+
+impl Default for WindowLimits {
+    fn default() -> Self {
+        Self { max_requests: Self::DEFAULT_MAX_REQUESTS, max_bytes: Self::DEFAULT_MAX_BYTES }
+    }
+}
+
+/// Error returned for a request that doesn't fit in the window, because the
+/// service isn't keeping up with the requests already in flight.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelBusy {
+    pub in_flight_requests: usize,
+    pub in_flight_bytes: usize,
+}
+
+impl fmt::Display for ChannelBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "channel busy: {} requests ({} bytes) in flight",
+            self.in_flight_requests, self.in_flight_bytes
+        )
+    }
+}
+
+impl std::error::Error for ChannelBusy {}
+
+/// Window-based flow control: tracks the requests in flight, and rejects new
+/// ones once the limits are reached rather than buffering them.
+#[derive(Debug)]
+pub struct Window {
+    limits: WindowLimits,
+    in_flight_requests: usize,
+    in_flight_bytes: usize,
+}
+
+impl Window {
+    pub fn new(limits: WindowLimits) -> Self {
+        Self { limits, in_flight_requests: 0, in_flight_bytes: 0 }
+    }
+
+    /// Reserves room for a request of `size` bytes. A request is always
+    /// admitted if nothing else is in flight, so that requests larger than the
+    /// window can still be sent.
+    pub fn acquire(&mut self, size: usize) -> Result<(), ChannelBusy> {
+        let fits = self.in_flight_requests < self.limits.max_requests
+            && self.in_flight_bytes.saturating_add(size) <= self.limits.max_bytes;
+        if self.in_flight_requests > 0 && !fits {
+            return Err(ChannelBusy {
+                in_flight_requests: self.in_flight_requests,
+                in_flight_bytes: self.in_flight_bytes,
+            });
+        }
+        self.in_flight_requests += 1;
+        self.in_flight_bytes += size;
+        Ok(())
+    }
+
+    /// Releases the room reserved for a request of `size` bytes, once it has
+    /// been responded to.
+    pub fn release(&mut self, size: usize) {
+        self.in_flight_requests = self.in_flight_requests.saturating_sub(1);
+        self.in_flight_bytes = self.in_flight_bytes.saturating_sub(size);
+    }
+}
@@ -0,0 +1,82 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! zstd compression of message bodies.
+//!
+//! A compressed body consists of the length of the decompressed body, as a
+//! little-endian u32, followed by a single zstd frame. Only the client
+//! compresses messages, so only it depends on a zstd encoder; the service
+//! decompresses them with a `no_std` decoder.
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+
+use ruzstd::io::Read as _;
+
+type Length = u32;
+const LENGTH_SIZE: usize = 4;
+static_assertions::assert_eq_size!([u8; LENGTH_SIZE], Length);
+
+/// Message bodies smaller than this are not worth compressing, as they fit in a
+/// single frame anyway.
+#[cfg(feature = "client")]
+pub const MIN_COMPRESSED_SIZE: usize = crate::frame::MAX_BODY_SIZE;
+
+/// zstd compression level used for message bodies, trading off compression
+/// ratio for speed.
+#[cfg(feature = "client")]
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[cfg(feature = "client")]
+pub fn compress(body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let length = Length::try_from(body.len())
+        .map_err(|_| anyhow::Error::msg("body too large to compress"))?;
+    let compressed = zstd::bulk::compress(body, COMPRESSION_LEVEL).map_err(anyhow::Error::msg)?;
+    let mut result = Vec::with_capacity(LENGTH_SIZE + compressed.len());
+    result.extend_from_slice(&length.to_le_bytes());
+    result.extend_from_slice(&compressed);
+    Ok(result)
+}
+
+pub fn decompress(compressed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if compressed.len() < LENGTH_SIZE {
+        anyhow::bail!("compressed body too short");
+    }
+    let length = {
+        let mut length_bytes = [0; LENGTH_SIZE];
+        length_bytes.copy_from_slice(&compressed[..LENGTH_SIZE]);
+        usize::try_from(Length::from_le_bytes(length_bytes))
+            .expect("couldn't convert body length to usize")
+    };
+    // The length comes from the peer, so it must be checked before allocating the
+    // body.
+    let max_length = crate::message::MAX_SIZE - crate::message::BODY_OFFSET;
+    if length > max_length {
+        anyhow::bail!("decompressed body length {} exceeds {} bytes", length, max_length);
+    }
+    let mut decoder = ruzstd::StreamingDecoder::new(&compressed[LENGTH_SIZE..])
+        .map_err(|err| anyhow::anyhow!("invalid zstd frame: {:?}", err))?;
+    let mut body = vec![0; length];
+    decoder
+        .read_exact(&mut body)
+        .map_err(|err| anyhow::anyhow!("couldn't decompress body: {:?}", err))?;
+    // Check that the body isn't longer than announced.
+    if decoder.read(&mut [0; 1]).map_err(|err| anyhow::anyhow!("{:?}", err))? != 0 {
+        anyhow::bail!("decompressed body longer than {} bytes", length);
+    }
+    Ok(body)
+}
@@ -38,6 +38,16 @@ bitflags! {
     pub struct Flags: u16 {
         const START = 1;
         const END = 2;
+        /// The body of the message is compressed, see [`crate::compression`].
+        /// On a negotiation response: the service accepts compressed requests.
+        const COMPRESSED = 4;
+        /// On a request: asks the service which protocol extensions it
+        /// supports. On a response: the service supports negotiation, and the
+        /// other flags list the extensions it supports.
+        const NEGOTIATE = 8;
+        /// On a request: the response to the invocation is no longer awaited.
+        /// On a negotiation response: the service understands cancellations.
+        const CANCEL = 16;
     }
 }
 pub const FLAGS_SIZE: usize = 2;
@@ -22,6 +22,7 @@
 pub mod client;
 
 pub mod basic_framed;
+mod compression;
 mod frame;
 pub mod message;
 pub mod server;
@@ -82,7 +83,11 @@ impl InvocationChannel {
         Self { inner: frame::Framed::new(socket) }
     }
 
-    pub fn read_message<M: message::Message>(&mut self) -> anyhow::Result<(M, Timer)> {
+    /// Reads a message, decompressing it if necessary, and returns it together
+    /// with the flags of its first frame.
+    pub fn read_message<M: message::Message>(
+        &mut self,
+    ) -> anyhow::Result<(M, frame::Flags, Timer)> {
         // `message_buffer` will contain the full message we are going to read. Instead
         // of allocating separate buffers and copying data into
         // `message_buffer`, we will ensure that `message_buffer` has enough
@@ -96,8 +101,9 @@ impl InvocationChannel {
             anyhow::bail!("expected a frame with the START flag set");
         }
 
-        if first_frame.flags.contains(frame::Flags::END) {
-            return Ok((M::decode(&message_buffer[..]), timer));
+        let flags = first_frame.flags.clone();
+        if flags.contains(frame::Flags::END) {
+            return Ok((Self::decode(&message_buffer[..], &flags)?, flags, timer));
         }
 
         // The length of the entire message is encoded in the body of the first frame.
@@ -110,6 +116,9 @@ impl InvocationChannel {
             usize::try_from(message::Length::from_le_bytes(buffer))
                 .expect("couldn't convert message length to usize")
         };
+        if message_length > message::MAX_SIZE {
+            anyhow::bail!("message length {} exceeds {} bytes", message_length, message::MAX_SIZE);
+        }
 
         // This likely causes a copy of the pre-existing data, but we needed to read the
         // first frame to figure out how much space we need for the entire
@@ -129,13 +138,41 @@ impl InvocationChannel {
             }
         }
 
-        Ok((M::decode(&message_buffer[..]), timer))
+        Ok((Self::decode(&message_buffer[..], &flags)?, flags, timer))
+    }
+
+    fn decode<M: message::Message>(
+        encoded_message: &[u8],
+        flags: &frame::Flags,
+    ) -> anyhow::Result<M> {
+        // On negotiation responses the flag lists the supported extensions instead.
+        if !flags.contains(frame::Flags::COMPRESSED) || flags.contains(frame::Flags::NEGOTIATE) {
+            return Ok(M::decode(encoded_message));
+        }
+        // Only the body of the message is compressed.
+        let body = compression::decompress(&encoded_message[message::BODY_OFFSET..])
+            .context("couldn't decompress message")?;
+        let mut decompressed_message = Vec::with_capacity(message::BODY_OFFSET + body.len());
+        decompressed_message.extend_from_slice(&encoded_message[..message::BODY_OFFSET]);
+        decompressed_message.extend_from_slice(&body);
+        Ok(M::decode(&decompressed_message))
     }
 
     pub fn write_message<M: message::Message>(&mut self, message: M) -> anyhow::Result<()> {
+        self.write_message_with_flags(message, frame::Flags::empty())
+    }
+
+    /// Writes a message, setting `flags` on all of its frames in addition to
+    /// the START and END flags.
+    pub fn write_message_with_flags<M: message::Message>(
+        &mut self,
+        message: M,
+        flags: frame::Flags,
+    ) -> anyhow::Result<()> {
         let encoded_data = message.encode();
         let frames: Vec<frame::Frame> = frame::bytes_into_frames(&encoded_data[..])?;
-        for frame in frames.into_iter() {
+        for mut frame in frames.into_iter() {
+            frame.flags.insert(flags.clone());
             self.inner.write_frame(frame).context("couldn't write frame")?
         }
         Ok(())
@@ -30,6 +30,11 @@ static_assertions::assert_eq_size!([u8; INVOCATION_ID_SIZE], InvocationId);
 
 pub const BODY_OFFSET: usize = 8;
 
+/// Maximum size of a message, including its header: the 2 GiB limit of protobuf
+/// messages. Longer announced lengths are rejected before any memory is
+/// allocated for them, as they come from the peer.
+pub const MAX_SIZE: usize = 2 << 30;
+
 // messages never have a length of zero, since it always includes a fixed size
 // header.
 #[allow(clippy::len_without_is_empty)]
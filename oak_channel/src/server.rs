@@ -14,11 +14,11 @@
 // limitations under the License.
 //
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
 
 use oak_core::timer::Timer;
 
-use crate::{message, Channel, InvocationChannel};
+use crate::{frame::Flags, message, Channel, InvocationChannel};
 
 pub struct ServerChannelHandle {
     inner: InvocationChannel,
@@ -28,8 +28,31 @@ impl ServerChannelHandle {
     pub fn new(socket: Box<dyn Channel>) -> Self {
         Self { inner: InvocationChannel::new(socket) }
     }
+    /// Reads the next request, decompressing it if necessary. Negotiation
+    /// requests and cancellations are handled directly rather than returned.
     pub fn read_request(&mut self) -> anyhow::Result<(message::RequestMessage, Timer)> {
-        self.inner.read_message()
+        loop {
+            let (request, flags, timer): (message::RequestMessage, _, _) =
+                self.inner.read_message()?;
+            if flags.contains(Flags::NEGOTIATE) {
+                self.inner.write_message_with_flags(
+                    message::ResponseMessage {
+                        invocation_id: request.invocation_id,
+                        body: Vec::new(),
+                    },
+                    Flags::NEGOTIATE | Flags::COMPRESSED | Flags::CANCEL,
+                )?;
+            } else if flags.contains(Flags::CANCEL) {
+                // Requests are handled one at a time, in the order they are
+                // read, so the cancelled invocation has been
+                // handled already by the time its cancellation
+                // is read. Cancellations of requests that the client
+                // hasn't sent yet are handled on the client side, so there's
+                // nothing left to do.
+            } else {
+                return Ok((request, timer));
+            }
+        }
     }
 
     pub fn write_response(&mut self, response: message::ResponseMessage) -> anyhow::Result<()> {
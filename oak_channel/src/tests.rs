@@ -18,10 +18,11 @@
 
 extern crate std;
 
-use alloc::{boxed::Box, collections::VecDeque, vec};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec};
+use std::sync::Mutex;
 
 use super::*;
-use crate::message::{Message, RequestMessage};
+use crate::message::{Message, RequestMessage, ResponseMessage};
 
 const BODY_LEN_MULTIPLIER: usize = 5;
 const MOCK_LARGE_PAYLOAD_LEN: usize = frame::MAX_BODY_SIZE * BODY_LEN_MULTIPLIER;
@@ -92,6 +93,28 @@ impl Write for MessageStore {
     }
 }
 
+/// A [`MessageStore`] that can be shared between the two ends of a channel.
+#[derive(Clone, Default)]
+struct SharedMessageStore {
+    inner: Arc<Mutex<MessageStore>>,
+}
+
+impl Read for SharedMessageStore {
+    fn read_exact(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.inner.lock().unwrap().read_exact(buf)
+    }
+}
+
+impl Write for SharedMessageStore {
+    fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.inner.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
 #[test]
 fn test_invocation_channel() {
     let mut invocation_channel = InvocationChannel::new(Box::new(MessageStore::default()));
@@ -100,7 +123,7 @@ fn test_invocation_channel() {
 
     invocation_channel.write_message(message.clone()).unwrap();
 
-    let (reconstructed_message, _): (RequestMessage, _) =
+    let (reconstructed_message, _, _): (RequestMessage, _, _) =
         invocation_channel.read_message().unwrap();
     assert_eq!(message, reconstructed_message);
 }
@@ -131,3 +154,109 @@ fn test_invocation_channel_expected_start_frame() {
 
     invocation_channel.read_message::<message::RequestMessage>().unwrap_err();
 }
+
+#[cfg(feature = "client")]
+#[test]
+fn test_invocation_channel_compressed_message() {
+    let mut invocation_channel = InvocationChannel::new(Box::new(MessageStore::default()));
+
+    let message =
+        message::RequestMessage { invocation_id: 4, body: vec![7; MOCK_LARGE_PAYLOAD_LEN] };
+    let compressed_message = message::RequestMessage {
+        invocation_id: 4,
+        body: compression::compress(&message.body).unwrap(),
+    };
+    assert!(compressed_message.body.len() < message.body.len());
+
+    invocation_channel
+        .write_message_with_flags(compressed_message, frame::Flags::COMPRESSED)
+        .unwrap();
+
+    let (reconstructed_message, _, _): (RequestMessage, _, _) =
+        invocation_channel.read_message().unwrap();
+    assert_eq!(message, reconstructed_message);
+}
+
+#[test]
+fn test_invocation_channel_corrupt_compressed_message() {
+    let mut invocation_channel = InvocationChannel::new(Box::new(MessageStore::default()));
+
+    let message = message::RequestMessage { invocation_id: 0, body: mock_payload() };
+    invocation_channel.write_message_with_flags(message, frame::Flags::COMPRESSED).unwrap();
+
+    invocation_channel.read_message::<message::RequestMessage>().unwrap_err();
+}
+
+#[test]
+fn test_decompress_rejects_oversized_length() {
+    // The announced length is checked before the zstd frame is even parsed.
+    let mut compressed = u32::MAX.to_le_bytes().to_vec();
+    compressed.extend_from_slice(&[0; 16]);
+
+    let err = compression::decompress(&compressed).unwrap_err();
+    assert!(err.to_string().contains("exceeds"), "unexpected error: {err}");
+}
+
+#[test]
+fn test_invocation_channel_rejects_oversized_message() {
+    let mut invocation_channel = {
+        let message = message::RequestMessage { invocation_id: 0, body: mock_payload() }.encode();
+        let mut start_frame = frame::bytes_into_frames(&message).unwrap().first().unwrap().clone();
+        // Announce a message longer than the maximum size in the first frame.
+        let mut body = start_frame.body.to_vec();
+        body[message::LENGTH_OFFSET..message::LENGTH_OFFSET + message::LENGTH_SIZE]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+        start_frame.body = &body;
+        let mut frame_store = frame::Framed::new(Box::new(MessageStore::default()));
+        frame_store.write_frame(start_frame).unwrap();
+        InvocationChannel { inner: frame_store }
+    };
+
+    invocation_channel.read_message::<message::RequestMessage>().unwrap_err();
+}
+
+#[test]
+fn test_server_negotiates_extensions() {
+    let store = SharedMessageStore::default();
+    let mut client = InvocationChannel::new(Box::new(store.clone()));
+    let mut server = server::ServerChannelHandle::new(Box::new(store));
+
+    client
+        .write_message_with_flags(
+            message::RequestMessage { invocation_id: 0, body: Vec::new() },
+            frame::Flags::NEGOTIATE,
+        )
+        .unwrap();
+    let message = message::RequestMessage { invocation_id: 1, body: mock_payload() };
+    client.write_message(message.clone()).unwrap();
+
+    // The negotiation is answered by the server handle, rather than returned as a
+    // request.
+    let (request, _) = server.read_request().unwrap();
+    assert_eq!(message, request);
+
+    let (response, flags, _): (ResponseMessage, _, _) = client.read_message().unwrap();
+    assert_eq!(0, response.invocation_id);
+    assert!(
+        flags.contains(frame::Flags::NEGOTIATE | frame::Flags::COMPRESSED | frame::Flags::CANCEL)
+    );
+}
+
+#[test]
+fn test_server_skips_cancellations() {
+    let store = SharedMessageStore::default();
+    let mut client = InvocationChannel::new(Box::new(store.clone()));
+    let mut server = server::ServerChannelHandle::new(Box::new(store));
+
+    client
+        .write_message_with_flags(
+            message::RequestMessage { invocation_id: 0, body: Vec::new() },
+            frame::Flags::CANCEL,
+        )
+        .unwrap();
+    let message = message::RequestMessage { invocation_id: 1, body: mock_payload() };
+    client.write_message(message.clone()).unwrap();
+
+    let (request, _) = server.read_request().unwrap();
+    assert_eq!(message, request);
+}
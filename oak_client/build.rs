@@ -17,10 +17,13 @@
 use oak_grpc_utils::{generate_grpc_code, CodegenOptions};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The gRPC client isn't available in browsers, which use the session
+    // gateway instead, so only the messages are generated for Wasm.
+    let build_client = std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() != Ok("wasm32");
     generate_grpc_code(
         &["../proto/session/messages.proto", "../proto/session/service_streaming.proto"],
         "..",
-        CodegenOptions { build_client: true, ..Default::default() },
+        CodegenOptions { build_client, ..Default::default() },
     )?;
 
     Ok(())
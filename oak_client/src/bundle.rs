@@ -0,0 +1,46 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Storage of attestation bundles, which hold everything needed to verify the
+//! evidence of a session again after the fact, e.g. for audits or incident
+//! investigations, without access to the server.
+
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use oak_attestation_verification::verifier::verify_bundle;
+use oak_proto_rust::oak::attestation::v1::{AttestationBundle, VerificationReport};
+use prost::Message;
+
+/// Writes `bundle` to a file, as a serialized [`AttestationBundle`] message.
+pub fn save(bundle: &AttestationBundle, path: &Path) -> anyhow::Result<()> {
+    fs::write(path, bundle.encode_to_vec())
+        .with_context(|| format!("couldn't write attestation bundle {}", path.display()))
+}
+
+/// Reads a bundle written by [`save`].
+pub fn load(path: &Path) -> anyhow::Result<AttestationBundle> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("couldn't read attestation bundle {}", path.display()))?;
+    AttestationBundle::decode(bytes.as_slice()).context("couldn't decode attestation bundle")
+}
+
+/// Verifies the bundle stored in a file again, as if at `now_utc_millis`, or
+/// at the time the bundle was captured if not set.
+pub fn verify(path: &Path, now_utc_millis: Option<i64>) -> anyhow::Result<VerificationReport> {
+    let bundle = load(path)?;
+    Ok(verify_bundle(now_utc_millis.unwrap_or(bundle.captured_at_utc_millis), &bundle))
+}
@@ -0,0 +1,337 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Caching of attestation verification results, so that clients that connect
+//! often to the same servers don't verify the same evidence every time.
+//!
+//! A cached result is only reused for the exact evidence and endorsements it
+//! was verified for, so a restarted enclave, or renewed endorsements, are
+//! always verified again. The [`ReverificationPolicy`] controls when
+//! verification is required even if they haven't changed, and a result is
+//! never reused once one of the endorsement statements it was verified with
+//! has expired.
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use oak_attestation_verification::claims::parse_endorsement_statement;
+use oak_proto_rust::oak::attestation::v1::{
+    endorsements, Endorsements, Evidence, ExtractedEvidence, TransparentReleaseEndorsement,
+};
+
+use crate::verifier::now_utc_millis;
+
+/// Controls when cached verification results must not be reused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReverificationPolicy {
+    /// How long a verification result is reused for. Bounds how long a client
+    /// keeps trusting endorsements that have been revoked since they were
+    /// verified. Results are discarded earlier if an endorsement statement
+    /// expires.
+    pub ttl: Duration,
+    /// Identifies the reference values the verifier checks evidence against.
+    /// Results verified against other reference values are not reused, so
+    /// changing them forces the evidence to be verified again.
+    pub reference_values_id: Vec<u8>,
+}
+
+impl Default for ReverificationPolicy {
+    fn default() -> Self {
+        Self { ttl: Duration::from_secs(3600), reference_values_id: Vec::new() }
+    }
+}
+
+struct CachedVerification {
+    evidence: Evidence,
+    endorsements: Endorsements,
+    reference_values_id: Vec<u8>,
+    verified_at_utc_millis: i64,
+    // The earliest `notAfter` of the endorsement statements, if any has one.
+    not_after_utc_millis: Option<i64>,
+    attestation_results: ExtractedEvidence,
+}
+
+/// Verification results by endpoint, shared by the clients of the same
+/// process.
+pub struct EvidenceCache {
+    policy: Mutex<ReverificationPolicy>,
+    entries: Mutex<HashMap<String, CachedVerification>>,
+    // Returns the current time in milliseconds since the Unix epoch.
+    clock: Box<dyn Fn() -> i64 + Send + Sync>,
+}
+
+impl EvidenceCache {
+    pub fn new(policy: ReverificationPolicy) -> Self {
+        Self::with_clock(policy, now_utc_millis)
+    }
+
+    fn with_clock(
+        policy: ReverificationPolicy,
+        clock: impl Fn() -> i64 + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            policy: Mutex::new(policy),
+            entries: Mutex::new(HashMap::new()),
+            clock: Box::new(clock),
+        }
+    }
+
+    /// Replaces the policy, e.g. after updating the reference values. Results
+    /// that the new policy doesn't allow reusing are verified again the next
+    /// time they are needed.
+    pub fn set_policy(&self, policy: ReverificationPolicy) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    /// Returns the results of verifying `evidence` and `endorsements` for
+    /// `endpoint`, if they were verified before, the policy allows reusing
+    /// them and none of the endorsement statements has expired since.
+    pub fn get(
+        &self,
+        endpoint: &str,
+        evidence: &Evidence,
+        endorsements: &Endorsements,
+    ) -> Option<ExtractedEvidence> {
+        let policy = self.policy.lock().unwrap().clone();
+        let now = (self.clock)();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(endpoint)?;
+        let ttl_millis = i64::try_from(policy.ttl.as_millis()).unwrap_or(i64::MAX);
+        if entry.reference_values_id != policy.reference_values_id
+            || now.saturating_sub(entry.verified_at_utc_millis) >= ttl_millis
+            || entry.not_after_utc_millis.is_some_and(|not_after| now > not_after)
+        {
+            entries.remove(endpoint);
+            return None;
+        }
+        (entry.evidence == *evidence && entry.endorsements == *endorsements)
+            .then(|| entry.attestation_results.clone())
+    }
+
+    /// Stores the results of verifying `evidence` and `endorsements` for
+    /// `endpoint`.
+    pub fn insert(
+        &self,
+        endpoint: &str,
+        evidence: &Evidence,
+        endorsements: &Endorsements,
+        attestation_results: &ExtractedEvidence,
+    ) {
+        let reference_values_id = self.policy.lock().unwrap().reference_values_id.clone();
+        self.entries.lock().unwrap().insert(
+            endpoint.to_string(),
+            CachedVerification {
+                evidence: evidence.clone(),
+                endorsements: endorsements.clone(),
+                reference_values_id,
+                verified_at_utc_millis: (self.clock)(),
+                not_after_utc_millis: endorsements_not_after(endorsements),
+                attestation_results: attestation_results.clone(),
+            },
+        );
+    }
+
+    /// Discards the results cached for `endpoint`, e.g. after its evidence was
+    /// rejected for reasons that the cache can't see.
+    pub fn invalidate(&self, endpoint: &str) {
+        self.entries.lock().unwrap().remove(endpoint);
+    }
+}
+
+// Returns the earliest `notAfter` of the endorsement statements in
+// `endorsements`, in milliseconds since the Unix epoch. Statements that can't
+// be parsed are ignored here, as verification rejects them.
+fn endorsements_not_after(endorsements: &Endorsements) -> Option<i64> {
+    transparent_release_endorsements(endorsements)
+        .filter_map(|endorsement| parse_endorsement_statement(&endorsement.endorsement).ok())
+        .filter_map(|statement| statement.predicate.validity)
+        .map(|validity| {
+            i64::try_from(validity.not_after.unix_timestamp_nanos() / 1000000).unwrap_or(i64::MAX)
+        })
+        .min()
+}
+
+fn transparent_release_endorsements(
+    endorsements: &Endorsements,
+) -> impl Iterator<Item = &TransparentReleaseEndorsement> {
+    let all: Vec<Option<&TransparentReleaseEndorsement>> = match endorsements.r#type.as_ref() {
+        Some(endorsements::Type::OakRestrictedKernel(endorsements)) => {
+            let kernel_layer = endorsements.kernel_layer.as_ref();
+            let application_layer = endorsements.application_layer.as_ref();
+            vec![
+                endorsements.root_layer.as_ref().and_then(|layer| layer.stage0.as_ref()),
+                kernel_layer.and_then(|layer| layer.kernel.as_ref()),
+                kernel_layer.and_then(|layer| layer.kernel_cmd_line.as_ref()),
+                kernel_layer.and_then(|layer| layer.init_ram_fs.as_ref()),
+                kernel_layer.and_then(|layer| layer.memory_map.as_ref()),
+                kernel_layer.and_then(|layer| layer.acpi.as_ref()),
+                application_layer.and_then(|layer| layer.binary.as_ref()),
+                application_layer.and_then(|layer| layer.configuration.as_ref()),
+            ]
+        }
+        Some(endorsements::Type::OakContainers(endorsements)) => {
+            let kernel_layer = endorsements.kernel_layer.as_ref();
+            let container_layer = endorsements.container_layer.as_ref();
+            vec![
+                endorsements.root_layer.as_ref().and_then(|layer| layer.stage0.as_ref()),
+                kernel_layer.and_then(|layer| layer.kernel.as_ref()),
+                kernel_layer.and_then(|layer| layer.kernel_cmd_line.as_ref()),
+                kernel_layer.and_then(|layer| layer.init_ram_fs.as_ref()),
+                kernel_layer.and_then(|layer| layer.memory_map.as_ref()),
+                kernel_layer.and_then(|layer| layer.acpi.as_ref()),
+                endorsements.system_layer.as_ref().and_then(|layer| layer.system_image.as_ref()),
+                container_layer.and_then(|layer| layer.binary.as_ref()),
+                container_layer.and_then(|layer| layer.configuration.as_ref()),
+            ]
+        }
+        Some(endorsements::Type::Cb(endorsements)) => {
+            vec![endorsements.root_layer.as_ref().and_then(|layer| layer.stage0.as_ref())]
+        }
+        None => Vec::new(),
+    };
+    all.into_iter().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    };
+
+    use oak_proto_rust::oak::attestation::v1::{
+        ApplicationLayerEndorsements, OakRestrictedKernelEndorsements,
+    };
+
+    use super::*;
+
+    const ENDPOINT: &str = "https://example.com";
+    // 1 March 2024, 00:00 UTC.
+    const START_UTC_MILLIS: i64 = 1709251200000;
+    // The endorsement statement below expires on 1 March 2024, 00:30 UTC.
+    const NOT_AFTER_UTC_MILLIS: i64 = START_UTC_MILLIS + 30 * 60 * 1000;
+    const ENDORSEMENT_STATEMENT: &str = r#"{
+        "_type": "https://in-toto.io/Statement/v1",
+        "predicateType": "https://github.com/project-oak/transparent-release/claim/v2",
+        "subject": [{"name": "app", "digest": {"sha256": "00"}}],
+        "predicate": {
+            "claimType": "https://github.com/project-oak/transparent-release/endorsement/v2",
+            "issuedOn": "2024-02-29T00:00:00Z",
+            "validity": {"notBefore": "2024-02-29T00:00:00Z", "notAfter": "2024-03-01T00:30:00Z"}
+        }
+    }"#;
+
+    // A clock that only moves when the test advances it.
+    #[derive(Clone)]
+    struct MockClock(Arc<AtomicI64>);
+
+    impl MockClock {
+        fn new() -> Self {
+            Self(Arc::new(AtomicI64::new(START_UTC_MILLIS)))
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.0.fetch_add(duration.as_millis() as i64, Ordering::SeqCst);
+        }
+
+        fn cache(&self, policy: ReverificationPolicy) -> EvidenceCache {
+            let clock = self.0.clone();
+            EvidenceCache::with_clock(policy, move || clock.load(Ordering::SeqCst))
+        }
+    }
+
+    fn policy(ttl: Duration) -> ReverificationPolicy {
+        ReverificationPolicy { ttl, reference_values_id: b"v1".to_vec() }
+    }
+
+    fn endorsements_with_statement() -> Endorsements {
+        Endorsements {
+            r#type: Some(endorsements::Type::OakRestrictedKernel(
+                OakRestrictedKernelEndorsements {
+                    application_layer: Some(ApplicationLayerEndorsements {
+                        binary: Some(TransparentReleaseEndorsement {
+                            endorsement: ENDORSEMENT_STATEMENT.as_bytes().to_vec(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )),
+        }
+    }
+
+    fn results() -> ExtractedEvidence {
+        ExtractedEvidence { encryption_public_key: b"key".to_vec(), ..Default::default() }
+    }
+
+    #[test]
+    fn reuses_results_within_ttl() {
+        let clock = MockClock::new();
+        let cache = clock.cache(policy(Duration::from_secs(60)));
+        let (evidence, endorsements) = (Evidence::default(), Endorsements::default());
+        cache.insert(ENDPOINT, &evidence, &endorsements, &results());
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(cache.get(ENDPOINT, &evidence, &endorsements), Some(results()));
+    }
+
+    #[test]
+    fn evicts_results_after_ttl() {
+        let clock = MockClock::new();
+        let cache = clock.cache(policy(Duration::from_secs(60)));
+        let (evidence, endorsements) = (Evidence::default(), Endorsements::default());
+        cache.insert(ENDPOINT, &evidence, &endorsements, &results());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(cache.get(ENDPOINT, &evidence, &endorsements), None);
+        // The expired entry is evicted rather than kept.
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn evicts_results_when_endorsement_expires() {
+        let clock = MockClock::new();
+        // The TTL alone would keep the result for a day.
+        let cache = clock.cache(policy(Duration::from_secs(24 * 60 * 60)));
+        let (evidence, endorsements) = (Evidence::default(), endorsements_with_statement());
+        assert_eq!(endorsements_not_after(&endorsements), Some(NOT_AFTER_UTC_MILLIS));
+        cache.insert(ENDPOINT, &evidence, &endorsements, &results());
+
+        clock.advance(Duration::from_secs(30 * 60));
+        assert_eq!(cache.get(ENDPOINT, &evidence, &endorsements), Some(results()));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(cache.get(ENDPOINT, &evidence, &endorsements), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn changing_reference_values_forces_reverification() {
+        let clock = MockClock::new();
+        let cache = clock.cache(policy(Duration::from_secs(60)));
+        let (evidence, endorsements) = (Evidence::default(), Endorsements::default());
+        cache.insert(ENDPOINT, &evidence, &endorsements, &results());
+
+        cache.set_policy(ReverificationPolicy {
+            reference_values_id: b"v2".to_vec(),
+            ..policy(Duration::from_secs(60))
+        });
+        assert_eq!(cache.get(ENDPOINT, &evidence, &endorsements), None);
+
+        // Results verified against the new reference values are reused.
+        cache.insert(ENDPOINT, &evidence, &endorsements, &results());
+        assert_eq!(cache.get(ENDPOINT, &evidence, &endorsements), Some(results()));
+    }
+}
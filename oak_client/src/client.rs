@@ -14,14 +14,20 @@
 // limitations under the License.
 //
 
-use std::vec::Vec;
+use std::{time::Duration, vec::Vec};
 
-use anyhow::{anyhow, Context};
-use oak_crypto::encryptor::ClientEncryptor;
+use anyhow::Context;
+use oak_proto_rust::oak::attestation::v1::AttestationBundle;
+use prost::Message;
+use web_time::Instant;
 
 use crate::{
+    cache::EvidenceCache,
+    collateral::PcsCollateralProvider,
+    encryptor::ClientEncryptor,
+    peer::PeerAttester,
     transport::{EvidenceProvider, Transport},
-    verifier::AttestationVerifier,
+    verifier::{now_utc_millis, AttestationVerifier},
 };
 
 const EMPTY_ASSOCIATED_DATA: &[u8] = b"";
@@ -32,51 +38,231 @@ const EMPTY_ASSOCIATED_DATA: &[u8] = b"";
 pub struct OakClient<T: Transport> {
     transport: T,
     server_encryption_public_key: Vec<u8>,
+    resumable_session: Option<ResumableSession>,
+    attestation_bundle: AttestationBundle,
+    peer_attester: Option<PeerAttester>,
+}
+
+/// A session with verified evidence, that can be resumed on another transport
+/// (e.g. after reconnecting) without fetching and verifying the evidence again.
+#[derive(Clone, Debug)]
+pub struct ResumableSession {
+    ticket: Vec<u8>,
+    expires_at: Instant,
+    server_encryption_public_key: Vec<u8>,
+    attestation_bundle: AttestationBundle,
+}
+
+impl ResumableSession {
+    /// Returns whether the server no longer accepts the ticket of the session.
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
+
+// How a request is sent over the transport.
+enum InvokeMode {
+    Unary,
+    Streaming,
+    Batch,
 }
 
 impl<T: Transport + EvidenceProvider> OakClient<T> {
-    pub async fn create(
+    pub async fn create(transport: T, verifier: &dyn AttestationVerifier) -> anyhow::Result<Self> {
+        Self::create_with_cache(transport, verifier, None, None).await
+    }
+
+    /// Like [`OakClient::create`], but reuses the results of verifying the
+    /// evidence of `endpoint` cached in `cache`, if the server returns the same
+    /// evidence and endorsements and the cache policy allows it.
+    pub async fn create_cached(
+        transport: T,
+        verifier: &dyn AttestationVerifier,
+        cache: &EvidenceCache,
+        endpoint: &str,
+    ) -> anyhow::Result<Self> {
+        Self::create_with_cache(transport, verifier, Some((cache, endpoint)), None).await
+    }
+
+    /// Like [`OakClient::create`], but adds the collateral needed to verify
+    /// Intel TDX evidence, fetched from `collateral_provider`, to the
+    /// endorsements returned by the server.
+    pub async fn create_with_collateral(
+        transport: T,
+        verifier: &dyn AttestationVerifier,
+        collateral_provider: &PcsCollateralProvider,
+    ) -> anyhow::Result<Self> {
+        Self::create_with_cache(transport, verifier, None, Some(collateral_provider)).await
+    }
+
+    async fn create_with_cache(
         mut transport: T,
         verifier: &dyn AttestationVerifier,
+        cache: Option<(&EvidenceCache, &str)>,
+        collateral_provider: Option<&PcsCollateralProvider>,
     ) -> anyhow::Result<Self> {
+        let requested_at = Instant::now();
         let endorsed_evidence =
             transport.get_endorsed_evidence().await.context("couldn't get endorsed evidence")?;
 
         let evidence = endorsed_evidence
             .evidence
             .context("endorsed evidence message doesn't contain evidence")?;
-        let endorsements = endorsed_evidence
+        let mut endorsements = endorsed_evidence
             .endorsements
             .context("endorsed evidence message doesn't contain endorsements")?;
-        let attestation_results = verifier
-            .verify(&evidence, &endorsements)
-            .context("couldn't verify endorsed evidence")?;
+        if let Some(collateral_provider) = collateral_provider {
+            collateral_provider
+                .add_collateral(&evidence, &mut endorsements)
+                .await
+                .context("couldn't get Intel TDX collateral")?;
+        }
+        let cached_results =
+            cache.and_then(|(cache, endpoint)| cache.get(endpoint, &evidence, &endorsements));
+        let attestation_results = match cached_results {
+            Some(attestation_results) => attestation_results,
+            None => {
+                let attestation_results = verifier
+                    .verify(&evidence, &endorsements)
+                    .context("couldn't verify endorsed evidence")?;
+                if let Some((cache, endpoint)) = cache {
+                    cache.insert(endpoint, &evidence, &endorsements, &attestation_results);
+                }
+                attestation_results
+            }
+        };
+
+        let server_encryption_public_key = attestation_results.encryption_public_key.to_vec();
+        let attestation_bundle = AttestationBundle {
+            evidence: Some(evidence),
+            endorsements: Some(endorsements),
+            reference_values: verifier.reference_values(),
+            captured_at_utc_millis: now_utc_millis(),
+        };
+        let resumable_session = transport.session_ticket().map(|ticket| ResumableSession {
+            // Count the lifetime from before the request, so that the ticket never
+            // expires later for the client than for the server.
+            expires_at: requested_at + Duration::from_secs(ticket.lifetime_seconds),
+            ticket: ticket.ticket,
+            server_encryption_public_key: server_encryption_public_key.clone(),
+            attestation_bundle: attestation_bundle.clone(),
+        });
 
         Ok(Self {
             transport,
-            server_encryption_public_key: attestation_results.encryption_public_key.to_vec(),
+            server_encryption_public_key,
+            resumable_session,
+            attestation_bundle,
+            peer_attester: None,
         })
     }
 
+    /// Like [`OakClient::create`], but resumes `session` instead of fetching
+    /// and verifying the evidence again, as long as the server accepts its
+    /// ticket. Falls back to a new session otherwise, e.g. if the ticket
+    /// expired or the enclave was restarted.
+    pub async fn resume(
+        mut transport: T,
+        session: &ResumableSession,
+        verifier: &dyn AttestationVerifier,
+    ) -> anyhow::Result<Self> {
+        if !session.is_expired() {
+            match transport.resume_session(&session.ticket).await {
+                Ok(()) => {
+                    return Ok(Self {
+                        transport,
+                        server_encryption_public_key: session.server_encryption_public_key.clone(),
+                        resumable_session: Some(session.clone()),
+                        attestation_bundle: session.attestation_bundle.clone(),
+                        peer_attester: None,
+                    })
+                }
+                Err(err) => log::info!("couldn't resume session, starting a new one: {:?}", err),
+            }
+        }
+        Self::create(transport, verifier).await
+    }
+
+    /// Sends the attestation created by `peer_attester` with each request, for
+    /// servers that require evidence from their peers.
+    pub fn with_peer_attester(mut self, peer_attester: PeerAttester) -> Self {
+        self.peer_attester = Some(peer_attester);
+        self
+    }
+
+    /// Returns the session to pass to [`OakClient::resume`] when reconnecting,
+    /// or `None` if the server doesn't support session resumption.
+    pub fn resumable_session(&self) -> Option<&ResumableSession> {
+        self.resumable_session.as_ref()
+    }
+
+    /// Returns the evidence and endorsements that the session was established
+    /// with, and the reference values they were verified against, to store
+    /// with [`crate::bundle::save`] and verify again later.
+    pub fn attestation_bundle(&self) -> &AttestationBundle {
+        &self.attestation_bundle
+    }
+
     pub async fn invoke(&mut self, request_body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.invoke_with(request_body, InvokeMode::Unary).await
+    }
+
+    /// Like [`OakClient::invoke`], but sends the request and receives the
+    /// response in chunks, for requests or responses larger than the maximum
+    /// message size of the transport.
+    pub async fn invoke_streaming(&mut self, request_body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.invoke_with(request_body, InvokeMode::Streaming).await
+    }
+
+    /// Like [`OakClient::invoke`], but for a request body that holds a batch
+    /// of independent requests, in the format expected by the application,
+    /// which are all encrypted together in one message.
+    pub async fn invoke_batch(&mut self, request_body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.invoke_with(request_body, InvokeMode::Batch).await
+    }
+
+    async fn invoke_with(
+        &mut self,
+        request_body: &[u8],
+        mode: InvokeMode,
+    ) -> anyhow::Result<Vec<u8>> {
         // Encrypt request.
         let mut client_encryptor = ClientEncryptor::create(&self.server_encryption_public_key)
+            .await
             .context("couldn't create encryptor")?;
+        // The peer attestation is sent as associated data, so that it is authenticated
+        // together with the request.
+        let associated_data = match self.peer_attester.as_ref() {
+            Some(peer_attester) => peer_attester
+                .attest(
+                    client_encryptor
+                        .serialized_encapsulated_public_key()
+                        .context("no encapsulated public key")?,
+                )
+                .await
+                .context("couldn't create peer attestation")?
+                .encode_to_vec(),
+            None => EMPTY_ASSOCIATED_DATA.to_vec(),
+        };
         let encrypted_request = client_encryptor
-            .encrypt(request_body, EMPTY_ASSOCIATED_DATA)
+            .encrypt(request_body, &associated_data)
+            .await
             .context("couldn't encrypt request")?;
 
         // Send request.
-        let encrypted_response = self
-            .transport
-            .invoke(&encrypted_request)
-            .await
-            .map_err(|error| anyhow!("couldn't send request: {:?}", error))?;
+        let encrypted_response = match mode {
+            InvokeMode::Unary => self.transport.invoke(&encrypted_request).await,
+            InvokeMode::Streaming => self.transport.invoke_streaming(&encrypted_request).await,
+            InvokeMode::Batch => self.transport.invoke_batch(&encrypted_request).await,
+        }
+        // Keep the cause, so that callers can tell transient transport errors apart.
+        .context("couldn't send request")?;
 
         // Decrypt response.
         // Currently we ignore the associated data.
         let (response, _) = client_encryptor
             .decrypt(&encrypted_response)
+            .await
             .context("client couldn't decrypt response")?;
 
         Ok(response)
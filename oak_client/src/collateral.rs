@@ -0,0 +1,191 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Fetching of the Intel DCAP collateral that TDX quotes are verified with,
+//! from the Intel Provisioning Certification Service (PCS) API.
+//!
+//! The collateral is signed by Intel, and verified against the root CA
+//! certificate in the reference values, so it can be fetched from any service
+//! implementing the API, e.g. a local caching service (PCCS), over plain HTTP.
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use anyhow::Context;
+use oak_attestation_verification::intel::{get_fmspc, TdQuote};
+use oak_proto_rust::oak::attestation::v1::{
+    endorsements, Endorsements, Evidence, IntelTdxCollateral, TeePlatform,
+};
+use web_time::Instant;
+
+const TCB_INFO_ISSUER_CHAIN_HEADER: &str = "TCB-Info-Issuer-Chain";
+const QE_IDENTITY_ISSUER_CHAIN_HEADER: &str = "SGX-Enclave-Identity-Issuer-Chain";
+
+/// Fetches the collateral of TDX platforms from a PCS API endpoint, and caches
+/// it by platform.
+pub struct PcsCollateralProvider {
+    base_uri: String,
+    refresh_interval: Duration,
+    cache: Mutex<HashMap<Vec<u8>, CachedCollateral>>,
+}
+
+struct CachedCollateral {
+    collateral: IntelTdxCollateral,
+    fetched_at: Instant,
+}
+
+impl PcsCollateralProvider {
+    /// Creates a provider that fetches collateral from the PCS API under
+    /// `base_uri`, e.g. `http://localhost:8081/tdx/certification/v4`, and
+    /// fetches it again once it is older than `refresh_interval`. The interval
+    /// should be shorter than the time between updates of the collateral,
+    /// which is a month for the Intel PCS.
+    pub fn new(base_uri: &str, refresh_interval: Duration) -> Self {
+        Self {
+            base_uri: base_uri.trim_end_matches('/').to_string(),
+            refresh_interval,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the collateral of the platforms identified by `fmspc`.
+    pub async fn get(&self, fmspc: &[u8]) -> anyhow::Result<IntelTdxCollateral> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(fmspc)
+            .filter(|cached| cached.fetched_at.elapsed() < self.refresh_interval)
+            .map(|cached| cached.collateral.clone());
+        if let Some(collateral) = cached {
+            return Ok(collateral);
+        }
+
+        let (tcb_info, tcb_info_issuer_chain) = self
+            .fetch(&format!("/tcb?fmspc={}", hex_encode(fmspc)), TCB_INFO_ISSUER_CHAIN_HEADER)
+            .await
+            .context("couldn't fetch TCB info")?;
+        let (qe_identity, qe_identity_issuer_chain) = self
+            .fetch("/qe/identity", QE_IDENTITY_ISSUER_CHAIN_HEADER)
+            .await
+            .context("couldn't fetch QE identity")?;
+        let collateral = IntelTdxCollateral {
+            tcb_info,
+            tcb_info_issuer_chain,
+            qe_identity,
+            qe_identity_issuer_chain,
+        };
+        self.cache.lock().unwrap().insert(
+            fmspc.to_vec(),
+            CachedCollateral { collateral: collateral.clone(), fetched_at: Instant::now() },
+        );
+        Ok(collateral)
+    }
+
+    /// Adds the collateral needed to verify `evidence` to `endorsements`, if
+    /// the evidence is from a TDX platform. Other evidence is left alone.
+    pub async fn add_collateral(
+        &self,
+        evidence: &Evidence,
+        endorsements: &mut Endorsements,
+    ) -> anyhow::Result<()> {
+        let root_layer = evidence.root_layer.as_ref().context("no root layer evidence")?;
+        if root_layer.platform() != TeePlatform::IntelTdx {
+            return Ok(());
+        }
+        let quote = TdQuote::parse(&root_layer.remote_attestation_report)
+            .context("invalid Intel TDX quote")?;
+        let collateral = self.get(&get_fmspc(&quote)?).await?;
+
+        let root_layer_endorsements =
+            match endorsements.r#type.as_mut().context("no endorsements")? {
+                endorsements::Type::OakRestrictedKernel(endorsements) => {
+                    &mut endorsements.root_layer
+                }
+                endorsements::Type::OakContainers(endorsements) => &mut endorsements.root_layer,
+                endorsements::Type::Cb(endorsements) => &mut endorsements.root_layer,
+            };
+        root_layer_endorsements.get_or_insert_with(Default::default).tdx_collateral =
+            Some(collateral);
+        Ok(())
+    }
+
+    /// Fetches `path` under the base URI, and returns the body of the response
+    /// and the issuer certificate chain in `issuer_chain_header`.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn fetch(
+        &self,
+        path: &str,
+        issuer_chain_header: &str,
+    ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let uri: hyper::Uri =
+            format!("{}{}", self.base_uri, path).parse().context("invalid collateral URI")?;
+        let response = hyper::Client::new()
+            .get(uri.clone())
+            .await
+            .with_context(|| format!("couldn't fetch {}", uri))?;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "couldn't fetch {}: {}",
+            uri,
+            response.status()
+        );
+        // The issuer chain is a URL-encoded list of PEM certificates.
+        let issuer_chain: Vec<u8> = percent_encoding::percent_decode(
+            response
+                .headers()
+                .get(issuer_chain_header)
+                .with_context(|| format!("no {} header", issuer_chain_header))?
+                .as_bytes(),
+        )
+        .collect();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .with_context(|| format!("couldn't read {}", uri))?;
+        Ok((body.to_vec(), issuer_chain))
+    }
+
+    /// Fetches `path` under the base URI, and returns the body of the response
+    /// and the issuer certificate chain in `issuer_chain_header`. The service
+    /// must allow cross-origin requests, and expose the header to them.
+    #[cfg(target_arch = "wasm32")]
+    async fn fetch(
+        &self,
+        path: &str,
+        issuer_chain_header: &str,
+    ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let uri = format!("{}{}", self.base_uri, path);
+        let response = crate::web::fetch("GET", &uri, None)
+            .await
+            .with_context(|| format!("couldn't fetch {}", uri))?;
+        anyhow::ensure!(response.ok(), "couldn't fetch {}: {}", uri, response.status());
+        // The issuer chain is a URL-encoded list of PEM certificates.
+        let issuer_chain = response
+            .headers()
+            .get(issuer_chain_header)
+            .ok()
+            .flatten()
+            .and_then(|issuer_chain| js_sys::decode_uri_component(&issuer_chain).ok())
+            .with_context(|| format!("no {} header", issuer_chain_header))?;
+        let body = crate::web::response_bytes(&response)
+            .await
+            .with_context(|| format!("couldn't read {}", uri))?;
+        Ok((body, String::from(issuer_chain).into_bytes()))
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
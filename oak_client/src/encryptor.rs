@@ -0,0 +1,57 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The encryptor of the requests of a session. Natively it wraps the HPKE
+//! implementation of `oak_crypto`, and in browsers it is backed by WebCrypto,
+//! whose operations are asynchronous, so both have an async interface.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use native::ClientEncryptor;
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) use crate::web::crypto::WebCryptoClientEncryptor as ClientEncryptor;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use oak_crypto::proto::oak::crypto::v1::{EncryptedRequest, EncryptedResponse};
+
+    pub(crate) struct ClientEncryptor(oak_crypto::encryptor::ClientEncryptor);
+
+    impl ClientEncryptor {
+        pub(crate) async fn create(serialized_server_public_key: &[u8]) -> anyhow::Result<Self> {
+            oak_crypto::encryptor::ClientEncryptor::create(serialized_server_public_key).map(Self)
+        }
+
+        pub(crate) fn serialized_encapsulated_public_key(&self) -> Option<&[u8]> {
+            self.0.serialized_encapsulated_public_key()
+        }
+
+        pub(crate) async fn encrypt(
+            &mut self,
+            plaintext: &[u8],
+            associated_data: &[u8],
+        ) -> anyhow::Result<EncryptedRequest> {
+            self.0.encrypt(plaintext, associated_data)
+        }
+
+        pub(crate) async fn decrypt(
+            &self,
+            encrypted_response: &EncryptedResponse,
+        ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+            self.0.decrypt(encrypted_response)
+        }
+    }
+}
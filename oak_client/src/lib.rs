@@ -20,7 +20,10 @@ pub mod proto {
             pub mod v1 {
                 #![allow(clippy::return_self_not_must_use)]
                 #![allow(clippy::large_enum_variant)]
+                #[cfg(not(target_arch = "wasm32"))]
                 tonic::include_proto!("oak.session.v1");
+                #[cfg(target_arch = "wasm32")]
+                include!(concat!(env!("OUT_DIR"), "/oak.session.v1.rs"));
             }
         }
         pub use oak_crypto::proto::oak::crypto;
@@ -28,6 +31,13 @@ pub mod proto {
     }
 }
 
+pub mod bundle;
+pub mod cache;
 pub mod client;
+pub mod collateral;
+mod encryptor;
+pub mod peer;
 pub mod transport;
 pub mod verifier;
+#[cfg(target_arch = "wasm32")]
+pub mod web;
@@ -0,0 +1,76 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Attestation of the client to servers that require evidence from their
+//! peers, e.g. when the client runs in another enclave.
+
+use anyhow::Context;
+use oak_proto_rust::oak::attestation::v1::{Evidence, PeerAttestation, RootLayerEndorsements};
+
+/// Signs messages with the signing key certified by the evidence of the
+/// client.
+#[async_trait::async_trait]
+pub trait PeerSigner {
+    /// Returns the signature over `message`, as the concatenation of the
+    /// big-endian `r` and `s` values of an ECDSA P-256 signature.
+    async fn sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+#[async_trait::async_trait]
+impl<S: oak_crypto::signer::Signer + Send + Sync> PeerSigner for S {
+    async fn sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(oak_crypto::signer::Signer::sign(self, message).signature)
+    }
+}
+
+/// Creates the attestation sent with each request, from the evidence of the
+/// client, the endorsements of its root layer and its signing key.
+pub struct PeerAttester {
+    evidence: Evidence,
+    root_layer_endorsements: RootLayerEndorsements,
+    signer: Box<dyn PeerSigner + Send + Sync>,
+}
+
+impl PeerAttester {
+    /// The `root_layer_endorsements` must contain the certificate or collateral
+    /// that the attestation report in the root layer of `evidence` is verified
+    /// with, as servers reject peers whose report they can't verify.
+    pub fn new(
+        evidence: Evidence,
+        root_layer_endorsements: RootLayerEndorsements,
+        signer: Box<dyn PeerSigner + Send + Sync>,
+    ) -> Self {
+        Self { evidence, root_layer_endorsements, signer }
+    }
+
+    /// Returns the attestation of a request with the encapsulated public key
+    /// `serialized_encapsulated_public_key`.
+    pub async fn attest(
+        &self,
+        serialized_encapsulated_public_key: &[u8],
+    ) -> anyhow::Result<PeerAttestation> {
+        let encapsulated_public_key_signature = self
+            .signer
+            .sign(serialized_encapsulated_public_key)
+            .await
+            .context("couldn't sign encapsulated public key")?;
+        Ok(PeerAttestation {
+            evidence: Some(self.evidence.clone()),
+            encapsulated_public_key_signature,
+            root_layer_endorsements: Some(self.root_layer_endorsements.clone()),
+        })
+    }
+}
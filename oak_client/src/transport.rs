@@ -14,33 +14,124 @@
 // limitations under the License.
 //
 
+#[cfg(not(target_arch = "wasm32"))]
 use anyhow::Context;
 use oak_crypto::proto::oak::crypto::v1::{EncryptedRequest, EncryptedResponse};
-use tonic::transport::Channel;
+use prost::Message;
+#[cfg(not(target_arch = "wasm32"))]
+use tonic::{transport::Channel, Streaming};
 
 use crate::proto::oak::session::v1::{
-    request_wrapper, response_wrapper, streaming_session_client::StreamingSessionClient,
-    EndorsedEvidence, GetEndorsedEvidenceRequest, InvokeRequest, RequestWrapper,
+    request_wrapper, EndorsedEvidence, InvokeRequestChunk, RequestWrapper, SessionTicket,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use crate::proto::oak::session::v1::{
+    response_wrapper, streaming_session_client::StreamingSessionClient, GetEndorsedEvidenceRequest,
+    InvokeBatchRequest, InvokeRequest, ResponseWrapper, ResumeSessionRequest,
+};
+
+/// Maximum size of the request chunks sent by
+/// [`Transport::invoke_streaming`], well below the default gRPC message size
+/// limit of 4 MiB.
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+/// Splits `encrypted_request` into the chunks sent by
+/// [`Transport::invoke_streaming`].
+pub(crate) fn request_chunks(encrypted_request: &EncryptedRequest) -> Vec<RequestWrapper> {
+    let encoded_request = encrypted_request.encode_to_vec();
+    let mut chunks: Vec<&[u8]> = encoded_request.chunks(MAX_CHUNK_SIZE).collect();
+    if chunks.is_empty() {
+        // An empty request is still sent as a single, last chunk.
+        chunks.push(&[]);
+    }
+    let last_index = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| RequestWrapper {
+            request: Some(request_wrapper::Request::InvokeRequestChunk(InvokeRequestChunk {
+                data: chunk.to_vec(),
+                last: index == last_index,
+            })),
+        })
+        .collect()
+}
 
+#[cfg(not(target_arch = "wasm32"))]
 pub struct GrpcStreamingTransport {
     rpc_client: StreamingSessionClient<Channel>,
+    /// The ticket issued with the last endorsed evidence.
+    issued_ticket: Option<SessionTicket>,
+    /// The ticket of the resumed session, if any. As every request uses a new
+    /// stream, each of them resumes the session first.
+    resumed_ticket: Option<Vec<u8>>,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl GrpcStreamingTransport {
     pub fn new(rpc_client: StreamingSessionClient<Channel>) -> Self {
-        Self { rpc_client }
+        Self { rpc_client, issued_ticket: None, resumed_ticket: None }
+    }
+
+    // Sends `requests` on a new stream, resuming the session first if it was
+    // resumed, and returns the stream of responses to them.
+    async fn open_session_stream(
+        &mut self,
+        requests: Vec<RequestWrapper>,
+    ) -> anyhow::Result<Streaming<ResponseWrapper>> {
+        let resume_session_request = self.resumed_ticket.as_ref().map(|ticket| RequestWrapper {
+            request: Some(request_wrapper::Request::ResumeSessionRequest(ResumeSessionRequest {
+                ticket: ticket.clone(),
+            })),
+        });
+        let resumed = resume_session_request.is_some();
+        let mut response_stream = self
+            .rpc_client
+            .stream(futures_util::stream::iter(resume_session_request.into_iter().chain(requests)))
+            .await
+            .context("couldn't send invoke request")?
+            .into_inner();
+
+        if resumed {
+            response_stream
+                .message()
+                .await
+                .context("gRPC server error when resuming session")?
+                .context("received empty response stream")?;
+        }
+        Ok(response_stream)
     }
 }
 
-#[async_trait::async_trait]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
 pub trait Transport {
     async fn invoke(
         &mut self,
         encrypted_request: &EncryptedRequest,
     ) -> anyhow::Result<EncryptedResponse>;
+
+    /// Like [`Transport::invoke`], but sends the request and receives the
+    /// response in chunks, so that neither is limited by the maximum message
+    /// size of the transport.
+    async fn invoke_streaming(
+        &mut self,
+        encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        self.invoke(encrypted_request).await
+    }
+
+    /// Like [`Transport::invoke`], but for a request that holds a batch of
+    /// requests, and whose response holds the batch of their responses.
+    async fn invoke_batch(
+        &mut self,
+        _encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        Err(anyhow::anyhow!("batch invocations aren't supported"))
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[async_trait::async_trait]
 impl Transport for GrpcStreamingTransport {
     async fn invoke(
@@ -48,19 +139,16 @@ impl Transport for GrpcStreamingTransport {
         encrypted_request: &EncryptedRequest,
     ) -> anyhow::Result<EncryptedResponse> {
         let mut response_stream = self
-            .rpc_client
-            .stream(futures_util::stream::iter(vec![RequestWrapper {
+            .open_session_stream(vec![RequestWrapper {
                 #[allow(clippy::needless_update)]
                 request: Some(request_wrapper::Request::InvokeRequest(InvokeRequest {
                     encrypted_request: Some(encrypted_request.clone()),
                     ..Default::default()
                 })),
-            }]))
-            .await
-            .context("couldn't send invoke request")?
-            .into_inner();
+            }])
+            .await?;
 
-        // Read the next (and only) message from the response stream.
+        // Read the next (and only) invoke response from the response stream.
         let response_wrapper = response_stream
             .message()
             .await
@@ -79,13 +167,89 @@ impl Transport for GrpcStreamingTransport {
             .encrypted_response
             .context("InvokeResponse does not include an encrypted message")
     }
+
+    async fn invoke_batch(
+        &mut self,
+        encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        let mut response_stream = self
+            .open_session_stream(vec![RequestWrapper {
+                request: Some(request_wrapper::Request::InvokeBatchRequest(InvokeBatchRequest {
+                    encrypted_request: Some(encrypted_request.clone()),
+                })),
+            }])
+            .await?;
+
+        let response_wrapper = response_stream
+            .message()
+            .await
+            .context("gRPC server error when invoking method")?
+            .context("received empty response stream")?;
+
+        let Some(response_wrapper::Response::InvokeBatchResponse(invoke_batch_response)) =
+            response_wrapper.response
+        else {
+            return Err(anyhow::anyhow!(
+                "response_wrapper does not have a valid invoke_batch_response message"
+            ));
+        };
+
+        invoke_batch_response
+            .encrypted_response
+            .context("InvokeBatchResponse does not include an encrypted message")
+    }
+
+    async fn invoke_streaming(
+        &mut self,
+        encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        let mut response_stream =
+            self.open_session_stream(request_chunks(encrypted_request)).await?;
+
+        let mut encoded_response = Vec::new();
+        loop {
+            let response_wrapper = response_stream
+                .message()
+                .await
+                .context("gRPC server error when invoking method")?
+                .context("response stream ended before the last response chunk")?;
+            let Some(response_wrapper::Response::InvokeResponseChunk(chunk)) =
+                response_wrapper.response
+            else {
+                return Err(anyhow::anyhow!(
+                    "response_wrapper does not have a valid invoke_response_chunk message"
+                ));
+            };
+            encoded_response.extend_from_slice(&chunk.data);
+            if chunk.last {
+                break;
+            }
+        }
+
+        EncryptedResponse::decode(encoded_response.as_slice())
+            .context("couldn't decode encrypted response")
+    }
 }
 
-#[async_trait::async_trait]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
 pub trait EvidenceProvider {
     async fn get_endorsed_evidence(&mut self) -> anyhow::Result<EndorsedEvidence>;
+
+    /// Returns the ticket issued with the last endorsed evidence, if the server
+    /// supports session resumption.
+    fn session_ticket(&self) -> Option<SessionTicket> {
+        None
+    }
+
+    /// Resumes the session that `ticket` was issued for, so that subsequent
+    /// requests are handled by the enclave whose evidence came with it.
+    async fn resume_session(&mut self, _ticket: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("session resumption isn't supported"))
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[async_trait::async_trait]
 impl EvidenceProvider for GrpcStreamingTransport {
     async fn get_endorsed_evidence(&mut self) -> anyhow::Result<EndorsedEvidence> {
@@ -116,8 +280,45 @@ impl EvidenceProvider for GrpcStreamingTransport {
             ));
         };
 
+        // Requests are now encrypted for this evidence, rather than for the
+        // enclave of a previously resumed session.
+        self.resumed_ticket = None;
+        self.issued_ticket = get_endorsed_evidence_response.session_ticket;
         get_endorsed_evidence_response
             .endorsed_evidence
             .context("get_endorsed_evidence_response message doesn't contain endorsed evidence")
     }
+
+    fn session_ticket(&self) -> Option<SessionTicket> {
+        self.issued_ticket.clone()
+    }
+
+    async fn resume_session(&mut self, ticket: &[u8]) -> anyhow::Result<()> {
+        let mut response_stream = self
+            .rpc_client
+            .stream(futures_util::stream::iter(vec![RequestWrapper {
+                request: Some(request_wrapper::Request::ResumeSessionRequest(
+                    ResumeSessionRequest { ticket: ticket.to_vec() },
+                )),
+            }]))
+            .await
+            .context("couldn't resume session")?
+            .into_inner();
+
+        let response_wrapper = response_stream
+            .message()
+            .await
+            .context("gRPC server error when resuming session")?
+            .context("received empty response stream")?;
+
+        let Some(response_wrapper::Response::ResumeSessionResponse(_)) = response_wrapper.response
+        else {
+            return Err(anyhow::anyhow!(
+                "response_wrapper doesn't contain a valid resume_session_response message"
+            ));
+        };
+
+        self.resumed_ticket = Some(ticket.to_vec());
+        Ok(())
+    }
 }
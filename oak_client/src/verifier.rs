@@ -14,9 +14,25 @@
 // limitations under the License.
 //
 
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use anyhow::Context;
-use oak_attestation_verification::verifier::verify_dice_chain;
-use oak_proto_rust::oak::attestation::v1::{Endorsements, Evidence, ExtractedEvidence};
+use oak_attestation_verification::{
+    policy::{appraise, parse_policy_json},
+    util::{convert_pem_to_raw, looks_like_pem, verify_signature_raw},
+    verifier::{verify, verify_dice_chain},
+};
+use oak_proto_rust::oak::attestation::v1::{
+    AppraisalPolicy, Endorsements, Evidence, ExtractedEvidence, ReferenceValues,
+    ReferenceValuesRelease, SignedReferenceValues,
+};
+use prost::Message;
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub trait AttestationVerifier {
     fn verify(
@@ -24,6 +40,40 @@ pub trait AttestationVerifier {
         evidence: &Evidence,
         endorsements: &Endorsements,
     ) -> anyhow::Result<ExtractedEvidence>;
+
+    /// Returns the reference values that evidence is currently verified
+    /// against, to record them with the evidence, if there are any.
+    fn reference_values(&self) -> Option<ReferenceValues> {
+        None
+    }
+}
+
+impl<V: AttestationVerifier + ?Sized> AttestationVerifier for Box<V> {
+    fn verify(
+        &self,
+        evidence: &Evidence,
+        endorsements: &Endorsements,
+    ) -> anyhow::Result<ExtractedEvidence> {
+        (**self).verify(evidence, endorsements)
+    }
+
+    fn reference_values(&self) -> Option<ReferenceValues> {
+        (**self).reference_values()
+    }
+}
+
+impl<V: AttestationVerifier + ?Sized> AttestationVerifier for Arc<V> {
+    fn verify(
+        &self,
+        evidence: &Evidence,
+        endorsements: &Endorsements,
+    ) -> anyhow::Result<ExtractedEvidence> {
+        (**self).verify(evidence, endorsements)
+    }
+
+    fn reference_values(&self) -> Option<ReferenceValues> {
+        (**self).reference_values()
+    }
 }
 
 /// Verifier that doesn't check the Evidence against Reference Values and only
@@ -37,6 +87,208 @@ impl AttestationVerifier for InsecureAttestationVerifier {
     }
 }
 
+/// Source of signed reference values, e.g. a file or a remote endpoint. The
+/// reference values are only trusted if they are signed with the key given to
+/// [`ReferenceValueVerifier`], so the source itself doesn't need to be trusted.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+pub trait ReferenceValueProvider: Send + Sync {
+    async fn fetch(&self) -> anyhow::Result<SignedReferenceValues>;
+}
+
+/// Reads a serialized [`SignedReferenceValues`] message from a file.
+pub struct FileReferenceValueProvider {
+    pub path: PathBuf,
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl ReferenceValueProvider for FileReferenceValueProvider {
+    async fn fetch(&self) -> anyhow::Result<SignedReferenceValues> {
+        let bytes = fs::read(&self.path)
+            .with_context(|| format!("couldn't read reference values {}", self.path.display()))?;
+        SignedReferenceValues::decode(bytes.as_slice())
+            .context("couldn't decode signed reference values")
+    }
+}
+
+/// Fetches a serialized [`SignedReferenceValues`] message with an HTTP GET
+/// request. In browsers, use `web::FetchReferenceValueProvider` instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct HttpReferenceValueProvider {
+    pub uri: hyper::Uri,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait::async_trait]
+impl ReferenceValueProvider for HttpReferenceValueProvider {
+    async fn fetch(&self) -> anyhow::Result<SignedReferenceValues> {
+        let response = hyper::Client::new()
+            .get(self.uri.clone())
+            .await
+            .with_context(|| format!("couldn't fetch reference values from {}", self.uri))?;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "couldn't fetch reference values from {}: {}",
+            self.uri,
+            response.status()
+        );
+        let bytes = hyper::body::to_bytes(response.into_body())
+            .await
+            .context("couldn't read reference values")?;
+        SignedReferenceValues::decode(bytes).context("couldn't decode signed reference values")
+    }
+}
+
+/// Verifier that checks the evidence against reference values loaded from a
+/// [`ReferenceValueProvider`].
+///
+/// The reference values are fetched again by
+/// [`ReferenceValueVerifier::refresh`] once they are older than the refresh
+/// interval. Releases older than the current one are rejected, and verification
+/// fails once the current release has expired.
+pub struct ReferenceValueVerifier {
+    provider: Box<dyn ReferenceValueProvider>,
+    signing_public_key: Vec<u8>,
+    refresh_interval: Duration,
+    current: Mutex<Option<LoadedRelease>>,
+}
+
+struct LoadedRelease {
+    release: ReferenceValuesRelease,
+    fetched_at: Instant,
+}
+
+impl ReferenceValueVerifier {
+    /// Creates a verifier that trusts reference values signed with the
+    /// PEM-encoded P-256 `signing_public_key`, and loads the current reference
+    /// values from `provider`.
+    pub async fn load(
+        provider: Box<dyn ReferenceValueProvider>,
+        signing_public_key: &str,
+        refresh_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(looks_like_pem(signing_public_key), "the signing key must be PEM-encoded");
+        let verifier = Self {
+            provider,
+            signing_public_key: convert_pem_to_raw(signing_public_key)?,
+            refresh_interval,
+            current: Mutex::new(None),
+        };
+        verifier.refresh().await?;
+        Ok(verifier)
+    }
+
+    /// Fetches the reference values again if they are older than the refresh
+    /// interval. If that fails, the current reference values are kept until
+    /// they expire.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let current_version = {
+            let current = self.current.lock().unwrap();
+            match current.as_ref() {
+                Some(loaded) if loaded.fetched_at.elapsed() < self.refresh_interval => {
+                    return Ok(())
+                }
+                loaded => loaded.map(|loaded| loaded.release.version),
+            }
+        };
+        let signed_reference_values = self.provider.fetch().await?;
+        verify_signature_raw(
+            &signed_reference_values.signature,
+            &signed_reference_values.release,
+            &self.signing_public_key,
+        )
+        .context("invalid reference values signature")?;
+        let release = ReferenceValuesRelease::decode(signed_reference_values.release.as_slice())
+            .context("couldn't decode reference values release")?;
+        if let Some(current_version) = current_version {
+            anyhow::ensure!(
+                release.version >= current_version,
+                "reference values version {} is older than the current version {}",
+                release.version,
+                current_version
+            );
+        }
+        anyhow::ensure!(release.expiry_utc_millis > now_utc_millis(), "reference values expired");
+        *self.current.lock().unwrap() = Some(LoadedRelease { release, fetched_at: Instant::now() });
+        Ok(())
+    }
+
+    /// Identifies the current reference values, e.g. to use as
+    /// [`crate::cache::ReverificationPolicy::reference_values_id`].
+    pub fn reference_values_id(&self) -> Vec<u8> {
+        let current = self.current.lock().unwrap();
+        current
+            .as_ref()
+            .map_or_else(Vec::new, |loaded| loaded.release.version.to_be_bytes().to_vec())
+    }
+}
+
+impl AttestationVerifier for ReferenceValueVerifier {
+    fn verify(
+        &self,
+        evidence: &Evidence,
+        endorsements: &Endorsements,
+    ) -> anyhow::Result<ExtractedEvidence> {
+        let now_utc_millis = now_utc_millis();
+        let current = self.current.lock().unwrap();
+        let release = &current.as_ref().context("reference values haven't been loaded")?.release;
+        anyhow::ensure!(release.expiry_utc_millis > now_utc_millis, "reference values expired");
+        let reference_values =
+            release.reference_values.as_ref().context("release has no reference values")?;
+        verify(now_utc_millis, evidence, endorsements, reference_values)
+    }
+
+    fn reference_values(&self) -> Option<ReferenceValues> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().and_then(|loaded| loaded.release.reference_values.clone())
+    }
+}
+
+/// Verifier that additionally appraises the evidence accepted by another
+/// verifier against an [`AppraisalPolicy`], e.g. to only accept known kernels
+/// without maintaining full reference values.
+pub struct PolicyVerifier<V> {
+    inner: V,
+    policy: AppraisalPolicy,
+}
+
+impl<V: AttestationVerifier> PolicyVerifier<V> {
+    pub fn new(inner: V, policy: AppraisalPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    /// Creates a verifier with the policy in a JSON file.
+    pub fn load(inner: V, policy_path: &Path) -> anyhow::Result<Self> {
+        let json = fs::read(policy_path)
+            .with_context(|| format!("couldn't read policy {}", policy_path.display()))?;
+        Ok(Self::new(inner, parse_policy_json(&json)?))
+    }
+}
+
+impl<V: AttestationVerifier> AttestationVerifier for PolicyVerifier<V> {
+    fn verify(
+        &self,
+        evidence: &Evidence,
+        endorsements: &Endorsements,
+    ) -> anyhow::Result<ExtractedEvidence> {
+        let extracted_evidence = self.inner.verify(evidence, endorsements)?;
+        appraise(&self.policy, &extracted_evidence, endorsements)
+            .context("evidence doesn't satisfy the attestation policy")?;
+        Ok(extracted_evidence)
+    }
+
+    fn reference_values(&self) -> Option<ReferenceValues> {
+        self.inner.reference_values()
+    }
+}
+
+pub(crate) fn now_utc_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| i64::try_from(duration.as_millis()).unwrap_or(i64::MAX))
+}
+
 pub fn extract_encryption_public_key(evidence: &Evidence) -> anyhow::Result<Vec<u8>> {
     let attestation_results =
         verify_dice_chain(evidence).context("couldn't verify the DICE chain")?;
@@ -0,0 +1,107 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Support for running the client in browsers, when built for
+//! `wasm32-unknown-unknown`.
+//!
+//! Browsers can't make gRPC requests, so the client talks to the session
+//! gateway of the launcher instead, with [`transport::GatewayTransport`]. The
+//! requests are encrypted with WebCrypto, and the evidence is verified in the
+//! browser, so web applications don't need a trusted proxy to talk to the
+//! enclave. Everything works both in windows and in workers.
+
+pub mod crypto;
+pub mod transport;
+
+use anyhow::{anyhow, Context};
+use oak_proto_rust::oak::attestation::v1::SignedReferenceValues;
+use prost::Message;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Request, RequestInit, Response, WorkerGlobalScope};
+
+use crate::verifier::ReferenceValueProvider;
+
+/// Fetches a serialized [`SignedReferenceValues`] message with the Fetch API.
+/// The server must allow cross-origin requests if it isn't the origin of the
+/// web application.
+pub struct FetchReferenceValueProvider {
+    pub url: String,
+}
+
+#[async_trait::async_trait(?Send)]
+impl ReferenceValueProvider for FetchReferenceValueProvider {
+    async fn fetch(&self) -> anyhow::Result<SignedReferenceValues> {
+        let response = fetch("GET", &self.url, None)
+            .await
+            .with_context(|| format!("couldn't fetch reference values from {}", self.url))?;
+        anyhow::ensure!(
+            response.ok(),
+            "couldn't fetch reference values from {}: {}",
+            self.url,
+            response.status()
+        );
+        let bytes = response_bytes(&response).await.context("couldn't read reference values")?;
+        SignedReferenceValues::decode(bytes.as_slice())
+            .context("couldn't decode signed reference values")
+    }
+}
+
+/// Sends an HTTP request with the Fetch API, and returns the response. Only
+/// fails if there is no response, e.g. because of a network error.
+pub(crate) async fn fetch(
+    method: &str,
+    url: &str,
+    body: Option<&[u8]>,
+) -> anyhow::Result<Response> {
+    let init = RequestInit::new();
+    init.set_method(method);
+    if let Some(body) = body {
+        init.set_body(&js_sys::Uint8Array::from(body));
+    }
+    let request = Request::new_with_str_and_init(url, &init).map_err(js_error)?;
+    if body.is_some() {
+        request.headers().set("content-type", "application/x-protobuf").map_err(js_error)?;
+    }
+    let global = js_sys::global();
+    let promise = match global.dyn_ref::<web_sys::Window>() {
+        Some(window) => window.fetch_with_request(&request),
+        None => global.unchecked_ref::<WorkerGlobalScope>().fetch_with_request(&request),
+    };
+    JsFuture::from(promise).await.map_err(js_error)?.dyn_into().map_err(js_error)
+}
+
+/// Reads the whole body of `response`.
+pub(crate) async fn response_bytes(response: &Response) -> anyhow::Result<Vec<u8>> {
+    let buffer =
+        JsFuture::from(response.array_buffer().map_err(js_error)?).await.map_err(js_error)?;
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
+/// Reads the body of `response` as text.
+pub(crate) async fn response_text(response: &Response) -> anyhow::Result<String> {
+    let text = JsFuture::from(response.text().map_err(js_error)?).await.map_err(js_error)?;
+    text.as_string().context("response body isn't text")
+}
+
+/// Converts a JavaScript exception to an error. The exception itself can't be
+/// kept, as errors must be `Send`.
+pub(crate) fn js_error(error: JsValue) -> anyhow::Error {
+    match error.dyn_ref::<js_sys::Error>() {
+        Some(error) => anyhow!("{}", String::from(error.message())),
+        None => anyhow!("{:?}", error),
+    }
+}
@@ -0,0 +1,466 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The client side of the Oak HPKE scheme, implemented with WebCrypto.
+//!
+//! This follows `oak_crypto::hpke`: HPKE in base mode, with
+//! DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and AES-256-GCM, where the request
+//! and response keys are both exported from the HPKE context. WebCrypto has no
+//! HPKE, so the key schedule of RFC9180 is implemented here on top of HMAC.
+//! The ephemeral private key and the AEAD keys are non-extractable WebCrypto
+//! keys, but the shared secret and every secret derived from it in the key
+//! schedule, including the raw request and response keys, pass through the
+//! memory of the Wasm module.
+//! X25519 requires a recent browser (Chrome 133, Firefox 130 or Safari 17).
+//! <https://www.rfc-editor.org/rfc/rfc9180.html>
+
+use anyhow::Context;
+use js_sys::{Array, Uint8Array};
+use oak_crypto::proto::oak::crypto::v1::{
+    AeadEncryptedMessage, EncryptedRequest, EncryptedResponse,
+};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    AesGcmParams, Crypto, CryptoKey, EcdhKeyDeriveParams, HmacImportParams, SubtleCrypto,
+};
+
+use super::js_error;
+
+/// Must be the same as `oak_crypto::hpke::OAK_HPKE_INFO`.
+const OAK_HPKE_INFO: &[u8] = b"Oak Hybrid Public Key Encryption v1";
+
+/// Identifiers of the algorithms, from the IANA registry.
+/// <https://www.rfc-editor.org/rfc/rfc9180.html#name-iana-considerations>
+const KEM_ID: u16 = 0x0020;
+const KDF_ID: u16 = 0x0001;
+const AEAD_ID: u16 = 0x0002;
+
+const MODE_BASE: u8 = 0x00;
+
+/// Represents `N_h` from RFC9180.
+const HASH_SIZE_BYTES: usize = 32;
+/// Represents `N_k` from RFC9180.
+const AEAD_KEY_SIZE_BYTES: usize = 32;
+/// Represents `N_n` from RFC9180.
+const AEAD_NONCE_SIZE_BYTES: usize = 12;
+
+/// Encrypts the requests of a session and decrypts their responses, like
+/// `oak_crypto::encryptor::ClientEncryptor`, but with asynchronous operations
+/// as WebCrypto requires.
+pub struct WebCryptoClientEncryptor {
+    /// Only sent in the initial request message of the session.
+    serialized_encapsulated_public_key: Option<Vec<u8>>,
+    request_key: CryptoKey,
+    response_key: CryptoKey,
+}
+
+impl WebCryptoClientEncryptor {
+    /// Creates an HPKE crypto context by generating a new ephemeral key pair.
+    /// The `serialized_server_public_key` must be a raw X25519 public key.
+    pub async fn create(serialized_server_public_key: &[u8]) -> anyhow::Result<Self> {
+        let crypto = crypto()?;
+        let subtle = crypto.subtle();
+
+        // The private key can't be exported, but the public key always can.
+        let ephemeral_key_pair: JsValue = promise(subtle.generate_key_with_str(
+            "X25519",
+            false,
+            &Array::of1(&JsValue::from_str("deriveBits")),
+        ))
+        .await
+        .context("couldn't generate ephemeral key pair")?;
+        let ephemeral_private_key = key_pair_member(&ephemeral_key_pair, "privateKey")?;
+        let ephemeral_public_key = key_pair_member(&ephemeral_key_pair, "publicKey")?;
+        let serialized_encapsulated_public_key =
+            bytes(promise(subtle.export_key("raw", &ephemeral_public_key)).await?);
+        let shared_secret = encapsulate(
+            &subtle,
+            serialized_server_public_key,
+            &ephemeral_private_key,
+            &serialized_encapsulated_public_key,
+        )
+        .await?;
+
+        // Export the request and response keys, as `oak_crypto::hpke` does.
+        // <https://www.rfc-editor.org/rfc/rfc9180.html#name-secret-export>
+        let suite_id = hpke_suite_id(AEAD_ID);
+        let exporter_secret =
+            exporter_secret(&subtle, &suite_id, &shared_secret, OAK_HPKE_INFO).await?;
+        let request_key = labeled_expand(
+            &subtle,
+            &suite_id,
+            &exporter_secret,
+            b"sec",
+            b"request_key",
+            AEAD_KEY_SIZE_BYTES,
+        )
+        .await?;
+        let response_key = labeled_expand(
+            &subtle,
+            &suite_id,
+            &exporter_secret,
+            b"sec",
+            b"response_key",
+            AEAD_KEY_SIZE_BYTES,
+        )
+        .await?;
+
+        Ok(Self {
+            serialized_encapsulated_public_key: Some(serialized_encapsulated_public_key),
+            request_key: import_aead_key(&subtle, &request_key).await?,
+            response_key: import_aead_key(&subtle, &response_key).await?,
+        })
+    }
+
+    /// Returns the encapsulated public key that will be sent with the next
+    /// request, or `None` if it was already sent.
+    pub fn serialized_encapsulated_public_key(&self) -> Option<&[u8]> {
+        self.serialized_encapsulated_public_key.as_deref()
+    }
+
+    /// Encrypts `plaintext` and authenticates `associated_data` using AEAD.
+    pub async fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> anyhow::Result<EncryptedRequest> {
+        let mut nonce = [0u8; AEAD_NONCE_SIZE_BYTES];
+        crypto()?.get_random_values_with_u8_array(&mut nonce).map_err(js_error)?;
+        let ciphertext = bytes(
+            promise(crypto()?.subtle().encrypt_with_object_and_u8_array(
+                &aead_params(&nonce, associated_data),
+                &self.request_key,
+                plaintext,
+            ))
+            .await
+            .context("couldn't encrypt request")?,
+        );
+
+        Ok(EncryptedRequest {
+            encrypted_message: Some(AeadEncryptedMessage {
+                nonce: nonce.to_vec(),
+                ciphertext,
+                associated_data: associated_data.to_vec(),
+            }),
+            // Encapsulated public key is only sent in the initial request message of the session.
+            serialized_encapsulated_public_key: self.serialized_encapsulated_public_key.take(),
+        })
+    }
+
+    /// Decrypts an [`EncryptedResponse`] using AEAD. Returns the plaintext and
+    /// associated data of the response.
+    pub async fn decrypt(
+        &self,
+        encrypted_response: &EncryptedResponse,
+    ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let encrypted_message = encrypted_response
+            .encrypted_message
+            .as_ref()
+            .context("response doesn't contain encrypted message")?;
+        anyhow::ensure!(
+            encrypted_message.nonce.len() == AEAD_NONCE_SIZE_BYTES,
+            "incorrect nonce size, expected {}, found {}",
+            AEAD_NONCE_SIZE_BYTES,
+            encrypted_message.nonce.len()
+        );
+        let plaintext = bytes(
+            promise(crypto()?.subtle().decrypt_with_object_and_u8_array(
+                &aead_params(&encrypted_message.nonce, &encrypted_message.associated_data),
+                &self.response_key,
+                &encrypted_message.ciphertext,
+            ))
+            .await
+            .context("couldn't decrypt response")?,
+        );
+        Ok((plaintext, encrypted_message.associated_data.to_vec()))
+    }
+}
+
+/// `Encap` of DHKEM(X25519, HKDF-SHA256) from RFC9180, with the ephemeral key
+/// pair given. Returns the shared secret.
+/// <https://www.rfc-editor.org/rfc/rfc9180.html#name-dh-based-kem-dhkem>
+async fn encapsulate(
+    subtle: &SubtleCrypto,
+    serialized_server_public_key: &[u8],
+    ephemeral_private_key: &CryptoKey,
+    serialized_encapsulated_public_key: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let server_public_key: CryptoKey = promise(subtle.import_key_with_str(
+        "raw",
+        &Uint8Array::from(serialized_server_public_key),
+        "X25519",
+        true,
+        &Array::new(),
+    ))
+    .await
+    .context("couldn't import server public key")?;
+    let dh = bytes(
+        promise(subtle.derive_bits_with_object(
+            &EcdhKeyDeriveParams::new("X25519", &server_public_key),
+            ephemeral_private_key,
+            (HASH_SIZE_BYTES * 8) as u32,
+        ))
+        .await
+        .context("couldn't derive shared secret")?,
+    );
+    let kem_suite_id = [b"KEM".as_slice(), KEM_ID.to_be_bytes().as_slice()].concat();
+    let kem_context = [serialized_encapsulated_public_key, serialized_server_public_key].concat();
+    let eae_prk = labeled_extract(subtle, &kem_suite_id, &[], b"eae_prk", &dh).await?;
+    labeled_expand(subtle, &kem_suite_id, &eae_prk, b"shared_secret", &kem_context, HASH_SIZE_BYTES)
+        .await
+}
+
+/// Returns the `suite_id` of the HPKE key schedule for the given AEAD.
+fn hpke_suite_id(aead_id: u16) -> Vec<u8> {
+    [
+        b"HPKE".as_slice(),
+        KEM_ID.to_be_bytes().as_slice(),
+        KDF_ID.to_be_bytes().as_slice(),
+        aead_id.to_be_bytes().as_slice(),
+    ]
+    .concat()
+}
+
+/// Derives the exporter secret with the key schedule of the base mode of
+/// RFC9180. The AEAD key and base nonce aren't needed, as all keys are
+/// exported.
+/// <https://www.rfc-editor.org/rfc/rfc9180.html#name-creating-the-encryption-co>
+async fn exporter_secret(
+    subtle: &SubtleCrypto,
+    suite_id: &[u8],
+    shared_secret: &[u8],
+    info: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let psk_id_hash = labeled_extract(subtle, suite_id, &[], b"psk_id_hash", &[]).await?;
+    let info_hash = labeled_extract(subtle, suite_id, &[], b"info_hash", info).await?;
+    let key_schedule_context =
+        [[MODE_BASE].as_slice(), psk_id_hash.as_slice(), info_hash.as_slice()].concat();
+    let secret = labeled_extract(subtle, suite_id, shared_secret, b"secret", &[]).await?;
+    labeled_expand(subtle, suite_id, &secret, b"exp", &key_schedule_context, HASH_SIZE_BYTES).await
+}
+
+fn crypto() -> anyhow::Result<Crypto> {
+    js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("crypto"))
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(|_| anyhow::anyhow!("WebCrypto isn't available"))
+}
+
+// Waits for a WebCrypto promise, and casts its result.
+async fn promise<T: JsCast>(promise: Result<js_sys::Promise, JsValue>) -> anyhow::Result<T> {
+    JsFuture::from(promise.map_err(js_error)?)
+        .await
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(|value| anyhow::anyhow!("unexpected WebCrypto result: {:?}", value))
+}
+
+fn bytes(buffer: js_sys::ArrayBuffer) -> Vec<u8> {
+    Uint8Array::new(&buffer).to_vec()
+}
+
+fn key_pair_member(key_pair: &JsValue, name: &str) -> anyhow::Result<CryptoKey> {
+    js_sys::Reflect::get(key_pair, &JsValue::from_str(name))
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(|_| anyhow::anyhow!("key pair has no {}", name))
+}
+
+async fn import_aead_key(subtle: &SubtleCrypto, key: &[u8]) -> anyhow::Result<CryptoKey> {
+    promise(subtle.import_key_with_str(
+        "raw",
+        &Uint8Array::from(key),
+        "AES-GCM",
+        false,
+        &Array::of2(&JsValue::from_str("encrypt"), &JsValue::from_str("decrypt")),
+    ))
+    .await
+    .context("couldn't import AEAD key")
+}
+
+fn aead_params(nonce: &[u8], associated_data: &[u8]) -> AesGcmParams {
+    let params = AesGcmParams::new("AES-GCM", &Uint8Array::from(nonce));
+    params.set_additional_data(&Uint8Array::from(associated_data));
+    params
+}
+
+async fn hmac(subtle: &SubtleCrypto, key: &[u8], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let key: CryptoKey = promise(subtle.import_key_with_object(
+        "raw",
+        &Uint8Array::from(key),
+        &HmacImportParams::new("HMAC", &JsValue::from_str("SHA-256")),
+        false,
+        &Array::of1(&JsValue::from_str("sign")),
+    ))
+    .await
+    .context("couldn't import HMAC key")?;
+    Ok(bytes(promise(subtle.sign_with_str_and_u8_array("HMAC", &key, data)).await?))
+}
+
+/// `LabeledExtract` from RFC9180, with HKDF-SHA256.
+async fn labeled_extract(
+    subtle: &SubtleCrypto,
+    suite_id: &[u8],
+    salt: &[u8],
+    label: &[u8],
+    ikm: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    // An empty salt is the same as a salt of `N_h` zeros, which WebCrypto
+    // accepts as an HMAC key.
+    let zeros = [0u8; HASH_SIZE_BYTES];
+    let salt = if salt.is_empty() { zeros.as_slice() } else { salt };
+    let labeled_ikm = [b"HPKE-v1".as_slice(), suite_id, label, ikm].concat();
+    hmac(subtle, salt, &labeled_ikm).await
+}
+
+/// `LabeledExpand` from RFC9180, with HKDF-SHA256.
+async fn labeled_expand(
+    subtle: &SubtleCrypto,
+    suite_id: &[u8],
+    prk: &[u8],
+    label: &[u8],
+    info: &[u8],
+    length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let length_bytes = (length as u16).to_be_bytes();
+    let labeled_info =
+        [length_bytes.as_slice(), b"HPKE-v1".as_slice(), suite_id, label, info].concat();
+    // HKDF-Expand: T(i) = HMAC(prk, T(i - 1) || info || i).
+    let mut output = Vec::with_capacity(length);
+    let mut block = Vec::new();
+    let mut counter = 1u8;
+    while output.len() < length {
+        let input = [block.as_slice(), labeled_info.as_slice(), &[counter]].concat();
+        block = hmac(subtle, prk, &input).await?;
+        output.extend_from_slice(&block);
+        counter += 1;
+    }
+    output.truncate(length);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use oak_crypto::{encryption_key::generate_encryption_key_pair, encryptor::ServerEncryptor};
+    use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+    use super::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    // Test vector A.1.1 of RFC9180: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and
+    // AES-128-GCM in base mode. The KEM, KDF and exporter are the same as for
+    // AES-256-GCM, only the AEAD in the `suite_id` differs.
+    // <https://www.rfc-editor.org/rfc/rfc9180.html#appendix-A.1.1>
+    const TEST_AEAD_ID: u16 = 0x0001;
+    const TEST_INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    const TEST_SKEM: &str = "52c4a758a802cd8b936eceea314432798d5baf2d7e9235dc084ab1b9cfa2f736";
+    const TEST_PKEM: &str = "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431";
+    const TEST_PKRM: &str = "3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d";
+    const TEST_SHARED_SECRET: &str =
+        "fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc";
+    const TEST_EXPORTER_SECRET: &str =
+        "45ff1c2e220db587171952c0592d5f5ebe103f1561a2614e38f2ffd47e99e3f8";
+    // Exporter contexts and the 32-byte values exported for them.
+    const TEST_EXPORTS: [(&str, &str); 3] = [
+        ("", "3853fe2b4035195a573ffc53856e77058e15d9ea064de3e59f4961d0095250ee"),
+        ("00", "2e8f0b54673c7029649d4eb9d5e33bf1872cf76d623ff164ac185da9e88c21a5"),
+        (
+            "54657374436f6e74657874",
+            "e9e43065102c3836401bed8c3c3c75ae46be1639869391d62c61f1ec7af54931",
+        ),
+    ];
+    // The PKCS #8 prefix of a raw X25519 private key, as WebCrypto can't import
+    // raw private keys.
+    const X25519_PKCS8_PREFIX: &str = "302e020100300506032b656e04220420";
+
+    fn unhex(value: &str) -> Vec<u8> {
+        hex::decode(value).expect("invalid hex")
+    }
+
+    async fn import_private_key(subtle: &SubtleCrypto, private_key: &[u8]) -> CryptoKey {
+        let pkcs8 = [unhex(X25519_PKCS8_PREFIX).as_slice(), private_key].concat();
+        promise(subtle.import_key_with_str(
+            "pkcs8",
+            &Uint8Array::from(pkcs8.as_slice()),
+            "X25519",
+            false,
+            &Array::of1(&JsValue::from_str("deriveBits")),
+        ))
+        .await
+        .expect("couldn't import private key")
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_encapsulate_matches_rfc9180_test_vector() {
+        let subtle = crypto().unwrap().subtle();
+        let ephemeral_private_key = import_private_key(&subtle, &unhex(TEST_SKEM)).await;
+
+        let shared_secret =
+            encapsulate(&subtle, &unhex(TEST_PKRM), &ephemeral_private_key, &unhex(TEST_PKEM))
+                .await
+                .unwrap();
+        assert_eq!(shared_secret, unhex(TEST_SHARED_SECRET));
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_key_schedule_matches_rfc9180_test_vector() {
+        let subtle = crypto().unwrap().subtle();
+        let suite_id = hpke_suite_id(TEST_AEAD_ID);
+
+        let exporter_secret =
+            exporter_secret(&subtle, &suite_id, &unhex(TEST_SHARED_SECRET), &unhex(TEST_INFO))
+                .await
+                .unwrap();
+        assert_eq!(exporter_secret, unhex(TEST_EXPORTER_SECRET));
+
+        for (exporter_context, exported_value) in TEST_EXPORTS {
+            let exported = labeled_expand(
+                &subtle,
+                &suite_id,
+                &exporter_secret,
+                b"sec",
+                &unhex(exporter_context),
+                HASH_SIZE_BYTES,
+            )
+            .await
+            .unwrap();
+            assert_eq!(exported, unhex(exported_value), "context {}", exporter_context);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_round_trip_with_native_server_encryptor() {
+        let (server_encryption_key, server_public_key) = generate_encryption_key_pair();
+        let mut client_encryptor =
+            WebCryptoClientEncryptor::create(&server_public_key).await.unwrap();
+
+        let encrypted_request =
+            client_encryptor.encrypt(b"request", b"request data").await.unwrap();
+        assert!(client_encryptor.serialized_encapsulated_public_key().is_none());
+        let (server_encryptor, request, request_associated_data) =
+            ServerEncryptor::decrypt(&encrypted_request, &server_encryption_key).unwrap();
+        assert_eq!(request, b"request");
+        assert_eq!(request_associated_data, b"request data");
+
+        let encrypted_response = server_encryptor.encrypt(b"response", b"response data").unwrap();
+        let (response, response_associated_data) =
+            client_encryptor.decrypt(&encrypted_response).await.unwrap();
+        assert_eq!(response, b"response");
+        assert_eq!(response_associated_data, b"response data");
+    }
+}
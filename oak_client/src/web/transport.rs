@@ -0,0 +1,332 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Transport over the session gateway of the launcher, which carries the same
+//! session messages as the gRPC streaming session, either over a WebSocket or
+//! as HTTP POST requests.
+
+use anyhow::{anyhow, bail, Context};
+use futures_channel::mpsc;
+use futures_util::StreamExt;
+use js_sys::Uint8Array;
+use oak_crypto::proto::oak::crypto::v1::{EncryptedRequest, EncryptedResponse};
+use prost::Message;
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, Response, WebSocket};
+
+use super::{fetch, js_error, response_bytes, response_text};
+use crate::{
+    proto::oak::session::v1::{
+        request_wrapper, response_wrapper, EndorsedEvidence, GetEndorsedEvidenceRequest,
+        InvokeBatchRequest, InvokeRequest, RequestWrapper, ResponseWrapper, ResumeSessionRequest,
+        SessionTicket,
+    },
+    transport::{request_chunks, EvidenceProvider, Transport},
+};
+
+const WEBSOCKET_PATH: &str = "/session/v1/ws";
+const SESSIONS_PATH: &str = "/session/v1/sessions";
+
+/// Header of failed HTTP requests holding the class of the error.
+const ERROR_CLASS_HEADER: &str = "oak-error-class";
+
+/// Transport for browsers, over the session gateway of the launcher.
+///
+/// Unlike gRPC streams, a gateway session keeps being routed to the same
+/// enclave, so a resumed session doesn't need to be resumed again for each
+/// request.
+pub struct GatewayTransport {
+    connection: Connection,
+    /// The ticket issued with the last endorsed evidence.
+    issued_ticket: Option<SessionTicket>,
+}
+
+enum Connection {
+    /// Requests are sent one per HTTP request to the URL of the session.
+    Http {
+        session_url: String,
+    },
+    WebSocket(WebSocketConnection),
+}
+
+impl GatewayTransport {
+    /// Starts a session with HTTP POST requests to the gateway at `base_url`,
+    /// e.g. `https://example.com:8443`. Requests can't be sent in chunks, so
+    /// [`Transport::invoke_streaming`] sends them whole.
+    pub async fn connect_http(base_url: &str) -> anyhow::Result<Self> {
+        let sessions_url = format!("{}{}", base_url.trim_end_matches('/'), SESSIONS_PATH);
+        let response = ensure_success(fetch("POST", &sessions_url, None).await?)
+            .await
+            .context("couldn't start session")?;
+        let session_id = response_text(&response).await.context("couldn't read session id")?;
+        Ok(Self {
+            connection: Connection::Http { session_url: format!("{sessions_url}/{session_id}") },
+            issued_ticket: None,
+        })
+    }
+
+    /// Starts a session over a WebSocket to the gateway at `base_url`, e.g.
+    /// `https://example.com:8443`, which is used as `wss://example.com:8443`.
+    pub async fn connect_websocket(base_url: &str) -> anyhow::Result<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        let websocket_base_url = if let Some(rest) = base_url.strip_prefix("https://") {
+            format!("wss://{rest}")
+        } else if let Some(rest) = base_url.strip_prefix("http://") {
+            format!("ws://{rest}")
+        } else {
+            base_url.to_string()
+        };
+        let connection =
+            WebSocketConnection::connect(&format!("{websocket_base_url}{WEBSOCKET_PATH}")).await?;
+        Ok(Self { connection: Connection::WebSocket(connection), issued_ticket: None })
+    }
+
+    // Sends `request`, and returns the response to it.
+    async fn request(
+        &mut self,
+        request: request_wrapper::Request,
+    ) -> anyhow::Result<response_wrapper::Response> {
+        let request = RequestWrapper { request: Some(request) };
+        let response_wrapper = match &mut self.connection {
+            Connection::Http { session_url } => {
+                let response = ensure_success(
+                    fetch("POST", session_url, Some(&request.encode_to_vec())).await?,
+                )
+                .await?;
+                ResponseWrapper::decode(response_bytes(&response).await?.as_slice())
+                    .context("couldn't decode response")?
+            }
+            Connection::WebSocket(websocket) => {
+                websocket.send(&request)?;
+                websocket.receive().await?
+            }
+        };
+        response_wrapper.response.context("received empty response")
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Transport for GatewayTransport {
+    async fn invoke(
+        &mut self,
+        encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        #[allow(clippy::needless_update)]
+        let request = request_wrapper::Request::InvokeRequest(InvokeRequest {
+            encrypted_request: Some(encrypted_request.clone()),
+            ..Default::default()
+        });
+        let response_wrapper::Response::InvokeResponse(invoke_response) =
+            self.request(request).await?
+        else {
+            bail!("response_wrapper does not have a valid invoke_response message");
+        };
+        invoke_response
+            .encrypted_response
+            .context("InvokeResponse does not include an encrypted message")
+    }
+
+    async fn invoke_batch(
+        &mut self,
+        encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        let request = request_wrapper::Request::InvokeBatchRequest(InvokeBatchRequest {
+            encrypted_request: Some(encrypted_request.clone()),
+        });
+        let response_wrapper::Response::InvokeBatchResponse(invoke_batch_response) =
+            self.request(request).await?
+        else {
+            bail!("response_wrapper does not have a valid invoke_batch_response message");
+        };
+        invoke_batch_response
+            .encrypted_response
+            .context("InvokeBatchResponse does not include an encrypted message")
+    }
+
+    async fn invoke_streaming(
+        &mut self,
+        encrypted_request: &EncryptedRequest,
+    ) -> anyhow::Result<EncryptedResponse> {
+        let Connection::WebSocket(websocket) = &mut self.connection else {
+            return self.invoke(encrypted_request).await;
+        };
+        // Only the last chunk of the request is answered.
+        for request in request_chunks(encrypted_request) {
+            websocket.send(&request)?;
+        }
+
+        let mut encoded_response = Vec::new();
+        loop {
+            let Some(response_wrapper::Response::InvokeResponseChunk(chunk)) =
+                websocket.receive().await?.response
+            else {
+                bail!("response_wrapper does not have a valid invoke_response_chunk message");
+            };
+            encoded_response.extend_from_slice(&chunk.data);
+            if chunk.last {
+                break;
+            }
+        }
+
+        EncryptedResponse::decode(encoded_response.as_slice())
+            .context("couldn't decode encrypted response")
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl EvidenceProvider for GatewayTransport {
+    async fn get_endorsed_evidence(&mut self) -> anyhow::Result<EndorsedEvidence> {
+        let response_wrapper::Response::GetEndorsedEvidenceResponse(get_endorsed_evidence_response) =
+            self.request(request_wrapper::Request::GetEndorsedEvidenceRequest(
+                GetEndorsedEvidenceRequest {},
+            ))
+            .await?
+        else {
+            bail!(
+                "response_wrapper doesn't contain a valid get_endorsed_evidence_response message"
+            );
+        };
+        self.issued_ticket = get_endorsed_evidence_response.session_ticket;
+        get_endorsed_evidence_response
+            .endorsed_evidence
+            .context("get_endorsed_evidence_response message doesn't contain endorsed evidence")
+    }
+
+    fn session_ticket(&self) -> Option<SessionTicket> {
+        self.issued_ticket.clone()
+    }
+
+    async fn resume_session(&mut self, ticket: &[u8]) -> anyhow::Result<()> {
+        let response_wrapper::Response::ResumeSessionResponse(_) = self
+            .request(request_wrapper::Request::ResumeSessionRequest(ResumeSessionRequest {
+                ticket: ticket.to_vec(),
+            }))
+            .await?
+        else {
+            bail!("response_wrapper doesn't contain a valid resume_session_response message");
+        };
+        Ok(())
+    }
+}
+
+// Turns failed HTTP requests into errors, with the message and error class
+// returned by the gateway.
+async fn ensure_success(response: Response) -> anyhow::Result<Response> {
+    if response.ok() {
+        return Ok(response);
+    }
+    let error_class = response.headers().get(ERROR_CLASS_HEADER).ok().flatten();
+    let message = response_text(&response).await.unwrap_or_default();
+    Err(anyhow!(
+        "gateway returned {} ({}): {}",
+        response.status(),
+        error_class.as_deref().unwrap_or("unknown error class"),
+        message
+    ))
+}
+
+enum WebSocketEvent {
+    Open,
+    Message(Vec<u8>),
+    /// The WebSocket was closed, with the reason given by the gateway.
+    Closed(String),
+}
+
+/// A WebSocket whose events are received from a channel, as the browser
+/// delivers them to callbacks.
+struct WebSocketConnection {
+    websocket: WebSocket,
+    events: mpsc::UnboundedReceiver<WebSocketEvent>,
+    // The callbacks must live as long as the WebSocket can call them.
+    _on_open: Closure<dyn FnMut()>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl WebSocketConnection {
+    async fn connect(url: &str) -> anyhow::Result<Self> {
+        let websocket = WebSocket::new(url).map_err(js_error)?;
+        websocket.set_binary_type(BinaryType::Arraybuffer);
+        let (sender, events) = mpsc::unbounded();
+        let on_open = {
+            let sender = sender.clone();
+            Closure::<dyn FnMut()>::new(move || {
+                let _ = sender.unbounded_send(WebSocketEvent::Open);
+            })
+        };
+        let on_message = {
+            let sender = sender.clone();
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                // The gateway only sends binary messages, which are array buffers with the
+                // binary type set above.
+                if let Ok(buffer) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
+                    let _ = sender
+                        .unbounded_send(WebSocketEvent::Message(Uint8Array::new(&buffer).to_vec()));
+                }
+            })
+        };
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+            let _ = sender.unbounded_send(WebSocketEvent::Closed(event.reason()));
+        });
+        websocket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        websocket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        websocket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        let mut connection = Self {
+            websocket,
+            events,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+        };
+        match connection.events.next().await {
+            Some(WebSocketEvent::Open) => Ok(connection),
+            Some(WebSocketEvent::Closed(reason)) => {
+                Err(anyhow!("couldn't connect to {}: {}", url, reason))
+            }
+            _ => Err(anyhow!("couldn't connect to {}", url)),
+        }
+    }
+
+    fn send(&self, request: &RequestWrapper) -> anyhow::Result<()> {
+        self.websocket
+            .send_with_u8_array(&request.encode_to_vec())
+            .map_err(js_error)
+            .context("couldn't send request")
+    }
+
+    async fn receive(&mut self) -> anyhow::Result<ResponseWrapper> {
+        match self.events.next().await {
+            Some(WebSocketEvent::Message(message)) => {
+                ResponseWrapper::decode(message.as_slice()).context("couldn't decode response")
+            }
+            // The gateway closes the WebSocket with the error message if a request fails.
+            Some(WebSocketEvent::Closed(reason)) => Err(anyhow!("gateway error: {}", reason)),
+            Some(WebSocketEvent::Open) | None => Err(anyhow!("WebSocket closed")),
+        }
+    }
+}
+
+impl Drop for WebSocketConnection {
+    fn drop(&mut self) {
+        // The callbacks are dropped with the connection, so the WebSocket must not
+        // call them anymore.
+        self.websocket.set_onopen(None);
+        self.websocket.set_onmessage(None);
+        self.websocket.set_onclose(None);
+        let _ = self.websocket.close();
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright 2024 The Project Oak Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef OAK_CLIENT_H
+#define OAK_CLIENT_H
+
+/* Generated by cbindgen from oak_client_ffi. Don't edit this file manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of a call.
+typedef enum OakStatus {
+  OAK_STATUS_OK = 0,
+  // An argument was null, or wasn't valid UTF-8 where a string was
+  // expected.
+  OAK_STATUS_INVALID_ARGUMENT = 1,
+  // The call failed, e.g. because the server couldn't be reached or its
+  // evidence wasn't verified.
+  OAK_STATUS_ERROR = 2,
+  // The call panicked. The objects it was given must not be used anymore.
+  OAK_STATUS_INTERNAL = 3,
+} OakStatus;
+
+// Attested session with an Oak server.
+typedef struct OakClient OakClient;
+
+// Verifier of the evidence of the servers that clients connect to.
+typedef struct OakVerifier OakVerifier;
+
+// Bytes allocated by the bindings, which must be released with
+// `oak_buffer_free`.
+typedef struct OakBuffer {
+  uint8_t *data;
+  uintptr_t len;
+} OakBuffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif  // __cplusplus
+
+// Returns the message of the last error on the calling thread. The message
+// is owned by the bindings, and is valid until the next call on the same
+// thread.
+const char *oak_last_error(void);
+
+// Creates a verifier that accepts any evidence. Sessions are still
+// encrypted, but the server isn't authenticated, so this must only be used
+// for testing.
+//
+// # Safety
+//
+// `verifier_out` must be valid for writes.
+OakStatus oak_verifier_create_insecure(OakVerifier **verifier_out);
+
+// Creates a verifier that verifies evidence against the serialized
+// `SignedReferenceValues` message in `signed_reference_values`, which must
+// be signed with the PEM-encoded P-256 `signing_public_key_pem`. The
+// reference values are only read once, so the verifier must be recreated
+// when they are updated.
+//
+// # Safety
+//
+// `signed_reference_values` must point to `signed_reference_values_len`
+// readable bytes, `signing_public_key_pem` must be a nul-terminated string,
+// and `verifier_out` must be valid for writes.
+OakStatus oak_verifier_create_with_reference_values(const uint8_t *signed_reference_values,
+                                                    uintptr_t signed_reference_values_len,
+                                                    const char *signing_public_key_pem,
+                                                    OakVerifier **verifier_out);
+
+// Adds the appraisal policy in `policy_json` to `verifier`, so that evidence
+// is only accepted if it also satisfies the policy. The verifier is left
+// unchanged if the call fails.
+//
+// # Safety
+//
+// `verifier` must have been created by the bindings and not freed yet, and
+// `policy_json` must be a nul-terminated string.
+OakStatus oak_verifier_add_policy(OakVerifier *verifier, const char *policy_json);
+
+// Releases `verifier`. Does nothing if it is null.
+//
+// # Safety
+//
+// `verifier` must have been created by the bindings and not freed yet.
+void oak_verifier_free(OakVerifier *verifier);
+
+// Connects to the server at `uri`, e.g. `http://localhost:8080`, verifies
+// its evidence with `verifier`, and establishes an encrypted session with
+// it. The verifier is only used while the session is established, so it can
+// be freed or reused right after.
+//
+// # Safety
+//
+// `uri` must be a nul-terminated string, `verifier` must have been created
+// by the bindings and not freed yet, and `client_out` must be valid for
+// writes.
+OakStatus oak_client_create(const char *uri, OakVerifier *verifier, OakClient **client_out);
+
+// Sends the `request_len` bytes of `request` to the server over the
+// encrypted session of `client`, and stores its decrypted response in
+// `response_out`, which must be released with `oak_buffer_free`.
+//
+// # Safety
+//
+// `client` must have been created by the bindings and not freed yet,
+// `request` must point to `request_len` readable bytes, and `response_out`
+// must be valid for writes.
+OakStatus oak_client_invoke(OakClient *client,
+                            const uint8_t *request,
+                            uintptr_t request_len,
+                            OakBuffer *response_out);
+
+// Closes the session of `client`, and releases it. Does nothing if it is
+// null.
+//
+// # Safety
+//
+// `client` must have been created by the bindings and not freed yet.
+void oak_client_free(OakClient *client);
+
+// Releases the bytes of `buffer`. Does nothing if they are null.
+//
+// # Safety
+//
+// `buffer` must have been returned by the bindings and not freed yet.
+void oak_buffer_free(OakBuffer buffer);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* OAK_CLIENT_H */
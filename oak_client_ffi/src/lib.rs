@@ -0,0 +1,528 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! C bindings of `oak_client`, so that applications in other languages can
+//! establish attested sessions with Oak servers without reimplementing the
+//! handshake and the encryption of requests.
+//!
+//! All functions return an [`OakStatus`], and on failure `oak_last_error`
+//! describes the error. Objects created by the bindings are owned by the
+//! caller, and must be released with the matching `*_free` function. Calls
+//! block until they complete; objects can be used from any thread, but not
+//! from several threads at once.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    mem::MaybeUninit,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use oak_attestation_verification::policy::parse_policy_json;
+use oak_client::{
+    client::OakClient as Client,
+    proto::oak::session::v1::streaming_session_client::StreamingSessionClient,
+    transport::GrpcStreamingTransport,
+    verifier::{
+        AttestationVerifier, InsecureAttestationVerifier, PolicyVerifier, ReferenceValueProvider,
+        ReferenceValueVerifier,
+    },
+};
+use oak_proto_rust::oak::attestation::v1::SignedReferenceValues;
+use prost::Message;
+use tokio::runtime::Runtime;
+use tonic::transport::Channel;
+
+/// Result of a call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OakStatus {
+    Ok = 0,
+    /// An argument was null, or wasn't valid UTF-8 where a string was
+    /// expected.
+    InvalidArgument = 1,
+    /// The call failed, e.g. because the server couldn't be reached or its
+    /// evidence wasn't verified.
+    Error = 2,
+    /// The call panicked. The objects it was given must not be used anymore.
+    Internal = 3,
+}
+
+/// Bytes allocated by the bindings, which must be released with
+/// `oak_buffer_free`.
+#[repr(C)]
+pub struct OakBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl OakBuffer {
+    fn new(bytes: Vec<u8>) -> Self {
+        let len = bytes.len();
+        let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        Self { data, len }
+    }
+}
+
+/// Verifier of the evidence of the servers that clients connect to.
+pub struct OakVerifier {
+    inner: Arc<dyn AttestationVerifier + Send + Sync>,
+}
+
+/// Attested session with an Oak server.
+pub struct OakClient {
+    inner: Client<GrpcStreamingTransport>,
+}
+
+enum Error {
+    InvalidArgument(String),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        Error::Failed(error)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+// The runtime that runs the calls, which keeps running the connections between
+// them.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().expect("couldn't create tokio runtime"))
+}
+
+// Runs `call`, turning its errors and panics into a status, as neither can
+// cross the C ABI.
+fn run(call: impl FnOnce() -> Result<(), Error>) -> OakStatus {
+    let (status, message) = match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => return OakStatus::Ok,
+        Ok(Err(Error::InvalidArgument(message))) => (OakStatus::InvalidArgument, message),
+        Ok(Err(Error::Failed(error))) => (OakStatus::Error, format!("{:#}", error)),
+        Err(_) => (OakStatus::Internal, "oak_client_ffi panicked".to_string()),
+    };
+    // Interior nul bytes would truncate the message, so they are dropped.
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+    status
+}
+
+unsafe fn string_arg<'a>(value: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if value.is_null() {
+        return Err(Error::InvalidArgument(format!("{} is null", name)));
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map_err(|_| Error::InvalidArgument(format!("{} isn't valid UTF-8", name)))
+}
+
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize, name: &str) -> Result<&'a [u8], Error> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if data.is_null() {
+        return Err(Error::InvalidArgument(format!("{} is null", name)));
+    }
+    Ok(std::slice::from_raw_parts(data, len))
+}
+
+unsafe fn object_arg<'a, T>(object: *mut T, name: &str) -> Result<&'a mut T, Error> {
+    object.as_mut().ok_or_else(|| Error::InvalidArgument(format!("{} is null", name)))
+}
+
+// Out-params are checked before any work is done, so that nothing is allocated
+// or sent that couldn't be handed back.
+unsafe fn out_arg<'a, T>(out: *mut T, name: &str) -> Result<&'a mut MaybeUninit<T>, Error> {
+    out.cast::<MaybeUninit<T>>()
+        .as_mut()
+        .ok_or_else(|| Error::InvalidArgument(format!("{} is null", name)))
+}
+
+/// Returns the message of the last error on the calling thread. The message
+/// is owned by the bindings, and is valid until the next call on the same
+/// thread.
+#[no_mangle]
+pub extern "C" fn oak_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+/// Creates a verifier that accepts any evidence. Sessions are still
+/// encrypted, but the server isn't authenticated, so this must only be used
+/// for testing.
+///
+/// # Safety
+///
+/// `verifier_out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn oak_verifier_create_insecure(
+    verifier_out: *mut *mut OakVerifier,
+) -> OakStatus {
+    run(|| {
+        let verifier_out = out_arg(verifier_out, "verifier_out")?;
+        let verifier = OakVerifier { inner: Arc::new(InsecureAttestationVerifier) };
+        verifier_out.write(Box::into_raw(Box::new(verifier)));
+        Ok(())
+    })
+}
+
+/// Creates a verifier that verifies evidence against the serialized
+/// `SignedReferenceValues` message in `signed_reference_values`, which must
+/// be signed with the PEM-encoded P-256 `signing_public_key_pem`. The
+/// reference values are only read once, so the verifier must be recreated
+/// when they are updated.
+///
+/// # Safety
+///
+/// `signed_reference_values` must point to `signed_reference_values_len`
+/// readable bytes, `signing_public_key_pem` must be a nul-terminated string,
+/// and `verifier_out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn oak_verifier_create_with_reference_values(
+    signed_reference_values: *const u8,
+    signed_reference_values_len: usize,
+    signing_public_key_pem: *const c_char,
+    verifier_out: *mut *mut OakVerifier,
+) -> OakStatus {
+    run(|| {
+        let signed_reference_values = bytes_arg(
+            signed_reference_values,
+            signed_reference_values_len,
+            "signed_reference_values",
+        )?;
+        let signing_public_key_pem = string_arg(signing_public_key_pem, "signing_public_key_pem")?;
+        let verifier_out = out_arg(verifier_out, "verifier_out")?;
+        let signed_reference_values = SignedReferenceValues::decode(signed_reference_values)
+            .context("couldn't decode signed reference values")?;
+        // The reference values are fixed, so they never need to be refreshed.
+        let verifier = runtime().block_on(ReferenceValueVerifier::load(
+            Box::new(FixedReferenceValueProvider(signed_reference_values)),
+            signing_public_key_pem,
+            Duration::MAX,
+        ))?;
+        let verifier = OakVerifier { inner: Arc::new(verifier) };
+        verifier_out.write(Box::into_raw(Box::new(verifier)));
+        Ok(())
+    })
+}
+
+/// Adds the appraisal policy in `policy_json` to `verifier`, so that evidence
+/// is only accepted if it also satisfies the policy. The verifier is left
+/// unchanged if the call fails.
+///
+/// # Safety
+///
+/// `verifier` must have been created by the bindings and not freed yet, and
+/// `policy_json` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn oak_verifier_add_policy(
+    verifier: *mut OakVerifier,
+    policy_json: *const c_char,
+) -> OakStatus {
+    run(|| {
+        let verifier = object_arg(verifier, "verifier")?;
+        let policy = parse_policy_json(string_arg(policy_json, "policy_json")?.as_bytes())?;
+        // The current verifier is only replaced once the new one is built.
+        verifier.inner = Arc::new(PolicyVerifier::new(verifier.inner.clone(), policy));
+        Ok(())
+    })
+}
+
+/// Releases `verifier`. Does nothing if it is null.
+///
+/// # Safety
+///
+/// `verifier` must have been created by the bindings and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn oak_verifier_free(verifier: *mut OakVerifier) {
+    if !verifier.is_null() {
+        drop(Box::from_raw(verifier));
+    }
+}
+
+/// Connects to the server at `uri`, e.g. `http://localhost:8080`, verifies
+/// its evidence with `verifier`, and establishes an encrypted session with
+/// it. The verifier is only used while the session is established, so it can
+/// be freed or reused right after.
+///
+/// # Safety
+///
+/// `uri` must be a nul-terminated string, `verifier` must have been created
+/// by the bindings and not freed yet, and `client_out` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn oak_client_create(
+    uri: *const c_char,
+    verifier: *mut OakVerifier,
+    client_out: *mut *mut OakClient,
+) -> OakStatus {
+    run(|| {
+        let uri = string_arg(uri, "uri")?;
+        let verifier = object_arg(verifier, "verifier")?;
+        let client_out = out_arg(client_out, "client_out")?;
+        let client = runtime().block_on(async {
+            let channel = Channel::from_shared(uri.to_string())
+                .context("couldn't create gRPC channel")?
+                .connect()
+                .await
+                .context("couldn't connect via gRPC channel")?;
+            let transport = GrpcStreamingTransport::new(StreamingSessionClient::new(channel));
+            Client::create(transport, verifier.inner.as_ref()).await
+        })?;
+        client_out.write(Box::into_raw(Box::new(OakClient { inner: client })));
+        Ok(())
+    })
+}
+
+/// Sends the `request_len` bytes of `request` to the server over the
+/// encrypted session of `client`, and stores its decrypted response in
+/// `response_out`, which must be released with `oak_buffer_free`.
+///
+/// # Safety
+///
+/// `client` must have been created by the bindings and not freed yet,
+/// `request` must point to `request_len` readable bytes, and `response_out`
+/// must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn oak_client_invoke(
+    client: *mut OakClient,
+    request: *const u8,
+    request_len: usize,
+    response_out: *mut OakBuffer,
+) -> OakStatus {
+    run(|| {
+        let client = object_arg(client, "client")?;
+        let request = bytes_arg(request, request_len, "request")?;
+        let response_out = out_arg(response_out, "response_out")?;
+        let response = runtime().block_on(client.inner.invoke(request))?;
+        response_out.write(OakBuffer::new(response));
+        Ok(())
+    })
+}
+
+/// Closes the session of `client`, and releases it. Does nothing if it is
+/// null.
+///
+/// # Safety
+///
+/// `client` must have been created by the bindings and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn oak_client_free(client: *mut OakClient) {
+    if !client.is_null() {
+        // The transport must be dropped within the runtime.
+        let _guard = runtime().enter();
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Releases the bytes of `buffer`. Does nothing if they are null.
+///
+/// # Safety
+///
+/// `buffer` must have been returned by the bindings and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn oak_buffer_free(buffer: OakBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)));
+    }
+}
+
+/// Provides the reference values given to the verifier when it was created.
+struct FixedReferenceValueProvider(SignedReferenceValues);
+
+#[async_trait::async_trait]
+impl ReferenceValueProvider for FixedReferenceValueProvider {
+    async fn fetch(&self) -> anyhow::Result<SignedReferenceValues> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oak_proto_rust::oak::attestation::v1::{Endorsements, Evidence, ExtractedEvidence};
+
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(oak_last_error()) }.to_str().unwrap().to_string()
+    }
+
+    fn insecure_verifier() -> *mut OakVerifier {
+        let mut verifier = ptr::null_mut();
+        assert_eq!(unsafe { oak_verifier_create_insecure(&mut verifier) }, OakStatus::Ok);
+        assert!(!verifier.is_null());
+        verifier
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        let verifier = insecure_verifier();
+        let mut client = ptr::null_mut();
+
+        assert_eq!(
+            unsafe { oak_verifier_create_insecure(ptr::null_mut()) },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "verifier_out is null");
+
+        assert_eq!(
+            unsafe { oak_client_create(ptr::null(), verifier, &mut client) },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "uri is null");
+
+        assert_eq!(
+            unsafe {
+                oak_client_create(c"http://localhost:8080".as_ptr(), ptr::null_mut(), &mut client)
+            },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "verifier is null");
+        assert!(client.is_null());
+
+        // Out-params are checked before connecting to the server.
+        assert_eq!(
+            unsafe {
+                oak_client_create(c"http://localhost:8080".as_ptr(), verifier, ptr::null_mut())
+            },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "client_out is null");
+
+        assert_eq!(
+            unsafe { oak_verifier_add_policy(verifier, ptr::null()) },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "policy_json is null");
+
+        let mut response = OakBuffer { data: ptr::null_mut(), len: 0 };
+        assert_eq!(
+            unsafe { oak_client_invoke(ptr::null_mut(), ptr::null(), 0, &mut response) },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "client is null");
+
+        // Empty byte arguments may be null, but non-empty ones may not.
+        assert_eq!(
+            unsafe {
+                oak_verifier_create_with_reference_values(
+                    ptr::null(),
+                    1,
+                    c"".as_ptr(),
+                    ptr::null_mut(),
+                )
+            },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "signed_reference_values is null");
+
+        unsafe { oak_verifier_free(verifier) };
+    }
+
+    #[test]
+    fn test_invalid_utf8_arguments() {
+        let verifier = insecure_verifier();
+        let invalid = c"\xff\xfe";
+
+        let mut client = ptr::null_mut();
+        assert_eq!(
+            unsafe { oak_client_create(invalid.as_ptr(), verifier, &mut client) },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "uri isn't valid UTF-8");
+        assert!(client.is_null());
+
+        assert_eq!(
+            unsafe { oak_verifier_add_policy(verifier, invalid.as_ptr()) },
+            OakStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "policy_json isn't valid UTF-8");
+
+        unsafe { oak_verifier_free(verifier) };
+    }
+
+    #[test]
+    fn test_failed_calls() {
+        let verifier = insecure_verifier();
+        assert_eq!(unsafe { oak_verifier_add_policy(verifier, c"{".as_ptr()) }, OakStatus::Error);
+        assert!(!last_error().is_empty());
+
+        // Successful calls leave the last error unchanged.
+        let error = last_error();
+        unsafe { oak_verifier_free(insecure_verifier()) };
+        assert_eq!(last_error(), error);
+
+        unsafe { oak_verifier_free(verifier) };
+    }
+
+    // Accepts any evidence, without extracting any values from it.
+    struct AcceptingVerifier;
+
+    impl AttestationVerifier for AcceptingVerifier {
+        fn verify(
+            &self,
+            _evidence: &Evidence,
+            _endorsements: &Endorsements,
+        ) -> anyhow::Result<ExtractedEvidence> {
+            Ok(ExtractedEvidence::default())
+        }
+    }
+
+    #[test]
+    fn test_failed_add_policy_keeps_verifier() {
+        let verifier = Box::into_raw(Box::new(OakVerifier { inner: Arc::new(AcceptingVerifier) }));
+        let verify =
+            || unsafe { &*verifier }.inner.verify(&Evidence::default(), &Endorsements::default());
+        assert!(verify().is_ok());
+
+        // The policy rejects evidence without evidence values.
+        assert_eq!(unsafe { oak_verifier_add_policy(verifier, c"{}".as_ptr()) }, OakStatus::Ok);
+        assert!(verify().is_err());
+
+        // A policy that can't be parsed leaves the verifier with the previous one.
+        assert_eq!(unsafe { oak_verifier_add_policy(verifier, c"{".as_ptr()) }, OakStatus::Error);
+        let error = verify().unwrap_err();
+        assert!(format!("{:#}", error).contains("attestation policy"), "{:#}", error);
+
+        unsafe { oak_verifier_free(verifier) };
+    }
+
+    #[test]
+    fn test_free_null() {
+        unsafe {
+            oak_verifier_free(ptr::null_mut());
+            oak_client_free(ptr::null_mut());
+            oak_buffer_free(OakBuffer { data: ptr::null_mut(), len: 0 });
+        }
+    }
+
+    #[test]
+    fn test_buffer_free() {
+        let buffer = OakBuffer::new(b"response".to_vec());
+        assert_eq!(unsafe { std::slice::from_raw_parts(buffer.data, buffer.len) }, b"response");
+        unsafe { oak_buffer_free(buffer) };
+
+        // Empty buffers are allocated too, and are freed the same way.
+        unsafe { oak_buffer_free(OakBuffer::new(Vec::new())) };
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * Copyright 2024 The Project Oak Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// Checks that the header matches the library, by calling each function of the
+// bindings from C without a server.
+
+#include <stdio.h>
+#include <string.h>
+
+#include "oak_client.h"
+
+#define CHECK(condition)                                                                           \
+  if (!(condition)) {                                                                              \
+    fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #condition);                  \
+    return 1;                                                                                      \
+  }
+
+int main(void) {
+  OakVerifier *verifier = NULL;
+  OakVerifier *reference_value_verifier = NULL;
+  OakClient *client = NULL;
+  OakBuffer response = {NULL, 0};
+
+  CHECK(oak_verifier_create_insecure(&verifier) == OAK_STATUS_OK);
+  CHECK(verifier != NULL);
+
+  CHECK(oak_verifier_add_policy(verifier, NULL) == OAK_STATUS_INVALID_ARGUMENT);
+  CHECK(strcmp(oak_last_error(), "policy_json is null") == 0);
+  CHECK(oak_verifier_add_policy(verifier, "{") == OAK_STATUS_ERROR);
+  CHECK(strlen(oak_last_error()) > 0);
+
+  const uint8_t reference_values[] = {0xff};
+  CHECK(oak_verifier_create_with_reference_values(reference_values, sizeof(reference_values), "",
+                                                  &reference_value_verifier) == OAK_STATUS_ERROR);
+  CHECK(reference_value_verifier == NULL);
+
+  // Nothing listens on port 1.
+  CHECK(oak_client_create("http://localhost:1", verifier, &client) == OAK_STATUS_ERROR);
+  CHECK(client == NULL);
+
+  CHECK(oak_client_invoke(NULL, NULL, 0, &response) == OAK_STATUS_INVALID_ARGUMENT);
+  CHECK(strcmp(oak_last_error(), "client is null") == 0);
+
+  oak_buffer_free(response);
+  oak_client_free(client);
+  oak_verifier_free(reference_value_verifier);
+  oak_verifier_free(verifier);
+  return 0;
+}
@@ -0,0 +1,55 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+mod telemetry;
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::Parser;
+use oak_containers_orchestrator::launcher_client::LauncherClient;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(default_value = "http://10.0.2.100:8080")]
+    launcher_addr: String,
+
+    /// How often to send a telemetry snapshot to the launcher, in seconds.
+    #[arg(long, default_value_t = 5)]
+    interval: u64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let launcher_client = LauncherClient::create(args.launcher_addr.parse()?)
+        .await
+        .map_err(|error| anyhow!("couldn't create client: {:?}", error))?;
+
+    // Dropping the sender if collecting the telemetry fails ends the stream, and
+    // the stream ending makes the next send fail, so either way we exit and let
+    // systemd restart us.
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::try_join!(
+        telemetry::run(sender, Duration::from_secs(args.interval)),
+        launcher_client.stream_guest_telemetry(ReceiverStream::new(receiver)),
+    )?;
+
+    Ok(())
+}
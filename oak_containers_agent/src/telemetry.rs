@@ -0,0 +1,110 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Collects the telemetry about the guest that the agent sends to the
+//! launcher.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use oak_containers_orchestrator::{
+    container_runtime::CONTAINER_UNIT, proto::oak::containers::GuestTelemetry,
+};
+use procfs::{Current, CurrentSI, KernelStats, Meminfo};
+use tokio::{process::Command, sync::mpsc};
+
+/// Sends a telemetry snapshot to `sender` every `interval`, until the receiver
+/// is dropped.
+pub async fn run(sender: mpsc::Sender<GuestTelemetry>, interval: Duration) -> Result<()> {
+    let mut container = ContainerMonitor::default();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let telemetry = snapshot(&mut container).await?;
+        if sender.send(telemetry).await.is_err() {
+            bail!("the launcher closed the telemetry stream");
+        }
+    }
+}
+
+async fn snapshot(container: &mut ContainerMonitor) -> Result<GuestTelemetry> {
+    let cpu = KernelStats::current().context("couldn't read kernel stats")?.total;
+    // Time spent waiting for I/O is idle time, and stolen time is time the host
+    // didn't give us.
+    let cpu_busy_milliseconds = cpu.user_ms()
+        + cpu.nice_ms()
+        + cpu.system_ms()
+        + cpu.irq_ms().unwrap_or_default()
+        + cpu.softirq_ms().unwrap_or_default();
+    let cpu_idle_milliseconds = cpu.idle_ms() + cpu.iowait_ms().unwrap_or_default();
+
+    let meminfo = Meminfo::current().context("couldn't read memory info")?;
+    let oom_kills = procfs::vmstat()
+        .context("couldn't read virtual memory stats")?
+        .get("oom_kill")
+        .copied()
+        .unwrap_or_default();
+
+    let (container_running, container_restarts) = container.update().await?;
+
+    Ok(GuestTelemetry {
+        cpu_busy_milliseconds,
+        cpu_idle_milliseconds,
+        memory_total_bytes: meminfo.mem_total,
+        memory_available_bytes: meminfo.mem_available.unwrap_or(meminfo.mem_free),
+        oom_kills: oom_kills.try_into().unwrap_or_default(),
+        container_running,
+        container_restarts,
+    })
+}
+
+/// Follows the systemd unit of the container, counting how many times it was
+/// started. Each start of the unit gets a new invocation ID, so we count the
+/// invocation IDs we've seen; a container that is restarted more than once
+/// between two snapshots is only counted once.
+#[derive(Default)]
+struct ContainerMonitor {
+    invocation_id: Option<String>,
+    starts: u64,
+}
+
+impl ContainerMonitor {
+    /// Returns whether the container is running, and how many times it was
+    /// restarted.
+    async fn update(&mut self) -> Result<(bool, u64)> {
+        let output = Command::new("/bin/systemctl")
+            .args(["show", "--property=ActiveState,InvocationID", CONTAINER_UNIT])
+            .output()
+            .await
+            .context("couldn't query the container unit")?;
+        if !output.status.success() {
+            bail!("systemctl exited with {}", output.status);
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        let property =
+            |name: &str| output.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix('='));
+        let running = property("ActiveState") == Some("active");
+        // The container unit doesn't exist until the orchestrator has set up the
+        // container, and the invocation ID is empty until then.
+        if let Some(invocation_id) = property("InvocationID").filter(|id| !id.is_empty()) {
+            if self.invocation_id.as_deref() != Some(invocation_id) {
+                self.invocation_id = Some(invocation_id.to_string());
+                self.starts += 1;
+            }
+        }
+        Ok((running, self.starts.saturating_sub(1)))
+    }
+}
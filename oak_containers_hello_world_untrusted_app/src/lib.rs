@@ -54,14 +54,14 @@ impl UntrustedApp {
     }
 
     pub async fn get_endorsed_evidence(&mut self) -> anyhow::Result<EndorsedEvidence> {
-        self.launcher.get_endorsed_evidence().await
+        Ok(self.launcher.get_endorsed_evidence().await?)
     }
 
     pub async fn get_group_keys(
         &mut self,
         request: GetGroupKeysRequest,
     ) -> anyhow::Result<GetGroupKeysResponse> {
-        self.launcher.get_group_keys(request).await
+        Ok(self.launcher.get_group_keys(request).await?)
     }
 
     pub async fn hello(
@@ -53,7 +53,10 @@ async fn run_hello_world_test(container_bundle: std::path::PathBuf) {
 
     init_dependencies();
 
-    let app_args = Args { container_bundle, ..Args::default_for_root(env!("WORKSPACE_ROOT")) };
+    let app_args = Args {
+        container_bundle: Some(container_bundle),
+        ..Args::default_for_root(env!("WORKSPACE_ROOT"))
+    };
     let mut untrusted_app =
         oak_containers_hello_world_untrusted_app::UntrustedApp::create(app_args)
             .await
@@ -104,7 +107,8 @@ async fn run_hello_world_test(container_bundle: std::path::PathBuf) {
 }
 
 async fn hello_world() {
-    run_hello_world_test(Args::default_for_root(env!("WORKSPACE_ROOT")).container_bundle).await;
+    run_hello_world_test(Args::default_for_root(env!("WORKSPACE_ROOT")).container_bundle.unwrap())
+        .await;
 }
 
 async fn cc_hello_world() {
@@ -36,18 +36,21 @@ pub mod proto {
 
 mod qemu;
 mod server;
+mod telemetry;
 
 use std::{
     fmt::Display,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
 };
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use clap::{Parser, ValueEnum};
+use oak_launcher_utils::error::LauncherError;
 use oak_proto_rust::oak::attestation::v1::{
     endorsements, Endorsements, Evidence, OakRestrictedKernelEndorsements,
 };
-pub use qemu::Params as QemuParams;
+pub use qemu::{NetworkBackend, Params as QemuParams};
 use tokio::{
     net::TcpListener,
     sync::oneshot::{channel, Receiver, Sender},
@@ -57,11 +60,16 @@ use tokio::{
 use tokio_vsock::VsockAddr;
 use tonic::transport::Channel as TonicChannel;
 
-use crate::proto::oak::{
-    key_provisioning::v1::{
-        key_provisioning_client::KeyProvisioningClient, GetGroupKeysRequest, GetGroupKeysResponse,
+use crate::{
+    proto::oak::{
+        containers::GuestTelemetry,
+        key_provisioning::v1::{
+            key_provisioning_client::KeyProvisioningClient, GetGroupKeysRequest,
+            GetGroupKeysResponse,
+        },
+        session::v1::EndorsedEvidence,
     },
-    session::v1::EndorsedEvidence,
+    telemetry::TelemetryCollector,
 };
 
 /// The local IP address assigned to the VM guest.
@@ -77,6 +85,14 @@ const VM_ORCHESTRATOR_LOCAL_PORT: u16 = 4000;
 /// adress.
 const PROXY_ADDRESS: Ipv4Addr = Ipv4Addr::LOCALHOST;
 
+/// The address at which the guest reaches the launcher service. With user-mode
+/// networking the VMM forwards it to the launcher, otherwise it must be
+/// assigned to the host end of the guest network.
+const LAUNCHER_GUEST_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+
+/// The port at which the guest reaches the launcher service.
+const LAUNCHER_GUEST_PORT: u16 = 8080;
+
 /// Number of seconds to wait for the VM to start up.
 const VM_START_TIMEOUT: u64 = 300;
 
@@ -95,8 +111,13 @@ pub enum ChannelType {
 pub struct Args {
     #[arg(long, required = true, value_parser = path_exists,)]
     pub system_image: std::path::PathBuf,
-    #[arg(long, required = true, value_parser = path_exists,)]
-    pub container_bundle: std::path::PathBuf,
+    #[arg(long, required_unless_present = "container_image", value_parser = path_exists,)]
+    pub container_bundle: Option<std::path::PathBuf>,
+    /// Reference of the container image to have the orchestrator pull from an
+    /// OCI registry instead of `--container-bundle`, of the form
+    /// `registry/repository@sha256:digest`.
+    #[arg(long, conflicts_with = "container_bundle")]
+    pub container_image: Option<String>,
     #[clap(skip)]
     pub application_config: Vec<u8>,
     #[command(flatten)]
@@ -105,6 +126,11 @@ pub struct Args {
     // Method of communication with the trusted application in the enclave.
     #[arg(long, value_enum, default_value_t = ChannelType::default())]
     pub communication_channel: ChannelType,
+
+    /// Address on which to serve the telemetry about the guest in the
+    /// Prometheus text format, e.g. `127.0.0.1:9090`.
+    #[arg(long)]
+    pub metrics_address: Option<SocketAddr>,
 }
 
 impl Args {
@@ -115,10 +141,12 @@ impl Args {
         ).into();
         Self {
             system_image,
-            container_bundle,
+            container_bundle: Some(container_bundle),
+            container_image: None,
             application_config: Vec::new(),
             qemu_params: qemu::Params::default_for_root(root),
             communication_channel: ChannelType::default(),
+            metrics_address: None,
         }
     }
 }
@@ -134,7 +162,7 @@ pub fn path_exists(s: &str) -> Result<std::path::PathBuf, String> {
 
 #[derive(Clone)]
 pub enum Channel {
-    Network { host_proxy_port: u16, trusted_app_address: Option<SocketAddr> },
+    Network { proxy_address: SocketAddr, trusted_app_address: Option<SocketAddr> },
     VirtioVsock { trusted_app_address: Option<VsockAddr> },
 }
 
@@ -149,7 +177,7 @@ impl TryFrom<Channel> for TrustedApplicationAddress {
 
     fn try_from(channel: Channel) -> Result<TrustedApplicationAddress, Self::Error> {
         match channel {
-            Channel::Network { host_proxy_port: _, trusted_app_address } => {
+            Channel::Network { proxy_address: _, trusted_app_address } => {
                 trusted_app_address.map(TrustedApplicationAddress::Network)
             }
             Channel::VirtioVsock { trusted_app_address } => {
@@ -172,7 +200,7 @@ impl Display for TrustedApplicationAddress {
 pub struct Launcher {
     vmm: qemu::Qemu,
     server: JoinHandle<Result<(), anyhow::Error>>,
-    host_orchestrator_proxy_port: u16,
+    orchestrator_address: SocketAddr,
     // Endorsed Attestation Evidence consists of Attestation Evidence (initialized by the
     // Orchestrator) and Attestation Endorsement (initialized by the Launcher).
     endorsed_evidence: Option<EndorsedEvidence>,
@@ -181,62 +209,101 @@ pub struct Launcher {
     app_ready_notifier: Option<Receiver<()>>,
     orchestrator_key_provisioning_client: Option<KeyProvisioningClient<TonicChannel>>,
     trusted_app_channel: Channel,
+    telemetry_collector: Arc<TelemetryCollector>,
+    metrics_server: Option<JoinHandle<Result<(), anyhow::Error>>>,
     shutdown: Option<Sender<()>>,
 }
 
 impl Launcher {
-    pub async fn create(args: Args) -> Result<Self, anyhow::Error> {
+    pub async fn create(args: Args) -> Result<Self, LauncherError> {
+        let user_networking = args.qemu_params.network_backend == NetworkBackend::User;
         // Let the OS assign an open port for the launcher service.
         let sockaddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
         let orchestrator_sockaddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-        let listener = TcpListener::bind(sockaddr).await?;
-        let port = listener.local_addr()?.port();
+        let listener = if user_networking {
+            TcpListener::bind(sockaddr)
+                .await
+                .map_err(|err| LauncherError::io("couldn't listen on the launcher address", err))?
+        } else {
+            // Without user-mode networking nothing forwards the guest's connections, so
+            // listen where the guest expects the launcher service.
+            TcpListener::bind(SocketAddr::new(
+                IpAddr::V4(LAUNCHER_GUEST_ADDRESS),
+                LAUNCHER_GUEST_PORT,
+            ))
+            .await
+            .map_err(|err| {
+                LauncherError::io("couldn't listen on the guest-facing launcher address", err)
+            })?
+        };
+        let port = listener
+            .local_addr()
+            .map_err(|err| LauncherError::io("couldn't get the launcher port", err))?
+            .port();
         log::info!("Launcher service listening on port {port}");
         let (evidence_sender, evidence_receiver) = channel::<Evidence>();
         let (shutdown_sender, shutdown_receiver) = channel::<()>();
         let (app_notifier_sender, app_notifier_receiver) = channel::<()>();
+        let telemetry_collector = Arc::new(TelemetryCollector::default());
+        let metrics_server = args
+            .metrics_address
+            .map(|address| tokio::spawn(telemetry::serve(address, telemetry_collector.clone())));
         let server = tokio::spawn(server::new(
             listener,
             args.system_image,
             args.container_bundle,
+            args.container_image,
             args.application_config,
             evidence_sender,
             app_notifier_sender,
+            telemetry_collector.clone(),
             shutdown_receiver,
         ));
 
         let trusted_app_channel = match args.communication_channel {
-            ChannelType::Network => {
+            ChannelType::Network if user_networking => {
                 // Also get an open port for that QEMU can use for proxying requests to the
                 // server. Since we don't have a mechanism to pass this port to
                 // QEMU we have to unbind it by dropping it. There is a small
                 // window for a race condition, but since the assigned port will
                 // be random the probability of another process grabbing the
                 // port before QEMU can should be very low.
-                let host_proxy_port = TcpListener::bind(sockaddr).await?.local_addr()?.port();
-                Channel::Network { host_proxy_port, trusted_app_address: None }
+                let host_proxy_port = free_port(sockaddr).await?;
+                Channel::Network {
+                    proxy_address: SocketAddr::new(IpAddr::V4(PROXY_ADDRESS), host_proxy_port),
+                    trusted_app_address: None,
+                }
             }
+            ChannelType::Network => Channel::Network {
+                proxy_address: SocketAddr::new(VM_LOCAL_ADDRESS, VM_LOCAL_PORT),
+                trusted_app_address: None,
+            },
             ChannelType::VirtioVsock => Channel::VirtioVsock { trusted_app_address: None },
         };
 
-        let host_orchestrator_proxy_port =
-            { TcpListener::bind(orchestrator_sockaddr).await?.local_addr()?.port() };
+        let orchestrator_address = if user_networking {
+            let host_orchestrator_proxy_port = free_port(orchestrator_sockaddr).await?;
+            SocketAddr::new(IpAddr::V4(PROXY_ADDRESS), host_orchestrator_proxy_port)
+        } else {
+            SocketAddr::new(VM_LOCAL_ADDRESS, VM_ORCHESTRATOR_LOCAL_PORT)
+        };
         let vmm = qemu::Qemu::start(
             args.qemu_params,
             port,
             match trusted_app_channel {
-                Channel::Network { host_proxy_port, trusted_app_address: _ } => {
-                    Some(host_proxy_port)
+                Channel::Network { proxy_address, trusted_app_address: _ } => {
+                    Some(proxy_address.port())
                 }
                 Channel::VirtioVsock { trusted_app_address: _ } => None,
             },
-            host_orchestrator_proxy_port,
-        )?;
+            orchestrator_address.port(),
+        )
+        .map_err(LauncherError::Vmm)?;
 
         Ok(Self {
             vmm,
             server,
-            host_orchestrator_proxy_port,
+            orchestrator_address,
             // Attestation Evidence will be sent by the Orchestrator once generated.
             // And after the Evidence is received it will be endorsed by the Launcher (it will
             // provide corresponding hardware manufacturer's certificates).
@@ -245,6 +312,8 @@ impl Launcher {
             app_ready_notifier: Some(app_notifier_receiver),
             orchestrator_key_provisioning_client: None,
             trusted_app_channel,
+            telemetry_collector,
+            metrics_server,
             shutdown: Some(shutdown_sender),
         })
     }
@@ -253,22 +322,26 @@ impl Launcher {
     /// the trusted application.
     ///
     /// This is a host-visible address that the VMM will proxy to the trusted
-    /// application's service endpoint.
+    /// application's service endpoint, or the guest's own address if the guest
+    /// isn't using user-mode networking.
     ///
     /// This call will wait until the trusted app has notifiied the launcher
     /// once that it is ready via the orchestrator.
     pub async fn get_trusted_app_address(
         &mut self,
-    ) -> Result<TrustedApplicationAddress, anyhow::Error> {
+    ) -> Result<TrustedApplicationAddress, LauncherError> {
         // If we haven't received a ready notification, wait for it.
         if let Some(receiver) = self.app_ready_notifier.take() {
             // Set a timeout since we don't want to wait forever if the VM didn't start
             // properly.
-            timeout(Duration::from_secs(VM_START_TIMEOUT), receiver).await??;
+            timeout(Duration::from_secs(VM_START_TIMEOUT), receiver)
+                .await
+                .context("the trusted application wasn't ready before timeout")
+                .and_then(|ready| ready.context("the trusted application didn't become ready"))
+                .map_err(LauncherError::Channel)?;
             match &mut self.trusted_app_channel {
-                Channel::Network { host_proxy_port, trusted_app_address } => {
-                    trusted_app_address
-                        .replace(SocketAddr::new(IpAddr::V4(PROXY_ADDRESS), *host_proxy_port));
+                Channel::Network { proxy_address, trusted_app_address } => {
+                    trusted_app_address.replace(*proxy_address);
                 }
                 Channel::VirtioVsock { trusted_app_address } => {
                     trusted_app_address.replace(VsockAddr::new(
@@ -278,12 +351,12 @@ impl Launcher {
                 }
             }
         }
-        self.trusted_app_channel.clone().try_into()
+        self.trusted_app_channel.clone().try_into().map_err(LauncherError::Channel)
     }
 
     /// Gets the endorsed attestation evidence that the untrusted application
     /// can send to remote clients, which will verify it before connecting.
-    pub async fn get_endorsed_evidence(&mut self) -> anyhow::Result<EndorsedEvidence> {
+    pub async fn get_endorsed_evidence(&mut self) -> Result<EndorsedEvidence, LauncherError> {
         // If we haven't received an attestation evidence, wait for it.
         #[allow(deprecated)]
         if let Some(receiver) = self.evidence_receiver.take() {
@@ -291,8 +364,9 @@ impl Launcher {
             // properly.
             let evidence = timeout(Duration::from_secs(VM_START_TIMEOUT), receiver)
                 .await
-                .context("couldn't get attestation evidence before timeout")?
-                .context("no attestation evidence available")?;
+                .context("couldn't get attestation evidence before timeout")
+                .and_then(|evidence| evidence.context("no attestation evidence available"))
+                .map_err(LauncherError::Attestation)?;
 
             // Initialize attestation endorsements.
             // TODO(#4074): Add layer endorsements.
@@ -313,41 +387,51 @@ impl Launcher {
         }
         self.endorsed_evidence
             .clone()
-            .ok_or_else(|| anyhow::anyhow!("endorsed evidence is not set"))
+            .ok_or_else(|| LauncherError::Attestation(anyhow!("endorsed evidence is not set")))
     }
 
     // Gets enclave group keys as part of Key Provisioning.
     pub async fn get_group_keys(
         &mut self,
         request: GetGroupKeysRequest,
-    ) -> anyhow::Result<GetGroupKeysResponse> {
-        if self.orchestrator_key_provisioning_client.is_none() {
-            // Create Orchestrator Key Provisioning gRPC client.
-            let orchestrator_uri =
-                format!("http://127.0.0.1:{}", self.host_orchestrator_proxy_port)
+    ) -> Result<GetGroupKeysResponse, LauncherError> {
+        let mut client = match &self.orchestrator_key_provisioning_client {
+            Some(client) => client.clone(),
+            None => {
+                // Create Orchestrator Key Provisioning gRPC client.
+                let orchestrator_uri = format!("http://{}", self.orchestrator_address)
                     .parse()
-                    .context("couldn't parse orchestrator URI")?;
-            let orchestrator_channel = TonicChannel::builder(orchestrator_uri)
-                .connect()
-                .await
-                .context("couldn't connect to orchestrator")?;
-            self.orchestrator_key_provisioning_client =
-                Some(KeyProvisioningClient::new(orchestrator_channel));
-        }
+                    .context("couldn't parse orchestrator URI")
+                    .map_err(LauncherError::Channel)?;
+                let orchestrator_channel = TonicChannel::builder(orchestrator_uri)
+                    .connect()
+                    .await
+                    .context("couldn't connect to orchestrator")
+                    .map_err(LauncherError::Channel)?;
+                self.orchestrator_key_provisioning_client
+                    .insert(KeyProvisioningClient::new(orchestrator_channel))
+                    .clone()
+            }
+        };
 
-        let get_group_keys_response = self
-            .orchestrator_key_provisioning_client
-            .clone()
-            .context("couldn't get orchestrator key provisioning client")?
+        let get_group_keys_response = client
             .get_group_keys(request)
             .await
-            .context("couldn't get group keys")?
+            .context("couldn't get group keys")
+            .map_err(LauncherError::Enclave)?
             .into_inner();
         Ok(get_group_keys_response)
     }
 
-    pub async fn wait(&mut self) -> Result<(), anyhow::Error> {
-        self.vmm.wait().await?;
+    /// Gets the latest telemetry that the guest has sent about itself, if any.
+    /// The telemetry comes from the guest, so it must only be used for
+    /// monitoring.
+    pub fn get_guest_telemetry(&self) -> Option<GuestTelemetry> {
+        self.telemetry_collector.latest()
+    }
+
+    pub async fn wait(&mut self) -> Result<(), LauncherError> {
+        self.vmm.wait().await.map_err(LauncherError::Vmm)?;
         Ok(())
     }
 
@@ -357,5 +441,27 @@ impl Launcher {
         }
         let _ = self.vmm.kill().await;
         self.server.abort();
+        if let Some(metrics_server) = self.metrics_server.take() {
+            metrics_server.abort();
+        }
     }
 }
+
+// Returns a port that is free on `address`, for QEMU to proxy connections
+// from.
+async fn free_port(address: SocketAddr) -> Result<u16, LauncherError> {
+    TcpListener::bind(address)
+        .await
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .map_err(|err| LauncherError::io("couldn't find a free port for QEMU", err))
+}
+
+#[tokio::test]
+async fn test_free_port_reports_io_errors() {
+    // The address is reserved for documentation, so no interface has it.
+    let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 0);
+    assert!(matches!(free_port(address).await, Err(LauncherError::Io { .. })));
+    let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    assert!(free_port(address).await.unwrap() > 0);
+}
@@ -18,12 +18,13 @@ use std::{
     io::{BufRead, BufReader},
     net::Ipv4Addr,
     os::{fd::AsRawFd, unix::net::UnixStream},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Stdio,
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
 use command_fds::CommandFdExt;
 
 use crate::path_exists;
@@ -69,6 +70,109 @@ pub struct Params {
     /// Warning: This CID needs to be globally unique on the whole host!
     #[arg(long)]
     pub virtio_guest_cid: Option<u32>,
+
+    /// Extra parameter to append to the kernel command line, e.g. a debug flag.
+    /// Can be repeated.
+    #[arg(long = "kernel-arg")]
+    pub kernel_args: Vec<String>,
+
+    /// Optional host directory to share with the guest via virtiofs. The
+    /// directory is exported read-only, and made available to the container
+    /// at `/oak_shared`. Requires `--memory-size`, as the guest memory has to
+    /// be shared with virtiofsd.
+    #[arg(long, value_parser = dir_exists, requires = "memory_size")]
+    pub shared_directory: Option<PathBuf>,
+
+    /// Path to the virtiofsd binary used to export `--shared-directory`.
+    #[arg(long, value_parser = path_exists, default_value = "/usr/libexec/virtiofsd")]
+    pub virtiofsd_binary: PathBuf,
+
+    /// How to connect the guest network device to the host.
+    #[arg(long, value_enum, default_value_t = NetworkBackend::default())]
+    pub network_backend: NetworkBackend,
+
+    /// Name of an existing host tap interface to connect the guest to, for
+    /// `--network-backend=tap`.
+    #[arg(long, required_if_eq("network_backend", "tap"))]
+    pub tap_interface: Option<String>,
+
+    /// Path to the socket of a vhost-user network backend to connect the guest
+    /// to, for `--network-backend=vhost-user`. Requires `--memory-size`, as the
+    /// guest memory has to be shared with the backend.
+    #[arg(long, required_if_eq("network_backend", "vhost-user"), requires = "memory_size")]
+    pub vhost_user_net_socket: Option<PathBuf>,
+
+    /// PCI address (e.g. `0000:41:00.0`) of a host device, such as a GPU, to
+    /// pass through to the guest with VFIO. The device has to be bound to the
+    /// `vfio-pci` driver on the host. Can be repeated.
+    #[arg(long = "vfio-device", value_parser = parse_pci_address)]
+    pub vfio_devices: Vec<String>,
+
+    /// Optional host file or block device to attach to the guest as a scratch
+    /// disk. The orchestrator encrypts it with a key that never leaves the
+    /// guest, and makes it available to the container at `/oak_scratch`. Its
+    /// previous contents are lost.
+    #[arg(long)]
+    pub scratch_disk: Option<PathBuf>,
+}
+
+/// How the guest network device is connected to the host.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum NetworkBackend {
+    /// QEMU user-mode networking, which forwards individual ports between the
+    /// host and the guest. Needs no setup on the host, but has low throughput.
+    #[default]
+    User,
+
+    /// A host tap interface, accelerated with vhost-net.
+    Tap,
+
+    /// A vhost-user backend, such as a DPDK or Open vSwitch port.
+    VhostUser,
+}
+
+/// The virtiofs tag under which the shared directory is exported. Must match
+/// the tag the orchestrator mounts.
+const SHARED_DIRECTORY_TAG: &str = "oak_shared";
+
+/// The serial number of the scratch disk, through which the orchestrator finds
+/// it. Must match the serial the orchestrator looks for.
+const SCRATCH_DISK_SERIAL: &str = "oak_scratch";
+
+/// How long to wait for virtiofsd to create its socket.
+const VIRTIOFSD_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the host kernel lists the PCI devices.
+const PCI_DEVICES_SYSFS_PATH: &str = "/sys/bus/pci/devices";
+
+/// Host drivers that VFIO allows for the other devices in the IOMMU group of a
+/// passed through device: the group is only viable if none of its devices is
+/// in use by the host.
+const VFIO_GROUP_DRIVERS: &[&str] = &["vfio-pci", "pcieport"];
+
+fn dir_exists(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    if !std::fs::metadata(s).map_err(|err| err.to_string())?.is_dir() {
+        Err(String::from("path does not represent a directory"))
+    } else {
+        Ok(path)
+    }
+}
+
+/// Parses a PCI address of the form `[domain:]bus:device.function`, and returns
+/// it with the domain, as sysfs lists it.
+fn parse_pci_address(s: &str) -> Result<String, String> {
+    let address = if s.matches(':').count() == 1 { format!("0000:{s}") } else { s.to_string() };
+    let valid = match address.split(|c| c == ':' || c == '.').collect::<Vec<_>>()[..] {
+        [domain, bus, device, function] => [(domain, 4), (bus, 2), (device, 2), (function, 1)]
+            .iter()
+            .all(|(part, len)| part.len() == *len && part.chars().all(|c| c.is_ascii_hexdigit())),
+        _ => false,
+    };
+    if !valid {
+        return Err(String::from("expected a PCI address like 0000:41:00.0"));
+    }
+    Ok(address.to_ascii_lowercase())
 }
 
 impl Params {
@@ -88,23 +192,45 @@ impl Params {
             ramdrive_size: 3_000_000,
             telnet_console: None,
             virtio_guest_cid: None,
+            kernel_args: Vec::new(),
+            shared_directory: None,
+            virtiofsd_binary: "/usr/libexec/virtiofsd".into(),
+            network_backend: NetworkBackend::default(),
+            tap_interface: None,
+            vhost_user_net_socket: None,
+            vfio_devices: Vec::new(),
+            scratch_disk: None,
         }
     }
 }
 
 pub struct Qemu {
     instance: tokio::process::Child,
+    virtiofsd: Option<tokio::process::Child>,
     guest_cid: Option<u32>,
 }
 
 impl Qemu {
+    /// Starts the VM. The ports are only used with user-mode networking, where
+    /// QEMU forwards them between the host and the guest; with the other
+    /// network backends the host and guest reach each other directly.
     pub fn start(
         params: Params,
         launcher_service_port: u16,
         host_proxy_port: Option<u16>,
         host_orchestrator_proxy_port: u16,
     ) -> Result<Self> {
-        let mut cmd = tokio::process::Command::new(params.vmm_binary);
+        for address in &params.vfio_devices {
+            check_vfio_device(address)?;
+        }
+
+        let virtiofsd = params
+            .shared_directory
+            .as_ref()
+            .map(|shared_directory| start_virtiofsd(&params.virtiofsd_binary, shared_directory))
+            .transpose()?;
+
+        let mut cmd = tokio::process::Command::new(&params.vmm_binary);
         let (guest_socket, host_socket) = UnixStream::pair()?;
         cmd.kill_on_drop(true);
         cmd.stderr(Stdio::inherit());
@@ -123,8 +249,8 @@ impl Qemu {
         // for remote attestation.
         cmd.args(["-cpu", "host"]);
         // Set memory size if given.
-        if let Some(memory_size) = params.memory_size {
-            cmd.args(["-m", &memory_size]);
+        if let Some(memory_size) = &params.memory_size {
+            cmd.args(["-m", memory_size.as_str()]);
         };
         // Number of CPUs to give to the VM.
         cmd.args(["-smp", format!("{}", params.num_cpus).as_str()]);
@@ -135,7 +261,29 @@ impl Qemu {
         // restart should be treated as a failure)
         cmd.arg("-no-reboot");
         // Use the `microvm` machine as the basis, and ensure ACPI and PCIe are enabled.
-        cmd.args(["-machine", "microvm,acpi=on,pcie=on"]);
+        if virtiofsd.is_some() || params.network_backend == NetworkBackend::VhostUser {
+            // virtiofsd and vhost-user network backends access the guest memory
+            // directly, so it has to be shared.
+            let memory_size = params.memory_size.as_deref().context("memory size not set")?;
+            cmd.args([
+                "-object",
+                format!("memory-backend-memfd,id=mem,size={memory_size},share=on").as_str(),
+            ]);
+            cmd.args(["-machine", "microvm,acpi=on,pcie=on,memory-backend=mem"]);
+        } else {
+            cmd.args(["-machine", "microvm,acpi=on,pcie=on"]);
+        }
+        if let Some((_, socket_path)) = &virtiofsd {
+            cmd.args([
+                "-chardev",
+                format!("socket,id=virtiofs,path={}", socket_path.display()).as_str(),
+            ]);
+            cmd.args([
+                "-device",
+                format!("vhost-user-fs-pci,chardev=virtiofs,tag={SHARED_DIRECTORY_TAG},rombar=0")
+                    .as_str(),
+            ]);
+        }
         // Route first serial port to console.
         if let Some(port) = params.telnet_console {
             cmd.args(["-serial", format!("telnet:localhost:{port},server").as_str()]);
@@ -146,25 +294,74 @@ impl Qemu {
         // Set up the networking. `rombar=0` is so that QEMU wouldn't bother with the
         // `efi-virtio.rom` file, as we're not using EFI anyway.
         let vm_address = crate::VM_LOCAL_ADDRESS;
-        let vm_orchestrator_port = crate::VM_ORCHESTRATOR_LOCAL_PORT;
-        let host_address = Ipv4Addr::LOCALHOST;
-
-        let mut netdev_rules = vec![
-            "user".to_string(),
-            "id=netdev".to_string(),
-            format!("guestfwd=tcp:10.0.2.100:8080-cmd:nc {host_address} {launcher_service_port}"),
-            format!(
-                "hostfwd=tcp:{host_address}:{host_orchestrator_proxy_port}-{vm_address}:{vm_orchestrator_port}"
-            ),
-        ];
-        if let Some(host_proxy_port) = host_proxy_port {
-            let vm_port = crate::VM_LOCAL_PORT;
-            netdev_rules.push(format!(
-                "hostfwd=tcp:{host_address}:{host_proxy_port}-{vm_address}:{vm_port}"
-            ));
-        };
-        cmd.args(["-netdev", netdev_rules.join(",").as_str()]);
+        match params.network_backend {
+            NetworkBackend::User => {
+                let vm_orchestrator_port = crate::VM_ORCHESTRATOR_LOCAL_PORT;
+                let host_address = Ipv4Addr::LOCALHOST;
+                let launcher_address = crate::LAUNCHER_GUEST_ADDRESS;
+                let launcher_port = crate::LAUNCHER_GUEST_PORT;
+                let mut netdev_rules = vec![
+                    "user".to_string(),
+                    "id=netdev".to_string(),
+                    format!(
+                        "guestfwd=tcp:{launcher_address}:{launcher_port}-cmd:nc {host_address} {launcher_service_port}"
+                    ),
+                    format!(
+                        "hostfwd=tcp:{host_address}:{host_orchestrator_proxy_port}-{vm_address}:{vm_orchestrator_port}"
+                    ),
+                ];
+                if let Some(host_proxy_port) = host_proxy_port {
+                    let vm_port = crate::VM_LOCAL_PORT;
+                    netdev_rules.push(format!(
+                        "hostfwd=tcp:{host_address}:{host_proxy_port}-{vm_address}:{vm_port}"
+                    ));
+                };
+                cmd.args(["-netdev", netdev_rules.join(",").as_str()]);
+            }
+            NetworkBackend::Tap => {
+                let tap_interface =
+                    params.tap_interface.as_deref().context("tap interface not set")?;
+                cmd.args([
+                    "-netdev",
+                    format!(
+                        "tap,id=netdev,ifname={tap_interface},script=no,downscript=no,vhost=on"
+                    )
+                    .as_str(),
+                ]);
+            }
+            NetworkBackend::VhostUser => {
+                let socket_path = params
+                    .vhost_user_net_socket
+                    .as_ref()
+                    .context("vhost-user network socket not set")?;
+                cmd.args([
+                    "-chardev",
+                    format!("socket,id=vhostnet,path={}", socket_path.display()).as_str(),
+                ]);
+                cmd.args(["-netdev", "vhost-user,id=netdev,chardev=vhostnet"]);
+            }
+        }
         cmd.args(["-device", "virtio-net,netdev=netdev,rombar=0"]);
+        for address in &params.vfio_devices {
+            cmd.args(["-device", format!("vfio-pci,host={address},rombar=0").as_str()]);
+        }
+        if let Some(scratch_disk) = &params.scratch_disk {
+            // Don't cache the disk on the host too, as the guest caches the decrypted
+            // data itself.
+            cmd.args([
+                "-drive",
+                format!(
+                    "if=none,id=scratch,file={},format=raw,cache=none,discard=unmap",
+                    scratch_disk.display()
+                )
+                .as_str(),
+            ]);
+            cmd.args([
+                "-device",
+                format!("virtio-blk-pci,drive=scratch,serial={SCRATCH_DISK_SERIAL},rombar=0")
+                    .as_str(),
+            ]);
+        }
         if let Some(virtio_guest_cid) = params.virtio_guest_cid {
             cmd.args([
                 "-device",
@@ -189,6 +386,9 @@ impl Qemu {
                 format!("ip={vm_address}:::255.255.255.0::eth0:off").as_str(),
                 "quiet",
             ]
+            .into_iter()
+            .chain(params.kernel_args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
             .join(" ")
             .as_str(),
         ]);
@@ -210,11 +410,18 @@ impl Qemu {
 
         let instance = cmd.spawn()?;
 
-        Ok(Self { instance, guest_cid: params.virtio_guest_cid })
+        Ok(Self {
+            instance,
+            virtiofsd: virtiofsd.map(|(virtiofsd, _)| virtiofsd),
+            guest_cid: params.virtio_guest_cid,
+        })
     }
 
     pub async fn kill(&mut self) -> Result<std::process::ExitStatus> {
         self.instance.start_kill()?;
+        if let Some(virtiofsd) = self.virtiofsd.as_mut() {
+            virtiofsd.start_kill()?;
+        }
         self.wait().await
     }
 
@@ -226,3 +433,85 @@ impl Qemu {
         self.guest_cid
     }
 }
+
+// Starts virtiofsd exporting `shared_directory` read-only, and returns it
+// together with the path of the socket QEMU connects to.
+fn start_virtiofsd(
+    virtiofsd_binary: &Path,
+    shared_directory: &Path,
+) -> Result<(tokio::process::Child, PathBuf)> {
+    let socket_path =
+        std::env::temp_dir().join(format!("oak_virtiofs_{}.sock", std::process::id()));
+    // A socket left behind by a previous launcher with the same pid would make
+    // virtiofsd fail.
+    let _ = std::fs::remove_file(&socket_path);
+
+    let mut cmd = tokio::process::Command::new(virtiofsd_binary);
+    cmd.kill_on_drop(true);
+    cmd.stdin(Stdio::null());
+    cmd.arg(format!("--socket-path={}", socket_path.display()));
+    cmd.arg(format!("--shared-dir={}", shared_directory.display()));
+    cmd.arg("--readonly");
+    log::debug!("virtiofsd command line: {:?}", cmd);
+    let mut virtiofsd = cmd.spawn().context("couldn't start virtiofsd")?;
+
+    // QEMU fails to start if the socket doesn't exist yet.
+    let start = Instant::now();
+    while !socket_path.exists() {
+        if let Some(status) = virtiofsd.try_wait()? {
+            bail!("virtiofsd exited with {}", status);
+        }
+        if start.elapsed() > VIRTIOFSD_STARTUP_TIMEOUT {
+            bail!("virtiofsd didn't create its socket in time");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok((virtiofsd, socket_path))
+}
+
+// Checks that the host device at PCI `address` can be passed through to the
+// guest, so that we fail with a clear error rather than QEMU's.
+fn check_vfio_device(address: &str) -> Result<()> {
+    let device = Path::new(PCI_DEVICES_SYSFS_PATH).join(address);
+    if !device.exists() {
+        bail!("there is no PCI device at {address}");
+    }
+    let iommu_group = std::fs::read_link(device.join("iommu_group")).with_context(|| {
+        format!("PCI device {address} isn't in an IOMMU group; is the IOMMU enabled?")
+    })?;
+    let group =
+        iommu_group.file_name().and_then(|group| group.to_str()).context("invalid IOMMU group")?;
+    if driver(&device)?.as_deref() != Some("vfio-pci") {
+        bail!("PCI device {address} isn't bound to the vfio-pci driver");
+    }
+    for entry in std::fs::read_dir(device.join("iommu_group/devices"))
+        .with_context(|| format!("couldn't list the devices in IOMMU group {group}"))?
+    {
+        let other = entry?.path();
+        if let Some(driver) = driver(&other)? {
+            if !VFIO_GROUP_DRIVERS.contains(&driver.as_str()) {
+                bail!(
+                    "PCI device {address} shares IOMMU group {group} with {}, which is bound to {driver}",
+                    other.file_name().unwrap_or_default().to_string_lossy()
+                );
+            }
+        }
+    }
+    let group_device = Path::new("/dev/vfio").join(group);
+    if !group_device.exists() {
+        bail!("{} doesn't exist; is the vfio-pci module loaded?", group_device.display());
+    }
+    Ok(())
+}
+
+// Returns the name of the host driver bound to the PCI device at `device`, if
+// any.
+fn driver(device: &Path) -> Result<Option<String>> {
+    match std::fs::read_link(device.join("driver")) {
+        Ok(driver) => Ok(driver.file_name().map(|name| name.to_string_lossy().into_owned())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| format!("couldn't read driver of {}", device.display()))
+        }
+    }
+}
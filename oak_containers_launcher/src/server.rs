@@ -40,15 +40,24 @@ use tokio::{
     sync::oneshot::{Receiver, Sender},
 };
 use tokio_stream::wrappers::TcpListenerStream;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
 
-use crate::proto::oak::containers::{
-    launcher_server::{Launcher, LauncherServer},
-    v1::{
-        hostlib_key_provisioning_server::{HostlibKeyProvisioning, HostlibKeyProvisioningServer},
-        GetGroupKeysResponse, GetKeyProvisioningRoleResponse, KeyProvisioningRole,
+use crate::{
+    proto::oak::containers::{
+        guest_telemetry_collector_server::{
+            GuestTelemetryCollector, GuestTelemetryCollectorServer,
+        },
+        launcher_server::{Launcher, LauncherServer},
+        v1::{
+            hostlib_key_provisioning_server::{
+                HostlibKeyProvisioning, HostlibKeyProvisioningServer,
+            },
+            GetGroupKeysResponse, GetKeyProvisioningRoleResponse, KeyProvisioningRole,
+        },
+        GetApplicationConfigResponse, GetContainerImageReferenceResponse, GetImageResponse,
+        GuestTelemetry, SendAttestationEvidenceRequest,
     },
-    GetApplicationConfigResponse, GetImageResponse, SendAttestationEvidenceRequest,
+    telemetry::TelemetryCollector,
 };
 
 // Most gRPC implementations limit message sizes to 4MiB. Let's stay
@@ -60,13 +69,16 @@ type GetImageResponseStream = Pin<Box<dyn Stream<Item = Result<GetImageResponse,
 #[derive(Default)]
 struct LauncherServerImplementation {
     system_image: std::path::PathBuf,
-    container_bundle: std::path::PathBuf,
+    container_bundle: Option<std::path::PathBuf>,
+    container_image: Option<String>,
     application_config: Vec<u8>,
     // Will be used to send the Attestation Evidence to the Launcher.
     evidence_sender: Mutex<Option<Sender<Evidence>>>,
     // Will be used to notify the untrusted application that the trusted application is ready and
     // listening on a socket address.
     app_ready_notifier: Mutex<Option<Sender<()>>>,
+    // Holds the telemetry that the agent in the system image streams about the guest.
+    telemetry_collector: Arc<TelemetryCollector>,
 }
 
 #[tonic::async_trait]
@@ -106,7 +118,10 @@ impl Launcher for LauncherServerImplementation {
         &self,
         _request: Request<()>,
     ) -> Result<Response<Self::GetContainerBundleStream>, tonic::Status> {
-        let container_bundle_file = tokio::fs::File::open(&self.container_bundle).await?;
+        let container_bundle = self.container_bundle.as_ref().ok_or_else(|| {
+            tonic::Status::failed_precondition("the container image is pulled from a registry")
+        })?;
+        let container_bundle_file = tokio::fs::File::open(container_bundle).await?;
 
         let mut buffer = vec![0_u8; MAX_RESPONSE_SIZE];
         let mut reader = BufReader::new(container_bundle_file);
@@ -130,6 +145,15 @@ impl Launcher for LauncherServerImplementation {
         Ok(Response::new(Box::pin(response_stream) as Self::GetContainerBundleStream))
     }
 
+    async fn get_container_image_reference(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<GetContainerImageReferenceResponse>, tonic::Status> {
+        Ok(tonic::Response::new(GetContainerImageReferenceResponse {
+            image_reference: self.container_image.clone().unwrap_or_default(),
+        }))
+    }
+
     async fn get_application_config(
         &self,
         _request: Request<()>,
@@ -206,6 +230,20 @@ impl HostlibKeyProvisioning for LauncherServerImplementation {
     }
 }
 
+#[tonic::async_trait]
+impl GuestTelemetryCollector for LauncherServerImplementation {
+    async fn stream_guest_telemetry(
+        &self,
+        request: Request<Streaming<GuestTelemetry>>,
+    ) -> Result<Response<()>, tonic::Status> {
+        let mut stream = request.into_inner();
+        while let Some(telemetry) = stream.message().await? {
+            self.telemetry_collector.record(telemetry);
+        }
+        Ok(Response::new(()))
+    }
+}
+
 #[tonic::async_trait]
 impl MetricsService for LauncherServerImplementation {
     async fn export(
@@ -233,7 +271,11 @@ impl LogsService for LauncherServerImplementation {
                             .attributes
                             .iter()
                             .find_map(|x| {
-                                if x.key == "_SYSTEMD_UNIT" { x.value.as_ref() } else { None }
+                                if x.key == "_SYSTEMD_UNIT" {
+                                    x.value.as_ref()
+                                } else {
+                                    None
+                                }
                             })
                             .and_then(|value| value.value.as_ref())
                             .and_then(|value| match value {
@@ -262,22 +304,27 @@ impl LogsService for LauncherServerImplementation {
 pub async fn new(
     listener: TcpListener,
     system_image: std::path::PathBuf,
-    container_bundle: std::path::PathBuf,
+    container_bundle: Option<std::path::PathBuf>,
+    container_image: Option<String>,
     application_config: Vec<u8>,
     evidence_sender: Sender<Evidence>,
     app_ready_notifier: Sender<()>,
+    telemetry_collector: Arc<TelemetryCollector>,
     shutdown: Receiver<()>,
 ) -> Result<(), anyhow::Error> {
     let server_impl = Arc::new(LauncherServerImplementation {
         system_image,
         container_bundle,
+        container_image,
         application_config,
         evidence_sender: Mutex::new(Some(evidence_sender)),
         app_ready_notifier: Mutex::new(Some(app_ready_notifier)),
+        telemetry_collector,
     });
     Server::builder()
         .add_service(LauncherServer::from_arc(server_impl.clone()))
         .add_service(HostlibKeyProvisioningServer::from_arc(server_impl.clone()))
+        .add_service(GuestTelemetryCollectorServer::from_arc(server_impl.clone()))
         .add_service(MetricsServiceServer::from_arc(server_impl.clone()))
         .add_service(LogsServiceServer::from_arc(server_impl))
         .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown.map(|_| ()))
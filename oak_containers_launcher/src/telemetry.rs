@@ -0,0 +1,163 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Collects the telemetry that the agent in the system image streams about the
+//! guest, and re-exports it in the Prometheus text format.
+
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::proto::oak::containers::GuestTelemetry;
+
+/// Holds the latest telemetry snapshot received from the guest.
+#[derive(Default)]
+pub struct TelemetryCollector {
+    latest: Mutex<Option<GuestTelemetry>>,
+}
+
+impl TelemetryCollector {
+    /// Records a snapshot from the guest, logging the events since the previous
+    /// one.
+    pub fn record(&self, telemetry: GuestTelemetry) {
+        let mut latest = self.latest.lock().expect("telemetry lock poisoned");
+        let previous = latest.clone().unwrap_or_default();
+        if telemetry.oom_kills > previous.oom_kills {
+            log::warn!(
+                "guest killed {} process(es) as it ran out of memory",
+                telemetry.oom_kills - previous.oom_kills
+            );
+        }
+        if telemetry.container_restarts > previous.container_restarts {
+            log::warn!("guest container was restarted");
+        }
+        if previous.container_running && !telemetry.container_running {
+            log::warn!("guest container stopped");
+        }
+        *latest = Some(telemetry);
+    }
+
+    /// Returns the latest snapshot, if the guest has sent any.
+    pub fn latest(&self) -> Option<GuestTelemetry> {
+        self.latest.lock().expect("telemetry lock poisoned").clone()
+    }
+
+    /// Formats the latest snapshot in the Prometheus text format. Empty if the
+    /// guest hasn't sent any.
+    pub fn to_prometheus(&self) -> String {
+        let telemetry = match self.latest() {
+            Some(telemetry) => telemetry,
+            None => return String::new(),
+        };
+        let metrics: [(&str, &str, &str, f64); 7] = [
+            (
+                "oak_guest_cpu_busy_seconds_total",
+                "counter",
+                "Time the guest CPUs have spent busy, summed over all CPUs.",
+                telemetry.cpu_busy_milliseconds as f64 / 1000.0,
+            ),
+            (
+                "oak_guest_cpu_idle_seconds_total",
+                "counter",
+                "Time the guest CPUs have spent idle, summed over all CPUs.",
+                telemetry.cpu_idle_milliseconds as f64 / 1000.0,
+            ),
+            (
+                "oak_guest_memory_total_bytes",
+                "gauge",
+                "Memory available to the guest kernel.",
+                telemetry.memory_total_bytes as f64,
+            ),
+            (
+                "oak_guest_memory_available_bytes",
+                "gauge",
+                "Memory available to guest processes without swapping.",
+                telemetry.memory_available_bytes as f64,
+            ),
+            (
+                "oak_guest_oom_kills_total",
+                "counter",
+                "Processes the guest kernel killed as it ran out of memory.",
+                telemetry.oom_kills as f64,
+            ),
+            (
+                "oak_guest_container_running",
+                "gauge",
+                "Whether the container is running.",
+                u8::from(telemetry.container_running).into(),
+            ),
+            (
+                "oak_guest_container_restarts_total",
+                "counter",
+                "Times the container was started after the first time.",
+                telemetry.container_restarts as f64,
+            ),
+        ];
+        let mut result = String::new();
+        for (name, metric_type, help, value) in metrics {
+            // Writing to a string can't fail.
+            let _ = write!(
+                result,
+                "# HELP {name} {help}\n# TYPE {name} {metric_type}\n{name} {value}\n"
+            );
+        }
+        result
+    }
+}
+
+/// Serves the telemetry on `address` for Prometheus to scrape. This only
+/// implements enough of HTTP to answer scrapes: every request gets the metrics,
+/// regardless of its path.
+pub async fn serve(address: SocketAddr, collector: Arc<TelemetryCollector>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("couldn't listen for metrics scrapes on {address}"))?;
+    log::info!("Serving guest telemetry on http://{address}/metrics");
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let collector = collector.clone();
+        tokio::spawn(async move {
+            // Read the request headers, which we don't need, so the client doesn't get
+            // a reset when we close the connection.
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => request.extend_from_slice(&buffer[..len]),
+                }
+                if request.len() > 64 * 1024 {
+                    return;
+                }
+            }
+            let body = collector.to_prometheus();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                log::debug!("couldn't send metrics: {err}");
+            }
+        });
+    }
+}
@@ -0,0 +1,93 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Finds the device nodes of the accelerators (e.g. GPUs) that the launcher
+//! passes through to the guest, so that they can be made available to the
+//! container.
+
+use std::{
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use nix::sys::stat::{major, minor};
+
+/// Directories holding the device nodes of accelerators using the standard
+/// kernel interfaces: DRM for GPUs, and the compute accelerator subsystem.
+const ACCELERATOR_DEVICE_DIRECTORIES: &[&str] = &["/dev/dri", "/dev/accel"];
+
+/// The NVIDIA driver doesn't use either of the standard interfaces, and puts
+/// its device nodes (`/dev/nvidia0`, `/dev/nvidiactl`, ...) straight in
+/// `/dev`.
+const NVIDIA_DEVICE_PREFIX: &str = "nvidia";
+
+/// A character device node of an accelerator.
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub path: PathBuf,
+    pub major: u64,
+    pub minor: u64,
+}
+
+/// Returns the device nodes of all the accelerators in the guest. These only
+/// exist if the launcher passed through a device and the system image has a
+/// driver for it.
+pub async fn find_devices() -> anyhow::Result<Vec<Device>> {
+    let mut devices = Vec::new();
+    for directory in ACCELERATOR_DEVICE_DIRECTORIES {
+        devices.extend(list_devices(Path::new(directory), |_| true).await?);
+    }
+    devices.extend(
+        list_devices(Path::new("/dev"), |name| name.starts_with(NVIDIA_DEVICE_PREFIX)).await?,
+    );
+    for device in &devices {
+        log::info!("found accelerator device {}", device.path.display());
+    }
+    Ok(devices)
+}
+
+async fn list_devices(
+    directory: &Path,
+    filter: impl Fn(&str) -> bool,
+) -> anyhow::Result<Vec<Device>> {
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        // The directory only exists if there is a device using that interface.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("couldn't list {}", directory.display()))
+        }
+    };
+    let mut devices = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_name().to_str().is_some_and(&filter) {
+            continue;
+        }
+        // Don't follow symlinks, e.g. `/dev/dri/by-path`, so each device is only
+        // listed once.
+        let metadata = tokio::fs::symlink_metadata(entry.path()).await?;
+        if !metadata.file_type().is_char_device() {
+            continue;
+        }
+        devices.push(Device {
+            path: entry.path(),
+            major: major(metadata.rdev()),
+            minor: minor(metadata.rdev()),
+        });
+    }
+    Ok(devices)
+}
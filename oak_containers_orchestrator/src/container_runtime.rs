@@ -20,27 +20,55 @@ use std::{
 
 use anyhow::Context;
 use nix::unistd::{Gid, Uid};
-use oci_spec::runtime::{LinuxIdMapping, LinuxIdMappingBuilder, Mount, Spec};
+use oci_spec::runtime::{
+    LinuxDeviceBuilder, LinuxDeviceCgroup, LinuxDeviceType, LinuxIdMapping, LinuxIdMappingBuilder,
+    Mount, Spec,
+};
 use tokio_util::sync::CancellationToken;
 
+use crate::accelerators::Device;
+
+/// Name of the systemd unit that the container runs in, through which the
+/// agent in the system image monitors it.
+pub const CONTAINER_UNIT: &str = "oak-container.service";
+
+/// Unpacks the container bundle provided by the launcher into `container_dir`.
+pub async fn unpack_bundle(container_bundle: &[u8], container_dir: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(container_dir).await?;
+    log::info!("Unpacking container bundle");
+    let mut archive = tar::Archive::new(container_bundle);
+    archive.unpack(container_dir)?;
+    Ok(())
+}
+
+/// Runs the container in `container_dir`, which must hold an OCI runtime
+/// bundle.
 pub async fn run(
-    container_bundle: &[u8],
     container_dir: &Path,
     runtime_uid: Uid,
     runtime_gid: Gid,
     ipc_socket_path: &Path,
+    shared_directory: Option<&Path>,
+    scratch_directory: Option<&Path>,
+    devices: &[Device],
     cancellation_token: CancellationToken,
 ) -> Result<(), anyhow::Error> {
-    tokio::fs::create_dir_all(container_dir).await?;
-    log::info!("Unpacking container bundle");
-    let mut archive = tar::Archive::new(container_bundle);
-    archive.unpack(container_dir)?;
-
     for entry in walkdir::WalkDir::new(container_dir) {
         let entry = entry?;
         lchown(entry.path(), Some(runtime_uid.into()), Some(runtime_gid.into()))
             .context(format!("failed to chown path {:?}", entry.path()))?;
     }
+    // The scratch volume is freshly formatted, so only root can write to it.
+    if let Some(scratch_directory) = scratch_directory {
+        lchown(scratch_directory, Some(runtime_uid.into()), Some(runtime_gid.into()))
+            .context(format!("failed to chown path {:?}", scratch_directory))?;
+    }
+    // The container runs as the runtime user, and runc bind-mounts the device
+    // nodes into it as it can't create them, so that user has to own them.
+    for device in devices {
+        lchown(&device.path, Some(runtime_uid.into()), Some(runtime_gid.into()))
+            .context(format!("failed to chown device {:?}", device.path))?;
+    }
 
     log::info!("Setting up container");
 
@@ -55,6 +83,26 @@ pub async fn run(
         mount.set_options(Some(vec!["rbind".to_string()]));
         mount
     });
+    if let Some(shared_directory) = shared_directory {
+        mounts.push({
+            let mut mount = Mount::default();
+            mount.set_source(Some(shared_directory.into()));
+            mount.set_destination(PathBuf::from("/oak_shared"));
+            mount.set_typ(Some("bind".to_string()));
+            mount.set_options(Some(vec!["rbind".to_string(), "ro".to_string()]));
+            mount
+        });
+    }
+    if let Some(scratch_directory) = scratch_directory {
+        mounts.push({
+            let mut mount = Mount::default();
+            mount.set_source(Some(scratch_directory.into()));
+            mount.set_destination(PathBuf::from("/oak_scratch"));
+            mount.set_typ(Some("bind".to_string()));
+            mount.set_options(Some(vec!["rbind".to_string()]));
+            mount
+        });
+    }
     spec.set_mounts(Some(mounts));
     let mut linux = spec.linux().as_ref().cloned().unwrap_or_default();
     let uid_mappings: Option<Vec<LinuxIdMapping>> = linux.uid_mappings().as_ref().map(|x| {
@@ -83,6 +131,29 @@ pub async fn run(
             .collect()
     });
     linux.set_gid_mappings(gid_mappings);
+    if !devices.is_empty() {
+        let linux_devices = devices
+            .iter()
+            .map(|device| {
+                LinuxDeviceBuilder::default()
+                    .path(device.path.clone())
+                    .typ(LinuxDeviceType::C)
+                    .major(device.major as i64)
+                    .minor(device.minor as i64)
+                    .build()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        // Allow the container to use the devices in the device cgroup as well.
+        let mut resources = linux.resources().as_ref().cloned().unwrap_or_default();
+        let mut device_cgroup = resources.devices().as_ref().cloned().unwrap_or_default();
+        device_cgroup.extend(linux_devices.iter().map(LinuxDeviceCgroup::from));
+        resources.set_devices(Some(device_cgroup));
+        linux.set_resources(Some(resources));
+        let mut all_devices = linux.devices().as_ref().cloned().unwrap_or_default();
+        all_devices.extend(linux_devices);
+        linux.set_devices(Some(all_devices));
+    }
     spec.set_linux(Some(linux));
     spec.save(spec_path).context("error writing OCI spec")?;
 
@@ -91,6 +162,7 @@ pub async fn run(
         let container_dir: &str =
             container_dir.as_os_str().try_into().expect("invalid container path");
         cmd.args([
+            format!("--unit={CONTAINER_UNIT}").as_str(),
             "--property=RuntimeDirectory=oakc",
             "--property=ProtectSystem=strict",
             format!("--property=ReadWritePaths={}", container_dir).as_str(),
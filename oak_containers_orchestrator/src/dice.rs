@@ -66,6 +66,21 @@ pub fn measure_container_and_config(
     let mut container_digest = Sha256::default();
     container_digest.update(container_bytes);
     let container_digest = container_digest.finalize();
+    container_claims(&container_digest, config_bytes)
+}
+
+/// Measures the configuration and returns it, together with the digest of the
+/// container image pulled from a registry, as a vector of additional CWT
+/// claims. The image digest takes the place of the digest of the container
+/// bundle.
+pub fn measure_container_image_and_config(
+    image_digest: &[u8; 32],
+    config_bytes: &[u8],
+) -> Vec<(ClaimName, Value)> {
+    container_claims(image_digest, config_bytes)
+}
+
+fn container_claims(container_digest: &[u8], config_bytes: &[u8]) -> Vec<(ClaimName, Value)> {
     let mut config_digest = Sha256::default();
     config_digest.update(config_bytes);
     let config_digest = config_digest.finalize();
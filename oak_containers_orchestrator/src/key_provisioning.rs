@@ -15,7 +15,8 @@
 
 use std::sync::Arc;
 
-use oak_attestation_verification::verifier::verify_dice_chain;
+use oak_attestation_verification::{policy::appraise, verifier::verify_dice_chain};
+use oak_proto_rust::oak::attestation::v1::AppraisalPolicy;
 use tokio::net::TcpListener;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::sync::CancellationToken;
@@ -31,11 +32,15 @@ use crate::{
 
 struct KeyProvisioningService {
     group_keys: Arc<GroupKeys>,
+    peer_attestation_policy: Option<AppraisalPolicy>,
 }
 
 impl KeyProvisioningService {
-    pub fn new(group_keys: Arc<GroupKeys>) -> Self {
-        Self { group_keys }
+    pub fn new(
+        group_keys: Arc<GroupKeys>,
+        peer_attestation_policy: Option<AppraisalPolicy>,
+    ) -> Self {
+        Self { group_keys, peer_attestation_policy }
     }
 }
 
@@ -52,7 +57,7 @@ impl KeyProvisioning for KeyProvisioningService {
         let evidence = request
             .evidence
             .ok_or(tonic::Status::invalid_argument("request message doesn't contain evidence"))?;
-        let _endorsements = request.endorsements.ok_or(tonic::Status::invalid_argument(
+        let endorsements = request.endorsements.ok_or(tonic::Status::invalid_argument(
             "request message doesn't contain endorsements",
         ))?;
         // TODO(#4442): Provide reference values by the hostlib and use `verify`
@@ -60,6 +65,13 @@ impl KeyProvisioning for KeyProvisioningService {
         let attestation_results = verify_dice_chain(&evidence).map_err(|err| {
             tonic::Status::invalid_argument(format!("couldn't verify endorsed evidence: {err}"))
         })?;
+        if let Some(policy) = self.peer_attestation_policy.as_ref() {
+            appraise(policy, &attestation_results, &endorsements).map_err(|err| {
+                tonic::Status::permission_denied(format!(
+                    "evidence doesn't satisfy the attestation policy: {err:#}"
+                ))
+            })?;
+        }
 
         // Encrypt group keys.
         let encrypted_encryption_private_key = self
@@ -79,9 +91,11 @@ impl KeyProvisioning for KeyProvisioningService {
 pub async fn create(
     address: &str,
     group_keys: Arc<GroupKeys>,
+    peer_attestation_policy: Option<AppraisalPolicy>,
     cancellation_token: CancellationToken,
 ) -> Result<(), anyhow::Error> {
-    let key_provisioning_service_instance = KeyProvisioningService::new(group_keys);
+    let key_provisioning_service_instance =
+        KeyProvisioningService::new(group_keys, peer_attestation_policy);
 
     let listener = TcpListener::bind(address).await?;
 
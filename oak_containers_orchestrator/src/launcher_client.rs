@@ -15,14 +15,16 @@
 
 use anyhow::Context;
 use opentelemetry_otlp::{TonicExporterBuilder, WithExportConfig};
+use tokio_stream::Stream;
 use tonic::transport::Channel;
 
 use crate::proto::oak::{
     attestation::v1::Evidence,
     containers::{
+        guest_telemetry_collector_client::GuestTelemetryCollectorClient,
         launcher_client::LauncherClient as GrpcLauncherClient,
         v1::{hostlib_key_provisioning_client::HostlibKeyProvisioningClient, KeyProvisioningRole},
-        SendAttestationEvidenceRequest,
+        GuestTelemetry, SendAttestationEvidenceRequest,
     },
     key_provisioning::v1::GroupKeys,
 };
@@ -32,14 +34,16 @@ pub struct LauncherClient {
     addr: tonic::transport::Uri,
     inner: GrpcLauncherClient<Channel>,
     hostlib_key_provisioning_client: HostlibKeyProvisioningClient<Channel>,
+    guest_telemetry_collector_client: GuestTelemetryCollectorClient<Channel>,
 }
 
 impl LauncherClient {
     pub async fn create(addr: tonic::transport::Uri) -> Result<Self, Box<dyn std::error::Error>> {
         let channel = Channel::builder(addr.clone()).connect().await?;
         let inner = GrpcLauncherClient::new(channel.clone());
-        let hostlib_key_provisioning_client = HostlibKeyProvisioningClient::new(channel);
-        Ok(Self { addr, inner, hostlib_key_provisioning_client })
+        let hostlib_key_provisioning_client = HostlibKeyProvisioningClient::new(channel.clone());
+        let guest_telemetry_collector_client = GuestTelemetryCollectorClient::new(channel);
+        Ok(Self { addr, inner, hostlib_key_provisioning_client, guest_telemetry_collector_client })
     }
 
     pub async fn get_container_bundle(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -61,6 +65,20 @@ impl LauncherClient {
         Ok(container_buf)
     }
 
+    /// Returns the reference of the container image to pull from a registry, or
+    /// `None` if the launcher provides the container bundle.
+    pub async fn get_container_image_reference(&self) -> anyhow::Result<Option<String>> {
+        match self.inner.clone().get_container_image_reference(()).await {
+            Ok(response) => {
+                let image_reference = response.into_inner().image_reference;
+                Ok((!image_reference.is_empty()).then_some(image_reference))
+            }
+            // Launchers that predate pulling images always provide the bundle.
+            Err(status) if status.code() == tonic::Code::Unimplemented => Ok(None),
+            Err(status) => Err(status).context("couldn't get container image reference"),
+        }
+    }
+
     pub async fn get_application_config(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let application_config = self
             .inner
@@ -120,6 +138,20 @@ impl LauncherClient {
             .context("get group keys weren't provided")
     }
 
+    /// Streams the guest telemetry to the launcher. Returns once `telemetry`
+    /// ends or the launcher closes the stream.
+    pub async fn stream_guest_telemetry(
+        &self,
+        telemetry: impl Stream<Item = GuestTelemetry> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        self.guest_telemetry_collector_client
+            .clone()
+            .stream_guest_telemetry(telemetry)
+            .await
+            .context("couldn't stream guest telemetry")?;
+        Ok(())
+    }
+
     pub fn openmetrics_builder(&self) -> TonicExporterBuilder {
         opentelemetry_otlp::new_exporter().tonic().with_endpoint(self.addr.clone().to_string())
     }
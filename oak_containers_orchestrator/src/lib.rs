@@ -35,6 +35,7 @@ pub mod proto {
     }
 }
 
+pub mod accelerators;
 pub mod container_runtime;
 pub mod crypto;
 pub mod dice;
@@ -43,3 +44,6 @@ pub mod key_provisioning;
 pub mod launcher_client;
 pub mod logging;
 pub mod metrics;
+pub mod oci_registry;
+pub mod scratch_volume;
+pub mod shared_directory;
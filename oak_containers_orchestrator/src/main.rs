@@ -17,8 +17,9 @@ use std::{path::PathBuf, sync::Arc};
 
 use anyhow::{anyhow, Context};
 use clap::Parser;
+use oak_attestation_verification::policy::parse_policy_json;
 use oak_containers_orchestrator::{
-    crypto::generate_instance_keys, launcher_client::LauncherClient,
+    crypto::generate_instance_keys, launcher_client::LauncherClient, oci_registry::ImageReference,
     proto::oak::containers::v1::KeyProvisioningRole,
 };
 use tokio_util::sync::CancellationToken;
@@ -42,6 +43,20 @@ struct Args {
 
     #[arg(long, default_value = "oakc")]
     runtime_user: String,
+
+    /// Where to mount the directory shared by the launcher, if any.
+    #[arg(long, default_value = "/oak_shared")]
+    shared_directory: PathBuf,
+
+    /// Where to mount the encrypted scratch volume, if the launcher attached a
+    /// scratch disk.
+    #[arg(long, default_value = "/oak_scratch")]
+    scratch_directory: PathBuf,
+
+    /// JSON appraisal policy that the evidence of followers must satisfy
+    /// before they are provisioned with the group keys.
+    #[arg(long)]
+    peer_attestation_policy: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -50,6 +65,17 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
+    let peer_attestation_policy = args
+        .peer_attestation_policy
+        .as_ref()
+        .map(|path| {
+            let json =
+                std::fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+            parse_policy_json(&json)
+        })
+        .transpose()
+        .context("couldn't load peer attestation policy")?;
+
     let launcher_client = Arc::new(
         LauncherClient::create(args.launcher_addr.parse()?)
             .await
@@ -73,21 +99,41 @@ async fn main() -> anyhow::Result<()> {
         };
 
     // Load application.
-    let container_bundle = launcher_client
-        .get_container_bundle()
-        .await
-        .map_err(|error| anyhow!("couldn't get container bundle: {:?}", error))?;
+    let image_reference = launcher_client.get_container_image_reference().await?;
     let application_config = launcher_client
         .get_application_config()
         .await
         .map_err(|error| anyhow!("couldn't get application config: {:?}", error))?;
+    let additional_claims = match image_reference {
+        Some(image_reference) => {
+            let image_reference: ImageReference = image_reference.parse()?;
+            oak_containers_orchestrator::oci_registry::pull(&image_reference, &args.container_dir)
+                .await
+                .context("couldn't pull container image")?;
+            oak_containers_orchestrator::dice::measure_container_image_and_config(
+                &image_reference.digest,
+                &application_config,
+            )
+        }
+        None => {
+            let container_bundle = launcher_client
+                .get_container_bundle()
+                .await
+                .map_err(|error| anyhow!("couldn't get container bundle: {:?}", error))?;
+            oak_containers_orchestrator::container_runtime::unpack_bundle(
+                &container_bundle,
+                &args.container_dir,
+            )
+            .await?;
+            oak_containers_orchestrator::dice::measure_container_and_config(
+                &container_bundle,
+                &application_config,
+            )
+        }
+    };
 
     // Generate attestation evidence and send it to the Hostlib.
     let dice_builder = oak_containers_orchestrator::dice::load_stage1_dice_data()?;
-    let additional_claims = oak_containers_orchestrator::dice::measure_container_and_config(
-        &container_bundle,
-        &application_config,
-    );
     let evidence = dice_builder.add_application_keys(
         additional_claims,
         &instance_public_keys.encryption_public_key,
@@ -122,6 +168,16 @@ async fn main() -> anyhow::Result<()> {
 
     let _metrics = oak_containers_orchestrator::metrics::run(launcher_client.clone())?;
 
+    let shared_directory =
+        oak_containers_orchestrator::shared_directory::mount_if_shared(&args.shared_directory)
+            .await?
+            .then_some(args.shared_directory.as_path());
+    let scratch_directory =
+        oak_containers_orchestrator::scratch_volume::mount_if_attached(&args.scratch_directory)
+            .await?
+            .then_some(args.scratch_directory.as_path());
+    let devices = oak_containers_orchestrator::accelerators::find_devices().await?;
+
     // Start application and gRPC servers.
     let user = nix::unistd::User::from_name(&args.runtime_user)
         .context(format!("error resolving user {}", args.runtime_user))?
@@ -139,14 +195,17 @@ async fn main() -> anyhow::Result<()> {
         oak_containers_orchestrator::key_provisioning::create(
             &args.orchestrator_addr,
             group_keys.context("group keys were not provisioned")?,
+            peer_attestation_policy,
             cancellation_token.clone(),
         ),
         oak_containers_orchestrator::container_runtime::run(
-            &container_bundle,
             &args.container_dir,
             user.uid,
             user.gid,
             &args.ipc_socket_path,
+            shared_directory,
+            scratch_directory,
+            &devices,
             cancellation_token,
         ),
     )?;
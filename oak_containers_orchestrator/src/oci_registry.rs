@@ -0,0 +1,566 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Pulls the container image from an OCI registry over the host network, as an
+//! alternative to the launcher providing the container bundle.
+//!
+//! The image is pinned by digest, and every manifest and blob is checked
+//! against the digest it is referred to by before it is used, so neither the
+//! host nor the registry can change what the orchestrator runs. The image
+//! digest is included in the attestation evidence in place of the digest of
+//! the container bundle. Layers are streamed to disk, and only unpacked once
+//! their digest has been verified.
+
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    io::{BufReader, Read},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use flate2::read::GzDecoder;
+use oci_spec::{
+    image::{Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType},
+    runtime::{Root, Spec},
+};
+use reqwest::{
+    header::{ACCEPT, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+const MANIFEST_MEDIA_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Media type of gzip-compressed layers in Docker images.
+const DOCKER_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// A container image in an OCI registry, pinned by the SHA2-256 digest of its
+/// manifest (or image index).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub digest: [u8; 32],
+}
+
+impl FromStr for ImageReference {
+    type Err = anyhow::Error;
+
+    /// Parses a reference of the form `registry/repository@sha256:digest`. A
+    /// tag before the digest is allowed, but ignored.
+    fn from_str(reference: &str) -> Result<Self, Self::Err> {
+        let (name, digest) = reference
+            .rsplit_once("@sha256:")
+            .with_context(|| format!("image reference {reference} isn't pinned by digest"))?;
+        let (registry, repository) = name
+            .split_once('/')
+            .with_context(|| format!("image reference {reference} doesn't name the registry"))?;
+        let repository =
+            repository.split_once(':').map_or(repository, |(repository, _)| repository);
+        if registry.is_empty() || repository.is_empty() {
+            bail!("invalid image reference {reference}");
+        }
+        let digest = hex::decode(digest)
+            .ok()
+            .and_then(|digest| digest.try_into().ok())
+            .with_context(|| format!("invalid digest in image reference {reference}"))?;
+        Ok(Self { registry: registry.to_string(), repository: repository.to_string(), digest })
+    }
+}
+
+impl Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}@sha256:{}", self.registry, self.repository, hex::encode(self.digest))
+    }
+}
+
+/// Pulls the image and unpacks it into an OCI runtime bundle in
+/// `container_dir`, like the one the launcher would otherwise provide.
+pub async fn pull(reference: &ImageReference, container_dir: &Path) -> anyhow::Result<()> {
+    log::info!("Pulling container image {reference}");
+    let mut client = RegistryClient::new(reference)?;
+
+    let mut manifest = client.get_manifest(&reference.digest).await?;
+    if let Some(media_type) = media_type(&manifest) {
+        if INDEX_MEDIA_TYPES.contains(&media_type.as_str()) {
+            let index = ImageIndex::from_reader(&manifest[..]).context("invalid image index")?;
+            let descriptor = index
+                .manifests()
+                .iter()
+                .find(|descriptor| {
+                    descriptor.platform().as_ref().map_or(false, |platform| {
+                        platform.os().to_string() == "linux"
+                            && platform.architecture().to_string() == "amd64"
+                    })
+                })
+                .context("the image index has no manifest for linux/amd64")?;
+            manifest = client.get_manifest(&parse_digest(descriptor)?).await?;
+        }
+    }
+    let manifest = ImageManifest::from_reader(&manifest[..]).context("invalid image manifest")?;
+
+    let image_config = client.get_blob(manifest.config()).await?;
+    let image_config = ImageConfiguration::from_reader(&image_config[..])
+        .context("invalid image configuration")?;
+
+    let rootfs = container_dir.join("rootfs");
+    tokio::fs::create_dir_all(&rootfs).await?;
+    let layer_path = container_dir.join("layer.blob");
+    for layer in manifest.layers() {
+        client.download_blob(layer, &layer_path).await?;
+        let blob = BufReader::new(std::fs::File::open(&layer_path)?);
+        unpack_layer(blob, layer.media_type(), &rootfs)
+            .with_context(|| format!("couldn't unpack layer {}", layer.digest()))?;
+    }
+    tokio::fs::remove_file(&layer_path).await?;
+
+    runtime_spec(&image_config)?
+        .save(container_dir.join("config.json"))
+        .context("error writing OCI spec")?;
+    Ok(())
+}
+
+/// A client for the OCI distribution API of a single repository, which can
+/// authenticate anonymously with a bearer token if the registry asks for one.
+struct RegistryClient {
+    http: reqwest::Client,
+    repository_url: String,
+    token: Option<String>,
+}
+
+impl RegistryClient {
+    fn new(reference: &ImageReference) -> anyhow::Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::builder().build()?,
+            repository_url: format!("https://{}/v2/{}", reference.registry, reference.repository),
+            token: None,
+        })
+    }
+
+    async fn get_manifest(&mut self, digest: &[u8; 32]) -> anyhow::Result<Vec<u8>> {
+        let digest_str = format!("sha256:{}", hex::encode(digest));
+        let manifest = self.get(&format!("manifests/{digest_str}"), MANIFEST_MEDIA_TYPES).await?;
+        verify_digest(&manifest, digest).with_context(|| format!("manifest {digest_str}"))?;
+        Ok(manifest)
+    }
+
+    async fn get_blob(&mut self, descriptor: &Descriptor) -> anyhow::Result<Vec<u8>> {
+        let blob = self.get(&format!("blobs/{}", descriptor.digest()), "*/*").await?;
+        let mut verifier = BlobVerifier::new(descriptor)?;
+        verifier.update(&blob)?;
+        verifier.finish()?;
+        Ok(blob)
+    }
+
+    /// Downloads a blob to `path`, verifying it as it arrives, so that layers
+    /// don't have to fit in memory. The file is removed if the blob doesn't
+    /// match its descriptor.
+    async fn download_blob(&mut self, descriptor: &Descriptor, path: &Path) -> anyhow::Result<()> {
+        let mut verifier = BlobVerifier::new(descriptor)?;
+        let mut response = self.send(&format!("blobs/{}", descriptor.digest()), "*/*").await?;
+        let mut file = tokio::fs::File::create(path).await?;
+        let result = async {
+            while let Some(chunk) = response.chunk().await? {
+                verifier.update(&chunk)?;
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            verifier.finish()
+        }
+        .await;
+        if result.is_err() {
+            drop(file);
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        result
+    }
+
+    async fn get(&mut self, path: &str, accept: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(self.send(path, accept).await?.bytes().await?.to_vec())
+    }
+
+    async fn send(&mut self, path: &str, accept: &str) -> anyhow::Result<reqwest::Response> {
+        let url = format!("{}/{}", self.repository_url, path);
+        let mut response = self.request(&url, accept).send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.token.is_none() {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .context("the registry requires authentication, but didn't say how")?
+                .to_str()?
+                .to_string();
+            self.token = Some(self.get_token(&challenge).await?);
+            response = self.request(&url, accept).send().await?;
+        }
+        response.error_for_status().with_context(|| format!("couldn't get {url}"))
+    }
+
+    fn request(&self, url: &str, accept: &str) -> reqwest::RequestBuilder {
+        let request = self.http.get(url).header(ACCEPT, accept);
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Gets an anonymous token for the `Bearer` challenge of the registry.
+    async fn get_token(&self, challenge: &str) -> anyhow::Result<String> {
+        let parameters = challenge
+            .strip_prefix("Bearer ")
+            .with_context(|| format!("unsupported authentication challenge {challenge}"))?;
+        let mut realm = None;
+        let mut query = Vec::new();
+        for parameter in parameters.split(',') {
+            let (key, value) = parameter
+                .split_once('=')
+                .with_context(|| format!("invalid authentication challenge {challenge}"))?;
+            let value = value.trim_matches('"');
+            match key.trim() {
+                "realm" => realm = Some(value),
+                key @ ("service" | "scope") => query.push((key, value)),
+                _ => {}
+            }
+        }
+        let realm = realm.context("the authentication challenge has no realm")?;
+        let response = self
+            .http
+            .get(realm)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()
+            .context("couldn't get a registry token")?
+            .bytes()
+            .await?;
+        let response: serde_json::Value = serde_json::from_slice(&response)?;
+        response["token"]
+            .as_str()
+            .or_else(|| response["access_token"].as_str())
+            .map(str::to_string)
+            .context("the registry token response has no token")
+    }
+}
+
+fn media_type(manifest: &[u8]) -> Option<String> {
+    let manifest: serde_json::Value = serde_json::from_slice(manifest).ok()?;
+    manifest["mediaType"].as_str().map(str::to_string)
+}
+
+fn parse_digest(descriptor: &Descriptor) -> anyhow::Result<[u8; 32]> {
+    descriptor
+        .digest()
+        .strip_prefix("sha256:")
+        .and_then(|digest| hex::decode(digest).ok())
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or_else(|| anyhow!("unsupported digest {}", descriptor.digest()))
+}
+
+fn verify_digest(bytes: &[u8], expected: &[u8; 32]) -> anyhow::Result<()> {
+    if Sha256::digest(bytes)[..] != expected[..] {
+        bail!("digest mismatch");
+    }
+    Ok(())
+}
+
+/// Checks the size and digest of a blob as it arrives against its descriptor.
+struct BlobVerifier {
+    name: String,
+    expected_size: u64,
+    expected_digest: [u8; 32],
+    size: u64,
+    hasher: Sha256,
+}
+
+impl BlobVerifier {
+    fn new(descriptor: &Descriptor) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: descriptor.digest().to_string(),
+            expected_size: u64::try_from(descriptor.size())
+                .map_err(|_| anyhow!("invalid size of blob {}", descriptor.digest()))?,
+            expected_digest: parse_digest(descriptor)?,
+            size: 0,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Adds the next bytes of the blob. Fails as soon as the blob is larger
+    /// than expected.
+    fn update(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.size = self.size.saturating_add(bytes.len() as u64);
+        if self.size > self.expected_size {
+            bail!("blob {} is larger than expected", self.name);
+        }
+        self.hasher.update(bytes);
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if self.size != self.expected_size {
+            bail!("blob {} doesn't have the expected size", self.name);
+        }
+        if self.hasher.finalize()[..] != self.expected_digest[..] {
+            bail!("blob {}: digest mismatch", self.name);
+        }
+        Ok(())
+    }
+}
+
+/// Unpacks a layer on top of the layers below it, applying the whiteouts that
+/// remove files of the layers below.
+fn unpack_layer<'a>(
+    blob: impl Read + 'a,
+    media_type: &MediaType,
+    rootfs: &Path,
+) -> anyhow::Result<()> {
+    let reader: Box<dyn Read + 'a> = match media_type {
+        MediaType::ImageLayer => Box::new(blob),
+        MediaType::ImageLayerGzip => Box::new(GzDecoder::new(blob)),
+        MediaType::Other(media_type) if media_type == DOCKER_LAYER_GZIP_MEDIA_TYPE => {
+            Box::new(GzDecoder::new(blob))
+        }
+        media_type => bail!("unsupported layer media type {media_type}"),
+    };
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let file_name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if !file_name.starts_with(".wh.") {
+            entry.unpack_in(rootfs)?;
+            continue;
+        }
+        if !path.components().all(|component| matches!(component, Component::Normal(_))) {
+            bail!("invalid whiteout path {}", path.display());
+        }
+        // Nothing to remove if the directory doesn't exist.
+        let Some(parent) = resolve_in_rootfs(rootfs, path.parent().unwrap_or(Path::new("")))?
+        else {
+            continue;
+        };
+        if file_name == ".wh..wh..opq" {
+            // An opaque whiteout hides everything the layers below put in the directory.
+            if parent.is_dir() {
+                for child in std::fs::read_dir(&parent)? {
+                    remove(&child?.path())?;
+                }
+            }
+        } else {
+            let name = &file_name[".wh.".len()..];
+            if matches!(name, "" | "." | "..") {
+                bail!("invalid whiteout path {}", path.display());
+            }
+            remove(&parent.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the directory at `path` relative to `rootfs`, following symlinks
+/// that earlier layers created, and checks that it is still inside `rootfs`,
+/// so that whiteouts can't remove files outside of it. Returns `None` if the
+/// directory doesn't exist.
+fn resolve_in_rootfs(rootfs: &Path, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let rootfs = rootfs.canonicalize()?;
+    let resolved = match rootfs.join(path).canonicalize() {
+        Ok(resolved) => resolved,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if !resolved.starts_with(&rootfs) {
+        bail!("whiteout path {} leads outside of the root filesystem", path.display());
+    }
+    Ok(Some(resolved))
+}
+
+fn remove(path: &Path) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// Creates the runtime configuration of the container from the configuration
+/// of the image, like `umoci unpack --rootless` does for container bundles.
+fn runtime_spec(image_config: &ImageConfiguration) -> anyhow::Result<Spec> {
+    let mut spec = Spec::rootless(0, 0);
+    let mut process = spec.process().clone().unwrap_or_default();
+    if let Some(config) = image_config.config() {
+        let args: Vec<String> = config
+            .entrypoint()
+            .iter()
+            .flatten()
+            .chain(config.cmd().iter().flatten())
+            .cloned()
+            .collect();
+        if !args.is_empty() {
+            process.set_args(Some(args));
+        }
+        if let Some(env) = config.env() {
+            process.set_env(Some(env.clone()));
+        }
+        if let Some(working_dir) = config.working_dir().as_ref().filter(|dir| !dir.is_empty()) {
+            process.set_cwd(working_dir.into());
+        }
+    }
+    spec.set_process(Some(process));
+    let mut root = Root::default();
+    root.set_readonly(Some(false));
+    spec.set_root(Some(root));
+    Ok(spec)
+}
+
+#[cfg(test)]
+const TEST_DIGEST: &str = "4242424242424242424242424242424242424242424242424242424242424242";
+
+// Builds an uncompressed layer with the given regular files, and symlinks for
+// entries with a target.
+#[cfg(test)]
+fn build_layer(entries: &[(&str, Option<&Path>)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, target) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        match target {
+            Some(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            }
+            None => {
+                header.set_size(path.len() as u64);
+                builder.append_data(&mut header, path, path.as_bytes()).unwrap();
+            }
+        }
+    }
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_parse_image_reference() {
+    let reference: ImageReference =
+        format!("ghcr.io/project-oak/app:latest@sha256:{TEST_DIGEST}").parse().unwrap();
+    assert_eq!(
+        reference,
+        ImageReference {
+            registry: "ghcr.io".to_string(),
+            repository: "project-oak/app".to_string(),
+            digest: [0x42; 32],
+        }
+    );
+    assert_eq!(reference.to_string(), format!("ghcr.io/project-oak/app@sha256:{TEST_DIGEST}"));
+}
+
+#[test]
+fn test_parse_invalid_image_reference_fails() {
+    for reference in [
+        "ghcr.io/project-oak/app:latest".to_string(),
+        format!("app@sha256:{TEST_DIGEST}"),
+        format!("/app@sha256:{TEST_DIGEST}"),
+        format!("ghcr.io/@sha256:{TEST_DIGEST}"),
+        "ghcr.io/project-oak/app@sha256:4242".to_string(),
+        format!("ghcr.io/project-oak/app@sha256:{}", "zz".repeat(32)),
+    ] {
+        assert!(reference.parse::<ImageReference>().is_err(), "{reference}");
+    }
+}
+
+#[test]
+fn test_blob_verifier() {
+    let blob = b"layer contents";
+    let digest = format!("sha256:{}", hex::encode(Sha256::digest(blob)));
+    let descriptor = Descriptor::new(MediaType::ImageLayer, blob.len() as i64, &digest);
+
+    // The blob may arrive in any number of chunks.
+    let mut verifier = BlobVerifier::new(&descriptor).unwrap();
+    verifier.update(&blob[..5]).unwrap();
+    verifier.update(&blob[5..]).unwrap();
+    assert!(verifier.finish().is_ok());
+
+    let mut verifier = BlobVerifier::new(&descriptor).unwrap();
+    verifier.update(b"other contents").unwrap();
+    let err = verifier.finish().unwrap_err();
+    assert!(err.to_string().contains("digest mismatch"), "{err}");
+
+    let mut verifier = BlobVerifier::new(&descriptor).unwrap();
+    verifier.update(&blob[..5]).unwrap();
+    assert!(verifier.finish().is_err());
+
+    let mut verifier = BlobVerifier::new(&descriptor).unwrap();
+    assert!(verifier.update(b"layer contents, and more").is_err());
+
+    let descriptor = Descriptor::new(MediaType::ImageLayer, blob.len() as i64, "md5:1234");
+    assert!(BlobVerifier::new(&descriptor).is_err());
+}
+
+#[test]
+fn test_unpack_layer_applies_whiteouts() {
+    let rootfs = tempfile::tempdir().unwrap();
+    let layers = [
+        build_layer(&[("a/b", None), ("a/c", None), ("d/e", None), ("f", None)]),
+        build_layer(&[("a/.wh.b", None), ("d/.wh..wh..opq", None), ("d/g", None)]),
+        build_layer(&[(".wh.f", None), ("missing/.wh.h", None)]),
+    ];
+    for layer in layers {
+        unpack_layer(&layer[..], &MediaType::ImageLayer, rootfs.path()).unwrap();
+    }
+
+    assert!(!rootfs.path().join("a/b").exists());
+    assert!(rootfs.path().join("a/c").exists());
+    // The opaque whiteout only hides the files of the layers below.
+    assert!(!rootfs.path().join("d/e").exists());
+    assert!(rootfs.path().join("d/g").exists());
+    assert!(!rootfs.path().join("f").exists());
+    // Whiteouts are never unpacked.
+    assert!(!rootfs.path().join("a/.wh.b").exists());
+    assert!(!rootfs.path().join("d/.wh..wh..opq").exists());
+}
+
+#[test]
+fn test_unpack_layer_rejects_whiteouts_outside_rootfs() {
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("victim"), b"host file").unwrap();
+    let rootfs = tempfile::tempdir().unwrap();
+
+    // A symlink planted by an earlier layer must not lead whiteouts outside.
+    let layer = build_layer(&[("link", Some(outside.path()))]);
+    unpack_layer(&layer[..], &MediaType::ImageLayer, rootfs.path()).unwrap();
+    for whiteout in ["link/.wh.victim", "link/.wh..wh..opq"] {
+        let layer = build_layer(&[(whiteout, None)]);
+        let err = unpack_layer(&layer[..], &MediaType::ImageLayer, rootfs.path()).unwrap_err();
+        assert!(err.to_string().contains("outside of the root filesystem"), "{whiteout}: {err}");
+    }
+
+    // Nor may whiteouts name the parent directory.
+    let layer = build_layer(&[(".wh...", None)]);
+    assert!(unpack_layer(&layer[..], &MediaType::ImageLayer, rootfs.path()).is_err());
+
+    assert!(outside.path().join("victim").exists());
+    assert!(rootfs.path().exists());
+}
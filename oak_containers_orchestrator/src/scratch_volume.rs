@@ -0,0 +1,138 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Sets up the scratch disk that the launcher may attach to the guest as an
+//! encrypted volume.
+//!
+//! The key is generated inside the guest and never leaves it, so the host only
+//! ever sees ciphertext. As the key is lost when the guest stops, the volume is
+//! scratch space: it is formatted on every start, and its contents don't
+//! survive restarts. The volume is encrypted with AES-XTS, which hides the data
+//! from the host but doesn't detect the host tampering with it, so workloads
+//! must not rely on the integrity of what they read back.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{bail, Context};
+use nix::mount::{mount, MsFlags};
+use rand_core::{OsRng, RngCore};
+use tokio::{io::AsyncWriteExt, process::Command};
+use zeroize::Zeroize;
+
+/// The serial number of the scratch disk, through which we find it among the
+/// block devices. Must match the serial the launcher gives the disk.
+pub const SCRATCH_DISK_SERIAL: &str = "oak_scratch";
+
+/// Lists the block devices, one per directory under this path.
+const BLOCK_SYSFS_PATH: &str = "/sys/block";
+
+/// Name of the device-mapper device holding the decrypted volume.
+const MAPPED_DEVICE_NAME: &str = "oak_scratch";
+
+/// Size of the AES-XTS key, which is two AES-256 keys.
+const KEY_SIZE: usize = 64;
+
+/// Sets up the encrypted volume on the scratch disk and mounts it at
+/// `mount_point`, if the launcher attached a scratch disk. Returns whether it
+/// was mounted.
+pub async fn mount_if_attached(mount_point: &Path) -> anyhow::Result<bool> {
+    let disk = match find_disk().await? {
+        Some(disk) => disk,
+        None => return Ok(false),
+    };
+    log::info!("setting up encrypted scratch volume on {}", disk.display());
+
+    let mut key = [0u8; KEY_SIZE];
+    OsRng.fill_bytes(&mut key);
+    let result = open_volume(&disk, &key).await;
+    key.zeroize();
+    result?;
+
+    let volume = Path::new("/dev/mapper").join(MAPPED_DEVICE_NAME);
+    // The disk is freshly encrypted with a new key, so it holds no file system
+    // we could reuse.
+    run(Command::new("/sbin/mkfs.ext4").args(["-q", "-F"]).arg(&volume))
+        .await
+        .context("couldn't create file system on scratch volume")?;
+
+    tokio::fs::create_dir_all(mount_point).await?;
+    mount(
+        Some(volume.as_path()),
+        mount_point,
+        Some("ext4"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        None::<&str>,
+    )
+    .context("couldn't mount scratch volume")?;
+    log::info!("mounted encrypted scratch volume at {}", mount_point.display());
+    Ok(true)
+}
+
+async fn find_disk() -> anyhow::Result<Option<PathBuf>> {
+    let mut devices =
+        tokio::fs::read_dir(BLOCK_SYSFS_PATH).await.context("couldn't list block devices")?;
+    while let Some(device) = devices.next_entry().await? {
+        // Only some block devices (such as virtio ones) have a serial number.
+        let serial = match tokio::fs::read_to_string(device.path().join("serial")).await {
+            Ok(serial) => serial,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).context("couldn't read block device serial"),
+        };
+        if serial.trim() == SCRATCH_DISK_SERIAL {
+            return Ok(Some(Path::new("/dev").join(device.file_name())));
+        }
+    }
+    Ok(None)
+}
+
+// Maps the decrypted view of `disk` with dm-crypt. We use plain dm-crypt rather
+// than LUKS, as there's no point in storing a header for a key that is never
+// reused.
+async fn open_volume(disk: &Path, key: &[u8; KEY_SIZE]) -> anyhow::Result<()> {
+    let mut cryptsetup = Command::new("/sbin/cryptsetup")
+        .args([
+            "open",
+            "--type=plain",
+            "--cipher=aes-xts-plain64",
+            format!("--key-size={}", KEY_SIZE * 8).as_str(),
+            // Read the key from stdin, so it never touches the file system.
+            "--key-file=-",
+        ])
+        .arg(disk)
+        .arg(MAPPED_DEVICE_NAME)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("couldn't start cryptsetup")?;
+    let mut stdin = cryptsetup.stdin.take().context("cryptsetup has no stdin")?;
+    stdin.write_all(key).await.context("couldn't pass key to cryptsetup")?;
+    drop(stdin);
+    let status = cryptsetup.wait().await?;
+    if !status.success() {
+        bail!("cryptsetup exited with {}", status);
+    }
+    Ok(())
+}
+
+async fn run(command: &mut Command) -> anyhow::Result<()> {
+    let status = command.status().await.with_context(|| format!("couldn't run {command:?}"))?;
+    if !status.success() {
+        bail!("{command:?} exited with {status}");
+    }
+    Ok(())
+}
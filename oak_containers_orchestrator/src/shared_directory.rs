@@ -0,0 +1,64 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Mounts the host directory that the launcher may share with the guest via
+//! virtiofs.
+
+use std::path::Path;
+
+use anyhow::Context;
+use nix::mount::{mount, MsFlags};
+
+/// The virtiofs tag under which the launcher exports the shared directory.
+pub const SHARED_DIRECTORY_TAG: &str = "oak_shared";
+
+/// Lists the tags of the virtiofs devices, one per directory under this path.
+const VIRTIOFS_SYSFS_PATH: &str = "/sys/fs/virtiofs";
+
+/// Mounts the shared directory read-only at `mount_point`, if the launcher
+/// shares one. Returns whether it was mounted.
+pub async fn mount_if_shared(mount_point: &Path) -> anyhow::Result<bool> {
+    if !is_shared().await? {
+        return Ok(false);
+    }
+    tokio::fs::create_dir_all(mount_point).await?;
+    mount(
+        Some(SHARED_DIRECTORY_TAG),
+        mount_point,
+        Some("virtiofs"),
+        MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        None::<&str>,
+    )
+    .context("couldn't mount shared directory")?;
+    log::info!("mounted shared directory at {}", mount_point.display());
+    Ok(true)
+}
+
+async fn is_shared() -> anyhow::Result<bool> {
+    let mut devices = match tokio::fs::read_dir(VIRTIOFS_SYSFS_PATH).await {
+        Ok(devices) => devices,
+        // The directory only exists if there is a virtiofs device.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err).context("couldn't list virtiofs devices"),
+    };
+    while let Some(device) = devices.next_entry().await? {
+        let tag = tokio::fs::read_to_string(device.path().join("tag")).await?;
+        if tag.trim() == SHARED_DIRECTORY_TAG {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
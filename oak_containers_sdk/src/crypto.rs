@@ -124,6 +124,46 @@ impl AsyncEncryptionKeyHandle for InstanceEncryptionKeyHandle {
     }
 }
 
+/// Derives session keys from the group key, which all enclaves of the group
+/// share if the group keys were provisioned by a leader enclave.
+pub struct GroupEncryptionKeyHandle {
+    orchestrator_crypto_client: OrchestratorCryptoClient,
+}
+
+impl GroupEncryptionKeyHandle {
+    pub async fn create() -> anyhow::Result<Self> {
+        Ok(Self {
+            orchestrator_crypto_client: OrchestratorCryptoClient::create()
+                .await
+                .context("couldn't create Orchestrator crypto client")?,
+        })
+    }
+}
+
+#[async_trait]
+impl AsyncEncryptionKeyHandle for GroupEncryptionKeyHandle {
+    async fn generate_recipient_context(
+        &self,
+        encapsulated_public_key: &[u8],
+    ) -> anyhow::Result<RecipientContext> {
+        let serialized_crypto_context = self
+            .orchestrator_crypto_client
+            .derive_session_keys(KeyOrigin::Group, encapsulated_public_key)
+            .await
+            .map_err(|error| {
+                tonic::Status::internal(format!(
+                    "couldn't get crypto context from the Orchestrator: {:?}",
+                    error
+                ))
+            })?;
+        let crypto_context =
+            RecipientContext::deserialize(serialized_crypto_context).map_err(|error| {
+                tonic::Status::internal(format!("couldn't deserialize crypto context: {:?}", error))
+            })?;
+        Ok(crypto_context)
+    }
+}
+
 #[async_trait(?Send)]
 pub trait Signer {
     async fn sign(&self, message: &[u8]) -> anyhow::Result<oak_crypto::signer::Signature>;
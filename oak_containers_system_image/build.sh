@@ -9,6 +9,7 @@ mkdir --parent target
 
 # build the orchestrator binary
 cargo build --package=oak_containers_orchestrator --profile=release-lto --target=x86_64-unknown-linux-musl -Z unstable-options --out-dir=./target
+cargo build --package=oak_containers_agent --profile=release-lto --target=x86_64-unknown-linux-musl -Z unstable-options --out-dir=./target
 cargo build --package=oak_containers_syslogd --release -Z unstable-options --out-dir=./target
 
 # We need to patch the binary to set the interpreter to the correct location, but we can't do it in-place, as that would
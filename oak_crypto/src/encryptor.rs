@@ -60,6 +60,12 @@ impl ClientEncryptor {
         })
     }
 
+    /// Returns the encapsulated public key that will be sent with the next
+    /// request, or `None` if it was already sent.
+    pub fn serialized_encapsulated_public_key(&self) -> Option<&[u8]> {
+        self.serialized_encapsulated_public_key.as_deref()
+    }
+
     /// Encrypts `plaintext` and authenticates `associated_data` using AEAD.
     /// Returns a [`EncryptedRequest`] proto message.
     /// <https://datatracker.ietf.org/doc/html/rfc5116>
@@ -215,6 +215,37 @@ impl ApplicationKeys {
         crate::cert::get_claims_set_from_certificate_bytes(&decoded)
             .map_err(|err| format!("failed get claims: {:?}", err))
     }
+
+    /// Returns the SHA2-256 digest of the config of the enclave application
+    /// that the certificates include, which is empty if the application has no
+    /// config.
+    pub fn application_config_digest(&self) -> Result<Vec<u8>, String> {
+        use coset::{cbor::value::Value, cwt::ClaimName};
+
+        use crate::cert::{
+            ENCLAVE_APPLICATION_LAYER_ID, FINAL_LAYER_CONFIG_MEASUREMENT_ID, SHA2_256_ID,
+        };
+
+        fn find(map: &[(Value, Value)], id: i64) -> Option<&Value> {
+            map.iter().find(|(key, _)| *key == Value::Integer(id.into())).map(|(_, value)| value)
+        }
+
+        let claims = self.claims()?;
+        let layer = claims
+            .rest
+            .iter()
+            .find_map(|(name, value)| match name {
+                ClaimName::PrivateUse(id) if *id == ENCLAVE_APPLICATION_LAYER_ID => value.as_map(),
+                _ => None,
+            })
+            .ok_or("no enclave application layer claim")?;
+        find(layer, FINAL_LAYER_CONFIG_MEASUREMENT_ID)
+            .and_then(Value::as_map)
+            .and_then(|digests| find(digests, SHA2_256_ID))
+            .and_then(Value::as_bytes)
+            .cloned()
+            .ok_or_else(|| "no application config measurement".into())
+    }
 }
 
 static_assertions::assert_eq_size!([u8; 2048], ApplicationKeys);
@@ -32,10 +32,9 @@ impl Source {
     // Restricted Kernel deals in 2 MiB pages, so that's what we use to request
     // memory.
     const PAGE_SIZE: usize = 0x20_0000usize;
-}
 
-unsafe impl FlexSource for Source {
-    unsafe fn alloc(&mut self, min_size: usize) -> Option<NonNull<[u8]>> {
+    /// Maps at least `min_size` bytes of fresh memory.
+    fn map(min_size: usize) -> Option<NonNull<[u8]>> {
         // Ensure that we're allocating page-sized chunks of memory.
         let size = if min_size % Self::PAGE_SIZE != 0 {
             Self::PAGE_SIZE * ((min_size / Self::PAGE_SIZE) + 1)
@@ -57,6 +56,12 @@ unsafe impl FlexSource for Source {
         .ok()
         .flatten()
     }
+}
+
+unsafe impl FlexSource for Source {
+    unsafe fn alloc(&mut self, min_size: usize) -> Option<NonNull<[u8]>> {
+        Self::map(min_size)
+    }
 
     fn min_align(&self) -> usize {
         Self::PAGE_SIZE
@@ -99,6 +104,18 @@ impl GrowableHeap {
     }
 }
 
+/// Allocations of at least this size bypass the heap and get their own mapping,
+/// which is returned to Restricted Kernel when the allocation is freed.
+///
+/// The heap never gives memory back, so without this, memory used by a large
+/// allocation (such as a lookup data snapshot or a Wasm memory) could not be
+/// used for anything but other heap allocations after it has been freed.
+const LARGE_ALLOCATION_SIZE: usize = Source::PAGE_SIZE;
+
+fn is_large_allocation(layout: &Layout) -> bool {
+    layout.size() >= LARGE_ALLOCATION_SIZE && layout.align() <= Source::PAGE_SIZE
+}
+
 /// Thread-safe version of GrowableHeap, above, usable as a global allocator.
 pub struct LockedGrowableHeap(Spinlock<GrowableHeap>);
 
@@ -118,10 +135,29 @@ impl Deref for LockedGrowableHeap {
 
 unsafe impl GlobalAlloc for LockedGrowableHeap {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if is_large_allocation(&layout) {
+            return Source::map(layout.size())
+                .map(|allocation| allocation.as_ptr() as *mut u8)
+                .unwrap_or_else(|| {
+                    log::error!("failed to allocate memory with layout: {:?}", layout);
+                    core::ptr::null_mut()
+                });
+        }
         self.0.lock().allocate(layout).map(NonNull::as_ptr).unwrap_or(core::ptr::null_mut())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if is_large_allocation(&layout) {
+            // Safety: the caller guarantees that the allocation is no longer in use, and
+            // large allocations have a mapping of their own.
+            if let Err(err) = oak_restricted_kernel_interface::syscall::munmap(
+                ptr as *mut core::ffi::c_void,
+                layout.size(),
+            ) {
+                log::error!("failed to unmap memory with layout {:?}: {:?}", layout, err);
+            }
+            return;
+        }
         self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout.align())
     }
 }
@@ -13,10 +13,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod pool;
+
 use anyhow::Context;
 use oak_client::{
-    client::OakClient, proto::oak::session::v1::streaming_session_client::StreamingSessionClient,
-    transport::GrpcStreamingTransport, verifier::AttestationVerifier,
+    cache::EvidenceCache,
+    client::{OakClient, ResumableSession},
+    peer::PeerAttester,
+    proto::oak::session::v1::streaming_session_client::StreamingSessionClient,
+    transport::GrpcStreamingTransport,
+    verifier::AttestationVerifier,
+};
+use oak_proto_rust::oak::{
+    attestation::v1::AttestationBundle,
+    oak_functions::abi::{BatchRequest, BatchResponse},
 };
 use prost::Message;
 use tonic::transport::Channel;
@@ -27,34 +37,122 @@ pub struct OakFunctionsClient {
 
 impl OakFunctionsClient {
     pub async fn new(uri: &str, verifier: &dyn AttestationVerifier) -> anyhow::Result<Self> {
-        let channel = Channel::from_shared(uri.to_string())
-            .context("couldn't create gRPC channel")?
-            .connect()
-            .await
-            .context("couldn't connect via gRPC channel")?;
-        let transport = GrpcStreamingTransport::new(StreamingSessionClient::new(channel));
+        let transport = connect(uri).await?;
         let oak_client =
             OakClient::create(transport, verifier).await.context("couldn't create Oak client")?;
         Ok(Self { oak_client })
     }
 
+    /// Like [`OakFunctionsClient::new`], but doesn't verify the evidence of
+    /// `uri` again if `cache` holds the results of verifying it, and the
+    /// policy of `cache` allows reusing them.
+    pub async fn new_cached(
+        uri: &str,
+        verifier: &dyn AttestationVerifier,
+        cache: &EvidenceCache,
+    ) -> anyhow::Result<Self> {
+        let transport = connect(uri).await?;
+        let oak_client = OakClient::create_cached(transport, verifier, cache, uri)
+            .await
+            .context("couldn't create Oak client")?;
+        Ok(Self { oak_client })
+    }
+
+    /// Connects to `uri` and resumes `session` if possible, without fetching
+    /// and verifying the evidence again.
+    pub async fn resume(
+        uri: &str,
+        session: &ResumableSession,
+        verifier: &dyn AttestationVerifier,
+    ) -> anyhow::Result<Self> {
+        let transport = connect(uri).await?;
+        let oak_client = OakClient::resume(transport, session, verifier)
+            .await
+            .context("couldn't create Oak client")?;
+        Ok(Self { oak_client })
+    }
+
+    /// Sends the attestation created by `peer_attester` with each request, for
+    /// instances initialized with a peer attestation policy.
+    pub fn with_peer_attester(self, peer_attester: PeerAttester) -> Self {
+        Self { oak_client: self.oak_client.with_peer_attester(peer_attester) }
+    }
+
+    /// Returns the session to pass to [`OakFunctionsClient::resume`] when
+    /// reconnecting, if the server supports session resumption.
+    pub fn resumable_session(&self) -> Option<&ResumableSession> {
+        self.oak_client.resumable_session()
+    }
+
+    /// Returns the evidence that the session was established with, to store
+    /// and verify again later.
+    pub fn attestation_bundle(&self) -> &AttestationBundle {
+        self.oak_client.attestation_bundle()
+    }
+
     pub async fn invoke(&mut self, request: &[u8]) -> Result<Vec<u8>, micro_rpc::Status> {
-        // An error here indicates a failure with gRPC or encoding / decoding.
-        let response_bytes = self.oak_client.invoke(request).await.map_err(|err| {
-            micro_rpc::Status::new_with_message(
-                micro_rpc::StatusCode::Internal,
-                format!("couldn't invoke Oak Functions: {:?}", err),
-            )
-        })?;
-        // An error here is specific to the Oak Functions application (e.g. the Wasm
-        // module does not have the correct exported / imported functions).
-        let response =
-            micro_rpc::ResponseWrapper::decode(response_bytes.as_slice()).map_err(|err| {
+        let response_bytes = self.oak_client.invoke(request).await;
+        decode_response(response_bytes)
+    }
+
+    /// Like [`OakFunctionsClient::invoke`], but streams the request and the
+    /// response in chunks, for workloads whose inputs or outputs are larger
+    /// than a single gRPC message.
+    pub async fn invoke_streaming(&mut self, request: &[u8]) -> Result<Vec<u8>, micro_rpc::Status> {
+        let response_bytes = self.oak_client.invoke_streaming(request).await;
+        decode_response(response_bytes)
+    }
+
+    /// Sends independent requests together in one encrypted message, and
+    /// returns their responses in the same order. The requests are all
+    /// handled against the same version of the lookup data.
+    pub async fn invoke_batch(
+        &mut self,
+        requests: &[Vec<u8>],
+    ) -> Result<Vec<Result<Vec<u8>, micro_rpc::Status>>, micro_rpc::Status> {
+        let batch_request = BatchRequest { requests: requests.to_vec() };
+        let response_bytes = self.oak_client.invoke_batch(&batch_request.encode_to_vec()).await;
+        let batch_response_bytes = decode_response(response_bytes)?;
+        let batch_response =
+            BatchResponse::decode(batch_response_bytes.as_slice()).map_err(|err| {
                 micro_rpc::Status::new_with_message(
                     micro_rpc::StatusCode::Internal,
-                    format!("couldn't deserialize response wrapper: {:?}", err),
+                    format!("couldn't deserialize batch response: {:?}", err),
                 )
             })?;
-        response.into()
+        Ok(batch_response
+            .responses
+            .into_iter()
+            .map(|response_bytes| decode_response(Ok(response_bytes)))
+            .collect())
     }
 }
+
+fn decode_response(response_bytes: anyhow::Result<Vec<u8>>) -> Result<Vec<u8>, micro_rpc::Status> {
+    // An error here indicates a failure with gRPC or encoding / decoding.
+    let response_bytes = response_bytes.map_err(|err| {
+        micro_rpc::Status::new_with_message(
+            micro_rpc::StatusCode::Internal,
+            format!("couldn't invoke Oak Functions: {:?}", err),
+        )
+    })?;
+    // An error here is specific to the Oak Functions application (e.g. the Wasm
+    // module does not have the correct exported / imported functions).
+    let response =
+        micro_rpc::ResponseWrapper::decode(response_bytes.as_slice()).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::Internal,
+                format!("couldn't deserialize response wrapper: {:?}", err),
+            )
+        })?;
+    response.into()
+}
+
+async fn connect(uri: &str) -> anyhow::Result<GrpcStreamingTransport> {
+    let channel = Channel::from_shared(uri.to_string())
+        .context("couldn't create gRPC channel")?
+        .connect()
+        .await
+        .context("couldn't connect via gRPC channel")?;
+    Ok(GrpcStreamingTransport::new(StreamingSessionClient::new(channel)))
+}
@@ -17,11 +17,21 @@
 //! Sends a gRPC request to an Oak Functions application and checks that the
 //! response has the correct format.
 
+use std::{path::PathBuf, time::Duration};
+
 use anyhow::Context;
 use clap::Parser;
-use oak_client::verifier::InsecureAttestationVerifier;
+use oak_client::{
+    bundle,
+    verifier::{
+        AttestationVerifier, FileReferenceValueProvider, HttpReferenceValueProvider,
+        InsecureAttestationVerifier, PolicyVerifier, ReferenceValueProvider,
+        ReferenceValueVerifier,
+    },
+};
 use oak_functions_abi::Request;
 use oak_functions_client::OakFunctionsClient;
+use oak_proto_rust::oak::attestation::v1::attestation_results::Status;
 use regex::Regex;
 
 const TWO_MIB: usize = 2 * 1024 * 1024;
@@ -37,7 +47,11 @@ pub struct Opt {
     )]
     uri: String,
 
-    #[arg(long, help = "request payload", required_unless_present = "test_large_message")]
+    #[arg(
+        long,
+        help = "request payload",
+        required_unless_present_any = &["test_large_message", "verify_attestation_bundle"]
+    )]
     request: Option<String>,
 
     /// Optional, only for testing.
@@ -52,6 +66,35 @@ pub struct Opt {
     /// Test sending a large message
     #[arg(long, conflicts_with_all = &["request", "expected_response_pattern", "iterations"])]
     test_large_message: bool,
+
+    /// Path or HTTP URI of the signed reference values to verify the evidence
+    /// against. Only the DICE chain of the evidence is verified if not set.
+    #[arg(long, requires = "reference_values_signing_key")]
+    reference_values: Option<String>,
+
+    /// Path to the PEM-encoded public key that the reference values are signed
+    /// with.
+    #[arg(long, requires = "reference_values")]
+    reference_values_signing_key: Option<PathBuf>,
+
+    /// Path to a JSON appraisal policy that the evidence must satisfy as well.
+    #[arg(long)]
+    attestation_policy: Option<PathBuf>,
+
+    /// Path to write the evidence, endorsements and reference values of the
+    /// session to, to verify them again later.
+    #[arg(long)]
+    export_attestation_bundle: Option<PathBuf>,
+
+    /// Verifies the attestation bundle at this path again, prints the report
+    /// and exits, without connecting to the application.
+    #[arg(long, conflicts_with_all = &["request", "test_large_message"])]
+    verify_attestation_bundle: Option<PathBuf>,
+
+    /// Time to verify the attestation bundle at, in milliseconds since the
+    /// epoch. Defaults to the time the bundle was captured.
+    #[arg(long, requires = "verify_attestation_bundle")]
+    verification_time_utc_millis: Option<i64>,
 }
 
 #[tokio::main]
@@ -59,9 +102,23 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let opt = Opt::parse();
 
-    let mut client = OakFunctionsClient::new(&opt.uri, &InsecureAttestationVerifier {})
+    if let Some(path) = &opt.verify_attestation_bundle {
+        let report = bundle::verify(path, opt.verification_time_utc_millis)?;
+        println!("{:#?}", report);
+        anyhow::ensure!(
+            report.status() == Status::Success,
+            "attestation bundle failed verification"
+        );
+        return Ok(());
+    }
+
+    let verifier = load_verifier(&opt).await?;
+    let mut client = OakFunctionsClient::new(&opt.uri, verifier.as_ref())
         .await
         .context("couldn't create Oak Functions client")?;
+    if let Some(path) = &opt.export_attestation_bundle {
+        bundle::save(client.attestation_bundle(), path)?;
+    }
 
     if opt.test_large_message {
         // The client should be a able to send a large message without
@@ -91,3 +148,35 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// Creates a verifier for the reference values and the policy given on the
+// command line, if any.
+async fn load_verifier(opt: &Opt) -> anyhow::Result<Box<dyn AttestationVerifier>> {
+    let verifier = load_reference_value_verifier(opt).await?;
+    match &opt.attestation_policy {
+        Some(path) => Ok(Box::new(PolicyVerifier::load(verifier, path)?)),
+        None => Ok(verifier),
+    }
+}
+
+async fn load_reference_value_verifier(opt: &Opt) -> anyhow::Result<Box<dyn AttestationVerifier>> {
+    let (Some(reference_values), Some(signing_key)) =
+        (&opt.reference_values, &opt.reference_values_signing_key)
+    else {
+        return Ok(Box::new(InsecureAttestationVerifier {}));
+    };
+    let provider: Box<dyn ReferenceValueProvider> = if reference_values.starts_with("http://") {
+        Box::new(HttpReferenceValueProvider {
+            uri: reference_values.parse().context("invalid reference values URI")?,
+        })
+    } else {
+        Box::new(FileReferenceValueProvider { path: reference_values.into() })
+    };
+    let signing_key = std::fs::read_to_string(signing_key)
+        .context("couldn't read reference values signing key")?;
+    // The client exits before the reference values would need to be refreshed.
+    let verifier = ReferenceValueVerifier::load(provider, &signing_key, Duration::MAX)
+        .await
+        .context("couldn't load reference values")?;
+    Ok(Box::new(verifier))
+}
@@ -0,0 +1,454 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A pool of attested sessions, for callers that send many requests
+//! concurrently and need them to survive transient failures.
+//!
+//! Sessions are opened lazily, and each of them handles one request at a time.
+//! A session that fails with a transient transport error is discarded, and
+//! replaced by a new one on its next use, and sessions are rotated once they
+//! are older than their lifetime, so that the pool eventually spreads over all
+//! the replicas behind an endpoint. The evidence of new sessions is only
+//! verified again if it changed, as the pool shares an [`EvidenceCache`]
+//! between them.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use oak_client::{
+    cache::{EvidenceCache, ReverificationPolicy},
+    verifier::AttestationVerifier,
+};
+use rand::Rng;
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use crate::{decode_response, OakFunctionsClient};
+
+/// Settings of an [`OakFunctionsClientPool`].
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Number of sessions kept with each endpoint, which bounds the number of
+    /// requests in flight to it.
+    pub sessions_per_endpoint: usize,
+    /// Maximum number of times a request is sent, including the first one.
+    pub max_attempts: usize,
+    /// Upper bound of the delay before the first retry. Each retry doubles it,
+    /// and the actual delay is picked at random below it, so that clients
+    /// failing at the same time don't retry in lockstep.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+    /// How long a session is used before it is replaced by a new one. Each
+    /// session is rotated up to a fifth earlier, so that they don't all expire
+    /// at once.
+    pub session_lifetime: Duration,
+    /// When the evidence of new sessions must be verified again even if it
+    /// hasn't changed.
+    pub reverification_policy: ReverificationPolicy,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            sessions_per_endpoint: 4,
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            session_lifetime: Duration::from_secs(600),
+            reverification_policy: ReverificationPolicy::default(),
+        }
+    }
+}
+
+/// Client keeping several attested sessions with each of a set of Oak
+/// Functions endpoints, and retrying requests that fail with transient
+/// errors.
+pub struct OakFunctionsClientPool {
+    uris: Vec<String>,
+    connector: Box<dyn Connector>,
+    cache: EvidenceCache,
+    config: PoolConfig,
+    slots: Vec<Mutex<Slot>>,
+    /// Index of the slot to try first for the next request.
+    next_slot: AtomicUsize,
+}
+
+struct Slot {
+    /// Index of the endpoint in [`OakFunctionsClientPool::uris`].
+    endpoint: usize,
+    session: Option<PooledSession>,
+}
+
+struct PooledSession {
+    session: Box<dyn Session>,
+    rotate_at: Instant,
+}
+
+// Establishes the sessions of the pool, so that tests can replace the
+// transport.
+#[async_trait]
+trait Connector: Send + Sync {
+    async fn connect(&self, uri: &str, cache: &EvidenceCache) -> anyhow::Result<Box<dyn Session>>;
+}
+
+#[async_trait]
+trait Session: Send {
+    async fn invoke(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+// Establishes attested sessions over gRPC.
+struct AttestedConnector {
+    verifier: Arc<dyn AttestationVerifier + Send + Sync>,
+}
+
+#[async_trait]
+impl Connector for AttestedConnector {
+    async fn connect(&self, uri: &str, cache: &EvidenceCache) -> anyhow::Result<Box<dyn Session>> {
+        let client = OakFunctionsClient::new_cached(uri, self.verifier.as_ref(), cache).await?;
+        Ok(Box::new(client))
+    }
+}
+
+#[async_trait]
+impl Session for OakFunctionsClient {
+    async fn invoke(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.oak_client.invoke(request).await
+    }
+}
+
+impl OakFunctionsClientPool {
+    /// Creates a pool of sessions with the endpoints at `uris`, whose evidence
+    /// is verified with `verifier`. No connection is made until the first
+    /// request.
+    pub fn new(
+        uris: &[&str],
+        verifier: Arc<dyn AttestationVerifier + Send + Sync>,
+        config: PoolConfig,
+    ) -> anyhow::Result<Self> {
+        Self::with_connector(uris, Box::new(AttestedConnector { verifier }), config)
+    }
+
+    fn with_connector(
+        uris: &[&str],
+        connector: Box<dyn Connector>,
+        config: PoolConfig,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(!uris.is_empty(), "the pool needs at least one endpoint");
+        anyhow::ensure!(config.sessions_per_endpoint > 0, "the pool needs at least one session");
+        anyhow::ensure!(config.max_attempts > 0, "requests need at least one attempt");
+        // Interleave the endpoints, so that consecutive requests go to different
+        // endpoints.
+        let slots = (0..config.sessions_per_endpoint)
+            .flat_map(|_| 0..uris.len())
+            .map(|endpoint| Mutex::new(Slot { endpoint, session: None }))
+            .collect();
+        Ok(Self {
+            uris: uris.iter().map(|uri| uri.to_string()).collect(),
+            connector,
+            cache: EvidenceCache::new(config.reverification_policy.clone()),
+            config,
+            slots,
+            next_slot: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the cache of the verification results of the endpoints, e.g. to
+    /// change its policy after the reference values were updated.
+    pub fn evidence_cache(&self) -> &EvidenceCache {
+        &self.cache
+    }
+
+    /// Invokes the application with `request`. The request is only sent again
+    /// if it never reached the server, i.e. if a session couldn't be
+    /// established, as it might not be safe to handle it twice.
+    pub async fn invoke(&self, request: &[u8]) -> Result<Vec<u8>, micro_rpc::Status> {
+        self.invoke_with_retries(request, false).await
+    }
+
+    /// Like [`OakFunctionsClientPool::invoke`], but also sends the request
+    /// again if it failed with a transient transport error after it was sent.
+    /// Only use this for requests that can be handled more than once, such as
+    /// lookups.
+    pub async fn invoke_idempotent(&self, request: &[u8]) -> Result<Vec<u8>, micro_rpc::Status> {
+        self.invoke_with_retries(request, true).await
+    }
+
+    async fn invoke_with_retries(
+        &self,
+        request: &[u8],
+        idempotent: bool,
+    ) -> Result<Vec<u8>, micro_rpc::Status> {
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let mut slot = self.checkout().await;
+            let error = match self.session(&mut slot).await {
+                Ok(session) => match session.invoke(request).await {
+                    Err(err) if is_transient(&err) => {
+                        // The connection may be broken, so the slot gets a new session.
+                        slot.session = None;
+                        if !idempotent {
+                            return decode_response(Err(err));
+                        }
+                        err
+                    }
+                    result => return decode_response(result),
+                },
+                Err(err) if is_transient(&err) => err,
+                Err(err) => return decode_response(Err(err)),
+            };
+            drop(slot);
+
+            if attempt >= self.config.max_attempts {
+                return decode_response(Err(error));
+            }
+            log::warn!("attempt {} failed, retrying: {:?}", attempt, error);
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..=backoff);
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+            attempt += 1;
+        }
+    }
+
+    // Returns a slot that isn't in use, or waits for one if all of them are.
+    async fn checkout(&self) -> MutexGuard<'_, Slot> {
+        let first = self.next_slot.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.slots.len() {
+            if let Ok(slot) = self.slots[(first + offset) % self.slots.len()].try_lock() {
+                return slot;
+            }
+        }
+        self.slots[first % self.slots.len()].lock().await
+    }
+
+    // Returns the session of `slot`, establishing a new one if it has none, or if
+    // it is due to be rotated.
+    async fn session<'a>(&self, slot: &'a mut Slot) -> anyhow::Result<&'a mut dyn Session> {
+        let uri = &self.uris[slot.endpoint];
+        if slot.session.as_ref().is_some_and(|session| Instant::now() >= session.rotate_at) {
+            log::debug!("rotating session with {}", uri);
+            slot.session = None;
+        }
+        let session = match &mut slot.session {
+            Some(session) => session,
+            slot_session @ None => {
+                let session = self
+                    .connector
+                    .connect(uri, &self.cache)
+                    .await
+                    .with_context(|| format!("couldn't establish session with {}", uri))?;
+                let lifetime =
+                    self.config.session_lifetime.mul_f64(rand::thread_rng().gen_range(0.8..=1.0));
+                slot_session.insert(PooledSession { session, rotate_at: Instant::now() + lifetime })
+            }
+        };
+        Ok(session.session.as_mut())
+    }
+}
+
+// Returns whether `error` may not happen again if the request is retried on a
+// new connection, e.g. because the server was restarting.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<tonic::transport::Error>() {
+            return true;
+        }
+        cause.downcast_ref::<tonic::Status>().is_some_and(|status| {
+            matches!(
+                status.code(),
+                tonic::Code::Unavailable | tonic::Code::Aborted | tonic::Code::DeadlineExceeded
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex as StdMutex};
+
+    use prost::Message;
+
+    use super::*;
+
+    // Establishes sessions that fail with the queued errors, and otherwise
+    // respond with their number.
+    #[derive(Default)]
+    struct FakeConnector {
+        connect_errors: StdMutex<VecDeque<tonic::Status>>,
+        invoke_errors: Arc<StdMutex<VecDeque<tonic::Status>>>,
+        connections: Arc<AtomicUsize>,
+        invocations: Arc<AtomicUsize>,
+    }
+
+    struct FakeSession {
+        number: usize,
+        invoke_errors: Arc<StdMutex<VecDeque<tonic::Status>>>,
+        invocations: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Connector for FakeConnector {
+        async fn connect(
+            &self,
+            _uri: &str,
+            _cache: &EvidenceCache,
+        ) -> anyhow::Result<Box<dyn Session>> {
+            let number = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(status) = self.connect_errors.lock().unwrap().pop_front() {
+                return Err(status.into());
+            }
+            Ok(Box::new(FakeSession {
+                number,
+                invoke_errors: self.invoke_errors.clone(),
+                invocations: self.invocations.clone(),
+            }))
+        }
+    }
+
+    #[async_trait]
+    impl Session for FakeSession {
+        async fn invoke(&mut self, _request: &[u8]) -> anyhow::Result<Vec<u8>> {
+            self.invocations.fetch_add(1, Ordering::Relaxed);
+            if let Some(status) = self.invoke_errors.lock().unwrap().pop_front() {
+                return Err(status.into());
+            }
+            let response: micro_rpc::ResponseWrapper =
+                Ok(self.number.to_string().into_bytes()).into();
+            Ok(response.encode_to_vec())
+        }
+    }
+
+    struct Fixture {
+        pool: OakFunctionsClientPool,
+        invoke_errors: Arc<StdMutex<VecDeque<tonic::Status>>>,
+        connections: Arc<AtomicUsize>,
+        invocations: Arc<AtomicUsize>,
+    }
+
+    fn create_pool(
+        connect_errors: Vec<tonic::Status>,
+        invoke_errors: Vec<tonic::Status>,
+        config: PoolConfig,
+    ) -> Fixture {
+        let connector = FakeConnector {
+            connect_errors: StdMutex::new(connect_errors.into()),
+            invoke_errors: Arc::new(StdMutex::new(invoke_errors.into())),
+            ..Default::default()
+        };
+        let invoke_errors = connector.invoke_errors.clone();
+        let connections = connector.connections.clone();
+        let invocations = connector.invocations.clone();
+        let config = PoolConfig { sessions_per_endpoint: 1, ..config };
+        let pool = OakFunctionsClientPool::with_connector(&["fake"], Box::new(connector), config)
+            .expect("couldn't create pool");
+        Fixture { pool, invoke_errors, connections, invocations }
+    }
+
+    fn unavailable() -> tonic::Status {
+        tonic::Status::unavailable("server is restarting")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_is_retried_up_to_max_attempts() {
+        let fixture = create_pool(
+            vec![unavailable(), unavailable()],
+            vec![],
+            PoolConfig { max_attempts: 3, ..Default::default() },
+        );
+        assert_eq!(fixture.pool.invoke(b"request").await, Ok(b"3".to_vec()));
+        assert_eq!(fixture.connections.load(Ordering::Relaxed), 3);
+        assert_eq!(fixture.invocations.load(Ordering::Relaxed), 1);
+
+        let fixture = create_pool(
+            vec![unavailable(), unavailable(), unavailable()],
+            vec![],
+            PoolConfig { max_attempts: 3, ..Default::default() },
+        );
+        assert!(fixture.pool.invoke(b"request").await.is_err());
+        assert_eq!(fixture.connections.load(Ordering::Relaxed), 3);
+        assert_eq!(fixture.invocations.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_permanent_errors_are_not_retried() {
+        let fixture = create_pool(
+            vec![tonic::Status::permission_denied("evidence was rejected")],
+            vec![],
+            PoolConfig { max_attempts: 3, ..Default::default() },
+        );
+        assert!(fixture.pool.invoke_idempotent(b"request").await.is_err());
+        assert_eq!(fixture.connections.load(Ordering::Relaxed), 1);
+        assert_eq!(fixture.invocations.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invoke_is_not_retried_after_send() {
+        let fixture = create_pool(
+            vec![],
+            vec![unavailable()],
+            PoolConfig { max_attempts: 3, ..Default::default() },
+        );
+        assert!(fixture.pool.invoke(b"request").await.is_err());
+        assert_eq!(fixture.invocations.load(Ordering::Relaxed), 1);
+
+        // The failed session was discarded.
+        assert_eq!(fixture.pool.invoke(b"request").await, Ok(b"2".to_vec()));
+        assert_eq!(fixture.connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invoke_idempotent_is_retried_after_send() {
+        let fixture = create_pool(
+            vec![],
+            vec![unavailable(), unavailable()],
+            PoolConfig { max_attempts: 3, ..Default::default() },
+        );
+        assert_eq!(fixture.pool.invoke_idempotent(b"request").await, Ok(b"3".to_vec()));
+        assert_eq!(fixture.invocations.load(Ordering::Relaxed), 3);
+
+        fixture.invoke_errors.lock().unwrap().extend([unavailable(), unavailable(), unavailable()]);
+        assert!(fixture.pool.invoke_idempotent(b"request").await.is_err());
+        assert_eq!(fixture.invocations.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sessions_are_rotated() {
+        let fixture = create_pool(
+            vec![],
+            vec![],
+            PoolConfig { session_lifetime: Duration::from_secs(100), ..Default::default() },
+        );
+        assert_eq!(fixture.pool.invoke(b"request").await, Ok(b"1".to_vec()));
+
+        // Sessions are rotated between 80% and 100% of their lifetime.
+        tokio::time::advance(Duration::from_secs(79)).await;
+        assert_eq!(fixture.pool.invoke(b"request").await, Ok(b"1".to_vec()));
+
+        tokio::time::advance(Duration::from_secs(21)).await;
+        assert_eq!(fixture.pool.invoke(b"request").await, Ok(b"2".to_vec()));
+        assert_eq!(fixture.connections.load(Ordering::Relaxed), 2);
+    }
+}
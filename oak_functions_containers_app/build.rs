@@ -33,6 +33,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 ".oak.functions",
                 "::oak_functions_service::proto::oak::functions",
             )],
+            ..Default::default()
         },
     )?;
 
@@ -18,23 +18,39 @@
 
 use std::{
     error::Error,
+    fs::File,
     future::Future,
+    io::Write,
     pin::Pin,
     sync::{Arc, OnceLock},
-    time::Instant,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use oak_attestation::handler::AsyncEncryptionHandler;
-use oak_crypto::encryption_key::AsyncEncryptionKeyHandle;
+use oak_crypto::{encryption_key::AsyncEncryptionKeyHandle, encryptor::ServerEncryptor};
 use oak_functions_service::{
+    clock::TimeConfig,
     instance::OakFunctionsInstance,
-    proto::oak::functions::{
-        AbortNextLookupDataResponse, Empty, ExtendNextLookupDataRequest,
-        ExtendNextLookupDataResponse, FinishNextLookupDataRequest, FinishNextLookupDataResponse,
-        InitializeRequest, InitializeResponse, InvokeRequest, InvokeResponse, LookupDataChunk,
-        ReserveRequest, ReserveResponse,
+    invocations::Invocations,
+    lookup_index::LookupIndex,
+    precompiled::{key_derivation_encapsulated_key, precompiled_module_key},
+    proto::oak::{
+        crypto::v1::EncryptedRequest,
+        functions::{
+            AbortInvocationRequest, AbortInvocationResponse, AbortNextLookupDataResponse, Empty,
+            ExtendInvocationRequest, ExtendInvocationResponse, ExtendNextLookupDataRequest,
+            ExtendNextLookupDataResponse, FinishInvocationRequest, FinishInvocationResponse,
+            FinishNextLookupDataRequest, FinishNextLookupDataResponse,
+            GetPrecompiledWasmModuleRequest, GetPrecompiledWasmModuleResponse,
+            GetSealedStateRequest, GetSealedStateResponse, InitializeRequest, InitializeResponse,
+            InvocationErrorClass, InvokeRequest, InvokeResponse, LookupDataChunk, LookupIndexChunk,
+            ProvisionSecretsRequest, ProvisionSecretsResponse, ReadInvocationResponseRequest,
+            ReadInvocationResponseResponse, ReloadWasmRequest, ReloadWasmResponse, ReserveRequest,
+            ReserveResponse, TerminateRequest, TerminateResponse,
+        },
     },
+    wasm::invocation_error_class,
     Handler, Observer,
 };
 use opentelemetry::{
@@ -71,20 +87,98 @@ pub mod proto {
 pub struct OakFunctionsContainersService<H: Handler> {
     instance: OnceLock<OakFunctionsInstance<H>>,
     encryption_key_handle: Arc<dyn AsyncEncryptionKeyHandle + Send + Sync>,
+    // From the application config, which is measured into the evidence.
+    aggregation_threshold: Option<u32>,
+    time_config: Option<TimeConfig>,
+    lookup_data_signing_public_key: Option<Vec<u8>>,
+    // Derived from the group key, see `oak_functions_service::precompiled`.
+    precompiled_module_key: Option<Vec<u8>>,
     observer: Option<Arc<dyn Observer + Send + Sync>>,
+    invocations: Invocations,
 }
 
 impl<H: Handler> OakFunctionsContainersService<H> {
     pub fn new(
         encryption_key_handle: Arc<dyn AsyncEncryptionKeyHandle + Send + Sync>,
+        aggregation_threshold: Option<u32>,
+        time_config: Option<TimeConfig>,
+        lookup_data_signing_public_key: Option<Vec<u8>>,
+        precompiled_module_key: Option<Vec<u8>>,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
     ) -> Self {
-        Self { instance: OnceLock::new(), encryption_key_handle, observer }
+        Self {
+            instance: OnceLock::new(),
+            encryption_key_handle,
+            aggregation_threshold,
+            time_config,
+            lookup_data_signing_public_key,
+            precompiled_module_key,
+            observer,
+            invocations: Invocations::default(),
+        }
     }
 
     fn get_instance(&self) -> tonic::Result<&OakFunctionsInstance<H>> {
         self.instance.get().ok_or_else(|| tonic::Status::failed_precondition("not initialized"))
     }
+
+    // Decrypts the request, handles its plaintext with `handler`, and encrypts
+    // the result wrapped in a micro RPC response wrapper.
+    async fn invoke_encrypted(
+        &self,
+        request: InvokeRequest,
+        handler: fn(&OakFunctionsInstance<H>, Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status>,
+    ) -> tonic::Result<InvokeResponse> {
+        let instance = self.get_instance()?;
+
+        let encrypted_request = request.encrypted_request.ok_or_else(|| {
+            tonic::Status::invalid_argument(
+                "InvokeRequest doesn't contain an encrypted request".to_string(),
+            )
+        })?;
+        let now_utc_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| i64::try_from(time.as_millis()).unwrap_or(i64::MAX));
+        instance.check_peer_attestation(&encrypted_request, now_utc_millis).map_err(map_status)?;
+
+        // Set by the handler if the invocation fails, before the response is encrypted.
+        let error_class = OnceLock::new();
+        let encrypted_response =
+            AsyncEncryptionHandler::create(self.encryption_key_handle.clone(), |r| async {
+                // Wrap the invocation result (which may be an Error) into a micro RPC Response
+                // wrapper protobuf, and encode that as bytes.
+                let response_result: Result<Vec<u8>, micro_rpc::Status> = handler(instance, r);
+                if let Err(err) = &response_result {
+                    let _ = error_class.set(invocation_error_class(err));
+                }
+                let response: micro_rpc::ResponseWrapper = response_result.into();
+                response.encode_to_vec()
+            })
+            .invoke(&encrypted_request)
+            .await
+            .map_err(|err| {
+                tonic::Status::internal(format!("couldn't call request handler: {:?}", err))
+            })?;
+        #[allow(clippy::needless_update)]
+        Ok(InvokeResponse {
+            encrypted_response: Some(encrypted_response),
+            error_class: error_class
+                .get()
+                .copied()
+                .unwrap_or(InvocationErrorClass::Unspecified)
+                .into(),
+            ..Default::default()
+        })
+    }
+}
+
+// Maps the lookup index in `file`, so that lookups read it in place and the
+// kernel can page it in on demand.
+fn map_lookup_index(file: &File) -> anyhow::Result<LookupIndex> {
+    // Safety: the file is an anonymous temporary file, so no other process can
+    // modify it, and we don't write to it once it's mapped.
+    let mmap = unsafe { memmap2::Mmap::map(file) }.context("couldn't map lookup index")?;
+    LookupIndex::new(Box::new(mmap))
 }
 
 fn map_status(status: micro_rpc::Status) -> tonic::Status {
@@ -124,12 +218,30 @@ where
         match self.instance.get() {
             Some(_) => Err(tonic::Status::failed_precondition("already initialized")),
             None => {
-                let instance = OakFunctionsInstance::new(&request, self.observer.clone())
-                    .map_err(map_status)?;
+                if !request.sealed_state.is_empty() {
+                    return Err(tonic::Status::unimplemented(
+                        "sealed state is only supported on the Restricted Kernel",
+                    ));
+                }
+                let instance = OakFunctionsInstance::new(
+                    &request,
+                    self.aggregation_threshold,
+                    self.time_config,
+                    self.lookup_data_signing_public_key.as_deref(),
+                    self.precompiled_module_key.clone(),
+                    self.observer.clone(),
+                )
+                .map_err(map_status)?;
+                let response = InitializeResponse {
+                    max_response_size: instance.max_response_size(),
+                    constant_response_size: instance.constant_response_size(),
+                    tenant_constant_response_sizes: instance.tenant_constant_response_sizes(),
+                    ..Default::default()
+                };
                 if self.instance.set(instance).is_err() {
                     return Err(tonic::Status::failed_precondition("already initialized"));
                 }
-                Ok(tonic::Response::new(InitializeResponse::default()))
+                Ok(tonic::Response::new(response))
             }
         }
     }
@@ -138,36 +250,9 @@ where
         &self,
         request: tonic::Request<InvokeRequest>,
     ) -> tonic::Result<tonic::Response<InvokeResponse>> {
-        let instance = self.get_instance()?;
-
-        let encrypted_request = request.into_inner().encrypted_request.ok_or_else(|| {
-            tonic::Status::invalid_argument(
-                "InvokeRequest doesn't contain an encrypted request".to_string(),
-            )
-        })?;
-
-        AsyncEncryptionHandler::create(self.encryption_key_handle.clone(), |r| async {
-            // Wrap the invocation result (which may be an Error) into a micro RPC Response
-            // wrapper protobuf, and encode that as bytes.
-            let response_result: Result<Vec<u8>, micro_rpc::Status> =
-                instance.handle_user_request(r);
-            let response: micro_rpc::ResponseWrapper = response_result.into();
-            response.encode_to_vec()
-        })
-        .invoke(&encrypted_request)
-        .await
-        .map(
-            #[allow(clippy::needless_update)]
-            |encrypted_response| {
-                tonic::Response::new(InvokeResponse {
-                    encrypted_response: Some(encrypted_response),
-                    ..Default::default()
-                })
-            },
-        )
-        .map_err(|err| {
-            tonic::Status::internal(format!("couldn't call handle_user_request handler: {:?}", err))
-        })
+        self.invoke_encrypted(request.into_inner(), OakFunctionsInstance::handle_user_request)
+            .await
+            .map(tonic::Response::new)
     }
 
     async fn extend_next_lookup_data(
@@ -211,11 +296,32 @@ where
             instance.extend_lookup_data_chunk(chunk?).map_err(map_status)?;
         }
         instance
-            .finish_next_lookup_data(FinishNextLookupDataRequest {})
+            .finish_next_lookup_data(FinishNextLookupDataRequest::default())
             .map(tonic::Response::new)
             .map_err(map_status)
     }
 
+    async fn stream_lookup_index(
+        &self,
+        request: tonic::Request<tonic::Streaming<LookupIndexChunk>>,
+    ) -> tonic::Result<tonic::Response<FinishNextLookupDataResponse>> {
+        let mut request = request.into_inner();
+
+        let instance = self.get_instance()?;
+        let mut file = tempfile::tempfile().map_err(|err| {
+            tonic::Status::internal(format!("couldn't create lookup index file: {:?}", err))
+        })?;
+        while let Some(chunk) = request.next().await {
+            file.write_all(&chunk?.data).map_err(|err| {
+                tonic::Status::internal(format!("couldn't write lookup index file: {:?}", err))
+            })?;
+        }
+        let index = map_lookup_index(&file).map_err(|err| {
+            tonic::Status::invalid_argument(format!("couldn't load lookup index: {:?}", err))
+        })?;
+        instance.finish_next_lookup_index(index).map(tonic::Response::new).map_err(map_status)
+    }
+
     async fn reserve(
         &self,
         request: tonic::Request<ReserveRequest>,
@@ -223,6 +329,152 @@ where
         let request = request.into_inner();
         self.get_instance()?.reserve(request).map(tonic::Response::new).map_err(map_status)
     }
+
+    async fn reload_wasm(
+        &self,
+        request: tonic::Request<ReloadWasmRequest>,
+    ) -> tonic::Result<tonic::Response<ReloadWasmResponse>> {
+        let request = request.into_inner();
+        self.get_instance()?.reload_wasm(&request).map(tonic::Response::new).map_err(map_status)
+    }
+
+    async fn terminate(
+        &self,
+        _request: tonic::Request<TerminateRequest>,
+    ) -> tonic::Result<tonic::Response<TerminateResponse>> {
+        // The container's lifetime is managed by the Oak Containers orchestrator, so
+        // there is nothing to shut down here; the launcher stops the VM
+        // instead.
+        Err(tonic::Status::unimplemented("terminate is not supported on Oak Containers"))
+    }
+
+    async fn extend_invocation(
+        &self,
+        request: tonic::Request<ExtendInvocationRequest>,
+    ) -> tonic::Result<tonic::Response<ExtendInvocationResponse>> {
+        self.get_instance()?;
+        let request = request.into_inner();
+        self.invocations.extend(request.invocation_id, &request.chunk).map_err(map_status)?;
+        Ok(tonic::Response::new(ExtendInvocationResponse {}))
+    }
+
+    async fn finish_invocation(
+        &self,
+        request: tonic::Request<FinishInvocationRequest>,
+    ) -> tonic::Result<tonic::Response<FinishInvocationResponse>> {
+        let invocation_id = request.into_inner().invocation_id;
+        let encrypted_request = self.invocations.take_request(invocation_id).map_err(map_status)?;
+        let response = match EncryptedRequest::decode(encrypted_request.as_slice()) {
+            #[allow(clippy::needless_update)]
+            Ok(encrypted_request) => {
+                self.handle_user_request(tonic::Request::new(InvokeRequest {
+                    encrypted_request: Some(encrypted_request),
+                    ..Default::default()
+                }))
+                .await
+            }
+            Err(err) => Err(tonic::Status::invalid_argument(format!(
+                "couldn't decode encrypted request: {err}"
+            ))),
+        };
+        match response {
+            Ok(response) => {
+                let response = response.into_inner();
+                let encrypted_response =
+                    response.encrypted_response.unwrap_or_default().encode_to_vec();
+                let response_size = encrypted_response.len() as u64;
+                self.invocations.set_response(invocation_id, encrypted_response);
+                Ok(tonic::Response::new(FinishInvocationResponse {
+                    response_size,
+                    error_class: response.error_class,
+                }))
+            }
+            Err(status) => {
+                self.invocations.abort(invocation_id);
+                Err(status)
+            }
+        }
+    }
+
+    async fn read_invocation_response(
+        &self,
+        request: tonic::Request<ReadInvocationResponseRequest>,
+    ) -> tonic::Result<tonic::Response<ReadInvocationResponseResponse>> {
+        let request = request.into_inner();
+        let (chunk, last) = self
+            .invocations
+            .read(request.invocation_id, request.max_chunk_size as usize)
+            .map_err(map_status)?;
+        Ok(tonic::Response::new(ReadInvocationResponseResponse { chunk, last }))
+    }
+
+    async fn abort_invocation(
+        &self,
+        request: tonic::Request<AbortInvocationRequest>,
+    ) -> tonic::Result<tonic::Response<AbortInvocationResponse>> {
+        self.invocations.abort(request.into_inner().invocation_id);
+        Ok(tonic::Response::new(AbortInvocationResponse {}))
+    }
+
+    async fn invoke_batch(
+        &self,
+        request: tonic::Request<InvokeRequest>,
+    ) -> tonic::Result<tonic::Response<InvokeResponse>> {
+        self.invoke_encrypted(request.into_inner(), OakFunctionsInstance::handle_user_request_batch)
+            .await
+            .map(tonic::Response::new)
+    }
+
+    async fn provision_secrets(
+        &self,
+        request: tonic::Request<ProvisionSecretsRequest>,
+    ) -> tonic::Result<tonic::Response<ProvisionSecretsResponse>> {
+        let instance = self.get_instance()?;
+        let encrypted_secrets = request.into_inner().encrypted_secrets.ok_or_else(|| {
+            tonic::Status::invalid_argument(
+                "ProvisionSecretsRequest doesn't contain encrypted secrets",
+            )
+        })?;
+        let (_, secrets, _) =
+            ServerEncryptor::decrypt_async(&encrypted_secrets, self.encryption_key_handle.as_ref())
+                .await
+                .map_err(|err| {
+                    tonic::Status::invalid_argument(format!("couldn't decrypt secrets: {:?}", err))
+                })?;
+        instance.provision_secrets(&secrets).map(tonic::Response::new).map_err(map_status)
+    }
+
+    async fn get_precompiled_wasm_module(
+        &self,
+        request: tonic::Request<GetPrecompiledWasmModuleRequest>,
+    ) -> tonic::Result<tonic::Response<GetPrecompiledWasmModuleResponse>> {
+        self.get_instance()?
+            .get_precompiled_wasm_module(&request.into_inner())
+            .map(tonic::Response::new)
+            .map_err(map_status)
+    }
+
+    async fn get_sealed_state(
+        &self,
+        _request: tonic::Request<GetSealedStateRequest>,
+    ) -> tonic::Result<tonic::Response<GetSealedStateResponse>> {
+        // The orchestrator doesn't expose a sealing key to the application.
+        Err(tonic::Status::unimplemented("sealed state is only supported on the Restricted Kernel"))
+    }
+}
+
+/// Derives the key of precompiled Wasm modules from the group key behind
+/// `group_key_handle`, see [`oak_functions_service::precompiled`].
+pub async fn derive_precompiled_module_key(
+    group_key_handle: &dyn AsyncEncryptionKeyHandle,
+) -> anyhow::Result<Vec<u8>> {
+    let session_keys = group_key_handle
+        .generate_recipient_context(&key_derivation_encapsulated_key())
+        .await
+        .context("couldn't derive session keys from the group key")?
+        .serialize()
+        .context("couldn't serialize session keys")?;
+    Ok(precompiled_module_key(&session_keys.request_key))
 }
 
 #[derive(Clone)]
@@ -372,7 +624,8 @@ const GRPC_STATUS_HEADER_CODE: &str = "grpc-status";
 const MAX_DECODING_MESSAGE_SIZE: usize = 1024 * 1024 * 1024;
 
 /// Starts up and serves an OakFunctionsContainersService instance from the
-/// provided stream of connections.
+/// provided stream of connections, with the aggregation threshold of the
+/// application config and the key of precompiled Wasm modules, if any.
 // The type of the stream is pretty horrible; we can define a slightly cleaner
 // type aliases for it when `type_alias_impl_trait` has been stabilized; see https://github.com/rust-lang/rust/issues/63063.
 pub async fn serve<H>(
@@ -386,6 +639,10 @@ pub async fn serve<H>(
             + Unpin,
     >,
     encryption_key_handle: Box<dyn AsyncEncryptionKeyHandle + Send + Sync>,
+    aggregation_threshold: Option<u32>,
+    time_config: Option<TimeConfig>,
+    lookup_data_signing_public_key: Option<Vec<u8>>,
+    precompiled_module_key: Option<Vec<u8>>,
     meter: Meter,
 ) -> anyhow::Result<()>
 where
@@ -413,6 +670,10 @@ where
         .add_service(
             OakFunctionsServer::new(OakFunctionsContainersService::<H>::new(
                 Arc::from(encryption_key_handle),
+                aggregation_threshold,
+                time_config,
+                lookup_data_signing_public_key,
+                precompiled_module_key,
                 Some(Arc::new(OtelObserver::new(meter))),
             ))
             .max_decoding_message_size(MAX_DECODING_MESSAGE_SIZE)
@@ -24,17 +24,20 @@ use std::{
 use anyhow::{anyhow, Context};
 use clap::Parser;
 use oak_containers_orchestrator::launcher_client::LauncherClient;
-use oak_containers_sdk::{InstanceEncryptionKeyHandle, OrchestratorClient};
+use oak_containers_sdk::{
+    GroupEncryptionKeyHandle, InstanceEncryptionKeyHandle, OrchestratorClient,
+};
 use oak_crypto::encryption_key::AsyncEncryptionKeyHandle;
 #[cfg(feature = "native")]
 use oak_functions_containers_app::native_handler::NativeHandler;
-use oak_functions_containers_app::serve as app_serve;
+use oak_functions_containers_app::{derive_precompiled_module_key, serve as app_serve};
 use oak_functions_service::{
+    clock::{TimeConfig, TimeSource},
     proto::oak::functions::config::{
         application_config::CommunicationChannel, ApplicationConfig, HandlerType,
         TcpCommunicationChannel,
     },
-    wasm::wasmtime::WasmtimeHandler,
+    wasm::engine::WasmEngineHandler,
 };
 use opentelemetry::{
     global::set_error_handler,
@@ -65,6 +68,10 @@ struct Args {
 async fn serve<S>(
     addr: S,
     handler_type: HandlerType,
+    aggregation_threshold: Option<u32>,
+    time_config: Option<TimeConfig>,
+    lookup_data_signing_public_key: Option<Vec<u8>>,
+    precompiled_module_key: Option<Vec<u8>>,
     stream: Box<
         dyn tokio_stream::Stream<
                 Item = Result<
@@ -84,11 +91,29 @@ where
 
     match handler_type {
         HandlerType::HandlerUnspecified | HandlerType::HandlerWasm => {
-            app_serve::<WasmtimeHandler>(stream, encryption_key_handle, meter).await
+            app_serve::<WasmEngineHandler>(
+                stream,
+                encryption_key_handle,
+                aggregation_threshold,
+                time_config,
+                lookup_data_signing_public_key,
+                precompiled_module_key,
+                meter,
+            )
+            .await
         }
         HandlerType::HandlerNative => {
             if cfg!(feature = "native") {
-                app_serve::<NativeHandler>(stream, encryption_key_handle, meter).await
+                app_serve::<NativeHandler>(
+                    stream,
+                    encryption_key_handle,
+                    aggregation_threshold,
+                    time_config,
+                    lookup_data_signing_public_key,
+                    precompiled_module_key,
+                    meter,
+                )
+                .await
             } else {
                 panic!(
                     "Application config specified `native` handler type, but this binary does not support that feature"
@@ -200,6 +225,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let aggregation_threshold = (application_config.aggregation_threshold > 0)
+        .then_some(application_config.aggregation_threshold);
+    let time_config = (application_config.time_granularity_seconds > 0).then_some(TimeConfig {
+        granularity_seconds: application_config.time_granularity_seconds,
+        source: TimeSource::System,
+    });
+    let lookup_data_signing_public_key =
+        Some(application_config.lookup_data_signing_public_key.clone())
+            .filter(|key| !key.is_empty());
+
+    // Without the key, the enclave compiles Wasm modules every time it starts.
+    let precompiled_module_key = match GroupEncryptionKeyHandle::create().await {
+        Ok(group_key_handle) => derive_precompiled_module_key(&group_key_handle)
+            .await
+            .map_err(|error| eprintln!("couldn't derive precompiled module key: {:?}", error))
+            .ok(),
+        Err(error) => {
+            eprintln!("couldn't create group key handle: {:?}", error);
+            None
+        }
+    };
+
     let server_handle = tokio::spawn(async move {
         let default_channel = CommunicationChannel::TcpChannel(TcpCommunicationChannel::default());
         let communication_config =
@@ -217,6 +264,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 serve(
                     addr,
                     application_config.handler_type(),
+                    aggregation_threshold,
+                    time_config,
+                    lookup_data_signing_public_key,
+                    precompiled_module_key,
                     Box::new(TcpListenerStream::new(listener)),
                     encryption_key_handle,
                     meter,
@@ -233,6 +284,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 serve(
                     addr,
                     application_config.handler_type(),
+                    aggregation_threshold,
+                    time_config,
+                    lookup_data_signing_public_key,
+                    precompiled_module_key,
                     Box::new(listener.incoming()),
                     encryption_key_handle,
                     meter,
@@ -22,7 +22,9 @@ use libloading::{Library, Symbol};
 use oak_functions_abi::{Request, Response};
 use oak_functions_service::{
     lookup::{LookupData, LookupDataManager},
-    Handler, Observer,
+    secrets::SecretStore,
+    wasm::memory::MemoryLimits,
+    Handler, HandlerConfig, Observer, Subsystems,
 };
 use ouroboros::self_referencing;
 use tempfile::{tempdir, TempDir};
@@ -181,6 +183,35 @@ impl NativeHandler {
     unsafe fn oak_main(&self) {
         self.library.borrow_oak_main()()
     }
+
+    /// Handles a request against the given lookup data.
+    fn invoke(
+        &self,
+        invoke_request: Request,
+        lookup_data: LookupData,
+    ) -> Result<Response, micro_rpc::Status> {
+        // Populate a new RequestContext. The threadlocal should be empty at this point;
+        // if it is not, we've somehow clashed with another thread.
+        assert!(
+            CONTEXT
+                .replace(Some(RequestContext {
+                    request: invoke_request.body,
+                    response: Vec::new(),
+                    lookup_data,
+                }))
+                .is_none(),
+            "request context was not empty"
+        );
+
+        // Safety: this is safe as long as the library adheres to our contracts.
+        unsafe { self.oak_main() };
+
+        // Clean up the request memory.
+        let ctx = CONTEXT.replace(None).expect("request context was empty");
+        let invoke_response =
+            Response::create(oak_functions_abi::StatusCode::Success, ctx.response);
+        Ok(invoke_response)
+    }
 }
 
 impl Handler for NativeHandler {
@@ -194,11 +225,32 @@ impl Handler for NativeHandler {
     /// Safety: It's up to the caller to guarantee that said shared object
     /// adheres to the semantics we require. This method should really be
     /// marked `unsafe` because of that.
+    ///
+    /// Native modules can't read provisioned secrets, consume the privacy
+    /// budget, aggregate contributions, run inferences or invoke helper modules
+    /// yet, and neither their execution nor their memory can be limited. They
+    /// can't serve one of several tenants either, as their lookups can't be
+    /// confined to a namespace.
     fn new_handler(
         module_bytes: &[u8],
+        config: &HandlerConfig,
         lookup_data_manager: Arc<LookupDataManager>,
+        _secret_store: Arc<SecretStore>,
+        _subsystems: Subsystems,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
     ) -> anyhow::Result<NativeHandler> {
+        anyhow::ensure!(
+            config.wasm_fuel_limit.is_none(),
+            "native handlers don't support fuel limits"
+        );
+        anyhow::ensure!(
+            config.wasm_memory_limits == MemoryLimits::default(),
+            "native handlers don't support memory limits"
+        );
+        anyhow::ensure!(
+            config.lookup_namespace.is_empty(),
+            "native handlers don't support lookup namespaces"
+        );
         let directory = tempdir().context("could not create temporary directory")?;
         let filename = directory.path().join("module.so");
         {
@@ -231,26 +283,17 @@ impl Handler for NativeHandler {
     }
 
     fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status> {
-        // Populate a new RequestContext. The threadlocal should be empty at this point;
-        // if it is not, we've somehow clashed with another thread.
-        assert!(
-            CONTEXT
-                .replace(Some(RequestContext {
-                    request: invoke_request.body,
-                    response: Vec::new(),
-                    lookup_data: self.lookup_data_manager.create_lookup_data(),
-                }))
-                .is_none(),
-            "request context was not empty"
-        );
-
-        // Safety: this is safe as long as the library adheres to our contracts.
-        unsafe { self.oak_main() };
+        self.invoke(invoke_request, self.lookup_data_manager.create_lookup_data())
+    }
 
-        // Clean up the request memory.
-        let ctx = CONTEXT.replace(None).expect("request context was empty");
-        let invoke_response =
-            Response::create(oak_functions_abi::StatusCode::Success, ctx.response);
-        Ok(invoke_response)
+    fn handle_invoke_batch(
+        &self,
+        invoke_requests: Vec<Request>,
+    ) -> Vec<Result<Response, micro_rpc::Status>> {
+        let lookup_data = self.lookup_data_manager.create_lookup_data();
+        invoke_requests
+            .into_iter()
+            .map(|invoke_request| self.invoke(invoke_request, lookup_data.clone()))
+            .collect()
     }
 }
@@ -31,7 +31,11 @@ use std::{
 use oak_crypto::encryption_key::generate_encryption_key_pair;
 use oak_functions_containers_app::serve;
 use oak_functions_service::{
-    proto::oak::functions::InitializeRequest, wasm::wasmtime::WasmtimeHandler,
+    proto::oak::functions::{
+        ExtendNextLookupDataRequest, FinishNextLookupDataRequest, InitializeRequest,
+        LookupDataChunk, LookupDataEntry,
+    },
+    wasm::engine::WasmEngineHandler,
 };
 use opentelemetry::metrics::{noop::NoopMeterProvider, MeterProvider};
 use tokio::net::TcpListener;
@@ -40,6 +44,12 @@ use tonic::{codec::CompressionEncoding, transport::Endpoint};
 
 use crate::proto::oak::functions::oak_functions_client::OakFunctionsClient;
 
+// The Ed25519 public key of the first test vector in RFC 8032.
+const LOOKUP_DATA_SIGNING_PUBLIC_KEY: [u8; 32] = [
+    0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+    0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+];
+
 #[tokio::test]
 async fn test_lookup() {
     let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup")
@@ -54,9 +64,13 @@ async fn test_lookup() {
 
     let (encryption_key, _) = generate_encryption_key_pair();
 
-    let server_handle = tokio::spawn(serve::<WasmtimeHandler>(
+    let server_handle = tokio::spawn(serve::<WasmEngineHandler>(
         stream,
         Box::new(encryption_key),
+        None,
+        None,
+        None,
+        None,
         NoopMeterProvider::new().meter(""),
     ));
 
@@ -74,10 +88,77 @@ async fn test_lookup() {
         .initialize(InitializeRequest {
             constant_response_size: 1000,
             wasm_module: fs::read(wasm_path).expect("failed to read wasm module"),
+            ..Default::default()
+        })
+        .await
+        .expect("failed to initialize Oak Functions");
+
+    server_handle.abort();
+    let _ = server_handle.await;
+}
+
+#[tokio::test]
+async fn test_unsigned_lookup_data_is_rejected_with_signing_key() {
+    let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup")
+        .expect("Failed to build Wasm module");
+
+    let (addr, stream) = {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (addr, Box::new(TcpListenerStream::new(listener)))
+    };
+
+    let (encryption_key, _) = generate_encryption_key_pair();
+
+    // The key comes from the measured application config, not from the
+    // initialize request, so the launcher can't remove it.
+    let server_handle = tokio::spawn(serve::<WasmEngineHandler>(
+        stream,
+        Box::new(encryption_key),
+        None,
+        None,
+        Some(LOOKUP_DATA_SIGNING_PUBLIC_KEY.to_vec()),
+        None,
+        NoopMeterProvider::new().meter(""),
+    ));
+
+    let mut oak_functions_client: OakFunctionsClient<tonic::transport::channel::Channel> = {
+        let channel = Endpoint::from_shared(format!("http://{addr}"))
+            .expect("couldn't form channel")
+            .connect_timeout(Duration::from_secs(120))
+            .connect()
+            .await
+            .expect("couldn't connect to trusted app");
+        OakFunctionsClient::new(channel).send_compressed(CompressionEncoding::Gzip)
+    };
+
+    oak_functions_client
+        .initialize(InitializeRequest {
+            constant_response_size: 1000,
+            wasm_module: fs::read(wasm_path).expect("failed to read wasm module"),
+            ..Default::default()
         })
         .await
         .expect("failed to initialize Oak Functions");
 
+    let chunk = LookupDataChunk {
+        items: vec![LookupDataEntry { key: b"key".to_vec(), value: b"value".to_vec() }],
+    };
+    oak_functions_client
+        .extend_next_lookup_data(ExtendNextLookupDataRequest {
+            chunk: Some(chunk),
+            ..Default::default()
+        })
+        .await
+        .expect("failed to extend lookup data");
+    let result =
+        oak_functions_client.finish_next_lookup_data(FinishNextLookupDataRequest::default()).await;
+    assert_eq!(
+        result.expect_err("unsigned lookup data was activated").code(),
+        tonic::Code::PermissionDenied
+    );
+
     server_handle.abort();
     let _ = server_handle.await;
 }
@@ -27,19 +27,26 @@ pub mod proto {
 }
 
 mod lookup;
+pub mod runner;
 pub mod server;
 
+use std::sync::Arc;
+
 use anyhow::Context;
 use oak_containers_launcher::{Launcher, TrustedApplicationAddress};
-use oak_functions_launcher::LookupDataConfig;
-use tokio::time::Duration;
+use oak_functions_launcher::{LookupDataConfig, LookupDataUpdate};
+use tokio::{
+    sync::watch,
+    time::{Duration, Instant},
+};
 use tokio_vsock::VsockStream;
 use tonic::transport::Endpoint;
 use tower::service_fn;
 
 use crate::proto::oak::functions::{
-    oak_functions_client::OakFunctionsClient as GrpcOakFunctionsClient, InitializeRequest,
-    InitializeResponse,
+    oak_functions_client::OakFunctionsClient as GrpcOakFunctionsClient,
+    GetPrecompiledWasmModuleRequest, InitializeRequest, InitializeResponse, PrecompiledWasmModule,
+    ReloadWasmRequest,
 };
 
 pub struct UntrustedApp {
@@ -99,25 +106,81 @@ impl UntrustedApp {
         Ok(initialize_response)
     }
 
+    /// Replaces the Wasm module of the initialized enclave, keeping the current
+    /// lookup data. Requests in flight complete using the previous module.
+    ///
+    /// `tenant_id` selects the tenant whose module is replaced if the enclave
+    /// serves several tenants, and must be empty otherwise.
+    pub async fn reload_wasm(
+        &mut self,
+        tenant_id: String,
+        wasm_module: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        log::info!("reloading Wasm module ({} bytes)", wasm_module.len());
+        self.oak_functions_client
+            .reload_wasm(ReloadWasmRequest { wasm_module, tenant_id })
+            .await
+            .context("couldn't reload Wasm module")?;
+        Ok(())
+    }
+
+    /// Returns the Wasm module of the initialized enclave compiled to native
+    /// code and encrypted, which enclaves of the same group can run after a
+    /// restart instead of compiling the module again.
+    pub async fn get_precompiled_wasm_module(&mut self) -> anyhow::Result<PrecompiledWasmModule> {
+        self.oak_functions_client
+            .get_precompiled_wasm_module(GetPrecompiledWasmModuleRequest {})
+            .await
+            .context("couldn't get precompiled Wasm module")?
+            .into_inner()
+            .precompiled_wasm_module
+            .context("response doesn't contain a precompiled Wasm module")
+    }
+
     pub async fn kill(&mut self) {
         self.launcher.kill().await;
     }
 
-    pub async fn setup_lookup_data(&mut self, config: LookupDataConfig) -> anyhow::Result<()> {
+    /// Loads the lookup data and, if an update interval is configured, spawns a
+    /// task that refreshes it periodically. The returned receiver is notified
+    /// after every refresh.
+    ///
+    /// If `index` is set, the lookup data is sent as an index built by the
+    /// launcher, which the enclave uses in place.
+    pub async fn setup_lookup_data(
+        &mut self,
+        config: LookupDataConfig,
+        index: bool,
+    ) -> anyhow::Result<watch::Receiver<LookupDataUpdate>> {
         log::info!("setting up lookup data");
-        update_lookup_data(&mut self.oak_functions_client, &config).await?;
+        let start = Instant::now();
+        update_lookup_data(&mut self.oak_functions_client, &config, index).await?;
+        let (updates, receiver) = watch::channel(LookupDataUpdate {
+            attempts: 1,
+            changed: true,
+            duration: start.elapsed(),
+            result: Ok(()),
+            memory_usage: None,
+        });
 
         // Spawn task to periodically refresh lookup data.
         if config.update_interval.is_some() {
-            tokio::spawn(setup_periodic_update(self.oak_functions_client.clone(), config));
+            tokio::spawn(setup_periodic_update(
+                self.oak_functions_client.clone(),
+                config,
+                index,
+                updates,
+            ));
         }
-        Ok(())
+        Ok(receiver)
     }
 }
 
 async fn setup_periodic_update(
     mut client: GrpcOakFunctionsClient<tonic::transport::channel::Channel>,
     config: LookupDataConfig,
+    index: bool,
+    updates: watch::Sender<LookupDataUpdate>,
 ) {
     // Only set periodic update if an interval is given.
     let mut interval =
@@ -125,19 +188,52 @@ async fn setup_periodic_update(
     loop {
         // Wait before updating because we just loaded the lookup data.
         interval.tick().await;
-        let _ = update_lookup_data(&mut client, &config).await;
-        // Ignore errors in updates of lookup data after the initial update.
+        // Retry failed updates with exponential backoff; if all retries fail, we keep
+        // serving the previous lookup data until the next update.
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            match update_lookup_data(&mut client, &config, index).await {
+                Ok(()) => break Ok(()),
+                Err(err) if attempts > config.max_retries => {
+                    log::error!(
+                        "couldn't update lookup data after {} attempts: {:?}",
+                        attempts,
+                        err
+                    );
+                    break Err(Arc::new(err));
+                }
+                Err(err) => {
+                    log::warn!("couldn't update lookup data (attempt {}): {:?}", attempts, err);
+                    tokio::time::sleep(config.retry_delay(attempts)).await;
+                }
+            }
+        };
+        let changed = result.is_ok();
+        updates.send_replace(LookupDataUpdate {
+            attempts,
+            changed,
+            duration: start.elapsed(),
+            result,
+            memory_usage: None,
+        });
     }
 }
 
 async fn update_lookup_data(
     client: &mut GrpcOakFunctionsClient<tonic::transport::channel::Channel>,
     config: &LookupDataConfig,
+    index: bool,
 ) -> anyhow::Result<()> {
     log::info!("updating lookup data");
-    let start = std::time::Instant::now();
-    let result =
-        lookup::update_lookup_data(client, &config.lookup_data_path, config.max_chunk_size).await;
+    let start = Instant::now();
+    let lookup_data = config.lookup_data_source.fetch().await?;
+    let result = if index {
+        lookup::update_lookup_index(client, lookup_data.path(), config.max_chunk_size).await
+    } else {
+        lookup::update_lookup_data(client, lookup_data.path(), config.max_chunk_size).await
+    };
     log::info!("updated lookup data in {}ms", start.elapsed().as_millis());
     result
 }
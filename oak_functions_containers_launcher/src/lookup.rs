@@ -14,15 +14,17 @@
 // limitations under the License.
 //
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::Path};
 
 use anyhow::{anyhow, Context};
+use oak_functions_service::lookup_index::build_lookup_index;
 use prost::Message;
 use ubyte::ByteUnit;
 
 use crate::proto::oak::functions::{
     oak_functions_client::OakFunctionsClient as GrpcOakFunctionsClient, Empty,
     ExtendNextLookupDataRequest, FinishNextLookupDataRequest, LookupDataChunk, LookupDataEntry,
+    LookupIndexChunk,
 };
 
 struct UpdateClient<'a, I: Iterator<Item = LookupDataChunk>> {
@@ -44,7 +46,7 @@ impl<I: Iterator<Item = LookupDataChunk>> UpdateClient<'_, I> {
     async fn extend(&mut self, chunk: Option<LookupDataChunk>) -> anyhow::Result<()> {
         let _ = self
             .inner
-            .extend_next_lookup_data(ExtendNextLookupDataRequest { chunk })
+            .extend_next_lookup_data(ExtendNextLookupDataRequest { chunk, ..Default::default() })
             .await
             .map_err(|err| anyhow!(format!("error handling client request: {:?}", err)))?;
         Ok(())
@@ -53,7 +55,7 @@ impl<I: Iterator<Item = LookupDataChunk>> UpdateClient<'_, I> {
     async fn finish(&mut self) -> anyhow::Result<()> {
         let _ = self
             .inner
-            .finish_next_lookup_data(FinishNextLookupDataRequest {})
+            .finish_next_lookup_data(FinishNextLookupDataRequest::default())
             .await
             .map_err(|err| anyhow!(format!("error handling client request: {:?}", err)));
         Ok(())
@@ -74,7 +76,7 @@ impl<I: Iterator<Item = LookupDataChunk>> UpdateClient<'_, I> {
 // client.
 pub async fn update_lookup_data(
     client: &mut GrpcOakFunctionsClient<tonic::transport::channel::Channel>,
-    lookup_data_path: &PathBuf,
+    lookup_data_path: &Path,
     max_chunk_size: ByteUnit,
 ) -> anyhow::Result<()> {
     let lookup_data = load_lookup_data(lookup_data_path)?;
@@ -83,6 +85,30 @@ pub async fn update_lookup_data(
     UpdateClient { inner: client, chunks }.update().await
 }
 
+// Loads lookup data from the given path, builds an index of it, and streams the
+// index to the client.
+pub async fn update_lookup_index(
+    client: &mut GrpcOakFunctionsClient<tonic::transport::channel::Channel>,
+    lookup_data_path: &Path,
+    max_chunk_size: ByteUnit,
+) -> anyhow::Result<()> {
+    let lookup_data = load_lookup_data(lookup_data_path)?;
+    let index = build_lookup_index(
+        lookup_data.iter().map(|(key, value)| (key.as_slice(), value.as_slice())),
+    )?;
+    log::info!("built lookup index of {} entries ({} bytes)", lookup_data.len(), index.len());
+    let chunks: Vec<LookupIndexChunk> = index
+        .chunks(max_chunk_size.as_u64() as usize)
+        .map(|data| LookupIndexChunk { data: data.to_vec() })
+        .collect();
+
+    client
+        .stream_lookup_index(futures::stream::iter(chunks))
+        .await
+        .map_err(|err| anyhow!(format!("error streaming lookup index: {:?}", err)))?;
+    Ok(())
+}
+
 fn chunk_up_lookup_data(
     source_lookup_data: HashMap<Vec<u8>, Vec<u8>>,
     max_chunk_size: ByteUnit,
@@ -115,7 +141,7 @@ fn chunk_up_lookup_data(
     chunks
 }
 
-fn load_lookup_data(file_path: &std::path::PathBuf) -> anyhow::Result<HashMap<Vec<u8>, Vec<u8>>> {
+fn load_lookup_data(file_path: &Path) -> anyhow::Result<HashMap<Vec<u8>, Vec<u8>>> {
     let bytes = std::fs::read(file_path).map_err(|error| {
         anyhow!("couldn't read the lookup data file {}: {}", file_path.display(), error)
     })?;
@@ -0,0 +1,375 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Runs the launcher with its parsed arguments, so that the launcher binary
+//! and the unified `oak_launcher` binary behave the same way.
+
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use oak_containers_launcher::ChannelType;
+use oak_functions_launcher::{health::HealthState, metrics::Metrics};
+use prost::Message;
+use ubyte::ByteUnit;
+
+use crate::proto::oak::functions::{
+    config::{
+        application_config::CommunicationChannel, ApplicationConfig, VsockCommunicationChannel,
+    },
+    InitializeRequest, PrecompiledWasmModule, Tenant, WasmEngine,
+};
+
+/// Arguments of the Oak Containers flavor of the launcher, in addition to the
+/// arguments shared with the Restricted Kernel flavor.
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[clap(flatten)]
+    pub containers_args: oak_containers_launcher::Args,
+
+    /// Whether to send the lookup data to the enclave as an immutable index
+    /// built by the launcher, which the enclave maps from a file and uses in
+    /// place instead of loading it into a hash table. The index keeps only
+    /// the last value of every key, and supports range and prefix lookups.
+    #[arg(long)]
+    pub lookup_data_index: bool,
+
+    /// The engine that runs the Wasm module in the enclave. Wasmtime compiles
+    /// the module ahead of time when the enclave is initialized, which makes
+    /// CPU-bound modules much faster than interpreting them with wasmi.
+    #[arg(long, value_enum, default_value_t = WasmEngineType::default())]
+    pub wasm_engine: WasmEngineType,
+
+    /// The number of contributions a bucket of the aggregation store needs
+    /// before the Wasm module can read it. It is part of the application
+    /// config, which is measured into the attestation evidence. The
+    /// aggregation store is disabled if not set.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub aggregation_threshold: Option<u32>,
+
+    /// Path to an ONNX model that the Wasm module can run inferences with. The
+    /// enclave must be built with the `ml` feature.
+    #[arg(long)]
+    pub ml_model: Option<PathBuf>,
+
+    /// A helper Wasm module that the Wasm module can invoke with
+    /// `oak_functions_sdk::invoke_module`, as `<name>=<path>`. Can be given
+    /// multiple times.
+    #[arg(long = "helper-module", value_parser = parse_helper_module)]
+    pub helper_modules: Vec<(String, PathBuf)>,
+
+    /// The Wasm module of a tenant, as `<id>=<path>`, to serve several tenants
+    /// from one enclave instead of the module given with `--wasm`. Requests
+    /// must then be `TenantRequest`s naming their tenant. Can be given
+    /// multiple times.
+    #[arg(
+        long = "tenant",
+        value_parser = parse_tenant,
+        conflicts_with = "helper_modules",
+    )]
+    pub tenants: Vec<(String, PathBuf)>,
+
+    /// The number of invocations the enclave runs at the same time. Further
+    /// invocations wait for one of them to complete. Invocations aren't
+    /// bounded if not set.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrent_invocations: Option<u32>,
+
+    /// Path to a file that keeps the Wasm module compiled to native code by
+    /// the enclave across restarts. If the file exists, the enclave runs the
+    /// module in it instead of compiling the Wasm module, as long as it was
+    /// compiled from the same Wasm module by an enclave of the same group.
+    /// Otherwise the file is written after the enclave compiled the module.
+    #[arg(long, conflicts_with = "tenants")]
+    pub precompiled_wasm_module: Option<PathBuf>,
+}
+
+fn parse_helper_module(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected <name>=<path>, got {}", s)),
+    }
+}
+
+fn parse_tenant(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        // Tenant IDs name the lookup data namespace of the tenant, which ends with a slash.
+        Some((id, path)) if !id.is_empty() && !id.contains('/') && !path.is_empty() => {
+            Ok((id.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected <id>=<path> with an ID without slashes, got {}", s)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum WasmEngineType {
+    Wasmi,
+    #[default]
+    Wasmtime,
+}
+
+impl From<WasmEngineType> for WasmEngine {
+    fn from(wasm_engine: WasmEngineType) -> Self {
+        match wasm_engine {
+            WasmEngineType::Wasmi => WasmEngine::Wasmi,
+            WasmEngineType::Wasmtime => WasmEngine::Wasmtime,
+        }
+    }
+}
+
+/// Launches the enclave on Oak Containers with `args`, and serves it as
+/// configured by `functions_args` until the launcher is interrupted or the VMM
+/// exits.
+pub async fn run(
+    mut args: Args,
+    functions_args: oak_functions_launcher::Args,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        args.tenants.is_empty() || functions_args.wasm.is_none(),
+        "--tenant can't be used with --wasm"
+    );
+    // gRPC messages are limited to 4 MiB.
+    let lookup_data_config = functions_args.lookup_data_config(ByteUnit::Mebibyte(4));
+
+    if functions_args.validate.validate_only {
+        let qemu_params = &args.containers_args.qemu_params;
+        let mut images = vec![
+            ("stage0".to_string(), qemu_params.stage0_binary.clone()),
+            ("kernel".to_string(), qemu_params.kernel.clone()),
+            ("initrd".to_string(), qemu_params.initrd.clone()),
+            ("system-image".to_string(), args.containers_args.system_image.clone()),
+        ];
+        images.extend(
+            args.containers_args
+                .container_bundle
+                .clone()
+                .map(|path| ("container-bundle".to_string(), path)),
+        );
+        let wasm_modules: Vec<(String, PathBuf)> =
+            args.tenants.iter().chain(args.helper_modules.iter()).cloned().collect();
+        let report = oak_functions_launcher::validate::validate(
+            &functions_args,
+            &wasm_modules,
+            &images,
+            &lookup_data_config,
+        )
+        .await;
+        return report.print();
+    }
+
+    let mut config = ApplicationConfig {
+        aggregation_threshold: args.aggregation_threshold.unwrap_or(0),
+        time_granularity_seconds: functions_args.time_granularity_seconds.unwrap_or(0),
+        ..Default::default()
+    };
+    if args.containers_args.communication_channel == ChannelType::VirtioVsock {
+        config.communication_channel =
+            Some(CommunicationChannel::VsockChannel(VsockCommunicationChannel::default()));
+
+        // If no explicit CID was specified, override it to be the current process ID.
+        args.containers_args.qemu_params.virtio_guest_cid.get_or_insert_with(std::process::id);
+    }
+    args.containers_args.application_config = config.encode_to_vec();
+
+    // Start the health server first, so that the launcher is reported as live (but
+    // not ready) while the enclave is being set up.
+    let health = Arc::new(HealthState::default());
+    if let Some(health_port) = functions_args.health_port {
+        let health_server = oak_functions_launcher::health::serve(
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, health_port)),
+            health.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = health_server.await {
+                log::error!("health server failed: {:?}", err);
+            }
+        });
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(metrics_port) = functions_args.metrics_port {
+        let metrics_server = oak_functions_launcher::metrics::serve(
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, metrics_port)),
+            metrics.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = metrics_server.await {
+                log::error!("metrics server failed: {:?}", err);
+            }
+        });
+    }
+
+    let mut untrusted_app = crate::UntrustedApp::create(args.containers_args)
+        .await
+        .context("couldn't create untrusted launcher")?;
+
+    let mut tenants = Vec::with_capacity(args.tenants.len());
+    for (id, path) in &args.tenants {
+        anyhow::ensure!(tenants.iter().all(|(other, _)| other != id), "duplicate tenant {}", id);
+        let wasm_module = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("couldn't read Wasm file {}", path.display()))?;
+        tenants.push((
+            id.clone(),
+            Tenant {
+                wasm_module,
+                // A constant response size of 0 asks the enclave to derive it from the Wasm
+                // module.
+                constant_response_size: functions_args.constant_response_size.unwrap_or(0),
+            },
+        ));
+    }
+    let wasm_bytes = match &functions_args.wasm {
+        Some(path) => tokio::fs::read(path)
+            .await
+            .with_context(|| format!("couldn't read Wasm file {}", path.display()))?,
+        None => {
+            anyhow::ensure!(!tenants.is_empty(), "either --wasm or --tenant is required");
+            Vec::new()
+        }
+    };
+    let ml_model = match &args.ml_model {
+        Some(path) => tokio::fs::read(path)
+            .await
+            .with_context(|| format!("couldn't read ML model {}", path.display()))?,
+        None => Vec::new(),
+    };
+    let mut helper_modules = Vec::with_capacity(args.helper_modules.len());
+    for (name, path) in &args.helper_modules {
+        anyhow::ensure!(
+            helper_modules.iter().all(|(other, _)| other != name),
+            "duplicate helper module {}",
+            name
+        );
+        let wasm_module = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("couldn't read helper module {}", path.display()))?;
+        helper_modules.push((name.clone(), wasm_module));
+    }
+    let precompiled_wasm_module = match &args.precompiled_wasm_module {
+        Some(path) if path.exists() => {
+            let bytes = tokio::fs::read(path).await.with_context(|| {
+                format!("couldn't read precompiled Wasm module {}", path.display())
+            })?;
+            Some(PrecompiledWasmModule::decode(bytes.as_slice()).with_context(|| {
+                format!("couldn't decode precompiled Wasm module {}", path.display())
+            })?)
+        }
+        _ => None,
+    };
+
+    let initialize_response = untrusted_app
+        .initialize_enclave(InitializeRequest {
+            wasm_module: wasm_bytes,
+            // A constant response size of 0 asks the enclave to derive it from the Wasm module.
+            constant_response_size: functions_args.constant_response_size.unwrap_or(0),
+            peer_attestation_policy: functions_args.peer_attestation_policy()?,
+            lookup_data_ordered_index: lookup_data_config.ordered_index,
+            lookup_data_memory_budget: lookup_data_config
+                .memory_budget
+                .map_or(0, |budget| budget.as_u64()),
+            wasm_engine: WasmEngine::from(args.wasm_engine).into(),
+            wasm_fuel_limit: functions_args.wasm_fuel_limit.unwrap_or(0),
+            wasm_memory_limit: functions_args.wasm_memory_limit.map_or(0, |limit| limit.as_u64()),
+            wasm_memory_growth_disabled: functions_args.wasm_memory_growth_disabled,
+            privacy_budget_epsilon: functions_args.privacy_budget_epsilon.unwrap_or(0.0),
+            ml_model,
+            helper_modules: helper_modules.into_iter().collect(),
+            tenants: tenants.into_iter().collect(),
+            max_concurrent_invocations: args.max_concurrent_invocations.unwrap_or(0),
+            precompiled_wasm_module,
+            ..Default::default()
+        })
+        .await
+        .map_err(|error| {
+            eprintln!("initialize response error: {}", error);
+            anyhow::anyhow!("couldn't get encrypted response: {}", error)
+        })?;
+    log::info!(
+        "constant response size: {} (maximum declared by the Wasm module: {:?})",
+        initialize_response.constant_response_size,
+        initialize_response.max_response_size
+    );
+    for (id, constant_response_size) in &initialize_response.tenant_constant_response_sizes {
+        log::info!("constant response size of tenant {}: {}", id, constant_response_size);
+    }
+    health.set_wasm_initialized();
+
+    // The enclave ignores precompiled modules it can't authenticate, e.g. ones
+    // compiled from an earlier Wasm module, so the file is always replaced with
+    // the module the enclave runs.
+    if let Some(path) = &args.precompiled_wasm_module {
+        match untrusted_app.get_precompiled_wasm_module().await {
+            Ok(precompiled_wasm_module) => {
+                tokio::fs::write(path, precompiled_wasm_module.encode_to_vec())
+                    .await
+                    .with_context(|| {
+                        format!("couldn't write precompiled Wasm module {}", path.display())
+                    })?;
+                log::info!("wrote precompiled Wasm module to {}", path.display());
+            }
+            Err(err) => log::warn!("couldn't get precompiled Wasm module: {:?}", err),
+        }
+    }
+
+    let endorsed_evidence = untrusted_app
+        .launcher
+        .get_endorsed_evidence()
+        .await
+        .context("couldn't get endorsed evidence")?;
+    let evidence =
+        endorsed_evidence.evidence.context("endorsed evidence message doesn't contain evidence")?;
+    let endorsements = endorsed_evidence
+        .endorsements
+        .context("endorsed evidence message doesn't contain endorsements")?;
+    health.set_evidence_obtained();
+
+    let lookup_data_updates =
+        untrusted_app.setup_lookup_data(lookup_data_config, args.lookup_data_index).await?;
+    health.watch_lookup_data(lookup_data_updates.clone());
+    metrics.watch_lookup_data(lookup_data_updates);
+
+    let server_future = crate::server::new(
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, functions_args.port)),
+        untrusted_app.oak_functions_client.clone(),
+        evidence,
+        endorsements,
+        metrics,
+    );
+
+    // Wait until something dies or we get a signal to terminate.
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("Ctrl-C received, terminating VMM");
+            untrusted_app.launcher.kill().await;
+        },
+        _ = server_future => {
+            log::info!("server terminated, terminating VMM");
+            untrusted_app.launcher.kill().await;
+        },
+        val = untrusted_app.launcher.wait() => {
+            log::error!("Unexpected VMM exit, status: {:?}", val);
+        },
+    }
+
+    Ok(())
+}
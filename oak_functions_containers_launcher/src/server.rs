@@ -17,15 +17,20 @@
 // TODO(#4409): this duplicates `oak_functions_launcher/src/server.rs`. Refactor
 // these to share code.
 
-use std::{net::SocketAddr, pin::Pin};
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Instant};
 
 use futures::{Future, Stream, StreamExt};
-use oak_functions_launcher::proto::oak::session::v1::{
-    request_wrapper, response_wrapper,
-    streaming_session_server::{StreamingSession, StreamingSessionServer},
-    EndorsedEvidence, GetEndorsedEvidenceResponse, InvokeResponse, RequestWrapper, ResponseWrapper,
+use oak_functions_launcher::{
+    metrics::{Metrics, GET_ENDORSED_EVIDENCE_REQUEST, INVOKE_REQUEST},
+    proto::oak::session::v1::{
+        request_wrapper, response_wrapper,
+        streaming_session_server::{StreamingSession, StreamingSessionServer},
+        EndorsedEvidence, GetEndorsedEvidenceResponse, InvokeResponse, RequestWrapper,
+        ResponseWrapper,
+    },
 };
 use oak_proto_rust::oak::attestation::v1::{Endorsements, Evidence};
+use prost::Message;
 use tonic::{transport::Server, Request, Response, Status, Streaming};
 
 use crate::proto::oak::functions::{
@@ -36,6 +41,7 @@ pub struct SessionProxy {
     connector_handle: GrpcOakFunctionsClient<tonic::transport::channel::Channel>,
     evidence: Evidence,
     endorsements: Endorsements,
+    metrics: Arc<Metrics>,
 }
 
 #[tonic::async_trait]
@@ -55,6 +61,7 @@ impl StreamingSession for SessionProxy {
             endorsements: Some(self.endorsements.clone()),
         };
         let mut connector_handle = self.connector_handle.clone();
+        let metrics = self.metrics.clone();
 
         let response_stream = async_stream::try_stream! {
             while let Some(request) = request_stream.next().await {
@@ -67,21 +74,44 @@ impl StreamingSession for SessionProxy {
 
                 let response = match request {
                     request_wrapper::Request::GetEndorsedEvidenceRequest(_) => {
+                        metrics.observe_request(GET_ENDORSED_EVIDENCE_REQUEST);
+                        #[allow(clippy::needless_update)]
                         response_wrapper::Response::GetEndorsedEvidenceResponse(GetEndorsedEvidenceResponse {
                             endorsed_evidence: Some(endorsed_evidence.clone()),
+                            ..Default::default()
                         })
                     }
+                    request_wrapper::Request::ResumeSessionRequest(_) => {
+                        Err(tonic::Status::failed_precondition("session resumption isn't supported"))?
+                    }
+                    request_wrapper::Request::InvokeRequestChunk(_) => {
+                        Err(tonic::Status::unimplemented("chunked invocations aren't supported"))?
+                    }
+                    request_wrapper::Request::InvokeBatchRequest(_) => {
+                        Err(tonic::Status::unimplemented("batch invocations aren't supported"))?
+                    }
                     request_wrapper::Request::InvokeRequest(invoke_request) => {
+                        metrics.observe_request(INVOKE_REQUEST);
+                        let request_size = invoke_request.encrypted_request.as_ref().map_or(0, Message::encoded_len);
                         #[allow(clippy::needless_update)]
                         let enclave_invoke_request = InvokeRequest {
                             encrypted_request: invoke_request.encrypted_request,
                             ..Default::default()
                         };
+                        let start = Instant::now();
                         let enclave_invoke_response = connector_handle
                             .handle_user_request(enclave_invoke_request)
                             .await
-                            .map_err(|err| tonic::Status::internal(format!("error handling client request: {:?}", err)))?
+                            .map_err(|err| {
+                                metrics.observe_enclave_error();
+                                tonic::Status::internal(format!("error handling client request: {:?}", err))
+                            })?
                             .into_inner();
+                        metrics.observe_invoke(
+                            request_size,
+                            enclave_invoke_response.encrypted_response.as_ref().map_or(0, Message::encoded_len),
+                            start.elapsed(),
+                        );
 
                         #[allow(clippy::needless_update)]
                         response_wrapper::Response::InvokeResponse(InvokeResponse {
@@ -105,8 +135,9 @@ pub fn new(
     connector_handle: GrpcOakFunctionsClient<tonic::transport::channel::Channel>,
     evidence: Evidence,
     endorsements: Endorsements,
+    metrics: Arc<Metrics>,
 ) -> impl Future<Output = Result<(), tonic::transport::Error>> {
-    let server_impl = SessionProxy { connector_handle, evidence, endorsements };
+    let server_impl = SessionProxy { connector_handle, evidence, endorsements, metrics };
 
     Server::builder().add_service(StreamingSessionServer::new(server_impl)).serve(addr)
 }
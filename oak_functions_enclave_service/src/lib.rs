@@ -19,21 +19,37 @@
 extern crate alloc;
 
 use alloc::{format, string::ToString, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use oak_attestation::{dice::evidence_to_proto, handler::EncryptionHandler};
 use oak_core::sync::OnceCell;
-use oak_crypto::encryption_key::EncryptionKeyHandle;
+use oak_crypto::{encryption_key::EncryptionKeyHandle, encryptor::ServerEncryptor};
 pub use oak_functions_service::proto;
 use oak_functions_service::{
+    application_config_digest,
+    clock::{TimeConfig, TimeSource},
     instance::OakFunctionsInstance,
-    proto::oak::functions::{
-        AbortNextLookupDataResponse, Empty, ExtendNextLookupDataRequest,
-        ExtendNextLookupDataResponse, FinishNextLookupDataRequest, FinishNextLookupDataResponse,
-        InitializeRequest, InitializeResponse, InvokeRequest, InvokeResponse, LookupDataChunk,
-        OakFunctions, ReserveRequest, ReserveResponse,
+    invocations::Invocations,
+    proto::oak::{
+        crypto::v1::EncryptedRequest,
+        functions::{
+            config::ApplicationConfig, AbortInvocationRequest, AbortInvocationResponse,
+            AbortNextLookupDataResponse, Empty, ExtendInvocationRequest, ExtendInvocationResponse,
+            ExtendNextLookupDataRequest, ExtendNextLookupDataResponse, FinishInvocationRequest,
+            FinishInvocationResponse, FinishNextLookupDataRequest, FinishNextLookupDataResponse,
+            GetPrecompiledWasmModuleRequest, GetPrecompiledWasmModuleResponse,
+            GetSealedStateRequest, GetSealedStateResponse, InitializeRequest, InitializeResponse,
+            InvocationErrorClass, InvokeRequest, InvokeResponse, LookupDataChunk, LookupIndexChunk,
+            OakFunctions, PersistentState, ProvisionSecretsRequest, ProvisionSecretsResponse,
+            ReadInvocationResponseRequest, ReadInvocationResponseResponse, ReloadWasmRequest,
+            ReloadWasmResponse, ReserveRequest, ReserveResponse, TerminateRequest,
+            TerminateResponse,
+        },
     },
+    wasm::invocation_error_class,
     Handler, Observer,
 };
+use oak_restricted_kernel_sdk::crypto::Sealer;
 use prost::Message;
 
 pub struct OakFunctionsService<EKH, EP, H>
@@ -46,6 +62,10 @@ where
     encryption_key_handle: Arc<EKH>,
     instance: OnceCell<OakFunctionsInstance<H>>,
     observer: Option<Arc<dyn Observer + Send + Sync>>,
+    // Seals the state the enclave keeps across restarts, if supported.
+    sealer: Option<Arc<dyn Sealer + Send + Sync>>,
+    terminate_requested: Arc<AtomicBool>,
+    invocations: Invocations,
 }
 
 impl<EKH, EP, H> OakFunctionsService<EKH, EP, H>
@@ -58,8 +78,127 @@ where
         evidence_provider: EP,
         encryption_key_handle: Arc<EKH>,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
+        sealer: Option<Arc<dyn Sealer + Send + Sync>>,
     ) -> Self {
-        Self { evidence_provider, encryption_key_handle, instance: OnceCell::new(), observer }
+        Self {
+            evidence_provider,
+            encryption_key_handle,
+            instance: OnceCell::new(),
+            observer,
+            sealer,
+            terminate_requested: Arc::new(AtomicBool::new(false)),
+            invocations: Invocations::default(),
+        }
+    }
+    /// Returns a flag that is set once the host has asked the enclave to
+    /// terminate. The server loop should stop after responding to that request.
+    pub fn terminate_requested(&self) -> Arc<AtomicBool> {
+        self.terminate_requested.clone()
+    }
+    // Decrypts the request, handles its plaintext with `handler`, and encrypts
+    // the result wrapped in a micro RPC response wrapper.
+    fn invoke_encrypted(
+        &self,
+        request: InvokeRequest,
+        handler: fn(&OakFunctionsInstance<H>, Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status>,
+    ) -> Result<InvokeResponse, micro_rpc::Status> {
+        let encryption_key_handle = self.encryption_key_handle.clone();
+        let instance = self.get_instance()?;
+
+        let encrypted_request = request.encrypted_request.ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                "InvokeRequest doesn't contain an encrypted request".to_string(),
+            )
+        })?;
+        // The Restricted Kernel has no clock, so the time is the host's.
+        let now_utc_millis =
+            i64::try_from(request.unix_time_seconds).unwrap_or(i64::MAX).saturating_mul(1000);
+        instance.check_peer_attestation(&encrypted_request, now_utc_millis)?;
+        instance.observe_host_time(request.unix_time_seconds);
+
+        let mut error_class = InvocationErrorClass::Unspecified;
+        let encrypted_response = EncryptionHandler::create(encryption_key_handle, |r| {
+            // Wrap the invocation result (which may be an Error) into a micro RPC Response
+            // wrapper protobuf, and encode that as bytes.
+            let response_result: Result<Vec<u8>, micro_rpc::Status> = handler(instance, r);
+            if let Err(err) = &response_result {
+                error_class = invocation_error_class(err);
+            }
+            let response: micro_rpc::ResponseWrapper = response_result.into();
+            response.encode_to_vec()
+        })
+        .invoke(&encrypted_request)
+        .map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::Internal,
+                format!("couldn't call request handler: {:?}", err),
+            )
+        })?;
+        #[allow(clippy::needless_update)]
+        Ok(InvokeResponse {
+            encrypted_response: Some(encrypted_response),
+            error_class: error_class.into(),
+            ..Default::default()
+        })
+    }
+    fn get_sealer(&self) -> Result<&(dyn Sealer + Send + Sync), micro_rpc::Status> {
+        self.sealer.as_deref().ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "sealed state isn't supported",
+            )
+        })
+    }
+    // Unseals the state of an earlier enclave and restores it in `instance`.
+    fn restore_sealed_state(
+        &self,
+        instance: &OakFunctionsInstance<H>,
+        sealed_state: &[u8],
+    ) -> Result<(), micro_rpc::Status> {
+        let serialized = self.get_sealer()?.unseal(sealed_state).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("couldn't unseal state: {:?}", err),
+            )
+        })?;
+        let state = PersistentState::decode(serialized.as_slice()).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("couldn't decode sealed state: {:?}", err),
+            )
+        })?;
+        instance.restore_persistent_state(&state)
+    }
+    // Decodes `application_config`, after checking that it is the config the
+    // Restricted Kernel measured into the evidence.
+    fn measured_application_config(
+        &self,
+        application_config: &[u8],
+    ) -> Result<ApplicationConfig, micro_rpc::Status> {
+        let measured = self
+            .evidence_provider
+            .get_evidence()
+            .application_keys
+            .application_config_digest()
+            .map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::Internal,
+                    format!("couldn't get the measured application config: {err}"),
+                )
+            })?;
+        if application_config_digest(application_config) != measured {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                "the application config isn't the one measured into the evidence",
+            ));
+        }
+        ApplicationConfig::decode(application_config).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("couldn't decode application config: {err}"),
+            )
+        })
     }
     fn get_instance(&self) -> Result<&OakFunctionsInstance<H>, micro_rpc::Status> {
         self.instance.get().ok_or_else(|| {
@@ -88,7 +227,34 @@ where
                 "already initialized",
             )),
             None => {
-                let instance = OakFunctionsInstance::new(&request, self.observer.clone())?;
+                // The aggregation store isn't available on the Restricted Kernel. Nor does it
+                // have group keys to derive the key of precompiled Wasm modules from.
+                let application_config =
+                    self.measured_application_config(&request.application_config)?;
+                let time_config =
+                    (application_config.time_granularity_seconds > 0).then_some(TimeConfig {
+                        granularity_seconds: application_config.time_granularity_seconds,
+                        source: TimeSource::Host,
+                    });
+                let lookup_data_signing_public_key =
+                    Some(application_config.lookup_data_signing_public_key.as_slice())
+                        .filter(|key| !key.is_empty());
+                let instance = OakFunctionsInstance::new(
+                    &request,
+                    None,
+                    time_config,
+                    lookup_data_signing_public_key,
+                    None,
+                    self.observer.clone(),
+                )?;
+                // Without a sealed state the enclave starts with the full privacy budget, as it
+                // can't tell a first launch from a host that withholds the state.
+                if !request.sealed_state.is_empty() {
+                    self.restore_sealed_state(&instance, &request.sealed_state)?;
+                }
+                let max_response_size = instance.max_response_size();
+                let constant_response_size = instance.constant_response_size();
+                let tenant_constant_response_sizes = instance.tenant_constant_response_sizes();
                 if self.instance.set(instance).is_err() {
                     return Err(micro_rpc::Status::new_with_message(
                         micro_rpc::StatusCode::FailedPrecondition,
@@ -102,7 +268,12 @@ where
                             format!("failed to convert evidence to proto: {err}"),
                         )
                     })?;
-                Ok(InitializeResponse { evidence: Some(evidence) })
+                Ok(InitializeResponse {
+                    evidence: Some(evidence),
+                    max_response_size,
+                    constant_response_size,
+                    tenant_constant_response_sizes,
+                })
             }
         }
     }
@@ -112,38 +283,7 @@ where
         request: InvokeRequest,
     ) -> Result<InvokeResponse, micro_rpc::Status> {
         log::debug!("called handle_user_request");
-        let encryption_key_handle = self.encryption_key_handle.clone();
-        let instance = self.get_instance()?;
-
-        let encrypted_request = request.encrypted_request.ok_or_else(|| {
-            micro_rpc::Status::new_with_message(
-                micro_rpc::StatusCode::InvalidArgument,
-                "InvokeRequest doesn't contain an encrypted request".to_string(),
-            )
-        })?;
-
-        EncryptionHandler::create(encryption_key_handle, |r| {
-            // Wrap the invocation result (which may be an Error) into a micro RPC Response
-            // wrapper protobuf, and encode that as bytes.
-            let response_result: Result<Vec<u8>, micro_rpc::Status> =
-                instance.handle_user_request(r);
-            let response: micro_rpc::ResponseWrapper = response_result.into();
-            response.encode_to_vec()
-        })
-        .invoke(&encrypted_request)
-        .map(
-            #[allow(clippy::needless_update)]
-            |encrypted_response| InvokeResponse {
-                encrypted_response: Some(encrypted_response),
-                ..Default::default()
-            },
-        )
-        .map_err(|err| {
-            micro_rpc::Status::new_with_message(
-                micro_rpc::StatusCode::Internal,
-                format!("couldn't call handle_user_request handler: {:?}", err),
-            )
-        })
+        self.invoke_encrypted(request, OakFunctionsInstance::handle_user_request)
     }
 
     fn extend_next_lookup_data(
@@ -179,10 +319,152 @@ where
     ) -> Result<FinishNextLookupDataResponse, micro_rpc::Status> {
         let instance = self.get_instance()?;
         instance.extend_lookup_data_chunk(request)?;
-        instance.finish_next_lookup_data(FinishNextLookupDataRequest {})
+        instance.finish_next_lookup_data(FinishNextLookupDataRequest::default())
     }
 
     fn reserve(&self, request: ReserveRequest) -> Result<ReserveResponse, micro_rpc::Status> {
         self.get_instance()?.reserve(request)
     }
+
+    fn reload_wasm(
+        &self,
+        request: ReloadWasmRequest,
+    ) -> Result<ReloadWasmResponse, micro_rpc::Status> {
+        log::debug!("called reload_wasm (Wasm module size: {} bytes)", request.wasm_module.len());
+        self.get_instance()?.reload_wasm(&request)
+    }
+
+    fn terminate(
+        &self,
+        _request: TerminateRequest,
+    ) -> Result<TerminateResponse, micro_rpc::Status> {
+        log::info!("called terminate");
+        self.terminate_requested.store(true, Ordering::Release);
+        Ok(TerminateResponse {})
+    }
+
+    fn extend_invocation(
+        &self,
+        request: ExtendInvocationRequest,
+    ) -> Result<ExtendInvocationResponse, micro_rpc::Status> {
+        self.get_instance()?;
+        self.invocations.extend(request.invocation_id, &request.chunk)?;
+        Ok(ExtendInvocationResponse {})
+    }
+
+    fn finish_invocation(
+        &self,
+        request: FinishInvocationRequest,
+    ) -> Result<FinishInvocationResponse, micro_rpc::Status> {
+        log::debug!("called finish_invocation");
+        let invocation_id = request.invocation_id;
+        let encrypted_request = self.invocations.take_request(invocation_id)?;
+        let response = EncryptedRequest::decode(encrypted_request.as_slice())
+            .map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::InvalidArgument,
+                    format!("couldn't decode encrypted request: {err}"),
+                )
+            })
+            .and_then(|encrypted_request| {
+                #[allow(clippy::needless_update)]
+                self.handle_user_request(InvokeRequest {
+                    encrypted_request: Some(encrypted_request),
+                    unix_time_seconds: request.unix_time_seconds,
+                    ..Default::default()
+                })
+            });
+        match response {
+            Ok(response) => {
+                let encrypted_response =
+                    response.encrypted_response.unwrap_or_default().encode_to_vec();
+                let response_size = encrypted_response.len() as u64;
+                self.invocations.set_response(invocation_id, encrypted_response);
+                Ok(FinishInvocationResponse { response_size, error_class: response.error_class })
+            }
+            Err(err) => {
+                self.invocations.abort(invocation_id);
+                Err(err)
+            }
+        }
+    }
+
+    fn read_invocation_response(
+        &self,
+        request: ReadInvocationResponseRequest,
+    ) -> Result<ReadInvocationResponseResponse, micro_rpc::Status> {
+        let (chunk, last) =
+            self.invocations.read(request.invocation_id, request.max_chunk_size as usize)?;
+        Ok(ReadInvocationResponseResponse { chunk, last })
+    }
+
+    fn abort_invocation(
+        &self,
+        request: AbortInvocationRequest,
+    ) -> Result<AbortInvocationResponse, micro_rpc::Status> {
+        self.invocations.abort(request.invocation_id);
+        Ok(AbortInvocationResponse {})
+    }
+
+    fn invoke_batch(&self, request: InvokeRequest) -> Result<InvokeResponse, micro_rpc::Status> {
+        log::debug!("called invoke_batch");
+        self.invoke_encrypted(request, OakFunctionsInstance::handle_user_request_batch)
+    }
+
+    fn provision_secrets(
+        &self,
+        request: ProvisionSecretsRequest,
+    ) -> Result<ProvisionSecretsResponse, micro_rpc::Status> {
+        log::debug!("called provision_secrets");
+        let instance = self.get_instance()?;
+        let encrypted_secrets = request.encrypted_secrets.ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                "ProvisionSecretsRequest doesn't contain encrypted secrets",
+            )
+        })?;
+        let (_, secrets, _) =
+            ServerEncryptor::decrypt(&encrypted_secrets, self.encryption_key_handle.as_ref())
+                .map_err(|err| {
+                    micro_rpc::Status::new_with_message(
+                        micro_rpc::StatusCode::InvalidArgument,
+                        format!("couldn't decrypt secrets: {:?}", err),
+                    )
+                })?;
+        instance.provision_secrets(&secrets)
+    }
+
+    fn stream_lookup_index(
+        &self,
+        _request: LookupIndexChunk,
+    ) -> Result<FinishNextLookupDataResponse, micro_rpc::Status> {
+        // Lookup indexes are used in place, which requires mapping them from a file.
+        Err(micro_rpc::Status::new_with_message(
+            micro_rpc::StatusCode::Unimplemented,
+            "lookup indexes are only supported on Oak Containers",
+        ))
+    }
+
+    fn get_precompiled_wasm_module(
+        &self,
+        request: GetPrecompiledWasmModuleRequest,
+    ) -> Result<GetPrecompiledWasmModuleResponse, micro_rpc::Status> {
+        log::debug!("called get_precompiled_wasm_module");
+        self.get_instance()?.get_precompiled_wasm_module(&request)
+    }
+
+    fn get_sealed_state(
+        &self,
+        _request: GetSealedStateRequest,
+    ) -> Result<GetSealedStateResponse, micro_rpc::Status> {
+        log::debug!("called get_sealed_state");
+        let state = self.get_instance()?.persistent_state();
+        let sealed_state = self.get_sealer()?.seal(&state.encode_to_vec()).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::Internal,
+                format!("couldn't seal state: {:?}", err),
+            )
+        })?;
+        Ok(GetSealedStateResponse { sealed_state })
+    }
 }
@@ -27,12 +27,16 @@ use oak_client::verifier::extract_encryption_public_key;
 use oak_crypto::{encryptor::ClientEncryptor, proto::oak::crypto::v1::EncryptedRequest};
 use oak_functions_enclave_service::{
     proto::oak::functions::{
-        ExtendNextLookupDataRequest, FinishNextLookupDataRequest, InitializeRequest, InvokeRequest,
-        LookupDataChunk, LookupDataEntry, OakFunctionsClient, OakFunctionsServer,
+        config::ApplicationConfig, ExtendNextLookupDataRequest, FinishNextLookupDataRequest,
+        GetSealedStateRequest, InitializeRequest, InvokeRequest, LookupDataChunk, LookupDataEntry,
+        OakFunctionsClient, OakFunctionsServer, Tenant,
     },
     OakFunctionsService,
 };
-use oak_proto_rust::oak::oak_functions::testing::{EchoAndPanicRequest, TestModuleClient};
+use oak_proto_rust::oak::oak_functions::{
+    abi::TenantRequest,
+    testing::{EchoAndPanicRequest, TestModuleClient},
+};
 use prost::Message;
 
 const MOCK_CONSTANT_RESPONSE_SIZE: u32 = 1024;
@@ -58,6 +62,7 @@ fn new_service_for_testing() -> OakFunctionsService<
                 .expect("failed to create EncryptionKeyHandle"),
         ),
         None,
+        Some(Arc::new(oak_restricted_kernel_sdk::testing::MockSealer)),
     )
 }
 
@@ -80,6 +85,67 @@ fn it_should_not_handle_user_requests_before_initialization() {
     );
 }
 
+#[test]
+fn it_should_restore_sealed_state() {
+    init();
+    let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("echo").unwrap();
+    let request = InitializeRequest {
+        wasm_module: std::fs::read(wasm_path).unwrap(),
+        constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        privacy_budget_epsilon: 1.0,
+        ..Default::default()
+    };
+
+    let mut client = OakFunctionsClient::new(OakFunctionsServer::new(new_service_for_testing()));
+    client.initialize(&request).into_ok().unwrap();
+    let sealed_state =
+        client.get_sealed_state(&GetSealedStateRequest {}).into_ok().unwrap().sealed_state;
+
+    // The next enclave starts from the sealed state.
+    let mut client = OakFunctionsClient::new(OakFunctionsServer::new(new_service_for_testing()));
+    let result =
+        client.initialize(&InitializeRequest { sealed_state, ..request.clone() }).into_ok();
+    assert!(result.is_ok());
+
+    // But not from state it can't unseal.
+    let mut client = OakFunctionsClient::new(OakFunctionsServer::new(new_service_for_testing()));
+    let result = client
+        .initialize(&InitializeRequest { sealed_state: b"not sealed".to_vec(), ..request })
+        .into_ok();
+    assert_matches!(
+        result,
+        Err(micro_rpc::Status { code: micro_rpc::StatusCode::InvalidArgument, .. })
+    );
+}
+
+#[test]
+fn it_should_reject_application_config_that_was_not_measured() {
+    init();
+    let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("echo").unwrap();
+    // The mock evidence doesn't measure an application config.
+    let request = InitializeRequest {
+        wasm_module: std::fs::read(wasm_path).unwrap(),
+        constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        application_config: ApplicationConfig { time_granularity_seconds: 1, ..Default::default() }
+            .encode_to_vec(),
+        ..Default::default()
+    };
+
+    let mut client = OakFunctionsClient::new(OakFunctionsServer::new(new_service_for_testing()));
+    let result = client.initialize(&request).into_ok();
+    assert_matches!(
+        result,
+        Err(micro_rpc::Status { code: micro_rpc::StatusCode::InvalidArgument, .. })
+    );
+
+    // Without a config, it initializes.
+    let mut client = OakFunctionsClient::new(OakFunctionsServer::new(new_service_for_testing()));
+    let result = client
+        .initialize(&InitializeRequest { application_config: Vec::new(), ..request })
+        .into_ok();
+    assert!(result.is_ok());
+}
+
 #[test]
 fn it_should_handle_user_requests_after_initialization() {
     init();
@@ -91,6 +157,7 @@ fn it_should_handle_user_requests_after_initialization() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -125,6 +192,7 @@ fn it_should_only_initialize_once() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
     client.initialize(&request).into_ok().unwrap();
 
@@ -147,6 +215,7 @@ fn it_should_error_on_invalid_wasm_module() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -212,6 +281,7 @@ fn it_should_support_lookup_data() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
@@ -227,10 +297,10 @@ fn it_should_support_lookup_data() {
         }],
     };
 
-    let request = ExtendNextLookupDataRequest { chunk: Some(chunk) };
+    let request = ExtendNextLookupDataRequest { chunk: Some(chunk), ..Default::default() };
 
     client.extend_next_lookup_data(&request).into_ok().unwrap();
-    client.finish_next_lookup_data(&FinishNextLookupDataRequest {}).into_ok().unwrap();
+    client.finish_next_lookup_data(&FinishNextLookupDataRequest::default()).into_ok().unwrap();
 
     // TODO(#4274): Deduplicate this logic with Oak Client library.
 
@@ -271,6 +341,83 @@ fn it_should_support_lookup_data() {
     assert_eq!(LOOKUP_TEST_VALUE, response_result.unwrap());
 }
 
+#[test]
+fn it_should_route_requests_to_tenants() {
+    init();
+    let service = new_service_for_testing();
+    let mut client = OakFunctionsClient::new(OakFunctionsServer::new(service));
+
+    let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup").unwrap();
+    let wasm_bytes = std::fs::read(wasm_path).unwrap();
+    let tenant =
+        Tenant { wasm_module: wasm_bytes, constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE };
+    let request = InitializeRequest {
+        tenants: [("a".to_string(), tenant.clone()), ("b".to_string(), tenant)].into(),
+        ..Default::default()
+    };
+
+    let initialize_response = client.initialize(&request).into_ok().unwrap();
+    assert_eq!(initialize_response.constant_response_size, 0);
+    assert_eq!(
+        initialize_response.tenant_constant_response_sizes,
+        [
+            ("a".to_string(), MOCK_CONSTANT_RESPONSE_SIZE),
+            ("b".to_string(), MOCK_CONSTANT_RESPONSE_SIZE)
+        ]
+        .into()
+    );
+    let evidence =
+        initialize_response.evidence.expect("initialize response doesn't have public key info");
+    let server_encryption_public_key =
+        extract_encryption_public_key(&evidence).expect("couldn't extract encryption public key");
+
+    // Every tenant only sees the entries under its own prefix.
+    let chunk = LookupDataChunk {
+        items: vec![
+            LookupDataEntry {
+                key: b"a/test_key".as_slice().into(),
+                value: b"value_a".as_slice().into(),
+            },
+            LookupDataEntry {
+                key: b"b/test_key".as_slice().into(),
+                value: b"value_b".as_slice().into(),
+            },
+        ],
+    };
+    let request = ExtendNextLookupDataRequest { chunk: Some(chunk), ..Default::default() };
+    client.extend_next_lookup_data(&request).into_ok().unwrap();
+    client.finish_next_lookup_data(&FinishNextLookupDataRequest::default()).into_ok().unwrap();
+
+    let mut invoke = |tenant_id: &str| -> Result<Vec<u8>, micro_rpc::Status> {
+        let request =
+            TenantRequest { tenant_id: tenant_id.to_string(), body: LOOKUP_TEST_KEY.to_vec() };
+        let mut client_encryptor = ClientEncryptor::create(&server_encryption_public_key)
+            .expect("couldn't create encryptor");
+        let encrypted_request = client_encryptor
+            .encrypt(&request.encode_to_vec(), EMPTY_ASSOCIATED_DATA)
+            .expect("couldn't encrypt request");
+        let encrypted_response = client
+            .handle_user_request(
+                #[allow(clippy::needless_update)]
+                &InvokeRequest { encrypted_request: Some(encrypted_request), ..Default::default() },
+            )
+            .expect("couldn't receive response")
+            .unwrap()
+            .encrypted_response
+            .expect("no encrypted response provided");
+        let (response_bytes, _) = client_encryptor
+            .decrypt(&encrypted_response)
+            .expect("client couldn't decrypt response");
+        micro_rpc::ResponseWrapper::decode(response_bytes.as_slice())
+            .expect("couldn't deserialize response wrapper")
+            .into()
+    };
+
+    assert_eq!(invoke("a").unwrap(), b"value_a");
+    assert_eq!(invoke("b").unwrap(), b"value_b");
+    assert_eq!(invoke("c").unwrap_err().code, micro_rpc::StatusCode::NotFound);
+}
+
 #[test]
 fn it_should_handle_wasm_panic() {
     init();
@@ -285,6 +432,7 @@ fn it_should_handle_wasm_panic() {
     let request = InitializeRequest {
         wasm_module: wasm_bytes,
         constant_response_size: MOCK_CONSTANT_RESPONSE_SIZE,
+        ..Default::default()
     };
 
     let initialize_response = client.initialize(&request).into_ok().unwrap();
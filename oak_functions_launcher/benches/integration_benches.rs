@@ -19,13 +19,13 @@
 
 extern crate test;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use oak_client::verifier::extract_encryption_public_key;
 use oak_crypto::encryptor::ClientEncryptor;
 use oak_functions_launcher::{
     proto::oak::functions::{InvokeRequest, OakFunctionsAsyncClient},
-    LookupDataConfig,
+    LookupDataConfig, LookupSource, WasmLimits,
 };
 use oak_launcher_utils::launcher;
 use prost::Message;
@@ -62,7 +62,15 @@ fn run_bench(b: &mut Bencher, config: &OakFunctionsTestConfig) {
     let params = launcher::Params {
         kernel: xtask::launcher::OAK_RESTRICTED_KERNEL_WRAPPER_BIN.clone(),
         vmm_binary: which::which("qemu-system-x86_64").unwrap(),
+        vmm: oak_launcher_utils::vmm::VmmType::Qemu,
+        sev_snp: false,
+        snp: Default::default(),
+        console_log: Default::default(),
+        channel: Default::default(),
         app_binary: Some(oak_functions_enclave_app_path.into()),
+        app_bundle: Vec::new(),
+        app_selector: None,
+        application_config: Vec::new(),
         bios_binary: workspace_path(&[
             "stage0_bin",
             "target",
@@ -73,6 +81,11 @@ fn run_bench(b: &mut Bencher, config: &OakFunctionsTestConfig) {
         gdb: None,
         initrd: oak_restricted_kernel_orchestrator_app_path.into(),
         memory_size: Some("256M".to_string()),
+        num_cpus: 1,
+        cpu_model: launcher::DEFAULT_CPU_MODEL.to_string(),
+        memory_balloon: false,
+        numa_nodes: 1,
+        kernel_args: Vec::new(),
     };
     log::debug!("launcher params: {:?}", params);
 
@@ -80,17 +93,29 @@ fn run_bench(b: &mut Bencher, config: &OakFunctionsTestConfig) {
     let constant_response_size: u32 = 1024;
 
     let lookup_data_config = LookupDataConfig {
-        lookup_data_path: config.lookup_data_path.to_path_buf(),
+        lookup_data_source: LookupSource::File(config.lookup_data_path.to_path_buf()),
         update_interval: None,
         max_chunk_size: ByteUnit::Gibibyte(2),
+        encrypted: false,
+        signing: None,
+        sharded: false,
+        ordered_index: false,
+        memory_budget: None,
+        max_retries: 0,
+        retry_backoff: Duration::ZERO,
     };
 
-    let (launched_instance, connector_handle, initialize_response) = runtime
+    let (launched_instance, connector_handle, initialize_response, _) = runtime
         .block_on(oak_functions_launcher::create(
             params,
             lookup_data_config,
             config.wasm_path.to_path_buf(),
-            constant_response_size,
+            Some(constant_response_size),
+            WasmLimits::default(),
+            None,
+            None,
+            None,
+            None,
         ))
         .expect("Failed to create launcher");
     log::info!("created launcher instance");
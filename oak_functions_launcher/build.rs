@@ -14,19 +14,37 @@
 // limitations under the License.
 //
 
+use std::{env, path::PathBuf};
+
 use oak_grpc_utils::{generate_grpc_code, CodegenOptions};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Generate gRPC code for exchanging messages with clients.
+    // Generate gRPC code for exchanging messages with clients, along with the
+    // descriptors served through gRPC server reflection.
     generate_grpc_code(
-        &["../proto/session/messages.proto", "../proto/session/service_streaming.proto"],
+        &[
+            "../proto/session/messages.proto",
+            "../proto/session/service_streaming.proto",
+            "../proto/session/relay.proto",
+        ],
         "..",
-        CodegenOptions { build_server: true, ..Default::default() },
+        CodegenOptions {
+            // The client is for dialing out to a relay.
+            build_client: true,
+            build_server: true,
+            file_descriptor_set_path: Some(
+                PathBuf::from(env::var("OUT_DIR")?).join("session_descriptor.bin"),
+            ),
+            ..Default::default()
+        },
     )?;
 
     // Generate micro RPC code for exchanging messages with the enclave.
     micro_rpc_build::compile(
-        &["../proto/oak_functions/service/oak_functions.proto"],
+        &[
+            "../proto/oak_functions/application_config.proto",
+            "../proto/oak_functions/service/oak_functions.proto",
+        ],
         &[".."],
         Default::default(),
     );
@@ -0,0 +1,265 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! HTTP server reporting the health of the launcher, so that orchestrators
+//! such as Kubernetes can gate traffic on it.
+//!
+//! * `/healthz` (liveness) succeeds for as long as the launcher is running.
+//! * `/readyz` (readiness) succeeds once the Wasm module is initialized and the
+//!   lookup data has been loaded, and fails with 503 until then. When several
+//!   replicas are run, it also fails while none of them is healthy.
+//!
+//! Both endpoints respond with a JSON [`Report`] that also describes the most
+//! recent lookup data refresh and the age of the attestation evidence.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::LookupDataUpdate;
+
+/// Health of the launcher, updated as the enclave is set up.
+#[derive(Default)]
+pub struct HealthState {
+    inner: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    wasm_initialized: bool,
+    lookup_data_loaded: bool,
+    healthy_replicas: Option<usize>,
+    evidence_obtained_at: Option<SystemTime>,
+    last_lookup_data_update: Option<(SystemTime, LookupDataUpdate)>,
+}
+
+/// The JSON body returned by the health endpoints.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub live: bool,
+    pub ready: bool,
+    pub wasm_initialized: bool,
+    pub lookup_data_loaded: bool,
+    /// Number of replicas currently serving, if several replicas are run.
+    pub healthy_replicas: Option<usize>,
+    /// Seconds since the attestation evidence was obtained from the enclave.
+    pub evidence_age_secs: Option<u64>,
+    pub last_lookup_data_update: Option<LookupDataUpdateReport>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LookupDataUpdateReport {
+    /// When the refresh completed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub changed: bool,
+    pub duration_ms: u128,
+}
+
+impl HealthState {
+    /// Records that the enclave accepted the Wasm module.
+    pub fn set_wasm_initialized(&self) {
+        self.inner.lock().unwrap().wasm_initialized = true;
+    }
+
+    /// Records that a replica became healthy and is served.
+    pub fn add_healthy_replica(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.healthy_replicas = Some(state.healthy_replicas.unwrap_or_default() + 1);
+    }
+
+    /// Records that a replica added by [`HealthState::add_healthy_replica`]
+    /// stopped being served.
+    pub fn remove_healthy_replica(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.healthy_replicas = state.healthy_replicas.map(|count| count.saturating_sub(1));
+    }
+
+    /// Records that attestation evidence was just obtained from the enclave.
+    pub fn set_evidence_obtained(&self) {
+        self.inner.lock().unwrap().evidence_obtained_at = Some(SystemTime::now());
+    }
+
+    /// Records the outcome of a lookup data refresh. Once a refresh has
+    /// succeeded the lookup data counts as loaded, even if later refreshes
+    /// fail, as the enclave keeps serving the previous data.
+    pub fn record_lookup_data_update(&self, update: &LookupDataUpdate) {
+        let mut state = self.inner.lock().unwrap();
+        state.lookup_data_loaded |= update.result.is_ok();
+        state.last_lookup_data_update = Some((SystemTime::now(), update.clone()));
+    }
+
+    /// Spawns a task that records every lookup data refresh reported by
+    /// `updates`, including the current one. Abort the task once the enclave
+    /// sending the updates is gone.
+    pub fn watch_lookup_data(
+        self: &Arc<Self>,
+        mut updates: watch::Receiver<LookupDataUpdate>,
+    ) -> JoinHandle<()> {
+        let state = self.clone();
+        tokio::spawn(async move {
+            state.record_lookup_data_update(&updates.borrow_and_update());
+            while updates.changed().await.is_ok() {
+                state.record_lookup_data_update(&updates.borrow_and_update());
+            }
+        })
+    }
+
+    pub fn report(&self) -> Report {
+        let state = self.inner.lock().unwrap();
+        Report {
+            live: true,
+            ready: state.wasm_initialized
+                && state.lookup_data_loaded
+                && state.healthy_replicas != Some(0),
+            wasm_initialized: state.wasm_initialized,
+            lookup_data_loaded: state.lookup_data_loaded,
+            healthy_replicas: state.healthy_replicas,
+            evidence_age_secs: state
+                .evidence_obtained_at
+                .map(|time| time.elapsed().unwrap_or_default().as_secs()),
+            last_lookup_data_update: state.last_lookup_data_update.as_ref().map(
+                |(time, update)| LookupDataUpdateReport {
+                    timestamp: time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                    succeeded: update.result.is_ok(),
+                    error: update.result.as_ref().err().map(|err| format!("{:#}", err)),
+                    attempts: update.attempts,
+                    changed: update.changed,
+                    duration_ms: update.duration.as_millis(),
+                },
+            ),
+        }
+    }
+}
+
+/// Serves the health endpoints on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, state: Arc<HealthState>) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = handle(&state, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    log::info!("health server listening on {}", addr);
+    server.await
+}
+
+fn handle(state: &HealthState, request: &Request<Body>) -> Response<Body> {
+    let report = state.report();
+    let status = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => StatusCode::OK,
+        (&Method::GET, "/readyz") if report.ready => StatusCode::OK,
+        (&Method::GET, "/readyz") => StatusCode::SERVICE_UNAVAILABLE,
+        _ => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+    };
+    let body = serde_json::to_vec(&report).expect("couldn't serialize health report");
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[test]
+fn test_readiness() {
+    let state = HealthState::default();
+    assert!(state.report().live);
+    assert!(!state.report().ready);
+
+    state.set_wasm_initialized();
+    assert!(!state.report().ready);
+
+    let update = LookupDataUpdate {
+        attempts: 1,
+        changed: true,
+        duration: std::time::Duration::from_millis(10),
+        result: Ok(()),
+        memory_usage: None,
+    };
+    state.record_lookup_data_update(&update);
+    assert!(state.report().ready);
+
+    // A failed refresh doesn't unload the lookup data that is already loaded.
+    let update = LookupDataUpdate {
+        attempts: 4,
+        changed: false,
+        duration: std::time::Duration::from_millis(10),
+        result: Err(Arc::new(anyhow::anyhow!("unavailable"))),
+        memory_usage: None,
+    };
+    state.record_lookup_data_update(&update);
+    let report = state.report();
+    assert!(report.ready);
+    let last_update = report.last_lookup_data_update.unwrap();
+    assert!(!last_update.succeeded);
+    assert_eq!(last_update.error.as_deref(), Some("unavailable"));
+    assert_eq!(last_update.attempts, 4);
+}
+
+#[test]
+fn test_readiness_follows_healthy_replicas() {
+    let state = HealthState::default();
+    state.set_wasm_initialized();
+    state.record_lookup_data_update(&LookupDataUpdate {
+        attempts: 1,
+        changed: true,
+        duration: std::time::Duration::from_millis(10),
+        result: Ok(()),
+        memory_usage: None,
+    });
+    assert!(state.report().ready);
+
+    state.add_healthy_replica();
+    state.add_healthy_replica();
+    assert_eq!(state.report().healthy_replicas, Some(2));
+    assert!(state.report().ready);
+
+    state.remove_healthy_replica();
+    assert!(state.report().ready);
+    state.remove_healthy_replica();
+    assert_eq!(state.report().healthy_replicas, Some(0));
+    assert!(!state.report().ready);
+}
+
+#[test]
+fn test_endpoints() {
+    let state = HealthState::default();
+    let get =
+        |path: &str| handle(&state, &Request::get(path).body(Body::empty()).unwrap()).status();
+    assert_eq!(get("/healthz"), StatusCode::OK);
+    assert_eq!(get("/readyz"), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(get("/unknown"), StatusCode::NOT_FOUND);
+}
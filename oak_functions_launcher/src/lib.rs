@@ -18,13 +18,24 @@
 #![feature(result_flattening)]
 #![feature(array_chunks)]
 
+pub mod health;
 mod lookup;
+pub mod lookup_source;
+pub mod metrics;
+pub mod replicated;
+pub mod runner;
+pub mod secret_provisioning;
 pub mod server;
+pub mod tls;
+pub mod validate;
 
 pub mod proto {
     pub mod oak {
         pub mod functions {
             #![allow(dead_code)]
+            pub mod config {
+                include!(concat!(env!("OUT_DIR"), "/oak.functions.config.rs"));
+            }
             use prost::Message;
             include!(concat!(env!("OUT_DIR"), "/oak.functions.rs"));
         }
@@ -35,49 +46,389 @@ pub mod proto {
                 #![allow(clippy::return_self_not_must_use)]
                 #![allow(clippy::large_enum_variant)]
                 tonic::include_proto!("oak.session.v1");
+
+                /// Descriptors of the session protos, for gRPC server
+                /// reflection.
+                pub const FILE_DESCRIPTOR_SET: &[u8] =
+                    tonic::include_file_descriptor_set!("session_descriptor");
             }
         }
     }
 }
 
-use std::{fs, path::PathBuf, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use clap::Parser;
+use oak_attestation_verification::{
+    policy::{appraise, parse_policy_json},
+    verifier::verify_dice_chain,
+};
 use oak_launcher_utils::{
     channel::{self, ConnectorHandle},
+    error::LauncherError,
     launcher,
 };
+use prost::Message;
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::AbortHandle,
+};
 use ubyte::ByteUnit;
 
-use crate::proto::oak::functions::{
-    InitializeRequest, InitializeResponse, OakFunctionsAsyncClient,
+pub use crate::{lookup::MemoryBudgetExceeded, lookup_source::LookupSource};
+use crate::{
+    proto::oak::{
+        attestation::v1::{AppraisalPolicy, Endorsements, Evidence},
+        functions::{
+            FinishNextLookupDataResponse, GetSealedStateRequest, InitializeRequest,
+            InitializeResponse, LookupDataManifest, LookupDataMemoryUsage, LookupDataSignature,
+            OakFunctionsAsyncClient, ReloadWasmRequest, TerminateRequest, WasmEngine,
+        },
+    },
+    secret_provisioning::{HttpKeyManagementService, SecretProvisioner},
 };
 
 #[derive(Parser, Debug)]
 #[group(skip)]
 pub struct Args {
-    /// Consistent response size that the enclave should apply
-    #[arg(long, default_value = "1024")]
-    pub constant_response_size: u32,
+    /// Consistent response size that the enclave should apply. If not set, the
+    /// maximum response size declared by the Wasm module is rounded up to the
+    /// next power of two.
+    #[arg(long)]
+    pub constant_response_size: Option<u32>,
 
     #[arg(long, default_value = "8080")]
     pub port: u16,
 
+    /// Port of the HTTP server reporting liveness (`/healthz`) and readiness
+    /// (`/readyz`) of the launcher. The health server is disabled if not set.
+    #[arg(long)]
+    pub health_port: Option<u16>,
+
+    /// Port of the HTTP server exporting Prometheus metrics on `/metrics`. The
+    /// metrics server is disabled if not set.
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+
     /// Path to a Wasm file to be loaded into the enclave and executed by it per invocation. See the documentation for details on its ABI. Ref: <https://github.com/project-oak/oak/blob/main/docs/oak_functions_abi.md>
+    ///
+    /// Required, unless the launcher supports serving several tenants and is
+    /// given their modules instead.
+    #[arg(
+            long,
+            value_parser = path_exists,
+        )]
+    pub wasm: Option<PathBuf>,
+
+    /// Location of the key / value entries in protobuf binary format for
+    /// lookup: either a path to a local file, an HTTP(S) URL or a GCS
+    /// object (`gs://bucket/object`). Remote lookup data is only downloaded
+    /// again if it changed.
+    #[arg(
+            long,
+            value_parser = lookup_source_exists,
+        )]
+    pub lookup_data: LookupSource,
+
+    /// Interval in seconds between periodic refreshes of the lookup data.
+    /// Periodic refreshes are disabled if set to 0.
+    #[arg(long, default_value = "600")]
+    pub lookup_data_update_interval: u64,
+
+    /// How many times a failed refresh of the lookup data is retried before
+    /// giving up until the next refresh.
+    #[arg(long, default_value = "3")]
+    pub lookup_data_max_retries: u32,
+
+    /// Path to a JSON appraisal policy that the enclave applies to the
+    /// evidence sent by its peers with each request, e.g. other enclaves.
+    /// Requests without evidence that satisfies the policy are rejected. The
+    /// enclave doesn't require evidence from its peers if not set.
+    #[arg(
+            long,
+            value_parser = path_exists,
+        )]
+    pub peer_attestation_policy: Option<PathBuf>,
+
+    /// Whether the enclave builds an ordered index of the lookup data, so
+    /// that Wasm modules can look up ranges of keys and keys with a prefix.
+    /// The index costs another 5 bytes per entry in the enclave.
+    #[arg(long)]
+    pub lookup_data_ordered_index: bool,
+
+    /// The maximum memory that the enclave may use for lookup data, e.g.
+    /// `4GiB`. As the enclave holds its active lookup data while it loads the
+    /// next lookup data, the budget must fit both. Lookup data that would
+    /// exceed it is rejected without being retried, and the enclave keeps
+    /// serving its active lookup data. Unlimited if not set.
+    #[arg(long, value_parser = parse_byte_unit)]
+    pub lookup_data_memory_budget: Option<ByteUnit>,
+
+    /// The fuel that every invocation of the Wasm module may consume, where
+    /// every Wasm instruction consumes about one unit. Invocations that run
+    /// out of fuel, e.g. because they loop forever, fail with
+    /// `ExecutionLimitExceeded` instead of blocking their thread. Metering
+    /// fuel slows down execution. Unlimited if not set.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub wasm_fuel_limit: Option<u64>,
+
+    /// The maximum size of the linear memory of the Wasm module, e.g.
+    /// `64MiB`. Wasm modules whose initial memory exceeds it are rejected, and
+    /// invocations that fail as the module can't grow its memory beyond it
+    /// fail with `MemoryLimitExceeded`. Unlimited if not set.
+    #[arg(long, value_parser = parse_byte_unit)]
+    pub wasm_memory_limit: Option<ByteUnit>,
+
+    /// Whether the linear memory of the Wasm module must keep its initial
+    /// size, which the module then has to declare large enough for all
+    /// requests.
+    #[arg(long)]
+    pub wasm_memory_growth_disabled: bool,
+
+    /// The differential privacy budget (epsilon) that the Wasm module consumes
+    /// across requests with `consume_budget`. Once it is exhausted, requests
+    /// fail with `PrivacyBudgetExhausted`. The budget is reset whenever the
+    /// enclave is relaunched, unless it is kept in `sealed_state`. No budget if
+    /// not set. Not supported with several replicas, as each would consume its
+    /// own budget.
+    #[arg(long, value_parser = parse_privacy_budget)]
+    pub privacy_budget_epsilon: Option<f64>,
+
+    /// The granularity in seconds of the time that the Wasm module reads with
+    /// `read_time`. It is part of the application config, which is measured
+    /// into the attestation evidence. The Wasm module can't read the time if
+    /// not set.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub time_granularity_seconds: Option<u64>,
+
+    #[clap(flatten)]
+    pub validate: validate::ValidateParams,
+}
+
+/// Options of the Restricted Kernel flavor of the launcher that the Oak
+/// Containers flavor doesn't support, in addition to [`Args`].
+#[derive(Parser, Debug)]
+#[group(skip)]
+pub struct RestrictedKernelArgs {
+    /// Port of the HTTP gateway that tunnels the encrypted session protocol
+    /// over HTTP POST and WebSocket, for clients that can't use gRPC such as
+    /// web pages. The gateway is disabled if not set.
+    #[arg(long)]
+    pub gateway_port: Option<u16>,
+
+    #[clap(flatten)]
+    pub tls: tls::TlsParams,
+
+    #[clap(flatten)]
+    pub rate_limit: server::rate_limit::RateLimitParams,
+
+    #[clap(flatten)]
+    pub size_limits: server::SizeLimits,
+
+    #[clap(flatten)]
+    pub resumption: server::resumption::ResumptionParams,
+
+    #[clap(flatten)]
+    pub relay: server::relay::RelayParams,
+
+    /// Number of enclave replicas to run behind the endpoint. Client sessions
+    /// are spread across the healthy replicas, and replicas that crash or stop
+    /// responding are relaunched.
+    #[arg(long, default_value = "1")]
+    pub replicas: usize,
+
+    /// Restart the enclave, with exponential backoff, if its VMM exits
+    /// unexpectedly, giving up after this many restarts. The launcher exits
+    /// with the enclave if not set.
+    #[arg(long, conflicts_with = "replicas")]
+    pub max_restarts: Option<u32>,
+
+    /// Path to a JSON appraisal policy that the evidence of the enclave must
+    /// satisfy before the enclave is served, e.g. to refuse to serve an enclave
+    /// in debug mode or on an outdated platform.
     #[arg(
             long,
             value_parser = path_exists,
         )]
-    pub wasm: PathBuf,
+    pub attestation_policy: Option<PathBuf>,
+
+    /// URL of a key management service that releases the secrets of the
+    /// enclave, wrapped for its encryption key, once the enclave's evidence
+    /// satisfies the attestation policy. The launcher POSTs the serialized
+    /// evidence, and delivers the returned secrets to the enclave before
+    /// serving it.
+    #[arg(long, requires = "attestation_policy")]
+    pub secret_provisioning_url: Option<String>,
+
+    /// Whether the lookup data is a sequence of length-delimited
+    /// `EncryptedLookupDataChunk` messages, which only the enclave can decrypt
+    /// with the `lookup_data_key` secret provisioned by the key management
+    /// service.
+    #[arg(long, requires = "secret_provisioning_url")]
+    pub lookup_data_encrypted: bool,
+
+    /// Location of the detached signature of the lookup data, a serialized
+    /// `oak.functions.LookupDataSignature`, in the same forms as
+    /// `--lookup-data`. It is fetched again with every refresh of the lookup
+    /// data.
+    #[arg(
+            long,
+            requires = "lookup_data_signing_key",
+            value_parser = lookup_source_exists,
+        )]
+    pub lookup_data_signature: Option<LookupSource>,
 
-    /// Path to a file containing key / value entries in protobuf binary format
-    /// for lookup.
+    /// Path to the raw 32-byte Ed25519 public key that the enclave verifies the
+    /// signature of the lookup data with. The enclave never activates lookup
+    /// data that isn't signed with this key, or that is older than its active
+    /// lookup data.
     #[arg(
             long,
+            requires = "lookup_data_signature",
             value_parser = path_exists,
         )]
-    pub lookup_data: PathBuf,
+    pub lookup_data_signing_key: Option<PathBuf>,
+
+    /// Whether the lookup data is a serialized
+    /// `oak.functions.LookupDataManifest` of shards rather than the lookup
+    /// data itself. When the lookup data is refreshed, only the shards
+    /// whose digests changed are sent to the enclave.
+    #[arg(long, conflicts_with = "lookup_data_signature")]
+    pub lookup_data_sharded: bool,
+
+    /// File in which the launcher keeps the state of the enclave across
+    /// relaunches, such as the consumed privacy budget. The enclave seals the
+    /// state, so that only the same enclave application can read it. Every
+    /// launched enclave starts from the state in the file, if it exists, and
+    /// the state is saved when the launcher shuts the enclave down.
+    #[arg(long, conflicts_with = "replicas")]
+    pub sealed_state: Option<PathBuf>,
+}
+
+/// Limits on every invocation of the Wasm module, see the corresponding
+/// [`Args`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WasmLimits {
+    pub fuel_limit: Option<u64>,
+    pub memory_limit: Option<ByteUnit>,
+    pub memory_growth_disabled: bool,
+}
+
+impl Args {
+    /// Returns the interval between periodic refreshes of the lookup data, if
+    /// enabled.
+    pub fn lookup_data_update_interval(&self) -> Option<Duration> {
+        (self.lookup_data_update_interval > 0)
+            .then(|| Duration::from_secs(self.lookup_data_update_interval))
+    }
+
+    /// Returns the configuration of plain lookup data, which is sent to the
+    /// enclave in chunks of at most `max_chunk_size`.
+    pub fn lookup_data_config(&self, max_chunk_size: ByteUnit) -> LookupDataConfig {
+        LookupDataConfig {
+            update_interval: self.lookup_data_update_interval(),
+            lookup_data_source: self.lookup_data.clone(),
+            max_chunk_size,
+            encrypted: false,
+            signing: None,
+            sharded: false,
+            ordered_index: self.lookup_data_ordered_index,
+            memory_budget: self.lookup_data_memory_budget,
+            max_retries: self.lookup_data_max_retries,
+            retry_backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Returns the limits on every invocation of the Wasm module.
+    pub fn wasm_limits(&self) -> WasmLimits {
+        WasmLimits {
+            fuel_limit: self.wasm_fuel_limit,
+            memory_limit: self.wasm_memory_limit,
+            memory_growth_disabled: self.wasm_memory_growth_disabled,
+        }
+    }
+
+    /// Loads the appraisal policy that the enclave applies to the evidence of
+    /// its peers, if set.
+    pub fn peer_attestation_policy(&self) -> anyhow::Result<Option<AppraisalPolicy>> {
+        let Some(path) = self.peer_attestation_policy.as_ref() else {
+            return Ok(None);
+        };
+        let json = fs::read(path)
+            .with_context(|| format!("couldn't read peer attestation policy {}", path.display()))?;
+        parse_policy_json(&json).map(Some)
+    }
+}
+
+impl RestrictedKernelArgs {
+    /// Returns the configuration of the lookup data, which is sent to the
+    /// enclave in chunks of at most `max_chunk_size`, taking into account
+    /// whether it is encrypted, signed or sharded.
+    pub fn lookup_data_config(
+        &self,
+        args: &Args,
+        max_chunk_size: ByteUnit,
+    ) -> anyhow::Result<LookupDataConfig> {
+        Ok(LookupDataConfig {
+            encrypted: self.lookup_data_encrypted,
+            signing: self.lookup_data_signing()?,
+            sharded: self.lookup_data_sharded,
+            ..args.lookup_data_config(max_chunk_size)
+        })
+    }
+
+    /// Loads the appraisal policy for the evidence of the enclave, if set.
+    pub fn attestation_policy(&self) -> anyhow::Result<Option<AppraisalPolicy>> {
+        let Some(path) = self.attestation_policy.as_ref() else {
+            return Ok(None);
+        };
+        let json = fs::read(path)
+            .with_context(|| format!("couldn't read attestation policy {}", path.display()))?;
+        let policy = parse_policy_json(&json)?;
+        // The launcher doesn't have endorsements of the enclave to check.
+        anyhow::ensure!(
+            policy.required_endorsement_claim_types.is_empty(),
+            "the launcher can't check required endorsement claim types"
+        );
+        Ok(Some(policy))
+    }
+
+    /// Returns where the signature of the lookup data is loaded from and the
+    /// key it is verified with, if the lookup data is signed.
+    pub fn lookup_data_signing(&self) -> anyhow::Result<Option<LookupDataSigning>> {
+        let (Some(signature_source), Some(path)) =
+            (self.lookup_data_signature.as_ref(), self.lookup_data_signing_key.as_ref())
+        else {
+            return Ok(None);
+        };
+        let public_key = fs::read(path)
+            .with_context(|| format!("couldn't read lookup data signing key {}", path.display()))?;
+        anyhow::ensure!(
+            public_key.len() == 32,
+            "lookup data signing key {} isn't a raw Ed25519 public key",
+            path.display()
+        );
+        Ok(Some(LookupDataSigning { signature_source: signature_source.clone(), public_key }))
+    }
+
+    /// Returns the provisioner of the secrets from the key management service,
+    /// if set.
+    pub fn secret_provisioner(&self) -> anyhow::Result<Option<SecretProvisioner>> {
+        let Some(url) = self.secret_provisioning_url.as_ref() else {
+            return Ok(None);
+        };
+        let policy = self
+            .attestation_policy()?
+            .context("secrets can only be provisioned with an attestation policy")?;
+        Ok(Some(SecretProvisioner::new(policy, Arc::new(HttpKeyManagementService::new(url)))))
+    }
 }
 
 fn path_exists(s: &str) -> Result<PathBuf, String> {
@@ -89,101 +440,822 @@ fn path_exists(s: &str) -> Result<PathBuf, String> {
     }
 }
 
+fn parse_byte_unit(s: &str) -> Result<ByteUnit, String> {
+    s.parse().map_err(|err: ubyte::Error| err.to_string())
+}
+
+fn parse_privacy_budget(s: &str) -> Result<f64, String> {
+    let epsilon: f64 = s.parse().map_err(|err: std::num::ParseFloatError| err.to_string())?;
+    if epsilon.is_finite() && epsilon > 0.0 {
+        Ok(epsilon)
+    } else {
+        Err(format!("the privacy budget must be finite and positive, got {}", epsilon))
+    }
+}
+
+fn lookup_source_exists(s: &str) -> Result<LookupSource, String> {
+    let source = s.parse()?;
+    if let LookupSource::File(_) = source {
+        path_exists(s)?;
+    }
+    Ok(source)
+}
+
+#[derive(Clone)]
 pub struct LookupDataConfig {
-    pub lookup_data_path: PathBuf,
+    pub lookup_data_source: LookupSource,
     // Only periodically updates if interval is given.
     pub update_interval: Option<Duration>,
     pub max_chunk_size: ByteUnit,
+    // Whether the lookup data is encrypted for the enclave, see
+    // `oak_functions_service::lookup_encryption`.
+    pub encrypted: bool,
+    // Set if the enclave must only activate signed lookup data.
+    pub signing: Option<LookupDataSigning>,
+    // Whether the lookup data source holds a manifest of shards.
+    pub sharded: bool,
+    // Whether the enclave builds an ordered index of the lookup data.
+    pub ordered_index: bool,
+    // The maximum memory the enclave may use for lookup data, if limited.
+    pub memory_budget: Option<ByteUnit>,
+    // How many times a failed update is retried before giving up.
+    pub max_retries: u32,
+    // Delay before the first retry of a failed update, doubled for every
+    // subsequent retry.
+    pub retry_backoff: Duration,
+}
+
+/// Where the signature of signed lookup data is loaded from, and the key the
+/// enclave verifies it with.
+#[derive(Clone, Debug)]
+pub struct LookupDataSigning {
+    pub signature_source: LookupSource,
+    /// The raw Ed25519 public key.
+    pub public_key: Vec<u8>,
+}
+
+impl LookupDataConfig {
+    // Returns the key the enclave verifies the signature of the lookup data
+    // with, or an empty key if the lookup data isn't signed. It is part of the
+    // measured application config.
+    fn signing_public_key(&self) -> Vec<u8> {
+        self.signing.as_ref().map(|signing| signing.public_key.clone()).unwrap_or_default()
+    }
+
+    /// Returns how long to wait before the given retry of a failed update,
+    /// starting from 1.
+    pub fn retry_delay(&self, retry: u32) -> Duration {
+        self.retry_backoff.saturating_mul(1 << retry.saturating_sub(1).min(16))
+    }
+}
+
+/// The outcome of a single refresh of the lookup data.
+#[derive(Clone, Debug)]
+pub struct LookupDataUpdate {
+    /// Number of attempts made, including retries.
+    pub attempts: u32,
+    /// Whether new lookup data was pushed to the enclave; false if the remote
+    /// lookup data hasn't changed since the previous refresh.
+    pub changed: bool,
+    /// Time taken by the refresh, including the backoff between retries.
+    pub duration: Duration,
+    /// The error of the last attempt, if the refresh failed.
+    pub result: Result<(), Arc<anyhow::Error>>,
+    /// Memory used by the lookup data as reported by the enclave, if the
+    /// refresh pushed new lookup data.
+    pub memory_usage: Option<LookupDataMemoryUsage>,
+}
+
+/// Handle to the task that keeps the lookup data in the enclave up to date.
+///
+/// The handle can be cloned freely. Once all handles are dropped, only the
+/// periodic refreshes (if any) keep running.
+#[derive(Clone)]
+pub struct LookupDataHandle {
+    reload_requests: mpsc::Sender<oneshot::Sender<LookupDataUpdate>>,
+    updates: watch::Receiver<LookupDataUpdate>,
+    refresher: AbortHandle,
+}
+
+impl LookupDataHandle {
+    /// Refreshes the lookup data immediately and returns the outcome of the
+    /// refresh.
+    pub async fn reload(&self) -> Result<LookupDataUpdate, LauncherError> {
+        let terminated = || LauncherError::Enclave(anyhow!("lookup data refresh task terminated"));
+        let (sender, receiver) = oneshot::channel();
+        self.reload_requests.send(sender).await.map_err(|_| terminated())?;
+        receiver.await.map_err(|_| terminated())
+    }
+
+    /// Returns the outcome of the most recent refresh.
+    pub fn last_update(&self) -> LookupDataUpdate {
+        self.updates.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified after every refresh, whether
+    /// periodic or triggered via `reload`.
+    pub fn subscribe(&self) -> watch::Receiver<LookupDataUpdate> {
+        self.updates.clone()
+    }
+
+    /// Stops all further refreshes, periodic or requested, for every handle.
+    pub fn stop(&self) {
+        self.refresher.abort();
+    }
+}
+
+/// How a guest instance was shut down by [`shutdown`].
+#[derive(Debug)]
+pub enum ShutdownStatus {
+    /// The enclave terminated on request and the VMM exited on its own.
+    Clean(ExitStatus),
+    /// The VMM didn't exit in time and was killed.
+    Forced(ExitStatus),
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn create(
     params: launcher::Params,
     lookup_data_config: LookupDataConfig,
     wasm_path: PathBuf,
-    constant_response_size: u32,
+    constant_response_size: Option<u32>,
+    wasm_limits: WasmLimits,
+    privacy_budget_epsilon: Option<f64>,
+    peer_attestation_policy: Option<AppraisalPolicy>,
+    secret_provisioner: Option<&SecretProvisioner>,
+    sealed_state_path: Option<&Path>,
 ) -> Result<
-    (Box<dyn launcher::GuestInstance>, channel::ConnectorHandle, InitializeResponse),
-    Box<dyn std::error::Error>,
+    (
+        Box<dyn launcher::GuestInstance>,
+        channel::ConnectorHandle,
+        InitializeResponse,
+        LookupDataHandle,
+    ),
+    LauncherError,
 > {
     log::info!("creating Oak Functions guest instance");
+    // The enclave checks that the config it is initialized with is the one the
+    // Restricted Kernel measured.
+    let application_config = params.application_config.clone();
     let (launched_instance, connector_handle) = launcher::launch(params).await?;
-    let intialize_response =
-        intialize_enclave(connector_handle.clone(), &wasm_path, constant_response_size).await?;
-    setup_lookup_data(connector_handle.clone(), lookup_data_config).await?;
-    Ok((launched_instance, connector_handle, intialize_response))
+    let intialize_response = intialize_enclave(
+        connector_handle.clone(),
+        &wasm_path,
+        application_config,
+        constant_response_size,
+        wasm_limits,
+        privacy_budget_epsilon,
+        peer_attestation_policy,
+        &lookup_data_config,
+        sealed_state_path,
+    )
+    .await?;
+    if let Some(secret_provisioner) = secret_provisioner {
+        secret_provisioner
+            .provision(
+                connector_handle.clone(),
+                intialize_response
+                    .evidence
+                    .as_ref()
+                    .ok_or_else(|| LauncherError::Attestation(anyhow!("no evidence provided")))?,
+            )
+            .await
+            .map_err(|err| {
+                // The evidence may have been rejected before any secret was provisioned.
+                err.downcast::<LauncherError>().unwrap_or_else(LauncherError::Attestation)
+            })?;
+    }
+    let lookup_data_handle =
+        setup_lookup_data(connector_handle.clone(), lookup_data_config).await?;
+    Ok((launched_instance, connector_handle, intialize_response, lookup_data_handle))
+}
+
+/// Checks that the evidence of a guest instance satisfies `policy`.
+pub fn check_evidence(policy: &AppraisalPolicy, evidence: &Evidence) -> Result<(), LauncherError> {
+    let extracted_evidence = verify_dice_chain(evidence)
+        .context("invalid DICE chain")
+        .map_err(LauncherError::Attestation)?;
+    appraise(policy, &extracted_evidence, &Endorsements::default())
+        .context("evidence doesn't satisfy the attestation policy")
+        .map_err(LauncherError::Attestation)
+}
+
+/// Sets up a (re)launched guest instance as [`create`] does, for use with a
+/// [`launcher::Supervisor`].
+pub struct GuestConfig {
+    pub lookup_data_config: LookupDataConfig,
+    pub wasm_path: PathBuf,
+    /// The application config that the instances are launched with, see
+    /// [`launcher::Params::application_config`].
+    pub application_config: Vec<u8>,
+    pub constant_response_size: Option<u32>,
+    /// Passed to every instance, see [`Args::wasm_limits`].
+    pub wasm_limits: WasmLimits,
+    /// Passed to every instance, see [`Args::privacy_budget_epsilon`]. Every
+    /// relaunched instance starts with the full budget.
+    pub privacy_budget_epsilon: Option<f64>,
+    /// Passed to every instance, see [`Args::peer_attestation_policy`].
+    pub peer_attestation_policy: Option<AppraisalPolicy>,
+    /// Instances whose evidence doesn't satisfy the policy are relaunched.
+    pub attestation_policy: Option<AppraisalPolicy>,
+    /// Provisions the secrets of every instance before it is served.
+    pub secret_provisioner: Option<SecretProvisioner>,
+    /// Every instance starts from the state in this file, see
+    /// [`Args::sealed_state`].
+    pub sealed_state_path: Option<PathBuf>,
+}
+
+/// A guest instance set up by [`GuestConfig`].
+pub struct InitializedGuest {
+    pub initialize_response: InitializeResponse,
+    pub lookup_data_handle: LookupDataHandle,
+}
+
+#[async_trait::async_trait]
+impl launcher::GuestSetup for GuestConfig {
+    type Output = InitializedGuest;
+
+    async fn setup(
+        &self,
+        connector_handle: channel::ConnectorHandle,
+    ) -> anyhow::Result<InitializedGuest> {
+        let initialize_response = intialize_enclave(
+            connector_handle.clone(),
+            &self.wasm_path,
+            self.application_config.clone(),
+            self.constant_response_size,
+            self.wasm_limits,
+            self.privacy_budget_epsilon,
+            self.peer_attestation_policy.clone(),
+            &self.lookup_data_config,
+            self.sealed_state_path.as_deref(),
+        )
+        .await?;
+        let evidence = initialize_response.evidence.as_ref().context("no evidence provided")?;
+        if let Some(policy) = self.attestation_policy.as_ref() {
+            check_evidence(policy, evidence)?;
+        }
+        if let Some(secret_provisioner) = self.secret_provisioner.as_ref() {
+            secret_provisioner.provision(connector_handle.clone(), evidence).await?;
+        }
+        let lookup_data_handle =
+            setup_lookup_data(connector_handle, self.lookup_data_config.clone()).await?;
+        Ok(InitializedGuest { initialize_response, lookup_data_handle })
+    }
+
+    fn teardown(&self, guest: &InitializedGuest) {
+        guest.lookup_data_handle.stop();
+    }
+}
+
+/// Routes every session to the supervised guest instance while it is running.
+impl server::SessionRouter for watch::Receiver<Option<launcher::Running<InitializedGuest>>> {
+    fn route(&self) -> Option<server::SessionTarget> {
+        let guest = self.borrow();
+        let guest = guest.as_ref()?;
+        Some(server::SessionTarget {
+            connector_handle: guest.connector_handle.clone(),
+            evidence: guest.setup.initialize_response.evidence.clone()?,
+        })
+    }
+}
+
+/// Shuts down a guest instance created by [`create`].
+///
+/// Stops refreshing the lookup data, asks the enclave to terminate and waits up
+/// to `timeout` for the VMM to exit, killing it if it doesn't.
+pub async fn shutdown(
+    mut guest_instance: Box<dyn launcher::GuestInstance>,
+    connector_handle: channel::ConnectorHandle,
+    lookup_data_handle: &LookupDataHandle,
+    timeout: Duration,
+) -> Result<ShutdownStatus, LauncherError> {
+    log::info!("shutting down Oak Functions guest instance");
+    lookup_data_handle.stop();
+
+    let terminate = async {
+        OakFunctionsAsyncClient::new(connector_handle)
+            .terminate(&TerminateRequest {})
+            .await
+            .flatten()
+            .map_err(|err| anyhow!("couldn't ask the enclave to terminate: {:?}", err))?;
+        guest_instance.wait().await
+    };
+    match tokio::time::timeout(timeout, terminate).await {
+        Ok(Ok(status)) => {
+            log::info!("guest instance exited: {}", status);
+            return Ok(ShutdownStatus::Clean(status));
+        }
+        Ok(Err(err)) => log::warn!("guest instance didn't shut down cleanly: {:?}", err),
+        Err(_) => log::warn!("guest instance didn't exit within {:?}", timeout),
+    }
+    let status = guest_instance.kill().await.map_err(LauncherError::Vmm)?;
+    Ok(ShutdownStatus::Forced(status))
 }
 
-// Initially loads lookup data and spawns task to periodically refresh lookup
-// data.
+// Initially loads lookup data and spawns task to refresh lookup data
+// periodically and on demand.
 async fn setup_lookup_data(
     connector_handle: channel::ConnectorHandle,
     config: LookupDataConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<LookupDataHandle, LauncherError> {
     log::info!("setting up lookup data");
     let mut client = OakFunctionsAsyncClient::new(connector_handle);
 
     // Block for [invariant that lookup data is fully loaded](https://github.com/project-oak/oak/tree/main/oak_functions/lookup/README.md#invariant-fully-loaded-lookup-data)
-    update_lookup_data(&mut client, &config).await?;
-
-    // Spawn task to periodically refresh lookup data.
-    if config.update_interval.is_some() {
-        tokio::spawn(setup_periodic_update(client, config));
+    let mut loaded = LoadedLookupData::default();
+    let update = update_lookup_data_with_retries(&mut client, &config, &mut loaded).await;
+    if let Err(err) = &update.result {
+        return Err(LauncherError::Enclave(anyhow!("couldn't load lookup data: {:#}", err)));
     }
-    Ok(())
+
+    let (reload_sender, reload_receiver) = mpsc::channel(1);
+    let (update_sender, update_receiver) = watch::channel(update);
+    let refresher =
+        tokio::spawn(refresh_lookup_data(client, config, loaded, reload_receiver, update_sender))
+            .abort_handle();
+    Ok(LookupDataHandle { reload_requests: reload_sender, updates: update_receiver, refresher })
 }
 
-async fn setup_periodic_update(
+// Refreshes the lookup data whenever the update interval elapses or a reload is
+// requested via a `LookupDataHandle`.
+async fn refresh_lookup_data(
     mut client: OakFunctionsAsyncClient<ConnectorHandle>,
     config: LookupDataConfig,
+    mut loaded: LoadedLookupData,
+    mut reload_requests: mpsc::Receiver<oneshot::Sender<LookupDataUpdate>>,
+    updates: watch::Sender<LookupDataUpdate>,
 ) {
-    // Only set periodic update if an interval is given.
-    let mut interval =
-        tokio::time::interval(config.update_interval.expect("No update interval given."));
-    loop {
-        // Wait before updating because we just loaded the lookup data.
+    let mut interval = config.update_interval.map(tokio::time::interval);
+    if let Some(interval) = interval.as_mut() {
+        // The first tick completes immediately, but we just loaded the lookup data.
         interval.tick().await;
-        let _ = update_lookup_data(&mut client, &config).await;
-        // Ignore errors in updates of lookup data after the initial update.
+    }
+    let mut handles_dropped = false;
+    loop {
+        let reply = tokio::select! {
+            _ = tick(&mut interval), if interval.is_some() => None,
+            request = reload_requests.recv(), if !handles_dropped => match request {
+                Some(reply) => Some(reply),
+                None => {
+                    handles_dropped = true;
+                    continue;
+                }
+            },
+            else => break,
+        };
+        let update = update_lookup_data_with_retries(&mut client, &config, &mut loaded).await;
+        if let Some(reply) = reply {
+            // The requester may have stopped waiting for the result.
+            let _ = reply.send(update.clone());
+        }
+        updates.send_replace(update);
+    }
+}
+
+// Waits for the next tick of the interval, or forever if there is no interval.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
     }
 }
 
+// What the enclave was last sent, to skip sending lookup data that didn't
+// change.
+#[derive(Default)]
+struct LoadedLookupData {
+    // The version of the remote lookup data (or manifest).
+    version: Option<String>,
+    // The digests of the shards of sharded lookup data.
+    shard_digests: Vec<Vec<u8>>,
+}
+
+// Updates the lookup data if it changed since it was `loaded`, retrying failed
+// updates with exponential backoff.
+async fn update_lookup_data_with_retries(
+    client: &mut OakFunctionsAsyncClient<ConnectorHandle>,
+    config: &LookupDataConfig,
+    loaded: &mut LoadedLookupData,
+) -> LookupDataUpdate {
+    let start = Instant::now();
+    let mut attempts = 0;
+    let mut changed = false;
+    let mut memory_usage = None;
+    let result = loop {
+        attempts += 1;
+        match update_lookup_data_if_changed(client, config, loaded).await {
+            Ok(response) => {
+                changed = response.is_some();
+                memory_usage = response.and_then(|response| response.memory_usage);
+                break Ok(());
+            }
+            Err(err) if err.is::<MemoryBudgetExceeded>() => {
+                log::error!("couldn't update lookup data: {:?}", err);
+                break Err(Arc::new(err));
+            }
+            Err(err) if attempts > config.max_retries => {
+                log::error!("couldn't update lookup data after {} attempts: {:?}", attempts, err);
+                break Err(Arc::new(err));
+            }
+            Err(err) => {
+                log::warn!("couldn't update lookup data (attempt {}): {:?}", attempts, err);
+                tokio::time::sleep(config.retry_delay(attempts)).await;
+            }
+        }
+    };
+    LookupDataUpdate { attempts, changed, duration: start.elapsed(), result, memory_usage }
+}
+
 // Trigger loading of lookup data from lookup data source.
 // Public for convenient testing.
 pub async fn update_lookup_data(
     client: &mut OakFunctionsAsyncClient<ConnectorHandle>,
     config: &LookupDataConfig,
-) -> anyhow::Result<()> {
-    log::info!("updating lookup data");
+) -> Result<(), LauncherError> {
+    update_lookup_data_if_changed(client, config, &mut LoadedLookupData::default())
+        .await
+        .map(|_| ())
+        .map_err(|err| LauncherError::Enclave(err.context("couldn't load lookup data")))
+}
+
+// Loads lookup data from the lookup data source, unless the source reports that
+// it hasn't changed since it was `loaded`. Returns the response of the enclave
+// if the lookup data was updated.
+async fn update_lookup_data_if_changed(
+    client: &mut OakFunctionsAsyncClient<ConnectorHandle>,
+    config: &LookupDataConfig,
+    loaded: &mut LoadedLookupData,
+) -> anyhow::Result<Option<FinishNextLookupDataResponse>> {
+    log::info!("updating lookup data from {}", config.lookup_data_source);
     let start = std::time::Instant::now();
-    let result =
-        lookup::update_lookup_data(client, &config.lookup_data_path, config.max_chunk_size).await;
+    let Some(fetched) =
+        config.lookup_data_source.fetch_if_changed(loaded.version.as_deref()).await?
+    else {
+        log::info!("lookup data unchanged");
+        return Ok(None);
+    };
+    if config.sharded {
+        let serialized = fs::read(fetched.path()).context("couldn't read lookup data manifest")?;
+        let manifest = LookupDataManifest::decode(serialized.as_slice())
+            .context("couldn't decode lookup data manifest")?;
+        let response = lookup::update_sharded_lookup_data(
+            client,
+            &manifest,
+            &loaded.shard_digests,
+            config.max_chunk_size,
+            config.encrypted,
+        )
+        .await?;
+        log::info!("updated sharded lookup data in {}ms", start.elapsed().as_millis());
+        loaded.version = fetched.version;
+        loaded.shard_digests = manifest.shards.into_iter().map(|shard| shard.digest).collect();
+        return Ok(Some(response));
+    }
+    let signature = match config.signing.as_ref() {
+        Some(signing) => Some(fetch_lookup_data_signature(&signing.signature_source).await?),
+        None => None,
+    };
+    let response = lookup::update_lookup_data(
+        client,
+        fetched.path(),
+        config.max_chunk_size,
+        config.encrypted,
+        signature,
+    )
+    .await?;
     log::info!("updated lookup data in {}ms", start.elapsed().as_millis());
-    result
+    // Only remember the version once the enclave has the data, so that a failed
+    // update is retried even if the source doesn't change.
+    loaded.version = fetched.version;
+    Ok(Some(response))
+}
+
+// Fetches the detached signature of the lookup data.
+async fn fetch_lookup_data_signature(source: &LookupSource) -> anyhow::Result<LookupDataSignature> {
+    let fetched = source.fetch().await?;
+    let serialized = fs::read(fetched.path())
+        .with_context(|| format!("couldn't read lookup data signature from {}", source))?;
+    LookupDataSignature::decode(serialized.as_slice())
+        .context("couldn't decode lookup data signature")
+}
+
+/// Saves the sealed state of the enclave to `path`, for the next enclave to
+/// start from, see [`Args::sealed_state`].
+pub async fn save_sealed_state(
+    connector_handle: channel::ConnectorHandle,
+    path: &Path,
+) -> Result<(), LauncherError> {
+    let response = OakFunctionsAsyncClient::new(connector_handle)
+        .get_sealed_state(&GetSealedStateRequest {})
+        .await
+        .flatten()
+        .map_err(|err| LauncherError::Enclave(anyhow!("couldn't get sealed state: {:?}", err)))?;
+    // Replace the file in one step, so that a crash never leaves a partial state
+    // behind.
+    let temporary_path = path.with_extension("tmp");
+    fs::write(&temporary_path, &response.sealed_state).map_err(|err| {
+        LauncherError::io(
+            format!("couldn't write sealed state to {}", temporary_path.display()),
+            err,
+        )
+    })?;
+    fs::rename(&temporary_path, path).map_err(|err| {
+        LauncherError::io(format!("couldn't move sealed state to {}", path.display()), err)
+    })?;
+    log::info!("saved sealed state to {}", path.display());
+    Ok(())
+}
+
+// Reads the sealed state of an earlier enclave, if one was saved.
+fn read_sealed_state(path: &Path) -> Result<Vec<u8>, LauncherError> {
+    match fs::read(path) {
+        Ok(sealed_state) => {
+            log::info!("read sealed state from {}", path.display());
+            Ok(sealed_state)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("no sealed state at {}, starting from scratch", path.display());
+            Ok(Vec::new())
+        }
+        Err(err) => Err(LauncherError::io(
+            format!("couldn't read sealed state from {}", path.display()),
+            err,
+        )),
+    }
 }
 
 // Loads application config (including Wasm bytes) into the enclave and returns
 // a remote attestation evidence.
+#[allow(clippy::too_many_arguments)]
 async fn intialize_enclave(
     connector_handle: channel::ConnectorHandle,
     wasm: &PathBuf,
-    constant_response_size: u32,
-) -> Result<InitializeResponse, Box<dyn std::error::Error>> {
-    let wasm_bytes = fs::read(wasm)
-        .with_context(|| format!("couldn't read Wasm file {}", wasm.display()))
-        .unwrap();
+    application_config: Vec<u8>,
+    constant_response_size: Option<u32>,
+    wasm_limits: WasmLimits,
+    privacy_budget_epsilon: Option<f64>,
+    peer_attestation_policy: Option<AppraisalPolicy>,
+    lookup_data_config: &LookupDataConfig,
+    sealed_state_path: Option<&Path>,
+) -> Result<InitializeResponse, LauncherError> {
+    let wasm_bytes = fs::read(wasm).map_err(|err| {
+        LauncherError::io(format!("couldn't read Wasm file {}", wasm.display()), err)
+    })?;
     log::info!(
         "read Wasm file from disk {} ({})",
         &wasm.display(),
         ubyte::ByteUnit::Byte(wasm_bytes.len() as u64)
     );
 
-    let request = InitializeRequest { wasm_module: wasm_bytes, constant_response_size };
+    // A constant response size of 0 asks the enclave to derive it from the Wasm
+    // module.
+    let request = InitializeRequest {
+        wasm_module: wasm_bytes,
+        constant_response_size: constant_response_size.unwrap_or(0),
+        peer_attestation_policy,
+        lookup_data_ordered_index: lookup_data_config.ordered_index,
+        lookup_data_memory_budget: lookup_data_config
+            .memory_budget
+            .map_or(0, |budget| budget.as_u64()),
+        // The Restricted Kernel only supports the interpreter.
+        wasm_engine: WasmEngine::Unspecified.into(),
+        wasm_fuel_limit: wasm_limits.fuel_limit.unwrap_or(0),
+        wasm_memory_limit: wasm_limits.memory_limit.map_or(0, |limit| limit.as_u64()),
+        wasm_memory_growth_disabled: wasm_limits.memory_growth_disabled,
+        privacy_budget_epsilon: privacy_budget_epsilon.unwrap_or(0.0),
+        // The Restricted Kernel can't load ML models.
+        ml_model: Vec::new(),
+        // This launcher doesn't send helper modules yet.
+        helper_modules: Default::default(),
+        // Nor does it serve several tenants.
+        tenants: Default::default(),
+        // The Restricted Kernel serves one request at a time.
+        max_concurrent_invocations: 0,
+        // The Restricted Kernel only interprets Wasm modules.
+        precompiled_wasm_module: None,
+        sealed_state: sealed_state_path.map(read_sealed_state).transpose()?.unwrap_or_default(),
+        application_config,
+    };
 
     let mut client = OakFunctionsAsyncClient::new(connector_handle);
     log::info!("sending initialize request");
     let initialize_response =
-        client.initialize(&request).await.flatten().expect("couldn't initialize service");
+        client.initialize(&request).await.flatten().map_err(|err| {
+            LauncherError::Enclave(anyhow!("couldn't initialize service: {:?}", err))
+        })?;
     log::info!("service initialized: {:?}", initialize_response);
+    log::info!(
+        "constant response size: {} (maximum declared by the Wasm module: {:?})",
+        initialize_response.constant_response_size,
+        initialize_response.max_response_size
+    );
 
     Ok(initialize_response)
 }
+
+/// Replaces the Wasm module of an initialized enclave with the one at `wasm`,
+/// without relaunching the guest instance or reloading the lookup data.
+///
+/// Requests that are in flight when the module is swapped complete using the
+/// previous module; once this returns, all new requests use the new module.
+pub async fn reload_wasm(
+    connector_handle: channel::ConnectorHandle,
+    wasm: &Path,
+) -> Result<(), LauncherError> {
+    let wasm_bytes = fs::read(wasm).map_err(|err| {
+        LauncherError::io(format!("couldn't read Wasm file {}", wasm.display()), err)
+    })?;
+    log::info!(
+        "reloading Wasm module {} ({})",
+        wasm.display(),
+        ubyte::ByteUnit::Byte(wasm_bytes.len() as u64)
+    );
+
+    let mut client = OakFunctionsAsyncClient::new(connector_handle);
+    client
+        .reload_wasm(&ReloadWasmRequest {
+            wasm_module: wasm_bytes,
+            // This launcher doesn't start enclaves that serve several tenants.
+            tenant_id: String::new(),
+        })
+        .await
+        .flatten()
+        .map_err(|err| LauncherError::Enclave(anyhow!("couldn't reload Wasm module: {:?}", err)))?;
+    log::info!("reloaded Wasm module");
+    Ok(())
+}
+
+#[test]
+fn test_retry_delay_doubles() {
+    let config = LookupDataConfig {
+        lookup_data_source: LookupSource::File(PathBuf::new()),
+        update_interval: None,
+        max_chunk_size: ByteUnit::Kibibyte(1),
+        encrypted: false,
+        signing: None,
+        sharded: false,
+        ordered_index: false,
+        memory_budget: None,
+        max_retries: 3,
+        retry_backoff: Duration::from_millis(100),
+    };
+    assert_eq!(config.retry_delay(1), Duration::from_millis(100));
+    assert_eq!(config.retry_delay(2), Duration::from_millis(200));
+    assert_eq!(config.retry_delay(3), Duration::from_millis(400));
+}
+
+#[test]
+fn test_attestation_policy_with_required_claim_types_fails() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    fs::write(file.path(), r#"{ "required_endorsement_claim_types": ["https://example.com"] }"#)
+        .unwrap();
+    let path = file.path().to_str().unwrap();
+    let args = RestrictedKernelArgs::try_parse_from([
+        "oak_functions_launcher",
+        "--attestation-policy",
+        path,
+    ])
+    .unwrap();
+    assert!(args.attestation_policy().is_err());
+}
+
+#[test]
+fn test_secret_provisioning_requires_attestation_policy() {
+    let args = RestrictedKernelArgs::try_parse_from([
+        "oak_functions_launcher",
+        "--secret-provisioning-url",
+        "https://kms.example.com/secrets",
+    ]);
+    assert!(args.is_err());
+}
+
+#[test]
+fn test_encrypted_lookup_data_requires_secret_provisioning() {
+    let args =
+        RestrictedKernelArgs::try_parse_from(["oak_functions_launcher", "--lookup-data-encrypted"]);
+    assert!(args.is_err());
+}
+
+#[test]
+fn test_lookup_data_signing_key_must_be_raw_ed25519_key() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    let key = oak_functions_test_utils::write_to_temp_file(&[1; 16]);
+    let args = RestrictedKernelArgs::try_parse_from([
+        "oak_functions_launcher",
+        "--lookup-data-signature",
+        path,
+        "--lookup-data-signing-key",
+        key.path().to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(args.lookup_data_signing().is_err());
+}
+
+#[test]
+fn test_parse_privacy_budget() {
+    assert_eq!(parse_privacy_budget("0.5"), Ok(0.5));
+    assert!(parse_privacy_budget("0").is_err());
+    assert!(parse_privacy_budget("-1").is_err());
+    assert!(parse_privacy_budget("inf").is_err());
+    assert!(parse_privacy_budget("NaN").is_err());
+    assert!(parse_privacy_budget("budget").is_err());
+}
+
+#[test]
+fn test_read_sealed_state_reports_io_errors() {
+    let directory = tempfile::tempdir().unwrap();
+    assert_eq!(read_sealed_state(&directory.path().join("missing")).unwrap(), Vec::<u8>::new());
+    assert!(matches!(read_sealed_state(directory.path()), Err(LauncherError::Io { .. })));
+}
+
+#[test]
+fn test_check_evidence_reports_attestation_errors() {
+    assert!(matches!(
+        check_evidence(&AppraisalPolicy::default(), &Evidence::default()),
+        Err(LauncherError::Attestation(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_reload_reports_enclave_errors_once_refreshes_stopped() {
+    let (reload_requests, _) = mpsc::channel(1);
+    let (_, updates) = watch::channel(LookupDataUpdate {
+        attempts: 1,
+        changed: true,
+        duration: Duration::ZERO,
+        result: Ok(()),
+        memory_usage: None,
+    });
+    let handle = LookupDataHandle {
+        reload_requests,
+        updates,
+        refresher: tokio::spawn(async {}).abort_handle(),
+    };
+    assert!(matches!(handle.reload().await, Err(LauncherError::Enclave(_))));
+}
+
+#[tokio::test]
+async fn test_run_reports_config_errors() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    let launcher_params = launcher::Params::try_parse_from([
+        "oak_functions_launcher",
+        "--vmm-binary",
+        path,
+        "--kernel",
+        path,
+        "--initrd",
+        path,
+        "--bios-binary",
+        path,
+    ])
+    .unwrap();
+    let functions_params =
+        Args::try_parse_from(["oak_functions_launcher", "--lookup-data", path]).unwrap();
+    let restricted_kernel_params =
+        RestrictedKernelArgs::try_parse_from(["oak_functions_launcher"]).unwrap();
+    // There is no Wasm module to launch the enclave with.
+    assert!(matches!(
+        runner::run(launcher_params, functions_params, restricted_kernel_params).await,
+        Err(LauncherError::Config(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_run_rejects_privacy_budget_with_several_replicas() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    let launcher_params = launcher::Params::try_parse_from([
+        "oak_functions_launcher",
+        "--vmm-binary",
+        path,
+        "--kernel",
+        path,
+        "--initrd",
+        path,
+        "--bios-binary",
+        path,
+    ])
+    .unwrap();
+    let functions_params = Args::try_parse_from([
+        "oak_functions_launcher",
+        "--wasm",
+        path,
+        "--lookup-data",
+        path,
+        "--privacy-budget-epsilon",
+        "1.0",
+    ])
+    .unwrap();
+    let restricted_kernel_params =
+        RestrictedKernelArgs::try_parse_from(["oak_functions_launcher", "--replicas", "2"])
+            .unwrap();
+    assert!(matches!(
+        runner::run(launcher_params, functions_params, restricted_kernel_params).await,
+        Err(LauncherError::Config(_))
+    ));
+}
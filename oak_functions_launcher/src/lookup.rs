@@ -14,62 +14,130 @@
 // limitations under the License.
 //
 
-use std::{fs, path::PathBuf};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    path::Path,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context};
-use hashbrown::HashMap;
 use prost::Message;
 use ubyte::ByteUnit;
 
 use crate::{
-    channel::ConnectorHandle,
+    channel::{is_channel_busy, ConnectorHandle},
+    lookup_source::FetchedLookupData,
     proto::oak::functions::{
-        Empty, ExtendNextLookupDataRequest, FinishNextLookupDataRequest, LookupDataChunk,
-        LookupDataEntry, OakFunctionsAsyncClient,
+        Empty, EncryptedLookupDataChunk, ExtendNextLookupDataRequest, FinishNextLookupDataRequest,
+        FinishNextLookupDataResponse, LookupDataChunk, LookupDataEntry, LookupDataManifest,
+        LookupDataShard, LookupDataSignature, OakFunctionsAsyncClient,
     },
+    LookupSource,
 };
 
-struct UpdateClient<'a, I: Iterator<Item = LookupDataChunk>> {
+// We will add the estimated size of every LookupDataEntry, and to account for
+// the LookupData overhead, we generously estimate 50 bytes.
+const CHUNK_OVERHEAD: ByteUnit = ByteUnit::Byte(50);
+
+// Overestimate delimiter size based on https://github.com/tokio-rs/prost/blob/0c350dc6ad3cd61dc9a1398dffab5ac312f3b245/src/lib.rs#L55
+const OVERESTIMATED_DELIMITER_SIZE: ByteUnit = ByteUnit::Byte(10);
+
+// Upper bound for the size of a single encoded entry, to avoid allocating
+// arbitrary amounts of memory for a corrupted length prefix.
+const MAX_ENTRY_SIZE: usize = 2 * 1024 * 1024 * 1024;
+
+// How long the Oak Functions Service may take to handle a single lookup data
+// request before the update is given up on, so that a hung guest doesn't block
+// the update forever.
+const LOOKUP_DATA_REQUEST_DEADLINE: Duration = Duration::from_secs(60);
+
+/// Returned when the enclave rejects lookup data that doesn't fit in its memory
+/// budget. The enclave keeps serving its active lookup data, and retrying the
+/// update doesn't help until the lookup data shrinks.
+#[derive(Debug)]
+pub struct MemoryBudgetExceeded(pub String);
+
+impl std::fmt::Display for MemoryBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "enclave rejected lookup data: {}", self.0)
+    }
+}
+
+impl std::error::Error for MemoryBudgetExceeded {}
+
+struct UpdateClient<'a> {
     inner: &'a mut OakFunctionsAsyncClient<ConnectorHandle>,
-    chunks: I,
 }
 
-impl<I: Iterator<Item = LookupDataChunk>> UpdateClient<'_, I> {
-    // Sends all chunks to the Oak Functions Service.
-    async fn update(&mut self) -> anyhow::Result<()> {
-        let mut next = self.chunks.next();
-        while next.is_some() {
-            self.extend(next).await?;
-            next = self.chunks.next();
+impl UpdateClient<'_> {
+    // Sends all chunks to the Oak Functions Service. If a chunk can't be read,
+    // the partially sent lookup data is discarded.
+    async fn extend_all<I: Iterator<Item = anyhow::Result<ExtendNextLookupDataRequest>>>(
+        &mut self,
+        requests: I,
+    ) -> anyhow::Result<()> {
+        for next in requests {
+            match next {
+                Ok(request) => self.extend(&request).await?,
+                Err(err) => {
+                    self.abort().await?;
+                    return Err(err);
+                }
+            }
         }
-        self.finish().await
+        Ok(())
     }
 
-    async fn extend(&mut self, chunk: Option<LookupDataChunk>) -> anyhow::Result<()> {
-        let _ = self
+    async fn extend(&mut self, request: &ExtendNextLookupDataRequest) -> anyhow::Result<()> {
+        let response = self
             .inner
-            .extend_next_lookup_data(&ExtendNextLookupDataRequest { chunk })
+            .extend_next_lookup_data_with_deadline(request, LOOKUP_DATA_REQUEST_DEADLINE)
             .await
             .flatten()
-            .map_err(|err| anyhow!(format!("error handling client request: {:?}", err)))?;
+            .map_err(|err| match err.code {
+                micro_rpc::StatusCode::ResourceExhausted if !is_channel_busy(&err) => {
+                    anyhow::Error::new(MemoryBudgetExceeded(err.message))
+                }
+                _ => anyhow!(format!("error handling client request: {:?}", err)),
+            })?;
+        if let Some(usage) = response.memory_usage {
+            log::debug!(
+                "next lookup data uses {} in the enclave",
+                ByteUnit::Byte(usage.next_bytes)
+            );
+        }
         Ok(())
     }
 
-    async fn finish(&mut self) -> anyhow::Result<()> {
-        let _ = self
+    async fn finish(
+        &mut self,
+        request: &FinishNextLookupDataRequest,
+    ) -> anyhow::Result<FinishNextLookupDataResponse> {
+        let response = self
             .inner
-            .finish_next_lookup_data(&FinishNextLookupDataRequest {})
+            .finish_next_lookup_data_with_deadline(request, LOOKUP_DATA_REQUEST_DEADLINE)
             .await
             .flatten()
-            .map_err(|err| anyhow!(format!("error handling client request: {:?}", err)));
-        Ok(())
+            .map_err(|err| anyhow!(format!("error handling client request: {:?}", err)))?;
+        if let Some(usage) = response.memory_usage.as_ref() {
+            log::info!(
+                "lookup data uses {} in the enclave (budget: {})",
+                ByteUnit::Byte(usage.active_bytes),
+                if usage.budget_bytes == 0 {
+                    "unlimited".to_string()
+                } else {
+                    ByteUnit::Byte(usage.budget_bytes).to_string()
+                }
+            );
+        }
+        Ok(response)
     }
 
-    #[allow(dead_code)]
     async fn abort(&mut self) -> anyhow::Result<()> {
         let _ = self
             .inner
-            .abort_next_lookup_data(&Empty {})
+            .abort_next_lookup_data_with_deadline(&Empty {}, LOOKUP_DATA_REQUEST_DEADLINE)
             .await
             .flatten()
             .map_err(|err| anyhow!(format!("error handling client request: {:?}", err)));
@@ -77,72 +145,270 @@ impl<I: Iterator<Item = LookupDataChunk>> UpdateClient<'_, I> {
     }
 }
 
-// Loads lookup data from the given path, encodes it, and sends it to the
-// client.
+// Streams lookup data from the given path to the client, one chunk at a time,
+// so that at most one chunk is held in memory.
+//
+// Encrypted lookup data is already split into chunks by the owner of the data,
+// so `max_chunk_size` only applies to plaintext lookup data. The signature, if
+// any, is checked by the enclave before it activates the lookup data.
 pub async fn update_lookup_data(
     client: &mut OakFunctionsAsyncClient<ConnectorHandle>,
-    lookup_data_path: &PathBuf,
+    lookup_data_path: &Path,
     max_chunk_size: ByteUnit,
-) -> anyhow::Result<()> {
-    let lookup_data = load_lookup_data(lookup_data_path)?;
-    let chunks = chunk_up_lookup_data(lookup_data, max_chunk_size).into_iter();
-
-    UpdateClient { inner: client, chunks }.update().await
+    encrypted: bool,
+    signature: Option<LookupDataSignature>,
+) -> anyhow::Result<FinishNextLookupDataResponse> {
+    let requests = read_requests(lookup_data_path, max_chunk_size, encrypted, None)?;
+    let mut client = UpdateClient { inner: client };
+    client.extend_all(requests).await?;
+    client.finish(&FinishNextLookupDataRequest { signature, ..Default::default() }).await
 }
 
-fn chunk_up_lookup_data(
-    source_lookup_data: HashMap<Vec<u8>, Vec<u8>>,
+// Streams the shards of sharded lookup data to the client, skipping the shards
+// whose digests are in `loaded_digests` as the enclave already has them.
+//
+// The enclave activates the new lookup data once every shard in the manifest
+// matches its digest, either as sent or as already loaded.
+pub async fn update_sharded_lookup_data(
+    client: &mut OakFunctionsAsyncClient<ConnectorHandle>,
+    manifest: &LookupDataManifest,
+    loaded_digests: &[Vec<u8>],
     max_chunk_size: ByteUnit,
-) -> Vec<LookupDataChunk> {
-    let mut chunks = Vec::new();
-
-    // We will add the estimated size of ever LookupDataEntry, and to account for
-    // the LookupData overhead, we generously estimate 50 bytes.
-    let mut estimated_chunk_size = ByteUnit::Byte(50);
-    // Overestimate delimiter size based on https://github.com/tokio-rs/prost/blob/0c350dc6ad3cd61dc9a1398dffab5ac312f3b245/src/lib.rs#L55
-    let overestimated_delimiter_size = ByteUnit::Byte(10);
-
-    let mut entries = Vec::new();
-
-    for (key, value) in source_lookup_data {
-        estimated_chunk_size += overestimated_delimiter_size
-            + ByteUnit::Byte(key.len() as u64)
-            + ByteUnit::Byte(value.len() as u64);
-
-        // If the next element would exceed the maximum chunk size, create a new chunk.
-        if estimated_chunk_size > max_chunk_size {
-            chunks.push(LookupDataChunk { items: entries });
-            estimated_chunk_size = ByteUnit::Byte(50);
-            entries = Vec::new();
+    encrypted: bool,
+) -> anyhow::Result<FinishNextLookupDataResponse> {
+    let mut client = UpdateClient { inner: client };
+    for (index, shard) in manifest.shards.iter().enumerate() {
+        if loaded_digests.contains(&shard.digest) {
+            log::debug!("shard {} ({}) unchanged", index, shard.location);
+            continue;
+        }
+        log::info!("updating shard {} from {}", index, shard.location);
+        // The fetched shard is removed once it is dropped, so keep it until it
+        // has been sent.
+        let (_fetched, requests) = match fetch_shard(shard).await.and_then(|fetched| {
+            read_requests(fetched.path(), max_chunk_size, encrypted, Some(index as u32))
+                .map(|requests| (fetched, requests))
+        }) {
+            Ok(shard) => shard,
+            Err(err) => {
+                client.abort().await?;
+                return Err(err);
+            }
         };
+        client.extend_all(requests).await?;
+    }
+    client
+        .finish(&FinishNextLookupDataRequest {
+            manifest: Some(manifest.clone()),
+            ..Default::default()
+        })
+        .await
+}
+
+/// Fetches a shard of sharded lookup data.
+pub async fn fetch_shard(shard: &LookupDataShard) -> anyhow::Result<FetchedLookupData> {
+    let source: LookupSource = shard
+        .location
+        .parse()
+        .map_err(|err| anyhow!("invalid shard location {}: {}", shard.location, err))?;
+    source.fetch().await
+}
 
-        entries.push(LookupDataEntry { key, value })
+// Returns the requests that extend the next lookup data (or one of its shards)
+// by the lookup data file at the given path.
+fn read_requests(
+    lookup_data_path: &Path,
+    max_chunk_size: ByteUnit,
+    encrypted: bool,
+    shard: Option<u32>,
+) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<ExtendNextLookupDataRequest>> + Send>> {
+    let file = File::open(lookup_data_path).map_err(|error| {
+        anyhow!("couldn't read the lookup data file {}: {}", lookup_data_path.display(), error)
+    })?;
+    let reader = BufReader::new(file);
+    if encrypted {
+        Ok(Box::new(EncryptedChunkReader { reader }.map(move |encrypted_chunk| {
+            encrypted_chunk.map(|encrypted_chunk| ExtendNextLookupDataRequest {
+                encrypted_chunk: Some(encrypted_chunk),
+                shard,
+                ..Default::default()
+            })
+        })))
+    } else {
+        Ok(Box::new(Chunks::new(EntryReader { reader }, max_chunk_size).map(move |chunk| {
+            chunk.map(|chunk| ExtendNextLookupDataRequest {
+                chunk: Some(chunk),
+                shard,
+                ..Default::default()
+            })
+        })))
     }
-    chunks.push(LookupDataChunk { items: entries });
-    chunks
 }
 
-fn load_lookup_data(file_path: &std::path::PathBuf) -> anyhow::Result<HashMap<Vec<u8>, Vec<u8>>> {
-    let bytes = fs::read(file_path).map_err(|error| {
-        anyhow!("couldn't read the lookup data file {}: {}", file_path.display(), error)
+/// The size of lookup data, as read by [`read_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LookupDataStats {
+    /// The number of entries, or of chunks if the lookup data is encrypted.
+    pub entries: u64,
+    /// The total size of the keys and values, or of the chunks if the lookup
+    /// data is encrypted.
+    pub bytes: u64,
+}
+
+/// Reads the lookup data file at the given path to the end, checking that it
+/// parses, and returns its size.
+pub fn read_stats(lookup_data_path: &Path, encrypted: bool) -> anyhow::Result<LookupDataStats> {
+    let file = File::open(lookup_data_path).map_err(|error| {
+        anyhow!("couldn't read the lookup data file {}: {}", lookup_data_path.display(), error)
     })?;
-    parse_lookup_entries(bytes.as_slice())
+    stats(BufReader::new(file), encrypted)
+}
+
+fn stats<R: BufRead>(reader: R, encrypted: bool) -> anyhow::Result<LookupDataStats> {
+    let mut stats = LookupDataStats::default();
+    if encrypted {
+        for chunk in (EncryptedChunkReader { reader }) {
+            stats.entries += 1;
+            stats.bytes += chunk?.encoded_len() as u64;
+        }
+    } else {
+        for entry in (EntryReader { reader }) {
+            let entry = entry?;
+            stats.entries += 1;
+            stats.bytes += (entry.key.len() + entry.value.len()) as u64;
+        }
+    }
+    Ok(stats)
+}
+
+// Reads the varint length prefix of the next length-delimited message, or
+// returns `None` if there are no more messages.
+fn read_length<R: BufRead>(reader: &mut R) -> anyhow::Result<Option<usize>> {
+    let mut length: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        match reader.read_exact(&mut byte) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof && i == 0 => return Ok(None),
+            result => result.context("couldn't read entry length")?,
+        }
+        length |= ((byte[0] & 0x7F) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(length as usize));
+        }
+    }
+    Err(anyhow!("invalid entry length"))
+}
+
+// Reads the next length-delimited message, or returns `None` if there are no
+// more messages.
+fn read_delimited<M: Message + Default, R: BufRead>(reader: &mut R) -> anyhow::Result<Option<M>> {
+    let Some(length) = read_length(reader)? else {
+        return Ok(None);
+    };
+    if length > MAX_ENTRY_SIZE {
+        return Err(anyhow!("entry too large: {} bytes", length));
+    }
+    let mut buf = vec![0u8; length];
+    reader.read_exact(&mut buf).context("couldn't read entry")?;
+    M::decode(&buf[..]).map(Some).context("couldn't decode entry")
 }
 
-fn parse_lookup_entries<B: prost::bytes::Buf>(
-    lookup_data_buffer: B,
-) -> anyhow::Result<HashMap<Vec<u8>, Vec<u8>>> {
-    let mut lookup_data_buffer = lookup_data_buffer;
-    let mut entries = HashMap::new();
-    while lookup_data_buffer.has_remaining() {
-        let entry =
-            oak_proto_rust::oak::oak_functions::lookup_data::Entry::decode_length_delimited(
-                &mut lookup_data_buffer,
-            )
-            .context("couldn't decode entry")?;
-        entries.insert(entry.key, entry.value);
+// Reads length-delimited lookup data entries one at a time.
+struct EntryReader<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> EntryReader<R> {
+    // Reads the next entry, or returns `None` if there are no more entries.
+    fn read_entry(&mut self) -> anyhow::Result<Option<LookupDataEntry>> {
+        let entry: Option<oak_proto_rust::oak::oak_functions::lookup_data::Entry> =
+            read_delimited(&mut self.reader)?;
+        Ok(entry.map(|entry| LookupDataEntry { key: entry.key, value: entry.value }))
+    }
+}
+
+impl<R: BufRead> Iterator for EntryReader<R> {
+    type Item = anyhow::Result<LookupDataEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
     }
-    Ok(entries)
+}
+
+// Reads length-delimited encrypted lookup data chunks one at a time.
+struct EncryptedChunkReader<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> Iterator for EncryptedChunkReader<R> {
+    type Item = anyhow::Result<EncryptedLookupDataChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_delimited(&mut self.reader).transpose()
+    }
+}
+
+// Groups entries into chunks of at most (approximately) `max_chunk_size`.
+//
+// There is always at least one chunk, even if there are no entries.
+struct Chunks<I: Iterator<Item = anyhow::Result<LookupDataEntry>>> {
+    entries: I,
+    max_chunk_size: ByteUnit,
+    // The entry that didn't fit into the previous chunk.
+    pending: Option<LookupDataEntry>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = anyhow::Result<LookupDataEntry>>> Chunks<I> {
+    fn new(entries: I, max_chunk_size: ByteUnit) -> Self {
+        Self { entries, max_chunk_size, pending: None, done: false }
+    }
+}
+
+impl<I: Iterator<Item = anyhow::Result<LookupDataEntry>>> Iterator for Chunks<I> {
+    type Item = anyhow::Result<LookupDataChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut estimated_chunk_size = CHUNK_OVERHEAD;
+        let mut items = Vec::new();
+        loop {
+            let entry = match self.pending.take().map(Ok).or_else(|| self.entries.next()) {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            };
+            estimated_chunk_size += OVERESTIMATED_DELIMITER_SIZE
+                + ByteUnit::Byte(entry.key.len() as u64)
+                + ByteUnit::Byte(entry.value.len() as u64);
+
+            // If the next element would exceed the maximum chunk size, start a new chunk.
+            if estimated_chunk_size > self.max_chunk_size && !items.is_empty() {
+                self.pending = Some(entry);
+                break;
+            }
+            items.push(entry);
+        }
+        Some(Ok(LookupDataChunk { items }))
+    }
+}
+
+#[cfg(test)]
+fn chunk_up_lookup_data(
+    source_lookup_data: hashbrown::HashMap<Vec<u8>, Vec<u8>>,
+    max_chunk_size: ByteUnit,
+) -> Vec<LookupDataChunk> {
+    let entries =
+        source_lookup_data.into_iter().map(|(key, value)| Ok(LookupDataEntry { key, value }));
+    Chunks::new(entries, max_chunk_size).collect::<anyhow::Result<_>>().unwrap()
 }
 
 #[test]
@@ -186,3 +452,74 @@ fn test_chunk_up_lookup_data_empty() {
     assert_eq!(chunks.len(), 1);
     assert_eq!(chunks[0].items.len(), 0)
 }
+
+#[cfg(test)]
+fn encode_entries(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (key, value) in entries {
+        oak_proto_rust::oak::oak_functions::lookup_data::Entry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+        .encode_length_delimited(&mut buf)
+        .unwrap();
+    }
+    buf
+}
+
+#[test]
+fn test_read_entries() {
+    let buf = encode_entries(&[(b"key1", b"value1"), (b"key2", &[0; 200])]);
+
+    let entries = EntryReader { reader: &buf[..] }.collect::<anyhow::Result<Vec<_>>>().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].key, b"key1");
+    assert_eq!(entries[1].value, vec![0; 200]);
+}
+
+#[test]
+fn test_read_truncated_entries() {
+    let buf = encode_entries(&[(b"key1", b"value1"), (b"key2", b"value2")]);
+
+    let entries = EntryReader { reader: &buf[..buf.len() - 1] }.collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].is_ok());
+    assert!(entries[1].is_err());
+}
+
+#[test]
+fn test_chunk_up_stops_on_error() {
+    let buf = encode_entries(&[(b"key1", b"value1"), (b"key2", b"value2")]);
+
+    let chunks = Chunks::new(EntryReader { reader: &buf[..buf.len() - 1] }, ByteUnit::Kibibyte(1))
+        .collect::<Vec<_>>();
+    assert_eq!(chunks.len(), 1);
+    assert!(chunks[0].is_err());
+}
+
+#[test]
+fn test_read_encrypted_chunks() {
+    let mut buf = Vec::new();
+    for i in 0..2u8 {
+        EncryptedLookupDataChunk { nonce: vec![i; 12], ciphertext: vec![i; 100] }
+            .encode_length_delimited(&mut buf)
+            .unwrap();
+    }
+
+    let chunks =
+        EncryptedChunkReader { reader: &buf[..] }.collect::<anyhow::Result<Vec<_>>>().unwrap();
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(chunks[1].nonce, vec![1; 12]);
+    assert_eq!(chunks[1].ciphertext, vec![1; 100]);
+}
+
+#[test]
+fn test_stats() {
+    let buf = encode_entries(&[(b"key1", b"value1"), (b"key2", &[0; 200])]);
+
+    assert_eq!(
+        stats(&buf[..], false).unwrap(),
+        LookupDataStats { entries: 2, bytes: 4 + 6 + 4 + 200 }
+    );
+    assert!(stats(&buf[..buf.len() - 1], false).is_err());
+}
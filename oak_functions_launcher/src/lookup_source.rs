@@ -0,0 +1,238 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Sources the lookup data can be loaded from.
+
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use reqwest::{header, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+/// Base URL of the GCS JSON API for downloading objects.
+const GCS_DOWNLOAD_URL: &str = "https://storage.googleapis.com/download/storage/v1";
+
+/// Response header containing the generation number of a GCS object.
+const GCS_GENERATION_HEADER: &str = "x-goog-generation";
+
+/// Metadata server endpoint for access tokens of the default service account,
+/// available when running on Google Cloud.
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// How long to wait for the metadata server before assuming that we're not
+/// running on Google Cloud.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where the lookup data is loaded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LookupSource {
+    /// A local file, which is re-read on every update.
+    File(PathBuf),
+    /// An HTTP(S) URL, which is only downloaded again if its ETag changed.
+    Http(String),
+    /// An object in a GCS bucket, which is only downloaded again if its
+    /// generation number changed.
+    Gcs { bucket: String, object: String },
+}
+
+impl FromStr for LookupSource {
+    type Err = String;
+
+    /// Parses `gs://bucket/object`, `http://...` and `https://...` as remote
+    /// sources, and anything else as a local path.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(location) = s.strip_prefix("gs://") {
+            match location.split_once('/') {
+                Some((bucket, object)) if !bucket.is_empty() && !object.is_empty() => {
+                    Ok(LookupSource::Gcs { bucket: bucket.to_string(), object: object.to_string() })
+                }
+                _ => Err(format!("invalid GCS location {}, expected gs://bucket/object", s)),
+            }
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(LookupSource::Http(s.to_string()))
+        } else {
+            Ok(LookupSource::File(PathBuf::from(s)))
+        }
+    }
+}
+
+impl fmt::Display for LookupSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupSource::File(path) => write!(f, "{}", path.display()),
+            LookupSource::Http(url) => write!(f, "{}", url),
+            LookupSource::Gcs { bucket, object } => write!(f, "gs://{}/{}", bucket, object),
+        }
+    }
+}
+
+/// Lookup data fetched from a `LookupSource`.
+pub struct FetchedLookupData {
+    file: FetchedFile,
+    /// Identifies the version of remote lookup data (the ETag or the GCS
+    /// generation number), if available.
+    pub version: Option<String>,
+}
+
+enum FetchedFile {
+    Local(PathBuf),
+    // Downloaded data, removed once the `FetchedLookupData` is dropped.
+    Downloaded(NamedTempFile),
+}
+
+impl FetchedLookupData {
+    /// Returns the path of the file that contains the lookup data.
+    pub fn path(&self) -> &Path {
+        match &self.file {
+            FetchedFile::Local(path) => path,
+            FetchedFile::Downloaded(file) => file.path(),
+        }
+    }
+}
+
+impl LookupSource {
+    /// Fetches the lookup data.
+    pub async fn fetch(&self) -> anyhow::Result<FetchedLookupData> {
+        self.fetch_if_changed(None).await?.context("lookup data source returned no data")
+    }
+
+    /// Fetches the lookup data, unless it is remote and its version still
+    /// matches `current_version`, in which case `None` is returned.
+    pub async fn fetch_if_changed(
+        &self,
+        current_version: Option<&str>,
+    ) -> anyhow::Result<Option<FetchedLookupData>> {
+        match self {
+            LookupSource::File(path) => Ok(Some(FetchedLookupData {
+                file: FetchedFile::Local(path.clone()),
+                version: None,
+            })),
+            LookupSource::Http(url) => {
+                let mut request = reqwest::Client::new().get(url);
+                if let Some(etag) = current_version {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                download(request, header::ETAG.as_str()).await
+            }
+            LookupSource::Gcs { bucket, object } => {
+                let client = reqwest::Client::new();
+                let mut url = format!(
+                    "{}/b/{}/o/{}?alt=media",
+                    GCS_DOWNLOAD_URL,
+                    urlencoding::encode(bucket),
+                    urlencoding::encode(object)
+                );
+                if let Some(generation) = current_version {
+                    url.push_str(&format!("&ifGenerationNotMatch={}", generation));
+                }
+                let mut request = client.get(url);
+                // Public objects can be downloaded without credentials, so we only
+                // authenticate if we can get a token.
+                if let Some(token) = gcs_access_token(&client).await {
+                    request = request.bearer_auth(token);
+                }
+                download(request, GCS_GENERATION_HEADER).await
+            }
+        }
+    }
+}
+
+/// Downloads the response body into a temporary file, unless the server
+/// reports that the data hasn't been modified.
+async fn download(
+    request: RequestBuilder,
+    version_header: &str,
+) -> anyhow::Result<Option<FetchedLookupData>> {
+    let response = request.send().await.context("couldn't request lookup data")?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let mut response = response.error_for_status().context("couldn't download lookup data")?;
+    let version = response
+        .headers()
+        .get(version_header)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    // Write the data to disk as it arrives, so that we never hold all of it in
+    // memory.
+    let mut file = NamedTempFile::new().context("couldn't create temporary file")?;
+    while let Some(chunk) = response.chunk().await.context("couldn't download lookup data")? {
+        file.write_all(&chunk).context("couldn't write lookup data")?;
+    }
+    file.flush().context("couldn't write lookup data")?;
+    log::info!(
+        "downloaded lookup data ({}), version {:?}",
+        ubyte::ByteUnit::Byte(file.as_file().metadata()?.len()),
+        version
+    );
+    Ok(Some(FetchedLookupData { file: FetchedFile::Downloaded(file), version }))
+}
+
+/// Returns an access token for the default service account, if we're running
+/// on Google Cloud.
+async fn gcs_access_token(client: &reqwest::Client) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Token {
+        access_token: String,
+    }
+
+    let response = client
+        .get(METADATA_TOKEN_URL)
+        .header("Metadata-Flavor", "Google")
+        .timeout(METADATA_TIMEOUT)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    response.json::<Token>().await.ok().map(|token| token.access_token)
+}
+
+#[test]
+fn test_parse_lookup_source() {
+    assert_eq!(
+        "/tmp/lookup_data".parse(),
+        Ok(LookupSource::File(PathBuf::from("/tmp/lookup_data")))
+    );
+    assert_eq!(
+        "https://example.com/lookup_data".parse(),
+        Ok(LookupSource::Http("https://example.com/lookup_data".to_string()))
+    );
+    assert_eq!(
+        "gs://bucket/path/to/lookup_data".parse(),
+        Ok(LookupSource::Gcs {
+            bucket: "bucket".to_string(),
+            object: "path/to/lookup_data".to_string()
+        })
+    );
+    assert!("gs://bucket".parse::<LookupSource>().is_err());
+    assert!("gs:///object".parse::<LookupSource>().is_err());
+}
+
+#[test]
+fn test_display_lookup_source() {
+    let source: LookupSource = "gs://bucket/lookup_data".parse().unwrap();
+    assert_eq!(source.to_string(), "gs://bucket/lookup_data");
+}
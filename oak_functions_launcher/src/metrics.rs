@@ -0,0 +1,260 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Prometheus metrics about the requests served by the launcher and the lookup
+//! data refreshes, exported in the text format on `/metrics`.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::LookupDataUpdate;
+
+/// Request type label of requests to invoke the Wasm module.
+pub const INVOKE_REQUEST: &str = "invoke";
+
+/// Request type label of requests to invoke the Wasm module with a batch of
+/// requests.
+pub const INVOKE_BATCH_REQUEST: &str = "invoke_batch";
+
+/// Request type label of requests for the endorsed evidence.
+pub const GET_ENDORSED_EVIDENCE_REQUEST: &str = "get_endorsed_evidence";
+
+/// Request type label of requests to resume a session.
+pub const RESUME_SESSION_REQUEST: &str = "resume_session";
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    invoke_latency: Histogram,
+    invoke_request_size: Histogram,
+    invoke_response_size: Histogram,
+    enclave_errors: IntCounter,
+    lookup_data_updates: IntCounterVec,
+    lookup_data_update_duration: Histogram,
+    lookup_data_memory: IntGauge,
+    lookup_data_memory_budget: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new_custom(Some("oak_functions_launcher".to_string()), None)
+            .expect("couldn't create metrics registry");
+        // Payloads range from a few bytes to the 4 MiB gRPC message limit and beyond.
+        let size_buckets = exponential_buckets(64.0, 4.0, 10).unwrap();
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests received from clients, by request type."),
+            &["type"],
+        )
+        .unwrap();
+        let invoke_latency = Histogram::with_opts(HistogramOpts::new(
+            "invoke_latency_seconds",
+            "Time taken by the enclave to handle invoke requests.",
+        ))
+        .unwrap();
+        let invoke_request_size = Histogram::with_opts(
+            HistogramOpts::new("invoke_request_bytes", "Size of encrypted invoke requests.")
+                .buckets(size_buckets.clone()),
+        )
+        .unwrap();
+        let invoke_response_size = Histogram::with_opts(
+            HistogramOpts::new("invoke_response_bytes", "Size of encrypted invoke responses.")
+                .buckets(size_buckets),
+        )
+        .unwrap();
+        let enclave_errors = IntCounter::new(
+            "enclave_errors_total",
+            "Invoke requests that failed because the enclave couldn't be reached or returned an \
+             error.",
+        )
+        .unwrap();
+        let lookup_data_updates = IntCounterVec::new(
+            Opts::new("lookup_data_updates_total", "Lookup data refreshes, by result."),
+            &["result"],
+        )
+        .unwrap();
+        let lookup_data_update_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "lookup_data_update_duration_seconds",
+                "Time taken by lookup data refreshes, including retries.",
+            )
+            .buckets(exponential_buckets(0.1, 2.0, 12).unwrap()),
+        )
+        .unwrap();
+        let lookup_data_memory = IntGauge::new(
+            "lookup_data_memory_bytes",
+            "Memory used by the lookup data in the enclave, as of the last refresh.",
+        )
+        .unwrap();
+        let lookup_data_memory_budget = IntGauge::new(
+            "lookup_data_memory_budget_bytes",
+            "Memory budget of the lookup data in the enclave, or 0 if unlimited.",
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(invoke_latency.clone())).unwrap();
+        registry.register(Box::new(invoke_request_size.clone())).unwrap();
+        registry.register(Box::new(invoke_response_size.clone())).unwrap();
+        registry.register(Box::new(enclave_errors.clone())).unwrap();
+        registry.register(Box::new(lookup_data_updates.clone())).unwrap();
+        registry.register(Box::new(lookup_data_update_duration.clone())).unwrap();
+        registry.register(Box::new(lookup_data_memory.clone())).unwrap();
+        registry.register(Box::new(lookup_data_memory_budget.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            invoke_latency,
+            invoke_request_size,
+            invoke_response_size,
+            enclave_errors,
+            lookup_data_updates,
+            lookup_data_update_duration,
+            lookup_data_memory,
+            lookup_data_memory_budget,
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a request received from a client.
+    pub fn observe_request(&self, request_type: &str) {
+        self.requests.with_label_values(&[request_type]).inc();
+    }
+
+    /// Records an invoke request that the enclave handled successfully.
+    pub fn observe_invoke(&self, request_size: usize, response_size: usize, latency: Duration) {
+        self.invoke_request_size.observe(request_size as f64);
+        self.invoke_response_size.observe(response_size as f64);
+        self.invoke_latency.observe(latency.as_secs_f64());
+    }
+
+    /// Records an invoke request that the enclave failed to handle.
+    pub fn observe_enclave_error(&self) {
+        self.enclave_errors.inc();
+    }
+
+    /// Records the outcome of a lookup data refresh.
+    pub fn observe_lookup_data_update(&self, update: &LookupDataUpdate) {
+        let result = if update.result.is_ok() { "success" } else { "failure" };
+        self.lookup_data_updates.with_label_values(&[result]).inc();
+        self.lookup_data_update_duration.observe(update.duration.as_secs_f64());
+        if let Some(memory_usage) = update.memory_usage.as_ref() {
+            self.lookup_data_memory.set(memory_usage.active_bytes as i64);
+            self.lookup_data_memory_budget.set(memory_usage.budget_bytes as i64);
+        }
+    }
+
+    /// Spawns a task that records every lookup data refresh reported by
+    /// `updates`, including the current one. Abort the task once the enclave
+    /// sending the updates is gone.
+    pub fn watch_lookup_data(
+        self: &Arc<Self>,
+        mut updates: watch::Receiver<LookupDataUpdate>,
+    ) -> JoinHandle<()> {
+        let metrics = self.clone();
+        tokio::spawn(async move {
+            metrics.observe_lookup_data_update(&updates.borrow_and_update());
+            while updates.changed().await.is_ok() {
+                metrics.observe_lookup_data_update(&updates.borrow_and_update());
+            }
+        })
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Serves the metrics on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = handle(&metrics, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    log::info!("metrics server listening on {}", addr);
+    server.await
+}
+
+fn handle(metrics: &Metrics, request: &Request<Body>) -> Response<Body> {
+    let mut response = Response::default();
+    if (request.method(), request.uri().path()) != (&Method::GET, "/metrics") {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    match metrics.encode() {
+        Ok(body) => {
+            *response.body_mut() = Body::from(body);
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(prometheus::TEXT_FORMAT));
+        }
+        Err(err) => {
+            log::error!("couldn't encode metrics: {:?}", err);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    response
+}
+
+#[test]
+fn test_encode_metrics() {
+    let metrics = Metrics::default();
+    metrics.observe_request(INVOKE_REQUEST);
+    metrics.observe_invoke(100, 200, Duration::from_millis(5));
+    metrics.observe_enclave_error();
+    metrics.observe_lookup_data_update(&LookupDataUpdate {
+        attempts: 1,
+        changed: true,
+        duration: Duration::from_secs(1),
+        result: Ok(()),
+        memory_usage: Some(crate::proto::oak::functions::LookupDataMemoryUsage {
+            active_bytes: 1024,
+            next_bytes: 0,
+            budget_bytes: 4096,
+        }),
+    });
+
+    let encoded = String::from_utf8(metrics.encode().unwrap()).unwrap();
+    assert!(encoded.contains("oak_functions_launcher_requests_total{type=\"invoke\"} 1"));
+    assert!(encoded.contains("oak_functions_launcher_invoke_latency_seconds_count 1"));
+    assert!(encoded.contains("oak_functions_launcher_enclave_errors_total 1"));
+    assert!(
+        encoded.contains("oak_functions_launcher_lookup_data_updates_total{result=\"success\"} 1")
+    );
+    assert!(encoded.contains("oak_functions_launcher_lookup_data_memory_bytes 1024"));
+    assert!(encoded.contains("oak_functions_launcher_lookup_data_memory_budget_bytes 4096"));
+}
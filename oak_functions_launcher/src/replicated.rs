@@ -0,0 +1,537 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Runs several replicas of the Oak Functions enclave behind one endpoint.
+//!
+//! Every replica is launched with the same Wasm module and lookup data, but has
+//! its own evidence, so a client session is pinned to the replica it was
+//! routed to. Each replica is supervised by a task that health-checks it and
+//! relaunches it if the VMM exits or stops responding.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use oak_launcher_utils::{channel::ConnectorHandle, launcher};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    check_evidence,
+    health::HealthState,
+    metrics::Metrics,
+    proto::oak::{
+        attestation::v1::{AppraisalPolicy, Evidence},
+        functions::{OakFunctionsAsyncClient, ReserveRequest},
+    },
+    secret_provisioning::SecretProvisioner,
+    server::{
+        resumption::{evidence_digest, EvidenceDigest},
+        SessionRouter, SessionTarget,
+    },
+    LookupDataConfig, LookupDataHandle, ShutdownStatus, WasmLimits,
+};
+
+/// Settings of a [`ReplicatedLauncher`].
+pub struct ReplicatedConfig {
+    /// Number of replicas to run.
+    pub replicas: usize,
+    /// Interval between health checks of each replica.
+    pub health_check_interval: Duration,
+    /// How long a replica may take to respond to a health check.
+    pub health_check_timeout: Duration,
+    /// Number of consecutive failed health checks after which a replica is
+    /// relaunched.
+    pub max_failed_health_checks: u32,
+    /// Delay before relaunching a replica that exited or failed to launch.
+    pub restart_backoff: Duration,
+    /// How long to wait for a replica to exit on shutdown before killing it.
+    pub shutdown_timeout: Duration,
+    /// Updated as replicas are launched, taken down and refresh their lookup
+    /// data. It is only ready while at least one replica is healthy.
+    pub health: Arc<HealthState>,
+    /// Records the lookup data refreshes of all replicas.
+    pub metrics: Arc<Metrics>,
+    /// Replicas whose evidence doesn't satisfy the policy are relaunched.
+    pub attestation_policy: Option<AppraisalPolicy>,
+    /// Passed to every replica, see [`crate::Args::wasm_limits`].
+    pub wasm_limits: WasmLimits,
+    /// Passed to every replica, see [`crate::Args::peer_attestation_policy`].
+    pub peer_attestation_policy: Option<AppraisalPolicy>,
+    /// Provisions the secrets of every replica before it is served.
+    pub secret_provisioner: Option<SecretProvisioner>,
+}
+
+/// What a replica needs to be launched.
+struct LaunchSettings {
+    params: launcher::Params,
+    lookup_data_config: LookupDataConfig,
+    wasm_path: PathBuf,
+    constant_response_size: Option<u32>,
+}
+
+// A launched replica that passed the attestation policy.
+struct Replica {
+    guest_instance: Box<dyn launcher::GuestInstance>,
+    connector_handle: ConnectorHandle,
+    evidence: Evidence,
+    lookup_data_handle: LookupDataHandle,
+}
+
+// Launches replicas and checks their health, so that the supervision of the
+// replicas can be tested without a VMM.
+#[async_trait::async_trait]
+trait LaunchReplica: Send + Sync + 'static {
+    async fn launch(&self, config: &ReplicatedConfig) -> anyhow::Result<Replica>;
+
+    async fn check_health(&self, connector_handle: &ConnectorHandle, timeout: Duration) -> bool;
+}
+
+#[async_trait::async_trait]
+impl LaunchReplica for LaunchSettings {
+    async fn launch(&self, config: &ReplicatedConfig) -> anyhow::Result<Replica> {
+        let (guest_instance, connector_handle, initialize_response, lookup_data_handle) =
+            crate::create(
+                self.params.clone(),
+                self.lookup_data_config.clone(),
+                self.wasm_path.clone(),
+                self.constant_response_size,
+                config.wasm_limits,
+                // Replicas would each track their own budget, so they don't support one.
+                None,
+                config.peer_attestation_policy.clone(),
+                config.secret_provisioner.as_ref(),
+                // Replicas would each save their own state to the same file.
+                None,
+            )
+            .await?;
+        let evidence =
+            initialize_response.evidence.ok_or_else(|| anyhow!("no evidence provided"))?;
+        if let Some(policy) = config.attestation_policy.as_ref() {
+            check_evidence(policy, &evidence)?;
+        }
+        Ok(Replica { guest_instance, connector_handle, evidence, lookup_data_handle })
+    }
+
+    async fn check_health(&self, connector_handle: &ConnectorHandle, timeout: Duration) -> bool {
+        check_health(connector_handle, timeout).await
+    }
+}
+
+/// Launches and supervises a set of enclave replicas and spreads client
+/// sessions across the healthy ones in round-robin order.
+pub struct ReplicatedLauncher {
+    // The current session target of each replica, `None` while it is not healthy.
+    replicas: Vec<watch::Sender<Option<SessionTarget>>>,
+    next_replica: AtomicUsize,
+    stop: watch::Sender<bool>,
+    supervisors: Mutex<Vec<JoinHandle<Option<ShutdownStatus>>>>,
+}
+
+impl ReplicatedLauncher {
+    /// Starts launching the replicas in the background. Use
+    /// [`ReplicatedLauncher::wait_until_healthy`] to wait for them to come up.
+    pub fn launch(
+        params: launcher::Params,
+        lookup_data_config: LookupDataConfig,
+        wasm_path: PathBuf,
+        constant_response_size: Option<u32>,
+        config: ReplicatedConfig,
+    ) -> Self {
+        let settings =
+            LaunchSettings { params, lookup_data_config, wasm_path, constant_response_size };
+        Self::start(Arc::new(settings), config)
+    }
+
+    fn start(launch: Arc<dyn LaunchReplica>, config: ReplicatedConfig) -> Self {
+        log::info!("launching {} Oak Functions replicas", config.replicas);
+        let replicas: Vec<_> = (0..config.replicas).map(|_| watch::channel(None).0).collect();
+        let (stop, _) = watch::channel(false);
+        let config = Arc::new(config);
+        let supervisors = replicas
+            .iter()
+            .enumerate()
+            .map(|(index, replica)| {
+                tokio::spawn(supervise(
+                    index,
+                    replica.clone(),
+                    launch.clone(),
+                    config.clone(),
+                    stop.subscribe(),
+                ))
+            })
+            .collect();
+        Self {
+            replicas,
+            next_replica: AtomicUsize::new(0),
+            stop,
+            supervisors: Mutex::new(supervisors),
+        }
+    }
+
+    /// Waits until every replica is healthy.
+    pub async fn wait_until_healthy(&self) {
+        for replica in &self.replicas {
+            // The sender lives as long as `self`, so this can't fail.
+            let _ = replica.subscribe().wait_for(Option::is_some).await;
+        }
+    }
+
+    /// Returns the number of replicas that are currently healthy.
+    pub fn healthy_replicas(&self) -> usize {
+        self.replicas.iter().filter(|replica| replica.borrow().is_some()).count()
+    }
+
+    /// Shuts down all replicas and returns how each of them was shut down, or
+    /// `None` for replicas that weren't running at the time. Takes `&self`, as
+    /// the launcher is shared with the server that routes sessions to it.
+    pub async fn shutdown(&self) -> Vec<Option<ShutdownStatus>> {
+        log::info!("shutting down Oak Functions replicas");
+        self.stop.send_replace(true);
+        let supervisors = std::mem::take(&mut *self.supervisors.lock().unwrap());
+        let mut statuses = Vec::with_capacity(supervisors.len());
+        for supervisor in supervisors {
+            statuses.push(supervisor.await.unwrap_or_else(|err| {
+                log::error!("replica supervisor failed: {:?}", err);
+                None
+            }));
+        }
+        statuses
+    }
+}
+
+impl SessionRouter for ReplicatedLauncher {
+    fn route(&self) -> Option<SessionTarget> {
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        (0..self.replicas.len())
+            .map(|offset| &self.replicas[(start + offset) % self.replicas.len()])
+            .find_map(|replica| replica.borrow().clone())
+    }
+
+    fn route_to(&self, digest: &EvidenceDigest) -> Option<SessionTarget> {
+        self.replicas.iter().find_map(|replica| {
+            replica.borrow().clone().filter(|target| evidence_digest(&target.evidence) == *digest)
+        })
+    }
+}
+
+// Why a running replica stopped being supervised.
+enum Outcome {
+    Exited,
+    Unhealthy,
+    Stopped,
+}
+
+// Launches a replica and keeps it running until `stop` is set.
+async fn supervise(
+    index: usize,
+    replica: watch::Sender<Option<SessionTarget>>,
+    launch: Arc<dyn LaunchReplica>,
+    config: Arc<ReplicatedConfig>,
+    mut stop: watch::Receiver<bool>,
+) -> Option<ShutdownStatus> {
+    loop {
+        if *stop.borrow() {
+            return None;
+        }
+        log::info!("launching replica {}", index);
+        let Replica { mut guest_instance, connector_handle, evidence, lookup_data_handle } =
+            match launch.launch(&config).await {
+                Ok(launched) => launched,
+                Err(err) => {
+                    log::error!("couldn't launch replica {}: {:?}", index, err);
+                    if wait_or_stop(config.restart_backoff, &mut stop).await {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+        config.health.set_wasm_initialized();
+        config.health.set_evidence_obtained();
+        // Only record the refreshes of this launch of the replica.
+        let watchers = [
+            config.health.watch_lookup_data(lookup_data_handle.subscribe()),
+            config.metrics.watch_lookup_data(lookup_data_handle.subscribe()),
+        ];
+        replica.send_replace(Some(SessionTarget {
+            connector_handle: connector_handle.clone(),
+            evidence,
+        }));
+        config.health.add_healthy_replica();
+        log::info!("replica {} is healthy", index);
+
+        let mut health_checks = tokio::time::interval(config.health_check_interval);
+        let mut failed_health_checks = 0;
+        let outcome = loop {
+            tokio::select! {
+                status = guest_instance.wait() => {
+                    log::error!("replica {} exited unexpectedly: {:?}", index, status);
+                    break Outcome::Exited;
+                }
+                _ = health_checks.tick() => {
+                    if launch.check_health(&connector_handle, config.health_check_timeout).await {
+                        failed_health_checks = 0;
+                        continue;
+                    }
+                    failed_health_checks += 1;
+                    log::warn!("replica {} failed {} health checks", index, failed_health_checks);
+                    if failed_health_checks >= config.max_failed_health_checks {
+                        break Outcome::Unhealthy;
+                    }
+                }
+                _ = stop.changed() => break Outcome::Stopped,
+            }
+        };
+
+        // Stop routing new sessions to the replica before taking it down.
+        replica.send_replace(None);
+        config.health.remove_healthy_replica();
+        watchers.iter().for_each(JoinHandle::abort);
+        match outcome {
+            Outcome::Stopped => {
+                return crate::shutdown(
+                    guest_instance,
+                    connector_handle,
+                    &lookup_data_handle,
+                    config.shutdown_timeout,
+                )
+                .await
+                .map_err(|err| log::error!("couldn't shut down replica {}: {:?}", index, err))
+                .ok();
+            }
+            Outcome::Unhealthy => {
+                lookup_data_handle.stop();
+                if let Err(err) = guest_instance.kill().await {
+                    log::error!("couldn't kill replica {}: {:?}", index, err);
+                }
+            }
+            Outcome::Exited => lookup_data_handle.stop(),
+        }
+        if wait_or_stop(config.restart_backoff, &mut stop).await {
+            return None;
+        }
+    }
+}
+
+// Checks that the enclave still handles requests, using a request that doesn't
+// change its state.
+async fn check_health(connector_handle: &ConnectorHandle, timeout: Duration) -> bool {
+    let mut client = OakFunctionsAsyncClient::new(connector_handle.clone());
+    matches!(
+        client.reserve_with_deadline(&ReserveRequest { additional_entries: 0 }, timeout).await,
+        Ok(Ok(_))
+    )
+}
+
+// Waits for `delay`, returning early with true if the replicas are being shut
+// down.
+async fn wait_or_stop(delay: Duration, stop: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => {}
+        _ = stop.wait_for(|stop| *stop) => return true,
+    }
+    *stop.borrow()
+}
+
+#[cfg(test)]
+const TEST_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+#[cfg(test)]
+fn test_config(replicas: usize) -> ReplicatedConfig {
+    ReplicatedConfig {
+        replicas,
+        health_check_interval: Duration::from_secs(10),
+        health_check_timeout: Duration::from_secs(1),
+        max_failed_health_checks: 3,
+        restart_backoff: TEST_RESTART_BACKOFF,
+        shutdown_timeout: Duration::from_secs(1),
+        health: Arc::new(HealthState::default()),
+        metrics: Arc::new(crate::metrics::Metrics::default()),
+        attestation_policy: None,
+        wasm_limits: WasmLimits::default(),
+        peer_attestation_policy: None,
+        secret_provisioner: None,
+    }
+}
+
+// A guest that exits once the sender matching `exited` is dropped.
+#[cfg(test)]
+struct FakeGuest {
+    exited: tokio::sync::oneshot::Receiver<()>,
+    kills: Arc<AtomicUsize>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl launcher::GuestInstance for FakeGuest {
+    async fn wait(&mut self) -> anyhow::Result<std::process::ExitStatus> {
+        let _ = (&mut self.exited).await;
+        Ok(std::process::ExitStatus::default())
+    }
+
+    async fn kill(self: Box<Self>) -> anyhow::Result<std::process::ExitStatus> {
+        self.kills.fetch_add(1, Ordering::SeqCst);
+        Ok(std::process::ExitStatus::default())
+    }
+
+    async fn connect(&self) -> anyhow::Result<Box<dyn oak_channel::Channel>> {
+        anyhow::bail!("the fake guest can't be connected to")
+    }
+}
+
+// Launches fake guests, whose health is set by the test.
+#[cfg(test)]
+#[derive(Default)]
+struct FakeLauncher {
+    launchable: std::sync::atomic::AtomicBool,
+    healthy: std::sync::atomic::AtomicBool,
+    launches: AtomicUsize,
+    kills: Arc<AtomicUsize>,
+    // Dropping one of these makes the guest of the matching launch exit.
+    exits: Mutex<Vec<tokio::sync::oneshot::Sender<()>>>,
+}
+
+#[cfg(test)]
+impl FakeLauncher {
+    fn new(launchable: bool, healthy: bool) -> Arc<Self> {
+        let launcher = Self::default();
+        launcher.launchable.store(launchable, Ordering::SeqCst);
+        launcher.healthy.store(healthy, Ordering::SeqCst);
+        Arc::new(launcher)
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl LaunchReplica for FakeLauncher {
+    async fn launch(&self, _config: &ReplicatedConfig) -> anyhow::Result<Replica> {
+        self.launches.fetch_add(1, Ordering::SeqCst);
+        if !self.launchable.load(Ordering::SeqCst) {
+            anyhow::bail!("couldn't start the VMM");
+        }
+        let (exit, exited) = tokio::sync::oneshot::channel();
+        self.exits.lock().unwrap().push(exit);
+        let (launcher_socket, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let connector_handle = oak_launcher_utils::channel::Connector::spawn(
+            Box::new(launcher_socket.try_clone().unwrap()),
+            Box::new(launcher_socket),
+            Default::default(),
+        );
+        let (_, updates) = watch::channel(crate::LookupDataUpdate {
+            attempts: 1,
+            changed: true,
+            duration: Duration::ZERO,
+            result: Ok(()),
+            memory_usage: None,
+        });
+        let lookup_data_handle = LookupDataHandle {
+            reload_requests: tokio::sync::mpsc::channel(1).0,
+            updates,
+            refresher: tokio::spawn(std::future::pending::<()>()).abort_handle(),
+        };
+        Ok(Replica {
+            guest_instance: Box::new(FakeGuest { exited, kills: self.kills.clone() }),
+            connector_handle,
+            evidence: Evidence::default(),
+            lookup_data_handle,
+        })
+    }
+
+    async fn check_health(&self, _connector_handle: &ConnectorHandle, _timeout: Duration) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_route_fails_without_healthy_replicas() {
+    let fake = FakeLauncher::new(false, true);
+    let config = test_config(2);
+    let health = config.health.clone();
+    let launcher = ReplicatedLauncher::start(fake.clone(), config);
+
+    // Every launch fails, so no replica is ever routed to.
+    tokio::time::sleep(TEST_RESTART_BACKOFF * 3).await;
+    assert!(fake.launches.load(Ordering::SeqCst) >= 4);
+    assert_eq!(launcher.healthy_replicas(), 0);
+    assert!(launcher.route().is_none());
+    assert!(!health.report().ready);
+
+    fake.launchable.store(true, Ordering::SeqCst);
+    launcher.wait_until_healthy().await;
+    assert!(launcher.route().is_some());
+    assert!(health.report().ready);
+
+    // Once all replicas are down again, readiness is withdrawn.
+    fake.launchable.store(false, Ordering::SeqCst);
+    fake.exits.lock().unwrap().clear();
+    tokio::time::sleep(TEST_RESTART_BACKOFF / 2).await;
+    assert_eq!(launcher.healthy_replicas(), 0);
+    assert!(launcher.route().is_none());
+    let report = health.report();
+    assert_eq!(report.healthy_replicas, Some(0));
+    assert!(!report.ready);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_exited_replica_is_restarted() {
+    let fake = FakeLauncher::new(true, true);
+    let config = test_config(1);
+    let health = config.health.clone();
+    let launcher = ReplicatedLauncher::start(fake.clone(), config);
+    launcher.wait_until_healthy().await;
+    assert_eq!(fake.launches.load(Ordering::SeqCst), 1);
+
+    fake.exits.lock().unwrap().clear();
+    tokio::time::sleep(TEST_RESTART_BACKOFF / 2).await;
+    assert!(launcher.route().is_none());
+    assert!(!health.report().ready);
+
+    // The replica is relaunched after the backoff.
+    launcher.wait_until_healthy().await;
+    assert_eq!(fake.launches.load(Ordering::SeqCst), 2);
+    assert!(launcher.route().is_some());
+    assert_eq!(health.report().healthy_replicas, Some(1));
+    assert!(health.report().ready);
+    assert_eq!(fake.kills.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_unhealthy_replica_is_evicted_and_relaunched() {
+    let fake = FakeLauncher::new(true, false);
+    let launcher = ReplicatedLauncher::start(fake.clone(), test_config(1));
+    launcher.wait_until_healthy().await;
+
+    // Health checks fail at 0s and 10s, which is below the limit of 3.
+    tokio::time::sleep(Duration::from_secs(15)).await;
+    assert!(launcher.route().is_some());
+    assert_eq!(fake.kills.load(Ordering::SeqCst), 0);
+
+    // The third failure, at 20s, evicts and kills the replica.
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert!(launcher.route().is_none());
+    assert_eq!(fake.kills.load(Ordering::SeqCst), 1);
+    assert_eq!(fake.launches.load(Ordering::SeqCst), 1);
+
+    fake.healthy.store(true, Ordering::SeqCst);
+    launcher.wait_until_healthy().await;
+    assert_eq!(fake.launches.load(Ordering::SeqCst), 2);
+    assert!(launcher.route().is_some());
+}
@@ -0,0 +1,464 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Runs the launcher with its parsed arguments, so that the launcher binary
+//! and the unified `oak_launcher` binary behave the same way.
+
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use oak_launcher_utils::{
+    channel::ConnectorHandle,
+    error::LauncherError,
+    launcher::{self, RestartPolicy, Supervisor},
+};
+use oak_proto_rust::oak::attestation::v1::{
+    endorsements, Endorsements, OakRestrictedKernelEndorsements,
+};
+use prost::Message;
+use tokio::{signal, task::JoinHandle};
+use tonic::transport::Endpoint;
+use ubyte::ByteUnit;
+
+use crate::{
+    health::HealthState,
+    metrics::Metrics,
+    proto::oak::functions::config::ApplicationConfig,
+    replicated::{ReplicatedConfig, ReplicatedLauncher},
+    server::{SessionPolicy, SessionRouter, SessionTarget},
+    tls::TlsConfig,
+    Args, GuestConfig, RestrictedKernelArgs,
+};
+
+/// How long to wait for the VMM to exit after asking the enclave to terminate.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between health checks of each replica, if running several.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a replica may take to respond to a health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of consecutive failed health checks after which a replica is
+/// relaunched.
+const MAX_FAILED_HEALTH_CHECKS: u32 = 3;
+
+/// Delay before relaunching a replica that exited or failed to launch. Also the
+/// initial delay before restarting a crashed enclave, if enabled.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between consecutive restarts of a crashed enclave.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Launches the enclave on the Restricted Kernel with `launcher_params`, and
+/// serves it as configured by `functions_params` and
+/// `restricted_kernel_params` until the launcher is interrupted or the VMM
+/// exits.
+pub async fn run(
+    mut launcher_params: launcher::Params,
+    functions_params: Args,
+    restricted_kernel_params: RestrictedKernelArgs,
+) -> Result<(), LauncherError> {
+    // Each replica would consume its own privacy budget.
+    if restricted_kernel_params.replicas > 1 && functions_params.privacy_budget_epsilon.is_some() {
+        return Err(LauncherError::Config(anyhow!(
+            "--privacy-budget-epsilon isn't supported with several replicas"
+        )));
+    }
+
+    // Only the Oak Containers launcher starts enclaves that serve several tenants.
+    let wasm = functions_params
+        .wasm
+        .clone()
+        .ok_or_else(|| LauncherError::Config(anyhow!("--wasm is required")))?;
+    let sealed_state_path = restricted_kernel_params.sealed_state.clone();
+
+    // Fix the maximum size of a chunk to the proto limit size of 2 GiB.
+    let lookup_data_config = restricted_kernel_params
+        .lookup_data_config(&functions_params, ByteUnit::Gibibyte(2))
+        .map_err(LauncherError::Config)?;
+
+    // The Restricted Kernel measures the application config into the evidence,
+    // so clients can check the time granularity and the lookup data signing key.
+    launcher_params.application_config = ApplicationConfig {
+        time_granularity_seconds: functions_params.time_granularity_seconds.unwrap_or(0),
+        lookup_data_signing_public_key: lookup_data_config.signing_public_key(),
+        ..Default::default()
+    }
+    .encode_to_vec();
+
+    if functions_params.validate.validate_only {
+        let mut images = vec![
+            ("stage0".to_string(), launcher_params.bios_binary.clone()),
+            ("kernel".to_string(), launcher_params.kernel.clone()),
+            ("initrd".to_string(), launcher_params.initrd.clone()),
+        ];
+        images.extend(launcher_params.app_binary.clone().map(|path| ("app".to_string(), path)));
+        images.extend(
+            launcher_params
+                .app_bundle
+                .iter()
+                .map(|(name, path)| (format!("app-{}", name), path.clone())),
+        );
+        let report =
+            crate::validate::validate(&functions_params, &[], &images, &lookup_data_config).await;
+        return report.print().map_err(LauncherError::Config);
+    }
+
+    // Start the health server first, so that the launcher is reported as live (but
+    // not ready) while the enclave is being set up.
+    let health = Arc::new(HealthState::default());
+    if let Some(health_port) = functions_params.health_port {
+        let health_server = crate::health::serve(
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, health_port)),
+            health.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = health_server.await {
+                log::error!("health server failed: {:?}", err);
+            }
+        });
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(metrics_port) = functions_params.metrics_port {
+        let metrics_server = crate::metrics::serve(
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, metrics_port)),
+            metrics.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = metrics_server.await {
+                log::error!("metrics server failed: {:?}", err);
+            }
+        });
+    }
+
+    // Initialize attestation endorsements.
+    // TODO(#4074): Add layer endorsements.
+    let oak_restricted_kernel_endorsements = OakRestrictedKernelEndorsements {
+        root_layer: None,
+        kernel_layer: None,
+        application_layer: None,
+    };
+    let endorsements = Endorsements {
+        r#type: Some(endorsements::Type::OakRestrictedKernel(oak_restricted_kernel_endorsements)),
+    };
+
+    let attestation_policy =
+        restricted_kernel_params.attestation_policy().map_err(LauncherError::Config)?;
+    let peer_attestation_policy =
+        functions_params.peer_attestation_policy().map_err(LauncherError::Config)?;
+    let secret_provisioner =
+        restricted_kernel_params.secret_provisioner().map_err(LauncherError::Config)?;
+
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, functions_params.port));
+    let tls = restricted_kernel_params.tls.load().map_err(LauncherError::Config)?;
+    let relay = restricted_kernel_params.relay.load().map_err(LauncherError::Config)?;
+    let policy = SessionPolicy {
+        rate_limiter: restricted_kernel_params.rate_limit.load().map_err(LauncherError::Config)?,
+        size_limits: restricted_kernel_params.size_limits,
+        session_tickets: restricted_kernel_params.resumption.load(),
+    };
+
+    if restricted_kernel_params.replicas > 1 {
+        let config = ReplicatedConfig {
+            replicas: restricted_kernel_params.replicas,
+            health_check_interval: HEALTH_CHECK_INTERVAL,
+            health_check_timeout: HEALTH_CHECK_TIMEOUT,
+            max_failed_health_checks: MAX_FAILED_HEALTH_CHECKS,
+            restart_backoff: RESTART_BACKOFF,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            health,
+            metrics: metrics.clone(),
+            attestation_policy,
+            wasm_limits: functions_params.wasm_limits(),
+            peer_attestation_policy,
+            secret_provisioner,
+        };
+        let launcher = Arc::new(ReplicatedLauncher::launch(
+            launcher_params,
+            lookup_data_config,
+            wasm,
+            functions_params.constant_response_size,
+            config,
+        ));
+        launcher.wait_until_healthy().await;
+
+        spawn_gateway(
+            restricted_kernel_params.gateway_port,
+            launcher.clone(),
+            &endorsements,
+            &metrics,
+            tls.as_ref(),
+            policy.clone(),
+        );
+        spawn_relay(relay.clone(), launcher.clone(), &endorsements, &metrics, policy.clone());
+        let server_future = crate::server::new_routed(
+            addr,
+            launcher.clone(),
+            endorsements,
+            metrics,
+            tls.as_ref(),
+            policy.clone(),
+        );
+
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                log::info!("Ctrl-C received, terminating replicas");
+            },
+            _ = server_future => {
+                log::info!("server terminated, terminating replicas");
+            },
+        }
+
+        let statuses = launcher.shutdown().await;
+        log::info!("replicas terminated: {:?}", statuses);
+        return Ok(());
+    }
+
+    if let Some(max_restarts) = restricted_kernel_params.max_restarts {
+        let guest_config = GuestConfig {
+            lookup_data_config,
+            wasm_path: wasm,
+            application_config: launcher_params.application_config.clone(),
+            constant_response_size: functions_params.constant_response_size,
+            wasm_limits: functions_params.wasm_limits(),
+            privacy_budget_epsilon: functions_params.privacy_budget_epsilon,
+            peer_attestation_policy,
+            attestation_policy,
+            secret_provisioner,
+            sealed_state_path: sealed_state_path.clone(),
+        };
+        let policy = RestartPolicy {
+            max_restarts: Some(max_restarts),
+            initial_backoff: RESTART_BACKOFF,
+            max_backoff: MAX_RESTART_BACKOFF,
+        };
+        let supervisor = Supervisor::start(launcher_params, guest_config, policy);
+
+        // Track the health and lookup data of every instance the supervisor launches.
+        let mut running = supervisor.subscribe();
+        let (health, task_metrics) = (health.clone(), metrics.clone());
+        tokio::spawn(async move {
+            let mut watchers: Vec<JoinHandle<()>> = Vec::new();
+            while running.changed().await.is_ok() {
+                let guest = running.borrow_and_update().clone();
+                // Stop recording the refreshes of the previous instance.
+                watchers.drain(..).for_each(|watcher| watcher.abort());
+                if let Some(guest) = guest {
+                    health.set_wasm_initialized();
+                    health.set_evidence_obtained();
+                    watchers = vec![
+                        health.watch_lookup_data(guest.setup.lookup_data_handle.subscribe()),
+                        task_metrics.watch_lookup_data(guest.setup.lookup_data_handle.subscribe()),
+                    ];
+                }
+            }
+        });
+        supervisor.wait_until_running().await.map_err(LauncherError::Vmm)?;
+
+        spawn_gateway(
+            restricted_kernel_params.gateway_port,
+            Arc::new(supervisor.subscribe()),
+            &endorsements,
+            &metrics,
+            tls.as_ref(),
+            policy.clone(),
+        );
+        spawn_relay(
+            relay.clone(),
+            Arc::new(supervisor.subscribe()),
+            &endorsements,
+            &metrics,
+            policy.clone(),
+        );
+        let server_future = crate::server::new_routed(
+            addr,
+            Arc::new(supervisor.subscribe()),
+            endorsements,
+            metrics,
+            tls.as_ref(),
+            policy.clone(),
+        );
+
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                log::info!("Ctrl-C received, terminating VMM");
+            },
+            _ = server_future => {
+                log::info!("server terminated, terminating VMM");
+            },
+            _ = supervisor.given_up() => {},
+        }
+
+        log::info!("enclave was restarted {} times", supervisor.restart_count());
+        if let Some((guest_instance, guest)) =
+            supervisor.stop().await.map_err(LauncherError::Vmm)?
+        {
+            save_sealed_state(&guest.connector_handle, sealed_state_path.as_deref()).await;
+            let status = crate::shutdown(
+                guest_instance,
+                guest.connector_handle,
+                &guest.setup.lookup_data_handle,
+                SHUTDOWN_TIMEOUT,
+            )
+            .await?;
+            log::info!("VMM terminated: {:?}", status);
+        }
+        return Ok(());
+    }
+
+    let (mut launched_instance, connector_handle, initialize_response, lookup_data_handle) =
+        crate::create(
+            launcher_params,
+            lookup_data_config,
+            wasm,
+            functions_params.constant_response_size,
+            functions_params.wasm_limits(),
+            functions_params.privacy_budget_epsilon,
+            peer_attestation_policy,
+            secret_provisioner.as_ref(),
+            sealed_state_path.as_deref(),
+        )
+        .await?;
+    health.set_wasm_initialized();
+    health.watch_lookup_data(lookup_data_handle.subscribe());
+    metrics.watch_lookup_data(lookup_data_handle.subscribe());
+
+    let evidence =
+        initialize_response.evidence.expect("no evidence provided in the initialize response");
+    if let Some(policy) = attestation_policy.as_ref() {
+        if let Err(err) = crate::check_evidence(policy, &evidence) {
+            crate::shutdown(
+                launched_instance,
+                connector_handle,
+                &lookup_data_handle,
+                SHUTDOWN_TIMEOUT,
+            )
+            .await?;
+            return Err(err);
+        }
+    }
+    health.set_evidence_obtained();
+
+    let target: Arc<dyn SessionRouter> = Arc::new(SessionTarget {
+        connector_handle: connector_handle.clone(),
+        evidence: evidence.clone(),
+    });
+    spawn_gateway(
+        restricted_kernel_params.gateway_port,
+        target.clone(),
+        &endorsements,
+        &metrics,
+        tls.as_ref(),
+        policy.clone(),
+    );
+    spawn_relay(relay, target, &endorsements, &metrics, policy.clone());
+    let server_future = crate::server::new(
+        addr,
+        connector_handle.clone(),
+        evidence,
+        endorsements,
+        metrics,
+        tls.as_ref(),
+        policy.clone(),
+    );
+
+    // Wait until something dies or we get a signal to terminate.
+    tokio::select! {
+        _ = signal::ctrl_c() => {
+            log::info!("Ctrl-C received, terminating VMM");
+        },
+        _ = server_future => {
+            log::info!("server terminated, terminating VMM");
+        },
+        val = launched_instance.wait() => {
+            log::error!("Unexpected VMM exit, status: {:?}", val);
+            return Ok(());
+        },
+    }
+
+    save_sealed_state(&connector_handle, sealed_state_path.as_deref()).await;
+    let status =
+        crate::shutdown(launched_instance, connector_handle, &lookup_data_handle, SHUTDOWN_TIMEOUT)
+            .await?;
+    log::info!("VMM terminated: {:?}", status);
+
+    Ok(())
+}
+
+// Saves the sealed state of the enclave before it is shut down, if enabled.
+// Failing to save it doesn't stop the shutdown, as the previous state remains.
+async fn save_sealed_state(connector_handle: &ConnectorHandle, path: Option<&Path>) {
+    let Some(path) = path else {
+        return;
+    };
+    if let Err(err) = crate::save_sealed_state(connector_handle.clone(), path).await {
+        log::error!("couldn't save sealed state: {:?}", err);
+    }
+}
+
+// Serves sessions through a tunnel to the relay in the background, if set.
+fn spawn_relay(
+    relay: Option<Endpoint>,
+    router: Arc<dyn SessionRouter>,
+    endorsements: &Endorsements,
+    metrics: &Arc<Metrics>,
+    policy: SessionPolicy,
+) {
+    let Some(relay) = relay else {
+        return;
+    };
+    tokio::spawn(crate::server::relay::run(
+        relay,
+        router,
+        endorsements.clone(),
+        metrics.clone(),
+        policy,
+    ));
+}
+
+// Serves the session gateway in the background if `gateway_port` is set.
+fn spawn_gateway(
+    gateway_port: Option<u16>,
+    router: Arc<dyn SessionRouter>,
+    endorsements: &Endorsements,
+    metrics: &Arc<Metrics>,
+    tls: Option<&TlsConfig>,
+    policy: SessionPolicy,
+) {
+    let Some(gateway_port) = gateway_port else {
+        return;
+    };
+    let gateway_server = crate::server::gateway::serve(
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, gateway_port)),
+        router,
+        endorsements.clone(),
+        metrics.clone(),
+        tls.map(TlsConfig::gateway_acceptor),
+        policy,
+    );
+    tokio::spawn(async move {
+        if let Err(err) = gateway_server.await {
+            log::error!("session gateway failed: {:?}", err);
+        }
+    });
+}
@@ -0,0 +1,116 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Provisioning of secrets to freshly launched enclaves.
+//!
+//! Once the evidence of an enclave satisfies the attestation policy, the
+//! launcher asks a key management service (KMS) for the secrets of the
+//! enclave, and delivers them to it. The KMS wraps the secrets by encrypting
+//! them with the encryption public key in the evidence, so that only the
+//! attested enclave can read them, and the launcher only ever handles
+//! ciphertext.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use oak_launcher_utils::channel::ConnectorHandle;
+use prost::Message;
+
+use crate::{
+    check_evidence,
+    proto::oak::{
+        attestation::v1::{AppraisalPolicy, Evidence},
+        crypto::v1::EncryptedRequest,
+        functions::{OakFunctionsAsyncClient, ProvisionSecretsRequest},
+    },
+};
+
+/// A key management service that wraps secrets for attested enclaves.
+#[async_trait::async_trait]
+pub trait KeyManagementService {
+    /// Returns the secrets of the enclave that `evidence` belongs to, as a
+    /// serialized `oak.functions.ProvisionedSecrets` message encrypted with
+    /// the encryption public key in the evidence.
+    ///
+    /// The KMS is expected to verify the evidence itself before releasing any
+    /// secrets, e.g. against its own reference values.
+    async fn wrapped_secrets(&self, evidence: &Evidence) -> anyhow::Result<EncryptedRequest>;
+}
+
+/// A KMS reached over HTTP(S): the serialized [`Evidence`] of the enclave is
+/// POSTed to a URL, and the response body is a serialized [`EncryptedRequest`].
+pub struct HttpKeyManagementService {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpKeyManagementService {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string(), client: reqwest::Client::new() }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyManagementService for HttpKeyManagementService {
+    async fn wrapped_secrets(&self, evidence: &Evidence) -> anyhow::Result<EncryptedRequest> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .body(evidence.encode_to_vec())
+            .send()
+            .await
+            .with_context(|| format!("couldn't request secrets from {}", self.url))?
+            .error_for_status()
+            .context("key management service refused to release secrets")?;
+        let body = response.bytes().await.context("couldn't read wrapped secrets")?;
+        EncryptedRequest::decode(body).context("couldn't decode wrapped secrets")
+    }
+}
+
+/// Provisions the secrets from a KMS to enclaves whose evidence satisfies the
+/// attestation policy.
+#[derive(Clone)]
+pub struct SecretProvisioner {
+    policy: AppraisalPolicy,
+    kms: Arc<dyn KeyManagementService + Send + Sync>,
+}
+
+impl SecretProvisioner {
+    pub fn new(policy: AppraisalPolicy, kms: Arc<dyn KeyManagementService + Send + Sync>) -> Self {
+        Self { policy, kms }
+    }
+
+    /// Checks `evidence` against the policy, and if it satisfies it delivers
+    /// the secrets wrapped by the KMS to the enclave behind `connector_handle`.
+    pub async fn provision(
+        &self,
+        connector_handle: ConnectorHandle,
+        evidence: &Evidence,
+    ) -> anyhow::Result<()> {
+        check_evidence(&self.policy, evidence)?;
+        let encrypted_secrets = self.kms.wrapped_secrets(evidence).await?;
+        let response = OakFunctionsAsyncClient::new(connector_handle)
+            .provision_secrets(&ProvisionSecretsRequest {
+                encrypted_secrets: Some(encrypted_secrets),
+            })
+            .await
+            .flatten()
+            .map_err(|err| anyhow!("couldn't provision secrets: {:?}", err))?;
+        log::info!("provisioned {} secrets to the enclave", response.secret_count);
+        Ok(())
+    }
+}
@@ -14,29 +14,543 @@
 // limitations under the License.
 //
 
-use std::{net::SocketAddr, pin::Pin};
+pub mod gateway;
+pub mod rate_limit;
+pub mod relay;
+pub mod resumption;
 
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
 use futures::{Future, Stream, StreamExt};
 use oak_proto_rust::oak::attestation::v1::{Endorsements, Evidence};
-use tonic::{transport::Server, Request, Response, Status, Streaming};
+use prost::Message;
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    transport::Server,
+    Code, Request, Response, Status, Streaming,
+};
 
+use self::{
+    rate_limit::{ClientQuota, RateLimiter},
+    resumption::{evidence_digest, EvidenceDigest, SessionTickets},
+};
 use crate::{
-    channel::ConnectorHandle,
+    channel::{is_channel_busy, ConnectorHandle},
+    metrics::{
+        Metrics, GET_ENDORSED_EVIDENCE_REQUEST, INVOKE_BATCH_REQUEST, INVOKE_REQUEST,
+        RESUME_SESSION_REQUEST,
+    },
     proto::oak::{
+        crypto::v1::EncryptedRequest,
         functions,
         session::v1::{
             request_wrapper, response_wrapper,
             streaming_session_server::{StreamingSession, StreamingSessionServer},
-            EndorsedEvidence, GetEndorsedEvidenceResponse, InvokeResponse, RequestWrapper,
-            ResponseWrapper,
+            EndorsedEvidence, GetEndorsedEvidenceResponse, InvokeBatchResponse, InvokeRequestChunk,
+            InvokeResponse, InvokeResponseChunk, RequestWrapper, ResponseWrapper,
+            ResumeSessionResponse, FILE_DESCRIPTOR_SET,
         },
     },
+    tls::TlsConfig,
 };
 
-pub struct SessionProxy {
+/// Maximum size of the response chunks read from the enclave and returned to
+/// clients in streaming invocations.
+const INVOKE_CHUNK_SIZE: u32 = 1 << 20;
+
+/// The id of the next streaming invocation, unique across sessions and
+/// enclaves.
+static NEXT_INVOCATION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The metadata entry of error statuses that holds their [`ErrorClass`].
+pub const ERROR_CLASS_METADATA_KEY: &str = "oak-error-class";
+
+/// The classes of errors returned to clients, so that they can tell them apart
+/// without parsing error messages.
+///
+/// Failures of the Wasm module itself are reported inside the encrypted
+/// response, which only the client can read. The enclave reveals their class
+/// though, which is passed on next to the encrypted response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The request is malformed; retrying it won't help.
+    InvalidRequest,
+    /// The enclave is overloaded; the request may be retried later.
+    EnclaveBusy,
+    /// The enclave can't serve requests at the moment, e.g. because it is
+    /// being (re)started.
+    EnclaveUnavailable,
+    /// The enclave failed to handle the request.
+    EnclaveError,
+    /// The request stream failed.
+    StreamError,
+    /// The client sent more requests than its rate limit allows; the request
+    /// may be retried later.
+    RateLimited,
+    /// The request is larger than the launcher forwards to the enclave.
+    RequestTooLarge,
+    /// The response of the enclave is larger than the launcher returns.
+    ResponseTooLarge,
+    /// The session can't be resumed, as its ticket is invalid or expired, or
+    /// its enclave isn't running anymore; the client has to fetch and verify
+    /// the evidence again.
+    SessionExpired,
+    /// The Wasm module trapped, e.g. because it panicked.
+    WasmTrap,
+    /// The Wasm module trapped after a lookup found no value.
+    LookupMiss,
+}
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::InvalidRequest => "invalid-request",
+            ErrorClass::EnclaveBusy => "enclave-busy",
+            ErrorClass::EnclaveUnavailable => "enclave-unavailable",
+            ErrorClass::EnclaveError => "enclave-error",
+            ErrorClass::StreamError => "stream-error",
+            ErrorClass::RateLimited => "rate-limited",
+            ErrorClass::RequestTooLarge => "request-too-large",
+            ErrorClass::ResponseTooLarge => "response-too-large",
+            ErrorClass::SessionExpired => "session-expired",
+            ErrorClass::WasmTrap => "wasm-trap",
+            ErrorClass::LookupMiss => "lookup-miss",
+        }
+    }
+
+    /// Returns the class of a failed invocation as revealed by the enclave, if
+    /// any.
+    pub fn of_invocation(class: functions::InvocationErrorClass) -> Option<Self> {
+        match class {
+            functions::InvocationErrorClass::Unspecified => None,
+            functions::InvocationErrorClass::WasmTrap => Some(ErrorClass::WasmTrap),
+            functions::InvocationErrorClass::LookupMiss => Some(ErrorClass::LookupMiss),
+        }
+    }
+}
+
+// Returns the `error_class` field of the response to a failed invocation.
+fn invocation_error_class(class: functions::InvocationErrorClass) -> String {
+    ErrorClass::of_invocation(class).map_or_else(String::new, |class| class.as_str().to_string())
+}
+
+// Returns the time that the enclave lets Wasm modules read, as the Restricted
+// Kernel has no clock.
+fn unix_time_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+fn error_status(code: Code, message: String, class: ErrorClass) -> tonic::Status {
+    let mut metadata = MetadataMap::new();
+    metadata.insert(ERROR_CLASS_METADATA_KEY, MetadataValue::from_static(class.as_str()));
+    tonic::Status::with_metadata(code, message, metadata)
+}
+
+/// Converts an error returned by the enclave, or by the channel to it, to the
+/// status returned to the client, keeping its status code.
+pub fn enclave_error_status(err: &micro_rpc::Status) -> tonic::Status {
+    let class = match err.code {
+        micro_rpc::StatusCode::InvalidArgument => ErrorClass::InvalidRequest,
+        _ if is_channel_busy(err) => ErrorClass::EnclaveBusy,
+        micro_rpc::StatusCode::FailedPrecondition
+        | micro_rpc::StatusCode::Unavailable
+        | micro_rpc::StatusCode::DeadlineExceeded
+        | micro_rpc::StatusCode::Cancelled => ErrorClass::EnclaveUnavailable,
+        _ => ErrorClass::EnclaveError,
+    };
+    error_status(
+        Code::from_i32(err.code as i32),
+        format!("error handling client request: {}", err.message),
+        class,
+    )
+}
+
+/// An enclave that client sessions can be routed to.
+#[derive(Clone)]
+pub struct SessionTarget {
+    pub connector_handle: ConnectorHandle,
+    pub evidence: Evidence,
+}
+
+/// Chooses the enclave that serves each new client session.
+///
+/// All requests of a session go to the same enclave, as the client encrypts
+/// them for the evidence it got at the start of the session.
+pub trait SessionRouter: Send + Sync + 'static {
+    /// Returns the enclave for a new session, or `None` if no enclave is
+    /// available.
+    fn route(&self) -> Option<SessionTarget>;
+
+    /// Returns the enclave with the evidence digest of a resumed session, or
+    /// `None` if it isn't running anymore.
+    fn route_to(&self, digest: &EvidenceDigest) -> Option<SessionTarget> {
+        self.route().filter(|target| evidence_digest(&target.evidence) == *digest)
+    }
+}
+
+impl SessionRouter for SessionTarget {
+    fn route(&self) -> Option<SessionTarget> {
+        Some(self.clone())
+    }
+}
+
+/// Limits on the size of the encrypted payloads passed between clients and the
+/// enclave, enforced by the launcher.
+#[derive(Parser, Clone, Copy, Debug, Default, PartialEq)]
+pub struct SizeLimits {
+    /// Maximum size in bytes of an encrypted request. Larger requests fail with
+    /// `INVALID_ARGUMENT` without being forwarded to the enclave.
+    #[arg(long)]
+    pub request_size_limit: Option<usize>,
+
+    /// Maximum size in bytes of an encrypted response. Requests with larger
+    /// responses fail with `OUT_OF_RANGE`.
+    #[arg(long)]
+    pub response_size_limit: Option<usize>,
+}
+
+/// The policies applied to client sessions.
+#[derive(Clone, Default)]
+pub struct SessionPolicy {
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub size_limits: SizeLimits,
+    /// Issues the tickets for resuming sessions, or `None` if session
+    /// resumption is disabled.
+    pub session_tickets: Option<Arc<SessionTickets>>,
+}
+
+/// A client session, bound to the enclave it was routed to.
+#[derive(Clone)]
+pub struct Session {
+    router: Arc<dyn SessionRouter>,
+    /// The enclave of the session, which changes if the session is resumed.
+    target: Arc<Mutex<SessionTarget>>,
+    endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    quota: Option<ClientQuota>,
+    policy: SessionPolicy,
+    /// The streaming invocation whose request is being uploaded, if any.
+    upload: Arc<Mutex<Option<Upload>>>,
+}
+
+impl Session {
+    /// Starts a session on the enclave chosen by `router`, whose invoke
+    /// requests count against `quota` if given.
+    pub fn start(
+        router: &Arc<dyn SessionRouter>,
+        endorsements: &Endorsements,
+        metrics: &Arc<Metrics>,
+        quota: Option<ClientQuota>,
+        policy: &SessionPolicy,
+    ) -> Result<Self, tonic::Status> {
+        let target = router.route().ok_or_else(|| {
+            error_status(
+                Code::Unavailable,
+                "no enclave available".to_string(),
+                ErrorClass::EnclaveUnavailable,
+            )
+        })?;
+        Ok(Self {
+            router: router.clone(),
+            target: Arc::new(Mutex::new(target)),
+            endorsements: endorsements.clone(),
+            metrics: metrics.clone(),
+            quota,
+            policy: policy.clone(),
+            upload: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Binds the session to the enclave that `ticket` was issued for.
+    fn resume(&self, ticket: &[u8]) -> Result<(), tonic::Status> {
+        let session_tickets = self.policy.session_tickets.as_ref().ok_or_else(|| {
+            error_status(
+                Code::FailedPrecondition,
+                "session resumption is disabled".to_string(),
+                ErrorClass::SessionExpired,
+            )
+        })?;
+        let digest = session_tickets.verify(ticket)?;
+        let target = self.router.route_to(&digest).ok_or_else(|| {
+            error_status(
+                Code::FailedPrecondition,
+                "the enclave of the session isn't running anymore".to_string(),
+                ErrorClass::SessionExpired,
+            )
+        })?;
+        *self.target.lock().unwrap() = target;
+        Ok(())
+    }
+
+    /// Handles one request of the session, returning its responses: a single
+    /// one for most requests, none for the chunks of a request but the last,
+    /// and the chunks of the response for the last one.
+    pub async fn handle(
+        &self,
+        request: RequestWrapper,
+    ) -> Result<Vec<ResponseWrapper>, tonic::Status> {
+        let request = request.request.ok_or_else(|| {
+            error_status(
+                Code::InvalidArgument,
+                "empty request message".to_string(),
+                ErrorClass::InvalidRequest,
+            )
+        })?;
+
+        let target = self.target.lock().unwrap().clone();
+        let response = match request {
+            request_wrapper::Request::GetEndorsedEvidenceRequest(_) => {
+                self.metrics.observe_request(GET_ENDORSED_EVIDENCE_REQUEST);
+                let session_ticket = self
+                    .policy
+                    .session_tickets
+                    .as_ref()
+                    .map(|session_tickets| session_tickets.issue(&target.evidence));
+                response_wrapper::Response::GetEndorsedEvidenceResponse(
+                    GetEndorsedEvidenceResponse {
+                        endorsed_evidence: Some(EndorsedEvidence {
+                            evidence: Some(target.evidence),
+                            endorsements: Some(self.endorsements.clone()),
+                        }),
+                        session_ticket,
+                    },
+                )
+            }
+            request_wrapper::Request::ResumeSessionRequest(resume_session_request) => {
+                self.metrics.observe_request(RESUME_SESSION_REQUEST);
+                self.resume(&resume_session_request.ticket)?;
+                response_wrapper::Response::ResumeSessionResponse(ResumeSessionResponse {})
+            }
+            request_wrapper::Request::InvokeRequestChunk(chunk) => {
+                return self.handle_chunk(target, chunk).await;
+            }
+            request_wrapper::Request::InvokeRequest(invoke_request) => {
+                self.metrics.observe_request(INVOKE_REQUEST);
+                let enclave_response =
+                    self.invoke(target, invoke_request.encrypted_request, false).await?;
+                #[allow(clippy::needless_update)]
+                response_wrapper::Response::InvokeResponse(InvokeResponse {
+                    error_class: invocation_error_class(enclave_response.error_class()),
+                    encrypted_response: enclave_response.encrypted_response,
+                    ..Default::default()
+                })
+            }
+            request_wrapper::Request::InvokeBatchRequest(invoke_batch_request) => {
+                self.metrics.observe_request(INVOKE_BATCH_REQUEST);
+                let enclave_response =
+                    self.invoke(target, invoke_batch_request.encrypted_request, true).await?;
+                response_wrapper::Response::InvokeBatchResponse(InvokeBatchResponse {
+                    encrypted_response: enclave_response.encrypted_response,
+                })
+            }
+        };
+        Ok(vec![ResponseWrapper { response: Some(response) }])
+    }
+
+    // Forwards an encrypted request to the enclave, as a single request or as a
+    // batch of requests, and returns the response of the enclave.
+    async fn invoke(
+        &self,
+        target: SessionTarget,
+        encrypted_request: Option<EncryptedRequest>,
+        batch: bool,
+    ) -> Result<functions::InvokeResponse, tonic::Status> {
+        let request_size = encrypted_request.as_ref().map_or(0, Message::encoded_len);
+        self.check_request_size(request_size)?;
+        if let Some(quota) = &self.quota {
+            quota.acquire()?;
+        }
+        #[allow(clippy::needless_update)]
+        let enclave_invoke_request = functions::InvokeRequest {
+            encrypted_request,
+            unix_time_seconds: unix_time_seconds(),
+            ..Default::default()
+        };
+        let mut enclave_client = functions::OakFunctionsAsyncClient::new(target.connector_handle);
+        let start = Instant::now();
+        let enclave_invoke_response = if batch {
+            enclave_client.invoke_batch(&enclave_invoke_request).await
+        } else {
+            enclave_client.handle_user_request(&enclave_invoke_request).await
+        }
+        .flatten()
+        .map_err(|err| self.enclave_error(&err))?;
+        let response_size =
+            enclave_invoke_response.encrypted_response.as_ref().map_or(0, Message::encoded_len);
+        self.metrics.observe_invoke(request_size, response_size, start.elapsed());
+        self.check_response_size(response_size)?;
+        Ok(enclave_invoke_response)
+    }
+
+    // Forwards a chunk of a request to the enclave as part of a streaming
+    // invocation, and returns the chunks of the response after the last one.
+    async fn handle_chunk(
+        &self,
+        target: SessionTarget,
+        chunk: InvokeRequestChunk,
+    ) -> Result<Vec<ResponseWrapper>, tonic::Status> {
+        // The upload is taken out of the session while the chunk is forwarded, so that
+        // it is aborted if forwarding fails.
+        let upload = self.upload.lock().unwrap().take();
+        let mut upload = match upload {
+            Some(upload) => upload,
+            None => {
+                self.metrics.observe_request(INVOKE_REQUEST);
+                if let Some(quota) = &self.quota {
+                    quota.acquire()?;
+                }
+                Upload::new(target.connector_handle)
+            }
+        };
+        upload.size += chunk.data.len();
+        self.check_request_size(upload.size)?;
+
+        let mut enclave_client =
+            functions::OakFunctionsAsyncClient::new(upload.connector_handle.clone());
+        enclave_client
+            .extend_invocation(&functions::ExtendInvocationRequest {
+                invocation_id: upload.invocation_id,
+                chunk: chunk.data,
+            })
+            .await
+            .flatten()
+            .map_err(|err| self.enclave_error(&err))?;
+        if !chunk.last {
+            *self.upload.lock().unwrap() = Some(upload);
+            return Ok(Vec::new());
+        }
+
+        let start = Instant::now();
+        let finish_response = enclave_client
+            .finish_invocation(&functions::FinishInvocationRequest {
+                invocation_id: upload.invocation_id,
+                unix_time_seconds: unix_time_seconds(),
+            })
+            .await
+            .flatten()
+            .map_err(|err| self.enclave_error(&err))?;
+        let response_size = usize::try_from(finish_response.response_size).unwrap_or(usize::MAX);
+        self.check_response_size(response_size)?;
+        let mut responses = Vec::new();
+        loop {
+            let read_response = enclave_client
+                .read_invocation_response(&functions::ReadInvocationResponseRequest {
+                    invocation_id: upload.invocation_id,
+                    max_chunk_size: INVOKE_CHUNK_SIZE,
+                })
+                .await
+                .flatten()
+                .map_err(|err| self.enclave_error(&err))?;
+            let error_class = if read_response.last {
+                invocation_error_class(finish_response.error_class())
+            } else {
+                String::new()
+            };
+            responses.push(ResponseWrapper {
+                response: Some(response_wrapper::Response::InvokeResponseChunk(
+                    InvokeResponseChunk {
+                        data: read_response.chunk,
+                        last: read_response.last,
+                        error_class,
+                    },
+                )),
+            });
+            if read_response.last {
+                break;
+            }
+        }
+        // The enclave discards the invocation once the last chunk has been read.
+        upload.finished = true;
+        self.metrics.observe_invoke(upload.size, response_size, start.elapsed());
+        Ok(responses)
+    }
+
+    fn check_request_size(&self, request_size: usize) -> Result<(), tonic::Status> {
+        match self.policy.size_limits.request_size_limit {
+            Some(limit) if request_size > limit => Err(error_status(
+                Code::InvalidArgument,
+                format!("request of {request_size} bytes exceeds limit of {limit} bytes"),
+                ErrorClass::RequestTooLarge,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_response_size(&self, response_size: usize) -> Result<(), tonic::Status> {
+        match self.policy.size_limits.response_size_limit {
+            Some(limit) if response_size > limit => Err(error_status(
+                Code::OutOfRange,
+                format!("response of {response_size} bytes exceeds limit of {limit} bytes"),
+                ErrorClass::ResponseTooLarge,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn enclave_error(&self, err: &micro_rpc::Status) -> tonic::Status {
+        if !is_channel_busy(err) {
+            self.metrics.observe_enclave_error();
+        }
+        enclave_error_status(err)
+    }
+}
+
+/// A streaming invocation whose request is being uploaded to the enclave.
+/// Aborted in the enclave when dropped before the response has been read, e.g.
+/// because the client went away.
+struct Upload {
+    invocation_id: u64,
     connector_handle: ConnectorHandle,
-    evidence: Evidence,
+    /// The size of the request uploaded so far.
+    size: usize,
+    finished: bool,
+}
+
+impl Upload {
+    fn new(connector_handle: ConnectorHandle) -> Self {
+        Self {
+            invocation_id: NEXT_INVOCATION_ID.fetch_add(1, Ordering::Relaxed),
+            connector_handle,
+            size: 0,
+            finished: false,
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let invocation_id = self.invocation_id;
+        let mut enclave_client =
+            functions::OakFunctionsAsyncClient::new(self.connector_handle.clone());
+        tokio::spawn(async move {
+            if let Err(err) = enclave_client
+                .abort_invocation(&functions::AbortInvocationRequest { invocation_id })
+                .await
+                .flatten()
+            {
+                log::warn!("couldn't abort streaming invocation {}: {:?}", invocation_id, err);
+            }
+        });
+    }
+}
+
+pub struct SessionProxy {
+    router: Arc<dyn SessionRouter>,
     endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    policy: SessionPolicy,
 }
 
 #[tonic::async_trait]
@@ -49,54 +563,34 @@ impl StreamingSession for SessionProxy {
         request: Request<Streaming<RequestWrapper>>,
     ) -> Result<Response<Self::StreamStream>, tonic::Status> {
         log::info!("handling client request");
+        let quota = self.policy.rate_limiter.as_ref().and_then(|rate_limiter| {
+            let peer_certs = request.peer_certs();
+            let client = rate_limiter.identify(
+                peer_certs.as_ref().and_then(|certs| certs.first()).map(|cert| cert.get_ref()),
+                request
+                    .metadata()
+                    .get(rate_limiter.api_key_header())
+                    .and_then(|value| value.to_str().ok()),
+                request.remote_addr().map(|addr| addr.ip()),
+            )?;
+            Some(ClientQuota { limiter: rate_limiter.clone(), client })
+        });
         let mut request_stream = request.into_inner();
-
-        let endorsed_evidence = EndorsedEvidence {
-            evidence: Some(self.evidence.clone()),
-            endorsements: Some(self.endorsements.clone()),
-        };
-        let connector_handle = self.connector_handle.clone();
+        let session =
+            Session::start(&self.router, &self.endorsements, &self.metrics, quota, &self.policy)?;
 
         let response_stream = async_stream::try_stream! {
             while let Some(request) = request_stream.next().await {
-                let request = request
-                    .map_err(|err| {
-                        tonic::Status::internal(format!("error reading message from request stream: {err}"))
-                    })?
-                    .request
-                    .ok_or_else(|| tonic::Status::invalid_argument("empty request message"))?;
-
-                let response = match request {
-                    request_wrapper::Request::GetEndorsedEvidenceRequest(_) => {
-                        response_wrapper::Response::GetEndorsedEvidenceResponse(GetEndorsedEvidenceResponse {
-                            endorsed_evidence: Some(endorsed_evidence.clone()),
-                        })
-                    }
-                    request_wrapper::Request::InvokeRequest(invoke_request) => {
-                        #[allow(clippy::needless_update)]
-                        let enclave_invoke_request = functions::InvokeRequest {
-                            encrypted_request: invoke_request.encrypted_request,
-                            ..Default::default()
-                        };
-                        let mut enclave_client =
-                            functions::OakFunctionsAsyncClient::new(connector_handle.clone());
-                        let enclave_invoke_response = enclave_client
-                            .handle_user_request(&enclave_invoke_request)
-                            .await
-                            .flatten()
-                            .map_err(|err| {
-                                tonic::Status::internal(format!("error handling client request: {:?}", err))
-                            })?;
-                        #[allow(clippy::needless_update)]
-                        response_wrapper::Response::InvokeResponse(InvokeResponse {
-                            encrypted_response: enclave_invoke_response.encrypted_response,
-                            ..Default::default()
-                        })
-                    }
-                };
-                yield ResponseWrapper {
-                    response: Some(response),
-                };
+                let request = request.map_err(|err| {
+                    error_status(
+                        Code::Internal,
+                        format!("error reading message from request stream: {err}"),
+                        ErrorClass::StreamError,
+                    )
+                })?;
+                for response in session.handle(request).await? {
+                    yield response;
+                }
             }
         };
 
@@ -104,13 +598,104 @@ impl StreamingSession for SessionProxy {
     }
 }
 
+/// Serves sessions on `addr`, over TLS if `tls` is given, applying `policy` to
+/// them.
 pub fn new(
     addr: SocketAddr,
     connector_handle: ConnectorHandle,
     evidence: Evidence,
     endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    tls: Option<&TlsConfig>,
+    policy: SessionPolicy,
 ) -> impl Future<Output = Result<(), tonic::transport::Error>> {
-    let server_impl = SessionProxy { connector_handle, evidence, endorsements };
+    new_routed(
+        addr,
+        Arc::new(SessionTarget { connector_handle, evidence }),
+        endorsements,
+        metrics,
+        tls,
+        policy,
+    )
+}
+
+/// Like [`new`], but spreads client sessions across the enclaves chosen by
+/// `router`.
+pub fn new_routed(
+    addr: SocketAddr,
+    router: Arc<dyn SessionRouter>,
+    endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    tls: Option<&TlsConfig>,
+    policy: SessionPolicy,
+) -> impl Future<Output = Result<(), tonic::transport::Error>> {
+    let server_impl = SessionProxy { router, endorsements, metrics, policy };
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()
+        .expect("couldn't build gRPC reflection service");
+    let builder = match tls {
+        Some(tls) => Server::builder().tls_config(tls.grpc_config()),
+        None => Ok(Server::builder()),
+    };
+
+    async move {
+        builder?
+            .add_service(StreamingSessionServer::new(server_impl))
+            .add_service(reflection_service)
+            .serve(addr)
+            .await
+    }
+}
+
+#[test]
+fn test_invocation_error_class() {
+    assert_eq!(invocation_error_class(functions::InvocationErrorClass::Unspecified), "");
+    assert_eq!(invocation_error_class(functions::InvocationErrorClass::WasmTrap), "wasm-trap");
+    assert_eq!(invocation_error_class(functions::InvocationErrorClass::LookupMiss), "lookup-miss");
+}
+
+#[test]
+fn test_enclave_error_status() {
+    let status = enclave_error_status(&micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::InvalidArgument,
+        "no encrypted request",
+    ));
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert_eq!(
+        status.metadata().get(ERROR_CLASS_METADATA_KEY).unwrap(),
+        ErrorClass::InvalidRequest.as_str()
+    );
+
+    let status =
+        enclave_error_status(&crate::channel::channel_busy(oak_channel::client::ChannelBusy {
+            in_flight_requests: 64,
+            in_flight_bytes: 1024,
+        }));
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert_eq!(
+        status.metadata().get(ERROR_CLASS_METADATA_KEY).unwrap(),
+        ErrorClass::EnclaveBusy.as_str()
+    );
+
+    // Budgets exhausted in the enclave aren't retried as a busy channel.
+    let status = enclave_error_status(&micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::ResourceExhausted,
+        "privacy budget exhausted",
+    ));
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert_eq!(
+        status.metadata().get(ERROR_CLASS_METADATA_KEY).unwrap(),
+        ErrorClass::EnclaveError.as_str()
+    );
 
-    Server::builder().add_service(StreamingSessionServer::new(server_impl)).serve(addr)
+    let status = enclave_error_status(&micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::Internal,
+        "couldn't decrypt request",
+    ));
+    assert_eq!(status.code(), Code::Internal);
+    assert_eq!(
+        status.metadata().get(ERROR_CLASS_METADATA_KEY).unwrap(),
+        ErrorClass::EnclaveError.as_str()
+    );
 }
@@ -0,0 +1,448 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! HTTP gateway tunnelling the session protocol for clients that can't use
+//! gRPC, such as web clients running a Wasm build of `oak_client`.
+//!
+//! The gateway only forwards session messages: requests and responses stay
+//! encrypted end to end, and clients still verify the enclave evidence
+//! themselves. Every request and response is the binary protobuf encoding of a
+//! [`RequestWrapper`] or [`ResponseWrapper`], as in the gRPC streaming session.
+//!
+//! * `GET /session/v1/ws` upgrades to a WebSocket that carries a single
+//!   session. Every binary message from the client is a request, and is
+//!   answered by a binary message with the response, or by a message for each
+//!   chunk of the response if it was sent in chunks. The WebSocket is closed
+//!   with the error message if a request fails.
+//! * `POST /session/v1/sessions` starts a session and responds with its id.
+//!   Requests of the session are then sent to `POST /session/v1/sessions/<id>`,
+//!   one per HTTP request, which therefore can't be sent in chunks. Sessions
+//!   expire after [`SESSION_IDLE_TIMEOUT`] without requests.
+//!
+//! Failed HTTP requests are answered with a status code derived from the gRPC
+//! one, the error message as body, and the [`ErrorClass`] in the
+//! `oak-error-class` header.
+//!
+//! [`ErrorClass`]: super::ErrorClass
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, CONNECTION, CONTENT_LENGTH,
+        CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+    },
+    server::conn::Http,
+    service::service_fn,
+    upgrade::Upgraded,
+    Body, Method, Request, Response, StatusCode,
+};
+use oak_proto_rust::oak::attestation::v1::Endorsements;
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+        Message as WebSocketMessage,
+    },
+    WebSocketStream,
+};
+use tonic::Code;
+
+use super::{
+    rate_limit::ClientQuota, Session, SessionPolicy, SessionRouter, ERROR_CLASS_METADATA_KEY,
+};
+use crate::{
+    metrics::Metrics,
+    proto::oak::session::v1::{request_wrapper, RequestWrapper},
+};
+
+const WEBSOCKET_PATH: &str = "/session/v1/ws";
+const SESSIONS_PATH: &str = "/session/v1/sessions";
+
+/// How long a session started through `POST` is kept without requests.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Upper bound for the size of a single request, in either transport.
+const MAX_REQUEST_SIZE: usize = 64 << 20;
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+struct Gateway {
+    router: Arc<dyn SessionRouter>,
+    endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    policy: SessionPolicy,
+    /// The sessions started through `POST`, with the time of their last
+    /// request, by session id.
+    sessions: Mutex<HashMap<String, (Session, Instant)>>,
+}
+
+/// The transport-level identity of a client connection.
+struct Peer {
+    address: IpAddr,
+    /// The DER encoding of the TLS client certificate, if any.
+    certificate: Option<Vec<u8>>,
+}
+
+/// Serves the gateway on `addr` until accepting connections fails, routing
+/// sessions to the enclaves chosen by `router`. Connections use TLS if `tls`
+/// is given, and `policy` is applied to the sessions.
+pub async fn serve(
+    addr: SocketAddr,
+    router: Arc<dyn SessionRouter>,
+    endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    tls: Option<TlsAcceptor>,
+    policy: SessionPolicy,
+) -> anyhow::Result<()> {
+    let gateway = Arc::new(Gateway {
+        router,
+        endorsements,
+        metrics,
+        policy,
+        sessions: Mutex::new(HashMap::new()),
+    });
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("session gateway listening on {}", addr);
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let (gateway, tls) = (gateway.clone(), tls.clone());
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => {
+                        let certificate = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .map(|cert| cert.to_vec());
+                        let peer = Peer { address: peer_addr.ip(), certificate };
+                        gateway.serve_connection(stream, peer).await
+                    }
+                    Err(err) => {
+                        log::warn!("TLS handshake with {} failed: {:?}", peer_addr, err);
+                        return;
+                    }
+                },
+                None => {
+                    let peer = Peer { address: peer_addr.ip(), certificate: None };
+                    gateway.serve_connection(stream, peer).await
+                }
+            };
+            if let Err(err) = result {
+                log::debug!("connection from {} failed: {:?}", peer_addr, err);
+            }
+        });
+    }
+}
+
+impl Gateway {
+    async fn serve_connection<S>(self: Arc<Self>, stream: S, peer: Peer) -> Result<(), hyper::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let peer = Arc::new(peer);
+        let service = service_fn(move |request| {
+            let (gateway, peer) = (self.clone(), peer.clone());
+            async move { Ok::<_, Infallible>(with_cors(gateway.handle(request, &peer).await)) }
+        });
+        Http::new().serve_connection(stream, service).with_upgrades().await
+    }
+
+    async fn handle(&self, request: Request<Body>, peer: &Peer) -> Response<Body> {
+        let path = request.uri().path().to_string();
+        match (request.method(), path.as_str()) {
+            // CORS preflight requests.
+            (&Method::OPTIONS, _) => Response::default(),
+            (&Method::GET, WEBSOCKET_PATH) => {
+                let quota = self.quota(&request, peer);
+                self.upgrade(request, quota)
+            }
+            (&Method::POST, SESSIONS_PATH) => {
+                match self.start_session(self.quota(&request, peer)) {
+                    Ok(session_id) => Response::new(Body::from(session_id)),
+                    Err(status) => error_response(&status),
+                }
+            }
+            (&Method::POST, path) => {
+                match path.strip_prefix(SESSIONS_PATH).and_then(|path| path.strip_prefix('/')) {
+                    Some(session_id) => self.handle_session_request(session_id, request).await,
+                    None => text_response(StatusCode::NOT_FOUND, "not found"),
+                }
+            }
+            _ => text_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    // Returns the rate limit that applies to the sessions started by `request`.
+    fn quota(&self, request: &Request<Body>, peer: &Peer) -> Option<ClientQuota> {
+        let rate_limiter = self.policy.rate_limiter.as_ref()?;
+        let client = rate_limiter.identify(
+            peer.certificate.as_deref(),
+            request
+                .headers()
+                .get(rate_limiter.api_key_header())
+                .and_then(|value| value.to_str().ok()),
+            Some(peer.address),
+        )?;
+        Some(ClientQuota { limiter: rate_limiter.clone(), client })
+    }
+
+    fn start_session(&self, quota: Option<ClientQuota>) -> Result<String, tonic::Status> {
+        let session =
+            Session::start(&self.router, &self.endorsements, &self.metrics, quota, &self.policy)?;
+        let session_id: String =
+            rand::random::<[u8; 16]>().iter().map(|byte| format!("{byte:02x}")).collect();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, last_used)| now.duration_since(*last_used) < SESSION_IDLE_TIMEOUT);
+        sessions.insert(session_id.clone(), (session, now));
+        Ok(session_id)
+    }
+
+    async fn handle_session_request(
+        &self,
+        session_id: &str,
+        request: Request<Body>,
+    ) -> Response<Body> {
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions.get_mut(session_id) {
+                Some((session, last_used)) if last_used.elapsed() < SESSION_IDLE_TIMEOUT => {
+                    *last_used = Instant::now();
+                    session.clone()
+                }
+                _ => return text_response(StatusCode::NOT_FOUND, "unknown or expired session"),
+            }
+        };
+
+        // Require the length upfront, so that oversized requests are rejected
+        // before they are read.
+        let content_length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        match content_length {
+            None => return text_response(StatusCode::LENGTH_REQUIRED, "missing content length"),
+            Some(length) if length > MAX_REQUEST_SIZE => {
+                return text_response(StatusCode::PAYLOAD_TOO_LARGE, "request too large")
+            }
+            Some(_) => {}
+        }
+        let body = match hyper::body::to_bytes(request.into_body()).await {
+            Ok(body) => body,
+            Err(err) => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("couldn't read request: {err}"),
+                )
+            }
+        };
+        let result = match RequestWrapper::decode(body) {
+            // Every HTTP request gets exactly one response.
+            Ok(RequestWrapper {
+                request: Some(request_wrapper::Request::InvokeRequestChunk(_)),
+            }) => Err(tonic::Status::invalid_argument(
+                "chunked requests are only supported over WebSocket",
+            )),
+            Ok(request) => session.handle(request).await,
+            Err(err) => Err(invalid_request(err)),
+        };
+        match result.map(|responses| responses.into_iter().next()) {
+            Ok(Some(response)) => {
+                let mut http_response = Response::new(Body::from(response.encode_to_vec()));
+                http_response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static(PROTOBUF_CONTENT_TYPE));
+                http_response
+            }
+            Ok(None) => unreachable!("requests other than chunks have a response"),
+            Err(status) => error_response(&status),
+        }
+    }
+
+    fn upgrade(&self, mut request: Request<Body>, quota: Option<ClientQuota>) -> Response<Body> {
+        let is_websocket = request
+            .headers()
+            .get(UPGRADE)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"websocket"));
+        let accept_key = match request.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) if is_websocket => derive_accept_key(key.as_bytes()),
+            _ => return text_response(StatusCode::BAD_REQUEST, "expected a WebSocket upgrade"),
+        };
+        let session = match Session::start(
+            &self.router,
+            &self.endorsements,
+            &self.metrics,
+            quota,
+            &self.policy,
+        ) {
+            Ok(session) => session,
+            Err(status) => return error_response(&status),
+        };
+
+        tokio::spawn(async move {
+            match hyper::upgrade::on(&mut request).await {
+                Ok(upgraded) => {
+                    let mut config = WebSocketConfig::default();
+                    config.max_message_size = Some(MAX_REQUEST_SIZE);
+                    let websocket =
+                        WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config))
+                            .await;
+                    serve_websocket(websocket, session).await;
+                }
+                Err(err) => log::warn!("couldn't upgrade to WebSocket: {:?}", err),
+            }
+        });
+
+        let mut response = Response::default();
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(
+            SEC_WEBSOCKET_ACCEPT,
+            HeaderValue::from_str(&accept_key).expect("invalid WebSocket accept key"),
+        );
+        response
+    }
+}
+
+// Handles the requests of a session sent over a WebSocket, until the client
+// closes it or a request fails.
+async fn serve_websocket(mut websocket: WebSocketStream<Upgraded>, session: Session) {
+    while let Some(message) = websocket.next().await {
+        let result = match message {
+            Ok(WebSocketMessage::Binary(request)) => {
+                match RequestWrapper::decode(request.as_slice()) {
+                    Ok(request) => session.handle(request).await,
+                    Err(err) => Err(invalid_request(err)),
+                }
+            }
+            Ok(WebSocketMessage::Text(_)) => {
+                Err(tonic::Status::invalid_argument("expected binary messages"))
+            }
+            // Pings are answered by the WebSocket implementation.
+            Ok(
+                WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_) | WebSocketMessage::Frame(_),
+            ) => continue,
+            Ok(WebSocketMessage::Close(_)) => return,
+            Err(err) => {
+                log::warn!("couldn't read WebSocket message: {:?}", err);
+                return;
+            }
+        };
+        let sent = match result {
+            Ok(responses) => {
+                let mut messages = futures::stream::iter(
+                    responses
+                        .into_iter()
+                        .map(|response| Ok(WebSocketMessage::Binary(response.encode_to_vec()))),
+                );
+                websocket.send_all(&mut messages).await
+            }
+            Err(status) => {
+                let close_frame = CloseFrame {
+                    code: match status.code() {
+                        Code::InvalidArgument => CloseCode::Policy,
+                        Code::ResourceExhausted | Code::Unavailable => CloseCode::Again,
+                        _ => CloseCode::Error,
+                    },
+                    reason: status.message().to_string().into(),
+                };
+                let _ = websocket.close(Some(close_frame)).await;
+                return;
+            }
+        };
+        if let Err(err) = sent {
+            log::warn!("couldn't write WebSocket message: {:?}", err);
+            return;
+        }
+    }
+}
+
+fn invalid_request(err: prost::DecodeError) -> tonic::Status {
+    tonic::Status::invalid_argument(format!("couldn't decode request: {err}"))
+}
+
+fn error_response(status: &tonic::Status) -> Response<Body> {
+    let http_status = match status.code() {
+        Code::InvalidArgument => StatusCode::BAD_REQUEST,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::FailedPrecondition | Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::OutOfRange => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let mut response = text_response(http_status, status.message());
+    if let Some(error_class) = status.metadata().get(ERROR_CLASS_METADATA_KEY) {
+        if let Ok(error_class) = HeaderValue::from_bytes(error_class.as_encoded_bytes()) {
+            response.headers_mut().insert(ERROR_CLASS_METADATA_KEY, error_class);
+        }
+    }
+    response
+}
+
+fn text_response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+// Allows web pages from any origin to use the gateway. The gateway doesn't need
+// to restrict origins, as the session payloads are encrypted for, and
+// authenticated by, the attested enclave rather than the gateway.
+fn with_cors(mut response: Response<Body>) -> Response<Body> {
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, OPTIONS"));
+    headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("content-type"));
+    // Lets web clients read the class of errors.
+    headers
+        .insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(ERROR_CLASS_METADATA_KEY));
+    response
+}
+
+#[test]
+fn test_error_response() {
+    let status = super::enclave_error_status(&micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::ResourceExhausted,
+        "too many requests in flight",
+    ));
+    let response = error_response(&status);
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        response.headers().get(ERROR_CLASS_METADATA_KEY).unwrap(),
+        super::ErrorClass::EnclaveBusy.as_str()
+    );
+}
@@ -0,0 +1,247 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Per-client rate limiting of invoke requests, so that a single client can't
+//! starve the others of the limited request throughput of the enclave.
+//!
+//! Every client has a token bucket that refills at a fixed rate up to a burst
+//! size, and every invoke request takes a token from it. Clients are identified
+//! by their TLS client certificate if they present one, otherwise by a known
+//! API key if they send one, and otherwise by their IP address. API keys are
+//! only used if they are listed in the configured file, as clients could
+//! otherwise get a fresh bucket by making up a new key.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Context;
+use clap::Parser;
+use tonic::Code;
+
+use super::{error_status, ErrorClass};
+
+/// The number of clients tracked before buckets that are full again are
+/// forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Rate limiting settings of the gRPC server and the session gateway.
+#[derive(Parser, Clone, Debug, Default, PartialEq)]
+pub struct RateLimitParams {
+    /// Sustained number of invoke requests per second allowed for each client.
+    /// Requests are not rate limited if not set.
+    #[arg(long)]
+    pub rate_limit: Option<f64>,
+
+    /// Number of invoke requests a client may send at once before being
+    /// limited to the sustained rate.
+    #[arg(long, default_value = "10", requires = "rate_limit")]
+    pub rate_limit_burst: u32,
+
+    /// Path to a file with the known API keys, one per line. Clients without a
+    /// TLS client certificate that send one of these keys are rate limited by
+    /// key rather than by IP address.
+    #[arg(long, requires = "rate_limit")]
+    pub api_keys: Option<PathBuf>,
+
+    /// Name of the header (or gRPC metadata entry) holding the API key.
+    #[arg(long, default_value = "x-api-key")]
+    pub api_key_header: String,
+}
+
+impl RateLimitParams {
+    /// Creates the configured rate limiter, or returns `None` if rate limiting
+    /// is disabled.
+    pub fn load(&self) -> anyhow::Result<Option<Arc<RateLimiter>>> {
+        let Some(rate) = self.rate_limit else {
+            return Ok(None);
+        };
+        anyhow::ensure!(rate > 0.0, "the rate limit must be positive");
+        let api_keys = match &self.api_keys {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("couldn't read API keys {}", path.display()))?
+                .lines()
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => HashSet::new(),
+        };
+        Ok(Some(Arc::new(RateLimiter {
+            rate,
+            burst: f64::from(self.rate_limit_burst.max(1)),
+            api_keys,
+            api_key_header: self.api_key_header.to_ascii_lowercase(),
+            buckets: Mutex::new(HashMap::new()),
+        })))
+    }
+}
+
+/// The identity that a client is rate limited by.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ClientId {
+    /// The DER encoding of the TLS client certificate.
+    Certificate(Vec<u8>),
+    ApiKey(String),
+    Address(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    api_keys: HashSet<String>,
+    api_key_header: String,
+    buckets: Mutex<HashMap<ClientId, Bucket>>,
+}
+
+impl RateLimiter {
+    /// The header (lowercase) or gRPC metadata key holding API keys.
+    pub fn api_key_header(&self) -> &str {
+        &self.api_key_header
+    }
+
+    /// Identifies a client by the first of the given credentials that can be
+    /// trusted.
+    pub fn identify(
+        &self,
+        certificate: Option<&[u8]>,
+        api_key: Option<&str>,
+        address: Option<IpAddr>,
+    ) -> Option<ClientId> {
+        if let Some(certificate) = certificate {
+            return Some(ClientId::Certificate(certificate.to_vec()));
+        }
+        match api_key {
+            Some(api_key) if self.api_keys.contains(api_key) => {
+                Some(ClientId::ApiKey(api_key.to_string()))
+            }
+            _ => address.map(ClientId::Address),
+        }
+    }
+
+    /// Takes a token from the bucket of `client`, failing with
+    /// `RESOURCE_EXHAUSTED` if it is empty.
+    pub fn acquire(&self, client: &ClientId) -> Result<(), tonic::Status> {
+        self.acquire_at(client, Instant::now())
+    }
+
+    fn acquire_at(&self, client: &ClientId, now: Instant) -> Result<(), tonic::Status> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(client) {
+            // Clients with full buckets are indistinguishable from new ones.
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, self.rate, self.burst);
+                bucket.tokens < self.burst
+            });
+        }
+        let bucket =
+            buckets.entry(client.clone()).or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens < 1.0 {
+            return Err(error_status(
+                Code::ResourceExhausted,
+                format!("rate limit of {} requests per second exceeded", self.rate),
+                ErrorClass::RateLimited,
+            ));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The rate limit that applies to the requests of one client.
+#[derive(Clone)]
+pub struct ClientQuota {
+    pub limiter: Arc<RateLimiter>,
+    pub client: ClientId,
+}
+
+impl ClientQuota {
+    pub fn acquire(&self) -> Result<(), tonic::Status> {
+        self.limiter.acquire(&self.client)
+    }
+}
+
+#[cfg(test)]
+fn test_limiter(api_keys: &[&str]) -> RateLimiter {
+    RateLimiter {
+        rate: 1.0,
+        burst: 2.0,
+        api_keys: api_keys.iter().map(|key| key.to_string()).collect(),
+        api_key_header: "x-api-key".to_string(),
+        buckets: Mutex::new(HashMap::new()),
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let limiter = test_limiter(&[]);
+    let client = ClientId::Address(IpAddr::from([10, 0, 0, 1]));
+    let other_client = ClientId::Address(IpAddr::from([10, 0, 0, 2]));
+    let start = Instant::now();
+
+    // The burst is allowed, but not more.
+    assert!(limiter.acquire_at(&client, start).is_ok());
+    assert!(limiter.acquire_at(&client, start).is_ok());
+    let status = limiter.acquire_at(&client, start).unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+
+    // Other clients have their own bucket.
+    assert!(limiter.acquire_at(&other_client, start).is_ok());
+
+    // The bucket refills over time.
+    let later = start + std::time::Duration::from_secs(1);
+    assert!(limiter.acquire_at(&client, later).is_ok());
+    assert!(limiter.acquire_at(&client, later).is_err());
+}
+
+#[test]
+fn test_identify() {
+    let limiter = test_limiter(&["known"]);
+    let address = IpAddr::from([10, 0, 0, 1]);
+    assert_eq!(
+        limiter.identify(Some(b"cert"), Some("known"), Some(address)),
+        Some(ClientId::Certificate(b"cert".to_vec()))
+    );
+    assert_eq!(
+        limiter.identify(None, Some("known"), Some(address)),
+        Some(ClientId::ApiKey("known".to_string()))
+    );
+    // Unknown API keys are ignored.
+    assert_eq!(
+        limiter.identify(None, Some("unknown"), Some(address)),
+        Some(ClientId::Address(address))
+    );
+    assert_eq!(limiter.identify(None, None, None), None);
+}
@@ -0,0 +1,257 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Reverse tunnel to a relay, for deployments where the launcher can't accept
+//! inbound connections, e.g. behind NAT or on egress-only networks.
+//!
+//! The launcher dials out to the relay's `SessionRelay` service, and serves the
+//! sessions of the clients connected to the relay over that outbound stream,
+//! multiplexed by session id. Sessions are handled exactly as those of the gRPC
+//! server, and their messages stay encrypted end to end, so the relay doesn't
+//! need to be trusted. If the tunnel fails, its sessions are dropped and the
+//! launcher dials out again with exponential backoff.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use oak_proto_rust::oak::attestation::v1::Endorsements;
+use tokio::sync::mpsc;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint};
+
+use super::{
+    rate_limit::ClientQuota, Session, SessionPolicy, SessionRouter, ERROR_CLASS_METADATA_KEY,
+};
+use crate::{
+    metrics::Metrics,
+    proto::oak::session::v1::{
+        session_relay_client::SessionRelayClient, tunnel_downstream, tunnel_upstream,
+        RequestWrapper, SessionError, TunnelDownstream, TunnelUpstream,
+    },
+};
+
+/// Delay before dialing out again after the tunnel failed for the first time.
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between consecutive attempts to dial out.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long the tunnel has to stay up for the backoff to be reset.
+const HEALTHY_TUNNEL_DURATION: Duration = Duration::from_secs(60);
+
+/// Number of messages of a session that are buffered while it handles a
+/// request.
+const SESSION_BUFFER_SIZE: usize = 16;
+
+/// Number of responses buffered before they are sent to the relay.
+const UPSTREAM_BUFFER_SIZE: usize = 64;
+
+/// Settings of the tunnel to a relay.
+#[derive(Parser, Clone, Debug, Default, PartialEq)]
+pub struct RelayParams {
+    /// URL of a relay to dial out to, and serve client sessions through, e.g.
+    /// `https://relay.example.com:443`. Sessions are still served on `--port`
+    /// too. The tunnel is disabled if not set.
+    #[arg(long)]
+    pub relay_url: Option<String>,
+
+    /// Path to PEM encoded CA certificates that the relay's certificate must be
+    /// issued by, for `https` relay URLs.
+    #[arg(long, requires = "relay_url")]
+    pub relay_ca: Option<PathBuf>,
+}
+
+impl RelayParams {
+    /// Creates the endpoint of the relay, or returns `None` if the tunnel is
+    /// disabled.
+    pub fn load(&self) -> anyhow::Result<Option<Endpoint>> {
+        let Some(relay_url) = &self.relay_url else {
+            return Ok(None);
+        };
+        let mut endpoint = Endpoint::from_shared(relay_url.clone()).context("invalid relay URL")?;
+        if let Some(relay_ca) = &self.relay_ca {
+            let ca_pem = fs::read(relay_ca)
+                .with_context(|| format!("couldn't read relay CA {}", relay_ca.display()))?;
+            endpoint = endpoint
+                .tls_config(ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca_pem)))
+                .context("invalid relay TLS config")?;
+        }
+        Ok(Some(endpoint))
+    }
+}
+
+/// Keeps a tunnel to the relay at `endpoint` open, serving the sessions of its
+/// clients on the enclaves chosen by `router`. Never returns.
+pub async fn run(
+    endpoint: Endpoint,
+    router: Arc<dyn SessionRouter>,
+    endorsements: Endorsements,
+    metrics: Arc<Metrics>,
+    policy: SessionPolicy,
+) {
+    let mut backoff = INITIAL_RECONNECT_BACKOFF;
+    loop {
+        let start = Instant::now();
+        match tunnel(&endpoint, &router, &endorsements, &metrics, &policy).await {
+            Ok(()) => log::warn!("relay closed the tunnel"),
+            Err(err) => log::warn!("tunnel to relay failed: {:?}", err),
+        }
+        if start.elapsed() >= HEALTHY_TUNNEL_DURATION {
+            backoff = INITIAL_RECONNECT_BACKOFF;
+        }
+        log::info!("dialing out to relay again in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = next_backoff(backoff);
+    }
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_RECONNECT_BACKOFF)
+}
+
+// Serves sessions over a single tunnel, until either end closes it.
+async fn tunnel(
+    endpoint: &Endpoint,
+    router: &Arc<dyn SessionRouter>,
+    endorsements: &Endorsements,
+    metrics: &Arc<Metrics>,
+    policy: &SessionPolicy,
+) -> anyhow::Result<()> {
+    let channel = endpoint.connect().await.context("couldn't connect to relay")?;
+    let (upstream_sender, mut upstream_receiver) = mpsc::channel(UPSTREAM_BUFFER_SIZE);
+    let upstream = async_stream::stream! {
+        while let Some(message) = upstream_receiver.recv().await {
+            yield message;
+        }
+    };
+    let mut downstream = SessionRelayClient::new(channel)
+        .tunnel(upstream)
+        .await
+        .context("couldn't open tunnel")?
+        .into_inner();
+    log::info!("serving sessions through relay {}", endpoint.uri());
+
+    // The requests of each session are forwarded to a task handling them in order,
+    // as the gRPC server does for a stream. Dropping the sender ends the task.
+    let mut sessions: HashMap<u64, mpsc::Sender<RequestWrapper>> = HashMap::new();
+    while let Some(message) =
+        downstream.message().await.context("couldn't read message from relay")?
+    {
+        let session_id = message.session_id;
+        let request = match message.message {
+            Some(tunnel_downstream::Message::Request(request)) => request,
+            Some(tunnel_downstream::Message::Closed(_)) => {
+                sessions.remove(&session_id);
+                continue;
+            }
+            None => continue,
+        };
+        let sender = match sessions.entry(session_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let quota = policy.rate_limiter.as_ref().and_then(|rate_limiter| {
+                    let client = rate_limiter.identify(
+                        None,
+                        Some(message.api_key.as_str()).filter(|api_key| !api_key.is_empty()),
+                        message.client_address.parse().ok(),
+                    )?;
+                    Some(ClientQuota { limiter: rate_limiter.clone(), client })
+                });
+                let session = match Session::start(router, endorsements, metrics, quota, policy) {
+                    Ok(session) => session,
+                    Err(status) => {
+                        send_error(&upstream_sender, session_id, &status).await;
+                        continue;
+                    }
+                };
+                entry.insert(spawn_session(session_id, session, upstream_sender.clone()))
+            }
+        };
+        if sender.send(request).await.is_err() {
+            // The session already failed and reported its error, so requests sent
+            // before the relay learned about it are dropped.
+            sessions.remove(&session_id);
+        }
+    }
+    Ok(())
+}
+
+// Handles the requests of a session in a task, sending its responses upstream.
+fn spawn_session(
+    session_id: u64,
+    session: Session,
+    upstream_sender: mpsc::Sender<TunnelUpstream>,
+) -> mpsc::Sender<RequestWrapper> {
+    let (sender, mut receiver) = mpsc::channel(SESSION_BUFFER_SIZE);
+    tokio::spawn(async move {
+        while let Some(request) = receiver.recv().await {
+            match session.handle(request).await {
+                Ok(responses) => {
+                    for response in responses {
+                        let message = TunnelUpstream {
+                            session_id,
+                            message: Some(tunnel_upstream::Message::Response(response)),
+                        };
+                        if upstream_sender.send(message).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(status) => {
+                    send_error(&upstream_sender, session_id, &status).await;
+                    return;
+                }
+            }
+        }
+    });
+    sender
+}
+
+async fn send_error(
+    upstream_sender: &mpsc::Sender<TunnelUpstream>,
+    session_id: u64,
+    status: &tonic::Status,
+) {
+    let error_class = status
+        .metadata()
+        .get(ERROR_CLASS_METADATA_KEY)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let message = TunnelUpstream {
+        session_id,
+        message: Some(tunnel_upstream::Message::Error(SessionError {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+            error_class,
+        })),
+    };
+    // The tunnel is being closed if this fails, which ends the session anyway.
+    let _ = upstream_sender.send(message).await;
+}
+
+#[test]
+fn test_next_backoff() {
+    assert_eq!(next_backoff(INITIAL_RECONNECT_BACKOFF), Duration::from_secs(2));
+    assert_eq!(next_backoff(Duration::from_secs(40)), MAX_RECONNECT_BACKOFF);
+    assert_eq!(next_backoff(MAX_RECONNECT_BACKOFF), MAX_RECONNECT_BACKOFF);
+}
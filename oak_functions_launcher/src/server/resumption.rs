@@ -0,0 +1,158 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Session tickets, which let clients resume a session on a new stream or
+//! connection without fetching and verifying the enclave evidence again.
+//!
+//! A ticket binds the session to the enclave whose evidence it was issued
+//! with, identified by the digest of that evidence, until it expires. Resuming
+//! only routes the session: requests stay encrypted for the public key the
+//! client verified, and the enclave doesn't keep any session state, so there is
+//! nothing to resume inside it. A restarted enclave has new evidence, so
+//! tickets issued for its previous instance don't resume sessions on it.
+//!
+//! Tickets are authenticated with a key generated when the launcher starts, so
+//! that clients can't extend their lifetime, and become invalid when it
+//! restarts.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use hmac::{Hmac, Mac};
+use oak_proto_rust::oak::attestation::v1::Evidence;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tonic::Code;
+
+use super::{error_status, ErrorClass};
+use crate::proto::oak::session::v1::SessionTicket;
+
+const DIGEST_SIZE: usize = 32;
+const EXPIRY_SIZE: usize = 8;
+const TAG_SIZE: usize = 32;
+const TICKET_SIZE: usize = DIGEST_SIZE + EXPIRY_SIZE + TAG_SIZE;
+
+/// The SHA-256 digest of the encoded evidence of an enclave.
+pub type EvidenceDigest = [u8; DIGEST_SIZE];
+
+/// Session resumption settings of the gRPC server and the session gateway.
+#[derive(Parser, Clone, Debug, PartialEq)]
+pub struct ResumptionParams {
+    /// Lifetime in seconds of the session tickets issued with the evidence.
+    /// Session resumption is disabled if 0.
+    #[arg(long, default_value = "3600")]
+    pub session_ticket_lifetime: u64,
+}
+
+impl ResumptionParams {
+    /// Creates the issuer of session tickets, or returns `None` if session
+    /// resumption is disabled.
+    pub fn load(&self) -> Option<Arc<SessionTickets>> {
+        (self.session_ticket_lifetime > 0).then(|| {
+            Arc::new(SessionTickets::new(Duration::from_secs(self.session_ticket_lifetime)))
+        })
+    }
+}
+
+/// Returns the digest that session tickets identify the enclave with
+/// `evidence` by.
+pub fn evidence_digest(evidence: &Evidence) -> EvidenceDigest {
+    Sha256::digest(evidence.encode_to_vec()).into()
+}
+
+/// Issues and verifies session tickets.
+pub struct SessionTickets {
+    key: [u8; 32],
+    lifetime: Duration,
+}
+
+impl SessionTickets {
+    pub fn new(lifetime: Duration) -> Self {
+        Self { key: rand::random(), lifetime }
+    }
+
+    /// Issues a ticket for the enclave with `evidence`.
+    pub fn issue(&self, evidence: &Evidence) -> SessionTicket {
+        self.issue_at(evidence, SystemTime::now())
+    }
+
+    /// Checks that `ticket` was issued by this launcher and hasn't expired, and
+    /// returns the digest of the evidence it was issued for.
+    pub fn verify(&self, ticket: &[u8]) -> Result<EvidenceDigest, tonic::Status> {
+        self.verify_at(ticket, SystemTime::now())
+    }
+
+    fn issue_at(&self, evidence: &Evidence, now: SystemTime) -> SessionTicket {
+        let expiry = seconds_since_epoch(now).saturating_add(self.lifetime.as_secs());
+        let mut ticket = Vec::with_capacity(TICKET_SIZE);
+        ticket.extend_from_slice(&evidence_digest(evidence));
+        ticket.extend_from_slice(&expiry.to_be_bytes());
+        let tag = self.mac(&ticket).finalize().into_bytes();
+        ticket.extend_from_slice(&tag);
+        SessionTicket { ticket, lifetime_seconds: self.lifetime.as_secs() }
+    }
+
+    fn verify_at(&self, ticket: &[u8], now: SystemTime) -> Result<EvidenceDigest, tonic::Status> {
+        let invalid = |message: &str| {
+            error_status(Code::FailedPrecondition, message.to_string(), ErrorClass::SessionExpired)
+        };
+        if ticket.len() != TICKET_SIZE {
+            return Err(invalid("invalid session ticket"));
+        }
+        let (payload, tag) = ticket.split_at(DIGEST_SIZE + EXPIRY_SIZE);
+        self.mac(payload).verify_slice(tag).map_err(|_| invalid("invalid session ticket"))?;
+        let (digest, expiry) = payload.split_at(DIGEST_SIZE);
+        let expiry = u64::from_be_bytes(expiry.try_into().expect("invalid expiry size"));
+        if seconds_since_epoch(now) >= expiry {
+            return Err(invalid("session ticket expired"));
+        }
+        Ok(digest.try_into().expect("invalid digest size"))
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(payload);
+        mac
+    }
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+#[test]
+fn test_session_ticket() {
+    let tickets = SessionTickets::new(Duration::from_secs(60));
+    let evidence = Evidence::default();
+    let now = SystemTime::now();
+    let ticket = tickets.issue_at(&evidence, now);
+    assert_eq!(ticket.lifetime_seconds, 60);
+
+    assert_eq!(tickets.verify_at(&ticket.ticket, now).unwrap(), evidence_digest(&evidence));
+    let status = tickets.verify_at(&ticket.ticket, now + Duration::from_secs(60)).unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+
+    // Tickets can't be modified, or used with another launcher.
+    let mut modified = ticket.ticket.clone();
+    modified[DIGEST_SIZE] ^= 1;
+    assert!(tickets.verify_at(&modified, now).is_err());
+    let other_tickets = SessionTickets::new(Duration::from_secs(60));
+    assert!(other_tickets.verify_at(&ticket.ticket, now).is_err());
+}
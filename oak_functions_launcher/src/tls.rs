@@ -0,0 +1,155 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! TLS termination for the public endpoints of the launcher.
+//!
+//! TLS only protects the transport between clients and the launcher, e.g. the
+//! session metadata; the requests themselves are encrypted for the enclave
+//! regardless.
+
+use std::{fs, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use clap::Parser;
+use tokio_rustls::{
+    rustls::{self, server::WebPkiClientVerifier, RootCertStore},
+    TlsAcceptor,
+};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+/// TLS settings of the gRPC server and the session gateway.
+#[derive(Parser, Clone, Debug, Default, PartialEq)]
+pub struct TlsParams {
+    /// Path to the PEM encoded certificate chain to serve. The public endpoints
+    /// use plaintext if not set.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key of the certificate.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Path to PEM encoded CA certificates. If set, clients must present a
+    /// certificate issued by one of them (mutual TLS).
+    #[arg(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+impl TlsParams {
+    /// Loads the configured certificates, or returns `None` if TLS is
+    /// disabled.
+    pub fn load(&self) -> anyhow::Result<Option<TlsConfig>> {
+        let (Some(cert_path), Some(key_path)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+        let cert_pem = fs::read(cert_path)
+            .with_context(|| format!("couldn't read certificate {}", cert_path.display()))?;
+        let key_pem = fs::read(key_path)
+            .with_context(|| format!("couldn't read private key {}", key_path.display()))?;
+        let client_ca_pem = self
+            .tls_client_ca
+            .as_ref()
+            .map(|path| {
+                fs::read(path)
+                    .with_context(|| format!("couldn't read client CA {}", path.display()))
+            })
+            .transpose()?;
+        TlsConfig::from_pem(&cert_pem, &key_pem, client_ca_pem.as_deref()).map(Some)
+    }
+}
+
+/// Parsed TLS settings, for both the gRPC server and the session gateway.
+#[derive(Clone)]
+pub struct TlsConfig {
+    identity: Identity,
+    client_ca: Option<Certificate>,
+    gateway_config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsConfig {
+    pub fn from_pem(
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        client_ca_pem: Option<&[u8]>,
+    ) -> anyhow::Result<Self> {
+        // tonic only parses the certificates when the server starts, so parse them
+        // for the gateway here to fail early on invalid ones.
+        let gateway_config = gateway_config(cert_pem, key_pem, client_ca_pem)?;
+        Ok(Self {
+            identity: Identity::from_pem(cert_pem, key_pem),
+            client_ca: client_ca_pem.map(Certificate::from_pem),
+            gateway_config: Arc::new(gateway_config),
+        })
+    }
+
+    /// The configuration of the gRPC server.
+    pub fn grpc_config(&self) -> ServerTlsConfig {
+        let config = ServerTlsConfig::new().identity(self.identity.clone());
+        match &self.client_ca {
+            Some(client_ca) => config.client_ca_root(client_ca.clone()),
+            None => config,
+        }
+    }
+
+    /// The acceptor for connections to the session gateway.
+    pub fn gateway_acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.gateway_config.clone())
+    }
+}
+
+fn gateway_config(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+    client_ca_pem: Option<&[u8]>,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .context("couldn't parse certificate chain")?;
+    let key = rustls_pemfile::private_key(&mut &key_pem[..])
+        .context("couldn't parse private key")?
+        .context("no private key found")?;
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca_pem {
+        Some(client_ca_pem) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut &client_ca_pem[..]) {
+                roots
+                    .add(cert.context("couldn't parse client CA")?)
+                    .context("invalid client CA")?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .context("couldn't create client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config =
+        builder.with_single_cert(certs, key).context("invalid certificate or private key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+#[test]
+fn test_tls_disabled_by_default() {
+    assert!(TlsParams::default().load().unwrap().is_none());
+}
+
+#[test]
+fn test_invalid_certificate() {
+    assert!(TlsConfig::from_pem(b"not a certificate", b"not a key", None).is_err());
+}
@@ -0,0 +1,491 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Checks of a deployment without launching it, for `--validate-only`.
+//!
+//! The checks catch the mistakes that would otherwise only show once the
+//! enclave is launched: Wasm modules that don't parse or don't export the Oak
+//! Functions ABI, lookup data that doesn't parse or can't fit in the memory
+//! budget of the enclave, and VM images that are missing or aren't the expected
+//! builds. The result is a JSON report, for CI pipelines to check a deployment
+//! before rolling it out.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use oak_functions_service::wasm::{ALLOC_FUNCTION_NAME, MAIN_FUNCTION_NAME, MEMORY_NAME};
+use prost::Message;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use ubyte::ByteUnit;
+use wasmparser::{CompositeType, ExternalKind, Payload, TypeRef, ValType};
+
+use crate::{
+    fetch_lookup_data_signature,
+    lookup::{self, LookupDataStats},
+    proto::oak::functions::LookupDataManifest,
+    Args, LookupDataConfig, WasmLimits,
+};
+
+/// The exports of the Wasm module that the enclave calls, see the Oak
+/// Functions ABI.
+const ENTRY_POINTS: &[(&str, ExternalKind)] = &[
+    (MAIN_FUNCTION_NAME, ExternalKind::Func),
+    (ALLOC_FUNCTION_NAME, ExternalKind::Func),
+    (MEMORY_NAME, ExternalKind::Memory),
+];
+
+/// The parameter and result types of the functions of [`ENTRY_POINTS`].
+const ENTRY_POINT_SIGNATURES: &[(&str, &[ValType], &[ValType])] =
+    &[(MAIN_FUNCTION_NAME, &[], &[]), (ALLOC_FUNCTION_NAME, &[ValType::I32], &[ValType::I32])];
+
+/// Size of a page of Wasm linear memory.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Memory that the ordered index of the lookup data costs per entry, see
+/// [`Args::lookup_data_ordered_index`].
+const ORDERED_INDEX_ENTRY_SIZE: u64 = 5;
+
+/// Settings of `--validate-only`.
+#[derive(Parser, Clone, Debug, Default, PartialEq)]
+pub struct ValidateParams {
+    /// Only check the deployment, without launching the enclave: that the Wasm
+    /// modules parse and export the Oak Functions ABI, that the lookup data
+    /// parses and fits in its memory budget, and that the VM images exist.
+    /// Prints a JSON report, and fails if any check fails.
+    #[arg(long)]
+    pub validate_only: bool,
+
+    /// The SHA2-256 digest that a VM image must have, as `<image>=<hex>`,
+    /// checked with `--validate-only`. The images are `stage0`, `kernel` and
+    /// `initrd`, plus `app` (or `app-<name>` for bundles) on the Restricted
+    /// Kernel, and `system-image` and `container-bundle` on Oak Containers. Can
+    /// be given multiple times.
+    #[arg(
+        long = "expected-sha2-256",
+        value_parser = parse_expected_digest,
+        requires = "validate_only"
+    )]
+    pub expected_digests: Vec<(String, String)>,
+}
+
+fn parse_expected_digest(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((image, digest))
+            if !image.is_empty()
+                && digest.len() == 64
+                && digest.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok((image.to_string(), digest.to_ascii_lowercase()))
+        }
+        _ => Err(format!("expected <image>=<hex SHA2-256 digest>, got {}", s)),
+    }
+}
+
+/// The result of `--validate-only`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    /// Whether all the checks passed.
+    pub valid: bool,
+    pub checks: Vec<Check>,
+}
+
+/// The result of one check of a [`Report`].
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    /// What was checked, e.g. `wasm` or `image:kernel`.
+    pub name: String,
+    pub passed: bool,
+    /// Why the check failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What was found, e.g. the size and digest of a file.
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub details: Map<String, Value>,
+}
+
+impl Report {
+    fn add(&mut self, name: String, result: anyhow::Result<Map<String, Value>>) {
+        let check = match result {
+            Ok(details) => Check { name, passed: true, error: None, details },
+            Err(err) => Check {
+                name,
+                passed: false,
+                error: Some(format!("{:#}", err)),
+                details: Map::new(),
+            },
+        };
+        self.valid &= check.passed;
+        self.checks.push(check);
+    }
+
+    /// Prints the report as JSON to stdout, and returns an error if a check
+    /// failed.
+    pub fn print(&self) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string_pretty(self).context("couldn't serialize report")?);
+        anyhow::ensure!(self.valid, "validation failed");
+        Ok(())
+    }
+}
+
+/// Checks the Wasm modules, the lookup data and the VM images of a deployment
+/// configured by `args`. The Wasm modules are checked in addition to the one
+/// given with [`Args::wasm`], e.g. the modules of tenants, and the VM images
+/// are named as in [`ValidateParams::expected_digests`].
+pub async fn validate(
+    args: &Args,
+    wasm_modules: &[(String, PathBuf)],
+    images: &[(String, PathBuf)],
+    lookup_data_config: &LookupDataConfig,
+) -> Report {
+    let mut report = Report { valid: true, checks: Vec::new() };
+
+    let wasm_limits = args.wasm_limits();
+    if let Some(wasm) = args.wasm.as_ref() {
+        report.add("wasm".to_string(), check_wasm_module(wasm, &wasm_limits));
+    }
+    for (name, path) in wasm_modules {
+        report.add(format!("wasm:{}", name), check_wasm_module(path, &wasm_limits));
+    }
+
+    report.add("lookup-data".to_string(), check_lookup_data(lookup_data_config).await);
+
+    for (name, path) in images {
+        let expected = args
+            .validate
+            .expected_digests
+            .iter()
+            .find(|(image, _)| image == name)
+            .map(|(_, digest)| digest.as_str());
+        report.add(format!("image:{}", name), check_image(path, expected));
+    }
+    for (image, _) in &args.validate.expected_digests {
+        if !images.iter().any(|(name, _)| name == image) {
+            report.add(
+                format!("image:{}", image),
+                Err(anyhow::anyhow!("there is no image called {}", image)),
+            );
+        }
+    }
+    report
+}
+
+// Checks that the Wasm module at `path` is valid, exports the entry points of
+// the ABI with their signatures, and that its initial memory fits in the memory
+// limit.
+fn check_wasm_module(path: &Path, limits: &WasmLimits) -> anyhow::Result<Map<String, Value>> {
+    let bytes =
+        fs::read(path).with_context(|| format!("couldn't read Wasm module {}", path.display()))?;
+    wasmparser::Validator::new().validate_all(&bytes).context("invalid Wasm module")?;
+
+    let mut exports = Vec::new();
+    let mut types = Vec::new();
+    // The type indices of the functions and the memories, imported ones first, as
+    // they are indexed.
+    let mut functions = Vec::new();
+    let mut memories = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for rec_group in reader {
+                    types.extend(rec_group?.into_types().map(|sub_type| sub_type.composite_type));
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    match import?.ty {
+                        TypeRef::Func(type_index) => functions.push(type_index),
+                        TypeRef::Memory(memory) => memories.push(memory),
+                        _ => {}
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for type_index in reader {
+                    functions.push(type_index?);
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    memories.push(memory?);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    exports.push((export.name.to_string(), export.kind, export.index));
+                }
+            }
+            _ => {}
+        }
+    }
+    for (entry_point, kind) in ENTRY_POINTS {
+        anyhow::ensure!(
+            exports.iter().any(|(name, export_kind, _)| name == entry_point && export_kind == kind),
+            "the Wasm module doesn't export {:?} `{}`",
+            kind,
+            entry_point
+        );
+    }
+    for (function, params, results) in ENTRY_POINT_SIGNATURES {
+        let function_type = exports
+            .iter()
+            .find(|(name, kind, _)| name == function && *kind == ExternalKind::Func)
+            .and_then(|(_, _, index)| functions.get(*index as usize))
+            .and_then(|type_index| types.get(*type_index as usize));
+        let Some(CompositeType::Func(function_type)) = function_type else {
+            anyhow::bail!("the type of the exported function `{}` isn't a function type", function);
+        };
+        anyhow::ensure!(
+            function_type.params() == *params && function_type.results() == *results,
+            "the exported function `{}` has type {:?} -> {:?}, expected {:?} -> {:?}",
+            function,
+            function_type.params(),
+            function_type.results(),
+            params,
+            results
+        );
+    }
+
+    let memory_index = exports
+        .iter()
+        .find(|(name, kind, _)| name == MEMORY_NAME && *kind == ExternalKind::Memory)
+        .map(|(_, _, index)| *index as usize);
+    let initial_memory = memory_index
+        .and_then(|index| memories.get(index))
+        .map(|memory| memory.initial * WASM_PAGE_SIZE)
+        .unwrap_or_default();
+    if let Some(memory_limit) = limits.memory_limit {
+        anyhow::ensure!(
+            initial_memory <= memory_limit.as_u64(),
+            "the initial memory of the Wasm module ({}) exceeds the memory limit ({})",
+            ByteUnit::Byte(initial_memory),
+            memory_limit
+        );
+    }
+
+    let mut details = file_details(path, &bytes);
+    details.insert("initial_memory_bytes".to_string(), json!(initial_memory));
+    Ok(details)
+}
+
+// Checks that the lookup data (and its signature, if signed) parses, and that
+// it fits in the memory budget of the enclave.
+async fn check_lookup_data(config: &LookupDataConfig) -> anyhow::Result<Map<String, Value>> {
+    let fetched = config.lookup_data_source.fetch().await?;
+    let mut stats = LookupDataStats::default();
+    let mut details = Map::new();
+    if config.sharded {
+        let serialized = fs::read(fetched.path()).context("couldn't read lookup data manifest")?;
+        let manifest = LookupDataManifest::decode(serialized.as_slice())
+            .context("couldn't decode lookup data manifest")?;
+        for shard in &manifest.shards {
+            let fetched_shard = lookup::fetch_shard(shard).await?;
+            let shard_stats = lookup::read_stats(fetched_shard.path(), config.encrypted)
+                .with_context(|| format!("invalid shard {}", shard.location))?;
+            stats.entries += shard_stats.entries;
+            stats.bytes += shard_stats.bytes;
+        }
+        details.insert("shards".to_string(), json!(manifest.shards.len()));
+    } else {
+        stats = lookup::read_stats(fetched.path(), config.encrypted)?;
+    }
+    if let Some(signing) = config.signing.as_ref() {
+        // The enclave verifies the signature, with the lookup data it receives.
+        fetch_lookup_data_signature(&signing.signature_source).await?;
+    }
+
+    details.insert("source".to_string(), json!(config.lookup_data_source.to_string()));
+    details.insert(
+        if config.encrypted { "chunks" } else { "entries" }.to_string(),
+        json!(stats.entries),
+    );
+    details.insert("bytes".to_string(), json!(stats.bytes));
+    // The size of encrypted lookup data only bounds the size of its entries.
+    if !config.encrypted {
+        // The enclave needs at least the keys and values, and their index, but
+        // its tables cost more.
+        let minimum_memory = stats.bytes
+            + if config.ordered_index { stats.entries * ORDERED_INDEX_ENTRY_SIZE } else { 0 };
+        if let Some(budget) = config.memory_budget {
+            anyhow::ensure!(
+                minimum_memory <= budget.as_u64(),
+                "the lookup data needs at least {} in the enclave, more than its memory budget \
+                 ({})",
+                ByteUnit::Byte(minimum_memory),
+                budget
+            );
+        }
+        details.insert("minimum_memory_bytes".to_string(), json!(minimum_memory));
+    }
+    Ok(details)
+}
+
+// Checks that the VM image at `path` exists, and has the `expected` SHA2-256
+// digest if set.
+fn check_image(path: &Path, expected: Option<&str>) -> anyhow::Result<Map<String, Value>> {
+    let bytes = fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    if let Some(expected) = expected {
+        let digest = sha2_256(&bytes);
+        anyhow::ensure!(
+            digest == expected,
+            "the SHA2-256 digest of {} is {}, expected {}",
+            path.display(),
+            digest,
+            expected
+        );
+    }
+    Ok(file_details(path, &bytes))
+}
+
+fn file_details(path: &Path, bytes: &[u8]) -> Map<String, Value> {
+    let mut details = Map::new();
+    details.insert("path".to_string(), json!(path.display().to_string()));
+    details.insert("size_bytes".to_string(), json!(bytes.len()));
+    details.insert("sha2_256".to_string(), json!(sha2_256(bytes)));
+    details
+}
+
+fn sha2_256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+#[test]
+fn test_parse_expected_digest() {
+    let digest = "AB".repeat(32);
+    assert_eq!(
+        parse_expected_digest(&format!("kernel={}", digest)),
+        Ok(("kernel".to_string(), "ab".repeat(32)))
+    );
+    assert!(parse_expected_digest(&format!("={}", digest)).is_err());
+    assert!(parse_expected_digest("kernel=abcd").is_err());
+    assert!(parse_expected_digest(&format!("kernel={}", "xy".repeat(32))).is_err());
+}
+
+#[test]
+fn test_check_image_compares_digests() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    fs::write(file.path(), b"stage0").unwrap();
+    let digest = sha2_256(b"stage0");
+
+    assert!(check_image(file.path(), None).is_ok());
+    assert!(check_image(file.path(), Some(&digest)).is_ok());
+    assert!(check_image(file.path(), Some(&"0".repeat(64))).is_err());
+    assert!(check_image(Path::new("/nonexistent"), None).is_err());
+}
+
+#[cfg(test)]
+fn write_wasm_module(wat: &str) -> tempfile::NamedTempFile {
+    oak_functions_test_utils::write_to_temp_file(&wat::parse_str(wat).unwrap())
+}
+
+#[test]
+fn test_check_wasm_module_checks_entry_points() {
+    let module = write_wasm_module(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "main"))
+            (func (export "alloc") (param i32) (result i32) local.get 0))"#,
+    );
+    let details = check_wasm_module(module.path(), &WasmLimits::default()).unwrap();
+    assert_eq!(details["initial_memory_bytes"], json!(WASM_PAGE_SIZE));
+
+    let missing_alloc = write_wasm_module(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "main")))"#,
+    );
+    assert!(check_wasm_module(missing_alloc.path(), &WasmLimits::default()).is_err());
+
+    let missing_memory = write_wasm_module(
+        r#"(module
+            (func (export "main"))
+            (func (export "alloc") (param i32) (result i32) local.get 0))"#,
+    );
+    assert!(check_wasm_module(missing_memory.path(), &WasmLimits::default()).is_err());
+}
+
+#[test]
+fn test_check_wasm_module_checks_signatures() {
+    let main_with_result = write_wasm_module(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "main") (result i32) i32.const 0)
+            (func (export "alloc") (param i32) (result i32) local.get 0))"#,
+    );
+    assert!(check_wasm_module(main_with_result.path(), &WasmLimits::default()).is_err());
+
+    let alloc_with_i64 = write_wasm_module(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "main"))
+            (func (export "alloc") (param i64) (result i64) local.get 0))"#,
+    );
+    assert!(check_wasm_module(alloc_with_i64.path(), &WasmLimits::default()).is_err());
+
+    // Imported functions come first in the index space of functions.
+    let imported_function = write_wasm_module(
+        r#"(module
+            (import "oak_functions" "write_request" (func (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "main"))
+            (func (export "alloc") (param i32) (result i32) local.get 0))"#,
+    );
+    assert!(check_wasm_module(imported_function.path(), &WasmLimits::default()).is_ok());
+}
+
+#[test]
+fn test_check_wasm_module_checks_memory_limit() {
+    let module = write_wasm_module(
+        r#"(module
+            (memory (export "memory") 16)
+            (func (export "main"))
+            (func (export "alloc") (param i32) (result i32) local.get 0))"#,
+    );
+    let limits =
+        |memory_limit| WasmLimits { memory_limit: Some(memory_limit), ..Default::default() };
+    assert!(check_wasm_module(module.path(), &limits(ByteUnit::Mebibyte(1))).is_ok());
+    assert!(check_wasm_module(module.path(), &limits(ByteUnit::Kibibyte(512))).is_err());
+}
+
+#[tokio::test]
+async fn test_check_lookup_data_checks_memory_budget() {
+    let lookup_data =
+        oak_functions_test_utils::write_to_temp_file(&oak_functions_test_utils::serialize_entries(
+            [(b"key".to_vec(), vec![0; 1000])].into_iter().collect(),
+        ));
+    let config = |memory_budget: &str| {
+        Args::try_parse_from([
+            "oak_functions_launcher",
+            "--lookup-data",
+            lookup_data.path().to_str().unwrap(),
+            "--lookup-data-memory-budget",
+            memory_budget,
+        ])
+        .unwrap()
+        .lookup_data_config(ByteUnit::Mebibyte(4))
+    };
+
+    let details = check_lookup_data(&config("1MiB")).await.unwrap();
+    assert_eq!(details["entries"], json!(1));
+    assert_eq!(details["minimum_memory_bytes"], json!(1003));
+    assert!(check_lookup_data(&config("1000B")).await.is_err());
+}
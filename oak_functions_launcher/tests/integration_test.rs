@@ -20,7 +20,8 @@ use std::{io::Write, time::Duration};
 use oak_client::verifier::InsecureAttestationVerifier;
 use oak_functions_client::OakFunctionsClient;
 use oak_functions_launcher::{
-    proto::oak::functions::OakFunctionsAsyncClient, update_lookup_data, LookupDataConfig,
+    proto::oak::functions::OakFunctionsAsyncClient, shutdown, update_lookup_data, LookupDataConfig,
+    LookupSource, ShutdownStatus, WasmLimits,
 };
 use oak_launcher_utils::launcher;
 use ubyte::ByteUnit;
@@ -55,6 +56,15 @@ async fn test_launcher_key_value_lookup() {
 
     let response = client.invoke(b"test_key").await.expect("failed to invoke");
     assert_eq!(response, b"test_value");
+
+    let responses = client
+        .invoke_batch(&[b"test_key".to_vec(), b"test_key".to_vec()])
+        .await
+        .expect("failed to invoke batch");
+    assert_eq!(responses.len(), 2);
+    for response in responses {
+        assert_eq!(response.expect("failed to handle request in batch"), b"test_value");
+    }
 }
 
 // Allow enough worker threads to collect output from background tasks.
@@ -142,7 +152,15 @@ async fn test_load_large_lookup_data() {
     let params = launcher::Params {
         kernel: xtask::launcher::OAK_RESTRICTED_KERNEL_WRAPPER_BIN.clone(),
         vmm_binary: which::which("qemu-system-x86_64").unwrap(),
+        vmm: oak_launcher_utils::vmm::VmmType::Qemu,
+        sev_snp: false,
+        snp: Default::default(),
+        console_log: Default::default(),
+        channel: Default::default(),
         app_binary: Some(oak_functions_enclave_app_path.into()),
+        app_bundle: Vec::new(),
+        app_selector: None,
+        application_config: Vec::new(),
         bios_binary: workspace_path(&[
             "stage0_bin",
             "target",
@@ -153,6 +171,11 @@ async fn test_load_large_lookup_data() {
         gdb: None,
         initrd: oak_restricted_kernel_orchestrator_app_path.into(),
         memory_size: Some("256M".to_string()),
+        num_cpus: 1,
+        cpu_model: launcher::DEFAULT_CPU_MODEL.to_string(),
+        memory_balloon: false,
+        numa_nodes: 1,
+        kernel_args: Vec::new(),
     };
     log::debug!("launcher params: {:?}", params);
 
@@ -164,23 +187,47 @@ async fn test_load_large_lookup_data() {
         &oak_functions_test_utils::serialize_entries(entries_one_chunk),
     );
     let lookup_data_config = LookupDataConfig {
-        lookup_data_path: lookup_data_file.path().to_path_buf(),
+        lookup_data_source: LookupSource::File(lookup_data_file.path().to_path_buf()),
         update_interval: None,
         max_chunk_size,
+        encrypted: false,
+        signing: None,
+        sharded: false,
+        ordered_index: false,
+        memory_budget: None,
+        max_retries: 0,
+        retry_backoff: Duration::ZERO,
     };
     let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup")
         .expect("Failed to build Wasm module");
-    let status_one_chunk =
-        oak_functions_launcher::create(params, lookup_data_config, wasm_path.into(), 1024).await;
+    let status_one_chunk = oak_functions_launcher::create(
+        params,
+        lookup_data_config,
+        wasm_path.into(),
+        Some(1024),
+        WasmLimits::default(),
+        None,
+        None,
+        None,
+        None,
+    )
+    .await;
     assert!(status_one_chunk.is_ok());
 
-    let (launched_instance, connector_handle, _) = status_one_chunk.unwrap();
-    let mut client = OakFunctionsAsyncClient::new(connector_handle);
+    let (launched_instance, connector_handle, _, lookup_data_handle) = status_one_chunk.unwrap();
+    let mut client = OakFunctionsAsyncClient::new(connector_handle.clone());
 
     let lookup_data_config = LookupDataConfig {
-        lookup_data_path: lookup_data_file.path().to_path_buf(),
+        lookup_data_source: LookupSource::File(lookup_data_file.path().to_path_buf()),
         update_interval: None,
         max_chunk_size,
+        encrypted: false,
+        signing: None,
+        sharded: false,
+        ordered_index: false,
+        memory_budget: None,
+        max_retries: 0,
+        retry_backoff: Duration::ZERO,
     };
 
     // Write 2 chunks in lookup data.
@@ -200,7 +247,16 @@ async fn test_load_large_lookup_data() {
     let status_four_chunks = update_lookup_data(&mut client, &lookup_data_config).await;
     assert!(status_four_chunks.is_ok());
 
-    launched_instance.kill().await.expect("Failed to stop launcher");
+    // Reload the lookup data through the handle returned by the launcher.
+    let update = lookup_data_handle.reload().await.expect("couldn't reload lookup data");
+    assert!(update.result.is_ok());
+    assert_eq!(update.attempts, 1);
+
+    let status =
+        shutdown(launched_instance, connector_handle, &lookup_data_handle, Duration::from_secs(30))
+            .await
+            .expect("Failed to stop launcher");
+    assert!(matches!(status, ShutdownStatus::Clean(_)), "unexpected shutdown: {:?}", status);
 }
 
 #[ignore = "too expensive"]
@@ -225,7 +281,15 @@ async fn test_load_two_gib_lookup_data() {
     let params = launcher::Params {
         kernel: xtask::launcher::OAK_RESTRICTED_KERNEL_WRAPPER_BIN.clone(),
         vmm_binary: which::which("qemu-system-x86_64").unwrap(),
+        vmm: oak_launcher_utils::vmm::VmmType::Qemu,
+        sev_snp: false,
+        snp: Default::default(),
+        console_log: Default::default(),
+        channel: Default::default(),
         app_binary: Some(oak_functions_enclave_app_path.into()),
+        app_bundle: Vec::new(),
+        app_selector: None,
+        application_config: Vec::new(),
         bios_binary: workspace_path(&[
             "stage0_bin",
             "target",
@@ -236,6 +300,11 @@ async fn test_load_two_gib_lookup_data() {
         gdb: None,
         initrd: oak_restricted_kernel_orchestrator_app_path.into(),
         memory_size: Some("256M".to_string()),
+        num_cpus: 1,
+        cpu_model: launcher::DEFAULT_CPU_MODEL.to_string(),
+        memory_balloon: false,
+        numa_nodes: 1,
+        kernel_args: Vec::new(),
     };
     log::debug!("launcher params: {:?}", params);
 
@@ -247,13 +316,30 @@ async fn test_load_two_gib_lookup_data() {
     );
     // This takes >5 min but will get there eventually.
     let lookup_data_config = LookupDataConfig {
-        lookup_data_path: lookup_data_file.path().to_path_buf(),
+        lookup_data_source: LookupSource::File(lookup_data_file.path().to_path_buf()),
         update_interval: None,
         max_chunk_size,
+        encrypted: false,
+        signing: None,
+        sharded: false,
+        ordered_index: false,
+        memory_budget: None,
+        max_retries: 0,
+        retry_backoff: Duration::ZERO,
     };
     let wasm_path = oak_functions_test_utils::build_rust_crate_wasm("key_value_lookup")
         .expect("Failed to build Wasm module");
-    let status =
-        oak_functions_launcher::create(params, lookup_data_config, wasm_path.into(), 1024).await;
+    let status = oak_functions_launcher::create(
+        params,
+        lookup_data_config,
+        wasm_path.into(),
+        Some(1024),
+        WasmLimits::default(),
+        None,
+        None,
+        None,
+        None,
+    )
+    .await;
     assert!(status.is_ok());
 }
@@ -40,9 +40,14 @@ use core::ops::Deref;
 
 use micro_rpc::{Status, StatusCode};
 use proto::oak::functions::wasm::v1::{
-    BytesValue, LogRequest, LogResponse, LookupDataMultiRequest, LookupDataMultiResponse,
-    LookupDataRequest, LookupDataResponse, ReadRequestRequest, ReadRequestResponse,
-    StdWasmApiClient, TestRequest, TestResponse, WriteResponseRequest, WriteResponseResponse,
+    AggregateReadRequest, AggregateReadResponse, AggregateWriteRequest, BytesValue,
+    ConsumeBudgetRequest, ConsumeBudgetResponse, InvokeModuleRequest, InvokeModuleResponse,
+    KeyValue, LogLevel, LogRequest, LogResponse, LookupDataMultiRequest, LookupDataMultiResponse,
+    LookupDataRequest, LookupDataResponse, LookupMultiRequest, LookupMultiResponse,
+    LookupPrefixRequest, LookupPrefixResponse, LookupRangeRequest, LookupRangeResponse,
+    MlInferRequest, MlInferResponse, ReadRandomRequest, ReadRandomResponse, ReadRequestRequest,
+    ReadRequestResponse, ReadSecretRequest, ReadSecretResponse, StdWasmApiClient, Tensor,
+    TestRequest, TestResponse, WriteResponseRequest, WriteResponseResponse,
 };
 
 /// See [`StdWasmApiClient::read_request`].
@@ -83,14 +88,129 @@ where
         })
 }
 
+/// See [`StdWasmApiClient::lookup_multi`].
+pub fn storage_get_values(key: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+    client()
+        .lookup_multi(&LookupMultiRequest { key: key.to_vec() })
+        .flatten()
+        .map(|LookupMultiResponse { values }| values)
+}
+
+/// See [`StdWasmApiClient::lookup_range`].
+pub fn storage_get_range(
+    start: &[u8],
+    end: Option<&[u8]>,
+    limit: u32,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Status> {
+    client()
+        .lookup_range(&LookupRangeRequest {
+            start: start.to_vec(),
+            end: end.map(|end| end.to_vec()),
+            limit,
+        })
+        .flatten()
+        .map(|LookupRangeResponse { items }| items.into_iter().map(key_value_to_tuple).collect())
+}
+
+/// See [`StdWasmApiClient::lookup_prefix`].
+pub fn storage_get_prefix(prefix: &[u8], limit: u32) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Status> {
+    client()
+        .lookup_prefix(&LookupPrefixRequest { prefix: prefix.to_vec(), limit })
+        .flatten()
+        .map(|LookupPrefixResponse { items }| items.into_iter().map(key_value_to_tuple).collect())
+}
+
+/// See [`StdWasmApiClient::read_secret`].
+pub fn read_secret(name: &str) -> Result<Option<Vec<u8>>, Status> {
+    client()
+        .read_secret(&ReadSecretRequest { name: name.to_string() })
+        .flatten()
+        .map(|ReadSecretResponse { value }| value)
+}
+
+/// Returns the seconds since the Unix epoch, see
+/// [`StdWasmApiClient::read_time`].
+pub fn read_time() -> Result<u64, Status> {
+    client()
+        .read_time(&ReadTimeRequest {})
+        .flatten()
+        .map(|ReadTimeResponse { unix_time_seconds }| unix_time_seconds)
+}
+
+/// Consumes `epsilon` of the privacy budget and returns the remaining budget,
+/// see [`StdWasmApiClient::consume_budget`].
+pub fn consume_budget(epsilon: f64) -> Result<f64, Status> {
+    client()
+        .consume_budget(&ConsumeBudgetRequest { epsilon })
+        .flatten()
+        .map(|ConsumeBudgetResponse { remaining_epsilon }| remaining_epsilon)
+}
+
+/// Contributes `value` to `bucket` of the aggregation store, see
+/// [`StdWasmApiClient::aggregate_write`].
+pub fn aggregate_write(bucket: &[u8], value: &[u8]) -> Result<(), Status> {
+    client()
+        .aggregate_write(&AggregateWriteRequest { bucket: bucket.to_vec(), value: value.to_vec() })
+        .flatten()
+        .map(|_| ())
+}
+
+/// Returns the contributions to `bucket` once it has enough of them, see
+/// [`StdWasmApiClient::aggregate_read`].
+pub fn aggregate_read(bucket: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+    client()
+        .aggregate_read(&AggregateReadRequest { bucket: bucket.to_vec() })
+        .flatten()
+        .map(|AggregateReadResponse { values }| values)
+}
+
+/// Reads `len` pseudo-random bytes, see [`StdWasmApiClient::read_random`].
+pub fn read_random(len: u32) -> Result<Vec<u8>, Status> {
+    client()
+        .read_random(&ReadRandomRequest { len })
+        .flatten()
+        .map(|ReadRandomResponse { data }| data)
+}
+
+/// Runs the ML model of the deployment on `inputs` and returns its outputs,
+/// see [`StdWasmApiClient::ml_infer`].
+pub fn ml_infer(inputs: Vec<Tensor>) -> Result<Vec<Tensor>, Status> {
+    client()
+        .ml_infer(&MlInferRequest { inputs })
+        .flatten()
+        .map(|MlInferResponse { outputs }| outputs)
+}
+
+/// Invokes the helper module called `name` with `request` and returns its
+/// response, see [`StdWasmApiClient::invoke_module`].
+pub fn invoke_module(name: &str, request: &[u8]) -> Result<Vec<u8>, Status> {
+    client()
+        .invoke_module(&InvokeModuleRequest { name: name.to_string(), request: request.to_vec() })
+        .flatten()
+        .map(|InvokeModuleResponse { response }| response)
+}
+
 fn bytes_value_to_option(b: BytesValue) -> Option<Vec<u8>> {
-    if b.found { Some(b.value) } else { None }
+    if b.found {
+        Some(b.value)
+    } else {
+        None
+    }
 }
 
-/// See [`StdWasmApiClient::log`].
+fn key_value_to_tuple(KeyValue { key, value }: KeyValue) -> (Vec<u8>, Vec<u8>) {
+    (key, value)
+}
+
+/// Writes a debug log message, see [`log`].
 pub fn write_log_message<T: AsRef<str>>(message: T) -> Result<(), Status> {
+    log(LogLevel::Debug, message)
+}
+
+/// See [`StdWasmApiClient::log`].
+pub fn log<T: AsRef<str>>(level: LogLevel, message: T) -> Result<(), Status> {
     client()
-        .log(&LogRequest { message: message.as_ref().to_string() })
+        .log(&LogRequest { message: message.as_ref().to_string(), level: level.into() })
         .flatten()
         .map(|LogResponse {}| ())
 }
@@ -136,6 +256,28 @@ macro_rules! log {
     }
 }
 
+/// Name of the Wasm custom section in which a module declares the maximum size
+/// of its responses, as a little-endian `u32`.
+///
+/// Use [`declare_max_response_size`] rather than creating the section
+/// directly.
+pub const MAX_RESPONSE_SIZE_SECTION: &str = "oak_functions_max_response_size";
+
+/// Declares the maximum size of the responses written by this module, so that
+/// the runtime can pick a constant response size that fits all of them.
+///
+/// Must be used at most once per module.
+#[macro_export]
+macro_rules! declare_max_response_size {
+    ($size:expr) => {
+        // The section name must be a literal; keep it in sync with
+        // `MAX_RESPONSE_SIZE_SECTION`.
+        #[link_section = "oak_functions_max_response_size"]
+        #[used]
+        static OAK_FUNCTIONS_MAX_RESPONSE_SIZE: [u8; 4] = u32::to_le_bytes($size);
+    };
+}
+
 /// A wrapper around the `invoke` function that implements the
 /// [`micro_rpc::Transport`] trait.
 ///
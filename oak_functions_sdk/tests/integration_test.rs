@@ -14,15 +14,22 @@
 // limitations under the License.
 //
 
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
 
 use lazy_static::lazy_static;
+use log::Level;
 use oak_functions_abi::{Request, Response};
 use oak_functions_service::{
-    logger::StandaloneLogger,
+    aggregation::AggregationStore,
+    clock::{Clock, TimeConfig, TimeSource},
+    helper_modules::{HelperModule, HelperModules},
+    logger::{OakLogger, StandaloneLogger},
     lookup::LookupDataManager,
-    wasm::{api::StdWasmApiFactory, WasmHandler},
-    Handler,
+    privacy_budget::PrivacyBudget,
+    proto::oak::functions::InvocationErrorClass,
+    randomness::{RandomnessSource, KEY_SIZE},
+    wasm::{api::StdWasmApiFactory, invocation_error_class, WasmHandler},
+    Handler, HandlerConfig, Subsystems,
 };
 
 lazy_static! {
@@ -48,15 +55,52 @@ lazy_static! {
     };
 }
 
+/// How the Wasm API that a test handler exposes differs from the default one.
+struct TestApi {
+    /// The lookup data.
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    subsystems: Subsystems,
+    randomness: Arc<RandomnessSource>,
+    logger: Arc<dyn OakLogger>,
+}
+
+impl Default for TestApi {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            subsystems: Subsystems::default(),
+            randomness: Arc::default(),
+            logger: Arc::new(StandaloneLogger),
+        }
+    }
+}
+
+// Creates a handler of `wasm_module_bytes` with the default handler config,
+// whose Wasm API is set up as `api`.
+fn create_wasm_handler(wasm_module_bytes: &[u8], api: TestApi) -> WasmHandler {
+    let lookup_data_manager =
+        Arc::new(LookupDataManager::for_test(api.entries, api.logger.clone()));
+    let api_factory = StdWasmApiFactory {
+        lookup_data_manager,
+        lookup_namespace: Vec::new(),
+        secret_store: Arc::default(),
+        subsystems: api.subsystems,
+        randomness: api.randomness,
+        logger: api.logger.clone(),
+    };
+    WasmHandler::create(
+        wasm_module_bytes,
+        Arc::new(api_factory),
+        api.logger,
+        None,
+        &HandlerConfig::default(),
+    )
+    .expect("couldn't instantiate WasmHandler")
+}
+
 #[tokio::test]
 async fn test_read_write() {
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi::default());
 
     let request = Request { body: b"ReadWrite".to_vec() };
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
@@ -65,13 +109,7 @@ async fn test_read_write() {
 
 #[tokio::test]
 async fn test_double_read() {
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi::default());
 
     let request = Request { body: b"DoubleRead".to_vec() };
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
@@ -80,45 +118,50 @@ async fn test_double_read() {
 
 #[tokio::test]
 async fn test_double_write() {
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi::default());
 
     let request = Request { body: b"DoubleWrite".to_vec() };
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
     oak_functions_test_utils::assert_response_body(response, "DoubleWriteResponse");
 }
 
+// Logs sensitive messages even if `oak_functions_service` denies sensitive
+// logging, so that the messages of the Wasm module can be captured.
+struct SensitiveLogger;
+
+impl OakLogger for SensitiveLogger {
+    fn log_sensitive(&self, level: Level, message: &str) {
+        log::log!(level, "{}", message);
+    }
+
+    fn log_public(&self, level: Level, message: &str) {
+        log::log!(level, "{}", message);
+    }
+}
+
 #[tokio::test]
 async fn test_write_log() {
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(
+        &LOOKUP_WASM_MODULE_BYTES,
+        TestApi { logger: Arc::new(SensitiveLogger), ..Default::default() },
+    );
 
+    let logs = oak_functions_test_utils::capture_logs();
     let request = Request { body: b"WriteLog".to_vec() };
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
     oak_functions_test_utils::assert_response_body(response, "WriteLogResponse");
+
+    let messages = logs.messages();
+    assert!(messages.contains(&(Level::Debug, "[Wasm] WriteLog".to_string())));
+    assert!(messages.contains(&(Level::Warn, "[Wasm] WriteLog".to_string())));
 }
 
 #[tokio::test]
 async fn test_storage_get_item() {
     let entries = Vec::from_iter([(b"StorageGet".to_vec(), b"StorageGetResponse".to_vec())]);
 
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(entries, logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
     let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+        create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi { entries, ..Default::default() });
 
     let request = Request { body: b"StorageGet".to_vec() };
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
@@ -130,13 +173,8 @@ async fn test_storage_get_item_not_found() {
     // empty lookup data, no key will be found
     let entries = Vec::default();
 
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(entries, logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
     let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+        create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi { entries, ..Default::default() });
 
     let request = Request { body: b"StorageGetItemNotFound".to_vec() };
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
@@ -149,13 +187,8 @@ async fn test_storage_get_item_huge_key() {
     let bytes: Vec<u8> = vec![42u8; 1 << 20];
     let entries = Vec::from_iter([(bytes.clone(), bytes.clone())]);
 
-    let logger = Arc::new(StandaloneLogger);
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(entries, logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
     let wasm_handler =
-        WasmHandler::create(&LOOKUP_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+        create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi { entries, ..Default::default() });
 
     let request = Request { body: b"LargeKey".to_vec() };
 
@@ -165,15 +198,9 @@ async fn test_storage_get_item_huge_key() {
 
 #[tokio::test]
 async fn test_echo() {
-    let logger = Arc::new(StandaloneLogger);
     let message_to_echo = "ECHO";
 
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&TESTING_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(&TESTING_WASM_MODULE_BYTES, TestApi::default());
 
     let request = Request { body: message_to_echo.as_bytes().to_vec() };
 
@@ -186,15 +213,9 @@ async fn test_blackhole() {
     // Keep in sync with
     // `workspace/oak_functions/sdk/oak_functions/tests/testing_module/src/lib.rs`.
 
-    let logger = Arc::new(StandaloneLogger);
     let message_to_blackhole = "BLACKHOLE";
 
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&TESTING_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(&TESTING_WASM_MODULE_BYTES, TestApi::default());
 
     let request = Request { body: message_to_blackhole.as_bytes().to_vec() };
 
@@ -202,23 +223,150 @@ async fn test_blackhole() {
     oak_functions_test_utils::assert_response_body(response, "Blackholed");
 }
 
+#[tokio::test]
+async fn test_read_time() {
+    let clock = Arc::new(
+        Clock::new(TimeConfig { granularity_seconds: 60, source: TimeSource::Host }).unwrap(),
+    );
+    clock.observe_host_time(1_000_030);
+    let wasm_handler = create_wasm_handler(
+        &LOOKUP_WASM_MODULE_BYTES,
+        TestApi { subsystems: Subsystems { clock, ..Default::default() }, ..Default::default() },
+    );
+
+    let request = Request { body: b"ReadTime".to_vec() };
+    let response: Response = wasm_handler.handle_invoke(request).unwrap();
+    oak_functions_test_utils::assert_response_body(response, "999960");
+}
+
+#[tokio::test]
+async fn test_read_time_without_granularity() {
+    let wasm_handler = create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi::default());
+
+    let request = Request { body: b"ReadTime".to_vec() };
+    assert!(wasm_handler.handle_invoke(request).is_err());
+}
+
+#[tokio::test]
+async fn test_consume_budget() {
+    let privacy_budget = Arc::new(PrivacyBudget::new(1.0).unwrap());
+
+    let wasm_handler = create_wasm_handler(
+        &LOOKUP_WASM_MODULE_BYTES,
+        TestApi {
+            subsystems: Subsystems { privacy_budget: privacy_budget.clone(), ..Default::default() },
+            ..Default::default()
+        },
+    );
+
+    let request = Request { body: b"ConsumeBudget".to_vec() };
+    let response: Response = wasm_handler.handle_invoke(request).unwrap();
+    assert_eq!(response.body().unwrap(), b"ConsumeBudgetResponse");
+    assert!(privacy_budget.check_not_exhausted().is_err());
+}
+
+#[tokio::test]
+async fn test_aggregate() {
+    let wasm_handler = create_wasm_handler(
+        &LOOKUP_WASM_MODULE_BYTES,
+        TestApi {
+            subsystems: Subsystems {
+                aggregation_store: Arc::new(AggregationStore::new(2).unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    let response: Response =
+        wasm_handler.handle_invoke(Request { body: b"Aggregate".to_vec() }).unwrap();
+    assert!(std::str::from_utf8(response.body().unwrap())
+        .unwrap()
+        .starts_with("BelowAggregationThreshold"));
+
+    let response: Response =
+        wasm_handler.handle_invoke(Request { body: b"Aggregate".to_vec() }).unwrap();
+    assert_eq!(response.body().unwrap(), b"2");
+}
+
+#[tokio::test]
+async fn test_read_random() {
+    let create_handler = || {
+        create_wasm_handler(
+            &LOOKUP_WASM_MODULE_BYTES,
+            TestApi {
+                randomness: Arc::new(RandomnessSource::with_key([42; KEY_SIZE])),
+                ..Default::default()
+            },
+        )
+    };
+    let invoke = |wasm_handler: &WasmHandler| -> Vec<u8> {
+        let request = Request { body: b"ReadRandom".to_vec() };
+        let response: Response = wasm_handler.handle_invoke(request).unwrap();
+        response.body().unwrap().to_vec()
+    };
+
+    let first_handler = create_handler();
+    let first = invoke(&first_handler);
+    let second = invoke(&first_handler);
+    assert_eq!(first.len(), 48);
+    assert_ne!(first, second);
+
+    // Replaying the requests against a handler with the same key yields the same
+    // randomness.
+    let replay_handler = create_handler();
+    assert_eq!(invoke(&replay_handler), first);
+    assert_eq!(invoke(&replay_handler), second);
+}
+
+#[tokio::test]
+async fn test_invoke_module() {
+    let create_handler = |subsystems: Subsystems| {
+        create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi { subsystems, ..Default::default() })
+    };
+
+    let helper: Arc<dyn HelperModule> = Arc::new(create_handler(Subsystems::default()));
+    let helper_modules = HelperModules::new(BTreeMap::from([("helper".to_string(), helper)]));
+    let wasm_handler = create_handler(Subsystems {
+        helper_modules: Arc::new(helper_modules),
+        ..Default::default()
+    });
+
+    let request = Request { body: b"InvokeModule".to_vec() };
+    let response: Response = wasm_handler.handle_invoke(request).unwrap();
+    assert_eq!(response.body().unwrap(), b"InvokeModuleResponse");
+}
+
 #[tokio::test]
 #[ignore]
 async fn test_huge_response() {
     // Keep in sync with
     // `workspace/oak_functions/sdk/oak_functions/tests/testing_module/src/lib.rs`.
 
-    let logger = Arc::new(StandaloneLogger);
-
-    let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory = StdWasmApiFactory { lookup_data_manager };
-
-    let wasm_handler =
-        WasmHandler::create(&TESTING_WASM_MODULE_BYTES, Arc::new(api_factory), logger, None)
-            .expect("couldn't instantiate WasmHandler");
+    let wasm_handler = create_wasm_handler(&TESTING_WASM_MODULE_BYTES, TestApi::default());
 
     let request = Request { body: "HUGE_RESPONSE".as_bytes().to_vec() };
 
     let response: Response = wasm_handler.handle_invoke(request).unwrap();
     assert_eq!(response.body, vec![42u8; 1 << 20])
 }
+
+#[tokio::test]
+async fn test_trap_is_classified() {
+    let wasm_handler = create_wasm_handler(&TESTING_WASM_MODULE_BYTES, TestApi::default());
+
+    // The module panics on requests it doesn't recognize.
+    let request = Request { body: b"UNRECOGNIZED".to_vec() };
+    let err = wasm_handler.handle_invoke(request).unwrap_err();
+    assert_eq!(invocation_error_class(&err), InvocationErrorClass::WasmTrap, "{:?}", err);
+}
+
+#[tokio::test]
+async fn test_trap_after_lookup_miss_is_classified() {
+    // Empty lookup data, so the module doesn't find the value it expects.
+    let wasm_handler = create_wasm_handler(&LOOKUP_WASM_MODULE_BYTES, TestApi::default());
+
+    let request = Request { body: b"StorageGet".to_vec() };
+    let err = wasm_handler.handle_invoke(request).unwrap_err();
+    assert_eq!(invocation_error_class(&err), InvocationErrorClass::LookupMiss, "{:?}", err);
+}
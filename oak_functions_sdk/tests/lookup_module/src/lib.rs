@@ -21,6 +21,7 @@ use std::collections::HashMap;
 use anyhow::{anyhow, Context};
 use assert_matches::assert_matches;
 use maplit::hashmap;
+use oak_functions_sdk::proto::oak::functions::wasm::v1::LogLevel;
 
 type TestFn = fn(&str) -> ();
 
@@ -39,6 +40,11 @@ impl TestManager<'static> {
                 "StorageGet" => Self::test_storage_get as TestFn,
                 "StorageGetItemNotFound" => Self::test_storage_get_item_not_found as TestFn,
                 "LargeKey" => Self::test_storage_get_item_huge_key as TestFn,
+                "ReadTime" => Self::test_read_time as TestFn,
+                "ConsumeBudget" => Self::test_consume_budget as TestFn,
+                "Aggregate" => Self::test_aggregate as TestFn,
+                "ReadRandom" => Self::test_read_random as TestFn,
+                "InvokeModule" => Self::test_invoke_module as TestFn,
             ],
         }
     }
@@ -81,14 +87,81 @@ impl TestManager<'static> {
             .expect("couldn't write second response");
     }
 
-    // TODO(#2417): Test logging of [`oak_functions_sdk::write_log_message`]
+    /// Tests [`oak_functions_sdk::write_log_message`] and
+    /// [`oak_functions_sdk::log`]. The messages are checked in the integration
+    /// test.
     fn test_write_log(request: &str) {
         let result = oak_functions_sdk::write_log_message(request);
         assert_matches!(result, Ok(_));
+        let result = oak_functions_sdk::log(LogLevel::Warn, request);
+        assert_matches!(result, Ok(_));
         let result = oak_functions_sdk::write_response(b"WriteLogResponse");
         assert_matches!(result, Ok(_));
     }
 
+    /// Tests that [`oak_functions_sdk::read_time`] doesn't go backwards. The
+    /// time is checked in the integration test.
+    fn test_read_time(_request: &str) {
+        let first = oak_functions_sdk::read_time().expect("couldn't read time");
+        let second = oak_functions_sdk::read_time().expect("couldn't read time");
+        assert!(second >= first);
+        oak_functions_sdk::write_response(second.to_string().as_bytes())
+            .expect("couldn't write response");
+    }
+
+    /// Tests [`oak_functions_sdk::consume_budget`]. The budget of 1 is set in
+    /// the integration test.
+    fn test_consume_budget(_request: &str) {
+        assert_eq!(oak_functions_sdk::consume_budget(0.75), Ok(0.25));
+        assert_matches!(
+            oak_functions_sdk::consume_budget(0.5),
+            Err(status) if status.message.starts_with("PrivacyBudgetExhausted")
+        );
+        assert_eq!(oak_functions_sdk::consume_budget(0.25), Ok(0.0));
+        oak_functions_sdk::write_response(b"ConsumeBudgetResponse")
+            .expect("couldn't write response");
+    }
+
+    /// Tests [`oak_functions_sdk::aggregate_write`] and
+    /// [`oak_functions_sdk::aggregate_read`]. The integration test invokes
+    /// the module twice with a threshold of 2, and checks the response of the
+    /// second invocation.
+    fn test_aggregate(_request: &str) {
+        oak_functions_sdk::aggregate_write(b"bucket", b"value").expect("couldn't write");
+        assert_matches!(oak_functions_sdk::aggregate_write(b"bucket", b"value"), Err(_));
+        let response = match oak_functions_sdk::aggregate_read(b"bucket") {
+            Ok(values) => values.len().to_string(),
+            Err(status) => status.message,
+        };
+        oak_functions_sdk::write_response(response.as_bytes()).expect("couldn't write response");
+    }
+
+    /// Tests [`oak_functions_sdk::read_random`]. The integration test checks
+    /// that the response is reproducible.
+    fn test_read_random(_request: &str) {
+        let mut random = oak_functions_sdk::read_random(40).expect("couldn't read random");
+        assert_eq!(random.len(), 40);
+        random.extend(oak_functions_sdk::read_random(8).expect("couldn't read random"));
+        oak_functions_sdk::write_response(&random).expect("couldn't write response");
+    }
+
+    /// Tests [`oak_functions_sdk::invoke_module`]. The integration test sets
+    /// this module itself as the helper module called `helper`.
+    fn test_invoke_module(_request: &str) {
+        assert_eq!(
+            oak_functions_sdk::invoke_module("helper", b"ReadWrite"),
+            Ok(b"ReadWriteResponse".to_vec())
+        );
+        // The helper module can't invoke helper modules, so this test fails inside it.
+        assert_matches!(oak_functions_sdk::invoke_module("helper", b"InvokeModule"), Err(_));
+        assert_matches!(
+            oak_functions_sdk::invoke_module("missing", b""),
+            Err(status) if status.message.starts_with("no helper module")
+        );
+        oak_functions_sdk::write_response(b"InvokeModuleResponse")
+            .expect("couldn't write response");
+    }
+
     /// Tests [`oak_functions_sdk::storage_get_item`] when the key is in the
     /// lookup data. The lookup data is set in the integration test. The
     /// value has to be checked in the integration test.
@@ -31,7 +31,7 @@ use oak_functions_service::{
     logger::StandaloneLogger,
     lookup::LookupDataManager,
     wasm::{wasmtime::WasmtimeHandler, WasmHandler},
-    Handler,
+    Handler, HandlerConfig, Subsystems,
 };
 use oak_proto_rust::oak::oak_functions::testing::{
     lookup_request::Mode, LookupRequest, LookupResponse, TestModuleClient,
@@ -67,7 +67,8 @@ fn bench_invoke_lookup(c: &mut Criterion) {
     let test_data = create_test_data(0, MAX_DATA_SIZE);
     test_state
         .lookup_data_manager
-        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())));
+        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+        .unwrap();
     test_state.lookup_data_manager.finish_next_lookup_data();
 
     c.bench_function("lookup wasm", |b| {
@@ -114,12 +115,14 @@ fn bench_invoke_lookup_multi(c: &mut Criterion) {
     let test_data = create_test_data(0, MAX_DATA_SIZE);
     test_state_wasmi
         .lookup_data_manager
-        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())));
+        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+        .unwrap();
     test_state_wasmi.lookup_data_manager.finish_next_lookup_data();
 
     test_state_wasmtime
         .lookup_data_manager
-        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())));
+        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+        .unwrap();
     test_state_wasmtime.lookup_data_manager.finish_next_lookup_data();
 
     fn run_lookup_with_items<H: Handler>(
@@ -179,7 +182,8 @@ fn flamegraph(c: &mut Criterion) {
     let test_data = create_test_data(0, MAX_DATA_SIZE);
     test_state
         .lookup_data_manager
-        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())));
+        .extend_next_lookup_data(test_data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+        .unwrap();
     test_state.lookup_data_manager.finish_next_lookup_data();
 
     fn run_lookup_with_items<H: Handler>(
@@ -233,8 +237,15 @@ fn create_test_state_with_wasm_module_name<H: Handler>(wasm_module_name: &str) -
         oak_functions_test_utils::build_rust_crate_wasm(wasm_module_name).unwrap();
     let wasm_module_bytes = std::fs::read(wasm_module_path).unwrap();
 
-    let wasm_handler =
-        H::new_handler(&wasm_module_bytes, lookup_data_manager.clone(), None).unwrap();
+    let wasm_handler = H::new_handler(
+        &wasm_module_bytes,
+        &HandlerConfig::default(),
+        lookup_data_manager.clone(),
+        Arc::default(),
+        Subsystems::default(),
+        None,
+    )
+    .unwrap();
 
     TestState { wasm_handler, lookup_data_manager }
 }
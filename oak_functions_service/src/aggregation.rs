@@ -0,0 +1,119 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Aggregation before release: Wasm modules write contributions to buckets
+//! with `AggregateWrite`, and can only read a bucket with `AggregateRead` once
+//! it has at least as many contributions as the aggregation threshold.
+//!
+//! Every invocation of the module contributes at most once to a bucket, but
+//! the enclave can't tell whether invocations come from distinct clients, so
+//! that has to be ensured by how clients are admitted. The contributions are
+//! only kept in the memory of the enclave, so they are lost when the enclave
+//! is relaunched.
+
+use alloc::{collections::BTreeMap, format, vec::Vec};
+
+use crate::lookup::{format_bytes, mutexes::Mutex};
+
+/// Holds the contributions to every bucket.
+#[derive(Default)]
+pub struct AggregationStore {
+    // The minimum number of contributions to read a bucket, if one was configured.
+    threshold: Option<u32>,
+    buckets: Mutex<BTreeMap<Vec<u8>, Vec<Vec<u8>>>>,
+}
+
+impl AggregationStore {
+    /// Creates a store whose buckets can be read once they have `threshold`
+    /// contributions, which must be positive.
+    pub fn new(threshold: u32) -> anyhow::Result<Self> {
+        anyhow::ensure!(threshold > 0, "the aggregation threshold must be positive");
+        Ok(Self { threshold: Some(threshold), buckets: Mutex::default() })
+    }
+
+    /// Adds `value` to the contributions to `bucket`.
+    pub fn write(&self, bucket: Vec<u8>, value: Vec<u8>) -> Result<(), micro_rpc::Status> {
+        self.threshold()?;
+        self.buckets.lock().entry(bucket).or_default().push(value);
+        Ok(())
+    }
+
+    /// Returns the contributions to `bucket`, in the order in which they were
+    /// written, if there are enough of them.
+    pub fn read(&self, bucket: &[u8]) -> Result<Vec<Vec<u8>>, micro_rpc::Status> {
+        let threshold = self.threshold()?;
+        let buckets = self.buckets.lock();
+        let values = buckets.get(bucket).map_or(&[][..], Vec::as_slice);
+        if values.len() < threshold as usize {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                format!(
+                    "BelowAggregationThreshold: bucket {} has {} of {} contributions",
+                    format_bytes(bucket),
+                    values.len(),
+                    threshold
+                ),
+            ));
+        }
+        Ok(values.to_vec())
+    }
+
+    fn threshold(&self) -> Result<u32, micro_rpc::Status> {
+        self.threshold.ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "no aggregation threshold was configured",
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn read_after_threshold() {
+        let store = AggregationStore::new(2).unwrap();
+        store.write(b"bucket".to_vec(), b"first".to_vec()).unwrap();
+        store.write(b"other".to_vec(), b"other".to_vec()).unwrap();
+
+        let err = store.read(b"bucket").unwrap_err();
+        assert_eq!(err.code, micro_rpc::StatusCode::FailedPrecondition);
+        assert!(err.message.starts_with("BelowAggregationThreshold"));
+        assert!(store.read(b"missing").is_err());
+
+        store.write(b"bucket".to_vec(), b"second".to_vec()).unwrap();
+        assert_eq!(store.read(b"bucket"), Ok(vec![b"first".to_vec(), b"second".to_vec()]));
+    }
+
+    #[test]
+    fn no_threshold() {
+        assert!(AggregationStore::new(0).is_err());
+
+        let store = AggregationStore::default();
+        assert_eq!(
+            store.write(b"bucket".to_vec(), b"value".to_vec()).unwrap_err().code,
+            micro_rpc::StatusCode::FailedPrecondition
+        );
+        assert_eq!(
+            store.read(b"bucket").unwrap_err().code,
+            micro_rpc::StatusCode::FailedPrecondition
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The time that Wasm modules read with `ReadTime`.
+//!
+//! Reading the time must be enabled by the application config, which is
+//! measured into the attestation evidence together with the granularity of the
+//! time. The time is rounded down to that granularity, so that modules can't
+//! time their own execution more finely, and never goes backwards for the
+//! modules of a clock, even if its source does.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Where a [`Clock`] reads the time from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeSource {
+    /// The clock of the enclave, as on Oak Containers.
+    #[cfg(feature = "std")]
+    System,
+    /// The time the host sends with every request, as on the Restricted Kernel,
+    /// which has no clock. See [`Clock::observe_host_time`].
+    Host,
+}
+
+/// How the time is read, from the application config.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimeConfig {
+    /// Must be positive.
+    pub granularity_seconds: u64,
+    pub source: TimeSource,
+}
+
+/// The time of the Wasm modules of a workload.
+#[derive(Default)]
+pub struct Clock {
+    // How the time is read, if reading it was configured.
+    config: Option<TimeConfig>,
+    // The latest time the host sent, or 0 if it didn't send any.
+    host_time: AtomicU64,
+    // The latest time that was read, so that time never goes backwards.
+    last_time: AtomicU64,
+}
+
+impl Clock {
+    pub fn new(config: TimeConfig) -> anyhow::Result<Self> {
+        anyhow::ensure!(config.granularity_seconds > 0, "the time granularity must be positive");
+        Ok(Self {
+            config: Some(config),
+            host_time: AtomicU64::new(0),
+            last_time: AtomicU64::new(0),
+        })
+    }
+
+    /// Records the time the host sent with a request, in seconds since the Unix
+    /// epoch.
+    pub fn observe_host_time(&self, unix_time_seconds: u64) {
+        self.host_time.store(unix_time_seconds, Ordering::Relaxed);
+    }
+
+    /// Returns the time in seconds since the Unix epoch, rounded down to the
+    /// configured granularity.
+    pub fn now(&self) -> Result<u64, micro_rpc::Status> {
+        let config = self.config.ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "no time granularity was configured",
+            )
+        })?;
+        let now = match config.source {
+            #[cfg(feature = "std")]
+            TimeSource::System => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            TimeSource::Host => match self.host_time.load(Ordering::Relaxed) {
+                0 => {
+                    return Err(micro_rpc::Status::new_with_message(
+                        micro_rpc::StatusCode::Unavailable,
+                        "the host didn't send the time",
+                    ))
+                }
+                time => time,
+            },
+        };
+        let now = now - now % config.granularity_seconds;
+        let last = self.last_time.fetch_max(now, Ordering::Relaxed);
+        Ok(last.max(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_time_is_rounded_and_monotonic() {
+        let clock =
+            Clock::new(TimeConfig { granularity_seconds: 60, source: TimeSource::Host }).unwrap();
+        assert_eq!(clock.now().unwrap_err().code, micro_rpc::StatusCode::Unavailable);
+
+        clock.observe_host_time(1_000_030);
+        assert_eq!(clock.now(), Ok(999_960));
+
+        // The host's time going backwards doesn't move the clock back.
+        clock.observe_host_time(900_000);
+        assert_eq!(clock.now(), Ok(999_960));
+
+        clock.observe_host_time(1_000_100);
+        assert_eq!(clock.now(), Ok(1_000_080));
+    }
+
+    #[test]
+    fn clocks_are_independent() {
+        let config = TimeConfig { granularity_seconds: 1, source: TimeSource::Host };
+        let first = Clock::new(config).unwrap();
+        let second = Clock::new(config).unwrap();
+        first.observe_host_time(2_000);
+        second.observe_host_time(1_000);
+        assert_eq!(first.now(), Ok(2_000));
+        assert_eq!(second.now(), Ok(1_000));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn system_time_is_rounded() {
+        let clock =
+            Clock::new(TimeConfig { granularity_seconds: 3600, source: TimeSource::System })
+                .unwrap();
+        let now = clock.now().unwrap();
+        assert!(now > 0);
+        assert_eq!(now % 3600, 0);
+    }
+
+    #[test]
+    fn no_granularity() {
+        assert!(
+            Clock::new(TimeConfig { granularity_seconds: 0, source: TimeSource::Host }).is_err()
+        );
+
+        let clock = Clock::default();
+        clock.observe_host_time(1_000);
+        assert_eq!(clock.now().unwrap_err().code, micro_rpc::StatusCode::FailedPrecondition);
+    }
+}
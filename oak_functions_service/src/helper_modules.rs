@@ -0,0 +1,98 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Helper Wasm modules that the main module invokes by name with
+//! `InvokeModule`, e.g. a tokenizer or a policy checker, so that they don't
+//! have to be linked into the main module.
+//!
+//! Helper modules run in their own handlers, with the same lookup data,
+//! secrets and limits as the main module, but they can't invoke modules
+//! themselves, so that invocations never recurse.
+
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+
+use oak_functions_abi::Request;
+
+use crate::Handler;
+
+/// A handler that the main module can invoke.
+pub trait HelperModule: Send + Sync {
+    fn invoke(&self, request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status>;
+}
+
+impl<H: Handler + Send + Sync> HelperModule for H {
+    fn invoke(&self, request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status> {
+        let response = self.handle_invoke(Request { body: request })?;
+        response.body().map(<[u8]>::to_vec).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::Internal,
+                format!("invalid response of helper module: {:?}", err),
+            )
+        })
+    }
+}
+
+/// The helper modules of the main module, by name.
+#[derive(Default)]
+pub struct HelperModules {
+    modules: BTreeMap<String, Arc<dyn HelperModule>>,
+}
+
+impl HelperModules {
+    pub fn new(modules: BTreeMap<String, Arc<dyn HelperModule>>) -> Self {
+        Self { modules }
+    }
+
+    /// Invokes the helper module called `name` with `request`, and returns its
+    /// response.
+    pub fn invoke(&self, name: &str, request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status> {
+        let module = self.modules.get(name).ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::NotFound,
+                format!("no helper module called {}", name),
+            )
+        })?;
+        module.invoke(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    struct Reverse;
+
+    impl HelperModule for Reverse {
+        fn invoke(&self, mut request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status> {
+            request.reverse();
+            Ok(request)
+        }
+    }
+
+    #[test]
+    fn invoke_by_name() {
+        let reverse: Arc<dyn HelperModule> = Arc::new(Reverse);
+        let helper_modules = HelperModules::new(BTreeMap::from([("reverse".into(), reverse)]));
+        assert_eq!(helper_modules.invoke("reverse", vec![1, 2, 3]), Ok(vec![3, 2, 1]));
+        assert_eq!(
+            helper_modules.invoke("missing", vec![]).unwrap_err().code,
+            micro_rpc::StatusCode::NotFound
+        );
+        assert!(HelperModules::default().invoke("reverse", vec![]).is_err());
+    }
+}
@@ -14,86 +14,718 @@
 // limitations under the License.
 //
 
-use alloc::{format, sync::Arc};
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc};
 
 use micro_rpc::{Status, Vec};
+use oak_attestation_verification::peer::verify_peer_attestation;
 use oak_functions_abi::Request;
+use oak_proto_rust::oak::{
+    attestation::v1::{AppraisalPolicy, PeerAttestation},
+    oak_functions::abi::{BatchRequest, BatchResponse, TenantRequest},
+};
+use prost::Message;
 
 use crate::{
+    aggregation::AggregationStore,
+    clock::{Clock, TimeConfig},
+    helper_modules::{HelperModule, HelperModules},
     logger::StandaloneLogger,
-    lookup::LookupDataManager,
-    proto::oak::functions::{
-        AbortNextLookupDataResponse, Empty, ExtendNextLookupDataRequest,
-        ExtendNextLookupDataResponse, FinishNextLookupDataRequest, FinishNextLookupDataResponse,
-        InitializeRequest, LookupDataChunk, ReserveRequest, ReserveResponse,
+    lookup::{mutexes::RwLock, LookupDataManager, MemoryBudgetExceeded},
+    lookup_encryption::{decrypt_lookup_data_chunk, LOOKUP_DATA_KEY},
+    lookup_index::LookupIndex,
+    lookup_signing::LookupDataVerifier,
+    ml::InferenceModel,
+    precompiled::{open_precompiled_module, seal_precompiled_module, wasm_module_digest},
+    privacy_budget::PrivacyBudget,
+    proto::oak::{
+        crypto::v1::EncryptedRequest,
+        functions::{
+            AbortNextLookupDataResponse, Empty, ExtendNextLookupDataRequest,
+            ExtendNextLookupDataResponse, FinishNextLookupDataRequest,
+            FinishNextLookupDataResponse, GetPrecompiledWasmModuleRequest,
+            GetPrecompiledWasmModuleResponse, InitializeRequest, LookupDataChunk,
+            LookupDataMemoryUsage, PersistentState, ProvisionSecretsResponse, ProvisionedSecrets,
+            ReloadWasmRequest, ReloadWasmResponse, ReserveRequest, ReserveResponse,
+        },
     },
-    Handler, Observer,
+    response_size,
+    secrets::SecretStore,
+    wasm::memory::MemoryLimits,
+    workers::WorkerPool,
+    Handler, HandlerConfig, Observer, Subsystems,
 };
 
 pub struct OakFunctionsInstance<H: Handler> {
     lookup_data_manager: Arc<LookupDataManager>,
-    wasm_handler: H::HandlerType,
+    observer: Option<Arc<dyn Observer + Send + Sync>>,
+    peer_attestation_policy: Option<AppraisalPolicy>,
+    secret_store: Arc<SecretStore>,
+    // Set if the lookup data must be signed.
+    lookup_data_verifier: Option<LookupDataVerifier>,
+    // Whether requests are `TenantRequest`s that are routed to the workload of their tenant.
+    multi_tenant: bool,
+    // The workloads by tenant ID. If the instance doesn't serve several tenants, its only workload
+    // has an empty ID.
+    workloads: BTreeMap<String, Workload<H>>,
+    // Runs the invocations of all workloads.
+    workers: WorkerPool,
+    // The AES-256-GCM key of precompiled Wasm modules, if the enclave supports them.
+    precompiled_module_key: Option<Vec<u8>>,
+}
+
+// A Wasm module with everything it uses that isn't shared by all tenants.
+struct Workload<H: Handler> {
+    max_response_size: Option<u32>,
+    constant_response_size: u32,
+    // Kept across reloads of the Wasm module, as they belong to the deployment. This also means
+    // that the inference model is only loaded once, as loading large models is slow.
+    subsystems: Subsystems,
+    handler_config: HandlerConfig,
+    // Swapped out as a whole by `reload_wasm`. Requests hold on to their own reference to the
+    // module, so requests in flight during a reload complete against the previous module.
+    wasm_module: RwLock<Arc<LoadedModule<H>>>,
+}
+
+// A Wasm module that a workload runs.
+struct LoadedModule<H: Handler> {
+    handler: H::HandlerType,
+    // Kept with the handler, so that a precompiled module is never bound to another module.
+    digest: [u8; 32],
 }
 
 impl<H: Handler> OakFunctionsInstance<H> {
     /// See [`crate::proto::oak::functions::OakFunctions::initialize`].
+    ///
+    /// The `aggregation_threshold`, `time_config` and
+    /// `lookup_data_signing_public_key` aren't part of the request, as they
+    /// must come from configuration that is bound into the attestation
+    /// evidence. If the key is set, the instance refuses lookup data that isn't
+    /// signed with it.
+    /// Precompiled Wasm modules are only supported if the platform provides a
+    /// `precompiled_module_key` that all enclaves of the group derive, see
+    /// [`crate::precompiled`].
     pub fn new(
         request: &InitializeRequest,
+        aggregation_threshold: Option<u32>,
+        time_config: Option<TimeConfig>,
+        lookup_data_signing_public_key: Option<&[u8]>,
+        precompiled_module_key: Option<Vec<u8>>,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
     ) -> Result<Self, micro_rpc::Status> {
-        let lookup_data_manager =
-            Arc::new(LookupDataManager::new_empty(Arc::new(StandaloneLogger)));
-        let wasm_handler =
-            H::new_handler(&request.wasm_module, lookup_data_manager.clone(), observer).map_err(
+        let wasm_engine = request.wasm_engine();
+        if !H::supports_wasm_engine(wasm_engine) {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("Wasm engine {} isn't supported", wasm_engine.as_str_name()),
+            ));
+        }
+        if let Some(policy) = request.peer_attestation_policy.as_ref() {
+            // Peers don't send their endorsements, so claims about them can never be
+            // checked.
+            if !policy.required_endorsement_claim_types.is_empty() {
+                return Err(micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::InvalidArgument,
+                    "peer attestation policies can't require endorsement claims",
+                ));
+            }
+        }
+        let multi_tenant = !request.tenants.is_empty();
+        if multi_tenant
+            && !(request.wasm_module.is_empty()
+                && request.helper_modules.is_empty()
+                && request.precompiled_wasm_module.is_none())
+        {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                "instances that serve several tenants can't have a Wasm module, helper modules or \
+                 a precompiled Wasm module",
+            ));
+        }
+        let lookup_data_verifier = lookup_data_signing_public_key
+            .map(LookupDataVerifier::new)
+            .transpose()
+            .map_err(invalid_argument)?;
+        let mut lookup_data_manager = LookupDataManager::new_empty(Arc::new(StandaloneLogger));
+        if request.lookup_data_ordered_index {
+            lookup_data_manager = lookup_data_manager.with_ordered_index();
+        }
+        if request.lookup_data_memory_budget > 0 {
+            lookup_data_manager =
+                lookup_data_manager.with_memory_budget(request.lookup_data_memory_budget as usize);
+        }
+        let inference_model = if request.ml_model.is_empty() {
+            InferenceModel::default()
+        } else {
+            InferenceModel::load(&request.ml_model).map_err(invalid_argument)?
+        };
+        let inference_model = Arc::new(inference_model);
+        let mut instance = Self {
+            lookup_data_manager: Arc::new(lookup_data_manager),
+            observer,
+            peer_attestation_policy: request.peer_attestation_policy.clone(),
+            secret_store: Arc::new(SecretStore::default()),
+            lookup_data_verifier,
+            multi_tenant,
+            workloads: BTreeMap::new(),
+            workers: WorkerPool::new(
+                (request.max_concurrent_invocations > 0)
+                    .then_some(request.max_concurrent_invocations as usize),
+            ),
+            precompiled_module_key,
+        };
+        if multi_tenant {
+            for (tenant_id, tenant) in &request.tenants {
+                if tenant_id.is_empty() || tenant_id.contains('/') {
+                    return Err(micro_rpc::Status::new_with_message(
+                        micro_rpc::StatusCode::InvalidArgument,
+                        format!("invalid tenant ID {:?}", tenant_id),
+                    ));
+                }
+                let workload = instance
+                    .new_workload(
+                        request,
+                        tenant_id,
+                        &tenant.wasm_module,
+                        tenant.constant_response_size,
+                        aggregation_threshold,
+                        time_config,
+                        &inference_model,
+                    )
+                    .map_err(|status| {
+                        micro_rpc::Status::new_with_message(
+                            status.code,
+                            format!("tenant {}: {}", tenant_id, status.message),
+                        )
+                    })?;
+                instance.workloads.insert(tenant_id.clone(), workload);
+            }
+        } else {
+            let workload = instance.new_workload(
+                request,
+                "",
+                &request.wasm_module,
+                request.constant_response_size,
+                aggregation_threshold,
+                time_config,
+                &inference_model,
+            )?;
+            instance.workloads.insert(String::new(), workload);
+        }
+        Ok(instance)
+    }
+    // Creates the workload of the tenant with `tenant_id`, which is empty if the
+    // instance doesn't serve several tenants.
+    fn new_workload(
+        &self,
+        request: &InitializeRequest,
+        tenant_id: &str,
+        wasm_module: &[u8],
+        constant_response_size: u32,
+        aggregation_threshold: Option<u32>,
+        time_config: Option<TimeConfig>,
+        inference_model: &Arc<InferenceModel>,
+    ) -> Result<Workload<H>, micro_rpc::Status> {
+        let max_response_size = declared_max_response_size(wasm_module)?;
+        let constant_response_size =
+            response_size::constant_response_size(constant_response_size, max_response_size)
+                .map_err(invalid_argument)?;
+        let handler_config = HandlerConfig {
+            wasm_engine: request.wasm_engine(),
+            wasm_fuel_limit: (request.wasm_fuel_limit > 0).then_some(request.wasm_fuel_limit),
+            wasm_memory_limits: MemoryLimits {
+                max_bytes: (request.wasm_memory_limit > 0).then_some(request.wasm_memory_limit),
+                growth_disabled: request.wasm_memory_growth_disabled,
+            },
+            lookup_namespace: if tenant_id.is_empty() {
+                Vec::new()
+            } else {
+                format!("{}/", tenant_id).into_bytes()
+            },
+            max_concurrent_invocations: (request.max_concurrent_invocations > 0)
+                .then_some(request.max_concurrent_invocations),
+        };
+        let privacy_budget = if request.privacy_budget_epsilon == 0.0 {
+            PrivacyBudget::default()
+        } else {
+            PrivacyBudget::new(request.privacy_budget_epsilon).map_err(invalid_argument)?
+        };
+        let aggregation_store = match aggregation_threshold {
+            Some(threshold) => AggregationStore::new(threshold).map_err(invalid_argument)?,
+            None => AggregationStore::default(),
+        };
+        let clock = match time_config {
+            Some(time_config) => Clock::new(time_config).map_err(invalid_argument)?,
+            None => Clock::default(),
+        };
+        let mut subsystems = Subsystems {
+            privacy_budget: Arc::new(privacy_budget),
+            aggregation_store: Arc::new(aggregation_store),
+            clock: Arc::new(clock),
+            inference_model: inference_model.clone(),
+            helper_modules: Arc::default(),
+        };
+        // Helper modules share the subsystems of the main module, except for the
+        // helper modules themselves. They don't report to the observer, whose
+        // metrics are about the main module.
+        let mut helper_modules = BTreeMap::<String, Arc<dyn HelperModule>>::new();
+        for (name, wasm_module) in &request.helper_modules {
+            let helper_module = new_wasm_handler::<H>(
+                wasm_module,
+                &handler_config,
+                &self.lookup_data_manager,
+                &self.secret_store,
+                &subsystems,
+                &None,
+            )
+            .map_err(|status| {
+                micro_rpc::Status::new_with_message(
+                    status.code,
+                    format!("helper module {}: {}", name, status.message),
+                )
+            })?;
+            helper_modules.insert(name.clone(), Arc::new(helper_module));
+        }
+        subsystems.helper_modules = Arc::new(HelperModules::new(helper_modules));
+        let digest = wasm_module_digest(wasm_module);
+        // Only the initial module of the instance may be precompiled, so the config
+        // kept for reloads doesn't have the precompiled module.
+        let precompiled_module = self.decrypt_precompiled_module(request, &digest);
+        let wasm_handler = new_wasm_handler::<H>(
+            wasm_module,
+            &HandlerConfig { precompiled_module, ..handler_config.clone() },
+            &self.lookup_data_manager,
+            &self.secret_store,
+            &subsystems,
+            &self.observer,
+        )?;
+        Ok(Workload {
+            max_response_size,
+            constant_response_size,
+            subsystems,
+            handler_config,
+            wasm_module: RwLock::new(Arc::new(LoadedModule { handler: wasm_handler, digest })),
+        })
+    }
+    // Returns the precompiled Wasm module of `request`, if it can be decrypted as
+    // compiled from the module with `digest`.
+    fn decrypt_precompiled_module(
+        &self,
+        request: &InitializeRequest,
+        digest: &[u8; 32],
+    ) -> Option<Arc<[u8]>> {
+        let sealed = request.precompiled_wasm_module.as_ref()?;
+        let Some(key) = self.precompiled_module_key.as_ref() else {
+            log::warn!("ignoring precompiled Wasm module, as they aren't supported");
+            return None;
+        };
+        match open_precompiled_module(key, digest, sealed) {
+            Ok(precompiled_module) => Some(precompiled_module.into()),
+            Err(err) => {
+                log::warn!("ignoring precompiled Wasm module: {:?}", err);
+                None
+            }
+        }
+    }
+    /// Returns the maximum response size declared by the Wasm module the
+    /// instance was initialized with, if any. Not set if the instance serves
+    /// several tenants.
+    pub fn max_response_size(&self) -> Option<u32> {
+        self.workloads.get("").and_then(|workload| workload.max_response_size)
+    }
+    /// Returns the size all responses are padded to, or zero if the instance
+    /// serves several tenants.
+    pub fn constant_response_size(&self) -> u32 {
+        self.workloads.get("").map_or(0, |workload| workload.constant_response_size)
+    }
+    /// Records the time the host sent with a request, for the Wasm modules of
+    /// every workload that read the time from the host.
+    pub fn observe_host_time(&self, unix_time_seconds: u64) {
+        for workload in self.workloads.values() {
+            workload.subsystems.clock.observe_host_time(unix_time_seconds);
+        }
+    }
+    /// Returns the size the responses of every tenant are padded to, if the
+    /// instance serves several tenants.
+    pub fn tenant_constant_response_sizes(&self) -> BTreeMap<String, u32> {
+        if !self.multi_tenant {
+            return BTreeMap::new();
+        }
+        self.workloads
+            .iter()
+            .map(|(tenant_id, workload)| (tenant_id.clone(), workload.constant_response_size))
+            .collect()
+    }
+    /// Checks that `request` carries the attestation of a peer that satisfies
+    /// the peer attestation policy the instance was initialized with, if any.
+    ///
+    /// The attestation is read from the associated data before the request is
+    /// decrypted, and is only authenticated by decrypting the request, so the
+    /// request must be rejected if it can't be decrypted afterwards. The
+    /// collateral of Intel TDX peers is checked against `now_utc_millis`.
+    pub fn check_peer_attestation(
+        &self,
+        request: &EncryptedRequest,
+        now_utc_millis: i64,
+    ) -> Result<(), micro_rpc::Status> {
+        let Some(policy) = self.peer_attestation_policy.as_ref() else {
+            return Ok(());
+        };
+        let associated_data = request
+            .encrypted_message
+            .as_ref()
+            .map(|message| message.associated_data.as_slice())
+            .unwrap_or_default();
+        let serialized_encapsulated_public_key =
+            request.serialized_encapsulated_public_key.as_deref().unwrap_or_default();
+        PeerAttestation::decode(associated_data)
+            .map_err(anyhow::Error::msg)
+            .and_then(|attestation| {
+                verify_peer_attestation(
+                    now_utc_millis,
+                    policy,
+                    &attestation,
+                    serialized_encapsulated_public_key,
+                )
+            })
+            .map(|_| ())
+            .map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::PermissionDenied,
+                    format!("couldn't verify peer attestation: {:?}", err),
+                )
+            })
+    }
+    /// See [`crate::proto::oak::functions::OakFunctions::handle_user_request`].
+    pub fn handle_user_request(&self, request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status> {
+        let (workload, request) = self.route(request)?;
+        workload.subsystems.privacy_budget.check_not_exhausted()?;
+        // Don't hold the lock while handling the request, so that a reload doesn't have
+        // to wait for requests in flight.
+        let wasm_module = workload.wasm_module.read().clone();
+        // TODO(#3442): Implement constant response size policy.
+        self.workers
+            .run(|| wasm_module.handler.handle_invoke(Request { body: request }))
+            .map(|response| response.body)
+    }
+    /// See [`crate::proto::oak::functions::OakFunctions::invoke_batch`].
+    pub fn handle_user_request_batch(
+        &self,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>, micro_rpc::Status> {
+        let (workload, request) = self.route(request)?;
+        workload.subsystems.privacy_budget.check_not_exhausted()?;
+        let batch_request = BatchRequest::decode(request.as_slice()).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("couldn't decode batch request: {:?}", err),
+            )
+        })?;
+        let wasm_module = workload.wasm_module.read().clone();
+        // The requests of a batch run one after the other on the same worker.
+        let responses = self
+            .workers
+            .run(|| {
+                wasm_module.handler.handle_invoke_batch(
+                    batch_request.requests.into_iter().map(|body| Request { body }).collect(),
+                )
+            })
+            .into_iter()
+            .map(|response| {
+                let response: micro_rpc::ResponseWrapper =
+                    response.map(|response| response.body).into();
+                response.encode_to_vec()
+            })
+            .collect();
+        Ok(BatchResponse { responses }.encode_to_vec())
+    }
+    /// See [`crate::proto::oak::functions::OakFunctions::reload_wasm`].
+    pub fn reload_wasm(
+        &self,
+        request: &ReloadWasmRequest,
+    ) -> Result<ReloadWasmResponse, micro_rpc::Status> {
+        let workload = self.workload(&request.tenant_id)?;
+        // The constant response size is fixed for the lifetime of the instance, so the
+        // new module must fit in it.
+        let max_response_size = declared_max_response_size(&request.wasm_module)?;
+        response_size::constant_response_size(workload.constant_response_size, max_response_size)
+            .map_err(invalid_argument)?;
+        // Initialize the new module before taking the lock, so that requests keep being
+        // served by the current module in the meantime, and a module that fails
+        // to initialize leaves the current one in place.
+        let wasm_handler = new_wasm_handler::<H>(
+            &request.wasm_module,
+            &workload.handler_config,
+            &self.lookup_data_manager,
+            &self.secret_store,
+            &workload.subsystems,
+            &self.observer,
+        )?;
+        let digest = wasm_module_digest(&request.wasm_module);
+        *workload.wasm_module.write() = Arc::new(LoadedModule { handler: wasm_handler, digest });
+        Ok(ReloadWasmResponse {})
+    }
+    /// See [`crate::proto::oak::functions::OakFunctions::get_precompiled_wasm_module`].
+    pub fn get_precompiled_wasm_module(
+        &self,
+        _request: &GetPrecompiledWasmModuleRequest,
+    ) -> Result<GetPrecompiledWasmModuleResponse, micro_rpc::Status> {
+        let key = self.precompiled_module_key.as_ref().ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "precompiled Wasm modules aren't supported",
+            )
+        })?;
+        if self.multi_tenant {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "precompiled Wasm modules aren't supported for several tenants",
+            ));
+        }
+        let wasm_module = self.workload("")?.wasm_module.read().clone();
+        let precompiled_module = wasm_module.handler.precompiled_module().ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "the Wasm engine doesn't precompile modules",
+            )
+        })?;
+        let precompiled_wasm_module =
+            seal_precompiled_module(key, &wasm_module.digest, &precompiled_module).map_err(
                 |err| {
                     micro_rpc::Status::new_with_message(
                         micro_rpc::StatusCode::Internal,
-                        format!("couldn't initialize Wasm handler: {:?}", err),
+                        format!("{:?}", err),
                     )
                 },
             )?;
-        Ok(Self { lookup_data_manager, wasm_handler })
+        Ok(GetPrecompiledWasmModuleResponse {
+            precompiled_wasm_module: Some(precompiled_wasm_module),
+        })
     }
-    /// See [`crate::proto::oak::functions::OakFunctions::handle_user_request`].
-    pub fn handle_user_request(&self, request: Vec<u8>) -> Result<Vec<u8>, micro_rpc::Status> {
-        // TODO(#3442): Implement constant response size policy.
-        self.wasm_handler.handle_invoke(Request { body: request }).map(|response| response.body)
+    /// Returns the state that the next enclave should start from, which the
+    /// platform seals, see [`crate::proto::oak::functions::PersistentState`].
+    pub fn persistent_state(&self) -> PersistentState {
+        PersistentState {
+            consumed_privacy_budget: self
+                .workloads
+                .iter()
+                .map(|(tenant_id, workload)| {
+                    (tenant_id.clone(), workload.subsystems.privacy_budget.consumed())
+                })
+                .collect(),
+        }
+    }
+    /// Restores the state of an earlier enclave, as returned by
+    /// [`Self::persistent_state`].
+    pub fn restore_persistent_state(
+        &self,
+        state: &PersistentState,
+    ) -> Result<(), micro_rpc::Status> {
+        for (tenant_id, consumed) in &state.consumed_privacy_budget {
+            // The tenants may have changed since the state was sealed.
+            match self.workloads.get(tenant_id) {
+                Some(workload) => workload.subsystems.privacy_budget.restore_consumed(*consumed)?,
+                None => log::warn!("ignoring the privacy budget of unknown tenant {}", tenant_id),
+            }
+        }
+        Ok(())
+    }
+    // Returns the workload that handles `request`, and the request to the workload,
+    // which is wrapped in a `TenantRequest` if the instance serves several
+    // tenants.
+    fn route(&self, request: Vec<u8>) -> Result<(&Workload<H>, Vec<u8>), micro_rpc::Status> {
+        if !self.multi_tenant {
+            return Ok((self.workload("")?, request));
+        }
+        let TenantRequest { tenant_id, body } =
+            TenantRequest::decode(request.as_slice()).map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::InvalidArgument,
+                    format!("couldn't decode tenant request: {:?}", err),
+                )
+            })?;
+        Ok((self.workload(&tenant_id)?, body))
+    }
+    // Returns the workload of the tenant with `tenant_id`, which is empty if the
+    // instance doesn't serve several tenants.
+    fn workload(&self, tenant_id: &str) -> Result<&Workload<H>, micro_rpc::Status> {
+        self.workloads.get(tenant_id).ok_or_else(|| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::NotFound,
+                format!("no tenant called {}", tenant_id),
+            )
+        })
+    }
+    /// See [`crate::proto::oak::functions::OakFunctions::provision_secrets`].
+    ///
+    /// `secrets` is the decrypted plaintext of the request, a serialized
+    /// [`ProvisionedSecrets`] message.
+    pub fn provision_secrets(
+        &self,
+        secrets: &[u8],
+    ) -> Result<ProvisionSecretsResponse, micro_rpc::Status> {
+        let secrets = ProvisionedSecrets::decode(secrets).map_err(|err| {
+            micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("couldn't decode provisioned secrets: {:?}", err),
+            )
+        })?;
+        self.secret_store.provision(
+            secrets.secrets.into_iter().map(|secret| (secret.name, secret.value)).collect(),
+        );
+        let secret_count = self.secret_store.len() as u32;
+        log::info!("provisioned {} secrets", secret_count);
+        Ok(ProvisionSecretsResponse { secret_count })
     }
     /// See [`crate::proto::oak::functions::OakFunctions::extend_next_lookup_data`].
     pub fn extend_next_lookup_data(
         &self,
         request: ExtendNextLookupDataRequest,
     ) -> Result<ExtendNextLookupDataResponse, micro_rpc::Status> {
-        self.lookup_data_manager.extend_next_lookup_data(to_data(request.chunk.as_ref().ok_or(
-            micro_rpc::Status::new_with_message(
-                micro_rpc::StatusCode::InvalidArgument,
-                "no chunk in extend request",
-            ),
-        )?));
-        Ok(ExtendNextLookupDataResponse {})
+        if request.shard.is_some() {
+            self.check_unsigned("sharded")?;
+        }
+        match (request.chunk, request.encrypted_chunk) {
+            (Some(chunk), None) => self.extend(request.shard, &chunk)?,
+            (None, Some(encrypted_chunk)) => {
+                let key = self.secret_store.get(LOOKUP_DATA_KEY).ok_or_else(|| {
+                    micro_rpc::Status::new_with_message(
+                        micro_rpc::StatusCode::FailedPrecondition,
+                        "no lookup data key provisioned",
+                    )
+                })?;
+                let chunk =
+                    decrypt_lookup_data_chunk(&key, &encrypted_chunk).map_err(invalid_argument)?;
+                self.extend(request.shard, &chunk)?;
+            }
+            _ => {
+                return Err(micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::InvalidArgument,
+                    "extend request must have exactly one of chunk and encrypted chunk",
+                ))
+            }
+        }
+        Ok(ExtendNextLookupDataResponse { memory_usage: Some(self.memory_usage()) })
     }
 
     pub fn extend_lookup_data_chunk(
         &self,
         chunk: LookupDataChunk,
     ) -> Result<(), micro_rpc::Status> {
-        self.lookup_data_manager.extend_next_lookup_data(to_data(&chunk));
-        Ok(())
+        self.extend(None, &chunk)
+    }
+
+    // Extends the next lookup data (or the given shard of it), and its digest if
+    // the lookup data must be signed. If the next lookup data exceeds the memory
+    // budget, it is discarded.
+    fn extend(&self, shard: Option<u32>, chunk: &LookupDataChunk) -> Result<(), micro_rpc::Status> {
+        if let Some(shard) = shard {
+            return self
+                .lookup_data_manager
+                .extend_next_shard(shard, to_data(chunk))
+                .map_err(memory_budget_exceeded);
+        }
+        if let Some(verifier) = self.lookup_data_verifier.as_ref() {
+            verifier.extend(to_data(chunk));
+        }
+        self.lookup_data_manager.extend_next_lookup_data(to_data(chunk)).map_err(|err| {
+            if let Some(verifier) = self.lookup_data_verifier.as_ref() {
+                verifier.abort();
+            }
+            memory_budget_exceeded(err)
+        })
+    }
+
+    // Returns the memory used by the lookup data.
+    fn memory_usage(&self) -> LookupDataMemoryUsage {
+        let usage = self.lookup_data_manager.memory_usage();
+        LookupDataMemoryUsage {
+            active_bytes: usage.active as u64,
+            next_bytes: usage.next as u64,
+            budget_bytes: usage.budget.unwrap_or(0) as u64,
+        }
     }
 
     /// See [`crate::proto::oak::functions::OakFunctions::finish_next_lookup_data`].
+    ///
+    /// If the lookup data must be signed, the next lookup data is discarded
+    /// unless the request carries a valid signature of it. Sharded lookup data
+    /// is discarded unless all its shards match the manifest.
     pub fn finish_next_lookup_data(
         &self,
-        _request: FinishNextLookupDataRequest,
+        request: FinishNextLookupDataRequest,
     ) -> Result<FinishNextLookupDataResponse, micro_rpc::Status> {
+        if let Some(manifest) = request.manifest {
+            self.check_unsigned("sharded")?;
+            let digests = manifest
+                .shards
+                .iter()
+                .map(|shard| {
+                    <[u8; 32]>::try_from(shard.digest.as_slice()).map_err(|_| {
+                        micro_rpc::Status::new_with_message(
+                            micro_rpc::StatusCode::InvalidArgument,
+                            "shard digests must be SHA-256 digests",
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            return self
+                .lookup_data_manager
+                .finish_next_sharded_lookup_data(&digests)
+                .map(|()| FinishNextLookupDataResponse { memory_usage: Some(self.memory_usage()) })
+                .map_err(|err| {
+                    micro_rpc::Status::new_with_message(
+                        micro_rpc::StatusCode::FailedPrecondition,
+                        format!("rejected sharded lookup data: {:?}", err),
+                    )
+                });
+        }
+        if let Some(verifier) = self.lookup_data_verifier.as_ref() {
+            match verifier.verify_next(request.signature.as_ref()) {
+                Ok(version) => log::info!("activating lookup data version {}", version),
+                Err(err) => {
+                    self.lookup_data_manager.abort_next_lookup_data();
+                    return Err(micro_rpc::Status::new_with_message(
+                        micro_rpc::StatusCode::PermissionDenied,
+                        format!("rejected lookup data: {:?}", err),
+                    ));
+                }
+            }
+        }
         self.lookup_data_manager.finish_next_lookup_data();
-        Ok(FinishNextLookupDataResponse {})
+        Ok(FinishNextLookupDataResponse { memory_usage: Some(self.memory_usage()) })
+    }
+
+    /// Replaces the current lookup data with an index of it built by the
+    /// launcher, see [`crate::lookup_index`].
+    pub fn finish_next_lookup_index(
+        &self,
+        index: LookupIndex,
+    ) -> Result<FinishNextLookupDataResponse, micro_rpc::Status> {
+        self.check_unsigned("indexed")?;
+        self.lookup_data_manager.finish_next_lookup_index(index).map_err(memory_budget_exceeded)?;
+        Ok(FinishNextLookupDataResponse { memory_usage: Some(self.memory_usage()) })
     }
+
+    // Sharded and indexed lookup data can't be signed yet, so they must be
+    // rejected if the lookup data must be signed.
+    fn check_unsigned(&self, kind: &str) -> Result<(), micro_rpc::Status> {
+        if self.lookup_data_verifier.is_some() {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                format!("{} lookup data can't be signed", kind),
+            ));
+        }
+        Ok(())
+    }
+
     /// See [`crate::proto::oak::functions::OakFunctions::abort_next_lookup_data`].
     pub fn abort_next_lookup_data(
         &self,
         _request: Empty,
     ) -> Result<AbortNextLookupDataResponse, Status> {
+        if let Some(verifier) = self.lookup_data_verifier.as_ref() {
+            verifier.abort();
+        }
         self.lookup_data_manager.abort_next_lookup_data();
         Ok(AbortNextLookupDataResponse {})
     }
@@ -111,6 +743,51 @@ impl<H: Handler> OakFunctionsInstance<H> {
     }
 }
 
+// Helper function to read the maximum response size declared by a Wasm module.
+fn declared_max_response_size(wasm_module: &[u8]) -> Result<Option<u32>, micro_rpc::Status> {
+    response_size::declared_max_response_size(wasm_module).map_err(invalid_argument)
+}
+
+fn memory_budget_exceeded(err: MemoryBudgetExceeded) -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::ResourceExhausted,
+        format!("{}", err),
+    )
+}
+
+fn invalid_argument(err: anyhow::Error) -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::InvalidArgument,
+        format!("{:?}", err),
+    )
+}
+
+// Helper function to create a Wasm handler backed by the given lookup data,
+// secrets and subsystems.
+fn new_wasm_handler<H: Handler>(
+    wasm_module: &[u8],
+    handler_config: &HandlerConfig,
+    lookup_data_manager: &Arc<LookupDataManager>,
+    secret_store: &Arc<SecretStore>,
+    subsystems: &Subsystems,
+    observer: &Option<Arc<dyn Observer + Send + Sync>>,
+) -> Result<H::HandlerType, micro_rpc::Status> {
+    H::new_handler(
+        wasm_module,
+        handler_config,
+        lookup_data_manager.clone(),
+        secret_store.clone(),
+        subsystems.clone(),
+        observer.clone(),
+    )
+    .map_err(|err| {
+        micro_rpc::Status::new_with_message(
+            micro_rpc::StatusCode::Internal,
+            format!("couldn't initialize Wasm handler: {:?}", err),
+        )
+    })
+}
+
 // Helper function to convert [`LookupDataChunk`] to [`Data`].
 fn to_data(chunk: &LookupDataChunk) -> impl Iterator<Item = (&[u8], &[u8])> {
     chunk.items.iter().map(|entry| (entry.key.as_ref(), entry.value.as_ref()))
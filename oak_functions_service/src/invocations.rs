@@ -0,0 +1,201 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Buffers of streaming invocations, whose encrypted request is uploaded and
+//! whose encrypted response is downloaded in chunks, as neither may fit in a
+//! single message.
+
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap},
+    format,
+    vec::Vec,
+};
+
+use micro_rpc::{Status, StatusCode};
+
+use crate::lookup::mutexes::Mutex;
+
+/// Maximum number of streaming invocations in progress at the same time.
+pub const MAX_PENDING_INVOCATIONS: usize = 64;
+
+enum Invocation {
+    /// The request is being uploaded.
+    Request(Vec<u8>),
+    /// The request is being handled.
+    Handling,
+    /// The response is being downloaded, from `offset` on.
+    Response { response: Vec<u8>, offset: usize },
+}
+
+/// The streaming invocations in progress, by invocation id.
+#[derive(Default)]
+pub struct Invocations {
+    invocations: Mutex<BTreeMap<u64, Invocation>>,
+}
+
+impl Invocations {
+    /// Appends `chunk` to the request of an invocation, starting the
+    /// invocation if it isn't known yet.
+    pub fn extend(&self, invocation_id: u64, chunk: &[u8]) -> Result<(), Status> {
+        let mut invocations = self.invocations.lock();
+        let pending = invocations.len();
+        match invocations.entry(invocation_id) {
+            Entry::Vacant(entry) => {
+                if pending >= MAX_PENDING_INVOCATIONS {
+                    return Err(Status::new_with_message(
+                        StatusCode::ResourceExhausted,
+                        "too many streaming invocations in progress",
+                    ));
+                }
+                entry.insert(Invocation::Request(chunk.to_vec()));
+                Ok(())
+            }
+            Entry::Occupied(mut entry) => match entry.get_mut() {
+                Invocation::Request(request) => {
+                    request.extend_from_slice(chunk);
+                    Ok(())
+                }
+                _ => Err(already_finished(invocation_id)),
+            },
+        }
+    }
+
+    /// Takes the uploaded request of an invocation, to be handled. The
+    /// invocation stays known until its response is read, or it is aborted.
+    pub fn take_request(&self, invocation_id: u64) -> Result<Vec<u8>, Status> {
+        let mut invocations = self.invocations.lock();
+        match invocations.get_mut(&invocation_id) {
+            Some(invocation @ Invocation::Request(_)) => {
+                match core::mem::replace(invocation, Invocation::Handling) {
+                    Invocation::Request(request) => Ok(request),
+                    _ => unreachable!(),
+                }
+            }
+            Some(_) => Err(already_finished(invocation_id)),
+            None => Err(unknown(invocation_id)),
+        }
+    }
+
+    /// Stores the response of an invocation, to be read in chunks. Does nothing
+    /// if the invocation was aborted in the meantime.
+    pub fn set_response(&self, invocation_id: u64, response: Vec<u8>) {
+        if let Some(invocation @ Invocation::Handling) =
+            self.invocations.lock().get_mut(&invocation_id)
+        {
+            *invocation = Invocation::Response { response, offset: 0 };
+        }
+    }
+
+    /// Returns the next chunk of at most `max_chunk_size` bytes of the response
+    /// of an invocation, and whether it is the last one. The invocation is
+    /// discarded once the last chunk has been read.
+    pub fn read(
+        &self,
+        invocation_id: u64,
+        max_chunk_size: usize,
+    ) -> Result<(Vec<u8>, bool), Status> {
+        if max_chunk_size == 0 {
+            return Err(Status::new_with_message(
+                StatusCode::InvalidArgument,
+                "the maximum chunk size must be positive",
+            ));
+        }
+        let mut invocations = self.invocations.lock();
+        let (chunk, last) = match invocations.get_mut(&invocation_id) {
+            Some(Invocation::Response { response, offset }) => {
+                let end = response.len().min(*offset + max_chunk_size);
+                let chunk = response[*offset..end].to_vec();
+                *offset = end;
+                (chunk, end == response.len())
+            }
+            Some(_) => {
+                return Err(Status::new_with_message(
+                    StatusCode::FailedPrecondition,
+                    format!("invocation {invocation_id} hasn't finished"),
+                ))
+            }
+            None => return Err(unknown(invocation_id)),
+        };
+        if last {
+            invocations.remove(&invocation_id);
+        }
+        Ok((chunk, last))
+    }
+
+    /// Discards an invocation, in whatever state it is.
+    pub fn abort(&self, invocation_id: u64) {
+        self.invocations.lock().remove(&invocation_id);
+    }
+}
+
+fn already_finished(invocation_id: u64) -> Status {
+    Status::new_with_message(
+        StatusCode::FailedPrecondition,
+        format!("invocation {invocation_id} already finished"),
+    )
+}
+
+fn unknown(invocation_id: u64) -> Status {
+    Status::new_with_message(StatusCode::NotFound, format!("unknown invocation {invocation_id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_streaming_invocation() {
+        let invocations = Invocations::default();
+        invocations.extend(1, b"hello ").unwrap();
+        invocations.extend(1, b"world").unwrap();
+        assert_eq!(invocations.take_request(1).unwrap(), b"hello world");
+        // The request can't be extended or taken again once it is being handled.
+        assert!(invocations.extend(1, b"!").is_err());
+        assert!(invocations.take_request(1).is_err());
+        assert!(invocations.read(1, 4).is_err());
+
+        invocations.set_response(1, vec![1, 2, 3, 4, 5]);
+        assert_eq!(invocations.read(1, 4).unwrap(), (vec![1, 2, 3, 4], false));
+        assert_eq!(invocations.read(1, 4).unwrap(), (vec![5], true));
+        // The invocation is discarded after the last chunk.
+        assert_eq!(invocations.read(1, 4).unwrap_err().code, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_aborted_invocation() {
+        let invocations = Invocations::default();
+        invocations.extend(1, b"request").unwrap();
+        invocations.take_request(1).unwrap();
+        invocations.abort(1);
+        // A response for an aborted invocation is dropped.
+        invocations.set_response(1, vec![1]);
+        assert_eq!(invocations.read(1, 4).unwrap_err().code, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_too_many_invocations() {
+        let invocations = Invocations::default();
+        for invocation_id in 0..MAX_PENDING_INVOCATIONS as u64 {
+            invocations.extend(invocation_id, b"").unwrap();
+        }
+        let status = invocations.extend(MAX_PENDING_INVOCATIONS as u64, b"").unwrap_err();
+        assert_eq!(status.code, StatusCode::ResourceExhausted);
+        // Invocations in progress can still be extended.
+        invocations.extend(0, b"more").unwrap();
+    }
+}
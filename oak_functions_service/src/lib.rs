@@ -21,10 +21,18 @@
 // Required for enabling benchmark tests.
 #![feature(test)]
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
+use aggregation::AggregationStore;
+use clock::Clock;
+use helper_modules::HelperModules;
 use lookup::LookupDataManager;
+use ml::InferenceModel;
 use oak_functions_abi::{Request, Response};
+use privacy_budget::PrivacyBudget;
+use proto::oak::functions::WasmEngine;
+use secrets::SecretStore;
+use wasm::memory::MemoryLimits;
 
 extern crate alloc;
 extern crate rand_core;
@@ -46,23 +54,95 @@ pub mod proto {
     }
 }
 
+pub mod aggregation;
+pub mod clock;
+pub mod helper_modules;
 pub mod instance;
+pub mod invocations;
 pub mod logger;
 pub mod lookup;
+pub mod lookup_encryption;
 pub mod lookup_htbl;
+pub mod lookup_index;
+pub mod lookup_signing;
+pub mod ml;
+pub mod precompiled;
+pub mod privacy_budget;
+pub mod randomness;
+pub mod response_size;
+pub mod secrets;
 pub mod wasm;
+pub mod workers;
 
 pub trait Observer {
     fn wasm_initialization(&self, duration: core::time::Duration);
     fn wasm_invocation(&self, duration: core::time::Duration);
 }
 
+/// How handlers run Wasm modules, as set in the `InitializeRequest`.
+#[derive(Clone, Debug, Default)]
+pub struct HandlerConfig {
+    pub wasm_engine: WasmEngine,
+    /// The fuel that every invocation of the Wasm module may consume, if
+    /// limited. Invocations that run out of fuel are aborted.
+    pub wasm_fuel_limit: Option<u64>,
+    /// How large the linear memory of the Wasm module may become.
+    pub wasm_memory_limits: MemoryLimits,
+    /// The prefix of the keys of the lookup data entries that the Wasm module
+    /// sees, see [`lookup::LookupData::with_namespace`]. Empty to see all
+    /// entries.
+    pub lookup_namespace: Vec<u8>,
+    /// How many invocations may run at the same time, if bounded. Wasmtime
+    /// then allocates the instances of the module from a pool of that size,
+    /// which is set up once and reused by every invocation.
+    pub max_concurrent_invocations: Option<u32>,
+    /// The module compiled to native code by an earlier handler, see
+    /// [`Handler::precompiled_module`]. Handlers that can't use it, e.g.
+    /// because it was compiled with a different config, compile the module.
+    pub precompiled_module: Option<Arc<[u8]>>,
+}
+
+/// The subsystems of the enclave that Wasm modules use through the Wasm API.
+/// They belong to the deployment, so handlers share them, and they are kept
+/// across reloads of the Wasm module.
+#[derive(Clone, Default)]
+pub struct Subsystems {
+    pub privacy_budget: Arc<PrivacyBudget>,
+    pub aggregation_store: Arc<AggregationStore>,
+    pub clock: Arc<Clock>,
+    pub inference_model: Arc<InferenceModel>,
+    /// Empty for the handlers of helper modules, so that invocations of
+    /// helper modules never recurse.
+    pub helper_modules: Arc<HelperModules>,
+}
+
+/// Returns the measurement of `application_config` in the attestation evidence
+/// of the Restricted Kernel: its SHA2-256 digest, or nothing if it is empty.
+pub fn application_config_digest(application_config: &[u8]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+
+    if application_config.is_empty() {
+        return Vec::new();
+    }
+    Sha256::digest(application_config).to_vec()
+}
+
 pub trait Handler {
-    type HandlerType: Handler;
+    type HandlerType: Handler + Send + Sync + 'static;
+
+    /// Returns whether handlers can run Wasm modules with `wasm_engine`.
+    fn supports_wasm_engine(wasm_engine: WasmEngine) -> bool {
+        wasm_engine == WasmEngine::Unspecified
+    }
 
+    /// Creates a handler that runs the module as set in `config`, whose Wasm
+    /// engine must be supported.
     fn new_handler(
         wasm_module_bytes: &[u8],
+        config: &HandlerConfig,
         lookup_data_manager: Arc<LookupDataManager>,
+        secret_store: Arc<SecretStore>,
+        subsystems: Subsystems,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
     ) -> anyhow::Result<Self::HandlerType>;
 
@@ -70,4 +150,18 @@ pub trait Handler {
     /// of the request to invoke and returns a reponse to invoke setting the
     /// raw bytes in the body of the response.
     fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status>;
+
+    /// Handles the requests of a batch in order, all against the same snapshot
+    /// of the lookup data, and returns their responses in the same order.
+    fn handle_invoke_batch(
+        &self,
+        invoke_requests: Vec<Request>,
+    ) -> Vec<Result<Response, micro_rpc::Status>>;
+
+    /// Returns the module compiled to native code, which later handlers of the
+    /// same module can run instead of compiling it, if the handler compiles
+    /// modules ahead of time.
+    fn precompiled_module(&self) -> Option<Vec<u8>> {
+        None
+    }
 }
@@ -15,18 +15,26 @@
 //
 
 use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::BTreeMap,
     format,
     string::{String, ToString},
     sync::Arc,
+    vec,
     vec::Vec,
 };
 
+use anyhow::Context;
 use log::{info, Level};
 
-use crate::{logger::OakLogger, lookup_htbl::LookupHtbl};
+use crate::{
+    logger::OakLogger, lookup_htbl::LookupHtbl, lookup_index::LookupIndex,
+    lookup_signing::LookupDataDigest,
+};
 
-// Data maintains the invariant on lookup data to have [at most one
-// value](https://github.com/project-oak/oak/tree/main/oak/oak_functions_service/README.md#invariant-at-most-one-value)
+// Data maintains the invariant on lookup data to have [at most one current
+// value](https://github.com/project-oak/oak/tree/main/oak/oak_functions_service/README.md#invariant-at-most-one-current-value)
 type Data = LookupHtbl;
 
 #[derive(Default)]
@@ -51,13 +59,13 @@ impl DataBuilder {
         core::mem::take(&mut self.data)
     }
 
-    /// Extends the DataBuilder with new data.
+    /// Inserts an entry into the DataBuilder.
     ///
-    /// Note, if new data contains a key already present in the existing data,
-    /// calling extend overwrites the value.
-    fn extend<'a, T: IntoIterator<Item = (&'a [u8], &'a [u8])>>(&mut self, new_data: T) {
+    /// Note, if the key is already present in the existing data, the value
+    /// becomes its current value.
+    fn insert(&mut self, key: &[u8], value: &[u8]) {
         self.state = BuilderState::Extending;
-        self.data.extend(new_data)
+        self.data.insert(key, value);
     }
 
     fn reserve(&mut self, additional_entries: usize) {
@@ -65,14 +73,164 @@ impl DataBuilder {
     }
 }
 
+// A shard of the next sharded lookup data, and the digest of the entries it was
+// extended with.
+#[derive(Default)]
+struct ShardBuilder {
+    data: Data,
+    digest: LookupDataDigest,
+}
+
+// The entries of a shard, either in a hash table built in the enclave, or in an
+// index built by the launcher.
+#[derive(Clone)]
+enum ShardData {
+    Table(Arc<Data>),
+    Index(Arc<LookupIndex>),
+}
+
+impl ShardData {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        match self {
+            ShardData::Table(data) => data.get(key),
+            ShardData::Index(index) => index.get(key),
+        }
+    }
+
+    fn get_all(&self, key: &[u8]) -> Vec<&[u8]> {
+        match self {
+            ShardData::Table(data) => data.get_all(key),
+            ShardData::Index(index) => index.get(key).into_iter().collect(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ShardData::Table(data) => data.len(),
+            ShardData::Index(index) => index.len(),
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        match self {
+            ShardData::Table(data) => data.memory_usage(),
+            ShardData::Index(index) => index.memory_usage(),
+        }
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        start: &[u8],
+    ) -> Box<dyn Iterator<Item = (&'a [u8], &'a [u8])> + 'a> {
+        match self {
+            ShardData::Table(data) => Box::new(data.iter_from(start)),
+            ShardData::Index(index) => Box::new(index.iter_from(start)),
+        }
+    }
+}
+
+// A shard of the current lookup data. Unsharded lookup data is a single shard
+// without a digest.
+struct Shard {
+    digest: Option<[u8; 32]>,
+    data: ShardData,
+}
+
+// The current lookup data. Shards are shared between snapshots, so that shards
+// that didn't change don't need to be sent again.
+#[derive(Default)]
+struct Snapshot {
+    shards: Vec<Shard>,
+    // Whether the ordered index of every shard was built.
+    ordered: bool,
+}
+
+impl Snapshot {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.shards.iter().find_map(|shard| shard.data.get(key))
+    }
+
+    fn get_all(&self, key: &[u8]) -> Vec<&[u8]> {
+        self.shards
+            .iter()
+            .map(|shard| shard.data.get_all(key))
+            .find(|values| !values.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.data.len()).sum()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.data.memory_usage()).sum()
+    }
+
+    // Returns up to `limit` entries ordered by key, starting from the first key
+    // greater than or equal to `start` and ending before the first key that
+    // isn't `in_range`. As with `get`, the first shard wins if shards share a
+    // key.
+    fn scan<F: Fn(&[u8]) -> bool>(
+        &self,
+        start: &[u8],
+        in_range: F,
+        limit: usize,
+    ) -> Vec<(&[u8], &[u8])> {
+        let mut entries = BTreeMap::new();
+        for shard in self.shards.iter() {
+            // Only the first `limit` entries of a shard can be among the first `limit`
+            // entries of the snapshot.
+            for (key, value) in
+                shard.data.iter_from(start).take_while(|(key, _)| in_range(key)).take(limit)
+            {
+                entries.entry(key).or_insert(value);
+            }
+        }
+        entries.into_iter().take(limit).collect()
+    }
+}
+
+/// Returned when the lookup data doesn't fit in the memory budget of the lookup
+/// store.
+#[derive(Debug)]
+pub struct MemoryBudgetExceeded {
+    /// The memory budget, in bytes.
+    pub budget: usize,
+    /// The memory the lookup store would need, in bytes.
+    pub required: usize,
+}
+
+impl core::fmt::Display for MemoryBudgetExceeded {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "lookup data needs {} bytes, which exceeds the memory budget of {} bytes",
+            self.required, self.budget
+        )
+    }
+}
+
+/// Memory used by the lookup store, in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryUsage {
+    /// Memory used by the current lookup data.
+    pub active: usize,
+    /// Memory used by the next lookup data received so far.
+    pub next: usize,
+    /// The memory budget, if any.
+    pub budget: Option<usize>,
+}
+
 #[cfg(feature = "std")]
-mod mutexes {
-    pub use parking_lot::{Mutex, RwLock};
+pub(crate) mod mutexes {
+    pub use parking_lot::{Mutex, MutexGuard, RwLock};
 }
 
 #[cfg(not(feature = "std"))]
-mod mutexes {
-    pub use spinning_top::{RwSpinlock as RwLock, Spinlock as Mutex};
+pub(crate) mod mutexes {
+    pub use spinning_top::{
+        guard::SpinlockGuard as MutexGuard, RwSpinlock as RwLock, Spinlock as Mutex,
+    };
 }
 
 /// Utility for managing lookup data.
@@ -92,10 +250,17 @@ mod mutexes {
 /// In the future we may replace both the mutex and the hash map with something
 /// like RCU.
 pub struct LookupDataManager {
-    data: mutexes::RwLock<Arc<Data>>,
+    data: mutexes::RwLock<Arc<Snapshot>>,
     // Behind a lock, because we have multiple references to LookupDataManager and need to mutate
     // data builder.
     data_builder: mutexes::Mutex<DataBuilder>,
+    // The shards of the next sharded lookup data received so far, by their index in the manifest.
+    shard_builders: mutexes::Mutex<BTreeMap<u32, ShardBuilder>>,
+    // Whether to build the ordered index of the lookup data, which range and prefix lookups
+    // require.
+    ordered_index: bool,
+    // The maximum memory used by the current and the next lookup data together.
+    memory_budget: Option<usize>,
     logger: Arc<dyn OakLogger>,
 }
 
@@ -103,39 +268,82 @@ impl LookupDataManager {
     /// Creates a new instance with empty backing data.
     pub fn new_empty(logger: Arc<dyn OakLogger>) -> Self {
         Self {
-            data: mutexes::RwLock::new(Arc::new(Data::default())),
+            data: mutexes::RwLock::new(Arc::new(Snapshot::default())),
             // Incrementally builds the backing data that will be used by new `LookupData`
             // instances when finished.
             data_builder: mutexes::Mutex::new(DataBuilder::default()),
+            shard_builders: mutexes::Mutex::new(BTreeMap::new()),
+            ordered_index: false,
+            memory_budget: None,
             logger,
         }
     }
 
+    /// Limits the memory used by the current and the next lookup data together
+    /// to `budget` bytes, as both are held in memory until the next lookup
+    /// data is finished. Extending the next lookup data beyond the budget
+    /// discards it, and fails with [`MemoryBudgetExceeded`] before the memory
+    /// is allocated.
+    ///
+    /// Memory usage is estimated from the allocations of the underlying hash
+    /// tables, so the budget should leave some headroom.
+    pub fn with_memory_budget(self, budget: usize) -> Self {
+        Self { memory_budget: Some(budget), ..self }
+    }
+
+    /// Builds the ordered index of all subsequent lookup data, so that it
+    /// supports range and prefix lookups. The index costs another 5 bytes per
+    /// entry, and sorting the entries when the lookup data is finished.
+    pub fn with_ordered_index(self) -> Self {
+        *self.data.write() = Arc::new(Snapshot { ordered: true, ..Default::default() });
+        Self { ordered_index: true, ..self }
+    }
+
     /// Creates an instance of LookupData populated with the given entries.
     pub fn for_test(data: Vec<(Vec<u8>, Vec<u8>)>, logger: Arc<dyn OakLogger>) -> Self {
         let test_manager = Self::new_empty(logger);
         test_manager.reserve(data.len() as u64).unwrap();
-        test_manager.extend_next_lookup_data(data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())));
+        test_manager
+            .extend_next_lookup_data(data.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .unwrap();
         test_manager.finish_next_lookup_data();
         test_manager
     }
 
-    pub fn reserve(&self, additional_entries: u64) -> anyhow::Result<()> {
+    pub fn reserve(&self, additional_entries: u64) -> Result<(), MemoryBudgetExceeded> {
+        let active = self.data.read().memory_usage();
         let mut data_builder = self.data_builder.lock();
+        let next = self.finished_memory(&data_builder.data)
+            + data_builder.data.reserve_memory(additional_entries as usize);
+        self.check_memory(active + next)?;
         data_builder.reserve(additional_entries as usize);
         Ok(())
     }
 
+    /// Extends the next lookup data with new entries.
+    ///
+    /// If the entries don't fit in the memory budget, the next lookup data is
+    /// discarded.
     pub fn extend_next_lookup_data<'a, T: IntoIterator<Item = (&'a [u8], &'a [u8])>>(
         &self,
         new_data: T,
-    ) {
+    ) -> Result<(), MemoryBudgetExceeded> {
         info!("Start extending next lookup data");
         {
+            let active = self.data.read().memory_usage();
             let mut data_builder = self.data_builder.lock();
-            data_builder.extend(new_data);
+            for (key, value) in new_data {
+                let next = self.finished_memory(&data_builder.data)
+                    + data_builder.data.insert_memory(key, value);
+                if let Err(err) = self.check_memory(active + next) {
+                    let _ = data_builder.build();
+                    return Err(err);
+                }
+                data_builder.insert(key, value);
+            }
         }
         info!("Finish extending next lookup data");
+        Ok(())
     }
 
     // Finish building the next lookup data and replace the current lookup data in
@@ -146,11 +354,17 @@ impl LookupDataManager {
         info!("Start replacing lookup data by next lookup data");
         {
             let mut data_builder = self.data_builder.lock();
-            let next_data = data_builder.build();
+            let mut next_data = data_builder.build();
+            if self.ordered_index {
+                next_data.build_ordered_index();
+            }
             next_data_len = next_data.len();
             let mut data = self.data.write();
             data_len = data.len();
-            *data = Arc::new(next_data);
+            *data = Arc::new(Snapshot {
+                shards: vec![Shard { digest: None, data: ShardData::Table(Arc::new(next_data)) }],
+                ordered: self.ordered_index,
+            });
         }
         info!(
             "Finished replacing lookup data with len {} by next lookup data with len {}",
@@ -158,16 +372,151 @@ impl LookupDataManager {
         );
     }
 
+    /// Replaces the current lookup data with an index of it built by the
+    /// launcher, which is used in place. The index supports range and prefix
+    /// lookups, but keeps only the last value of every key.
+    ///
+    /// Fails if the index doesn't fit in the memory budget, in which case the
+    /// current lookup data is kept.
+    pub fn finish_next_lookup_index(&self, index: LookupIndex) -> Result<(), MemoryBudgetExceeded> {
+        info!("Start replacing lookup data by lookup index");
+        let mut data = self.data.write();
+        let data_len = data.len();
+        self.check_memory(data.memory_usage() + index.memory_usage())?;
+        let index_len = index.len();
+        *data = Arc::new(Snapshot {
+            shards: vec![Shard { digest: None, data: ShardData::Index(Arc::new(index)) }],
+            ordered: true,
+        });
+        info!(
+            "Finished replacing lookup data with len {} by lookup index with len {}",
+            data_len, index_len
+        );
+        Ok(())
+    }
+
     pub fn abort_next_lookup_data(&self) {
         info!("Start aborting next lookup data");
         {
             let mut data_builder = self.data_builder.lock();
             // Clear the builder throwing away the intermediate result.
             let _ = data_builder.build();
+            self.shard_builders.lock().clear();
         }
         info!("Finish aborting next lookup data");
     }
 
+    /// Extends the shard with the given index in the manifest of the next
+    /// sharded lookup data.
+    ///
+    /// If the entries don't fit in the memory budget, all shards of the next
+    /// lookup data are discarded. Shards of the current lookup data count
+    /// against the budget even if the next lookup data replaces them.
+    pub fn extend_next_shard<'a, T: IntoIterator<Item = (&'a [u8], &'a [u8])>>(
+        &self,
+        shard: u32,
+        new_data: T,
+    ) -> Result<(), MemoryBudgetExceeded> {
+        let active = self.data.read().memory_usage();
+        let mut shard_builders = self.shard_builders.lock();
+        let other_shards: usize = shard_builders
+            .iter()
+            .filter(|(index, _)| **index != shard)
+            .map(|(_, builder)| self.finished_memory(&builder.data))
+            .sum();
+        let builder = shard_builders.entry(shard).or_default();
+        for (key, value) in new_data {
+            let next = other_shards
+                + self.finished_memory(&builder.data)
+                + builder.data.insert_memory(key, value);
+            if let Err(err) = self.check_memory(active + next) {
+                shard_builders.clear();
+                return Err(err);
+            }
+            builder.digest.update(key, value);
+            builder.data.insert(key, value);
+        }
+        Ok(())
+    }
+
+    /// Finishes building the next sharded lookup data, whose shards have the
+    /// given digests, and replaces the current lookup data with it.
+    ///
+    /// Every shard must either have been sent in full, matching its digest, or
+    /// be a shard of the current lookup data with the same digest. Otherwise
+    /// the next lookup data is discarded and the current lookup data is kept.
+    pub fn finish_next_sharded_lookup_data(&self, digests: &[[u8; 32]]) -> anyhow::Result<()> {
+        info!("Start replacing lookup data by next sharded lookup data");
+        let mut shard_builders = core::mem::take(&mut *self.shard_builders.lock());
+        let mut data = self.data.write();
+        anyhow::ensure!(
+            shard_builders.keys().all(|index| (*index as usize) < digests.len()),
+            "received shards that aren't in the manifest"
+        );
+        let mut shards = Vec::with_capacity(digests.len());
+        let (mut sent, mut kept) = (0, 0);
+        for (index, digest) in digests.iter().enumerate() {
+            let shard = match shard_builders.remove(&(index as u32)) {
+                Some(mut builder) => {
+                    anyhow::ensure!(
+                        builder.digest.finalize() == *digest,
+                        "shard {} doesn't match its digest",
+                        index
+                    );
+                    if self.ordered_index {
+                        builder.data.build_ordered_index();
+                    }
+                    sent += 1;
+                    ShardData::Table(Arc::new(builder.data))
+                }
+                None => {
+                    kept += 1;
+                    data.shards
+                        .iter()
+                        .find(|shard| shard.digest.as_ref() == Some(digest))
+                        .map(|shard| shard.data.clone())
+                        .with_context(|| format!("shard {} is missing", index))?
+                }
+            };
+            shards.push(Shard { digest: Some(*digest), data: shard });
+        }
+        *data = Arc::new(Snapshot { shards, ordered: self.ordered_index });
+        info!(
+            "Finished replacing lookup data by sharded lookup data with len {} ({} shards sent, {} \
+             kept)",
+            data.len(),
+            sent,
+            kept
+        );
+        Ok(())
+    }
+
+    /// Returns the memory used by the current and the next lookup data.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let active = self.data.read().memory_usage();
+        let next_data = self.finished_memory(&self.data_builder.lock().data);
+        let next_shards: usize = self
+            .shard_builders
+            .lock()
+            .values()
+            .map(|builder| self.finished_memory(&builder.data))
+            .sum();
+        MemoryUsage { active, next: next_data + next_shards, budget: self.memory_budget }
+    }
+
+    // Returns the memory that `data` will use once finished, including its ordered
+    // index if it is built.
+    fn finished_memory(&self, data: &Data) -> usize {
+        data.memory_usage() + if self.ordered_index { data.ordered_index_memory() } else { 0 }
+    }
+
+    fn check_memory(&self, required: usize) -> Result<(), MemoryBudgetExceeded> {
+        match self.memory_budget {
+            Some(budget) if required > budget => Err(MemoryBudgetExceeded { budget, required }),
+            _ => Ok(()),
+        }
+    }
+
     /// Creates a new `LookupData` instance with a reference to the current
     /// backing data.
     pub fn create_lookup_data(&self) -> LookupData {
@@ -185,18 +534,68 @@ impl LookupDataManager {
 /// Provides access to shared lookup data.
 #[derive(Clone)]
 pub struct LookupData {
-    data: Arc<Data>,
+    data: Arc<Snapshot>,
     logger: Arc<dyn OakLogger>,
+    // Prepended to all keys that are looked up, and stripped from the keys that are returned.
+    namespace: Vec<u8>,
 }
 
 impl LookupData {
-    fn new(data: Arc<Data>, logger: Arc<dyn OakLogger>) -> Self {
-        Self { data, logger }
+    fn new(data: Arc<Snapshot>, logger: Arc<dyn OakLogger>) -> Self {
+        Self { data, logger, namespace: Vec::new() }
+    }
+
+    /// Restricts lookups to the entries whose keys start with `namespace`, as
+    /// if that prefix were stripped from their keys. [`LookupData::len`]
+    /// still counts all entries.
+    pub fn with_namespace(self, namespace: Vec<u8>) -> Self {
+        Self { namespace, ..self }
     }
 
     /// Gets an individual entry from the backing data.
     pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
-        self.data.get(key)
+        self.data.get(&self.namespaced(key))
+    }
+
+    /// Gets all values of a key from the backing data, in the order of its
+    /// entries in the lookup data. The last one is the value returned by
+    /// [`LookupData::get`].
+    pub fn get_all(&self, key: &[u8]) -> Vec<&[u8]> {
+        self.data.get_all(&self.namespaced(key))
+    }
+
+    /// Gets up to `limit` entries with keys in the range from `start`
+    /// (inclusive) to `end` (exclusive), or to the last key if `end` is not
+    /// set, ordered by key.
+    ///
+    /// Fails if the ordered index of the backing data wasn't built.
+    pub fn get_range(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+        anyhow::ensure!(self.data.ordered, "lookup data has no ordered index");
+        let end = end.map(|end| self.namespaced(end));
+        let entries = self.data.scan(
+            &self.namespaced(start),
+            |key| {
+                key.starts_with(&self.namespace) && !end.as_ref().is_some_and(|end| key >= &**end)
+            },
+            limit,
+        );
+        Ok(self.strip_namespace(entries))
+    }
+
+    /// Gets up to `limit` entries with keys starting with `prefix`, ordered by
+    /// key.
+    ///
+    /// Fails if the ordered index of the backing data wasn't built.
+    pub fn get_prefix(&self, prefix: &[u8], limit: usize) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+        anyhow::ensure!(self.data.ordered, "lookup data has no ordered index");
+        let prefix = self.namespaced(prefix);
+        let entries = self.data.scan(&prefix, |key| key.starts_with(&prefix), limit);
+        Ok(self.strip_namespace(entries))
     }
 
     /// Gets the number of entries in the backing data.
@@ -206,7 +605,19 @@ impl LookupData {
 
     /// Whether the backing data is empty.
     pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+        self.data.len() == 0
+    }
+
+    fn namespaced<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.namespace.is_empty() {
+            Cow::Borrowed(key)
+        } else {
+            Cow::Owned([self.namespace.as_slice(), key].concat())
+        }
+    }
+
+    fn strip_namespace<'a>(&self, entries: Vec<(&'a [u8], &'a [u8])>) -> Vec<(&'a [u8], &'a [u8])> {
+        entries.into_iter().map(|(key, value)| (&key[self.namespace.len()..], value)).collect()
     }
 
     /// Logs an error message.
@@ -241,6 +652,7 @@ mod tests {
     use alloc::vec::Vec;
 
     use super::*;
+    use crate::lookup_index::build_lookup_index;
 
     #[derive(Clone)]
     struct TestLogger;
@@ -286,14 +698,18 @@ mod tests {
         let lookup_data_0 = manager.create_lookup_data();
 
         manager.reserve(4).unwrap();
-        manager.extend_next_lookup_data(
-            create_test_data(0, 2).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(0, 2).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         let lookup_data_1 = manager.create_lookup_data();
 
-        manager.extend_next_lookup_data(
-            create_test_data(2, 4).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(2, 4).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         manager.finish_next_lookup_data();
         let lookup_data_2 = manager.create_lookup_data();
 
@@ -307,19 +723,27 @@ mod tests {
         let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
 
         manager.reserve(7).unwrap();
-        manager.extend_next_lookup_data(
-            create_test_data(0, 2).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
-        manager.extend_next_lookup_data(
-            create_test_data(2, 3).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(0, 2).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
+        manager
+            .extend_next_lookup_data(
+                create_test_data(2, 3).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         // Note the overlap which results in a bit of wasted space.
-        manager.extend_next_lookup_data(
-            create_test_data(2, 6).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
-        manager.extend_next_lookup_data(
-            create_test_data(6, 7).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(2, 6).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
+        manager
+            .extend_next_lookup_data(
+                create_test_data(6, 7).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         manager.finish_next_lookup_data();
 
         let lookup_data = manager.create_lookup_data();
@@ -333,16 +757,20 @@ mod tests {
         let lookup_data_0 = manager.create_lookup_data();
 
         manager.reserve(2).unwrap();
-        manager.extend_next_lookup_data(
-            create_test_data(0, 2).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(0, 2).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         manager.abort_next_lookup_data();
         let lookup_data_1 = manager.create_lookup_data();
 
         manager.reserve(1).unwrap();
-        manager.extend_next_lookup_data(
-            create_test_data(0, 1).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(0, 1).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         manager.finish_next_lookup_data();
         let lookup_data_2 = manager.create_lookup_data();
 
@@ -351,6 +779,177 @@ mod tests {
         assert_eq!(lookup_data_2.len(), 1);
     }
 
+    #[test]
+    fn test_update_sharded_lookup_data_keeps_unchanged_shards() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
+        let shard_0 = create_test_data(0, 2);
+        let shard_1 = create_test_data(2, 4);
+        let digests = [digest(&shard_0), digest(&shard_1)];
+        manager
+            .extend_next_shard(0, shard_0.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .unwrap();
+        manager
+            .extend_next_shard(1, shard_1.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .unwrap();
+        manager.finish_next_sharded_lookup_data(&digests).unwrap();
+        assert_eq!(manager.create_lookup_data().len(), 4);
+
+        // Only send the shard that changed.
+        let shard_1 = create_test_data(2, 5);
+        let digests = [digests[0], digest(&shard_1)];
+        manager
+            .extend_next_shard(1, shard_1.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .unwrap();
+        manager.finish_next_sharded_lookup_data(&digests).unwrap();
+        let lookup_data = manager.create_lookup_data();
+        assert_eq!(lookup_data.len(), 5);
+        assert_eq!(lookup_data.get(b"key0"), Some(b"value0".as_ref()));
+    }
+
+    #[test]
+    fn test_update_sharded_lookup_data_rejects_mismatched_shard() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
+        reserve_and_extend_test_data(&manager, 0, 1);
+        let shard = create_test_data(0, 2);
+        manager.extend_next_shard(0, shard.iter().map(|(k, v)| (k.as_ref(), v.as_ref()))).unwrap();
+        assert!(manager.finish_next_sharded_lookup_data(&[[0; 32]]).is_err());
+        assert_eq!(manager.create_lookup_data().len(), 1);
+    }
+
+    #[test]
+    fn test_update_sharded_lookup_data_rejects_missing_shard() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
+        let digests = [digest(&create_test_data(0, 2))];
+        assert!(manager.finish_next_sharded_lookup_data(&digests).is_err());
+    }
+
+    #[test]
+    fn test_memory_budget_rejects_oversized_lookup_data() {
+        // Room for a single 2MiB data chunk, but not for two.
+        let manager =
+            LookupDataManager::new_empty(Arc::new(TestLogger)).with_memory_budget(3 << 20);
+        reserve_and_extend_test_data(&manager, 0, 2);
+        let usage = manager.memory_usage();
+        assert!(usage.active > 0);
+        assert_eq!(usage.next, 0);
+        assert_eq!(usage.budget, Some(3 << 20));
+
+        // The next lookup data doesn't fit next to the current lookup data, so it is
+        // discarded without affecting the current lookup data.
+        let err = manager
+            .extend_next_lookup_data(
+                create_test_data(0, 1).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap_err();
+        assert_eq!(err.budget, 3 << 20);
+        assert!(err.required > err.budget);
+        assert_eq!(manager.memory_usage().next, 0);
+        assert_eq!(manager.create_lookup_data().len(), 2);
+    }
+
+    #[test]
+    fn test_get_all_values() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
+        manager.extend_next_lookup_data([(b"key".as_ref(), b"value1".as_ref())]).unwrap();
+        manager
+            .extend_next_lookup_data([
+                (b"other".as_ref(), b"value".as_ref()),
+                (b"key".as_ref(), b"value2".as_ref()),
+            ])
+            .unwrap();
+        manager.finish_next_lookup_data();
+        let lookup_data = manager.create_lookup_data();
+
+        assert_eq!(lookup_data.len(), 2);
+        assert_eq!(lookup_data.get(b"key"), Some(b"value2".as_ref()));
+        assert_eq!(lookup_data.get_all(b"key"), [b"value1".as_ref(), b"value2".as_ref()]);
+        assert_eq!(lookup_data.get_all(b"other"), [b"value".as_ref()]);
+        assert!(lookup_data.get_all(b"missing").is_empty());
+    }
+
+    #[test]
+    fn test_get_range_and_prefix() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger)).with_ordered_index();
+        let shard_0 = create_test_data(0, 12);
+        let shard_1 = create_test_data(12, 20);
+        manager
+            .extend_next_shard(0, shard_0.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .unwrap();
+        manager
+            .extend_next_shard(1, shard_1.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .unwrap();
+        manager.finish_next_sharded_lookup_data(&[digest(&shard_0), digest(&shard_1)]).unwrap();
+        let lookup_data = manager.create_lookup_data();
+
+        // Keys are ordered bytewise, across shards.
+        assert_eq!(
+            keys(lookup_data.get_range(b"key11", Some(b"key14".as_ref()), 10).unwrap()),
+            ["key11", "key12", "key13"]
+        );
+        assert_eq!(keys(lookup_data.get_range(b"key18", None, 10).unwrap()), ["key18", "key19"]);
+        assert_eq!(keys(lookup_data.get_prefix(b"key1", 3).unwrap()), ["key1", "key10", "key11"]);
+        assert_eq!(lookup_data.get_prefix(b"key1", 100).unwrap().len(), 11);
+        assert_eq!(
+            lookup_data.get_prefix(b"key7", 1).unwrap(),
+            [(b"key7".as_ref(), b"value7".as_ref())]
+        );
+        assert!(lookup_data.get_prefix(b"other", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_namespace() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger)).with_ordered_index();
+        manager
+            .extend_next_lookup_data([
+                (b"a/key1".as_ref(), b"a1".as_ref()),
+                (b"a/key2".as_ref(), b"a2".as_ref()),
+                (b"b/key1".as_ref(), b"b1".as_ref()),
+                (b"key1".as_ref(), b"1".as_ref()),
+            ])
+            .unwrap();
+        manager.finish_next_lookup_data();
+        let lookup_data = manager.create_lookup_data().with_namespace(b"a/".to_vec());
+
+        assert_eq!(lookup_data.get(b"key1"), Some(b"a1".as_ref()));
+        assert_eq!(lookup_data.get_all(b"key2"), [b"a2".as_ref()]);
+        assert_eq!(lookup_data.get(b"b/key1"), None);
+        assert_eq!(keys(lookup_data.get_range(b"", None, 10).unwrap()), ["key1", "key2"]);
+        assert_eq!(
+            keys(lookup_data.get_range(b"key1", Some(b"key2".as_ref()), 10).unwrap()),
+            ["key1"]
+        );
+        assert_eq!(keys(lookup_data.get_prefix(b"key", 10).unwrap()), ["key1", "key2"]);
+    }
+
+    #[test]
+    fn test_get_range_without_ordered_index_fails() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
+        reserve_and_extend_test_data(&manager, 0, 2);
+        assert!(manager.create_lookup_data().get_range(b"", None, 1).is_err());
+    }
+
+    #[test]
+    fn test_finish_next_lookup_index() {
+        let manager = LookupDataManager::new_empty(Arc::new(TestLogger));
+        reserve_and_extend_test_data(&manager, 0, 2);
+        let lookup_data_0 = manager.create_lookup_data();
+
+        let data = create_test_data(0, 20);
+        let index = build_lookup_index(data.iter().map(|(k, v)| (k.as_ref(), v.as_ref()))).unwrap();
+        let index_len = index.len();
+        manager.finish_next_lookup_index(LookupIndex::new(Box::new(index)).unwrap()).unwrap();
+        let lookup_data_1 = manager.create_lookup_data();
+
+        assert_eq!(lookup_data_0.len(), 2);
+        assert_eq!(lookup_data_1.len(), 20);
+        assert_eq!(lookup_data_1.get(b"key19"), Some(b"value19".as_ref()));
+        assert_eq!(lookup_data_1.get_all(b"key19"), [b"value19".as_ref()]);
+        // Lookup indexes are ordered, even if the manager doesn't build ordered
+        // indexes.
+        assert_eq!(keys(lookup_data_1.get_prefix(b"key1", 3).unwrap()), ["key1", "key10", "key11"]);
+        assert_eq!(manager.memory_usage().active, index_len);
+    }
+
     #[test]
     fn test_format_bytes() {
         // Valid UTF-8 string.
@@ -370,11 +969,23 @@ mod tests {
         vec
     }
 
+    fn keys(entries: Vec<(&[u8], &[u8])>) -> Vec<String> {
+        entries.iter().map(|(key, _)| format_bytes(key)).collect()
+    }
+
+    fn digest(data: &[(Vec<u8>, Vec<u8>)]) -> [u8; 32] {
+        let mut digest = LookupDataDigest::default();
+        data.iter().for_each(|(k, v)| digest.update(k, v));
+        digest.finalize()
+    }
+
     fn reserve_and_extend_test_data(manager: &LookupDataManager, start: i32, end: i32) {
         manager.reserve((end - start) as u64).unwrap();
-        manager.extend_next_lookup_data(
-            create_test_data(start, end).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
-        );
+        manager
+            .extend_next_lookup_data(
+                create_test_data(start, end).iter().map(|(k, v)| (k.as_ref(), v.as_ref())),
+            )
+            .unwrap();
         manager.finish_next_lookup_data();
     }
 }
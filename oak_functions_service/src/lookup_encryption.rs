@@ -0,0 +1,109 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Encryption of lookup data by its owner, so that the host only ever handles
+//! ciphertext and the lookup data is decrypted inside the enclave.
+//!
+//! The key is released only to attested enclaves, as the [`LOOKUP_DATA_KEY`]
+//! secret (see [`crate::secrets`]).
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm,
+};
+use anyhow::{anyhow, Context};
+use prost::Message;
+use rand_core::{OsRng, RngCore};
+
+use crate::proto::oak::functions::{EncryptedLookupDataChunk, LookupDataChunk};
+
+/// Name of the provisioned secret that holds the AES-256-GCM key of the lookup
+/// data.
+pub const LOOKUP_DATA_KEY: &str = "lookup_data_key";
+
+const NONCE_SIZE_BYTES: usize = 12;
+
+/// Encrypts `chunk` under `key` with a random nonce.
+pub fn encrypt_lookup_data_chunk(
+    key: &[u8],
+    chunk: &LookupDataChunk,
+) -> anyhow::Result<EncryptedLookupDataChunk> {
+    let cipher = new_cipher(key)?;
+    let mut nonce = [0u8; NONCE_SIZE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt((&nonce).into(), chunk.encode_to_vec().as_slice())
+        .map_err(|error| anyhow!("couldn't encrypt lookup data chunk: {}", error))?;
+    Ok(EncryptedLookupDataChunk { nonce: nonce.to_vec(), ciphertext })
+}
+
+/// Decrypts `encrypted_chunk` with `key`, failing if the chunk wasn't
+/// encrypted under `key` or was tampered with.
+pub fn decrypt_lookup_data_chunk(
+    key: &[u8],
+    encrypted_chunk: &EncryptedLookupDataChunk,
+) -> anyhow::Result<LookupDataChunk> {
+    let cipher = new_cipher(key)?;
+    let nonce: [u8; NONCE_SIZE_BYTES] = encrypted_chunk
+        .nonce
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("invalid nonce size: {} bytes", encrypted_chunk.nonce.len()))?;
+    let plaintext = cipher
+        .decrypt((&nonce).into(), encrypted_chunk.ciphertext.as_slice())
+        .map_err(|error| anyhow!("couldn't decrypt lookup data chunk: {}", error))?;
+    LookupDataChunk::decode(plaintext.as_slice()).context("couldn't decode lookup data chunk")
+}
+
+fn new_cipher(key: &[u8]) -> anyhow::Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|_| anyhow!("invalid lookup data key size: {} bytes", key.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::proto::oak::functions::LookupDataEntry;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn test_chunk() -> LookupDataChunk {
+        LookupDataChunk {
+            items: vec![LookupDataEntry { key: b"key".to_vec(), value: b"value".to_vec() }],
+        }
+    }
+
+    #[test]
+    fn decrypt_encrypted_chunk() {
+        let encrypted = encrypt_lookup_data_chunk(&KEY, &test_chunk()).unwrap();
+        assert_eq!(decrypt_lookup_data_chunk(&KEY, &encrypted).unwrap(), test_chunk());
+    }
+
+    #[test]
+    fn decrypt_fails_with_wrong_key() {
+        let encrypted = encrypt_lookup_data_chunk(&KEY, &test_chunk()).unwrap();
+        assert!(decrypt_lookup_data_chunk(&[8; 32], &encrypted).is_err());
+    }
+
+    #[test]
+    fn decrypt_fails_with_tampered_ciphertext() {
+        let mut encrypted = encrypt_lookup_data_chunk(&KEY, &test_chunk()).unwrap();
+        encrypted.ciphertext[0] ^= 1;
+        assert!(decrypt_lookup_data_chunk(&KEY, &encrypted).is_err());
+    }
+}
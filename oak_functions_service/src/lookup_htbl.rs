@@ -64,13 +64,18 @@
 //! the prior array size. In contrast, this scheme has only 40% of the table
 //! empty and is never resized.
 //!
+//! Inserting a key that is already in the table doesn't free the k/v pair it
+//! replaces, so the earlier values of a key are kept in a chain from the data
+//! index of the pair that replaced them, and `get_all` returns all of them.
+//! Only keys with several values cost memory for the chain.
+//!
 //! There are certain limits imposed by this structure:
 //!
 //!   * Every k/v pair must fit into a 2MiB chunk.
 //!   * The total memory allocated to keys and values, and their compressed
 //!     lengths, is < 1TiB.
 
-use alloc::{boxed::Box, vec, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
 use core::mem;
 
 use rand_core::{OsRng, RngCore};
@@ -79,6 +84,13 @@ use rand_core::{OsRng, RngCore};
 // saves 12 bytes per k/v pair.  It is referred to as u40 below.
 const INDEX_SIZE: usize = 5;
 
+// The minimum size of a data chunk, 2MiB.
+const MIN_CHUNK_SIZE: usize = 1 << 21;
+
+// Roughly the memory used to chain a replaced k/v pair, ignoring the overhead
+// of the BTreeMap nodes.
+const REPLACED_SIZE: usize = 2 * mem::size_of::<usize>();
+
 #[derive(Clone, Copy, Default)]
 struct Entry {
     hash_byte: u8,
@@ -102,6 +114,11 @@ pub struct LookupHtbl {
     chunk_bits: usize,
     chunk_size: usize,
     chunk_mask: usize,
+    // The data indices of the k/v pairs ordered by key, if the ordered index was built.
+    ordered: Vec<[u8; INDEX_SIZE]>,
+    // Maps the data index of a k/v pair to the data index of the pair with the same key that it
+    // replaced.
+    replaced: BTreeMap<usize, usize>,
 }
 
 // Retured by LookupHtbl::lookup.
@@ -135,9 +152,8 @@ impl LookupHtbl {
         // Set chunk size such that we have chunk sizes about 1/10th the size of the
         // entries table.
         let mut chunk_size = allocated_entries.next_power_of_two();
-        if chunk_size < 1 << 21 {
-            // The minimum chunk size is 2MiB.
-            chunk_size = 1 << 21;
+        if chunk_size < MIN_CHUNK_SIZE {
+            chunk_size = MIN_CHUNK_SIZE;
         }
         self.chunk_size = chunk_size;
         self.chunk_mask = chunk_size - 1;
@@ -217,6 +233,24 @@ impl LookupHtbl {
         }
     }
 
+    /// Return all values inserted with the key, in insertion order.  The last
+    /// one is the value returned by `get`.
+    pub fn get_all(&self, key: &[u8]) -> Vec<&[u8]> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let mut values = Vec::new();
+        if let LookupResult::Found(_, data_index) = self.lookup(key) {
+            let mut next = Some(data_index);
+            while let Some(data_index) = next {
+                values.push(self.read_value(data_index));
+                next = self.replaced.get(&data_index).copied();
+            }
+        }
+        values.reverse();
+        values
+    }
+
     // Return the value associated with the k/v pair at data_index.
     fn read_value(&self, data_index: usize) -> &[u8] {
         let chunk: &[u8] = &self.data_chunks[data_index >> self.chunk_bits];
@@ -243,6 +277,7 @@ impl LookupHtbl {
             LookupResult::Found(table_index, old_data_index) => {
                 let entry = &mut self.table[table_index];
                 write_index(entry, data_index);
+                self.replaced.insert(data_index, old_data_index);
                 Some(self.read_value(old_data_index))
             }
             LookupResult::NotFound(table_index, hash_byte) => {
@@ -334,10 +369,79 @@ impl LookupHtbl {
         self.used_entries == 0
     }
 
+    /// Return roughly how many bytes the table has allocated, which is
+    /// dominated by the table of entries and the data chunks.
+    pub fn memory_usage(&self) -> usize {
+        self.table.len() * mem::size_of::<Entry>()
+            + self.data_chunks.len() * self.chunk_size
+            + self.ordered.len() * INDEX_SIZE
+            + self.replaced.len() * REPLACED_SIZE
+    }
+
+    /// Return roughly how many more bytes `reserve` allocates for
+    /// `max_entries`.
+    pub fn reserve_memory(&self, max_entries: usize) -> usize {
+        if self.used_entries != 0 {
+            return 0;
+        }
+        ((5 * max_entries + 1) / 3) * mem::size_of::<Entry>()
+    }
+
+    /// Return roughly how many more bytes inserting the k/v pair may allocate,
+    /// so that callers can check it fits before inserting.
+    pub fn insert_memory(&self, key: &[u8], value: &[u8]) -> usize {
+        let additional_data_len = 2 * INDEX_SIZE + key.len() + value.len();
+        if self.table.is_empty() {
+            // `insert` reserves a table for a single entry first.
+            return self.reserve_memory(1) + MIN_CHUNK_SIZE.max(additional_data_len);
+        }
+        let mut memory = REPLACED_SIZE;
+        if self.used_entries >= self.max_entries {
+            // `grow_table` doubles the table.
+            memory += self.table.len() * mem::size_of::<Entry>();
+        }
+        let end_index = self.used_data + additional_data_len;
+        if self.data_chunks.len() <= end_index >> self.chunk_bits {
+            memory += self.chunk_size;
+        }
+        memory
+    }
+
+    /// Return how many bytes `build_ordered_index` allocates.
+    pub fn ordered_index_memory(&self) -> usize {
+        self.used_entries * INDEX_SIZE
+    }
+
     /// Return an iterator that can be used to iterate through k/v pairs.
     pub fn iter(&self) -> LookupHtblIter {
         LookupHtblIter { htbl: self, table_index: 0 }
     }
+
+    /// Build an index of the k/v pairs ordered by key, which `iter_from`
+    /// requires.  The index costs another 5 bytes per k/v pair.  NOTE: The
+    /// index must be built again after inserting more k/v pairs.
+    pub fn build_ordered_index(&mut self) {
+        let mut ordered: Vec<[u8; INDEX_SIZE]> = Vec::with_capacity(self.used_entries);
+        ordered.extend(
+            self.table.iter().filter(|entry| read_index(entry) != 0).map(|entry| entry.data_index),
+        );
+        ordered.sort_unstable_by(|a, b| {
+            self.read_key(read_raw_index(a)).cmp(self.read_key(read_raw_index(b)))
+        });
+        self.ordered = ordered;
+    }
+
+    /// Return an iterator through the k/v pairs with keys greater than or
+    /// equal to `start`, ordered by key.  This is empty unless the ordered
+    /// index was built.
+    pub fn iter_from<'a>(&'a self, start: &[u8]) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        let first =
+            self.ordered.partition_point(|index| self.read_key(read_raw_index(index)) < start);
+        self.ordered[first..].iter().map(move |index| {
+            let data_index = read_raw_index(index);
+            (self.read_key(data_index), self.read_value(data_index))
+        })
+    }
 }
 
 pub struct LookupHtblIter<'a> {
@@ -394,8 +498,14 @@ fn reduce(hash: u64, table_len: usize) -> usize {
 // Read a u40 index from unaligned memory LE, and return it as a usize.
 #[inline]
 fn read_index(entry: &Entry) -> usize {
+    read_raw_index(&entry.data_index)
+}
+
+// Read a u40 index from its LE bytes.
+#[inline]
+fn read_raw_index(data_index: &[u8; INDEX_SIZE]) -> usize {
     let mut val = [0u8; 8];
-    val[0..INDEX_SIZE].copy_from_slice(&entry.data_index);
+    val[0..INDEX_SIZE].copy_from_slice(data_index);
     u64::from_le_bytes(val) as usize
 }
 
@@ -517,6 +627,40 @@ mod tests {
         assert!(hits != 0 && misses != 0 && total != 0);
     }
 
+    #[test]
+    fn test_memory_usage() {
+        let mut table = LookupHtbl::default();
+        assert_eq!(table.memory_usage(), 0);
+        let expected = table.insert_memory(b"key", b"value");
+        table.insert(b"key", b"value");
+        assert_eq!(table.memory_usage(), expected);
+
+        // Growing the table doesn't need another chunk yet.
+        let expected = table.memory_usage() + table.insert_memory(b"key2", b"value");
+        table.insert(b"key2", b"value");
+        assert!(table.memory_usage() <= expected);
+        assert!(table.memory_usage() < 2 * MIN_CHUNK_SIZE);
+
+        let expected = table.memory_usage() + table.ordered_index_memory();
+        table.build_ordered_index();
+        assert_eq!(table.memory_usage(), expected);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut table = LookupHtbl::default();
+        for key in ["b", "d", "a", "c", "ab"] {
+            table.insert(key.as_bytes(), key.to_uppercase().as_bytes());
+        }
+        assert_eq!(table.iter_from(b"").count(), 0);
+
+        table.build_ordered_index();
+        let keys: Vec<&[u8]> = table.iter_from(b"ab").map(|(key, _)| key).collect();
+        assert_eq!(keys, [b"ab".as_ref(), b"b", b"c", b"d"]);
+        assert_eq!(table.iter_from(b"bb").next(), Some((b"c".as_ref(), b"C".as_ref())));
+        assert_eq!(table.iter_from(b"e").next(), None);
+    }
+
     #[test]
     fn test_for_loop() {
         let keys = ["key1".as_bytes(), "key2".as_bytes(), "key3".as_bytes()];
@@ -603,6 +747,8 @@ mod tests {
         assert!(table.get("key".as_bytes()) == Some("value1".as_bytes()));
         table.insert("key".as_bytes(), "value2".as_bytes());
         assert!(table.get("key".as_bytes()) == Some("value2".as_bytes()));
+        assert_eq!(table.get_all("key".as_bytes()), ["value1".as_bytes(), "value2".as_bytes()]);
+        assert!(table.get_all("other".as_bytes()).is_empty());
     }
 
     // The RNG function should act like a random oracle, in which case the odds of
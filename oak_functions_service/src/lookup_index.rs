@@ -0,0 +1,322 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! An immutable hash index of lookup data, which is built once outside the
+//! enclave and then used in place, e.g. mapped from a file, instead of being
+//! deserialized entry by entry into a [`crate::lookup_htbl::LookupHtbl`].
+//!
+//! The index has the following layout, with all integers little-endian:
+//!
+//! ```ignore
+//!     magic: [u8; 8],           // b"OAKLKIDX"
+//!     entry_count: u64,
+//!     table_len: u64,           // A power of two greater than entry_count.
+//!     table: [u64; table_len],  // The number of an entry plus one, or 0.
+//!     offsets: [u64; entry_count],
+//!     entries: [Entry; entry_count],
+//! ```
+//!
+//! where every entry is:
+//!
+//! ```ignore
+//!     key_len: u32,
+//!     value_len: u32,
+//!     key: [u8],
+//!     value: [u8],
+//! ```
+//!
+//! Entries are numbered in bytewise order of their keys, and `offsets` holds
+//! the position of each entry in the index, so the index supports range and
+//! prefix lookups without an extra ordered index. `table` is an open
+//! addressing hash table with linear probing, keyed by the FNV-1a hash of the
+//! key. As the index is built outside the enclave, the hash has no secret.
+//! Every key has a single value: the last one in the lookup data.
+//!
+//! Loading an index only checks that its entries are in bounds and ordered,
+//! which doesn't allocate and is much faster than inserting every entry into
+//! a hash table.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+
+use anyhow::Context;
+
+const MAGIC: &[u8; 8] = b"OAKLKIDX";
+
+// The size of the magic, the entry count and the table length.
+const HEADER_SIZE: usize = 24;
+
+// The size of the key and value lengths of an entry.
+const ENTRY_HEADER_SIZE: usize = 8;
+
+/// Builds an index of the given entries. If several entries have the same
+/// key, the index keeps the value of the last one.
+pub fn build_lookup_index<'a, T: IntoIterator<Item = (&'a [u8], &'a [u8])>>(
+    entries: T,
+) -> anyhow::Result<Vec<u8>> {
+    let entries: BTreeMap<&[u8], &[u8]> = entries.into_iter().collect();
+    let table_len = (2 * entries.len()).next_power_of_two();
+    let entries_start = HEADER_SIZE + 8 * (table_len + entries.len());
+
+    let mut table = vec![0u64; table_len];
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut data = Vec::new();
+    for (number, (key, value)) in entries.iter().enumerate() {
+        offsets.push((entries_start + data.len()) as u64);
+        data.extend_from_slice(&u32::try_from(key.len()).context("key too long")?.to_le_bytes());
+        data.extend_from_slice(
+            &u32::try_from(value.len()).context("value too long")?.to_le_bytes(),
+        );
+        data.extend_from_slice(key);
+        data.extend_from_slice(value);
+
+        let mut slot = hash(key) as usize & (table_len - 1);
+        while table[slot] != 0 {
+            slot = (slot + 1) & (table_len - 1);
+        }
+        table[slot] = number as u64 + 1;
+    }
+
+    let mut index = Vec::with_capacity(entries_start + data.len());
+    index.extend_from_slice(MAGIC);
+    index.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    index.extend_from_slice(&(table_len as u64).to_le_bytes());
+    table.iter().chain(offsets.iter()).for_each(|n| index.extend_from_slice(&n.to_le_bytes()));
+    index.extend_from_slice(&data);
+    Ok(index)
+}
+
+/// An index of lookup data in the format built by [`build_lookup_index`].
+pub struct LookupIndex {
+    bytes: Box<dyn AsRef<[u8]> + Send + Sync>,
+    len: usize,
+    table_len: usize,
+}
+
+impl LookupIndex {
+    /// Loads the index in `bytes`, checking that its entries are in bounds and
+    /// ordered by key. The index is read in place, so `bytes` must not change
+    /// while it is in use.
+    pub fn new(bytes: Box<dyn AsRef<[u8]> + Send + Sync>) -> anyhow::Result<Self> {
+        let data = (*bytes).as_ref();
+        anyhow::ensure!(data.len() >= HEADER_SIZE && data[..8] == MAGIC[..], "not a lookup index");
+        let len = usize::try_from(read_u64(data, 8))?;
+        let table_len = usize::try_from(read_u64(data, 16))?;
+        anyhow::ensure!(
+            table_len.is_power_of_two() && table_len > len,
+            "invalid lookup index table length {}",
+            table_len
+        );
+        let entries_start = table_len
+            .checked_add(len)
+            .and_then(|words| words.checked_mul(8))
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .filter(|entries_start| *entries_start <= data.len())
+            .context("lookup index is truncated")?;
+
+        let index = Self { bytes, len, table_len };
+        let data = (*index.bytes).as_ref();
+        for slot in 0..table_len {
+            anyhow::ensure!(
+                read_u64(data, HEADER_SIZE + 8 * slot) <= len as u64,
+                "lookup index table refers to a missing entry"
+            );
+        }
+        let mut previous_key: Option<&[u8]> = None;
+        for number in 0..len {
+            let offset = usize::try_from(read_u64(data, index.offset_position(number)))?;
+            anyhow::ensure!(
+                offset >= entries_start && offset.saturating_add(ENTRY_HEADER_SIZE) <= data.len(),
+                "lookup index entry {} is out of bounds",
+                number
+            );
+            let key_len = read_u32(data, offset) as usize;
+            let value_len = read_u32(data, offset + 4) as usize;
+            anyhow::ensure!(
+                (offset + ENTRY_HEADER_SIZE)
+                    .checked_add(key_len)
+                    .and_then(|end| end.checked_add(value_len))
+                    .is_some_and(|end| end <= data.len()),
+                "lookup index entry {} is out of bounds",
+                number
+            );
+            let (key, _) = index.entry(number);
+            anyhow::ensure!(
+                previous_key.is_none_or(|previous_key| previous_key < key),
+                "lookup index keys aren't ordered"
+            );
+            previous_key = Some(key);
+        }
+        Ok(index)
+    }
+
+    /// Gets the value of a key.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let data = self.data();
+        let mut slot = hash(key) as usize & (self.table_len - 1);
+        // The table is never full, so the loop ends at an empty slot, but a bound
+        // keeps lookups in a malicious index finite.
+        for _ in 0..self.table_len {
+            match read_u64(data, HEADER_SIZE + 8 * slot) {
+                0 => return None,
+                number => {
+                    let (entry_key, value) = self.entry(number as usize - 1);
+                    if entry_key == key {
+                        return Some(value);
+                    }
+                }
+            }
+            slot = (slot + 1) & (self.table_len - 1);
+        }
+        None
+    }
+
+    /// Returns an iterator over the entries ordered by key, starting from the
+    /// first key greater than or equal to `start`.
+    pub fn iter_from<'a>(&'a self, start: &[u8]) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        // Binary search for the first entry that isn't before `start`.
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.entry(middle).0 < start {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        (low..self.len).map(move |number| self.entry(number))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the size of the index in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.data().len()
+    }
+
+    fn data(&self) -> &[u8] {
+        (*self.bytes).as_ref()
+    }
+
+    fn offset_position(&self, number: usize) -> usize {
+        HEADER_SIZE + 8 * (self.table_len + number)
+    }
+
+    // Reads the key and value of an entry. Only valid once the bounds of the entry
+    // were checked.
+    fn entry(&self, number: usize) -> (&[u8], &[u8]) {
+        let data = self.data();
+        let offset = read_u64(data, self.offset_position(number)) as usize;
+        let key_len = read_u32(data, offset) as usize;
+        let value_len = read_u32(data, offset + 4) as usize;
+        let key_start = offset + ENTRY_HEADER_SIZE;
+        let value_start = key_start + key_len;
+        (&data[key_start..value_start], &data[value_start..value_start + value_len])
+    }
+}
+
+fn read_u64(data: &[u8], position: usize) -> u64 {
+    u64::from_le_bytes(data[position..position + 8].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes(data[position..position + 4].try_into().unwrap())
+}
+
+// The 64-bit FNV-1a hash.
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, vec::Vec};
+
+    use super::*;
+
+    fn entries(count: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..count)
+            .map(|i| (format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()))
+            .collect()
+    }
+
+    fn load(entries: &[(Vec<u8>, Vec<u8>)]) -> LookupIndex {
+        let bytes = build_lookup_index(entries.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+            .expect("couldn't build lookup index");
+        LookupIndex::new(Box::new(bytes)).expect("couldn't load lookup index")
+    }
+
+    #[test]
+    fn test_get() {
+        let entries = entries(1000);
+        let index = load(&entries);
+        assert_eq!(index.len(), 1000);
+        for (key, value) in entries.iter() {
+            assert_eq!(index.get(key), Some(value.as_ref()));
+        }
+        assert_eq!(index.get(b"key1000"), None);
+        assert_eq!(index.get(b""), None);
+    }
+
+    #[test]
+    fn test_empty() {
+        let index = load(&[]);
+        assert!(index.is_empty());
+        assert_eq!(index.get(b"key"), None);
+        assert_eq!(index.iter_from(b"").count(), 0);
+    }
+
+    #[test]
+    fn test_last_value_wins() {
+        let index =
+            load(&[(b"key".to_vec(), b"value1".to_vec()), (b"key".to_vec(), b"value2".to_vec())]);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get(b"key"), Some(b"value2".as_ref()));
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let index = load(&entries(20));
+        let keys: Vec<&[u8]> = index.iter_from(b"key15").map(|(key, _)| key).take(6).collect();
+        assert_eq!(keys, [b"key15".as_ref(), b"key16", b"key17", b"key18", b"key19", b"key2"]);
+        assert_eq!(index.iter_from(b"key9").count(), 1);
+        assert_eq!(index.iter_from(b"l").count(), 0);
+    }
+
+    #[test]
+    fn test_rejects_invalid_index() {
+        let bytes = build_lookup_index([(b"key".as_ref(), b"value".as_ref())]).unwrap();
+        assert!(LookupIndex::new(Box::new(bytes[..bytes.len() - 1].to_vec())).is_err());
+        assert!(LookupIndex::new(Box::new(bytes[..HEADER_SIZE].to_vec())).is_err());
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 1;
+        assert!(LookupIndex::new(Box::new(bad_magic)).is_err());
+
+        // Point the offset of the entry past the end of the index.
+        let mut bad_offset = bytes.clone();
+        let position = HEADER_SIZE + 8 * 2;
+        bad_offset[position..position + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(LookupIndex::new(Box::new(bad_offset)).is_err());
+    }
+}
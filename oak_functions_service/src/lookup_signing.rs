@@ -0,0 +1,223 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Verification of lookup data snapshots signed by the owner of the data, so
+//! that the host can neither tamper with the lookup data nor roll it back to an
+//! older snapshot.
+//!
+//! See [`LookupDataSignature`] for what the signature covers. The key the
+//! signatures are verified with comes from the application config, which is
+//! measured into the attestation evidence, rather than from the host, which
+//! could otherwise replace it with its own key or omit it.
+
+use alloc::vec::Vec;
+
+use anyhow::{anyhow, Context};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use prost::encoding::{encode_varint, encoded_len_varint};
+use sha2::{Digest, Sha256};
+
+use crate::{lookup::mutexes::Mutex, proto::oak::functions::LookupDataSignature};
+
+/// Prefix of every signed message, so that signatures over lookup data can't be
+/// confused with other signatures by the same key.
+pub const SIGNATURE_CONTEXT: &[u8] = b"oak_functions_lookup_data";
+
+/// Computes the digest of a lookup data snapshot from its entries, in the order
+/// they are in the lookup data file.
+#[derive(Default)]
+pub struct LookupDataDigest {
+    hasher: Sha256,
+}
+
+impl LookupDataDigest {
+    /// Adds the next entry of the snapshot.
+    ///
+    /// The entry is hashed as a length-delimited
+    /// `oak.functions.lookup_data.Entry` message, without copying the key and
+    /// the value.
+    pub fn update(&mut self, key: &[u8], value: &[u8]) {
+        let mut header = Vec::with_capacity(32);
+        let entry_len = field_len(key) + field_len(value);
+        encode_varint(entry_len as u64, &mut header);
+        if !key.is_empty() {
+            header.push(0x0A);
+            encode_varint(key.len() as u64, &mut header);
+        }
+        self.hasher.update(&header);
+        self.hasher.update(key);
+        if !value.is_empty() {
+            header.clear();
+            header.push(0x12);
+            encode_varint(value.len() as u64, &mut header);
+            self.hasher.update(&header);
+            self.hasher.update(value);
+        }
+    }
+
+    /// Returns the digest of the entries added so far.
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+}
+
+// Returns the encoded length of a bytes field, which is omitted if empty.
+fn field_len(bytes: &[u8]) -> usize {
+    if bytes.is_empty() {
+        0
+    } else {
+        1 + encoded_len_varint(bytes.len() as u64) + bytes.len()
+    }
+}
+
+/// Returns the message signed for the snapshot with the given version and
+/// digest.
+pub fn signed_message(version: u64, digest: &[u8; 32]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, &version.to_be_bytes(), digest].concat()
+}
+
+/// Verifies the signatures of the lookup data snapshots sent to the enclave,
+/// and keeps track of the version of the active snapshot.
+pub struct LookupDataVerifier {
+    public_key: VerifyingKey,
+    // Digest of the next lookup data received so far.
+    next_digest: Mutex<LookupDataDigest>,
+    // Version of the active lookup data, if any was activated.
+    version: Mutex<Option<u64>>,
+}
+
+impl LookupDataVerifier {
+    /// Creates a verifier for snapshots signed with the raw Ed25519
+    /// `public_key`.
+    pub fn new(public_key: &[u8]) -> anyhow::Result<Self> {
+        let public_key = VerifyingKey::try_from(public_key)
+            .map_err(|error| anyhow!("invalid lookup data signing key: {}", error))?;
+        Ok(Self {
+            public_key,
+            next_digest: Mutex::new(LookupDataDigest::default()),
+            version: Mutex::new(None),
+        })
+    }
+
+    /// Adds entries to the digest of the next lookup data.
+    pub fn extend<'a, T: Iterator<Item = (&'a [u8], &'a [u8])>>(&self, entries: T) {
+        let mut next_digest = self.next_digest.lock();
+        entries.for_each(|(key, value)| next_digest.update(key, value));
+    }
+
+    /// Discards the digest of the next lookup data.
+    pub fn abort(&self) {
+        *self.next_digest.lock() = LookupDataDigest::default();
+    }
+
+    /// Checks that `signature` is a valid signature of the next lookup data
+    /// with a newer version than the active lookup data. On success the next
+    /// lookup data must be activated; either way, the next digest is reset.
+    pub fn verify_next(&self, signature: Option<&LookupDataSignature>) -> anyhow::Result<u64> {
+        let digest = core::mem::take(&mut *self.next_digest.lock()).finalize();
+        let signature = signature.context("lookup data isn't signed")?;
+        let mut version = self.version.lock();
+        if let Some(current) = *version {
+            anyhow::ensure!(
+                signature.version > current,
+                "lookup data version {} isn't newer than the active version {}",
+                signature.version,
+                current
+            );
+        }
+        let ed25519_signature = Signature::from_slice(&signature.signature)
+            .map_err(|error| anyhow!("invalid lookup data signature: {}", error))?;
+        self.public_key
+            .verify(&signed_message(signature.version, &digest), &ed25519_signature)
+            .map_err(|error| anyhow!("couldn't verify lookup data signature: {}", error))?;
+        *version = Some(signature.version);
+        Ok(signature.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use ed25519_dalek::{Signer, SigningKey};
+    use oak_proto_rust::oak::oak_functions::lookup_data::Entry;
+    use prost::Message;
+
+    use super::*;
+
+    const ENTRIES: [(&[u8], &[u8]); 3] = [(b"key", b"value"), (b"", b"value"), (b"key", b"")];
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[3; 32])
+    }
+
+    fn sign(version: u64) -> LookupDataSignature {
+        let mut digest = LookupDataDigest::default();
+        ENTRIES.iter().for_each(|(key, value)| digest.update(key, value));
+        let signature = signing_key().sign(&signed_message(version, &digest.finalize()));
+        LookupDataSignature { version, signature: signature.to_bytes().to_vec() }
+    }
+
+    fn verifier() -> LookupDataVerifier {
+        LookupDataVerifier::new(signing_key().verifying_key().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn digest_matches_lookup_data_file() {
+        let mut file = vec![];
+        for (key, value) in ENTRIES {
+            Entry { key: key.to_vec(), value: value.to_vec() }
+                .encode_length_delimited(&mut file)
+                .unwrap();
+        }
+        let mut digest = LookupDataDigest::default();
+        ENTRIES.iter().for_each(|(key, value)| digest.update(key, value));
+
+        assert_eq!(digest.finalize(), <[u8; 32]>::from(Sha256::digest(&file)));
+    }
+
+    #[test]
+    fn verify_next_accepts_newer_versions() {
+        let verifier = verifier();
+        verifier.extend(ENTRIES.into_iter());
+        assert_eq!(verifier.verify_next(Some(&sign(1))).unwrap(), 1);
+        verifier.extend(ENTRIES.into_iter());
+        assert_eq!(verifier.verify_next(Some(&sign(2))).unwrap(), 2);
+    }
+
+    #[test]
+    fn verify_next_rejects_rollback() {
+        let verifier = verifier();
+        verifier.extend(ENTRIES.into_iter());
+        verifier.verify_next(Some(&sign(2))).unwrap();
+        verifier.extend(ENTRIES.into_iter());
+        assert!(verifier.verify_next(Some(&sign(1))).is_err());
+    }
+
+    #[test]
+    fn verify_next_rejects_tampered_data() {
+        let verifier = verifier();
+        verifier.extend(ENTRIES[..2].iter().copied());
+        assert!(verifier.verify_next(Some(&sign(1))).is_err());
+    }
+
+    #[test]
+    fn verify_next_rejects_unsigned_data() {
+        let verifier = verifier();
+        verifier.extend(ENTRIES.into_iter());
+        assert!(verifier.verify_next(None).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Inference with a machine learning model that the launcher sends with the
+//! `InitializeRequest`, for models that are too heavy to run inside Wasm.
+//!
+//! Models are in the ONNX format, and run with [tract](https://github.com/sonos/tract).
+//! tract requires the standard library, so models can only be loaded with the
+//! `ml` feature, which Oak Functions on Oak Containers can enable.
+
+use alloc::vec::Vec;
+
+use oak_functions_sdk::proto::oak::functions::wasm::v1::Tensor;
+
+/// The model that Wasm modules run inferences with, if one was loaded.
+#[derive(Default)]
+pub struct InferenceModel {
+    #[cfg(feature = "ml")]
+    plan: Option<Plan>,
+}
+
+#[cfg(feature = "ml")]
+type Plan = tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>;
+
+impl InferenceModel {
+    /// Loads and optimizes the ONNX model in `model_bytes`, whose inputs must
+    /// have fixed shapes.
+    #[cfg(feature = "ml")]
+    pub fn load(model_bytes: &[u8]) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        use tract_onnx::prelude::*;
+
+        let plan = tract_onnx::onnx()
+            .model_for_read(&mut &model_bytes[..])
+            .and_then(|model| model.into_optimized())
+            .and_then(|model| model.into_runnable())
+            .context("couldn't load ML model")?;
+        Ok(Self { plan: Some(plan) })
+    }
+
+    /// Fails, as models can only be loaded with the `ml` feature.
+    #[cfg(not(feature = "ml"))]
+    pub fn load(_model_bytes: &[u8]) -> anyhow::Result<Self> {
+        anyhow::bail!("ML models aren't supported without the `ml` feature")
+    }
+
+    /// Runs the model on `inputs`, and returns its outputs as `f32` tensors.
+    pub fn infer(&self, inputs: Vec<Tensor>) -> Result<Vec<Tensor>, micro_rpc::Status> {
+        #[cfg(feature = "ml")]
+        if let Some(plan) = self.plan.as_ref() {
+            return run(plan, inputs).map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    micro_rpc::StatusCode::InvalidArgument,
+                    alloc::format!("couldn't run ML model: {:?}", err),
+                )
+            });
+        }
+        let _ = inputs;
+        Err(micro_rpc::Status::new_with_message(
+            micro_rpc::StatusCode::FailedPrecondition,
+            "no ML model was loaded",
+        ))
+    }
+}
+
+#[cfg(feature = "ml")]
+fn run(plan: &Plan, inputs: Vec<Tensor>) -> anyhow::Result<Vec<Tensor>> {
+    use tract_onnx::prelude::{TValue, TVec};
+
+    let inputs = inputs
+        .into_iter()
+        .map(|input| {
+            let shape: Vec<usize> = input.shape.iter().map(|&dim| dim as usize).collect();
+            tract_onnx::prelude::Tensor::from_shape(&shape, &input.values).map(TValue::from)
+        })
+        .collect::<anyhow::Result<TVec<TValue>>>()?;
+    plan.run(inputs)?
+        .iter()
+        .map(|output| {
+            let output = output.cast_to::<f32>()?;
+            Ok(Tensor {
+                shape: output.shape().iter().map(|&dim| dim as u64).collect(),
+                values: output.as_slice::<f32>()?.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn infer_without_model() {
+        let model = InferenceModel::default();
+        let input = Tensor { shape: vec![1], values: vec![1.0] };
+        assert_eq!(
+            model.infer(vec![input]).unwrap_err().code,
+            micro_rpc::StatusCode::FailedPrecondition
+        );
+    }
+
+    #[test]
+    fn load_invalid_model() {
+        assert!(InferenceModel::load(b"not a model").is_err());
+    }
+}
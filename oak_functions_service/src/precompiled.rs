@@ -0,0 +1,140 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Wasm modules compiled to native code by one enclave and run by another,
+//! which then skips compiling them, e.g. after the launcher restarts the
+//! enclave.
+//!
+//! The launcher keeps precompiled modules between enclaves, so the enclave
+//! encrypts them under a key that only enclaves of the same group can derive,
+//! bound to the digest of the Wasm module they were compiled from. The Wasm
+//! module stays the identity of the workload, and the enclave never runs native
+//! code it didn't compile itself from that module.
+
+use alloc::vec::Vec;
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use anyhow::anyhow;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::proto::oak::functions::PrecompiledWasmModule;
+
+const NONCE_SIZE_BYTES: usize = 12;
+
+const KEY_DERIVATION_LABEL: &[u8] = b"oak_functions precompiled Wasm module key";
+
+/// Returns the fixed encapsulated public key that enclaves derive session keys
+/// for from their group key, see [`precompiled_module_key`].
+pub fn key_derivation_encapsulated_key() -> [u8; 32] {
+    Sha256::digest(KEY_DERIVATION_LABEL).into()
+}
+
+/// Returns the key of precompiled Wasm modules, given the request key of the
+/// session keys that the group key derives for
+/// [`key_derivation_encapsulated_key`]. As the encapsulated key is fixed, all
+/// enclaves with the same group key get the same key.
+pub fn precompiled_module_key(session_request_key: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(KEY_DERIVATION_LABEL)
+        .chain_update(session_request_key)
+        .finalize()
+        .to_vec()
+}
+
+/// Returns the SHA-256 digest of `wasm_module`, which its precompiled form is
+/// bound to.
+pub fn wasm_module_digest(wasm_module: &[u8]) -> [u8; 32] {
+    Sha256::digest(wasm_module).into()
+}
+
+/// Encrypts `precompiled`, which was compiled from the Wasm module with
+/// `wasm_module_digest`, under `key` with a random nonce.
+pub fn seal_precompiled_module(
+    key: &[u8],
+    wasm_module_digest: &[u8; 32],
+    precompiled: &[u8],
+) -> anyhow::Result<PrecompiledWasmModule> {
+    let cipher = new_cipher(key)?;
+    let mut nonce = [0u8; NONCE_SIZE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt((&nonce).into(), Payload { msg: precompiled, aad: wasm_module_digest })
+        .map_err(|error| anyhow!("couldn't encrypt precompiled module: {}", error))?;
+    Ok(PrecompiledWasmModule { nonce: nonce.to_vec(), ciphertext })
+}
+
+/// Decrypts `sealed` with `key`, failing if it wasn't encrypted under `key`
+/// for the Wasm module with `wasm_module_digest`, or was tampered with.
+pub fn open_precompiled_module(
+    key: &[u8],
+    wasm_module_digest: &[u8; 32],
+    sealed: &PrecompiledWasmModule,
+) -> anyhow::Result<Vec<u8>> {
+    let cipher = new_cipher(key)?;
+    let nonce: [u8; NONCE_SIZE_BYTES] = sealed
+        .nonce
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("invalid nonce size: {} bytes", sealed.nonce.len()))?;
+    cipher
+        .decrypt((&nonce).into(), Payload { msg: &sealed.ciphertext, aad: wasm_module_digest })
+        .map_err(|error| anyhow!("couldn't decrypt precompiled module: {}", error))
+}
+
+fn new_cipher(key: &[u8]) -> anyhow::Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|_| anyhow!("invalid precompiled module key size: {} bytes", key.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn open_sealed_module() {
+        let digest = wasm_module_digest(b"module");
+        let sealed = seal_precompiled_module(&KEY, &digest, b"native code").unwrap();
+        assert_eq!(open_precompiled_module(&KEY, &digest, &sealed).unwrap(), b"native code");
+    }
+
+    #[test]
+    fn open_fails_with_wrong_key() {
+        let digest = wasm_module_digest(b"module");
+        let sealed = seal_precompiled_module(&KEY, &digest, b"native code").unwrap();
+        assert!(open_precompiled_module(&[8; 32], &digest, &sealed).is_err());
+    }
+
+    #[test]
+    fn open_fails_for_other_module() {
+        let sealed =
+            seal_precompiled_module(&KEY, &wasm_module_digest(b"module"), b"native code").unwrap();
+        assert!(open_precompiled_module(&KEY, &wasm_module_digest(b"other"), &sealed).is_err());
+    }
+
+    #[test]
+    fn open_fails_with_tampered_ciphertext() {
+        let digest = wasm_module_digest(b"module");
+        let mut sealed = seal_precompiled_module(&KEY, &digest, b"native code").unwrap();
+        sealed.ciphertext[0] ^= 1;
+        assert!(open_precompiled_module(&KEY, &digest, &sealed).is_err());
+    }
+}
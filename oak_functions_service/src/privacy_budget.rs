@@ -0,0 +1,173 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The differential privacy budget of a deployment, which Wasm modules consume
+//! with `ConsumeBudget` before they release the results of a query.
+//!
+//! The budget is tracked in the memory of the enclave, so it is reset when the
+//! enclave is relaunched, unless the platform can seal the consumed budget for
+//! the next enclave, see `PersistentState`.
+
+use alloc::format;
+
+use crate::lookup::mutexes::Mutex;
+
+/// Tracks how much of the privacy budget (epsilon) was consumed.
+#[derive(Default)]
+pub struct PrivacyBudget {
+    // The total budget, if one was configured.
+    epsilon: Option<f64>,
+    consumed: Mutex<f64>,
+}
+
+impl PrivacyBudget {
+    /// Creates a budget of `epsilon`, which must be finite and positive.
+    pub fn new(epsilon: f64) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            epsilon.is_finite() && epsilon > 0.0,
+            "the privacy budget must be finite and positive, got {}",
+            epsilon
+        );
+        Ok(Self { epsilon: Some(epsilon), consumed: Mutex::new(0.0) })
+    }
+
+    /// Consumes `epsilon` of the budget and returns the remaining budget.
+    ///
+    /// Nothing is consumed if the remaining budget is too small, so that a
+    /// module can't consume more than the budget in total.
+    pub fn consume(&self, epsilon: f64) -> Result<f64, micro_rpc::Status> {
+        let Some(budget) = self.epsilon else {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::FailedPrecondition,
+                "no privacy budget was configured",
+            ));
+        };
+        if !epsilon.is_finite() || epsilon <= 0.0 {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("epsilon must be finite and positive, got {}", epsilon),
+            ));
+        }
+        let mut consumed = self.consumed.lock();
+        if *consumed + epsilon > budget {
+            return Err(budget_exhausted(budget - *consumed));
+        }
+        *consumed += epsilon;
+        Ok(budget - *consumed)
+    }
+
+    /// Returns how much of the budget was consumed.
+    pub fn consumed(&self) -> f64 {
+        *self.consumed.lock()
+    }
+
+    /// Restores the budget consumed by an earlier enclave.
+    ///
+    /// The consumed budget never decreases, so restoring an older state after
+    /// more of the budget was consumed has no effect.
+    pub fn restore_consumed(&self, consumed: f64) -> Result<(), micro_rpc::Status> {
+        if !consumed.is_finite() || consumed < 0.0 {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("the consumed budget must be finite and not negative, got {}", consumed),
+            ));
+        }
+        let mut current = self.consumed.lock();
+        *current = current.max(consumed);
+        Ok(())
+    }
+
+    /// Fails if a budget was configured and all of it was consumed, in which
+    /// case no more requests may be handled.
+    pub fn check_not_exhausted(&self) -> Result<(), micro_rpc::Status> {
+        match self.epsilon {
+            Some(budget) if *self.consumed.lock() >= budget => Err(budget_exhausted(0.0)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn budget_exhausted(remaining: f64) -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::ResourceExhausted,
+        format!("PrivacyBudgetExhausted: the remaining privacy budget is {}", remaining),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consume_until_exhausted() {
+        let budget = PrivacyBudget::new(1.0).unwrap();
+        assert_eq!(budget.consume(0.25), Ok(0.75));
+        assert!(budget.check_not_exhausted().is_ok());
+
+        // Too much for the remaining budget, so nothing is consumed.
+        let err = budget.consume(1.0).unwrap_err();
+        assert_eq!(err.code, micro_rpc::StatusCode::ResourceExhausted);
+        assert_eq!(budget.consume(0.75), Ok(0.0));
+
+        assert_eq!(
+            budget.check_not_exhausted().unwrap_err().code,
+            micro_rpc::StatusCode::ResourceExhausted
+        );
+        assert_eq!(budget.consume(0.1).unwrap_err().code, micro_rpc::StatusCode::ResourceExhausted);
+    }
+
+    #[test]
+    fn invalid_epsilon() {
+        assert!(PrivacyBudget::new(0.0).is_err());
+        assert!(PrivacyBudget::new(f64::INFINITY).is_err());
+        assert!(PrivacyBudget::new(f64::NAN).is_err());
+
+        let budget = PrivacyBudget::new(1.0).unwrap();
+        for epsilon in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(
+                budget.consume(epsilon).unwrap_err().code,
+                micro_rpc::StatusCode::InvalidArgument
+            );
+        }
+    }
+
+    #[test]
+    fn restore_consumed() {
+        let budget = PrivacyBudget::new(1.0).unwrap();
+        assert!(budget.restore_consumed(0.5).is_ok());
+        assert_eq!(budget.consumed(), 0.5);
+        assert_eq!(budget.consume(0.25), Ok(0.25));
+
+        // An older state doesn't give back any of the budget.
+        assert!(budget.restore_consumed(0.5).is_ok());
+        assert_eq!(budget.consumed(), 0.75);
+
+        assert!(budget.restore_consumed(1.0).is_ok());
+        assert!(budget.check_not_exhausted().is_err());
+        assert!(budget.restore_consumed(f64::NAN).is_err());
+        assert!(budget.restore_consumed(-1.0).is_err());
+    }
+
+    #[test]
+    fn no_budget() {
+        let budget = PrivacyBudget::default();
+        assert!(budget.check_not_exhausted().is_ok());
+        assert_eq!(
+            budget.consume(0.1).unwrap_err().code,
+            micro_rpc::StatusCode::FailedPrecondition
+        );
+    }
+}
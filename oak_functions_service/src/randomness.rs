@@ -0,0 +1,127 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Deterministic pseudo-randomness for Wasm modules.
+//!
+//! Every invocation reads from its own stream, which is derived from a key of
+//! the handler and the number of invocations before it, so modules never see
+//! the raw entropy of the enclave. With a fixed key, replaying the same
+//! sequence of requests yields the same randomness, which makes tests
+//! deterministic.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// The size of the key from which all streams are derived.
+pub const KEY_SIZE: usize = 32;
+
+/// The maximum number of bytes a Wasm module can read at once.
+pub const MAX_READ_SIZE: usize = 1024 * 1024;
+
+const BLOCK_SIZE: u64 = 32;
+
+/// Hands out a stream of pseudo-random bytes to every invocation.
+pub struct RandomnessSource {
+    key: [u8; KEY_SIZE],
+    invocations: AtomicU64,
+}
+
+impl RandomnessSource {
+    /// Creates a source whose streams are derived from `key` instead of a
+    /// random key, so that they can be reproduced.
+    pub fn with_key(key: [u8; KEY_SIZE]) -> Self {
+        Self { key, invocations: AtomicU64::new(0) }
+    }
+
+    /// Returns the stream of the next invocation.
+    pub fn next_stream(&self) -> RandomStream {
+        RandomStream {
+            key: self.key,
+            invocation: self.invocations.fetch_add(1, Ordering::Relaxed),
+            offset: 0,
+        }
+    }
+}
+
+impl Default for RandomnessSource {
+    /// Creates a source with a random key.
+    fn default() -> Self {
+        let mut key = [0; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        Self::with_key(key)
+    }
+}
+
+/// The pseudo-random bytes of a single invocation.
+///
+/// Block `i` of the stream of invocation `n` is `SHA-256(key || n || i)`.
+#[derive(Clone)]
+pub struct RandomStream {
+    key: [u8; KEY_SIZE],
+    invocation: u64,
+    // The number of bytes read so far.
+    offset: u64,
+}
+
+impl RandomStream {
+    /// Returns the next `len` bytes of the stream.
+    pub fn read(&mut self, len: usize) -> Vec<u8> {
+        let mut random = Vec::with_capacity(len);
+        while random.len() < len {
+            let block = Sha256::new()
+                .chain_update(self.key)
+                .chain_update(self.invocation.to_le_bytes())
+                .chain_update((self.offset / BLOCK_SIZE).to_le_bytes())
+                .finalize();
+            let start = (self.offset % BLOCK_SIZE) as usize;
+            let end = block.len().min(start + len - random.len());
+            random.extend_from_slice(&block[start..end]);
+            self.offset += (end - start) as u64;
+        }
+        random
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_are_reproducible() {
+        let first = RandomnessSource::with_key([1; KEY_SIZE]);
+        let second = RandomnessSource::with_key([1; KEY_SIZE]);
+
+        // Reading in parts yields the same bytes as reading at once.
+        let mut stream = first.next_stream();
+        let mut parts = stream.read(5);
+        parts.extend(stream.read(40));
+        assert_eq!(parts, second.next_stream().read(45));
+
+        assert_eq!(first.next_stream().read(100), second.next_stream().read(100));
+    }
+
+    #[test]
+    fn streams_differ() {
+        let source = RandomnessSource::with_key([1; KEY_SIZE]);
+        let first = source.next_stream().read(32);
+        assert_ne!(first, source.next_stream().read(32));
+        assert_ne!(first, RandomnessSource::with_key([2; KEY_SIZE]).next_stream().read(32));
+        assert_ne!(first, RandomnessSource::default().next_stream().read(32));
+    }
+}
@@ -0,0 +1,151 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Negotiation of the constant response size between the launcher and the Wasm
+//! module.
+//!
+//! A Wasm module can declare the maximum size of its responses with
+//! [`oak_functions_sdk::declare_max_response_size`]. If the launcher doesn't
+//! request a specific constant response size, the declared maximum is rounded
+//! up to the next power of two, so that the size only reveals a coarse bucket.
+
+use anyhow::{anyhow, Context};
+use oak_functions_sdk::MAX_RESPONSE_SIZE_SECTION;
+
+/// Constant response size used if the launcher doesn't request one and the
+/// Wasm module doesn't declare its maximum response size.
+pub const DEFAULT_CONSTANT_RESPONSE_SIZE: u32 = 1024;
+
+/// Id of custom sections in the Wasm binary format.
+const CUSTOM_SECTION_ID: u8 = 0;
+
+/// Size of the magic number and version that precede the sections of a Wasm
+/// module.
+const WASM_HEADER_SIZE: usize = 8;
+
+/// Returns the maximum response size the Wasm module declares, if any.
+pub fn declared_max_response_size(wasm_module: &[u8]) -> anyhow::Result<Option<u32>> {
+    let mut sections = wasm_module.get(WASM_HEADER_SIZE..).context("Wasm module is too short")?;
+    while let Some((&id, rest)) = sections.split_first() {
+        let (size, rest) = read_u32(rest)?;
+        let contents = rest.get(..size as usize).context("truncated Wasm section")?;
+        sections = &rest[size as usize..];
+        if id != CUSTOM_SECTION_ID {
+            continue;
+        }
+        let (name_size, contents) = read_u32(contents)?;
+        let name = contents.get(..name_size as usize).context("truncated Wasm section name")?;
+        if name != MAX_RESPONSE_SIZE_SECTION.as_bytes() {
+            continue;
+        }
+        let value = contents[name_size as usize..]
+            .try_into()
+            .map_err(|_| anyhow!("invalid {} section", MAX_RESPONSE_SIZE_SECTION))?;
+        return Ok(Some(u32::from_le_bytes(value)));
+    }
+    Ok(None)
+}
+
+/// Returns the constant response size to use: the requested size if non-zero,
+/// or else the declared maximum rounded up to the next power of two.
+///
+/// Fails if the requested size is smaller than the declared maximum, as that
+/// would truncate responses.
+pub fn constant_response_size(requested: u32, declared_max: Option<u32>) -> anyhow::Result<u32> {
+    match (requested, declared_max) {
+        (0, Some(declared_max)) => Ok(declared_max.checked_next_power_of_two().unwrap_or(u32::MAX)),
+        (0, None) => Ok(DEFAULT_CONSTANT_RESPONSE_SIZE),
+        (requested, Some(declared_max)) if requested < declared_max => Err(anyhow!(
+            "constant response size {} is smaller than the maximum response size {} declared by \
+             the Wasm module",
+            requested,
+            declared_max
+        )),
+        (requested, _) => Ok(requested),
+    }
+}
+
+// Reads an unsigned LEB128 integer, as used by the Wasm binary format.
+fn read_u32(bytes: &[u8]) -> anyhow::Result<(u32, &[u8])> {
+    let mut value = 0u32;
+    for (i, &byte) in bytes.iter().enumerate().take(5) {
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    Err(anyhow!("invalid integer in Wasm module"))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn wasm_module(sections: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        for (id, contents) in sections {
+            module.push(*id);
+            module.push(contents.len() as u8);
+            module.extend_from_slice(contents);
+        }
+        module
+    }
+
+    fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        contents.push(name.len() as u8);
+        contents.extend_from_slice(name.as_bytes());
+        contents.extend_from_slice(data);
+        contents
+    }
+
+    #[test]
+    fn test_declared_max_response_size() {
+        let section = custom_section(MAX_RESPONSE_SIZE_SECTION, &3000u32.to_le_bytes());
+        let other = custom_section("name", b"ignored");
+        let module = wasm_module(&[(1, b"\x00"), (0, &other), (0, &section)]);
+
+        assert_eq!(declared_max_response_size(&module).unwrap(), Some(3000));
+    }
+
+    #[test]
+    fn test_undeclared_max_response_size() {
+        let module = wasm_module(&[(1, b"\x00")]);
+
+        assert_eq!(declared_max_response_size(&module).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_max_response_size() {
+        let section = custom_section(MAX_RESPONSE_SIZE_SECTION, b"\x01\x02");
+        let module = wasm_module(&[(0, &section)]);
+
+        assert!(declared_max_response_size(&module).is_err());
+        assert!(declared_max_response_size(b"\0asm").is_err());
+    }
+
+    #[test]
+    fn test_constant_response_size() {
+        assert_eq!(constant_response_size(0, Some(3000)).unwrap(), 4096);
+        assert_eq!(constant_response_size(0, Some(4096)).unwrap(), 4096);
+        assert_eq!(constant_response_size(0, None).unwrap(), DEFAULT_CONSTANT_RESPONSE_SIZE);
+        assert_eq!(constant_response_size(5000, Some(3000)).unwrap(), 5000);
+        assert_eq!(constant_response_size(5000, None).unwrap(), 5000);
+        assert!(constant_response_size(2000, Some(3000)).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Storage of the secrets provisioned to the enclave after it was attested,
+//! e.g. API keys or keys for decrypting lookup data, which Wasm modules read
+//! with `ReadSecret`.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use crate::lookup::mutexes::RwLock;
+
+/// Holds the provisioned secrets by name. Secrets only ever exist in the
+/// memory of the enclave, and are never logged.
+#[derive(Default)]
+pub struct SecretStore {
+    secrets: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+impl SecretStore {
+    /// Replaces all secrets with `secrets`, so that secrets revoked by the
+    /// key management service disappear when the enclave is provisioned again.
+    pub fn provision(&self, secrets: BTreeMap<String, Vec<u8>>) {
+        *self.secrets.write() = secrets;
+    }
+
+    /// Returns the secret named `name`, if it was provisioned.
+    pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+        self.secrets.read().get(name).cloned()
+    }
+
+    /// Returns the number of provisioned secrets.
+    pub fn len(&self) -> usize {
+        self.secrets.read().len()
+    }
+
+    /// Returns whether no secrets were provisioned.
+    pub fn is_empty(&self) -> bool {
+        self.secrets.read().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{borrow::ToOwned, vec};
+
+    use super::*;
+
+    #[test]
+    fn provision_replaces_secrets() {
+        let store = SecretStore::default();
+        assert!(store.is_empty());
+
+        store.provision(BTreeMap::from([
+            ("api_key".to_owned(), vec![1, 2, 3]),
+            ("data_key".to_owned(), vec![4, 5, 6]),
+        ]));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("api_key"), Some(vec![1, 2, 3]));
+
+        store.provision(BTreeMap::from([("data_key".to_owned(), vec![7])]));
+        assert_eq!(store.get("api_key"), None);
+        assert_eq!(store.get("data_key"), Some(vec![7]));
+    }
+}
@@ -14,27 +14,53 @@
 // limitations under the License.
 //
 
-use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeSet, format, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use log::Level;
 use oak_functions_sdk::proto::oak::functions::wasm::v1::{
-    BytesValue, LogRequest, LogResponse, LookupDataMultiRequest, LookupDataMultiResponse,
-    LookupDataRequest, LookupDataResponse, ReadRequestRequest, ReadRequestResponse, StdWasmApi,
+    AggregateReadRequest, AggregateReadResponse, AggregateWriteRequest, AggregateWriteResponse,
+    BytesValue, ConsumeBudgetRequest, ConsumeBudgetResponse, InvokeModuleRequest,
+    InvokeModuleResponse, KeyValue, LogLevel, LogRequest, LogResponse, LookupDataMultiRequest,
+    LookupDataMultiResponse, LookupDataRequest, LookupDataResponse, LookupMultiRequest,
+    LookupMultiResponse, LookupPrefixRequest, LookupPrefixResponse, LookupRangeRequest,
+    LookupRangeResponse, MlInferRequest, MlInferResponse, ReadRandomRequest, ReadRandomResponse,
+    ReadRequestRequest, ReadRequestResponse, ReadSecretRequest, ReadSecretResponse, StdWasmApi,
     StdWasmApiServer, TestRequest, TestResponse, WriteResponseRequest, WriteResponseResponse,
 };
 use spinning_top::Spinlock;
 
 use super::{WasmApi, WasmApiFactory};
 use crate::{
-    logger::{OakLogger, StandaloneLogger},
+    logger::OakLogger,
     lookup::{format_bytes, limit, LookupData, LookupDataManager},
+    randomness::{RandomStream, RandomnessSource, MAX_READ_SIZE},
+    secrets::SecretStore,
+    Subsystems,
 };
 
+/// The maximum number of items returned by a single range or prefix lookup.
+const MAX_SCAN_LIMIT: u32 = 10_000;
+
 /// The main purpose of this factory is to allow creating a new instance of the
 /// [`StdWasmApiImpl`] for each incoming gRPC request, with an immutable
 /// snapshot of the current lookup data.
 pub struct StdWasmApiFactory {
     pub lookup_data_manager: Arc<LookupDataManager>,
+    /// See [`LookupData::with_namespace`].
+    pub lookup_namespace: Vec<u8>,
+    pub secret_store: Arc<SecretStore>,
+    pub subsystems: Subsystems,
+    /// Shared by all invocations, which read from consecutive streams.
+    pub randomness: Arc<RandomnessSource>,
+    /// Logs the messages of the Wasm module, among others.
+    pub logger: Arc<dyn OakLogger>,
+}
+
+impl StdWasmApiFactory {
+    fn create_lookup_data(&self) -> LookupData {
+        self.lookup_data_manager.create_lookup_data().with_namespace(self.lookup_namespace.clone())
+    }
 }
 
 impl WasmApiFactory for StdWasmApiFactory {
@@ -43,11 +69,62 @@ impl WasmApiFactory for StdWasmApiFactory {
         request: Vec<u8>,
         response: Arc<Spinlock<Vec<u8>>>,
     ) -> Box<dyn WasmApi> {
-        Box::new(StdWasmApiImpl {
-            lookup_data: self.lookup_data_manager.create_lookup_data(),
-            logger: Arc::new(StandaloneLogger),
+        Box::new(StdWasmApiImpl::new(
+            self.create_lookup_data(),
+            self.secret_store.clone(),
+            self.subsystems.clone(),
+            self.randomness.next_stream(),
+            self.logger.clone(),
             request,
             response,
+        ))
+    }
+
+    fn snapshot(&self) -> Arc<dyn WasmApiFactory + Send + Sync> {
+        Arc::new(SnapshotWasmApiFactory {
+            lookup_data: self.create_lookup_data(),
+            secret_store: self.secret_store.clone(),
+            subsystems: self.subsystems.clone(),
+            randomness: self.randomness.clone(),
+            logger: self.logger.clone(),
+        })
+    }
+}
+
+/// Creates instances of [`StdWasmApiImpl`] that all share the same snapshot of
+/// the lookup data, e.g. for the requests of a batch.
+pub struct SnapshotWasmApiFactory {
+    lookup_data: LookupData,
+    secret_store: Arc<SecretStore>,
+    subsystems: Subsystems,
+    randomness: Arc<RandomnessSource>,
+    logger: Arc<dyn OakLogger>,
+}
+
+impl WasmApiFactory for SnapshotWasmApiFactory {
+    fn create_wasm_api(
+        &self,
+        request: Vec<u8>,
+        response: Arc<Spinlock<Vec<u8>>>,
+    ) -> Box<dyn WasmApi> {
+        Box::new(StdWasmApiImpl::new(
+            self.lookup_data.clone(),
+            self.secret_store.clone(),
+            self.subsystems.clone(),
+            self.randomness.next_stream(),
+            self.logger.clone(),
+            request,
+            response,
+        ))
+    }
+
+    fn snapshot(&self) -> Arc<dyn WasmApiFactory + Send + Sync> {
+        Arc::new(SnapshotWasmApiFactory {
+            lookup_data: self.lookup_data.clone(),
+            secret_store: self.secret_store.clone(),
+            subsystems: self.subsystems.clone(),
+            randomness: self.randomness.clone(),
+            logger: self.logger.clone(),
         })
     }
 }
@@ -59,11 +136,50 @@ impl WasmApiFactory for StdWasmApiFactory {
 #[derive(Clone)]
 pub struct StdWasmApiImpl {
     lookup_data: LookupData,
+    secret_store: Arc<SecretStore>,
+    subsystems: Subsystems,
+    /// The buckets this invocation contributed to, as every invocation may
+    /// contribute at most once to a bucket.
+    aggregated_buckets: BTreeSet<Vec<u8>>,
+    random: RandomStream,
     logger: Arc<dyn OakLogger>,
     /// Current request, as received from the client.
     request: Vec<u8>,
     /// Current response, as received from the Wasm module.
     response: Arc<Spinlock<Vec<u8>>>,
+    /// Whether a lookup of this invocation found no value. Shared with the
+    /// clones that serve the Wasm module.
+    lookup_missed: Arc<AtomicBool>,
+}
+
+impl StdWasmApiImpl {
+    fn new(
+        lookup_data: LookupData,
+        secret_store: Arc<SecretStore>,
+        subsystems: Subsystems,
+        random: RandomStream,
+        logger: Arc<dyn OakLogger>,
+        request: Vec<u8>,
+        response: Arc<Spinlock<Vec<u8>>>,
+    ) -> Self {
+        Self {
+            lookup_data,
+            secret_store,
+            subsystems,
+            aggregated_buckets: BTreeSet::new(),
+            random,
+            logger,
+            request,
+            response,
+            lookup_missed: Arc::default(),
+        }
+    }
+
+    fn observe_lookup(&self, found: bool) {
+        if !found {
+            self.lookup_missed.store(true, Ordering::Relaxed);
+        }
+    }
 }
 
 impl StdWasmApi for StdWasmApiImpl {
@@ -84,8 +200,16 @@ impl StdWasmApi for StdWasmApiImpl {
     }
 
     fn log(&mut self, request: LogRequest) -> Result<LogResponse, ::micro_rpc::Status> {
-        self.logger.log_sensitive(Level::Debug, "invoked log");
-        self.logger.log_sensitive(Level::Debug, &format!("[Wasm] {}", request.message));
+        let level = match request.level() {
+            LogLevel::Error => Level::Error,
+            LogLevel::Warn => Level::Warn,
+            LogLevel::Info => Level::Info,
+            LogLevel::Unspecified | LogLevel::Debug => Level::Debug,
+            LogLevel::Trace => Level::Trace,
+        };
+        // Log messages may contain sensitive data, so they are dropped unless
+        // sensitive logging is enabled.
+        self.logger.log_sensitive(level, &format!("[Wasm] {}", request.message));
         Ok(LogResponse::default())
     }
 
@@ -101,6 +225,7 @@ impl StdWasmApi for StdWasmApiImpl {
             &format!("lookup_data(): key: {}", format_bytes(key_to_log)),
         );
         let value = self.lookup_data.get(&key);
+        self.observe_lookup(value.is_some());
 
         // Log found value.
         value.as_ref().map_or_else(
@@ -137,18 +262,206 @@ impl StdWasmApi for StdWasmApiImpl {
                 None => BytesValue { found: false, value: Vec::new() },
             })
             .collect();
+        self.observe_lookup(values.iter().all(|value| value.found));
 
         Ok(LookupDataMultiResponse { values })
     }
 
+    fn lookup_multi(
+        &mut self,
+        request: LookupMultiRequest,
+    ) -> Result<LookupMultiResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(
+            Level::Debug,
+            &format!("lookup_multi(): key: {}", format_bytes(limit(&request.key, 512))),
+        );
+        let values: Vec<Vec<u8>> =
+            self.lookup_data.get_all(&request.key).into_iter().map(Into::into).collect();
+        self.observe_lookup(!values.is_empty());
+        self.logger
+            .log_sensitive(Level::Debug, &format!("lookup_multi(): {} values", values.len()));
+        Ok(LookupMultiResponse { values })
+    }
+
+    fn lookup_range(
+        &mut self,
+        request: LookupRangeRequest,
+    ) -> Result<LookupRangeResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(
+            Level::Debug,
+            &format!(
+                "lookup_range(): start: {}, end: {:?}, limit: {}",
+                format_bytes(limit(&request.start, 512)),
+                request.end.as_ref().map(|end| format_bytes(limit(end, 512))),
+                request.limit
+            ),
+        );
+        check_scan_limit(request.limit)?;
+        let entries = self
+            .lookup_data
+            .get_range(&request.start, request.end.as_deref(), request.limit as usize)
+            .map_err(failed_precondition)?;
+        self.logger
+            .log_sensitive(Level::Debug, &format!("lookup_range(): {} items", entries.len()));
+        Ok(LookupRangeResponse { items: to_key_values(entries) })
+    }
+
+    fn lookup_prefix(
+        &mut self,
+        request: LookupPrefixRequest,
+    ) -> Result<LookupPrefixResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(
+            Level::Debug,
+            &format!(
+                "lookup_prefix(): prefix: {}, limit: {}",
+                format_bytes(limit(&request.prefix, 512)),
+                request.limit
+            ),
+        );
+        check_scan_limit(request.limit)?;
+        let entries = self
+            .lookup_data
+            .get_prefix(&request.prefix, request.limit as usize)
+            .map_err(failed_precondition)?;
+        self.logger
+            .log_sensitive(Level::Debug, &format!("lookup_prefix(): {} items", entries.len()));
+        Ok(LookupPrefixResponse { items: to_key_values(entries) })
+    }
+
+    fn read_secret(
+        &mut self,
+        request: ReadSecretRequest,
+    ) -> Result<ReadSecretResponse, ::micro_rpc::Status> {
+        // Only the name is logged, never the value.
+        self.logger.log_sensitive(Level::Debug, &format!("read_secret(): name: {}", request.name));
+        Ok(ReadSecretResponse { value: self.secret_store.get(&request.name) })
+    }
+
+    fn read_time(
+        &mut self,
+        _request: ReadTimeRequest,
+    ) -> Result<ReadTimeResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(Level::Debug, "invoked read_time");
+        Ok(ReadTimeResponse { unix_time_seconds: self.subsystems.clock.now()? })
+    }
+
+    fn consume_budget(
+        &mut self,
+        request: ConsumeBudgetRequest,
+    ) -> Result<ConsumeBudgetResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(
+            Level::Debug,
+            &format!("consume_budget(): epsilon: {}", request.epsilon),
+        );
+        let remaining_epsilon = self.subsystems.privacy_budget.consume(request.epsilon)?;
+        Ok(ConsumeBudgetResponse { remaining_epsilon })
+    }
+
+    fn aggregate_write(
+        &mut self,
+        request: AggregateWriteRequest,
+    ) -> Result<AggregateWriteResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(
+            Level::Debug,
+            &format!("aggregate_write(): bucket: {}", format_bytes(&request.bucket)),
+        );
+        if self.aggregated_buckets.contains(&request.bucket) {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::AlreadyExists,
+                format!(
+                    "the invocation already contributed to bucket {}",
+                    format_bytes(&request.bucket)
+                ),
+            ));
+        }
+        self.subsystems.aggregation_store.write(request.bucket.clone(), request.value)?;
+        self.aggregated_buckets.insert(request.bucket);
+        Ok(AggregateWriteResponse {})
+    }
+
+    fn aggregate_read(
+        &mut self,
+        request: AggregateReadRequest,
+    ) -> Result<AggregateReadResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(
+            Level::Debug,
+            &format!("aggregate_read(): bucket: {}", format_bytes(&request.bucket)),
+        );
+        let values = self.subsystems.aggregation_store.read(&request.bucket)?;
+        Ok(AggregateReadResponse { values })
+    }
+
+    fn read_random(
+        &mut self,
+        request: ReadRandomRequest,
+    ) -> Result<ReadRandomResponse, ::micro_rpc::Status> {
+        self.logger.log_sensitive(Level::Debug, &format!("read_random(): len: {}", request.len));
+        let len = request.len as usize;
+        if len > MAX_READ_SIZE {
+            return Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::InvalidArgument,
+                format!("can't read more than {} random bytes at once, got {}", MAX_READ_SIZE, len),
+            ));
+        }
+        Ok(ReadRandomResponse { data: self.random.read(len) })
+    }
+
+    fn ml_infer(
+        &mut self,
+        request: MlInferRequest,
+    ) -> Result<MlInferResponse, ::micro_rpc::Status> {
+        self.logger
+            .log_sensitive(Level::Debug, &format!("ml_infer(): inputs: {}", request.inputs.len()));
+        let outputs = self.subsystems.inference_model.infer(request.inputs)?;
+        Ok(MlInferResponse { outputs })
+    }
+
+    fn invoke_module(
+        &mut self,
+        request: InvokeModuleRequest,
+    ) -> Result<InvokeModuleResponse, ::micro_rpc::Status> {
+        self.logger
+            .log_sensitive(Level::Debug, &format!("invoke_module(): name: {}", request.name));
+        let response = self.subsystems.helper_modules.invoke(&request.name, request.request)?;
+        Ok(InvokeModuleResponse { response })
+    }
+
     fn test(&mut self, req: TestRequest) -> Result<TestResponse, micro_rpc::Status> {
         self.logger.log_sensitive(Level::Debug, "invoked test");
         Ok(TestResponse { body: if req.echo { req.body } else { Vec::new() } })
     }
 }
 
+fn check_scan_limit(limit: u32) -> Result<(), micro_rpc::Status> {
+    if limit > MAX_SCAN_LIMIT {
+        return Err(micro_rpc::Status::new_with_message(
+            micro_rpc::StatusCode::InvalidArgument,
+            format!("limit {} exceeds the maximum of {}", limit, MAX_SCAN_LIMIT),
+        ));
+    }
+    Ok(())
+}
+
+fn failed_precondition(err: anyhow::Error) -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::FailedPrecondition,
+        format!("{:?}", err),
+    )
+}
+
+fn to_key_values(entries: Vec<(&[u8], &[u8])>) -> Vec<KeyValue> {
+    entries
+        .into_iter()
+        .map(|(key, value)| KeyValue { key: key.to_vec(), value: value.to_vec() })
+        .collect()
+}
+
 impl WasmApi for StdWasmApiImpl {
     fn transport(&mut self) -> Box<dyn micro_rpc::Transport<Error = !>> {
         Box::new(StdWasmApiServer::new(self.clone()))
     }
+
+    fn lookup_missed(&self) -> bool {
+        self.lookup_missed.load(Ordering::Relaxed)
+    }
 }
@@ -0,0 +1,290 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A request handler that runs Wasm modules with the engine selected in the
+//! `InitializeRequest`.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use oak_functions_abi::{Request, Response};
+
+use crate::{
+    lookup::LookupDataManager,
+    proto::oak::functions::WasmEngine,
+    secrets::SecretStore,
+    wasm::{wasmtime::WasmtimeHandler, WasmHandler},
+    Handler, HandlerConfig, Observer, Subsystems,
+};
+
+/// Runs Wasm modules with either wasmi or Wasmtime. Wasmtime is the default,
+/// as its ahead-of-time compilation makes CPU-bound modules much faster, at the
+/// cost of a slower initialization.
+pub enum WasmEngineHandler {
+    Wasmi(WasmHandler),
+    Wasmtime(WasmtimeHandler),
+}
+
+impl Handler for WasmEngineHandler {
+    type HandlerType = WasmEngineHandler;
+
+    fn supports_wasm_engine(_wasm_engine: WasmEngine) -> bool {
+        true
+    }
+
+    fn new_handler(
+        wasm_module_bytes: &[u8],
+        config: &HandlerConfig,
+        lookup_data_manager: Arc<LookupDataManager>,
+        secret_store: Arc<SecretStore>,
+        subsystems: Subsystems,
+        observer: Option<Arc<dyn Observer + Send + Sync>>,
+    ) -> anyhow::Result<WasmEngineHandler> {
+        match config.wasm_engine {
+            WasmEngine::Wasmi => WasmHandler::new_handler(
+                wasm_module_bytes,
+                config,
+                lookup_data_manager,
+                secret_store,
+                subsystems,
+                observer,
+            )
+            .map(WasmEngineHandler::Wasmi),
+            WasmEngine::Unspecified | WasmEngine::Wasmtime => WasmtimeHandler::new_handler(
+                wasm_module_bytes,
+                config,
+                lookup_data_manager,
+                secret_store,
+                subsystems,
+                observer,
+            )
+            .map(WasmEngineHandler::Wasmtime),
+        }
+    }
+
+    fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status> {
+        match self {
+            WasmEngineHandler::Wasmi(handler) => handler.handle_invoke(invoke_request),
+            WasmEngineHandler::Wasmtime(handler) => handler.handle_invoke(invoke_request),
+        }
+    }
+
+    fn handle_invoke_batch(
+        &self,
+        invoke_requests: Vec<Request>,
+    ) -> Vec<Result<Response, micro_rpc::Status>> {
+        match self {
+            WasmEngineHandler::Wasmi(handler) => handler.handle_invoke_batch(invoke_requests),
+            WasmEngineHandler::Wasmtime(handler) => handler.handle_invoke_batch(invoke_requests),
+        }
+    }
+
+    fn precompiled_module(&self) -> Option<Vec<u8>> {
+        match self {
+            WasmEngineHandler::Wasmi(handler) => handler.precompiled_module(),
+            WasmEngineHandler::Wasmtime(handler) => handler.precompiled_module(),
+        }
+    }
+}
+
+// Runs the example modules on both engines, which must behave the same.
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{logger::StandaloneLogger, wasm::memory::MemoryLimits};
+
+    const ENGINES: [WasmEngine; 2] = [WasmEngine::Wasmi, WasmEngine::Wasmtime];
+
+    fn new_handler(
+        example: &str,
+        wasm_engine: WasmEngine,
+        data: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<WasmEngineHandler> {
+        new_handler_with_config(example, HandlerConfig { wasm_engine, ..Default::default() }, data)
+    }
+
+    fn new_handler_with_config(
+        example: &str,
+        config: HandlerConfig,
+        data: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<WasmEngineHandler> {
+        let wasm_module_path = oak_functions_test_utils::build_rust_crate_wasm(example).unwrap();
+        let wasm_module_bytes = std::fs::read(wasm_module_path).unwrap();
+        let lookup_data_manager =
+            Arc::new(LookupDataManager::for_test(data, Arc::new(StandaloneLogger)));
+        WasmEngineHandler::new_handler(
+            &wasm_module_bytes,
+            &config,
+            lookup_data_manager,
+            Arc::default(),
+            Subsystems::default(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_selects_engine() {
+        assert!(matches!(
+            new_handler("echo", WasmEngine::Wasmi, vec![]).unwrap(),
+            WasmEngineHandler::Wasmi(_)
+        ));
+        assert!(matches!(
+            new_handler("echo", WasmEngine::Wasmtime, vec![]).unwrap(),
+            WasmEngineHandler::Wasmtime(_)
+        ));
+        assert!(matches!(
+            new_handler("echo", WasmEngine::Unspecified, vec![]).unwrap(),
+            WasmEngineHandler::Wasmtime(_)
+        ));
+    }
+
+    #[test]
+    fn test_echo() {
+        for wasm_engine in ENGINES {
+            let handler = new_handler("echo", wasm_engine, vec![]).unwrap();
+            let response =
+                handler.handle_invoke(Request { body: b"Hello, world!".to_vec() }).unwrap();
+            assert_eq!(response.body, b"Hello, world!", "{:?}", wasm_engine);
+        }
+    }
+
+    #[test]
+    fn test_key_value_lookup() {
+        let data = vec![(b"key".to_vec(), b"value".to_vec())];
+        for wasm_engine in ENGINES {
+            let handler = new_handler("key_value_lookup", wasm_engine, data.clone()).unwrap();
+            let responses = handler.handle_invoke_batch(vec![
+                Request { body: b"key".to_vec() },
+                Request { body: b"missing".to_vec() },
+            ]);
+            let bodies: Vec<Vec<u8>> =
+                responses.into_iter().map(|response| response.unwrap().body).collect();
+            assert_eq!(bodies, [b"value".to_vec(), vec![]], "{:?}", wasm_engine);
+        }
+    }
+
+    #[test]
+    fn test_invalid_module() {
+        // The module compiles, but it doesn't export `main`, so requests fail.
+        for wasm_engine in ENGINES {
+            let handler = new_handler("invalid_module", wasm_engine, vec![]).unwrap();
+            let err = handler.handle_invoke(Request { body: vec![] }).unwrap_err();
+            assert_eq!(err.code, micro_rpc::StatusCode::Internal, "{:?}", wasm_engine);
+        }
+    }
+
+    #[test]
+    fn test_fuel_limit() {
+        for wasm_engine in ENGINES {
+            let config = HandlerConfig {
+                wasm_engine,
+                wasm_fuel_limit: Some(1_000_000),
+                ..Default::default()
+            };
+            let handler = new_handler_with_config("echo", config, vec![]).unwrap();
+            let response = handler.handle_invoke(Request { body: b"Hello".to_vec() }).unwrap();
+            assert_eq!(response.body, b"Hello", "{:?}", wasm_engine);
+
+            // Too little fuel to even read the request.
+            let config =
+                HandlerConfig { wasm_engine, wasm_fuel_limit: Some(10), ..Default::default() };
+            let handler = new_handler_with_config("echo", config, vec![]).unwrap();
+            let err = handler.handle_invoke(Request { body: b"Hello".to_vec() }).unwrap_err();
+            assert_eq!(err.code, micro_rpc::StatusCode::ResourceExhausted, "{:?}", wasm_engine);
+            assert!(err.message.starts_with("ExecutionLimitExceeded"), "{:?}", wasm_engine);
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_invocations() {
+        for wasm_engine in ENGINES {
+            let config = HandlerConfig {
+                wasm_engine,
+                max_concurrent_invocations: Some(1),
+                ..Default::default()
+            };
+            let handler = new_handler_with_config("echo", config, vec![]).unwrap();
+            // Invocations that follow each other reuse the instance.
+            for _ in 0..3 {
+                let response = handler.handle_invoke(Request { body: b"Hello".to_vec() }).unwrap();
+                assert_eq!(response.body, b"Hello", "{:?}", wasm_engine);
+            }
+        }
+    }
+
+    #[test]
+    fn test_precompiled_module() {
+        let handler = new_handler("echo", WasmEngine::Wasmi, vec![]).unwrap();
+        assert!(handler.precompiled_module().is_none());
+
+        let handler = new_handler("echo", WasmEngine::Wasmtime, vec![]).unwrap();
+        let precompiled_module = handler.precompiled_module().unwrap();
+        let config = HandlerConfig {
+            wasm_engine: WasmEngine::Wasmtime,
+            precompiled_module: Some(precompiled_module.into()),
+            ..Default::default()
+        };
+        let handler = new_handler_with_config("echo", config, vec![]).unwrap();
+        let response = handler.handle_invoke(Request { body: b"Hello".to_vec() }).unwrap();
+        assert_eq!(response.body, b"Hello");
+
+        // The module is compiled if the precompiled module can't be loaded.
+        let config = HandlerConfig {
+            wasm_engine: WasmEngine::Wasmtime,
+            precompiled_module: Some(b"not native code".as_slice().into()),
+            ..Default::default()
+        };
+        let handler = new_handler_with_config("echo", config, vec![]).unwrap();
+        let response = handler.handle_invoke(Request { body: b"Hello".to_vec() }).unwrap();
+        assert_eq!(response.body, b"Hello");
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        const MEMORY_LIMIT: u64 = 16 << 20;
+        for wasm_engine in ENGINES {
+            let config = HandlerConfig {
+                wasm_engine,
+                wasm_memory_limits: MemoryLimits {
+                    max_bytes: Some(MEMORY_LIMIT),
+                    growth_disabled: false,
+                },
+                ..Default::default()
+            };
+            let handler = new_handler_with_config("echo", config, vec![]).unwrap();
+            let response = handler.handle_invoke(Request { body: b"Hello".to_vec() }).unwrap();
+            assert_eq!(response.body, b"Hello", "{:?}", wasm_engine);
+
+            // The module can't allocate the request.
+            let body = vec![0; 2 * MEMORY_LIMIT as usize];
+            let err = handler.handle_invoke(Request { body }).unwrap_err();
+            assert_eq!(err.code, micro_rpc::StatusCode::ResourceExhausted, "{:?}", wasm_engine);
+            assert!(err.message.starts_with("MemoryLimitExceeded"), "{:?}", wasm_engine);
+
+            // The initial memory of the module doesn't fit.
+            let config = HandlerConfig {
+                wasm_engine,
+                wasm_memory_limits: MemoryLimits {
+                    max_bytes: Some(1 << 16),
+                    growth_disabled: false,
+                },
+                ..Default::default()
+            };
+            assert!(new_handler_with_config("echo", config, vec![]).is_err(), "{:?}", wasm_engine);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Limits on the linear memory of Wasm modules, independent of the Wasm
+//! engine.
+//!
+//! The initial memory of a module is checked when the handler is created, so
+//! that modules that could never run are rejected upfront. Growing the memory
+//! beyond the limits fails like any other failed `memory.grow`, and the
+//! [`MemoryLimiter`] of the invocation records it, so that the handler can
+//! report the invocation as having exceeded its memory limit if the module
+//! then traps, as Rust modules do when they run out of memory.
+
+use micro_rpc::StatusCode;
+
+/// The size of a page of linear memory.
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+/// How large the linear memory of a Wasm module may become.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryLimits {
+    /// The maximum size of the linear memory in bytes, if limited beyond the
+    /// maximum the module declares.
+    pub max_bytes: Option<u64>,
+    /// Whether the linear memory must keep its initial size.
+    pub growth_disabled: bool,
+}
+
+impl MemoryLimits {
+    /// Checks that a module whose linear memory starts with `initial_bytes`
+    /// can be instantiated within the limits.
+    pub fn check_initial_size(&self, initial_bytes: u64) -> anyhow::Result<()> {
+        match self.max_bytes {
+            Some(max_bytes) if initial_bytes > max_bytes => Err(anyhow::anyhow!(
+                "the initial memory of the Wasm module ({} bytes) exceeds the memory limit ({} \
+                 bytes)",
+                initial_bytes,
+                max_bytes
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Enforces [`MemoryLimits`] for a single invocation of a Wasm module.
+pub struct MemoryLimiter {
+    limits: MemoryLimits,
+    // Whether the memory of the instance was created. Engines create memories
+    // by growing them from zero bytes, which is always allowed within the
+    // maximum size.
+    memory_created: bool,
+    exceeded: bool,
+}
+
+impl MemoryLimiter {
+    pub fn new(limits: MemoryLimits) -> Self {
+        MemoryLimiter { limits, memory_created: false, exceeded: false }
+    }
+
+    /// Returns whether the module tried to grow its memory beyond the limits.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    // Returns whether the memory may grow to `desired` bytes.
+    fn memory_growing(&mut self, desired: usize) -> bool {
+        let growth_allowed = !self.limits.growth_disabled || !self.memory_created;
+        let within_max = self.limits.max_bytes.is_none_or(|max_bytes| desired as u64 <= max_bytes);
+        self.memory_created = true;
+        if growth_allowed && within_max {
+            true
+        } else {
+            self.exceeded = true;
+            false
+        }
+    }
+}
+
+impl wasmi::ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool, wasmi::errors::MemoryError> {
+        Ok(self.memory_growing(desired))
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        _desired: u32,
+        _maximum: Option<u32>,
+    ) -> Result<bool, wasmi::errors::TableError> {
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+impl wasmtime::ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        Ok(self.memory_growing(desired))
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        _desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Returns the error of an invocation that failed as it tried to grow its
+/// memory beyond the limits.
+pub(crate) fn memory_limit_exceeded() -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        StatusCode::ResourceExhausted,
+        "MemoryLimitExceeded: the Wasm module tried to grow its memory beyond the limit",
+    )
+}
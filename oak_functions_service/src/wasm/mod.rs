@@ -19,8 +19,12 @@
 extern crate alloc;
 
 pub mod api;
+#[cfg(feature = "wasmtime")]
+pub mod engine;
+pub mod memory;
 #[cfg(test)]
 mod tests;
+pub mod wasi;
 
 #[cfg(feature = "wasmtime")]
 pub mod wasmtime;
@@ -32,15 +36,19 @@ use std::time::Instant;
 use api::StdWasmApiFactory;
 use byteorder::{ByteOrder, LittleEndian};
 use log::Level;
+use memory::{memory_limit_exceeded, MemoryLimiter, MemoryLimits};
 use micro_rpc::StatusCode;
 use oak_functions_abi::{Request, Response};
 use spinning_top::Spinlock;
+use wasi::WasiState;
 use wasmi::Store;
 
 use crate::{
     logger::{OakLogger, StandaloneLogger},
     lookup::LookupDataManager,
-    Handler, Observer,
+    proto::oak::functions::{InvocationErrorClass, WasmEngine},
+    secrets::SecretStore,
+    Handler, HandlerConfig, Observer, Subsystems,
 };
 
 /// Fixed name of the function to start a Wasm. Every Oak Wasm module must
@@ -68,28 +76,8 @@ pub type AbiPointerOffset = u32;
 pub struct UserState {
     wasm_api_transport: Box<dyn micro_rpc::Transport<Error = !>>,
     logger: Arc<dyn OakLogger>,
-}
-
-/// Stubs a Wasm imported function in the provided linker.
-///
-/// The stubbed function logs an error and returns an error in the form of a
-/// Wasm trap (similar to an exception).
-macro_rules! stub_wasm_function {
-    ($linker:ident, $function_mod:ident . $function_name:ident, ($($t:ty),*) -> $r:ty) => {
-        $linker.func_wrap(
-            stringify!($function_mod),
-            stringify!($function_name),
-            |caller: wasmi::Caller<'_, UserState>, $(_: $t),*| {
-                caller
-                    .data()
-                    .log_error(concat!("called stubbed ", stringify!($function_mod), ".", stringify!($function_name)));
-                Err::<$r, wasmi::core::Trap>(
-                    wasmi::core::Trap::new(
-                        concat!("called stubbed ", stringify!($function_mod), ".", stringify!($function_name))))
-            },
-        )
-        .expect(concat!("failed to define ", stringify!($function_mod), ".", stringify!($function_name), " in linker"));
-    };
+    wasi: WasiState,
+    memory_limiter: MemoryLimiter,
 }
 
 impl UserState {
@@ -100,8 +88,14 @@ impl UserState {
     fn new(
         wasm_api_transport: Box<dyn micro_rpc::Transport<Error = !>>,
         logger: Arc<dyn OakLogger>,
+        memory_limits: MemoryLimits,
     ) -> Self {
-        UserState { wasm_api_transport, logger }
+        UserState {
+            wasm_api_transport,
+            wasi: WasiState::new(logger.clone()),
+            logger,
+            memory_limiter: MemoryLimiter::new(memory_limits),
+        }
     }
 
     // Use an `OakLogger` to log.
@@ -160,50 +154,24 @@ impl OakLinker {
             )
             .expect("failed to define invoke in linker");
 
-        // TODO(#3929): One of our dependency requires various WASI functions to be
-        // linked, but, to the best of our knowledge, does not use them at run
-        // time. As a workaround, we stub them for now but we should remove
-        // them, if possible.
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.clock_time_get,
-            (i32, i64, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.proc_exit,
-            (i32) -> ()
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.environ_sizes_get,
-            (i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.environ_get,
-            (i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_close,
-            (i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_write,
-            (i32, i32, i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_read,
-            (i32, i32, i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_seek,
-            (i32, i64, i32, i32) -> i32
-        );
+        // Libraries compiled for WASI run unmodified against a sandboxed subset of
+        // WASI preview 1.
+        wasi::link_wasi!(linker, wasmi);
+        linker
+            .func_wrap(
+                wasi::MODULE,
+                "proc_exit",
+                |mut caller: wasmi::Caller<'_, UserState>,
+                 exit_code: i32|
+                 -> Result<(), wasmi::core::Trap> {
+                    caller.data_mut().wasi.exit_code = Some(exit_code);
+                    Err(wasmi::core::Trap::new(format!(
+                        "Wasm module exited with code {}",
+                        exit_code
+                    )))
+                },
+            )
+            .expect("failed to define proc_exit in linker");
 
         OakLinker { linker }
     }
@@ -392,6 +360,9 @@ pub struct WasmHandler {
     logger: Arc<dyn OakLogger>,
     #[cfg_attr(not(feature = "std"), allow(dead_code))]
     observer: Option<Arc<dyn Observer + Send + Sync>>,
+    // The fuel every invocation starts with, if limited.
+    fuel_limit: Option<u64>,
+    memory_limits: MemoryLimits,
 }
 
 /// A trait for creating Wasm APIs that can be called from Wasm modules.
@@ -405,6 +376,10 @@ pub trait WasmApiFactory {
         request: Vec<u8>,
         response: Arc<Spinlock<Vec<u8>>>,
     ) -> Box<dyn WasmApi>;
+
+    /// Returns a factory whose Wasm APIs all see the lookup data as it is now,
+    /// even if it is replaced in the meantime.
+    fn snapshot(&self) -> Arc<dyn WasmApiFactory + Send + Sync>;
 }
 
 /// A trait for Wasm APIs that can be called from Wasm modules.
@@ -412,6 +387,11 @@ pub trait WasmApiFactory {
 /// The API is implemented as exposing an infallible [`micro_rpc::Transport`].
 pub trait WasmApi {
     fn transport(&mut self) -> Box<dyn micro_rpc::Transport<Error = !>>;
+
+    /// Returns whether a lookup of the invocation found no value.
+    fn lookup_missed(&self) -> bool {
+        false
+    }
 }
 
 impl WasmHandler {
@@ -420,10 +400,23 @@ impl WasmHandler {
         wasm_api_factory: Arc<dyn WasmApiFactory + Send + Sync>,
         logger: Arc<dyn OakLogger>,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
+        handler_config: &HandlerConfig,
     ) -> anyhow::Result<Self> {
-        let engine = wasmi::Engine::default();
+        let fuel_limit = handler_config.wasm_fuel_limit;
+        let memory_limits = handler_config.wasm_memory_limits;
+        let mut config = wasmi::Config::default();
+        // Metering fuel slows down execution, so it is only enabled if needed.
+        config.consume_fuel(fuel_limit.is_some());
+        let engine = wasmi::Engine::new(&config);
         let module = wasmi::Module::new(&engine, wasm_module_bytes)
             .map_err(|err| anyhow::anyhow!("couldn't load module from buffer: {:?}", err))?;
+        if let Some(memory_type) =
+            module.get_export(MEMORY_NAME).as_ref().and_then(wasmi::ExternType::memory)
+        {
+            let initial_bytes =
+                memory_type.initial_pages().to_bytes().map_or(u64::MAX, |bytes| bytes as u64);
+            memory_limits.check_initial_size(initial_bytes)?;
+        }
 
         let linker = OakLinker::new(module.engine());
 
@@ -433,35 +426,37 @@ impl WasmHandler {
             wasm_api_factory,
             logger,
             observer,
+            fuel_limit,
+            memory_limits,
         })
     }
-}
-
-impl Handler for WasmHandler {
-    type HandlerType = WasmHandler;
-
-    fn new_handler(
-        wasm_module_bytes: &[u8],
-        lookup_data_manager: Arc<LookupDataManager>,
-        observer: Option<Arc<dyn Observer + Send + Sync>>,
-    ) -> anyhow::Result<WasmHandler> {
-        let logger = Arc::new(StandaloneLogger);
-        let wasm_api_factory = Arc::new(StdWasmApiFactory { lookup_data_manager });
-
-        Self::create(wasm_module_bytes, wasm_api_factory, logger, observer)
-    }
 
-    fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status> {
+    // Handles a request with Wasm APIs created by `wasm_api_factory`.
+    fn invoke(
+        &self,
+        wasm_api_factory: &dyn WasmApiFactory,
+        invoke_request: Request,
+    ) -> Result<Response, micro_rpc::Status> {
         #[cfg(feature = "std")]
         let now = Instant::now();
         let module = self.wasm_module.clone();
 
         let request = invoke_request.body;
         let response = Arc::new(Spinlock::new(Vec::new()));
-        let mut wasm_api = self.wasm_api_factory.create_wasm_api(request, response.clone());
-        let user_state = UserState::new(wasm_api.transport(), self.logger.clone());
+        let mut wasm_api = wasm_api_factory.create_wasm_api(request, response.clone());
+        let user_state =
+            UserState::new(wasm_api.transport(), self.logger.clone(), self.memory_limits);
         // For isolated requests we need to create a new store for every request.
         let mut store = wasmi::Store::new(module.engine(), user_state);
+        store.limiter(|user_state| &mut user_state.memory_limiter);
+        if let Some(fuel_limit) = self.fuel_limit {
+            store.add_fuel(fuel_limit).map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    StatusCode::Internal,
+                    format!("couldn't add fuel: {:?}", err),
+                )
+            })?;
+        }
         let instance = self.linker.instantiate(&mut store, module)?;
 
         instance.exports(&store).for_each(|export| {
@@ -491,11 +486,27 @@ impl Handler for WasmHandler {
         if let Some(ref observer) = self.observer {
             observer.wasm_invocation(now.elapsed());
         }
+        if result
+            .as_ref()
+            .is_err_and(|trap| matches!(trap.trap_code(), Some(wasmi::core::TrapCode::OutOfFuel)))
+        {
+            return Err(execution_limit_exceeded());
+        }
+        if result.is_err() && store.data().memory_limiter.exceeded() {
+            return Err(memory_limit_exceeded());
+        }
 
         store.data().logger.log_sensitive(
             Level::Info,
             &format!("running Wasm module completed with result: {:?}", result),
         );
+        // Exiting through `proc_exit` traps as well, which is only a failure for
+        // non-zero exit codes.
+        if let Err(trap) = &result {
+            if store.data().wasi.exit_code != Some(0) {
+                return Err(wasm_trapped(trap, wasm_api.lookup_missed()));
+            }
+        }
 
         let response_bytes = response.lock().clone();
         store
@@ -509,6 +520,89 @@ impl Handler for WasmHandler {
     }
 }
 
+impl Handler for WasmHandler {
+    type HandlerType = WasmHandler;
+
+    fn supports_wasm_engine(wasm_engine: WasmEngine) -> bool {
+        matches!(wasm_engine, WasmEngine::Unspecified | WasmEngine::Wasmi)
+    }
+
+    fn new_handler(
+        wasm_module_bytes: &[u8],
+        config: &HandlerConfig,
+        lookup_data_manager: Arc<LookupDataManager>,
+        secret_store: Arc<SecretStore>,
+        subsystems: Subsystems,
+        observer: Option<Arc<dyn Observer + Send + Sync>>,
+    ) -> anyhow::Result<WasmHandler> {
+        let logger = Arc::new(StandaloneLogger);
+        let wasm_api_factory = Arc::new(StdWasmApiFactory {
+            lookup_data_manager,
+            lookup_namespace: config.lookup_namespace.clone(),
+            secret_store,
+            subsystems,
+            randomness: Arc::default(),
+            logger: logger.clone(),
+        });
+
+        Self::create(wasm_module_bytes, wasm_api_factory, logger, observer, config)
+    }
+
+    fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status> {
+        self.invoke(self.wasm_api_factory.as_ref(), invoke_request)
+    }
+
+    fn handle_invoke_batch(
+        &self,
+        invoke_requests: Vec<Request>,
+    ) -> Vec<Result<Response, micro_rpc::Status>> {
+        let wasm_api_factory = self.wasm_api_factory.snapshot();
+        invoke_requests
+            .into_iter()
+            .map(|invoke_request| self.invoke(wasm_api_factory.as_ref(), invoke_request))
+            .collect()
+    }
+}
+
+/// Returns the error of an invocation that ran out of fuel.
+pub(crate) fn execution_limit_exceeded() -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        StatusCode::ResourceExhausted,
+        "ExecutionLimitExceeded: the Wasm module ran out of fuel",
+    )
+}
+
+const WASM_TRAP_PREFIX: &str = "WasmTrap: ";
+const LOOKUP_MISS_PREFIX: &str = "LookupMiss: ";
+
+/// Returns the error of an invocation in which the Wasm module trapped, e.g.
+/// because it panicked. If a lookup of the invocation found no value, the
+/// trap is attributed to it, as modules commonly panic on missing values.
+pub(crate) fn wasm_trapped(
+    trap: &dyn core::fmt::Display,
+    lookup_missed: bool,
+) -> micro_rpc::Status {
+    let prefix = if lookup_missed { LOOKUP_MISS_PREFIX } else { WASM_TRAP_PREFIX };
+    micro_rpc::Status::new_with_message(
+        StatusCode::Internal,
+        format!("{prefix}the Wasm module trapped: {trap}"),
+    )
+}
+
+/// Returns the class of the error an invocation failed with.
+///
+/// The class is revealed to the host next to the encrypted response, so that
+/// failures of the Wasm module can be told apart without the error itself.
+pub fn invocation_error_class(status: &micro_rpc::Status) -> InvocationErrorClass {
+    if status.message.starts_with(WASM_TRAP_PREFIX) {
+        InvocationErrorClass::WasmTrap
+    } else if status.message.starts_with(LOOKUP_MISS_PREFIX) {
+        InvocationErrorClass::LookupMiss
+    } else {
+        InvocationErrorClass::Unspecified
+    }
+}
+
 /// A helper function to move between our specific result type `Result<(),
 /// StatusCode>` and the `wasmi` specific result type `Result<i32,
 /// wasmi::Trap>`.
@@ -23,14 +23,14 @@ use oak_functions_abi::Request;
 use spinning_top::Spinlock;
 
 use super::{
-    api::StdWasmApiFactory, OakLinker, UserState, WasmApiFactory, WasmHandler, ALLOC_FUNCTION_NAME,
-    MEMORY_NAME,
+    api::StdWasmApiFactory, memory::MemoryLimits, OakLinker, UserState, WasmApiFactory,
+    WasmHandler, ALLOC_FUNCTION_NAME, MEMORY_NAME,
 };
 use crate::{
     logger::StandaloneLogger,
     lookup::LookupDataManager,
     wasm::{AbiPointer, AbiPointerOffset},
-    Handler,
+    Handler, HandlerConfig, Subsystems,
 };
 
 #[test]
@@ -146,21 +146,32 @@ struct TestState {
 fn create_test_state() -> TestState {
     let logger = Arc::new(StandaloneLogger);
     let lookup_data_manager = Arc::new(LookupDataManager::for_test(Vec::default(), logger.clone()));
-    let api_factory =
-        Arc::new(StdWasmApiFactory { lookup_data_manager: lookup_data_manager.clone() });
+    let api_factory = Arc::new(StdWasmApiFactory {
+        lookup_data_manager: lookup_data_manager.clone(),
+        lookup_namespace: Vec::new(),
+        secret_store: Arc::default(),
+        subsystems: Subsystems::default(),
+        randomness: Arc::default(),
+        logger: logger.clone(),
+    });
 
     let wasm_module_path = oak_functions_test_utils::build_rust_crate_wasm("echo").unwrap();
     let wasm_module_bytes = std::fs::read(wasm_module_path).unwrap();
 
-    let wasm_handler =
-        WasmHandler::create(&wasm_module_bytes, api_factory.clone(), logger.clone(), None)
-            .expect("couldn't create WasmHandler");
+    let wasm_handler = WasmHandler::create(
+        &wasm_module_bytes,
+        api_factory.clone(),
+        logger.clone(),
+        None,
+        &HandlerConfig::default(),
+    )
+    .expect("couldn't create WasmHandler");
 
     let request = Vec::new();
     let response = Arc::new(Spinlock::new(Vec::new()));
     let mut wasm_api = api_factory.create_wasm_api(request.clone(), response.clone());
 
-    let user_state = UserState::new(wasm_api.transport(), logger.clone());
+    let user_state = UserState::new(wasm_api.transport(), logger.clone(), MemoryLimits::default());
 
     let module = wasm_handler.wasm_module.clone();
     let mut store = wasmi::Store::new(module.engine(), user_state);
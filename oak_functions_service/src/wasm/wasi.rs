@@ -0,0 +1,475 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A sandboxed subset of [WASI preview 1](https://github.com/WebAssembly/WASI/blob/main/legacy/preview1/docs.md),
+//! so that libraries that expect WASI imports run unmodified in Oak Functions.
+//!
+//! The functions here are independent of the Wasm engine: they operate on the
+//! linear memory of the Wasm module and return a WASI errno. The engines link
+//! them into the `wasi_snapshot_preview1` module.
+//!
+//! - Clocks only advance within an invocation, and only in steps of
+//!   [`CLOCK_QUANTUM_NANOS`], so that they can't be used for fine-grained
+//!   timing. The realtime clock is quantized wall-clock time; all other clocks
+//!   measure the time since the invocation started. Without a time source (e.g.
+//!   on the Restricted Kernel), every read advances a clock by one quantum.
+//! - `random_get` returns randomness from the enclave.
+//! - Writes to stdout and stderr are logged as sensitive debug messages, and
+//!   stdin is empty.
+//! - There are no arguments, environment variables or preopened directories,
+//!   and all other functions fail with `ENOSYS`.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use log::Level;
+use rand_core::{OsRng, RngCore};
+
+use crate::logger::OakLogger;
+
+/// The name of the import module of WASI preview 1.
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+pub const ERRNO_SUCCESS: i32 = 0;
+pub const ERRNO_BADF: i32 = 8;
+pub const ERRNO_FAULT: i32 = 21;
+pub const ERRNO_INVAL: i32 = 28;
+pub const ERRNO_IO: i32 = 29;
+pub const ERRNO_NOSYS: i32 = 52;
+pub const ERRNO_SPIPE: i32 = 70;
+
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_PROCESS_CPUTIME_ID: i32 = 2;
+const CLOCK_THREAD_CPUTIME_ID: i32 = 3;
+
+/// The resolution of all clocks, in nanoseconds.
+pub const CLOCK_QUANTUM_NANOS: u64 = 1_000_000;
+
+const STDIN: i32 = 0;
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const RIGHTS_FD_READ: u64 = 1 << 1;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+
+/// The WASI state of a single invocation of a Wasm module.
+pub struct WasiState {
+    logger: Arc<dyn OakLogger>,
+    #[cfg(feature = "std")]
+    start: std::time::Instant,
+    last_realtime: u64,
+    last_monotonic: u64,
+    /// The code the module exited with through `proc_exit`, if it did.
+    pub(crate) exit_code: Option<i32>,
+}
+
+impl WasiState {
+    pub fn new(logger: Arc<dyn OakLogger>) -> Self {
+        Self {
+            logger,
+            #[cfg(feature = "std")]
+            start: std::time::Instant::now(),
+            last_realtime: 0,
+            last_monotonic: 0,
+            exit_code: None,
+        }
+    }
+
+    // Reads a clock, quantized, and never going backwards.
+    fn time(&mut self, clock_id: i32) -> Option<u64> {
+        let (now, last) = match clock_id {
+            CLOCK_REALTIME => (realtime(), &mut self.last_realtime),
+            CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+                #[cfg(feature = "std")]
+                let now = Some(self.start.elapsed().as_nanos() as u64);
+                #[cfg(not(feature = "std"))]
+                let now = None;
+                (now, &mut self.last_monotonic)
+            }
+            _ => return None,
+        };
+        let now = now.unwrap_or(*last + CLOCK_QUANTUM_NANOS);
+        *last = (*last).max(now - now % CLOCK_QUANTUM_NANOS);
+        Some(*last)
+    }
+}
+
+#[cfg(feature = "std")]
+fn realtime() -> Option<u64> {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .map(|time| time.as_nanos() as u64)
+}
+
+#[cfg(not(feature = "std"))]
+fn realtime() -> Option<u64> {
+    None
+}
+
+pub fn args_get(_: &mut WasiState, _memory: &mut [u8], _argv: i32, _argv_buf: i32) -> i32 {
+    ERRNO_SUCCESS
+}
+
+pub fn args_sizes_get(
+    _: &mut WasiState,
+    memory: &mut [u8],
+    argc_ptr: i32,
+    argv_buf_size_ptr: i32,
+) -> i32 {
+    errno(write_u32(memory, argc_ptr, 0).and_then(|()| write_u32(memory, argv_buf_size_ptr, 0)))
+}
+
+pub fn environ_get(_: &mut WasiState, _memory: &mut [u8], _environ: i32, _environ_buf: i32) -> i32 {
+    ERRNO_SUCCESS
+}
+
+pub fn environ_sizes_get(
+    _: &mut WasiState,
+    memory: &mut [u8],
+    environc_ptr: i32,
+    environ_buf_size_ptr: i32,
+) -> i32 {
+    errno(
+        write_u32(memory, environc_ptr, 0)
+            .and_then(|()| write_u32(memory, environ_buf_size_ptr, 0)),
+    )
+}
+
+pub fn clock_res_get(
+    _: &mut WasiState,
+    memory: &mut [u8],
+    clock_id: i32,
+    resolution_ptr: i32,
+) -> i32 {
+    match clock_id {
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+            errno(write(memory, resolution_ptr, &CLOCK_QUANTUM_NANOS.to_le_bytes()))
+        }
+        _ => ERRNO_INVAL,
+    }
+}
+
+pub fn clock_time_get(
+    state: &mut WasiState,
+    memory: &mut [u8],
+    clock_id: i32,
+    _precision: i64,
+    time_ptr: i32,
+) -> i32 {
+    match state.time(clock_id) {
+        Some(time) => errno(write(memory, time_ptr, &time.to_le_bytes())),
+        None => ERRNO_INVAL,
+    }
+}
+
+pub fn random_get(_: &mut WasiState, memory: &mut [u8], buf: i32, buf_len: i32) -> i32 {
+    errno(
+        range(memory, buf, buf_len as u32)
+            .and_then(|range| OsRng.try_fill_bytes(&mut memory[range]).map_err(|_| ERRNO_IO)),
+    )
+}
+
+pub fn fd_write(
+    state: &mut WasiState,
+    memory: &mut [u8],
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    nwritten_ptr: i32,
+) -> i32 {
+    let stream = match fd {
+        STDOUT => "stdout",
+        STDERR => "stderr",
+        _ => return ERRNO_BADF,
+    };
+    let result = gather(memory, iovs, iovs_len).and_then(|bytes| {
+        state.logger.log_sensitive(
+            Level::Debug,
+            &format!("[Wasm {}] {}", stream, String::from_utf8_lossy(&bytes).trim_end()),
+        );
+        write_u32(memory, nwritten_ptr, bytes.len() as u32)
+    });
+    errno(result)
+}
+
+pub fn fd_read(
+    _: &mut WasiState,
+    memory: &mut [u8],
+    fd: i32,
+    _iovs: i32,
+    _iovs_len: i32,
+    nread_ptr: i32,
+) -> i32 {
+    match fd {
+        // Stdin is always at its end.
+        STDIN => errno(write_u32(memory, nread_ptr, 0)),
+        _ => ERRNO_BADF,
+    }
+}
+
+pub fn fd_close(_: &mut WasiState, _memory: &mut [u8], fd: i32) -> i32 {
+    match fd {
+        STDIN | STDOUT | STDERR => ERRNO_SUCCESS,
+        _ => ERRNO_BADF,
+    }
+}
+
+pub fn fd_seek(
+    _: &mut WasiState,
+    _memory: &mut [u8],
+    fd: i32,
+    _offset: i64,
+    _whence: i32,
+    _newoffset_ptr: i32,
+) -> i32 {
+    match fd {
+        STDIN | STDOUT | STDERR => ERRNO_SPIPE,
+        _ => ERRNO_BADF,
+    }
+}
+
+pub fn fd_fdstat_get(_: &mut WasiState, memory: &mut [u8], fd: i32, stat_ptr: i32) -> i32 {
+    let rights = match fd {
+        STDIN => RIGHTS_FD_READ,
+        STDOUT | STDERR => RIGHTS_FD_WRITE,
+        _ => return ERRNO_BADF,
+    };
+    // The layout of `fdstat`: the file type, 16-bit flags, the base rights and
+    // the inheriting rights.
+    let mut stat = [0u8; 24];
+    stat[0] = FILETYPE_CHARACTER_DEVICE;
+    stat[8..16].copy_from_slice(&rights.to_le_bytes());
+    errno(write(memory, stat_ptr, &stat))
+}
+
+pub fn fd_prestat_get(_: &mut WasiState, _memory: &mut [u8], _fd: i32, _prestat_ptr: i32) -> i32 {
+    // There are no preopened directories.
+    ERRNO_BADF
+}
+
+pub fn sched_yield(_: &mut WasiState, _memory: &mut [u8]) -> i32 {
+    ERRNO_SUCCESS
+}
+
+/// Links the WASI shim into `$linker`, a linker of the Wasm engine `$engine`
+/// (`wasmi` or `wasmtime`) for a `UserState` with a `wasi` field of type
+/// [`WasiState`].
+///
+/// `proc_exit` must be linked separately, as it traps in an engine-specific
+/// way.
+macro_rules! link_wasi {
+    ($linker:ident, $engine:ident) => {
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, args_get(argv: i32, argv_buf: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, args_sizes_get(argc: i32, argv_buf_size: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, environ_get(environ: i32, environ_buf: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, environ_sizes_get(environc: i32, environ_buf_size: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, clock_res_get(clock_id: i32, resolution: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, clock_time_get(clock_id: i32, precision: i64, time: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, random_get(buf: i32, buf_len: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, fd_write(fd: i32, iovs: i32, iovs_len: i32, nwritten: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, fd_read(fd: i32, iovs: i32, iovs_len: i32, nread: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, fd_close(fd: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, fd_seek(fd: i32, offset: i64, whence: i32, newoffset: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, fd_fdstat_get(fd: i32, stat: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, fd_prestat_get(fd: i32, prestat: i32));
+        $crate::wasm::wasi::link_wasi!(@function $linker, $engine, sched_yield());
+
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_advise(i32, i64, i64, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_allocate(i32, i64, i64));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_datasync(i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_fdstat_set_flags(i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_fdstat_set_rights(i32, i64, i64));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_filestat_get(i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_filestat_set_size(i32, i64));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_filestat_set_times(i32, i64, i64, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_pread(i32, i32, i32, i64, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_prestat_dir_name(i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_pwrite(i32, i32, i32, i64, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_readdir(i32, i32, i32, i64, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_renumber(i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_sync(i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, fd_tell(i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_create_directory(i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_filestat_get(i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_filestat_set_times(i32, i32, i32, i32, i64, i64, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_link(i32, i32, i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_open(i32, i32, i32, i32, i32, i64, i64, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_readlink(i32, i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_remove_directory(i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_rename(i32, i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_symlink(i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, path_unlink_file(i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, poll_oneoff(i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, proc_raise(i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, sock_accept(i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, sock_recv(i32, i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, sock_send(i32, i32, i32, i32, i32));
+        $crate::wasm::wasi::link_wasi!(@unsupported $linker, $engine, sock_shutdown(i32, i32));
+    };
+    (@function $linker:ident, $engine:ident, $function_name:ident($($arg:ident: $t:ty),*)) => {
+        $linker
+            .func_wrap(
+                $crate::wasm::wasi::MODULE,
+                stringify!($function_name),
+                |mut caller: $engine::Caller<'_, UserState>, $($arg: $t),*| -> i32 {
+                    match caller.get_export(MEMORY_NAME).and_then($engine::Extern::into_memory) {
+                        Some(memory) => {
+                            let (memory, user_state) = memory.data_and_store_mut(&mut caller);
+                            $crate::wasm::wasi::$function_name(&mut user_state.wasi, memory, $($arg),*)
+                        }
+                        None => $crate::wasm::wasi::ERRNO_FAULT,
+                    }
+                },
+            )
+            .expect(concat!("failed to define ", stringify!($function_name), " in linker"));
+    };
+    (@unsupported $linker:ident, $engine:ident, $function_name:ident($($t:ty),*)) => {
+        $linker
+            .func_wrap(
+                $crate::wasm::wasi::MODULE,
+                stringify!($function_name),
+                |_: $engine::Caller<'_, UserState>, $(_: $t),*| -> i32 {
+                    $crate::wasm::wasi::ERRNO_NOSYS
+                },
+            )
+            .expect(concat!("failed to define ", stringify!($function_name), " in linker"));
+    };
+}
+
+pub(crate) use link_wasi;
+
+fn errno(result: Result<(), i32>) -> i32 {
+    result.err().unwrap_or(ERRNO_SUCCESS)
+}
+
+// Returns the range of `len` bytes of `memory` at `ptr`, if it is in bounds.
+fn range(memory: &[u8], ptr: i32, len: u32) -> Result<core::ops::Range<usize>, i32> {
+    let start = ptr as u32 as usize;
+    let end = start.checked_add(len as usize).filter(|end| *end <= memory.len());
+    end.map(|end| start..end).ok_or(ERRNO_FAULT)
+}
+
+fn write(memory: &mut [u8], ptr: i32, bytes: &[u8]) -> Result<(), i32> {
+    let range = range(memory, ptr, bytes.len() as u32)?;
+    memory[range].copy_from_slice(bytes);
+    Ok(())
+}
+
+fn write_u32(memory: &mut [u8], ptr: i32, value: u32) -> Result<(), i32> {
+    write(memory, ptr, &value.to_le_bytes())
+}
+
+fn read_u32(memory: &[u8], ptr: i32) -> Result<u32, i32> {
+    let range = range(memory, ptr, 4)?;
+    Ok(u32::from_le_bytes(memory[range].try_into().unwrap()))
+}
+
+// Concatenates the buffers of an array of `iovec`s, each of which is a pointer
+// and a length.
+fn gather(memory: &[u8], iovs: i32, iovs_len: i32) -> Result<Vec<u8>, i32> {
+    let mut bytes = Vec::new();
+    for i in 0..iovs_len as u32 {
+        let iov = (iovs as u32).checked_add(8 * i).ok_or(ERRNO_FAULT)? as i32;
+        let buf = read_u32(memory, iov)?;
+        let buf_len = read_u32(memory, iov.wrapping_add(4))?;
+        bytes.extend_from_slice(&memory[range(memory, buf as i32, buf_len)?]);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use spinning_top::Spinlock;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct TestLogger {
+        messages: Spinlock<Vec<String>>,
+    }
+
+    impl OakLogger for TestLogger {
+        fn log_sensitive(&self, _level: Level, message: &str) {
+            self.messages.lock().push(message.to_string());
+        }
+        fn log_public(&self, _level: Level, _message: &str) {}
+    }
+
+    #[test]
+    fn test_fd_write_logs_stdout() {
+        let logger = Arc::new(TestLogger::default());
+        let mut state = WasiState::new(logger.clone());
+        let mut memory = vec![0u8; 64];
+        memory[32..38].copy_from_slice(b"hello ");
+        memory[40..46].copy_from_slice(b"world\n");
+        // Two iovecs at 0 and 8.
+        write_u32(&mut memory, 0, 32).unwrap();
+        write_u32(&mut memory, 4, 6).unwrap();
+        write_u32(&mut memory, 8, 40).unwrap();
+        write_u32(&mut memory, 12, 6).unwrap();
+
+        assert_eq!(fd_write(&mut state, &mut memory, STDOUT, 0, 2, 16), ERRNO_SUCCESS);
+        assert_eq!(read_u32(&memory, 16), Ok(12));
+        assert_eq!(*logger.messages.lock(), ["[Wasm stdout] hello world"]);
+
+        assert_eq!(fd_write(&mut state, &mut memory, 3, 0, 2, 16), ERRNO_BADF);
+        // The second iovec points outside of the memory.
+        write_u32(&mut memory, 8, 60).unwrap();
+        assert_eq!(fd_write(&mut state, &mut memory, STDERR, 0, 2, 16), ERRNO_FAULT);
+    }
+
+    #[test]
+    fn test_clocks_are_quantized_and_monotonic() {
+        let mut state = WasiState::new(Arc::new(TestLogger::default()));
+        let mut memory = vec![0u8; 16];
+        let mut last = 0;
+        for _ in 0..10 {
+            assert_eq!(
+                clock_time_get(&mut state, &mut memory, CLOCK_MONOTONIC, 1, 8),
+                ERRNO_SUCCESS
+            );
+            let time = u64::from_le_bytes(memory[8..16].try_into().unwrap());
+            assert_eq!(time % CLOCK_QUANTUM_NANOS, 0);
+            assert!(time >= last);
+            last = time;
+        }
+        assert_eq!(clock_time_get(&mut state, &mut memory, 4, 1, 8), ERRNO_INVAL);
+        assert_eq!(clock_time_get(&mut state, &mut memory, CLOCK_REALTIME, 1, 12), ERRNO_FAULT);
+    }
+
+    #[test]
+    fn test_random_get() {
+        let mut state = WasiState::new(Arc::new(TestLogger::default()));
+        let mut memory = vec![0u8; 64];
+        assert_eq!(random_get(&mut state, &mut memory, 16, 32), ERRNO_SUCCESS);
+        assert!(memory[16..48].iter().any(|byte| *byte != 0));
+        assert!(memory[..16].iter().chain(memory[48..].iter()).all(|byte| *byte == 0));
+        assert_eq!(random_get(&mut state, &mut memory, 48, 32), ERRNO_FAULT);
+    }
+
+    #[test]
+    fn test_no_arguments_or_environment() {
+        let mut state = WasiState::new(Arc::new(TestLogger::default()));
+        let mut memory = vec![0xffu8; 8];
+        assert_eq!(args_sizes_get(&mut state, &mut memory, 0, 4), ERRNO_SUCCESS);
+        assert_eq!(memory, [0; 8]);
+        assert_eq!(fd_prestat_get(&mut state, &mut memory, 3, 0), ERRNO_BADF);
+    }
+}
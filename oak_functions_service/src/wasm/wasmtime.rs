@@ -36,8 +36,16 @@ use wasmtime::{self, Store};
 use crate::{
     logger::{OakLogger, StandaloneLogger},
     lookup::LookupDataManager,
-    wasm::{api::StdWasmApiFactory, WasmApiFactory},
-    Handler, Observer,
+    proto::oak::functions::WasmEngine,
+    secrets::SecretStore,
+    wasm::{
+        api::StdWasmApiFactory,
+        execution_limit_exceeded,
+        memory::{memory_limit_exceeded, MemoryLimiter, MemoryLimits, WASM_PAGE_SIZE},
+        wasi::{self, WasiState},
+        wasm_trapped, WasmApiFactory,
+    },
+    Handler, HandlerConfig, Observer, Subsystems,
 };
 
 /// Fixed name of the function to start a Wasm. Every Oak Wasm module must
@@ -49,6 +57,10 @@ pub const ALLOC_FUNCTION_NAME: &str = "alloc";
 /// The name of the memory every Oak Wasm module has.
 pub const MEMORY_NAME: &str = "memory";
 
+// The number of pages of a 32-bit linear memory, which Wasmtime's instance pool
+// reserves for every instance if the memory isn't limited.
+const MAX_MEMORY_PAGES: u64 = 1 << 16;
+
 // Needs to be consistent with the definition of the Wasm import module in the
 // Oak Functions ABI.
 const OAK_FUNCTIONS: &str = "oak_functions";
@@ -65,28 +77,8 @@ pub type AbiPointerOffset = u32;
 pub struct UserState {
     wasm_api_transport: Box<dyn micro_rpc::Transport<Error = !>>,
     logger: Arc<dyn OakLogger>,
-}
-
-/// Stubs a Wasm imported function in the provided linker.
-///
-/// The stubbed function logs an error and returns an error in the form of a
-/// Wasm trap (similar to an exception).
-macro_rules! stub_wasm_function {
-    ($linker:ident, $function_mod:ident . $function_name:ident, ($($t:ty),*) -> $r:ty) => {
-        $linker.func_wrap(
-            stringify!($function_mod),
-            stringify!($function_name),
-            |caller: wasmtime::Caller<'_, UserState>, $(_: $t),*| {
-                caller
-                    .data()
-                    .log_error(concat!("called stubbed ", stringify!($function_mod), ".", stringify!($function_name)));
-                Err::<$r, ::anyhow::Error>(
-                    ::anyhow::anyhow!(
-                        concat!("called stubbed ", stringify!($function_mod), ".", stringify!($function_name))))
-            },
-        )
-        .expect(concat!("failed to define ", stringify!($function_mod), ".", stringify!($function_name), " in linker"));
-    };
+    wasi: WasiState,
+    memory_limiter: MemoryLimiter,
 }
 
 impl UserState {
@@ -97,8 +89,14 @@ impl UserState {
     fn new(
         wasm_api_transport: Box<dyn micro_rpc::Transport<Error = !>>,
         logger: Arc<dyn OakLogger>,
+        memory_limits: MemoryLimits,
     ) -> Self {
-        UserState { wasm_api_transport, logger }
+        UserState {
+            wasm_api_transport,
+            wasi: WasiState::new(logger.clone()),
+            logger,
+            memory_limiter: MemoryLimiter::new(memory_limits),
+        }
     }
 
     // Use an `OakLogger` to log.
@@ -157,50 +155,21 @@ impl OakLinker {
             )
             .expect("failed to define invoke in linker");
 
-        // TODO(#3929): One of our dependency requires various WASI functions to be
-        // linked, but, to the best of our knowledge, does not use them at run
-        // time. As a workaround, we stub them for now but we should remove
-        // them, if possible.
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.clock_time_get,
-            (i32, i64, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.proc_exit,
-            (i32) -> ()
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.environ_sizes_get,
-            (i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.environ_get,
-            (i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_close,
-            (i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_write,
-            (i32, i32, i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_read,
-            (i32, i32, i32, i32) -> i32
-        );
-        stub_wasm_function!(
-            linker,
-            wasi_snapshot_preview1.fd_seek,
-            (i32, i64, i32, i32) -> i32
-        );
+        // Libraries compiled for WASI run unmodified against a sandboxed subset of
+        // WASI preview 1.
+        wasi::link_wasi!(linker, wasmtime);
+        linker
+            .func_wrap(
+                wasi::MODULE,
+                "proc_exit",
+                |mut caller: wasmtime::Caller<'_, UserState>,
+                 exit_code: i32|
+                 -> anyhow::Result<()> {
+                    caller.data_mut().wasi.exit_code = Some(exit_code);
+                    Err(anyhow::anyhow!("Wasm module exited with code {}", exit_code))
+                },
+            )
+            .expect("failed to define proc_exit in linker");
 
         OakLinker { linker }
     }
@@ -386,6 +355,9 @@ pub struct WasmtimeHandler {
     logger: Arc<dyn OakLogger>,
     #[cfg_attr(not(feature = "std"), allow(dead_code))]
     observer: Option<Arc<dyn Observer + Send + Sync>>,
+    // The fuel every invocation starts with, if limited.
+    fuel_limit: Option<u64>,
+    memory_limits: MemoryLimits,
 }
 
 impl WasmtimeHandler {
@@ -394,12 +366,61 @@ impl WasmtimeHandler {
         wasm_api_factory: Arc<dyn WasmApiFactory + Send + Sync>,
         logger: Arc<dyn OakLogger>,
         observer: Option<Arc<dyn Observer + Send + Sync>>,
+        handler_config: &HandlerConfig,
     ) -> anyhow::Result<Self> {
-        let config = wasmtime::Config::new();
+        let fuel_limit = handler_config.wasm_fuel_limit;
+        let memory_limits = handler_config.wasm_memory_limits;
+        // Compile the module ahead of time with Cranelift, optimizing for the speed of
+        // the generated code, as modules are compiled once and then handle many
+        // requests.
+        let mut config = wasmtime::Config::new();
+        config.strategy(wasmtime::Strategy::Cranelift);
+        config.cranelift_opt_level(wasmtime::OptLevel::Speed);
+        // Metering fuel slows down execution, so it is only enabled if needed.
+        config.consume_fuel(fuel_limit.is_some());
+        // If invocations are bounded, reserve the instances they need up front, and
+        // reuse them instead of allocating them for every invocation. Reused
+        // instances are reset, so invocations stay isolated from each other.
+        if let Some(pool_size) = handler_config.max_concurrent_invocations {
+            let mut pooling = wasmtime::PoolingAllocationConfig::default();
+            pooling
+                .total_core_instances(pool_size)
+                .total_memories(pool_size)
+                .total_tables(pool_size)
+                .memory_pages(
+                    memory_limits
+                        .max_bytes
+                        .map_or(MAX_MEMORY_PAGES, |max_bytes| max_bytes.div_ceil(WASM_PAGE_SIZE)),
+                );
+            config.allocation_strategy(wasmtime::InstanceAllocationStrategy::Pooling(pooling));
+        }
         let engine = wasmtime::Engine::new(&config)
             .map_err(|err| anyhow::anyhow!("couldn't create Wasmtime engine: {:?}", err))?;
-        let module = wasmtime::Module::new(&engine, wasm_module_bytes)
-            .map_err(|err| anyhow::anyhow!("couldn't load module from buffer: {:?}", err))?;
+        let precompiled_module =
+            handler_config.precompiled_module.as_deref().and_then(|precompiled| {
+                // Safety: precompiled modules are only passed on from earlier handlers of
+                // the same module, see `crate::precompiled`. Wasmtime checks that they
+                // were compiled for the same engine config.
+                unsafe { wasmtime::Module::deserialize(&engine, precompiled) }
+                    .map_err(|err| {
+                        logger.log_public(
+                            Level::Warn,
+                            &format!("couldn't load precompiled module, compiling it: {:?}", err),
+                        )
+                    })
+                    .ok()
+            });
+        let module = match precompiled_module {
+            Some(module) => module,
+            None => wasmtime::Module::new(&engine, wasm_module_bytes)
+                .map_err(|err| anyhow::anyhow!("couldn't load module from buffer: {:?}", err))?,
+        };
+        if let Some(memory_type) =
+            module.get_export(MEMORY_NAME).as_ref().and_then(wasmtime::ExternType::memory)
+        {
+            memory_limits
+                .check_initial_size(memory_type.minimum().saturating_mul(WASM_PAGE_SIZE))?;
+        }
 
         let linker = OakLinker::new(module.engine());
 
@@ -409,35 +430,37 @@ impl WasmtimeHandler {
             wasm_api_factory,
             logger,
             observer,
+            fuel_limit,
+            memory_limits,
         })
     }
-}
 
-impl Handler for WasmtimeHandler {
-    type HandlerType = WasmtimeHandler;
-
-    fn new_handler(
-        wasm_module_bytes: &[u8],
-        lookup_data_manager: Arc<LookupDataManager>,
-        observer: Option<Arc<dyn Observer + Send + Sync>>,
-    ) -> anyhow::Result<WasmtimeHandler> {
-        let logger = Arc::new(StandaloneLogger);
-        let wasm_api_factory = Arc::new(StdWasmApiFactory { lookup_data_manager });
-
-        Self::create(wasm_module_bytes, wasm_api_factory, logger, observer)
-    }
-
-    fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status> {
+    // Handles a request with Wasm APIs created by `wasm_api_factory`.
+    fn invoke(
+        &self,
+        wasm_api_factory: &dyn WasmApiFactory,
+        invoke_request: Request,
+    ) -> Result<Response, micro_rpc::Status> {
         #[cfg(feature = "std")]
         let now = Instant::now();
         let module = self.wasm_module.clone();
 
         let request = invoke_request.body;
         let response = Arc::new(Spinlock::new(Vec::new()));
-        let mut wasm_api = self.wasm_api_factory.create_wasm_api(request, response.clone());
-        let user_state = UserState::new(wasm_api.transport(), self.logger.clone());
+        let mut wasm_api = wasm_api_factory.create_wasm_api(request, response.clone());
+        let user_state =
+            UserState::new(wasm_api.transport(), self.logger.clone(), self.memory_limits);
         // For isolated requests we need to create a new store for every request.
         let mut store = wasmtime::Store::new(module.engine(), user_state);
+        store.limiter(|user_state| &mut user_state.memory_limiter);
+        if let Some(fuel_limit) = self.fuel_limit {
+            store.set_fuel(fuel_limit).map_err(|err| {
+                micro_rpc::Status::new_with_message(
+                    StatusCode::Internal,
+                    format!("couldn't set fuel: {:?}", err),
+                )
+            })?;
+        }
         let instance = self.linker.instantiate(&mut store, module)?;
 
         // Does not work in wasmtime
@@ -468,11 +491,25 @@ impl Handler for WasmtimeHandler {
         if let Some(ref observer) = self.observer {
             observer.wasm_invocation(now.elapsed());
         }
+        if result.as_ref().is_err_and(|err| err.downcast_ref() == Some(&wasmtime::Trap::OutOfFuel))
+        {
+            return Err(execution_limit_exceeded());
+        }
+        if result.is_err() && store.data().memory_limiter.exceeded() {
+            return Err(memory_limit_exceeded());
+        }
 
         store.data().logger.log_sensitive(
             Level::Info,
             &format!("running Wasm module completed with result: {:?}", result),
         );
+        // Exiting through `proc_exit` traps as well, which is only a failure for
+        // non-zero exit codes.
+        if let Err(trap) = &result {
+            if store.data().wasi.exit_code != Some(0) {
+                return Err(wasm_trapped(trap, wasm_api.lookup_missed()));
+            }
+        }
 
         let response_bytes = response.lock().clone();
         store
@@ -486,6 +523,54 @@ impl Handler for WasmtimeHandler {
     }
 }
 
+impl Handler for WasmtimeHandler {
+    type HandlerType = WasmtimeHandler;
+
+    fn supports_wasm_engine(wasm_engine: WasmEngine) -> bool {
+        matches!(wasm_engine, WasmEngine::Unspecified | WasmEngine::Wasmtime)
+    }
+
+    fn new_handler(
+        wasm_module_bytes: &[u8],
+        config: &HandlerConfig,
+        lookup_data_manager: Arc<LookupDataManager>,
+        secret_store: Arc<SecretStore>,
+        subsystems: Subsystems,
+        observer: Option<Arc<dyn Observer + Send + Sync>>,
+    ) -> anyhow::Result<WasmtimeHandler> {
+        let logger = Arc::new(StandaloneLogger);
+        let wasm_api_factory = Arc::new(StdWasmApiFactory {
+            lookup_data_manager,
+            lookup_namespace: config.lookup_namespace.clone(),
+            secret_store,
+            subsystems,
+            randomness: Arc::default(),
+            logger: logger.clone(),
+        });
+
+        Self::create(wasm_module_bytes, wasm_api_factory, logger, observer, config)
+    }
+
+    fn handle_invoke(&self, invoke_request: Request) -> Result<Response, micro_rpc::Status> {
+        self.invoke(self.wasm_api_factory.as_ref(), invoke_request)
+    }
+
+    fn handle_invoke_batch(
+        &self,
+        invoke_requests: Vec<Request>,
+    ) -> Vec<Result<Response, micro_rpc::Status>> {
+        let wasm_api_factory = self.wasm_api_factory.snapshot();
+        invoke_requests
+            .into_iter()
+            .map(|invoke_request| self.invoke(wasm_api_factory.as_ref(), invoke_request))
+            .collect()
+    }
+
+    fn precompiled_module(&self) -> Option<Vec<u8>> {
+        self.wasm_module.serialize().ok()
+    }
+}
+
 /// A helper function to move between our specific result type `Result<(),
 /// StatusCode>` and the `wasmtime` specific result type `Result<i32,
 /// wasmtime::Trap>`.
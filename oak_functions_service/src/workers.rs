@@ -0,0 +1,133 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Bounds the number of invocations that run at the same time, so that an
+//! enclave with several vCPUs handles requests in parallel without running
+//! more invocations than it has resources for.
+
+use crate::lookup::mutexes::{Mutex, MutexGuard};
+
+/// A pool of workers that run invocations. Every invocation occupies a worker
+/// while it runs, and waits for a worker to become free if all of them are
+/// busy.
+#[derive(Default)]
+pub struct WorkerPool {
+    // The number of workers, or `None` if invocations never wait.
+    size: Option<usize>,
+    busy: Mutex<usize>,
+    #[cfg(feature = "std")]
+    freed: parking_lot::Condvar,
+}
+
+impl WorkerPool {
+    /// Creates a pool of `size` workers, or an unbounded pool if `None`.
+    pub fn new(size: Option<usize>) -> Self {
+        Self { size, ..Default::default() }
+    }
+
+    /// Returns the number of workers, if bounded.
+    pub fn size(&self) -> Option<usize> {
+        self.size
+    }
+
+    /// Runs `invocation` on a worker, once one is free.
+    pub fn run<T>(&self, invocation: impl FnOnce() -> T) -> T {
+        let _worker = self.take_worker();
+        invocation()
+    }
+
+    fn take_worker(&self) -> Option<Worker<'_>> {
+        let size = self.size?;
+        let mut busy = self.busy.lock();
+        while *busy >= size {
+            busy = self.wait(busy);
+        }
+        *busy += 1;
+        Some(Worker { pool: self })
+    }
+
+    #[cfg(feature = "std")]
+    fn wait<'a>(&self, mut busy: MutexGuard<'a, usize>) -> MutexGuard<'a, usize> {
+        self.freed.wait(&mut busy);
+        busy
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn wait<'a>(&'a self, busy: MutexGuard<'a, usize>) -> MutexGuard<'a, usize> {
+        // There are no threads to park, so spin until another vCPU frees a worker.
+        drop(busy);
+        core::hint::spin_loop();
+        self.busy.lock()
+    }
+}
+
+// A busy worker, which becomes free again when dropped.
+struct Worker<'a> {
+    pool: &'a WorkerPool,
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        *self.pool.busy.lock() -= 1;
+        #[cfg(feature = "std")]
+        self.pool.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_bounds_concurrent_invocations() {
+        let pool = Arc::new(WorkerPool::new(Some(2)));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let threads: std::vec::Vec<_> = (0..8)
+            .map(|_| {
+                let (pool, running, max_running) =
+                    (pool.clone(), running.clone(), max_running.clone());
+                std::thread::spawn(move || {
+                    pool.run(|| {
+                        let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now_running, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(10));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(*pool.busy.lock(), 0);
+    }
+
+    #[test]
+    fn test_unbounded() {
+        let pool = WorkerPool::new(None);
+        // Invocations don't take a worker, so nested ones don't wait.
+        assert_eq!(pool.run(|| pool.run(|| 42)), 42);
+        assert_eq!(pool.size(), None);
+    }
+}
@@ -17,12 +17,12 @@
 //! Test utilities to help with unit testing of Oak Functions code.
 
 use std::{
-    collections::HashMap, future::Future, io::Write, pin::Pin, process::Command, task::Poll,
-    time::Duration,
+    cell::RefCell, collections::HashMap, future::Future, io::Write, pin::Pin, process::Command,
+    sync::Once, task::Poll, time::Duration,
 };
 
 use anyhow::Context;
-use log::info;
+use log::{info, Level};
 use nix::unistd::Pid;
 use oak_client::verifier::InsecureAttestationVerifier;
 use oak_functions_abi::Response;
@@ -267,3 +267,61 @@ pub fn assert_response_body(response: Response, expected: &str) {
         expected
     )
 }
+
+thread_local! {
+    // The log records captured on this thread, if capturing.
+    static CAPTURED_LOGS: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+}
+
+// A logger that records the messages logged on threads that capture logs.
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        CAPTURED_LOGS.with(|logs| {
+            if let Some(logs) = logs.borrow_mut().as_mut() {
+                logs.push((record.level(), record.args().to_string()));
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Captures the log messages of the current thread until it is dropped, see
+/// [`capture_logs`].
+pub struct LogCapture {
+    _private: (),
+}
+
+impl LogCapture {
+    /// Returns the messages captured so far, in the order they were logged.
+    pub fn messages(&self) -> Vec<(Level, String)> {
+        CAPTURED_LOGS.with(|logs| logs.borrow().clone().unwrap_or_default())
+    }
+}
+
+impl Drop for LogCapture {
+    fn drop(&mut self) {
+        CAPTURED_LOGS.with(|logs| logs.borrow_mut().take());
+    }
+}
+
+/// Starts capturing the log messages of the current thread, e.g. those that a
+/// Wasm module logs while a test invokes it synchronously.
+///
+/// Installs a global logger on first use, so it can't be combined with other
+/// loggers such as `env_logger` in the same test binary.
+pub fn capture_logs() -> LogCapture {
+    static INSTALL_LOGGER: Once = Once::new();
+    INSTALL_LOGGER.call_once(|| {
+        log::set_logger(&CapturingLogger).expect("couldn't install capturing logger");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    CAPTURED_LOGS.with(|logs| *logs.borrow_mut() = Some(Vec::new()));
+    LogCapture { _private: () }
+}
@@ -14,7 +14,7 @@
 // limitations under the License.
 //
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Options for building gRPC code.
 #[derive(Default)]
@@ -25,6 +25,9 @@ pub struct CodegenOptions {
     pub build_server: bool,
     /// Specify externally provided Protobuf packages or types.
     pub extern_paths: Vec<ExternPath>,
+    /// If set, also write the encoded file descriptor set of the protos to this
+    /// path, e.g. for serving gRPC server reflection.
+    pub file_descriptor_set_path: Option<PathBuf>,
 }
 
 #[derive(Default)]
@@ -72,6 +75,9 @@ pub fn generate_grpc_code(
     for extern_path in options.extern_paths {
         config = config.extern_path(extern_path.proto_path, extern_path.rust_path);
     }
+    if let Some(file_descriptor_set_path) = options.file_descriptor_set_path {
+        config = config.file_descriptor_set_path(file_descriptor_set_path);
+    }
     config.compile(protos, &[include])
 }
 
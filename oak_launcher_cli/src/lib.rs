@@ -0,0 +1,226 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Command line shared by the Restricted Kernel and Oak Containers flavors of
+//! the Oak Functions launcher.
+//!
+//! The options of the application, its lookup data and its endpoints that both
+//! flavors support come before the subcommand that selects the flavor. The
+//! subcommand takes the options of the VM, and the options that only its flavor
+//! supports, such as replicas or signed lookup data on the Restricted Kernel.
+
+use std::io::Write;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use oak_launcher_utils::{config::ConfigParams, launcher};
+
+/// Command line of the `oak_launcher` binary.
+#[derive(Parser, Debug)]
+#[command(name = "oak_launcher")]
+pub struct Cli {
+    #[clap(flatten)]
+    pub config: ConfigParams,
+
+    #[clap(flatten)]
+    pub logging: LoggingArgs,
+
+    /// Options of the application, its lookup data and its endpoints that both
+    /// flavors support.
+    #[clap(flatten)]
+    pub functions: oak_functions_launcher::Args,
+
+    #[command(subcommand)]
+    pub flavor: Flavor,
+}
+
+/// The kind of enclave the application runs in.
+#[derive(Subcommand, Debug)]
+pub enum Flavor {
+    /// Runs the application on the Restricted Kernel.
+    RestrictedKernel(RestrictedKernelFlavor),
+    /// Runs the application on Oak Containers.
+    Containers(oak_functions_containers_launcher::runner::Args),
+}
+
+/// Options of the `restricted-kernel` subcommand.
+#[derive(Parser, Debug)]
+pub struct RestrictedKernelFlavor {
+    #[clap(flatten)]
+    pub params: launcher::Params,
+
+    /// Options that Oak Containers doesn't support.
+    #[clap(flatten)]
+    pub options: oak_functions_launcher::RestrictedKernelArgs,
+}
+
+impl Cli {
+    /// Runs the launcher of the selected flavor until it is interrupted or the
+    /// VMM exits. Errors of the Restricted Kernel launcher are
+    /// [`oak_launcher_utils::error::LauncherError`]s, which can be recovered
+    /// with [`anyhow::Error::downcast_ref`].
+    pub async fn run(self) -> anyhow::Result<()> {
+        match self.flavor {
+            Flavor::RestrictedKernel(flavor) => {
+                oak_functions_launcher::runner::run(flavor.params, self.functions, flavor.options)
+                    .await?;
+                Ok(())
+            }
+            Flavor::Containers(args) => {
+                oak_functions_containers_launcher::runner::run(args, self.functions).await
+            }
+        }
+    }
+}
+
+/// Options of the logs of the launcher.
+#[derive(Parser, Clone, Debug, PartialEq)]
+pub struct LoggingArgs {
+    /// Which logs to write, in the syntax of `RUST_LOG`, e.g. `info` or
+    /// `info,oak_functions_launcher=debug`. Overrides `RUST_LOG`.
+    #[arg(long)]
+    pub log_filter: Option<String>,
+
+    /// Format of the logs. JSON logs have one object per line, for log
+    /// collectors.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LoggingArgs {
+    /// Sets up the logger of the process.
+    pub fn init(&self) {
+        let mut builder = env_logger::Builder::from_default_env();
+        if let Some(filter) = self.log_filter.as_ref() {
+            builder.parse_filters(filter);
+        }
+        if self.log_format == LogFormat::Json {
+            builder.format(|buf, record| {
+                let line = serde_json::json!({
+                    "timestamp": buf.timestamp().to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            });
+        }
+        builder.init();
+    }
+}
+
+#[cfg(test)]
+fn restricted_kernel_flags(path: &str) -> Vec<&str> {
+    vec![
+        "restricted-kernel",
+        "--vmm-binary",
+        path,
+        "--kernel",
+        path,
+        "--initrd",
+        path,
+        "--bios-binary",
+        path,
+    ]
+}
+
+#[cfg(test)]
+fn containers_flags(path: &str) -> Vec<&str> {
+    vec![
+        "containers",
+        "--vmm-binary",
+        path,
+        "--stage0-binary",
+        path,
+        "--kernel",
+        path,
+        "--initrd",
+        path,
+        "--ramdrive-size",
+        "1000000",
+        "--virtiofsd-binary",
+        path,
+        "--system-image",
+        path,
+        "--container-bundle",
+        path,
+    ]
+}
+
+#[test]
+fn test_parse_restricted_kernel() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    let mut flags = vec!["oak_launcher", "--wasm", path, "--port", "8081"];
+    flags.extend(restricted_kernel_flags(path));
+    flags.extend(["--replicas", "2"]);
+    let cli = Cli::try_parse_from(flags).unwrap();
+    assert_eq!(cli.functions.port, 8081);
+    assert!(matches!(cli.flavor, Flavor::RestrictedKernel(flavor) if flavor.options.replicas == 2));
+}
+
+#[test]
+fn test_parse_containers() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    let mut flags = vec!["oak_launcher", "--wasm", path, "--port", "8081"];
+    flags.extend(containers_flags(path));
+    flags.extend(["--wasm-engine", "wasmi"]);
+    let cli = Cli::try_parse_from(flags).unwrap();
+    assert_eq!(cli.functions.port, 8081);
+    assert!(
+        matches!(cli.flavor, Flavor::Containers(args) if args.wasm_engine == oak_functions_containers_launcher::runner::WasmEngineType::Wasmi)
+    );
+}
+
+#[test]
+fn test_restricted_kernel_options_only_follow_their_subcommand() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+
+    let mut flags = vec!["oak_launcher", "--wasm", path, "--replicas", "2"];
+    flags.extend(restricted_kernel_flags(path));
+    assert!(Cli::try_parse_from(flags).is_err());
+
+    let mut flags = vec!["oak_launcher", "--wasm", path];
+    flags.extend(containers_flags(path));
+    flags.extend(["--replicas", "2"]);
+    assert!(Cli::try_parse_from(flags).is_err());
+}
+
+#[test]
+fn test_parse_requires_flavor() {
+    assert!(Cli::try_parse_from(["oak_launcher", "--port", "8081"]).is_err());
+}
+
+#[tokio::test]
+async fn test_run_keeps_restricted_kernel_errors() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap();
+    // There is no Wasm module to launch the enclave with.
+    let mut flags = vec!["oak_launcher", "--lookup-data", path];
+    flags.extend(restricted_kernel_flags(path));
+    let err = Cli::try_parse_from(flags).unwrap().run().await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<oak_launcher_utils::error::LauncherError>(),
+        Some(oak_launcher_utils::error::LauncherError::Config(_))
+    ));
+}
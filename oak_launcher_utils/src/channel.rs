@@ -14,83 +14,484 @@
 // limitations under the License.
 //
 
-use oak_channel::client::{ClientChannelHandle, RequestEncoder};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use clap::{Parser, ValueEnum};
+use micro_rpc::AsyncTransport;
+use oak_channel::{
+    client::{ChannelBusy, ClientChannelHandle, RequestEncoder, Window, WindowLimits},
+    message::{InvocationId, RequestMessage},
+};
+use tokio::sync::{mpsc, oneshot};
+
+type Response = Result<Vec<u8>, micro_rpc::Status>;
+
+/// Flow control settings of the channel to the guest.
+#[derive(Parser, Clone, Debug, PartialEq)]
+pub struct ChannelParams {
+    /// Maximum number of requests sent to the guest but not responded to yet.
+    /// Further requests fail as busy until responses arrive.
+    #[arg(long, default_value_t = WindowLimits::DEFAULT_MAX_REQUESTS)]
+    pub channel_max_in_flight_requests: usize,
+
+    /// Maximum total size in bytes of the requests in flight to the guest.
+    #[arg(long, default_value_t = WindowLimits::DEFAULT_MAX_BYTES)]
+    pub channel_max_in_flight_bytes: usize,
+
+    /// The virtio device that carries the channel. The kernel must have been
+    /// built with support for it.
+    #[arg(long, value_enum, default_value_t = ChannelTransport::default())]
+    pub channel_transport: ChannelTransport,
+
+    /// The vsock context ID of the first guest with the virtio-vsock transport.
+    /// Every further guest, e.g. a replica or a restarted guest, gets the next
+    /// one, so the IDs mustn't be in use by other VMs on the host.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(3..))]
+    pub vsock_first_guest_cid: u32,
+}
+
+/// The virtio devices that can carry the channel to the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ChannelTransport {
+    /// A virtio console, which moves one byte at a time.
+    #[default]
+    VirtioConsole,
+    /// A virtio-vsock stream socket, which the guest listens on. Moves data in
+    /// packets of up to 64 KiB, which makes large lookup data much faster to
+    /// send.
+    VirtioVsock,
+}
+
+impl ChannelTransport {
+    /// Returns the argument that makes the restricted kernel use the transport.
+    pub fn kernel_arg(&self) -> Option<&'static str> {
+        match self {
+            // The console is the default channel of the kernel.
+            ChannelTransport::VirtioConsole => None,
+            ChannelTransport::VirtioVsock => Some("channel=virtio_mmio_vsock"),
+        }
+    }
+}
+
+/// The vsock port the restricted kernel listens on for the channel.
+pub const GUEST_VSOCK_PORT: u32 = 1024;
+
+impl Default for ChannelParams {
+    fn default() -> Self {
+        let limits = WindowLimits::default();
+        Self {
+            channel_max_in_flight_requests: limits.max_requests,
+            channel_max_in_flight_bytes: limits.max_bytes,
+            channel_transport: ChannelTransport::default(),
+            vsock_first_guest_cid: 3,
+        }
+    }
+}
+
+impl ChannelParams {
+    pub fn window_limits(&self) -> WindowLimits {
+        WindowLimits {
+            max_requests: self.channel_max_in_flight_requests,
+            max_bytes: self.channel_max_in_flight_bytes,
+        }
+    }
+}
+
+/// Tag at the start of the message of the status returned for requests that
+/// were rejected because too many requests were in flight already. It tells
+/// them apart from [`micro_rpc::StatusCode::ResourceExhausted`] statuses
+/// returned by the runtime itself, e.g. for an exhausted memory budget.
+const CHANNEL_BUSY_TAG: &str = "[channel-busy] ";
+
+/// Returns whether `status` is the error returned for requests that were
+/// rejected because too many requests were in flight already, in which case
+/// they may be retried later.
+pub fn is_channel_busy(status: &micro_rpc::Status) -> bool {
+    status.code == micro_rpc::StatusCode::ResourceExhausted
+        && status.message.starts_with(CHANNEL_BUSY_TAG)
+}
+
+/// The requests that have been sent to the runtime but not answered yet.
+struct InFlight {
+    request_encoder: RequestEncoder,
+    window: Window,
+    /// Size and response sender of each request, by invocation id.
+    responses: HashMap<InvocationId, (usize, oneshot::Sender<Response>)>,
+}
+
+/// `None` once the channel has failed, as no more responses will arrive.
+type PendingResponses = Arc<Mutex<Option<InFlight>>>;
+
+/// What the writer thread writes to the channel.
+enum Command {
+    Request(RequestMessage),
+    Cancel(InvocationId),
+}
 
 /// Singleton responsible for sending requests, and receiving responses over the
 /// underlying communication channel with the baremetal runtime.
+///
+/// Several requests may be in flight at the same time: requests are written in
+/// the order they are dispatched, and every response is routed back to its
+/// request by invocation id, so callers don't wait for each other's round
+/// trips, and responses may arrive in any order. The Restricted Kernel runtime
+/// still handles one request at a time, in order, so a slow request (e.g. a
+/// large lookup data chunk) delays the requests written after it until it has
+/// been handled, unless they are cancelled first. Large requests are
+/// compressed if the runtime supports it.
+///
+/// Requests beyond the window limits fail immediately (see
+/// [`is_channel_busy`]) rather than being buffered, so that a runtime that
+/// stops draining the channel can't make the launcher exhaust its memory.
+///
+/// Requests whose caller stops waiting for the response, e.g. because of a
+/// deadline, are cancelled: they are dropped if they haven't been written yet,
+/// and otherwise the runtime is told that the response is no longer awaited.
 pub struct Connector {
-    inner: ClientChannelHandle,
-    request_encoder: RequestEncoder,
+    pending_responses: PendingResponses,
+    commands: mpsc::UnboundedSender<Command>,
 }
 
 impl Connector {
     /// Spawn an instance of the [`Connector`] in a seperate task, and return a
     /// cloneable [`ConnectorHandle`] for it.
-    pub fn spawn(inner: Box<dyn oak_channel::Channel>) -> ConnectorHandle {
-        // A message based communication channel that permits other parts of the
-        // untrusted launcher to send requests to the task that handles communicating
-        // with the runtime and receive responses.
-        let (request_dispatcher, mut request_receiver) =
-            bmrng::unbounded_channel::<Vec<u8>, Result<Vec<u8>, micro_rpc::Status>>();
-
-        // Spawn task to handle communicating with the runtime and receiving responses.
-        tokio::spawn(async move {
-            let mut connector = Self {
-                inner: ClientChannelHandle::new(inner),
-                request_encoder: RequestEncoder::default(),
-            };
-            while let Ok((request, response_dispatcher)) = request_receiver.recv().await {
-                // At the moment requests are sent sequentially, and in FIFO order. The next
-                // request is sent only once a response to the previous message
-                // has been implemented. TODO(#2848): Implement message
-                // prioritization, and non sequential invocations.
-                let response = connector.invoke(request.as_ref());
-                response_dispatcher.respond(response).unwrap();
+    ///
+    /// `reader` and `writer` must be handles to the same underlying channel;
+    /// responses are only read from the former, and requests only written to
+    /// the latter.
+    pub fn spawn(
+        reader: Box<dyn oak_channel::Channel>,
+        writer: Box<dyn oak_channel::Channel>,
+        window_limits: WindowLimits,
+    ) -> ConnectorHandle {
+        let mut request_encoder = RequestEncoder::default();
+        let negotiation_id = request_encoder.encode_request(&[]).invocation_id;
+        let pending_responses: PendingResponses = Arc::new(Mutex::new(Some(InFlight {
+            request_encoder,
+            window: Window::new(window_limits),
+            responses: HashMap::new(),
+        })));
+        let (commands, command_receiver) = mpsc::unbounded_channel();
+
+        // Reading from and writing to the channel blocks, so both are done on
+        // dedicated threads. Requests dispatched before the protocol extensions
+        // have been negotiated wait in the command queue.
+        {
+            let pending_responses = pending_responses.clone();
+            thread::spawn(move || {
+                let mut reader = ClientChannelHandle::new(reader);
+                let mut writer = ClientChannelHandle::new(writer);
+                match writer.negotiate(&mut reader, negotiation_id) {
+                    Ok(extensions) => log::info!("runtime protocol extensions: {:?}", extensions),
+                    Err(err) => {
+                        log::warn!("couldn't negotiate protocol extensions: {:?}", err);
+                        fail_pending_responses(
+                            &pending_responses,
+                            &format!("couldn't negotiate protocol extensions: {err}"),
+                        );
+                        return;
+                    }
+                }
+                {
+                    let pending_responses = pending_responses.clone();
+                    thread::spawn(move || read_responses(reader, pending_responses));
+                }
+                write_commands(writer, command_receiver, pending_responses);
+            });
+        }
+
+        ConnectorHandle { connector: Arc::new(Self { pending_responses, commands }) }
+    }
+
+    fn dispatch(
+        &self,
+        request: &[u8],
+    ) -> Result<(InvocationId, oneshot::Receiver<Response>), micro_rpc::Status> {
+        let mut pending_responses = self.pending_responses.lock().unwrap();
+        let in_flight =
+            pending_responses.as_mut().ok_or_else(|| channel_failed("channel closed"))?;
+        in_flight.window.acquire(request.len()).map_err(channel_busy)?;
+        let request_message = in_flight.request_encoder.encode_request(request);
+        let invocation_id = request_message.invocation_id;
+        let (response_sender, response_receiver) = oneshot::channel();
+        in_flight.responses.insert(invocation_id, (request.len(), response_sender));
+        // The writer thread only stops after failing all pending responses, including
+        // this one.
+        let _ = self.commands.send(Command::Request(request_message));
+        Ok((invocation_id, response_receiver))
+    }
+
+    fn cancel(&self, invocation_id: InvocationId) {
+        let mut pending_responses = self.pending_responses.lock().unwrap();
+        let Some(in_flight) = pending_responses.as_mut() else {
+            return;
+        };
+        // Nothing to do if the response has arrived already.
+        if let Some((size, _)) = in_flight.responses.remove(&invocation_id) {
+            in_flight.window.release(size);
+            let _ = self.commands.send(Command::Cancel(invocation_id));
+        }
+    }
+}
+
+// Writes requests and cancellations to the channel until the connector is
+// dropped or writing fails.
+fn write_commands(
+    mut channel: ClientChannelHandle,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    pending_responses: PendingResponses,
+) {
+    // Invocations that were cancelled before their request was written, and so
+    // don't need to be cancelled on the runtime side.
+    let mut skipped = HashSet::new();
+    while let Some(command) = commands.blocking_recv() {
+        let result = match command {
+            Command::Request(request_message) => {
+                let invocation_id = request_message.invocation_id;
+                let pending = pending_responses
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .is_some_and(|in_flight| in_flight.responses.contains_key(&invocation_id));
+                if pending {
+                    channel.write_request(request_message)
+                } else {
+                    skipped.insert(invocation_id);
+                    Ok(())
+                }
+            }
+            Command::Cancel(invocation_id) => {
+                if skipped.remove(&invocation_id) {
+                    Ok(())
+                } else {
+                    channel.write_cancellation(invocation_id)
+                }
             }
+        };
+        if let Err(err) = result {
+            log::warn!("couldn't write to channel: {:?}", err);
+            fail_pending_responses(
+                &pending_responses,
+                &format!("couldn't write to channel: {err}"),
+            );
+            return;
+        }
+    }
+}
+
+// Reads responses from the channel and routes them to their requests, until
+// reading fails, e.g. because the runtime exited.
+fn read_responses(mut channel: ClientChannelHandle, pending_responses: PendingResponses) {
+    loop {
+        let response_message = match channel.read_response() {
+            Ok((response_message, _)) => response_message,
+            Err(err) => {
+                log::warn!("couldn't read response: {:?}", err);
+                fail_pending_responses(
+                    &pending_responses,
+                    &format!("couldn't read response: {err}"),
+                );
+                return;
+            }
+        };
+        let response_sender = pending_responses.lock().unwrap().as_mut().and_then(|in_flight| {
+            let (size, response_sender) =
+                in_flight.responses.remove(&response_message.invocation_id)?;
+            in_flight.window.release(size);
+            Some(response_sender)
         });
+        match response_sender {
+            Some(response_sender) => {
+                // The requester may have given up waiting already.
+                let _ = response_sender.send(Ok(response_message.body));
+            }
+            None => log::debug!(
+                "dropping response to cancelled invocation {}",
+                response_message.invocation_id
+            ),
+        }
+    }
+}
 
-        ConnectorHandle { request_dispatcher }
+// Fails all pending responses, as well as any requests dispatched from now on.
+fn fail_pending_responses(pending_responses: &PendingResponses, message: &str) {
+    if let Some(in_flight) = pending_responses.lock().unwrap().take() {
+        for (_, (_, response_sender)) in in_flight.responses {
+            let _ = response_sender.send(Err(channel_failed(message)));
+        }
     }
+}
 
-    fn invoke(&mut self, request: &[u8]) -> Result<Vec<u8>, micro_rpc::Status> {
-        let request_message = self.request_encoder.encode_request(request);
-        let request_message_invocation_id = request_message.invocation_id;
-        self.inner
-            .write_request(request_message)
-            .map_err(|_| micro_rpc::Status::new(micro_rpc::StatusCode::Internal))?;
+fn channel_failed(message: &str) -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(micro_rpc::StatusCode::Internal, message.to_string())
+}
 
-        let (response_message, _) = self
-            .inner
-            .read_response()
-            .map_err(|_| micro_rpc::Status::new(micro_rpc::StatusCode::Internal))?;
+/// Returns the status of a request rejected by the window, which
+/// [`is_channel_busy`] recognizes.
+pub fn channel_busy(err: ChannelBusy) -> micro_rpc::Status {
+    micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::ResourceExhausted,
+        format!("{CHANNEL_BUSY_TAG}{err}"),
+    )
+}
 
-        // For now all messages are sent in sequence, hence we expect that the
-        // id of the next response matches the preceeding request.
-        // TODO(#2848): Allow messages to be sent and received out of order.
-        assert_eq!(request_message_invocation_id, response_message.invocation_id);
+/// Cancels an invocation when dropped, unless its response has arrived.
+struct CancelOnDrop<'a> {
+    connector: &'a Connector,
+    invocation_id: InvocationId,
+}
 
-        Ok(response_message.body)
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        self.connector.cancel(self.invocation_id);
     }
 }
 
 /// Implementation of an [`micro_rpc::AsyncTransport`] that enables client
-/// generated by the micro_rpc to communicate with the [`Connector`] instance
-/// via an MPSC request-response channel.
+/// generated by the micro_rpc to communicate with the [`Connector`] instance.
+///
+/// Dropping an invocation future before it completes cancels the invocation.
 #[derive(Clone)]
 pub struct ConnectorHandle {
-    request_dispatcher:
-        bmrng::unbounded::UnboundedRequestSender<Vec<u8>, Result<Vec<u8>, micro_rpc::Status>>,
+    connector: Arc<Connector>,
 }
 
 #[async_trait::async_trait]
 impl micro_rpc::AsyncTransport for ConnectorHandle {
     type Error = micro_rpc::Status;
     async fn invoke(&mut self, request_bytes: &[u8]) -> Result<Vec<u8>, Self::Error> {
-        self.request_dispatcher.send_receive(request_bytes.to_vec()).await.map_err(|err| {
-            micro_rpc::Status::new_with_message(
-                micro_rpc::StatusCode::Internal,
-                format!("failed when invoking the request_dispatcher: {}", err),
-            )
-        })?
+        let (invocation_id, response_receiver) = self.connector.dispatch(request_bytes)?;
+        let _cancel_on_drop = CancelOnDrop { connector: &self.connector, invocation_id };
+        response_receiver.await.unwrap_or_else(|_| Err(channel_failed("connector stopped")))
+    }
+}
+
+#[async_trait::async_trait]
+impl micro_rpc::AsyncDeadlineTransport for ConnectorHandle {
+    /// Fails with [`micro_rpc::StatusCode::DeadlineExceeded`] on timeout.
+    async fn invoke_with_timeout(
+        &mut self,
+        request_bytes: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Self::Error> {
+        tokio::time::timeout(timeout, self.invoke(request_bytes)).await.unwrap_or_else(|_| {
+            Err(micro_rpc::Status::new_with_message(
+                micro_rpc::StatusCode::DeadlineExceeded,
+                format!("no response within {:?}", timeout),
+            ))
+        })
     }
 }
+
+#[cfg(test)]
+fn spawn_test_connector() -> (ConnectorHandle, oak_channel::server::ServerChannelHandle) {
+    spawn_test_connector_with_limits(WindowLimits::default())
+}
+
+#[cfg(test)]
+fn spawn_test_connector_with_limits(
+    limits: WindowLimits,
+) -> (ConnectorHandle, oak_channel::server::ServerChannelHandle) {
+    let (launcher_socket, guest_socket) = std::os::unix::net::UnixStream::pair().unwrap();
+    let connector = Connector::spawn(
+        Box::new(launcher_socket.try_clone().unwrap()),
+        Box::new(launcher_socket),
+        limits,
+    );
+    (connector, oak_channel::server::ServerChannelHandle::new(Box::new(guest_socket)))
+}
+
+#[tokio::test]
+async fn test_responses_are_routed_out_of_order() {
+    let (connector, mut guest) = spawn_test_connector();
+    // The guest answers the second request before the first one.
+    let guest = thread::spawn(move || {
+        let (first, _) = guest.read_request().unwrap();
+        let (second, _) = guest.read_request().unwrap();
+        for request in [second, first] {
+            guest
+                .write_response(oak_channel::message::ResponseMessage {
+                    invocation_id: request.invocation_id,
+                    body: request.body.iter().rev().copied().collect(),
+                })
+                .unwrap();
+        }
+    });
+
+    let (mut first_handle, mut second_handle) = (connector.clone(), connector.clone());
+    let (first, second) =
+        tokio::join!(first_handle.invoke(b"first"), second_handle.invoke(b"second"));
+    assert_eq!(first.unwrap(), b"tsrif");
+    assert_eq!(second.unwrap(), b"dnoces");
+    guest.join().unwrap();
+}
+
+#[tokio::test]
+async fn test_pending_requests_fail_when_reading_fails() {
+    let (mut connector, mut guest) = spawn_test_connector();
+    // The guest exits without answering, which ends the reader thread.
+    let guest = thread::spawn(move || {
+        guest.read_request().unwrap();
+    });
+
+    let status = connector.invoke(b"request").await.unwrap_err();
+    assert_eq!(status.code, micro_rpc::StatusCode::Internal);
+    guest.join().unwrap();
+    // Requests dispatched after the failure fail straight away.
+    let status = connector.invoke(b"request").await.unwrap_err();
+    assert_eq!(status.code, micro_rpc::StatusCode::Internal);
+}
+
+#[tokio::test]
+async fn test_requests_beyond_the_window_are_channel_busy() {
+    let (connector, mut guest) =
+        spawn_test_connector_with_limits(WindowLimits { max_requests: 1, max_bytes: 1 << 20 });
+    let (rejected_sender, rejected_receiver) = std::sync::mpsc::channel();
+    // The guest holds the first request until the second one has been rejected.
+    let guest = thread::spawn(move || {
+        let (request, _) = guest.read_request().unwrap();
+        rejected_receiver.recv().unwrap();
+        guest
+            .write_response(oak_channel::message::ResponseMessage {
+                invocation_id: request.invocation_id,
+                body: request.body,
+            })
+            .unwrap();
+    });
+
+    let mut first_handle = connector.clone();
+    let first = tokio::spawn(async move { first_handle.invoke(b"first").await });
+    // Wait for the first request to take the only slot of the window.
+    while connector
+        .connector
+        .pending_responses
+        .lock()
+        .unwrap()
+        .as_ref()
+        .unwrap()
+        .responses
+        .is_empty()
+    {
+        tokio::task::yield_now().await;
+    }
+    let second = connector.clone().invoke(b"second").await.unwrap_err();
+    assert_eq!(second.code, micro_rpc::StatusCode::ResourceExhausted);
+    assert!(is_channel_busy(&second));
+    rejected_sender.send(()).unwrap();
+
+    assert_eq!(first.await.unwrap().unwrap(), b"first");
+    guest.join().unwrap();
+}
+
+#[test]
+fn test_runtime_resource_exhausted_is_not_channel_busy() {
+    assert!(!is_channel_busy(&micro_rpc::Status::new_with_message(
+        micro_rpc::StatusCode::ResourceExhausted,
+        "memory budget exceeded",
+    )));
+}
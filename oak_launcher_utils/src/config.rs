@@ -0,0 +1,504 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Settings of launchers from a configuration file and from environment
+//! variables, in addition to their flags.
+//!
+//! Settings are named after the flags that set them, e.g. `memory-size` for
+//! `--memory-size`, so that every flag can be set in all three ways and they
+//! are validated the same way. Flags override environment variables, which
+//! override the configuration file. If the launcher has subcommands, the
+//! settings of the subcommand given on the command line can be set as well,
+//! and those of the other subcommands are ignored.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{error::ErrorKind, Arg, ArgAction, Command, Parser};
+use serde_json::{Map, Value};
+
+/// The flag of the configuration file, to be flattened into the arguments of
+/// launchers that are parsed with [`parse`].
+#[derive(Parser, Clone, Debug, PartialEq)]
+pub struct ConfigParams {
+    /// Path to a TOML, YAML or JSON file holding settings of the launcher,
+    /// named after their flags, e.g. `memory-size = "256M"`. Settings can be
+    /// grouped in tables by their common prefix, e.g. `update-interval` in a
+    /// `lookup-data` table sets `--lookup-data-update-interval`. Boolean flags
+    /// are set with `true` or `false`, and repeated flags with arrays.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+/// Name of the flag of [`ConfigParams`].
+const CONFIG_FLAG: &str = "config";
+
+/// Parses the arguments of the process like [`Parser::parse`], after adding
+/// the settings from the file given with `--config`, and from environment
+/// variables named after the flags with `env_prefix`, e.g.
+/// `OAK_FUNCTIONS_LAUNCHER_MEMORY_SIZE` for `--memory-size`. Environment
+/// variables of boolean flags are `true` or `false`, and environment variables
+/// of repeated flags only hold one value. The configuration file can be given
+/// with an environment variable as well.
+///
+/// Exits with an error message if a setting is unknown or invalid.
+pub fn parse<P: Parser>(env_prefix: &str) -> P {
+    let mut command = P::command();
+    match merge_settings(&command, env_prefix, std::env::args_os().collect()) {
+        Ok(args) => P::parse_from(args),
+        Err(err) => command.error(ErrorKind::InvalidValue, format!("{:#}", err)).exit(),
+    }
+}
+
+// Returns `args` with the settings from the configuration file and the
+// environment inserted before the flags, unless the flags override them. The
+// settings of the subcommand given in `args`, if any, are inserted after it.
+fn merge_settings(
+    command: &Command,
+    env_prefix: &str,
+    args: Vec<OsString>,
+) -> Result<Vec<OsString>> {
+    let mut args = args.into_iter();
+    let program = args.next().unwrap_or_default();
+    let mut flags: Vec<OsString> = args.collect();
+    let subcommand_flags = flags
+        .iter()
+        .position(|flag| {
+            command.get_subcommands().any(|subcommand| flag.to_str() == Some(subcommand.get_name()))
+        })
+        .map(|index| flags.split_off(index));
+    let mut settings = Settings::new(command);
+    let mut subcommand_settings = subcommand_flags.as_ref().map(|subcommand_flags| {
+        let name = subcommand_flags[0].to_str().unwrap_or_default();
+        Settings::new(command.find_subcommand(name).expect("subcommand not found"))
+    });
+
+    let config_path = flag_value(command, &flags, CONFIG_FLAG)
+        .or_else(|| std::env::var_os(env_variable(env_prefix, CONFIG_FLAG)).map(PathBuf::from));
+    if let Some(path) = config_path {
+        let mut values = Vec::new();
+        flatten_table(None, read_config(&path)?, &mut values);
+        for (name, value) in values {
+            let target = if settings.arguments.contains_key(name.as_str()) {
+                &mut settings
+            } else if subcommand_settings
+                .as_ref()
+                .map_or(true, |settings| !settings.arguments.contains_key(name.as_str()))
+                && is_subcommand_setting(command, &name)
+            {
+                // Settings of other subcommands are ignored, so that one file can
+                // hold the settings of all of them.
+                continue;
+            } else {
+                subcommand_settings
+                    .as_mut()
+                    .filter(|settings| settings.arguments.contains_key(name.as_str()))
+                    .with_context(|| {
+                        format!(
+                            "unknown setting `{}` in configuration file {}",
+                            name,
+                            path.display()
+                        )
+                    })?
+            };
+            target
+                .add_config(name, value)
+                .with_context(|| format!("invalid configuration file {}", path.display()))?;
+        }
+    }
+    settings.add_env(env_prefix)?;
+    settings.remove_flags(&flags);
+
+    let mut args: Vec<OsString> =
+        std::iter::once(program).chain(settings.into_args()).chain(flags).collect();
+    if let (Some(mut subcommand_settings), Some(subcommand_flags)) =
+        (subcommand_settings, subcommand_flags)
+    {
+        subcommand_settings.add_env(env_prefix)?;
+        subcommand_settings.remove_flags(&subcommand_flags[1..]);
+        let mut subcommand_flags = subcommand_flags.into_iter();
+        args.extend(subcommand_flags.next());
+        args.extend(subcommand_settings.into_args());
+        args.extend(subcommand_flags);
+    }
+    Ok(args)
+}
+
+// The settings of a command, i.e. of the launcher or of the subcommand that
+// selects its flavor.
+struct Settings<'a> {
+    arguments: BTreeMap<&'a str, &'a Arg>,
+    // The arguments for each setting, from the lowest to the highest precedence.
+    values: BTreeMap<String, Vec<OsString>>,
+}
+
+impl<'a> Settings<'a> {
+    fn new(command: &'a Command) -> Self {
+        let arguments = command
+            .get_arguments()
+            .filter_map(|arg| arg.get_long().map(|long| (long, arg)))
+            .filter(|(long, _)| *long != CONFIG_FLAG)
+            .collect();
+        Self { arguments, values: BTreeMap::new() }
+    }
+
+    fn add_config(&mut self, name: String, value: Value) -> Result<()> {
+        if self.values.contains_key(&name) {
+            bail!("`{}` is set more than once", name);
+        }
+        let setting_args = config_args(self.arguments[name.as_str()], &name, value)?;
+        self.values.insert(name, setting_args);
+        Ok(())
+    }
+
+    fn add_env(&mut self, env_prefix: &str) -> Result<()> {
+        for (name, arg) in &self.arguments {
+            let variable = env_variable(env_prefix, name);
+            if let Some(value) = std::env::var_os(&variable) {
+                let setting_args = env_args(arg, name, value)
+                    .with_context(|| format!("invalid environment variable {}", variable))?;
+                self.values.insert(name.to_string(), setting_args);
+            }
+        }
+        Ok(())
+    }
+
+    // Drops the settings that are overridden by `flags`.
+    fn remove_flags(&mut self, flags: &[OsString]) {
+        let arguments: Vec<&Arg> = self.arguments.values().copied().collect();
+        for flag in flags {
+            let Some(flag) = flag.to_str() else {
+                continue;
+            };
+            if flag == "--" {
+                break;
+            }
+            for (arg, _) in named_args(&arguments, flag) {
+                if let Some(long) = arg.get_long() {
+                    self.values.remove(long);
+                }
+            }
+        }
+    }
+
+    fn into_args(self) -> impl Iterator<Item = OsString> {
+        self.values.into_values().flatten()
+    }
+}
+
+// Returns whether `name` is the name of a setting of any subcommand of
+// `command`.
+fn is_subcommand_setting(command: &Command, name: &str) -> bool {
+    command
+        .get_subcommands()
+        .flat_map(Command::get_arguments)
+        .any(|arg| arg.get_long() == Some(name))
+}
+
+// Returns the value of `--{name}`, or of its short flag, in `flags`, if set.
+fn flag_value(command: &Command, flags: &[OsString], name: &str) -> Option<PathBuf> {
+    let arguments: Vec<&Arg> = command.get_arguments().collect();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let Some(flag) = flag.to_str() else {
+            continue;
+        };
+        if flag == "--" {
+            break;
+        }
+        if let Some((_, value)) =
+            named_args(&arguments, flag).into_iter().find(|(arg, _)| arg.get_long() == Some(name))
+        {
+            return match value {
+                Some(value) => Some(PathBuf::from(value)),
+                None => flags.next().map(PathBuf::from),
+            };
+        }
+    }
+    None
+}
+
+// Returns the arguments among `arguments` that `flag` sets, each with the value
+// given in `flag` itself, if any, e.g. `256M` in `--memory-size=256M`. Short
+// flags may be grouped, e.g. `-vc FILE`, and the first of them that takes a
+// value takes the rest of the group as its value, e.g. `FILE` in `-cFILE`.
+fn named_args<'a, 'f>(arguments: &[&'a Arg], flag: &'f str) -> Vec<(&'a Arg, Option<&'f str>)> {
+    if let Some(long) = flag.strip_prefix("--") {
+        let (name, value) = match long.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (long, None),
+        };
+        return arguments
+            .iter()
+            .filter(|arg| arg.get_long() == Some(name))
+            .map(|arg| (*arg, value))
+            .collect();
+    }
+    let Some(shorts) = flag.strip_prefix('-') else {
+        return Vec::new();
+    };
+    let mut named = Vec::new();
+    for (index, short) in shorts.char_indices() {
+        let Some(arg) = arguments.iter().find(|arg| arg.get_short() == Some(short)) else {
+            break;
+        };
+        if matches!(arg.get_action(), ArgAction::Set | ArgAction::Append) {
+            let value = &shorts[index + short.len_utf8()..];
+            let value = value.strip_prefix('=').unwrap_or(value);
+            named.push((*arg, (!value.is_empty()).then_some(value)));
+            break;
+        }
+        named.push((*arg, None));
+    }
+    named
+}
+
+fn env_variable(env_prefix: &str, name: &str) -> String {
+    format!("{}_{}", env_prefix, name.to_uppercase().replace('-', "_"))
+}
+
+// Reads the configuration file at `path`, whose format is given by its
+// extension.
+fn read_config(path: &Path) -> Result<Map<String, Value>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("couldn't read configuration file {}", path.display()))?;
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    let config: Value = match extension {
+        "toml" => toml::from_str(&contents).map_err(anyhow::Error::from),
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(anyhow::Error::from),
+        "json" => serde_json::from_str(&contents).map_err(anyhow::Error::from),
+        _ => bail!(
+            "configuration file {} must have a .toml, .yaml, .yml or .json extension",
+            path.display()
+        ),
+    }
+    .with_context(|| format!("couldn't parse configuration file {}", path.display()))?;
+    match config {
+        Value::Object(table) => Ok(table),
+        _ => bail!("configuration file {} must hold a table of settings", path.display()),
+    }
+}
+
+// Collects the settings of `table`, prefixing the names of settings in nested
+// tables with the names of the tables. Underscores are accepted in place of
+// hyphens in each key.
+fn flatten_table(
+    prefix: Option<&str>,
+    table: Map<String, Value>,
+    settings: &mut Vec<(String, Value)>,
+) {
+    for (key, value) in table {
+        let key = key.replace('_', "-");
+        let name = match prefix {
+            Some(prefix) => format!("{}-{}", prefix, key),
+            None => key,
+        };
+        match value {
+            Value::Object(table) => flatten_table(Some(&name), table, settings),
+            value => settings.push((name, value)),
+        }
+    }
+}
+
+// Returns the flags that set `value` for `arg`, checking that the value has
+// the right type for it.
+fn config_args(arg: &Arg, name: &str, value: Value) -> Result<Vec<OsString>> {
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Bool(true)) => Ok(vec![format!("--{}", name).into()]),
+        (ArgAction::SetTrue, Value::Bool(false)) => Ok(Vec::new()),
+        (ArgAction::SetTrue, _) => bail!("`{}` must be true or false", name),
+        (ArgAction::Append, Value::Array(values)) => {
+            values.iter().map(|value| Ok(flag_with_value(name, scalar(name, value)?))).collect()
+        }
+        (_, Value::Array(_)) => bail!("`{}` can't have several values", name),
+        (_, value) => Ok(vec![flag_with_value(name, scalar(name, &value)?)]),
+    }
+}
+
+fn env_args(arg: &Arg, name: &str, value: OsString) -> Result<Vec<OsString>> {
+    match arg.get_action() {
+        ArgAction::SetTrue => match value.to_str() {
+            Some("true" | "1") => Ok(vec![format!("--{}", name).into()]),
+            Some("false" | "0") => Ok(Vec::new()),
+            _ => bail!("`{}` must be true or false", name),
+        },
+        _ => {
+            let mut flag = OsString::from(format!("--{}=", name));
+            flag.push(value);
+            Ok(vec![flag])
+        }
+    }
+}
+
+fn scalar(name: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        _ => bail!("`{}` must be a string, a number or a boolean", name),
+    }
+}
+
+// Passes the value in the same argument as the flag, so that values starting
+// with a hyphen aren't taken for flags.
+fn flag_with_value(name: &str, value: String) -> OsString {
+    format!("--{}={}", name, value).into()
+}
+
+#[cfg(test)]
+fn test_command() -> Command {
+    Command::new("launcher")
+        .arg(Arg::new(CONFIG_FLAG).long(CONFIG_FLAG).short('c'))
+        .arg(Arg::new("memory-size").long("memory-size").short('m'))
+        .arg(Arg::new("verbose").long("verbose").short('v').action(ArgAction::SetTrue))
+        .arg(Arg::new("kernel-arg").long("kernel-arg").action(ArgAction::Append))
+        .arg(Arg::new("lookup-data-update-interval").long("lookup-data-update-interval"))
+}
+
+// Merges the settings of `config`, written to a file with `extension`, and of
+// the environment into `flags`, with `{path}` in `flags` replaced by the path
+// of the file.
+#[cfg(test)]
+fn merge_test_settings(
+    env_prefix: &str,
+    extension: &str,
+    config: &str,
+    flags: &[&str],
+) -> Result<Vec<String>> {
+    let file = tempfile::Builder::new().suffix(&format!(".{}", extension)).tempfile().unwrap();
+    fs::write(file.path(), config).unwrap();
+    let path = file.path().to_str().unwrap();
+    let args = std::iter::once("launcher")
+        .chain(flags.iter().copied())
+        .map(|flag| flag.replace("{path}", path).into())
+        .collect();
+    Ok(merge_settings(&test_command(), env_prefix, args)?
+        .into_iter()
+        .map(|arg| arg.into_string().unwrap().replace(path, "{path}"))
+        .collect())
+}
+
+#[test]
+fn test_flags_override_environment_which_overrides_file() {
+    let config = r#"
+        memory-size = "1G"
+        kernel-arg = ["console=ttyS0", "quiet"]
+    "#;
+    let merge =
+        |flags: &[&str]| merge_test_settings("CONFIG_TEST_PRECEDENCE", "toml", config, flags);
+    assert_eq!(
+        merge(&["--config", "{path}"]).unwrap(),
+        [
+            "launcher",
+            "--kernel-arg=console=ttyS0",
+            "--kernel-arg=quiet",
+            "--memory-size=1G",
+            "--config",
+            "{path}"
+        ]
+    );
+
+    std::env::set_var("CONFIG_TEST_PRECEDENCE_MEMORY_SIZE", "2G");
+    std::env::set_var("CONFIG_TEST_PRECEDENCE_VERBOSE", "true");
+    assert_eq!(
+        merge(&["--config", "{path}"]).unwrap(),
+        [
+            "launcher",
+            "--kernel-arg=console=ttyS0",
+            "--kernel-arg=quiet",
+            "--memory-size=2G",
+            "--verbose",
+            "--config",
+            "{path}"
+        ]
+    );
+    assert_eq!(
+        merge(&["--config", "{path}", "--memory-size=3G", "--kernel-arg", "nokaslr"]).unwrap(),
+        [
+            "launcher",
+            "--verbose",
+            "--config",
+            "{path}",
+            "--memory-size=3G",
+            "--kernel-arg",
+            "nokaslr"
+        ]
+    );
+}
+
+#[test]
+fn test_short_flags() {
+    let merge = |flags: &[&str]| {
+        merge_test_settings("CONFIG_TEST_SHORT_FLAGS", "toml", "memory-size = \"1G\"", flags)
+    };
+    for flags in [
+        &["-c", "{path}"][..],
+        &["-c{path}"],
+        &["-c={path}"],
+        &["-vc", "{path}"],
+        &["--config={path}"],
+    ] {
+        let mut expected = vec!["launcher", "--memory-size=1G"];
+        expected.extend(flags);
+        assert_eq!(merge(flags).unwrap(), expected, "flags {:?}", flags);
+    }
+
+    // Short flags override the settings too, alone or grouped.
+    for flags in
+        [&["-c", "{path}", "-m", "2G"][..], &["-c", "{path}", "-m2G"], &["-vm2G", "-c{path}"]]
+    {
+        let mut expected = vec!["launcher"];
+        expected.extend(flags);
+        assert_eq!(merge(flags).unwrap(), expected, "flags {:?}", flags);
+    }
+}
+
+#[test]
+fn test_nested_tables() {
+    let expected = ["launcher", "--lookup-data-update-interval=10s", "--memory-size=1G"];
+    for (extension, config) in [
+        ("toml", "memory_size = \"1G\"\n[lookup_data]\nupdate_interval = \"10s\""),
+        ("toml", "memory-size = \"1G\"\n[lookup.data]\nupdate-interval = \"10s\""),
+        ("yaml", "memory-size: 1G\nlookup-data:\n  update_interval: 10s"),
+        ("json", r#"{"memory-size": "1G", "lookup_data": {"update-interval": "10s"}}"#),
+    ] {
+        let merged =
+            merge_test_settings("CONFIG_TEST_NESTED", extension, config, &["-c", "{path}"])
+                .unwrap();
+        assert_eq!(merged[..3], expected, "configuration {:?}", config);
+    }
+}
+
+#[test]
+fn test_bad_keys() {
+    for (config, message) in [
+        ("memroy-size = \"1G\"", "unknown setting `memroy-size`"),
+        ("[lookup_data]\nrefresh = \"10s\"", "unknown setting `lookup-data-refresh`"),
+        ("memory-size = \"1G\"\nmemory_size = \"2G\"", "`memory-size` is set more than once"),
+        ("verbose = \"yes\"", "`verbose` must be true or false"),
+        ("memory-size = [\"1G\", \"2G\"]", "`memory-size` can't have several values"),
+        ("kernel-arg = [[\"quiet\"]]", "`kernel-arg` must be a string, a number or a boolean"),
+    ] {
+        let err = merge_test_settings("CONFIG_TEST_BAD_KEYS", "toml", config, &["-c", "{path}"])
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains(message), "{:#} doesn't mention {}", err, message);
+    }
+}
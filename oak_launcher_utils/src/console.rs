@@ -0,0 +1,215 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Capture of the console output of guest instances.
+//!
+//! Every line the guest writes to its console is tagged with a timestamp and
+//! the id of the guest, logged, made available to subscribers as a stream and
+//! optionally appended to a log file that is rotated once it grows too large.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::broadcast,
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::error::LauncherError;
+
+/// How many lines a slow subscriber may fall behind before it misses lines.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Settings of the console log file.
+#[derive(Parser, Clone, Debug, PartialEq)]
+pub struct ConsoleLogParams {
+    /// File to also write the guest console output to.
+    #[arg(long)]
+    pub console_log_file: Option<PathBuf>,
+
+    /// Size in bytes beyond which the console log file is rotated.
+    #[arg(long, default_value_t = 10 << 20)]
+    pub console_log_max_size: u64,
+
+    /// Number of rotated console log files to keep, as `<file>.1` (the most
+    /// recent) to `<file>.<n>`.
+    #[arg(long, default_value_t = 5)]
+    pub console_log_max_files: usize,
+}
+
+impl Default for ConsoleLogParams {
+    fn default() -> Self {
+        Self { console_log_file: None, console_log_max_size: 10 << 20, console_log_max_files: 5 }
+    }
+}
+
+/// A line written by a guest to its console.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsoleLine {
+    /// When the launcher received the line.
+    pub timestamp: SystemTime,
+    /// Id of the guest instance, unique within the launcher process.
+    pub guest_id: u32,
+    pub text: String,
+}
+
+impl fmt::Display for ConsoleLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} guest-{}: {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            self.guest_id,
+            self.text
+        )
+    }
+}
+
+/// A stream of the console lines of a guest instance.
+pub type ConsoleStream = Pin<Box<dyn Stream<Item = ConsoleLine> + Send>>;
+
+/// The console output of a guest instance.
+pub struct ConsoleLog {
+    lines: broadcast::Sender<ConsoleLine>,
+}
+
+impl ConsoleLog {
+    /// Spawns a task that reads the console output of the guest from `console`
+    /// until the guest closes it.
+    pub fn capture(
+        console: std::os::unix::net::UnixStream,
+        guest_id: u32,
+        params: &ConsoleLogParams,
+    ) -> Result<Self, LauncherError> {
+        let console = console
+            .set_nonblocking(true)
+            .and_then(|()| tokio::net::UnixStream::from_std(console))
+            .map_err(|err| LauncherError::io("couldn't capture guest console", err))?;
+        let mut file = params
+            .console_log_file
+            .as_ref()
+            .map(|path| {
+                RotatingFile::open(path, params.console_log_max_size, params.console_log_max_files)
+            })
+            .transpose()?;
+        let (lines, _) = broadcast::channel(SUBSCRIBER_CAPACITY);
+        let sender = lines.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(console).lines();
+            loop {
+                let text = match reader.next_line().await {
+                    Ok(Some(text)) => text,
+                    Ok(None) => break,
+                    Err(err) => {
+                        log::warn!("couldn't read guest console: {:?}", err);
+                        break;
+                    }
+                };
+                log::info!("console: {:?}", text);
+                let line = ConsoleLine { timestamp: SystemTime::now(), guest_id, text };
+                if let Some(file) = file.as_mut() {
+                    if let Err(err) = file.write_line(&line.to_string()) {
+                        log::warn!("couldn't write console log file: {:?}", err);
+                    }
+                }
+                // There may be no subscribers.
+                let _ = sender.send(line);
+            }
+        });
+        Ok(Self { lines })
+    }
+
+    /// Returns a stream of the lines written from now on. Lines are skipped if
+    /// the stream isn't consumed fast enough.
+    pub fn subscribe(&self) -> ConsoleStream {
+        Box::pin(BroadcastStream::new(self.lines.subscribe()).filter_map(Result::ok))
+    }
+}
+
+/// A log file that is rotated once it grows beyond a maximum size.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> Result<Self, LauncherError> {
+        let (size, file) = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|file| Ok((file.metadata()?.len(), file)))
+            .map_err(|err| {
+                LauncherError::io(format!("couldn't open console log file {}", path.display()), err)
+            })?;
+        Ok(Self { path: path.to_path_buf(), max_size, max_files, file, size })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 >= self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // Shifts `<path>.<n>` to `<path>.<n + 1>`, dropping the oldest file, and
+    // starts a new file at `path`.
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+#[tokio::test]
+async fn test_capture_reports_io_errors() {
+    let (_, console) = std::os::unix::net::UnixStream::pair().unwrap();
+    let params = ConsoleLogParams {
+        console_log_file: Some("/nonexistent/console.log".into()),
+        ..Default::default()
+    };
+    assert!(matches!(ConsoleLog::capture(console, 0, &params), Err(LauncherError::Io { .. })));
+}
@@ -0,0 +1,80 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Errors returned by the public APIs of the launchers, so that binaries
+//! embedding them can tell which step of launching a guest instance failed.
+
+/// Why a guest instance couldn't be launched or set up. Every variant holds
+/// the underlying error, which is included in its message.
+#[derive(Debug, thiserror::Error)]
+pub enum LauncherError {
+    /// The arguments or the configuration of the launcher were invalid, e.g. a
+    /// required flag was missing or a policy couldn't be parsed.
+    #[error("invalid configuration: {0:#}")]
+    Config(anyhow::Error),
+    /// A local file or socket couldn't be used, e.g. the Wasm module couldn't
+    /// be read.
+    #[error("{context}: {error}")]
+    Io { context: String, error: std::io::Error },
+    /// The VMM couldn't be started.
+    #[error("couldn't start VMM: {0:#}")]
+    Vmm(anyhow::Error),
+    /// The communication channel with the guest couldn't be established.
+    #[error("couldn't connect to guest instance: {0:#}")]
+    Channel(anyhow::Error),
+    /// The enclave rejected or failed a request, e.g. to initialize the
+    /// application or to load its lookup data.
+    #[error("enclave error: {0:#}")]
+    Enclave(anyhow::Error),
+    /// The evidence of the enclave was missing or wasn't accepted, or secrets
+    /// couldn't be provisioned with it.
+    #[error("attestation error: {0:#}")]
+    Attestation(anyhow::Error),
+}
+
+impl LauncherError {
+    /// Returns a [`LauncherError::Io`] error, described by `context`.
+    pub fn io(context: impl Into<String>, error: std::io::Error) -> Self {
+        LauncherError::Io { context: context.into(), error }
+    }
+}
+
+#[test]
+fn test_messages_include_the_underlying_error() {
+    let error = LauncherError::io(
+        "couldn't read Wasm file",
+        std::io::Error::from(std::io::ErrorKind::NotFound),
+    );
+    assert_eq!(error.to_string(), "couldn't read Wasm file: entity not found");
+
+    let underlying = || anyhow::anyhow!("refused").context("couldn't connect");
+    for (error, message) in [
+        (LauncherError::Config(underlying()), "invalid configuration"),
+        (LauncherError::Vmm(underlying()), "couldn't start VMM"),
+        (LauncherError::Channel(underlying()), "couldn't connect to guest instance"),
+        (LauncherError::Enclave(underlying()), "enclave error"),
+        (LauncherError::Attestation(underlying()), "attestation error"),
+    ] {
+        assert_eq!(error.to_string(), format!("{}: couldn't connect: refused", message));
+    }
+}
+
+#[test]
+fn test_variant_survives_anyhow() {
+    let error = anyhow::Error::from(LauncherError::Attestation(anyhow::anyhow!("rejected")))
+        .context("couldn't provision secrets");
+    assert!(matches!(error.downcast::<LauncherError>(), Ok(LauncherError::Attestation(_))));
+}
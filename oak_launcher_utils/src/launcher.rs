@@ -16,23 +16,39 @@
 
 use std::{
     fs,
-    io::{BufRead, BufReader},
     net::Shutdown,
     os::{
-        fd::AsRawFd,
+        fd::{AsRawFd, OwnedFd},
         unix::{net, net::UnixStream},
     },
     path::PathBuf,
     process::Stdio,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use clap::Parser;
 use command_fds::CommandFdExt;
 use log::info;
-
-use crate::channel::{Connector, ConnectorHandle};
+use oak_restricted_kernel_interface::initial_data::{
+    encode_application_config, encode_bundle, NamedApplication,
+};
+use serde_json::{json, Value};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    channel::{ChannelParams, ChannelTransport, Connector, ConnectorHandle, GUEST_VSOCK_PORT},
+    console::{ConsoleLog, ConsoleLogParams, ConsoleStream},
+    error::LauncherError,
+    qmp::QmpClient,
+    snp::SnpParams,
+    vmm::{ChannelDevice, VmmType},
+};
 
 /// Represents parameters used for launching VM instances.
 #[derive(Parser, Clone, Debug, PartialEq)]
@@ -41,6 +57,23 @@ pub struct Params {
     #[arg(long, value_parser = path_exists)]
     pub vmm_binary: PathBuf,
 
+    /// Which VMM `--vmm-binary` is.
+    #[arg(long, value_enum, default_value_t = VmmType::Qemu)]
+    pub vmm: VmmType,
+
+    /// Launch the guest as an AMD SEV-SNP confidential VM.
+    #[arg(long)]
+    pub sev_snp: bool,
+
+    #[clap(flatten)]
+    pub snp: SnpParams,
+
+    #[clap(flatten)]
+    pub console_log: ConsoleLogParams,
+
+    #[clap(flatten)]
+    pub channel: ChannelParams,
+
     /// Path to the enclave binary to load into the VM.
     #[arg(long, value_parser = path_exists)]
     pub kernel: PathBuf,
@@ -50,6 +83,27 @@ pub struct Params {
     #[arg(long, value_parser = path_exists)]
     pub app_binary: Option<PathBuf>,
 
+    /// Named application binaries to send to the enclave as a bundle instead of
+    /// `--app-binary`, in `NAME=PATH` form. The enclave runs the one named by
+    /// `--app-selector`.
+    #[arg(
+        long,
+        value_parser = parse_bundle_entry,
+        conflicts_with = "app_binary",
+        requires = "app_selector"
+    )]
+    pub app_bundle: Vec<(String, PathBuf)>,
+
+    /// Name of the application in `--app-bundle` to run.
+    #[arg(long, requires = "app_bundle")]
+    pub app_selector: Option<String>,
+
+    /// The serialized config of the application, which the enclave measures
+    /// into its attestation evidence next to the application binary. Empty if
+    /// the application has no config.
+    #[clap(skip)]
+    pub application_config: Vec<u8>,
+
     /// Path to the BIOS image to use.
     #[arg(long, value_parser = path_exists)]
     pub bios_binary: PathBuf,
@@ -63,9 +117,138 @@ pub struct Params {
     #[arg(long)]
     pub memory_size: Option<String>,
 
+    /// How many vCPUs to give to the VM.
+    #[arg(long, default_value_t = 1)]
+    pub num_cpus: u8,
+
+    /// CPU model and flags to expose to the guest, in QEMU's `-cpu` syntax. The
+    /// model must support RDRAND, which remote attestation requires.
+    #[arg(long, default_value = DEFAULT_CPU_MODEL)]
+    pub cpu_model: String,
+
+    /// Add a virtio memory balloon to the guest, so that its memory can be
+    /// resized while it runs with [`GuestInstance::set_memory_target`].
+    #[arg(long)]
+    pub memory_balloon: bool,
+
+    /// Number of NUMA nodes to split the VM into. The vCPUs and memory are
+    /// divided evenly between the nodes, so `--memory-size` must be set.
+    #[arg(long, default_value_t = 1)]
+    pub numa_nodes: u8,
+
     /// Path to the initrd image to use.
     #[arg(long, value_parser = path_exists, requires_all = &["kernel"])]
     pub initrd: PathBuf,
+
+    /// Extra parameter to append to the kernel command line, which the VMM
+    /// passes to the guest via fw_cfg, e.g. `quiet`. Can be repeated.
+    #[arg(long = "kernel-arg")]
+    pub kernel_args: Vec<String>,
+}
+
+/// CPU model exposed to the guest by default. Needed to expose advanced CPU
+/// features, specifically RDRAND, which is required for remote attestation.
+pub const DEFAULT_CPU_MODEL: &str = "IvyBridge-IBRS,enforce";
+
+impl Params {
+    /// Checks that the requested guest resources are consistent with each
+    /// other and available on the host.
+    pub fn validate(&self) -> Result<()> {
+        let host_cpus = std::thread::available_parallelism()
+            .context("couldn't get the number of host CPUs")?
+            .get();
+        if self.num_cpus == 0 || usize::from(self.num_cpus) > host_cpus {
+            bail!("invalid vCPU count {}: the host has {} CPUs", self.num_cpus, host_cpus);
+        }
+
+        let memory_size = self.memory_size.as_deref().map(parse_memory_size).transpose()?;
+        if let (Some(memory_size), Some(host_memory)) = (memory_size, host_memory_size()?) {
+            if memory_size > host_memory {
+                bail!(
+                    "invalid memory size {}: the host has {} bytes of memory",
+                    self.memory_size.as_deref().unwrap_or_default(),
+                    host_memory
+                );
+            }
+        }
+
+        if self.numa_nodes == 0 || self.num_cpus % self.numa_nodes != 0 {
+            bail!(
+                "invalid NUMA node count {}: the {} vCPUs must be divided evenly between the nodes",
+                self.numa_nodes,
+                self.num_cpus
+            );
+        }
+        if self.numa_nodes > 1 {
+            let memory_size = memory_size.context("the memory size must be set to use NUMA")?;
+            if memory_size % u64::from(self.numa_nodes) != 0 {
+                bail!("the memory size must be divided evenly between the NUMA nodes");
+            }
+        }
+        if self.memory_balloon && self.sev_snp {
+            bail!("the memory balloon isn't supported for SEV-SNP guests");
+        }
+
+        for (index, (name, _)) in self.app_bundle.iter().enumerate() {
+            if self.app_bundle[..index].iter().any(|(other, _)| other == name) {
+                bail!("duplicate application {} in the bundle", name);
+            }
+        }
+        if let Some(selector) = &self.app_selector {
+            if !self.app_bundle.iter().any(|(name, _)| name == selector) {
+                bail!("selected application {} isn't in the bundle", selector);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the kernel command line, which includes the arguments the
+    /// channel transport needs.
+    pub fn kernel_command_line(&self) -> String {
+        let mut args: Vec<&str> = self.kernel_args.iter().map(String::as_str).collect();
+        args.extend(self.channel.channel_transport.kernel_arg());
+        args.join(" ")
+    }
+}
+
+/// Parses a memory size in QEMU's `-m` syntax, e.g. `256M`, into bytes. Sizes
+/// without a suffix are in megabytes.
+pub(crate) fn parse_memory_size(size: &str) -> Result<u64> {
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, "M"),
+    };
+    let shift = match unit {
+        "B" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => bail!("invalid memory size {}", size),
+    };
+    let number: u64 = number.parse().with_context(|| format!("invalid memory size {}", size))?;
+    number
+        .checked_shl(shift)
+        .filter(|bytes| bytes >> shift == number)
+        .context("memory size too large")
+}
+
+/// Returns the total memory of the host in bytes, if known.
+fn host_memory_size() -> Result<Option<u64>> {
+    let Ok(meminfo) = fs::read_to_string("/proc/meminfo") else {
+        return Ok(None);
+    };
+    let Some(line) = meminfo.lines().find(|line| line.starts_with("MemTotal:")) else {
+        return Ok(None);
+    };
+    let kilobytes: u64 = line
+        .trim_start_matches("MemTotal:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .context("couldn't parse the host memory size")?;
+    Ok(Some(kilobytes << 10))
 }
 
 /// Checks if file with a given path exists.
@@ -78,33 +261,112 @@ fn path_exists(s: &str) -> Result<PathBuf, String> {
     }
 }
 
+/// Parses an `--app-bundle` entry of the form `NAME=PATH`.
+fn parse_bundle_entry(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid bundle entry {}: expected NAME=PATH", s))?;
+    Ok((name.to_string(), path_exists(path)?))
+}
+
+/// Reads the application binaries and config to send to the enclave, if any,
+/// as the initial payload the enclave expects.
+fn read_initial_data(params: &Params) -> Result<Option<Vec<u8>>> {
+    let Some(payload) = read_applications(params)? else {
+        if !params.application_config.is_empty() {
+            bail!("an application config requires an application binary");
+        }
+        return Ok(None);
+    };
+    if params.application_config.is_empty() {
+        return Ok(Some(payload));
+    }
+    Ok(Some(encode_application_config(&params.application_config, &payload)))
+}
+
+fn read_applications(params: &Params) -> Result<Option<Vec<u8>>> {
+    let read = |path: &PathBuf| {
+        let bytes = fs::read(path)
+            .with_context(|| format!("couldn't read application binary {}", path.display()))?;
+        log::info!("read application binary from disk {} ({} bytes)", path.display(), bytes.len());
+        Ok::<_, anyhow::Error>(bytes)
+    };
+    if let Some(app_binary) = &params.app_binary {
+        return read(app_binary).map(Some);
+    }
+    let Some(selector) = &params.app_selector else {
+        return Ok(None);
+    };
+    let binaries =
+        params.app_bundle.iter().map(|(_, path)| read(path)).collect::<Result<Vec<_>>>()?;
+    let applications: Vec<NamedApplication> = params
+        .app_bundle
+        .iter()
+        .zip(&binaries)
+        .map(|((name, _), binary)| NamedApplication { name, binary })
+        .collect();
+    Ok(Some(encode_bundle(selector, &applications)))
+}
+
+/// How long to wait for the guest to listen on its vsock port.
+const VSOCK_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The launcher's end of the channel to the guest.
+enum HostSocket {
+    Unix(net::UnixStream),
+    Vsock(vsock::VsockStream),
+}
+
+impl HostSocket {
+    fn try_clone(&self) -> Result<Box<dyn oak_channel::Channel>> {
+        Ok(match self {
+            HostSocket::Unix(socket) => Box::new(socket.try_clone()?),
+            HostSocket::Vsock(socket) => Box::new(socket.try_clone()?),
+        })
+    }
+}
+
+/// Connects to the guest once its kernel listens on the vsock port, which it
+/// does after booting.
+fn connect_vsock(
+    guest_cid: u32,
+    instance: &mut tokio::process::Child,
+) -> Result<vsock::VsockStream> {
+    let deadline = Instant::now() + VSOCK_CONNECT_TIMEOUT;
+    loop {
+        match vsock::VsockStream::connect_with_cid_port(guest_cid, GUEST_VSOCK_PORT) {
+            Ok(socket) => return Ok(socket),
+            Err(err) if Instant::now() >= deadline => {
+                return Err(err).context("couldn't connect to the guest over vsock")
+            }
+            Err(_) => {
+                if let Some(status) = instance.try_wait()? {
+                    bail!("VMM exited before the guest listened on vsock: {}", status);
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
 /// Represents an a guest instance launched in virtualized environment.
 pub struct Instance {
     guest_console: net::UnixStream,
-    host_socket: net::UnixStream,
+    host_socket: HostSocket,
     instance: tokio::process::Child,
+    console_log: Option<ConsoleLog>,
+    qmp: Option<tokio::sync::Mutex<QmpClient>>,
 }
 
 impl Instance {
     /// Starts virtualized instance with given parameters and stream to write
-    /// console logs to.
-    pub fn start(params: Params, guest_console: net::UnixStream) -> Result<Self> {
-        let app_bytes = if let Some(app_binary) = params.app_binary {
-            let bytes = fs::read(&app_binary).with_context(|| {
-                format!("couldn't read application binary {}", app_binary.display())
-            })?;
-            log::info!(
-                "read application binary from disk {} ({} bytes)",
-                app_binary.display(),
-                bytes.len()
-            );
-            Some(bytes)
-        } else {
-            None
-        };
+    /// console logs to. `guest_id` tells instances on the same host apart.
+    pub fn start(params: Params, guest_console: net::UnixStream, guest_id: u32) -> Result<Self> {
+        params.validate()?;
+        let app_bytes = read_initial_data(&params)?;
 
-        let mut cmd = tokio::process::Command::new(params.vmm_binary);
-        let (guest_socket, mut host_socket) = net::UnixStream::pair()?;
+        let mut cmd = tokio::process::Command::new(&params.vmm_binary);
+        let (guest_socket, host_socket) = net::UnixStream::pair()?;
 
         // Clone the console stream so we can use it in the child process and also
         // return it from this method.
@@ -114,63 +376,75 @@ impl Instance {
         // to the child process, since that takes ownership of them.
         let guest_console_fd = guest_console.as_raw_fd();
         let guest_socket_fd = guest_socket.as_raw_fd();
+        // The VMM end of the connection the launcher controls the VMM through, if
+        // it needs one.
+        let (vmm_control, qmp) = if params.memory_balloon {
+            let (vmm_control, launcher_control) = net::UnixStream::pair()?;
+            (Some(vmm_control), Some(tokio::sync::Mutex::new(QmpClient::new(launcher_control)?)))
+        } else {
+            (None, None)
+        };
+        let vmm_control_fd = vmm_control.as_ref().map(AsRawFd::as_raw_fd);
+        let channel_device = match params.channel.channel_transport {
+            ChannelTransport::VirtioConsole => ChannelDevice::VirtioConsole { fd: guest_socket_fd },
+            ChannelTransport::VirtioVsock => ChannelDevice::VirtioVsock {
+                guest_cid: params
+                    .channel
+                    .vsock_first_guest_cid
+                    .checked_add(guest_id)
+                    .context("ran out of vsock context IDs")?,
+            },
+        };
 
         cmd.stderr(Stdio::inherit());
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::inherit());
-        cmd.preserved_fds(vec![guest_console.into(), guest_socket.into()]);
-
-        // Construct the command-line arguments for `qemu`.
-        cmd.arg("-enable-kvm");
-        // Needed to expose advanced CPU features. Specifically RDRAND which is required
-        // for remote attestation.
-        cmd.args(["-cpu", "IvyBridge-IBRS,enforce"]);
-        // Set memory size if given.
-        if let Some(memory_size) = params.memory_size {
-            cmd.args(["-m", &memory_size]);
-        };
-        // Disable a bunch of hardware we don't need.
-        cmd.arg("-nodefaults");
-        cmd.arg("-nographic");
-        // If the VM restarts, don't restart it (we're not expecting any restarts so any
-        // restart should be treated as a failure)
-        cmd.arg("-no-reboot");
-        // Use the `microvm` machine as the basis, and ensure ACPI is enabled.
-        cmd.args(["-machine", "microvm,acpi=on"]);
-        // Route first serial port to console.
-        cmd.args(["-chardev", format!("socket,id=consock,fd={guest_console_fd}").as_str()]);
-        cmd.args(["-serial", "chardev:consock"]);
-        // Add the virtio device.
-        cmd.args(["-chardev", format!("socket,id=commsock,fd={guest_socket_fd}").as_str()]);
-        cmd.args(["-device", "virtio-serial-device,max_ports=1"]);
-        cmd.args(["-device", "virtconsole,chardev=commsock"]);
-        // Use stage0 as the BIOS.
-        cmd.args(["-bios", params.bios_binary.into_os_string().into_string().unwrap().as_str()]);
-        // stage0 accoutrements: kernel that's compatible with the linux boot protocol
-        cmd.args(["-kernel", params.kernel.into_os_string().into_string().unwrap().as_str()]);
-
-        if let Some(gdb_port) = params.gdb {
-            // Listen for a gdb connection on the provided port and wait for debugger before
-            // booting
-            cmd.args(["-gdb", format!("tcp::{gdb_port}").as_str()]);
-            cmd.arg("-S");
-        }
-
-        cmd.args(["-initrd", params.initrd.into_os_string().into_string().unwrap().as_str()]);
+        let mut preserved_fds: Vec<OwnedFd> = vec![guest_console.into(), guest_socket.into()];
+        preserved_fds.extend(vmm_control.map(OwnedFd::from));
+        cmd.preserved_fds(preserved_fds);
+
+        if params.sev_snp {
+            let measurements = crate::snp::expected_measurements(&params)?;
+            info!("expected launch digest: {}", hex::encode(measurements.launch_digest));
+        }
+        params.vmm.backend().configure(
+            &mut cmd,
+            &params,
+            guest_console_fd,
+            &channel_device,
+            vmm_control_fd,
+        )?;
 
         info!("executing: {:?}", cmd);
 
-        let instance = cmd.spawn()?;
+        let mut instance = cmd.spawn()?;
 
+        let host_socket = match channel_device {
+            ChannelDevice::VirtioConsole { .. } => HostSocket::Unix(host_socket),
+            ChannelDevice::VirtioVsock { guest_cid } => {
+                HostSocket::Vsock(connect_vsock(guest_cid, &mut instance)?)
+            }
+        };
         if let Some(app_bytes) = app_bytes {
-            oak_channel::basic_framed::send_raw(&mut host_socket, &app_bytes)
+            let mut host_socket = host_socket.try_clone()?;
+            oak_channel::basic_framed::send_raw(host_socket.as_mut(), &app_bytes)
                 .context("failed to send application")?;
             #[cfg(feature = "exchange_evidence")]
-            let _evidence = oak_channel::basic_framed::receive_raw(&mut host_socket)
+            let _evidence = oak_channel::basic_framed::receive_raw(host_socket.as_mut())
                 .context("failed to receive attestion evidence")?;
         }
 
-        Ok(Self { guest_console: guest_console_clone, host_socket, instance })
+        Ok(Self {
+            guest_console: guest_console_clone,
+            host_socket,
+            instance,
+            console_log: None,
+            qmp,
+        })
+    }
+
+    fn qmp(&self) -> Result<&tokio::sync::Mutex<QmpClient>> {
+        self.qmp.as_ref().context("the guest instance was launched without a memory balloon")
     }
 }
 
@@ -190,7 +464,23 @@ impl GuestInstance for Instance {
 
     async fn connect(&self) -> Result<Box<dyn oak_channel::Channel>> {
         info!("connecting to guest instance");
-        Ok(Box::new(self.host_socket.try_clone()?))
+        self.host_socket.try_clone()
+    }
+
+    fn logs(&self) -> ConsoleStream {
+        match &self.console_log {
+            Some(console_log) => console_log.subscribe(),
+            None => Box::pin(tokio_stream::empty()),
+        }
+    }
+
+    async fn set_memory_target(&self, bytes: u64) -> Result<()> {
+        self.qmp()?.lock().await.execute("balloon", json!({ "value": bytes })).await.map(|_| ())
+    }
+
+    async fn memory_size(&self) -> Result<u64> {
+        let result = self.qmp()?.lock().await.execute("query-balloon", Value::Null).await?;
+        result["actual"].as_u64().context("invalid query-balloon result")
     }
 }
 
@@ -198,49 +488,447 @@ impl GuestInstance for Instance {
 /// interface of different implementations, e.g. a VM in which the guest is
 /// running or the guest running directly as a unix binary.
 #[async_trait]
-pub trait GuestInstance {
+pub trait GuestInstance: Send {
     /// Wait for the guest instance process to finish.
     async fn wait(&mut self) -> Result<std::process::ExitStatus>;
 
     /// Kill the guest instance.
     async fn kill(self: Box<Self>) -> Result<std::process::ExitStatus>;
 
-    /// Creates a channel to communicate with the guest instance.
+    /// Creates a channel to communicate with the guest instance. Every call
+    /// returns a new handle to the same connection, so that it can be read
+    /// from and written to concurrently.
     async fn connect(&self) -> Result<Box<dyn oak_channel::Channel>>;
+
+    /// Returns a stream of the lines the guest writes to its console from now
+    /// on.
+    fn logs(&self) -> ConsoleStream {
+        Box::pin(tokio_stream::empty())
+    }
+
+    /// Asks the guest to shrink or grow to `bytes` of memory, by inflating or
+    /// deflating its memory balloon. The guest adjusts the balloon in the
+    /// background, see [`GuestInstance::memory_size`] for how far it got.
+    async fn set_memory_target(&self, _bytes: u64) -> Result<()> {
+        bail!("the guest instance doesn't support resizing its memory")
+    }
+
+    /// Returns the memory of the guest in bytes, without the memory held by its
+    /// memory balloon.
+    async fn memory_size(&self) -> Result<u64> {
+        bail!("the guest instance doesn't support resizing its memory")
+    }
 }
 
+/// Id of the next guest instance launched by [`launch`].
+static NEXT_GUEST_ID: AtomicU32 = AtomicU32::new(0);
+
 /// Launches a new guest instance in given mode.
 pub async fn launch(
     params: Params,
-) -> Result<(Box<dyn GuestInstance>, ConnectorHandle), Box<dyn std::error::Error>> {
-    // Provide a way for the launched instance to send logs
-    let guest_writer: UnixStream = {
-        // Create two linked consoles. Technically both can read/write, but we'll
-        // use them as a one way channel.
-        let (console_writer, console_receiver) = UnixStream::pair()?;
-
-        // Log everything sent by the writer.
-        tokio::spawn(async {
-            let mut reader = BufReader::new(console_receiver);
-
-            let mut line = String::new();
-            while reader.read_line(&mut line).expect("couldn't read line") > 0 {
-                // remove the new line character
-                line.pop();
-                log::info!("console: {:?}", line);
-                line.clear();
+) -> Result<(Box<dyn GuestInstance>, ConnectorHandle), LauncherError> {
+    // Provide a way for the launched instance to send logs. Create two linked
+    // consoles. Technically both can read/write, but we'll use them as a one way
+    // channel. The console is captured before the instance starts, so that it
+    // can't block on a full console while it is being set up.
+    let (guest_writer, console_receiver) = UnixStream::pair()
+        .map_err(|err| LauncherError::io("couldn't create guest console", err))?;
+    let guest_id = NEXT_GUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let console_log = ConsoleLog::capture(console_receiver, guest_id, &params.console_log)?;
+
+    log::info!("launching instance {}", guest_id);
+
+    let window_limits = params.channel.window_limits();
+    let mut guest_instance =
+        Box::new(Instance::start(params, guest_writer, guest_id).map_err(LauncherError::Vmm)?);
+    guest_instance.console_log = Some(console_log);
+
+    let connector_handle = Connector::spawn(
+        guest_instance.connect().await.map_err(LauncherError::Channel)?,
+        guest_instance.connect().await.map_err(LauncherError::Channel)?,
+        window_limits,
+    );
+
+    Ok((guest_instance, connector_handle))
+}
+
+/// How a [`Supervisor`] restarts a guest instance after its VMM exits.
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    /// Maximum number of restarts before giving up, or `None` to keep
+    /// restarting indefinitely.
+    pub max_restarts: Option<u32>,
+    /// Delay before the first restart, doubled for every consecutive restart.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between restarts. An instance that stays up for
+    /// longer than this resets the backoff.
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    /// Returns how long to wait before the given consecutive restart, starting
+    /// from 1.
+    pub fn backoff(&self, restart: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << restart.saturating_sub(1).min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// Prepares a freshly launched guest instance to serve requests, e.g. by
+/// sending it the application and its data. Run after every (re)launch.
+#[async_trait]
+pub trait GuestSetup: Send + Sync + 'static {
+    /// What setting up the guest instance produces, e.g. its attestation
+    /// evidence.
+    type Output: Send + Sync + 'static;
+
+    async fn setup(&self, connector_handle: ConnectorHandle) -> Result<Self::Output>;
+
+    /// Releases what [`GuestSetup::setup`] produced once the guest instance has
+    /// exited, before it is restarted.
+    fn teardown(&self, _output: &Self::Output) {}
+}
+
+/// A supervised guest instance that is set up and running.
+pub struct Running<T> {
+    pub connector_handle: ConnectorHandle,
+    pub setup: Arc<T>,
+}
+
+impl<T> Clone for Running<T> {
+    fn clone(&self) -> Self {
+        Self { connector_handle: self.connector_handle.clone(), setup: self.setup.clone() }
+    }
+}
+
+// Launches the guest instances of a [`Supervisor`], so that supervision can be
+// tested without a VMM.
+#[async_trait]
+trait Launch: Send + Sync + 'static {
+    async fn launch(&self) -> Result<(Box<dyn GuestInstance>, ConnectorHandle), LauncherError>;
+}
+
+#[async_trait]
+impl Launch for Params {
+    async fn launch(&self) -> Result<(Box<dyn GuestInstance>, ConnectorHandle), LauncherError> {
+        launch(self.clone()).await
+    }
+}
+
+/// What the supervisor task hands back when it stops: the guest instance that
+/// was running at the time, if any.
+type Stopped<T> = Option<(Box<dyn GuestInstance>, Running<T>)>;
+
+/// Keeps a guest instance running, relaunching and setting it up again with
+/// exponential backoff whenever its VMM exits.
+pub struct Supervisor<T> {
+    running: watch::Receiver<Option<Running<T>>>,
+    restarts: Arc<AtomicU32>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<Result<Stopped<T>>>,
+}
+
+impl<T: Send + Sync + 'static> Supervisor<T> {
+    /// Launches a guest instance with `params` and sets it up with `setup` in
+    /// the background, restarting it according to `policy`.
+    pub fn start<S: GuestSetup<Output = T>>(
+        params: Params,
+        setup: S,
+        policy: RestartPolicy,
+    ) -> Self {
+        Self::start_with(params, setup, policy)
+    }
+
+    fn start_with<L: Launch, S: GuestSetup<Output = T>>(
+        launcher: L,
+        setup: S,
+        policy: RestartPolicy,
+    ) -> Self {
+        let (running_sender, running) = watch::channel(None);
+        let restarts = Arc::new(AtomicU32::new(0));
+        let (stop, stop_receiver) = watch::channel(false);
+        let task = tokio::spawn(supervise(
+            launcher,
+            setup,
+            policy,
+            running_sender,
+            restarts.clone(),
+            stop_receiver,
+        ));
+        Self { running, restarts, stop, task }
+    }
+
+    /// Returns the guest instance if it is currently set up and running.
+    pub fn running(&self) -> Option<Running<T>> {
+        self.running.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified whenever the guest instance comes up
+    /// or goes down.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Running<T>>> {
+        self.running.clone()
+    }
+
+    /// Waits until the guest instance is set up and running. Fails if the
+    /// supervisor gave up restarting it.
+    pub async fn wait_until_running(&self) -> Result<Running<T>> {
+        let mut running = self.running.clone();
+        let running = running
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| anyhow!("guest instance supervisor gave up"))?;
+        Ok(running.clone().expect("guest instance isn't running"))
+    }
+
+    /// Waits until the supervisor gives up restarting the guest instance, which
+    /// may never happen.
+    pub async fn given_up(&self) {
+        let mut running = self.running.clone();
+        // Only fails once the supervisor task has returned.
+        let _ = running.wait_for(|_| false).await;
+    }
+
+    /// Returns how many times the guest instance has been restarted.
+    pub fn restart_count(&self) -> u32 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Stops supervising and hands back the guest instance that is currently
+    /// running, if any, so that the caller can shut it down. Fails if the
+    /// supervisor had already given up restarting it.
+    pub async fn stop(self) -> Result<Stopped<T>> {
+        self.stop.send_replace(true);
+        self.task.await?
+    }
+}
+
+// Why a running guest instance stopped being supervised.
+enum Outcome {
+    Exited,
+    Stopped,
+    SupervisorDropped,
+}
+
+async fn supervise<L: Launch, S: GuestSetup>(
+    launcher: L,
+    setup: S,
+    policy: RestartPolicy,
+    running: watch::Sender<Option<Running<S::Output>>>,
+    restarts: Arc<AtomicU32>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<Stopped<S::Output>> {
+    let mut consecutive_restarts = 0;
+    loop {
+        let launched_at = Instant::now();
+        match launch_and_setup(&launcher, &setup).await {
+            Ok((mut guest_instance, current)) => {
+                running.send_replace(Some(current.clone()));
+                let outcome = tokio::select! {
+                    status = guest_instance.wait() => {
+                        log::error!("guest instance exited unexpectedly: {:?}", status);
+                        Outcome::Exited
+                    }
+                    stopped = stop.wait_for(|stop| *stop) => match stopped {
+                        Ok(_) => Outcome::Stopped,
+                        Err(_) => Outcome::SupervisorDropped,
+                    },
+                };
+                match outcome {
+                    Outcome::Exited => {
+                        running.send_replace(None);
+                        setup.teardown(&current.setup);
+                    }
+                    Outcome::Stopped => return Ok(Some((guest_instance, current))),
+                    Outcome::SupervisorDropped => {
+                        running.send_replace(None);
+                        setup.teardown(&current.setup);
+                        guest_instance.kill().await?;
+                        return Ok(None);
+                    }
+                }
             }
-        });
+            Err(err) => log::error!("couldn't launch guest instance: {:?}", err),
+        }
 
-        console_writer
-    };
+        if launched_at.elapsed() > policy.max_backoff {
+            consecutive_restarts = 0;
+        }
+        let restart_count = restarts.load(Ordering::Relaxed);
+        if policy.max_restarts.is_some_and(|max_restarts| restart_count >= max_restarts) {
+            return Err(anyhow!("guest instance failed after {} restarts", restart_count));
+        }
+        consecutive_restarts += 1;
+        let delay = policy.backoff(consecutive_restarts);
+        info!("restarting guest instance in {:?}", delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.wait_for(|stop| *stop) => return Ok(None),
+        }
+        restarts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn launch_and_setup<L: Launch, S: GuestSetup>(
+    launcher: &L,
+    setup: &S,
+) -> Result<(Box<dyn GuestInstance>, Running<S::Output>)> {
+    let (guest_instance, connector_handle) =
+        launcher.launch().await.context("couldn't launch guest instance")?;
+    match setup.setup(connector_handle.clone()).await {
+        Ok(output) => Ok((guest_instance, Running { connector_handle, setup: Arc::new(output) })),
+        Err(err) => {
+            // Don't leave a VMM behind that will never be used.
+            guest_instance.kill().await?;
+            Err(err.context("couldn't set up guest instance"))
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_policy(max_restarts: Option<u32>) -> RestartPolicy {
+    RestartPolicy {
+        max_restarts,
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(4),
+    }
+}
+
+// A guest instance that exits once the sender matching `exited` is dropped.
+#[cfg(test)]
+struct FakeGuest {
+    exited: tokio::sync::oneshot::Receiver<()>,
+    kills: Arc<AtomicU32>,
+}
 
-    log::info!("launching instance");
+#[cfg(test)]
+#[async_trait]
+impl GuestInstance for FakeGuest {
+    async fn wait(&mut self) -> Result<std::process::ExitStatus> {
+        let _ = (&mut self.exited).await;
+        Ok(std::process::ExitStatus::default())
+    }
 
-    let guest_instance = Box::new(Instance::start(params, guest_writer)?);
+    async fn kill(self: Box<Self>) -> Result<std::process::ExitStatus> {
+        self.kills.fetch_add(1, Ordering::SeqCst);
+        Ok(std::process::ExitStatus::default())
+    }
 
-    let channel = guest_instance.connect().await?;
-    let connector_handle = Connector::spawn(channel);
+    async fn connect(&self) -> Result<Box<dyn oak_channel::Channel>> {
+        bail!("the fake guest instance can't be connected to")
+    }
+}
 
-    Ok((guest_instance, connector_handle))
+// Launches fake guest instances, recording when each launch happened.
+#[cfg(test)]
+#[derive(Default)]
+struct FakeLauncher {
+    launchable: bool,
+    // Whether guest instances exit as soon as they are launched.
+    exit_immediately: bool,
+    launches: std::sync::Mutex<Vec<tokio::time::Instant>>,
+    kills: Arc<AtomicU32>,
+    exits: std::sync::Mutex<Vec<tokio::sync::oneshot::Sender<()>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Launch for Arc<FakeLauncher> {
+    async fn launch(&self) -> Result<(Box<dyn GuestInstance>, ConnectorHandle), LauncherError> {
+        self.launches.lock().unwrap().push(tokio::time::Instant::now());
+        if !self.launchable {
+            return Err(LauncherError::Vmm(anyhow!("couldn't start the VMM")));
+        }
+        let (exit, exited) = tokio::sync::oneshot::channel();
+        if !self.exit_immediately {
+            self.exits.lock().unwrap().push(exit);
+        }
+        let (launcher_socket, _) = UnixStream::pair().unwrap();
+        let connector_handle = Connector::spawn(
+            Box::new(launcher_socket.try_clone().unwrap()),
+            Box::new(launcher_socket),
+            Default::default(),
+        );
+        Ok((Box::new(FakeGuest { exited, kills: self.kills.clone() }), connector_handle))
+    }
+}
+
+#[cfg(test)]
+struct FakeSetup;
+
+#[cfg(test)]
+#[async_trait]
+impl GuestSetup for FakeSetup {
+    type Output = ();
+
+    async fn setup(&self, _connector_handle: ConnectorHandle) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backoff_doubles_up_to_the_maximum() {
+    let policy = test_policy(None);
+    assert_eq!(policy.backoff(1), Duration::from_secs(1));
+    assert_eq!(policy.backoff(2), Duration::from_secs(2));
+    assert_eq!(policy.backoff(3), Duration::from_secs(4));
+    assert_eq!(policy.backoff(4), Duration::from_secs(4));
+    assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(4));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_supervisor_backs_off_and_gives_up_after_max_restarts() {
+    let launcher =
+        Arc::new(FakeLauncher { launchable: true, exit_immediately: true, ..Default::default() });
+    let supervisor = Supervisor::start_with(launcher.clone(), FakeSetup, test_policy(Some(4)));
+
+    supervisor.given_up().await;
+    assert_eq!(supervisor.restart_count(), 4);
+    assert!(supervisor.running().is_none());
+    assert!(supervisor.wait_until_running().await.is_err());
+    let launches = launcher.launches.lock().unwrap().clone();
+    let delays: Vec<_> = launches.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    assert_eq!(delays, [1, 2, 4, 4].map(Duration::from_secs));
+    assert!(supervisor.stop().await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_supervisor_restarts_exited_instance() {
+    let launcher = Arc::new(FakeLauncher { launchable: true, ..Default::default() });
+    let supervisor = Supervisor::start_with(launcher.clone(), FakeSetup, test_policy(Some(1)));
+    supervisor.wait_until_running().await.unwrap();
+
+    launcher.exits.lock().unwrap().clear();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(supervisor.running().is_none());
+
+    supervisor.wait_until_running().await.unwrap();
+    assert_eq!(supervisor.restart_count(), 1);
+    assert_eq!(launcher.launches.lock().unwrap().len(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stopped_supervisor_hands_back_running_instance() {
+    let launcher = Arc::new(FakeLauncher { launchable: true, ..Default::default() });
+    let supervisor = Supervisor::start_with(launcher.clone(), FakeSetup, test_policy(None));
+    supervisor.wait_until_running().await.unwrap();
+
+    let (guest_instance, _) = supervisor.stop().await.unwrap().unwrap();
+    // The caller shuts the instance down, the supervisor leaves it running.
+    assert_eq!(launcher.kills.load(Ordering::SeqCst), 0);
+    guest_instance.kill().await.unwrap();
+    assert_eq!(launcher.launches.lock().unwrap().len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_supervisor_stops_while_backing_off() {
+    let launcher = Arc::new(FakeLauncher::default());
+    let supervisor = Supervisor::start_with(launcher.clone(), FakeSetup, test_policy(None));
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let launches = launcher.launches.lock().unwrap().len();
+    assert!(launches > 1);
+
+    assert!(supervisor.stop().await.unwrap().is_none());
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(launcher.launches.lock().unwrap().len(), launches);
 }
@@ -19,4 +19,10 @@
 #![feature(array_chunks)]
 
 pub mod channel;
+pub mod config;
+pub mod console;
+pub mod error;
 pub mod launcher;
+pub mod qmp;
+pub mod snp;
+pub mod vmm;
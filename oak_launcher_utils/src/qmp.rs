@@ -0,0 +1,82 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A client for the QEMU Machine Protocol (QMP), through which the launcher
+//! controls a running QEMU instance, e.g. to resize the memory balloon of the
+//! guest.
+
+use std::os::unix::net;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+
+pub struct QmpClient {
+    stream: BufReader<UnixStream>,
+    negotiated: bool,
+}
+
+impl QmpClient {
+    /// Creates a client for the QMP monitor on the other end of `stream`.
+    pub fn new(stream: net::UnixStream) -> Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream: BufReader::new(UnixStream::from_std(stream)?), negotiated: false })
+    }
+
+    /// Executes `command` with the given arguments, which may be `null` for
+    /// commands without any, and returns its result.
+    pub async fn execute(&mut self, command: &str, arguments: Value) -> Result<Value> {
+        if !self.negotiated {
+            // QEMU greets us first, and only accepts commands once we've negotiated
+            // capabilities.
+            self.read_message().await.context("didn't get the QMP greeting")?;
+            self.request("qmp_capabilities", Value::Null).await?;
+            self.negotiated = true;
+        }
+        self.request(command, arguments).await
+    }
+
+    async fn request(&mut self, command: &str, arguments: Value) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if !arguments.is_null() {
+            request["arguments"] = arguments;
+        }
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        self.stream.get_mut().write_all(&line).await.context("couldn't send QMP command")?;
+        loop {
+            let message = self.read_message().await?;
+            if let Some(result) = message.get("return") {
+                return Ok(result.clone());
+            }
+            if let Some(error) = message.get("error") {
+                bail!("QMP command {} failed: {}", command, error);
+            }
+            // Anything else is an asynchronous event, which we don't need.
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await.context("couldn't read QMP message")? == 0 {
+            bail!("QEMU closed the QMP connection");
+        }
+        serde_json::from_str(&line).context("invalid QMP message")
+    }
+}
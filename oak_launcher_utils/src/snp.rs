@@ -0,0 +1,138 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! SEV-SNP launch configuration, and the measurements expected of a guest
+//! launched with it.
+//!
+//! The hardware launch digest only covers stage0 and the initial vCPU state.
+//! The kernel, initrd and kernel command line are loaded by stage0, which
+//! measures them into the DICE evidence of the guest instead.
+
+use std::fs;
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use clap::Parser;
+use sha2::{Digest, Sha256};
+
+use crate::launcher::Params;
+
+/// Default guest policy: SMT allowed, and the reserved bit 17 set, as the
+/// SEV-SNP ABI requires.
+pub const DEFAULT_POLICY: u64 = 0x30000;
+
+/// Size of an ID block, as defined in the SEV-SNP ABI.
+const ID_BLOCK_SIZE: usize = 96;
+
+/// Size of an ID authentication information structure.
+const ID_AUTH_SIZE: usize = 4096;
+
+/// Size of the host data included in attestation reports.
+const HOST_DATA_SIZE: usize = 32;
+
+/// SEV-SNP settings of a guest, only used if `--sev-snp` is set.
+#[derive(Parser, Clone, Debug, PartialEq)]
+pub struct SnpParams {
+    /// Guest policy, e.g. `0x30000`.
+    #[arg(long = "snp-policy", value_parser = parse_policy, default_value = "0x30000")]
+    pub policy: u64,
+
+    /// Base64-encoded ID block that the guest is launched with.
+    #[arg(
+        long = "snp-id-block",
+        value_parser = base64_of_size::<ID_BLOCK_SIZE>,
+        requires = "id_auth"
+    )]
+    pub id_block: Option<String>,
+
+    /// Base64-encoded ID authentication information structure, signing the ID
+    /// block.
+    #[arg(
+        long = "snp-id-auth",
+        value_parser = base64_of_size::<ID_AUTH_SIZE>,
+        requires = "id_block"
+    )]
+    pub id_auth: Option<String>,
+
+    /// Base64-encoded 32 bytes of data provided by the host, which are included
+    /// in attestation reports.
+    #[arg(long = "snp-host-data", value_parser = base64_of_size::<HOST_DATA_SIZE>)]
+    pub host_data: Option<String>,
+}
+
+impl Default for SnpParams {
+    fn default() -> Self {
+        Self { policy: DEFAULT_POLICY, id_block: None, id_auth: None, host_data: None }
+    }
+}
+
+impl SnpParams {
+    /// Returns the properties of QEMU's `sev-snp-guest` object for these
+    /// settings.
+    pub fn qemu_properties(&self) -> String {
+        let mut properties = format!("cbitpos=51,reduced-phys-bits=1,policy={:#x}", self.policy);
+        if let (Some(id_block), Some(id_auth)) = (&self.id_block, &self.id_auth) {
+            properties.push_str(&format!(",id-block={id_block},id-auth={id_auth}"));
+        }
+        if let Some(host_data) = &self.host_data {
+            properties.push_str(&format!(",host-data={host_data}"));
+        }
+        properties
+    }
+}
+
+/// The measurements of a guest launched with given parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpectedMeasurements {
+    /// The SEV-SNP launch digest, covering stage0 and the initial vCPU state.
+    pub launch_digest: [u8; 48],
+    /// The digests that stage0 measures into the DICE evidence.
+    pub kernel_sha2_256: [u8; 32],
+    pub initrd_sha2_256: [u8; 32],
+    pub cmdline_sha2_256: [u8; 32],
+}
+
+/// Computes the measurements of a guest launched with `params`, so that they
+/// can be checked against its attestation evidence before trusting it with
+/// secrets.
+pub fn expected_measurements(params: &Params) -> Result<ExpectedMeasurements> {
+    let stage0 = snp_measurement::load_stage0(params.bios_binary.clone())?;
+    let launch_digest = snp_measurement::launch_digest(&stage0, params.num_cpus.into(), false);
+    Ok(ExpectedMeasurements {
+        launch_digest,
+        kernel_sha2_256: file_sha2_256(&params.kernel)?,
+        initrd_sha2_256: file_sha2_256(&params.initrd)?,
+        cmdline_sha2_256: Sha256::digest(params.kernel_command_line()).into(),
+    })
+}
+
+fn file_sha2_256(path: &std::path::Path) -> Result<[u8; 32]> {
+    let bytes = fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    Ok(Sha256::digest(bytes).into())
+}
+
+fn parse_policy(s: &str) -> Result<u64, String> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|err| err.to_string())
+}
+
+/// Checks that `s` is the base64 encoding of `N` bytes.
+fn base64_of_size<const N: usize>(s: &str) -> Result<String, String> {
+    let bytes = STANDARD.decode(s).map_err(|err| err.to_string())?;
+    if bytes.len() != N {
+        return Err(format!("expected {} bytes, got {}", N, bytes.len()));
+    }
+    Ok(s.to_string())
+}
@@ -0,0 +1,248 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The VMMs that guest instances can be launched with.
+
+use std::{os::fd::RawFd, path::Path};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use tokio::process::Command;
+
+use crate::launcher::{parse_memory_size, Params, DEFAULT_CPU_MODEL};
+
+/// The supported VMMs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum VmmType {
+    Qemu,
+    Crosvm,
+}
+
+impl VmmType {
+    pub fn backend(&self) -> Box<dyn VmmBackend> {
+        match self {
+            VmmType::Qemu => Box::new(Qemu),
+            VmmType::Crosvm => Box::new(Crosvm),
+        }
+    }
+}
+
+/// The virtio device that carries the channel to the guest.
+pub enum ChannelDevice {
+    /// A virtio console connected to the socket `fd`.
+    VirtioConsole { fd: RawFd },
+    /// A virtio-vsock device with the context ID `guest_cid`.
+    VirtioVsock { guest_cid: u32 },
+}
+
+/// Translates the launcher parameters into the command-line arguments of a
+/// particular VMM.
+pub trait VmmBackend {
+    /// Adds the arguments that make the VMM run the guest described by
+    /// `params` to `cmd`. The first serial port of the guest must be
+    /// connected to `console_fd`, and the channel to `channel_device`. If
+    /// `control_fd` is set, the VMM must accept QMP commands on it.
+    fn configure(
+        &self,
+        cmd: &mut Command,
+        params: &Params,
+        console_fd: RawFd,
+        channel_device: &ChannelDevice,
+        control_fd: Option<RawFd>,
+    ) -> Result<()>;
+}
+
+pub struct Qemu;
+
+impl VmmBackend for Qemu {
+    fn configure(
+        &self,
+        cmd: &mut Command,
+        params: &Params,
+        console_fd: RawFd,
+        channel_device: &ChannelDevice,
+        control_fd: Option<RawFd>,
+    ) -> Result<()> {
+        cmd.arg("-enable-kvm");
+        cmd.args(["-cpu", &params.cpu_model]);
+        cmd.args(["-smp", &params.num_cpus.to_string()]);
+        // Set memory size if given.
+        if let Some(memory_size) = &params.memory_size {
+            cmd.args(["-m", memory_size.as_str()]);
+        };
+        // Split the vCPUs and memory evenly between the NUMA nodes, if there are
+        // several.
+        if params.numa_nodes > 1 {
+            let memory_size = parse_memory_size(params.memory_size.as_deref().unwrap_or_default())?;
+            let node_memory_size = memory_size / u64::from(params.numa_nodes);
+            let node_cpus = params.num_cpus / params.numa_nodes;
+            for node in 0..params.numa_nodes {
+                let first_cpu = node * node_cpus;
+                let last_cpu = first_cpu + node_cpus - 1;
+                cmd.args([
+                    "-object",
+                    format!("memory-backend-ram,id=mem{node},size={node_memory_size}").as_str(),
+                ]);
+                cmd.args([
+                    "-numa",
+                    format!("node,nodeid={node},cpus={first_cpu}-{last_cpu},memdev=mem{node}")
+                        .as_str(),
+                ]);
+            }
+        }
+        // Disable a bunch of hardware we don't need.
+        cmd.arg("-nodefaults");
+        cmd.arg("-nographic");
+        // If the VM restarts, don't restart it (we're not expecting any restarts so any
+        // restart should be treated as a failure)
+        cmd.arg("-no-reboot");
+        // Use the `microvm` machine as the basis, and ensure ACPI is enabled.
+        if params.sev_snp {
+            cmd.args(["-machine", "microvm,acpi=on,confidential-guest-support=sev0"]);
+            cmd.args([
+                "-object",
+                format!("sev-snp-guest,id=sev0,{}", params.snp.qemu_properties()).as_str(),
+            ]);
+        } else {
+            cmd.args(["-machine", "microvm,acpi=on"]);
+        }
+        // Route first serial port to console.
+        cmd.args(["-chardev", format!("socket,id=consock,fd={console_fd}").as_str()]);
+        cmd.args(["-serial", "chardev:consock"]);
+        // Add the virtio device.
+        match channel_device {
+            ChannelDevice::VirtioConsole { fd } => {
+                cmd.args(["-chardev", format!("socket,id=commsock,fd={fd}").as_str()]);
+                cmd.args(["-device", "virtio-serial-device,max_ports=1"]);
+                cmd.args(["-device", "virtconsole,chardev=commsock"]);
+            }
+            ChannelDevice::VirtioVsock { guest_cid } => {
+                // The MMIO variant, as `microvm` has no PCI bus.
+                cmd.args(["-device", format!("vhost-vsock-device,guest-cid={guest_cid}").as_str()]);
+            }
+        }
+        if params.memory_balloon {
+            cmd.args(["-device", "virtio-balloon-device"]);
+        }
+        // Let the launcher control QEMU through QMP, e.g. to resize the balloon.
+        if let Some(control_fd) = control_fd {
+            cmd.args(["-chardev", format!("socket,id=qmpsock,fd={control_fd}").as_str()]);
+            cmd.args(["-mon", "chardev=qmpsock,mode=control"]);
+        }
+        // Use stage0 as the BIOS.
+        cmd.args(["-bios", path_str(&params.bios_binary)?]);
+        // stage0 accoutrements: kernel that's compatible with the linux boot protocol
+        cmd.args(["-kernel", path_str(&params.kernel)?]);
+
+        if let Some(gdb_port) = params.gdb {
+            // Listen for a gdb connection on the provided port and wait for debugger before
+            // booting
+            cmd.args(["-gdb", format!("tcp::{gdb_port}").as_str()]);
+            cmd.arg("-S");
+        }
+
+        cmd.args(["-initrd", path_str(&params.initrd)?]);
+        let kernel_command_line = params.kernel_command_line();
+        if !kernel_command_line.is_empty() {
+            cmd.args(["-append", kernel_command_line.as_str()]);
+        }
+        Ok(())
+    }
+}
+
+/// crosvm has no equivalent of QEMU's `-kernel` and `-initrd` with a custom
+/// BIOS, so the kernel, initrd and command line are passed to stage0 as named
+/// fw_cfg files instead.
+pub struct Crosvm;
+
+impl VmmBackend for Crosvm {
+    fn configure(
+        &self,
+        cmd: &mut Command,
+        params: &Params,
+        console_fd: RawFd,
+        channel_device: &ChannelDevice,
+        control_fd: Option<RawFd>,
+    ) -> Result<()> {
+        if params.sev_snp {
+            bail!("crosvm doesn't support launching SEV-SNP guests");
+        }
+        if params.numa_nodes > 1 {
+            bail!("crosvm doesn't support NUMA guests");
+        }
+        if control_fd.is_some() {
+            bail!("crosvm can't be controlled through QMP");
+        }
+        // crosvm only has a PCI variant of the vsock device, which the restricted
+        // kernel doesn't drive.
+        let ChannelDevice::VirtioConsole { fd: comms_fd } = channel_device else {
+            bail!("crosvm doesn't support the virtio-vsock channel");
+        };
+        if params.cpu_model != DEFAULT_CPU_MODEL {
+            log::warn!("crosvm passes the host CPU model through, ignoring {}", params.cpu_model);
+        }
+
+        cmd.arg("run");
+        cmd.args(["--cpus", &params.num_cpus.to_string()]);
+        if let Some(memory_size) = &params.memory_size {
+            // crosvm takes the memory size in megabytes.
+            let memory_size = parse_memory_size(memory_size)? >> 20;
+            cmd.args(["--mem", &memory_size.to_string()]);
+        }
+        // Route first serial port to console.
+        cmd.args([
+            "--serial",
+            format!("type=file,path=/proc/self/fd/{console_fd},num=1,console=true").as_str(),
+        ]);
+        // Add the virtio console. crosvm reads and writes it through separate paths,
+        // which both refer to the same socket.
+        cmd.args([
+            "--serial",
+            format!(
+                "type=file,path=/proc/self/fd/{comms_fd},input=/proc/self/fd/{comms_fd},\
+                 hardware=virtio-console,num=1"
+            )
+            .as_str(),
+        ]);
+        // Use stage0 as the BIOS.
+        cmd.args(["--bios", path_str(&params.bios_binary)?]);
+        cmd.args([
+            "--fw-cfg",
+            format!("name=opt/stage0/elf_kernel,path={}", path_str(&params.kernel)?).as_str(),
+        ]);
+        cmd.args([
+            "--fw-cfg",
+            format!("name=opt/stage0/initramfs,path={}", path_str(&params.initrd)?).as_str(),
+        ]);
+        let kernel_command_line = params.kernel_command_line();
+        if !kernel_command_line.is_empty() {
+            cmd.args([
+                "--fw-cfg",
+                format!("name=opt/stage0/cmdline,string={kernel_command_line}").as_str(),
+            ]);
+        }
+
+        if let Some(gdb_port) = params.gdb {
+            // crosvm waits for the debugger to attach before booting.
+            cmd.args(["--gdb", &gdb_port.to_string()]);
+        }
+        Ok(())
+    }
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().with_context(|| format!("path {} isn't valid UTF-8", path.display()))
+}
@@ -20,6 +20,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "../proto/attestation/dice.proto",
         "../proto/attestation/endorsement.proto",
         "../proto/attestation/evidence.proto",
+        "../proto/attestation/peer_attestation.proto",
         "../proto/attestation/reference_value.proto",
         "../proto/attestation/verification.proto",
         "../proto/digest.proto",
@@ -17,7 +17,7 @@
 use alloc::{boxed::Box, string::String, vec::Vec};
 use core::ptr::NonNull;
 
-use acpi::{AcpiHandler, AcpiTables, AmlTable, PhysicalMapping};
+use acpi::{platform::ProcessorState, AcpiHandler, AcpiTables, AmlTable, PhysicalMapping};
 use aml::{
     resource::{resource_descriptor_list, MemoryRangeDescriptor, Resource},
     value::Args,
@@ -348,6 +348,23 @@ impl Acpi {
         Ok(acpi)
     }
 
+    /// Returns the local APIC IDs of all application processors listed in the
+    /// MADT that are not disabled.
+    pub fn application_processors(&self) -> Result<Vec<u32>> {
+        let platform_info = self
+            .tables
+            .platform_info()
+            .map_err(|err| anyhow!("failed to read ACPI platform info: {:?}", err))?;
+        let processor_info =
+            platform_info.processor_info.ok_or_else(|| anyhow!("no processor info in the MADT"))?;
+        Ok(processor_info
+            .application_processors
+            .iter()
+            .filter(|processor| processor.state != ProcessorState::Disabled)
+            .map(|processor| processor.local_apic_id)
+            .collect())
+    }
+
     pub fn devices(&mut self) -> Result<Vec<AcpiDevice>> {
         self.walk(|_aml, name, _level| Ok(Some(AcpiDevice { name: name.clone() })))
     }
@@ -14,6 +14,8 @@
 // limitations under the License.
 //
 
+use alloc::boxed::Box;
+
 use spinning_top::Spinlock;
 use x86_64::{
     instructions::tables::load_tss,
@@ -37,24 +39,38 @@ struct Descriptors {
 
 const DOUBLE_FAULT_STACK_INDEX: u16 = 1;
 
-static DESCRIPTORS: Spinlock<Descriptors> = Spinlock::new(Descriptors {
-    gdt: GlobalDescriptorTable::new(),
-    tss: TaskStateSegment::new(),
-    kernel_cs_selector: SegmentSelector::NULL,
-    kernel_ds_selector: SegmentSelector::NULL,
-    user_cs_selector: SegmentSelector::NULL,
-    user_ds_selector: SegmentSelector::NULL,
-    tss_selector: SegmentSelector::NULL,
-});
+impl Descriptors {
+    const fn new() -> Self {
+        Self {
+            gdt: GlobalDescriptorTable::new(),
+            tss: TaskStateSegment::new(),
+            kernel_cs_selector: SegmentSelector::NULL,
+            kernel_ds_selector: SegmentSelector::NULL,
+            user_cs_selector: SegmentSelector::NULL,
+            user_ds_selector: SegmentSelector::NULL,
+            tss_selector: SegmentSelector::NULL,
+        }
+    }
+}
+
+/// Descriptors of the bootstrap processor.
+///
+/// Application processors get their own copy (see `init_ap_gdt`), as every CPU
+/// needs its own TSS.
+static DESCRIPTORS: Spinlock<Descriptors> = Spinlock::new(Descriptors::new());
 
 /// Does basic initialization of the GDT for the kernel itself.
 pub fn init_gdt_early() {
-    let mut descriptors = DESCRIPTORS.lock();
+    init_kernel_segments(&mut DESCRIPTORS.lock());
+}
+
+fn init_kernel_segments(descriptors: &mut Descriptors) {
     descriptors.kernel_cs_selector = descriptors.gdt.add_entry(Descriptor::kernel_code_segment());
     descriptors.kernel_ds_selector = descriptors.gdt.add_entry(Descriptor::kernel_data_segment());
 
-    // Safety: descriptors are 'static, so this is safe to load, but unfortunately
-    // the fact isn't visible through the MutexGuard.
+    // Safety: descriptors are 'static (either stored in the static variable or
+    // leaked), so this is safe to load, but unfortunately the fact isn't visible
+    // through the MutexGuard.
     unsafe {
         descriptors.gdt.load_unsafe();
     }
@@ -107,3 +123,23 @@ pub fn init_gdt(double_fault_stack: VirtAddr, privileged_interrupt_stack: VirtAd
 
     DOUBLE_FAULT_STACK_INDEX
 }
+
+/// Sets up the GDT and TSS for an application processor.
+///
+/// Application processors only ever run kernel code, so we don't need the
+/// Ring 3 descriptors here; the TSS is only used to switch to a separate stack
+/// on double faults, using the same stack index as the bootstrap processor.
+pub fn init_ap_gdt(double_fault_stack: VirtAddr) {
+    // The descriptors need to live as long as the CPU is running, which is forever.
+    let descriptors: &'static mut Descriptors = Box::leak(Box::new(Descriptors::new()));
+    init_kernel_segments(descriptors);
+
+    descriptors.tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = double_fault_stack;
+    let tss_descriptor = Descriptor::tss_segment(&descriptors.tss);
+    descriptors.tss_selector = descriptors.gdt.add_entry(tss_descriptor);
+    // Safety: the GDT is 'static and contains a valid TSS descriptor.
+    unsafe {
+        descriptors.gdt.load_unsafe();
+        load_tss(descriptors.tss_selector);
+    }
+}
@@ -301,6 +301,16 @@ pub unsafe fn init_idt(double_fault_stack_index: u16) {
     idt.load_unsafe();
 }
 
+/// Loads the (shared) IDT on an application processor.
+///
+/// # Safety
+///
+/// The caller needs to guarantee that the TSS of the current CPU has a valid
+/// entry for the double fault stack index used in the IDT.
+pub unsafe fn load_idt() {
+    IDT.lock().load_unsafe();
+}
+
 struct Pic {
     command: PortWrapper<u8>,
     data: PortWrapper<u8>,
@@ -55,12 +55,19 @@ mod serial;
 pub mod shutdown;
 #[cfg(feature = "simple_io_channel")]
 mod simpleio;
+pub mod smp;
 mod snp;
 mod syscall;
 #[cfg(feature = "vsock_channel")]
 mod virtio;
+#[cfg(any(feature = "virtio_console_channel", feature = "virtio_vsock_channel"))]
+mod virtio_balloon;
 #[cfg(feature = "virtio_console_channel")]
 mod virtio_console;
+#[cfg(any(feature = "virtio_console_channel", feature = "virtio_vsock_channel"))]
+mod virtio_mmio;
+#[cfg(feature = "virtio_vsock_channel")]
+mod virtio_vsock;
 
 extern crate alloc;
 
@@ -162,6 +169,10 @@ pub fn start_kernel(info: &BootParams) -> ! {
     } else {
         None
     };
+    // Stage0 parks the APs in a wakeup mailbox (unless SEV-ES is enabled). As with
+    // the SNP pages, we have to find the mailbox now, while the identity mapping is
+    // still in place.
+    let ap_info = smp::get_ap_info(info);
 
     // Safety: in the linker script we specify that the ELF header should be placed
     // at 0x200000.
@@ -389,6 +400,11 @@ pub fn start_kernel(info: &BootParams) -> ! {
     let channel =
         get_channel(&kernel_args, GUEST_HOST_HEAP.get().unwrap(), acpi.as_mut(), sev_status);
 
+    #[cfg(any(feature = "virtio_console_channel", feature = "virtio_vsock_channel"))]
+    if let Some(acpi) = acpi.as_mut() {
+        virtio_balloon::init(acpi, sev_status);
+    }
+
     #[cfg(feature = "initrd")]
     let application_bytes: Box<[u8]> = {
         let virt_addr = {
@@ -428,13 +444,22 @@ pub fn start_kernel(info: &BootParams) -> ! {
     };
 
     #[cfg(not(feature = "initrd"))]
-    let application_bytes: Box<[u8]> = {
+    let (application_bytes, application_config): (Box<[u8]>, Box<[u8]>) = {
         // We need to load the application binary before we hand the channel over to the
         // syscalls, which expose it to the user space.
         info!("Loading application binary...");
-        oak_channel::basic_framed::receive_raw::<dyn Channel>(&mut *channel)
-            .expect("failed to load application binary from channel")
-            .into_boxed_slice()
+        let initial_data = oak_channel::basic_framed::receive_raw::<dyn Channel>(&mut *channel)
+            .expect("failed to load application binary from channel");
+        let (application_config, initial_data) =
+            oak_restricted_kernel_interface::initial_data::split_application_config(&initial_data)
+                .expect("failed to parse application config");
+        let initial_data =
+            oak_restricted_kernel_interface::initial_data::InitialData::parse(initial_data)
+                .expect("failed to parse initial data");
+        if let Some(selector) = initial_data.selector() {
+            info!("Selected application {} from the bundle", selector);
+        }
+        (initial_data.selected_application().into(), application_config.into())
     };
 
     log::info!("Binary loaded, size: {}", application_bytes.len());
@@ -450,8 +475,13 @@ pub fn start_kernel(info: &BootParams) -> ! {
 
         let derived_key =
             oak_restricted_kernel_dice::generate_derived_key(&stage0_dice_data, &app_digest);
-        let restricted_kernel_dice_data =
-            oak_restricted_kernel_dice::generate_dice_data(stage0_dice_data, &app_digest);
+        let app_config_digest = (!application_config.is_empty())
+            .then(|| oak_restricted_kernel_dice::measure_app_digest_sha2_256(&application_config));
+        let restricted_kernel_dice_data = oak_restricted_kernel_dice::generate_dice_data(
+            stage0_dice_data,
+            &app_digest,
+            app_config_digest.as_ref(),
+        );
 
         (derived_key, restricted_kernel_dice_data)
     };
@@ -459,6 +489,12 @@ pub fn start_kernel(info: &BootParams) -> ! {
     let application =
         payload::Application::new(application_bytes).expect("failed to parse application");
 
+    // We're done with the boot parameters and everything else stage0 left in low
+    // memory, so it's safe to reuse it for the AP trampoline.
+    if let (Some(ap_info), Some(acpi)) = (ap_info, acpi.as_ref()) {
+        smp::start_aps(ap_info, acpi);
+    }
+
     syscall::enable_syscalls(
         channel,
         #[cfg(feature = "initrd")]
@@ -483,6 +519,8 @@ pub fn start_kernel(info: &BootParams) -> ! {
 enum ChannelType {
     #[cfg(feature = "virtio_console_channel")]
     VirtioConsole,
+    #[cfg(feature = "virtio_vsock_channel")]
+    VirtioMmioVsock,
     #[cfg(feature = "vsock_channel")]
     VirtioVsock,
     #[cfg(feature = "serial_channel")]
@@ -512,6 +550,10 @@ fn get_channel<'a, A: Allocator + Sync>(
         ChannelType::VirtioConsole => Box::new(virtio_console::get_console_channel(
             acpi.expect("ACPI not available; unable to use virtio console"),
         )),
+        #[cfg(feature = "virtio_vsock_channel")]
+        ChannelType::VirtioMmioVsock => Box::new(virtio_vsock::get_vsock_channel(
+            acpi.expect("ACPI not available; unable to use virtio vsock"),
+        )),
         #[cfg(feature = "vsock_channel")]
         ChannelType::VirtioVsock => Box::new(virtio::get_vsock_channel(alloc)),
         #[cfg(feature = "serial_channel")]
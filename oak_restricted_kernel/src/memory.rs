@@ -26,14 +26,14 @@ use log::info;
 use spinning_top::Spinlock;
 use x86_64::{
     structures::paging::{
-        mapper::FlagUpdateError, page::PageRange, FrameAllocator, Page, PageSize, PhysFrame,
-        Size2MiB,
+        mapper::FlagUpdateError, page::PageRange, FrameAllocator, FrameDeallocator, Page, PageSize,
+        PhysFrame, Size2MiB,
     },
     VirtAddr,
 };
 
 use crate::{
-    mm::{Mapper, PageTableFlags},
+    mm::{self, Mapper, PageTableFlags},
     FRAME_ALLOCATOR, PAGE_TABLES,
 };
 
@@ -140,6 +140,62 @@ impl GrowableHeap {
     }
 }
 
+/// Allocations of at least this size bypass the heap and are backed directly by
+/// contiguous physical frames, which are accessed through the direct mapping of
+/// physical memory.
+///
+/// The heap never shrinks, so without this a large allocation (say, a lookup
+/// data snapshot) would keep its memory tied up in the heap after it has been
+/// freed; large allocations return their frames to the frame allocator instead.
+const LARGE_ALLOCATION_SIZE: usize = Size2MiB::SIZE as usize;
+
+fn is_large_allocation(layout: &Layout) -> bool {
+    layout.size() >= LARGE_ALLOCATION_SIZE && layout.align() <= Size2MiB::SIZE as usize
+}
+
+/// Allocates a large allocation from the frame allocator.
+///
+/// Returns `None` if there is not enough contiguous physical memory, in which
+/// case the caller should fall back to the heap.
+fn allocate_large(layout: &Layout) -> Option<NonNull<u8>> {
+    let count = layout.size().div_ceil(Size2MiB::SIZE as usize);
+    let frames = FRAME_ALLOCATOR.lock().allocate_contiguous(count)?;
+    match mm::direct_mapping(frames) {
+        Some(addr) => NonNull::new(addr.as_mut_ptr()),
+        None => {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            // Safety: we've just allocated these frames and haven't used them.
+            frames.for_each(|frame| unsafe { frame_allocator.deallocate_frame(frame) });
+            None
+        }
+    }
+}
+
+/// Returns the frames of a large allocation to the frame allocator.
+///
+/// Returns `false` if the pointer was not allocated by `allocate_large` (that
+/// is, the allocation fell back to the heap).
+///
+/// # Safety
+///
+/// The caller must guarantee that the pointer and layout match an allocation
+/// that is no longer in use.
+unsafe fn deallocate_large(ptr: NonNull<u8>, layout: &Layout) -> bool {
+    // Heap allocations are never in the direct mapping.
+    let start = match mm::direct_mapping_to_physical(VirtAddr::from_ptr(ptr.as_ptr())) {
+        Some(start) => start,
+        None => return false,
+    };
+    let start = PhysFrame::<Size2MiB>::from_start_address(start)
+        .expect("large allocation is not aligned to a frame");
+    let count = layout.size().div_ceil(Size2MiB::SIZE as usize) as u64;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for frame in PhysFrame::range(start, start + count) {
+        frame_allocator.deallocate_frame(frame);
+    }
+    true
+}
+
 struct LockedGrowableHeap(Spinlock<GrowableHeap>);
 
 impl LockedGrowableHeap {
@@ -158,6 +214,11 @@ impl Deref for LockedGrowableHeap {
 
 unsafe impl GlobalAlloc for LockedGrowableHeap {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if is_large_allocation(&layout) {
+            if let Some(allocation) = allocate_large(&layout) {
+                return allocation.as_ptr();
+            }
+        }
         self.0
             .lock()
             .allocate_first_fit(layout)
@@ -166,7 +227,11 @@ unsafe impl GlobalAlloc for LockedGrowableHeap {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+        let ptr = NonNull::new_unchecked(ptr);
+        if is_large_allocation(&layout) && deallocate_large(ptr, &layout) {
+            return;
+        }
+        self.0.lock().deallocate(ptr, layout)
     }
 }
 
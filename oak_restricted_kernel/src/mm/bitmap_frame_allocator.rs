@@ -94,6 +94,11 @@ impl<S: PageSize, const N: usize> BitmapAllocator<S, N> {
         self.valid.bitand(self.allocated.not())
     }
 
+    /// Returns the range of frames managed by the allocator.
+    pub fn range(&self) -> PhysFrameRange<S> {
+        self.range
+    }
+
     /// Returns the largest contiguous section of unallocated memory.
     pub fn largest_available(&self) -> Option<PhysFrameRange<S>> {
         self.available()
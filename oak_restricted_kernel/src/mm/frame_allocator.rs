@@ -70,6 +70,11 @@ impl<const N: usize> PhysicalMemoryAllocator<N> {
         self.large_frames.mark_valid(range, valid)
     }
 
+    /// Returns the range of physical memory tracked by the allocator.
+    pub fn range(&self) -> PhysFrameRange<Size2MiB> {
+        self.large_frames.range()
+    }
+
     pub fn largest_available(&mut self) -> Option<PhysFrameRange<Size2MiB>> {
         self.large_frames.largest_available()
     }
@@ -20,11 +20,10 @@ use log::info;
 use oak_linux_boot_params::Ramdisk;
 use oak_linux_boot_params::{BootE820Entry, E820EntryType};
 use oak_sev_guest::msr::{get_sev_status, SevStatus};
-#[cfg(feature = "initrd")]
-use x86_64::structures::paging::frame::PhysFrameRange;
 use x86_64::{
     addr::{align_down, align_up},
     structures::paging::{
+        frame::PhysFrameRange,
         mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError},
         FrameAllocator, Page, PageSize, PageTable, PageTableFlags as BasePageTableFlags, PhysFrame,
         Size2MiB, Size4KiB,
@@ -47,6 +46,9 @@ pub const KERNEL_OFFSET: u64 = 0xFFFF_FFFF_8000_0000;
 /// The offset used for the direct mapping of all physical memory.
 const DIRECT_MAPPING_OFFSET: VirtAddr = VirtAddr::new_truncate(0xFFFF_8800_0000_0000);
 
+/// The size of the direct mapping of physical memory (128 GiB).
+const DIRECT_MAPPING_SIZE: u64 = 0x20_0000_0000;
+
 /// For now we use a fixed position for the encrypted bit. For now we assume
 /// that we will be running on AMD Arcadia-Milan CPUs, which use bit 51.
 pub const ENCRYPTED_BIT_POSITION: u8 = 51;
@@ -174,6 +176,7 @@ pub fn init(
     #[cfg(feature = "initrd")] ramdisk: &Ramdisk,
 ) {
     let mut alloc = FRAME_ALLOCATOR.lock();
+    let tracked = alloc.range();
 
     /* Step 1: mark all RAM as available (event though it may contain data!) */
     memory_map
@@ -196,6 +199,19 @@ pub fn init(
                 PhysAddr::new(align_down((e.addr() + e.size()) as u64, Size2MiB::SIZE)),
             )
         })
+        .map(|(start, limit)| {
+            // Clip the range to the memory the frame allocator can track, so that we can
+            // still use the rest of the memory if the machine has more than that.
+            let tracked_limit = tracked.end.start_address();
+            if limit > tracked_limit {
+                log::warn!(
+                    "ignoring memory [{:#018x}..{:#018x}) beyond what the frame allocator can track",
+                    core::cmp::max(start, tracked_limit),
+                    limit
+                );
+            }
+            (start, core::cmp::min(limit, tracked_limit))
+        })
         .filter(|(start, limit)| limit > start)
         .map(|(start, limit)| {
             // Safety: align_down/align_up guarantees we're aligned to 2 MiB boundaries,
@@ -276,6 +292,29 @@ pub fn ramdisk_range(ramdisk: &Ramdisk) -> PhysFrameRange<Size2MiB> {
     )
 }
 
+/// Returns the address of a physical frame range in the direct mapping of all
+/// physical memory, if the whole range is covered by the direct mapping.
+///
+/// Unlike `Translator::translate_physical`, this doesn't need to lock the page
+/// tables.
+pub fn direct_mapping<S: PageSize>(frames: PhysFrameRange<S>) -> Option<VirtAddr> {
+    if frames.end.start_address().as_u64() > DIRECT_MAPPING_SIZE {
+        return None;
+    }
+    Some(DIRECT_MAPPING_OFFSET + frames.start.start_address().as_u64())
+}
+
+/// Returns the physical address for an address in the direct mapping of all
+/// physical memory, or `None` if the address is not in the direct mapping.
+pub fn direct_mapping_to_physical(addr: VirtAddr) -> Option<PhysAddr> {
+    let offset = addr.as_u64().checked_sub(DIRECT_MAPPING_OFFSET.as_u64())?;
+    if offset < DIRECT_MAPPING_SIZE {
+        Some(PhysAddr::new(offset))
+    } else {
+        None
+    }
+}
+
 pub fn encryption() -> MemoryEncryption {
     // Should we set the C-bit (encrypted memory for SEV)? For now, let's assume
     // it's bit 51.
@@ -338,7 +377,7 @@ pub fn initial_pml4(program_headers: &[ProgramHeader]) -> Result<PhysFrame, &'st
         page_tables::create_offset_map(
             PhysFrame::<Size2MiB>::range(
                 PhysFrame::from_start_address(PhysAddr::new(0x00_0000_0000)).unwrap(),
-                PhysFrame::from_start_address(PhysAddr::new(0x20_0000_0000)).unwrap(),
+                PhysFrame::from_start_address(PhysAddr::new(DIRECT_MAPPING_SIZE)).unwrap(),
             ),
             DIRECT_MAPPING_OFFSET,
             PageTableFlags::PRESENT
@@ -401,3 +440,24 @@ pub fn allocate_stack() -> VirtAddr {
 
     (stack_page + 1).start_address()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_mapping_round_trip() {
+        let start = PhysFrame::<Size2MiB>::from_start_address(PhysAddr::new(0x40_0000)).unwrap();
+        let addr = direct_mapping(PhysFrame::range(start, start + 2)).unwrap();
+        assert_eq!(direct_mapping_to_physical(addr), Some(start.start_address()));
+    }
+
+    #[test]
+    fn direct_mapping_out_of_range() {
+        let start =
+            PhysFrame::<Size2MiB>::from_start_address(PhysAddr::new(DIRECT_MAPPING_SIZE)).unwrap();
+        assert_eq!(direct_mapping(PhysFrame::range(start - 1, start + 1)), None);
+        assert_eq!(direct_mapping_to_physical(VirtAddr::new(0xFFFF_C900_0000_0000)), None);
+        assert_eq!(direct_mapping_to_physical(VirtAddr::new(0x20_0000)), None);
+    }
+}
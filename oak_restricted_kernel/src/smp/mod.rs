@@ -0,0 +1,388 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Support for multiple CPUs.
+//!
+//! Stage 0 starts all application processors (APs) and parks them in a wakeup
+//! mailbox in real mode (see `ApWakeupMailbox` in stage0 for the protocol).
+//! We wake them up one by one, pointing them at a trampoline in low memory that
+//! brings them to long mode and into the kernel. Every AP then sets up its own
+//! GDT and TSS and enters an idle loop, where it runs tasks from its run queue
+//! and steals tasks from the other APs when its own queue is empty.
+//!
+//! The bootstrap processor is busy running the application, so it doesn't
+//! take part in scheduling; it only hands out work via [`spawn`].
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::{
+    arch::global_asm,
+    ffi::c_void,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+use oak_core::sync::OnceCell;
+use oak_linux_boot_params::{ApInfoSetupData, BootParams, E820EntryType, SetupDataType};
+use spinning_top::Spinlock;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{acpi::Acpi, avx, descriptors, interrupts, mm, mm::Translator, PAGE_TABLES};
+
+global_asm!(include_str!("trampoline.s"), options(att_syntax, raw));
+
+extern "C" {
+    #[link_name = "ap_trampoline_start"]
+    static AP_TRAMPOLINE_START: c_void;
+    #[link_name = "ap_trampoline_handoff"]
+    static AP_TRAMPOLINE_HANDOFF: c_void;
+    #[link_name = "ap_trampoline_end"]
+    static AP_TRAMPOLINE_END: c_void;
+}
+
+/// Physical address where we place the AP trampoline.
+///
+/// The wakeup vector is entered in real mode, so the trampoline has to be in
+/// the first megabyte of memory; the page tables used by the trampoline follow
+/// the code, as CR3 is loaded in 32-bit mode. This must match
+/// `AP_TRAMPOLINE_BASE` in `trampoline.s`.
+const TRAMPOLINE_ADDRESS: PhysAddr = PhysAddr::new_truncate(0x7_0000);
+
+/// Number of pages used by the trampoline: code, PML4, PDPT and PD.
+const TRAMPOLINE_PAGES: u64 = 4;
+
+/// How long to wait for an AP to acknowledge the wakeup command. As in stage0,
+/// the number is arbitrary and has no connection to actual time.
+const AP_WAKEUP_SPINS: u64 = 1 << 24;
+
+const MAILBOX_COMMAND_NOOP: u16 = 0;
+const MAILBOX_COMMAND_WAKEUP: u16 = 1;
+
+/// The part of the stage0 wakeup mailbox that is relevant to the OS.
+#[repr(C)]
+struct Mailbox {
+    command: AtomicU16,
+    _reserved: u16,
+    apic_id: AtomicU32,
+    wakeup_vector: AtomicU64,
+}
+
+/// Data passed to an AP in the trampoline; see `ap_trampoline_handoff` in
+/// `trampoline.s`.
+#[repr(C)]
+struct Handoff {
+    stack: u64,
+    entry: u64,
+    cpu_index: u64,
+    kernel_cr3: u64,
+}
+
+/// Information about the APs that stage0 has parked in the wakeup mailbox.
+#[derive(Clone, Copy)]
+pub struct ApInfo {
+    ap_count: u32,
+    mailbox_address: PhysAddr,
+}
+
+/// A unit of work that can be run on any AP.
+pub type Task = Box<dyn FnOnce() + Send>;
+
+struct Cpu {
+    online: AtomicBool,
+    run_queue: Spinlock<VecDeque<Task>>,
+}
+
+/// Per-CPU state of the APs, indexed by the CPU index handed to the AP.
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::new();
+
+/// The CPU index that `spawn` will try next.
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+/// Extracts the information about the parked APs from the boot parameters.
+///
+/// Returns `None` if stage0 didn't park any APs for us (for example, under
+/// SEV-ES, where stage0 can't provide the mailbox) or if the memory for the
+/// trampoline is not available.
+///
+/// Like `get_snp_page_addresses`, this function must only be used while the
+/// identity mapping is still in place.
+pub fn get_ap_info(info: &BootParams) -> Option<ApInfo> {
+    let mut setup_data_ptr = info.hdr.setup_data();
+    let mut ap_info = None;
+    while !setup_data_ptr.is_null() {
+        // Safety: stage0 builds a valid null-terminated list of setup data entries.
+        let setup_data = unsafe { &*setup_data_ptr };
+        let type_ = setup_data.type_;
+        if type_ == SetupDataType::OakApInfo {
+            // Safety: setup data entries of this type are always `ApInfoSetupData`.
+            let setup_data = unsafe { &*(setup_data_ptr as *const ApInfoSetupData) };
+            ap_info = Some(ApInfo {
+                ap_count: setup_data.ap_count,
+                mailbox_address: PhysAddr::new(setup_data.mailbox_address),
+            });
+            break;
+        }
+        setup_data_ptr = setup_data.next;
+    }
+    let ap_info = ap_info.filter(|ap_info| ap_info.ap_count > 0)?;
+
+    // The trampoline reuses memory stage0 was using, so make sure it's actually
+    // RAM.
+    let trampoline_start = TRAMPOLINE_ADDRESS.as_u64() as usize;
+    let trampoline_end = trampoline_start + (TRAMPOLINE_PAGES * Size4KiB::SIZE) as usize;
+    if !info.e820_table().iter().any(|entry| {
+        entry.entry_type() == Some(E820EntryType::RAM)
+            && entry.addr() <= trampoline_start
+            && trampoline_end <= entry.end()
+    }) {
+        log::warn!(
+            "[{:#018x}..{:#018x}) is not available for the AP trampoline",
+            trampoline_start,
+            trampoline_end
+        );
+        return None;
+    }
+
+    Some(ap_info)
+}
+
+/// Wakes up the APs parked in the stage0 mailbox and starts the scheduler on
+/// them.
+///
+/// The trampoline overwrites low memory, so this must only be called once the
+/// kernel is done with the boot parameters (and everything else stage0 left in
+/// low memory).
+pub fn start_aps(ap_info: ApInfo, acpi: &Acpi) {
+    let apic_ids = match acpi.application_processors() {
+        Ok(apic_ids) => apic_ids,
+        Err(err) => {
+            log::warn!("not starting APs: {}", err);
+            return;
+        }
+    };
+    if apic_ids.len() != ap_info.ap_count as usize {
+        log::warn!("stage0 parked {} APs, but the MADT lists {}", ap_info.ap_count, apic_ids.len());
+    }
+    if CPUS.set(apic_ids.iter().map(|_| Cpu::new()).collect()).is_err() {
+        panic!("APs have already been started");
+    }
+    let cpus = CPUS.get().unwrap();
+
+    let (kernel_pml4, _) = Cr3::read();
+    let (mailbox, handoff) = {
+        let pt_guard = PAGE_TABLES.lock();
+        let pt = pt_guard.get().unwrap();
+        let handoff = install_trampoline(pt, kernel_pml4);
+        let mailbox = pt
+            .translate_physical(ap_info.mailbox_address)
+            .expect("failed to translate the AP mailbox address");
+        // Safety: stage0 told us this is where the mailbox is, and the APs in it are
+        // only touching the fields with atomic operations.
+        (unsafe { &*mailbox.as_ptr::<Mailbox>() }, handoff)
+    };
+
+    for (index, apic_id) in apic_ids.iter().enumerate() {
+        // Safety: the handoff block is only read by the AP that we're about to wake up,
+        // and we wait for that AP to come online before touching the block again.
+        unsafe {
+            handoff.write_volatile(Handoff {
+                stack: mm::allocate_stack().as_u64(),
+                entry: ap_main as usize as u64,
+                cpu_index: index as u64,
+                kernel_cr3: kernel_pml4.start_address().as_u64(),
+            });
+        }
+        if !wake_up(mailbox, *apic_id) {
+            // We can't reuse the handoff block if there's any chance the AP will still pick
+            // up the command, so don't try to start the remaining APs.
+            log::warn!("AP {} didn't respond; not starting any more APs", apic_id);
+            break;
+        }
+        // Once the AP has acknowledged the command it's on its way to `ap_main`.
+        while !cpus[index].online.load(Ordering::Acquire) {
+            spin_loop();
+        }
+    }
+
+    log::info!("{} of {} APs online", online_ap_count(), apic_ids.len());
+}
+
+/// Returns the number of APs that are online and running the scheduler.
+pub fn online_ap_count() -> usize {
+    CPUS.get()
+        .map(|cpus| cpus.iter().filter(|cpu| cpu.online.load(Ordering::Acquire)).count())
+        .unwrap_or(0)
+}
+
+/// Schedules a task to run on one of the APs.
+///
+/// Tasks are handed out to the APs that are online in a round-robin fashion;
+/// APs that run out of work steal tasks from the others. If no APs are online,
+/// the task is run on the current CPU before returning.
+pub fn spawn<F: FnOnce() + Send + 'static>(task: F) {
+    let cpu = CPUS.get().and_then(|cpus| {
+        (0..cpus.len())
+            .map(|_| &cpus[NEXT_CPU.fetch_add(1, Ordering::Relaxed) % cpus.len()])
+            .find(|cpu| cpu.online.load(Ordering::Acquire))
+    });
+    match cpu {
+        Some(cpu) => cpu.run_queue.lock().push_back(Box::new(task)),
+        None => task(),
+    }
+}
+
+impl Cpu {
+    fn new() -> Self {
+        Self { online: AtomicBool::new(false), run_queue: Spinlock::new(VecDeque::new()) }
+    }
+}
+
+/// Copies the trampoline to low memory and builds the page tables it uses to
+/// enter long mode.
+///
+/// The trampoline page tables identity map the first 2 MiB of memory, so that
+/// the trampoline keeps running after enabling paging, and share the kernel
+/// half with the kernel page tables, so that the trampoline can jump into the
+/// kernel.
+///
+/// Returns a pointer to the handoff block in the trampoline.
+fn install_trampoline<T: Translator>(pt: &T, kernel_pml4: PhysFrame) -> *mut Handoff {
+    // Safety: the symbols are defined in trampoline.s; we're only interested in
+    // their addresses.
+    let (start, handoff, end) = unsafe {
+        (
+            &AP_TRAMPOLINE_START as *const _ as usize,
+            &AP_TRAMPOLINE_HANDOFF as *const _ as usize,
+            &AP_TRAMPOLINE_END as *const _ as usize,
+        )
+    };
+    assert!(end - start <= Size4KiB::SIZE as usize, "AP trampoline doesn't fit into a page");
+
+    let page = |index: u64| {
+        pt.translate_physical(TRAMPOLINE_ADDRESS + index * Size4KiB::SIZE)
+            .expect("failed to translate the AP trampoline address")
+    };
+    let encrypted = mm::encryption().bit();
+
+    // Safety: the first 2 MiB of memory are never handed out by the frame
+    // allocator, `get_ap_info` checked that the trampoline pages are RAM, and
+    // stage0 is done with them.
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, page(0).as_mut_ptr(), end - start);
+    }
+    let (pml4, pdpt, pd) = unsafe {
+        (
+            &mut *page(1).as_mut_ptr::<PageTable>(),
+            &mut *page(2).as_mut_ptr::<PageTable>(),
+            &mut *page(3).as_mut_ptr::<PageTable>(),
+        )
+    };
+    // Safety: CR3 points to the active kernel PML4.
+    let kernel_pml4 = unsafe {
+        &*pt.translate_physical(PhysAddr::new(kernel_pml4.start_address().as_u64() & !encrypted))
+            .expect("failed to translate the kernel PML4 address")
+            .as_ptr::<PageTable>()
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    pml4.zero();
+    for (entry, kernel_entry) in pml4.iter_mut().zip(kernel_pml4.iter()).skip(256) {
+        *entry = kernel_entry.clone();
+    }
+    pml4[0].set_addr(TRAMPOLINE_ADDRESS + 2 * Size4KiB::SIZE, flags);
+    pdpt.zero();
+    pdpt[0].set_addr(TRAMPOLINE_ADDRESS + 3 * Size4KiB::SIZE, flags);
+    pd.zero();
+    pd[0].set_addr(PhysAddr::new(encrypted), flags | PageTableFlags::HUGE_PAGE);
+
+    (page(0) + (handoff - start) as u64).as_mut_ptr()
+}
+
+/// Sends the wakeup command to the AP with the given local APIC ID.
+///
+/// Returns `false` if the AP didn't acknowledge the command in time, in which
+/// case the command has been withdrawn.
+fn wake_up(mailbox: &Mailbox, apic_id: u32) -> bool {
+    mailbox.apic_id.store(apic_id, Ordering::Relaxed);
+    mailbox.wakeup_vector.store(TRAMPOLINE_ADDRESS.as_u64(), Ordering::Relaxed);
+    mailbox.command.store(MAILBOX_COMMAND_WAKEUP, Ordering::Release);
+    for _ in 0..AP_WAKEUP_SPINS {
+        if mailbox.command.load(Ordering::Acquire) == MAILBOX_COMMAND_NOOP {
+            return true;
+        }
+        spin_loop();
+    }
+    // If the exchange fails, the AP acknowledged the command at the last moment.
+    mailbox
+        .command
+        .compare_exchange(
+            MAILBOX_COMMAND_WAKEUP,
+            MAILBOX_COMMAND_NOOP,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+}
+
+/// Entry point for the APs, called from the trampoline.
+extern "C" fn ap_main(cpu_index: u64, kernel_cr3: u64) -> ! {
+    // Switch from the trampoline page tables to the kernel page tables, dropping
+    // the identity mapping of low memory.
+    // Safety: the kernel half of the trampoline page tables is identical to the
+    // kernel page tables.
+    unsafe {
+        Cr3::write(
+            PhysFrame::from_start_address(PhysAddr::new(kernel_cr3)).unwrap(),
+            Cr3Flags::empty(),
+        );
+    }
+    avx::enable_avx();
+    descriptors::init_ap_gdt(mm::allocate_stack());
+    // Safety: we've just loaded a TSS with a valid double fault stack.
+    unsafe {
+        interrupts::load_idt();
+    }
+
+    let index = cpu_index as usize;
+    let cpus = CPUS.get().expect("AP started before the per-CPU state was set up");
+    cpus[index].online.store(true, Ordering::Release);
+    idle(cpus, index)
+}
+
+/// Idle loop of an AP: runs tasks from its own run queue, and steals tasks from
+/// the other APs when its own queue is empty.
+fn idle(cpus: &[Cpu], index: usize) -> ! {
+    loop {
+        match next_task(cpus, index) {
+            Some(task) => task(),
+            // We don't have interrupts to wake us up from `hlt`, so we have to spin.
+            None => spin_loop(),
+        }
+    }
+}
+
+fn next_task(cpus: &[Cpu], index: usize) -> Option<Task> {
+    let task = cpus[index].run_queue.lock().pop_front();
+    task.or_else(|| {
+        // Steal from the back of the other queues, skipping queues that are busy.
+        (1..cpus.len())
+            .map(|offset| &cpus[(index + offset) % cpus.len()])
+            .find_map(|cpu| cpu.run_queue.try_lock().and_then(|mut queue| queue.pop_back()))
+    })
+}
@@ -0,0 +1,105 @@
+/*
+ * Copyright 2024 The Project Oak Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+# Trampoline that takes an application processor from real mode (where stage0 leaves it after the
+# wakeup mailbox command) to long mode, and then jumps into the kernel.
+#
+# The code is not executed in place: the kernel copies everything between `ap_trampoline_start` and
+# `ap_trampoline_end` to `AP_TRAMPOLINE_BASE` in low memory, and places the page tables for the
+# trampoline in the three pages following it. `AP_TRAMPOLINE_BASE` must match `TRAMPOLINE_ADDRESS`
+# in `smp/mod.rs`.
+.set AP_TRAMPOLINE_BASE, 0x70000
+.set AP_TRAMPOLINE_PML4, AP_TRAMPOLINE_BASE + 0x1000
+
+.section .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_handoff
+.global ap_trampoline_end
+
+.code16
+.align 4096
+ap_trampoline_start:
+    cli
+    cld
+    # The mailbox jumps here with CS = AP_TRAMPOLINE_BASE >> 4 and IP = 0, so using CS as the data
+    # segment lets us address everything relative to the start of the trampoline.
+    mov %cs, %ax
+    mov %ax, %ds
+    lgdtl (ap_gdt_descriptor - ap_trampoline_start)
+    mov %cr0, %eax
+    or $0x1, %eax           # CR0.PE
+    mov %eax, %cr0
+    ljmpl $0x8, $(ap_protected_mode - ap_trampoline_start + AP_TRAMPOLINE_BASE)
+
+.code32
+ap_protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    # Enable PAE (required for long mode) and SSE (as the kernel is compiled with SSE enabled).
+    mov %cr4, %eax
+    or $0x620, %eax         # CR4.PAE | CR4.OSFXSR | CR4.OSXMMEXCPT
+    mov %eax, %cr4
+    mov $AP_TRAMPOLINE_PML4, %eax
+    mov %eax, %cr3
+    # Enable long mode and the no-execute bit, as the kernel page tables use it.
+    mov $0xC0000080, %ecx   # ECX = IA32_EFER
+    rdmsr
+    or $0x900, %eax         # EFER.LME | EFER.NXE
+    wrmsr
+    mov %cr0, %eax
+    and $~0x4, %eax         # clear CR0.EM
+    or $0x80000002, %eax    # CR0.PG | CR0.MP
+    mov %eax, %cr0
+    ljmp $0x18, $(ap_long_mode - ap_trampoline_start + AP_TRAMPOLINE_BASE)
+
+.code64
+ap_long_mode:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %fs
+    mov %ax, %gs
+    mov %ax, %ss
+    # Pick up our stack and the kernel entry point from the handoff block. The first two arguments
+    # to the entry point (RDI, RSI) are the CPU index and the kernel page table root.
+    mov (ap_trampoline_handoff - ap_trampoline_start + AP_TRAMPOLINE_BASE), %rsp
+    mov (ap_trampoline_handoff - ap_trampoline_start + AP_TRAMPOLINE_BASE + 8), %rax
+    mov (ap_trampoline_handoff - ap_trampoline_start + AP_TRAMPOLINE_BASE + 16), %rdi
+    mov (ap_trampoline_handoff - ap_trampoline_start + AP_TRAMPOLINE_BASE + 24), %rsi
+    # Same as in boot.s: we jmp rather than call, so fix the stack alignment.
+    push $0
+    jmp *%rax
+
+.align 16
+ap_gdt:
+    .quad 0x0000000000000000  # null descriptor
+    .quad 0x00CF9A000000FFFF  # 0x08: 32-bit code
+    .quad 0x00CF92000000FFFF  # 0x10: data
+    .quad 0x00AF9A000000FFFF  # 0x18: 64-bit code
+ap_gdt_descriptor:
+    .word ap_gdt_descriptor - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start + AP_TRAMPOLINE_BASE
+
+# Filled in by the bootstrap processor before waking up each AP; see `Handoff` in `smp/mod.rs`.
+.align 8
+ap_trampoline_handoff:
+    .quad 0                 # stack pointer
+    .quad 0                 # entry point
+    .quad 0                 # CPU index
+    .quad 0                 # kernel page table root
+ap_trampoline_end:
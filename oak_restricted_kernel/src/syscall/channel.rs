@@ -35,6 +35,10 @@ impl ChannelDescriptor {
 impl FileDescriptor for ChannelDescriptor {
     fn read(&mut self, buf: &mut [u8]) -> Result<isize, Errno> {
         let size: isize = buf.len().try_into().map_err(|_| Errno::EINVAL)?;
+        // Reading from the channel is where the application waits for work, so it's a
+        // good time to give memory to or take memory from the host.
+        #[cfg(any(feature = "virtio_console_channel", feature = "virtio_vsock_channel"))]
+        crate::virtio_balloon::poll();
         self.channel.read_exact(buf).map_err(|_| Errno::EIO).map(|_| size)
     }
 
@@ -80,6 +80,9 @@ impl FileDescriptor for DerivedKeyDescriptor {
                 if write_state.index == data_as_slice.len() {
                     let derived_key =
                         <DerivedKey as zerocopy::FromBytes>::read_from(data_as_slice).unwrap();
+                    // The key is that of the application that the current one will switch to,
+                    // which is the only one that gets to seal data with it.
+                    super::seal::register(&derived_key);
                     let _ = core::mem::replace(
                         self,
                         Self::Readable(DerivedKeyState { index: 0, data: derived_key }),
@@ -97,7 +100,11 @@ impl FileDescriptor for DerivedKeyDescriptor {
 }
 
 /// Registers a file descriptor for reading a derived key (0x21)
+///
+/// Once the key is known, this also makes the key available for sealing data.
 pub fn register(#[cfg(not(feature = "initrd"))] key: DerivedKey) {
+    #[cfg(not(feature = "initrd"))]
+    super::seal::register(&key);
     super::fd::register(
         DERIVED_KEY_FD,
         Box::new(DerivedKeyDescriptor::new(
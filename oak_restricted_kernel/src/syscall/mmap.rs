@@ -27,7 +27,9 @@ use oak_restricted_kernel_interface::{
 };
 use x86_64::{
     align_up,
-    structures::paging::{FrameAllocator, Page, PageSize, Size2MiB},
+    structures::paging::{
+        mapper::UnmapError, FrameAllocator, FrameDeallocator, Page, PageSize, Size2MiB,
+    },
     VirtAddr,
 };
 
@@ -195,3 +197,47 @@ pub fn syscall_mmap(
     mmap(Some(VirtAddr::from_ptr(addr)), size, prot, flags)
         .map_or_else(|err| err as isize, |ptr| ptr.as_ptr() as isize)
 }
+
+pub fn munmap(addr: VirtAddr, size: usize) -> Result<(), Errno> {
+    if size == 0 || !addr.is_aligned(Size2MiB::SIZE) || addr.as_u64() < Size2MiB::SIZE {
+        log::warn!("invalid range passed to munmap: {:?}, size {}", addr, size);
+        return Err(Errno::EINVAL);
+    }
+    let end = addr
+        .as_u64()
+        .checked_add(align_up(size as u64, Size2MiB::SIZE))
+        .filter(|end| *end <= u64::pow(2, 47))
+        .ok_or_else(|| {
+            log::warn!("munmap: range extends beyond user space: {:?}, size {}", addr, size);
+            Errno::EINVAL
+        })?;
+    let pages = Page::<Size2MiB>::range(
+        Page::containing_address(addr),
+        Page::containing_address(VirtAddr::new(end)),
+    );
+
+    let pt_guard = PAGE_TABLES.lock();
+    let pt = pt_guard.get().unwrap();
+    for page in pages {
+        // Safety: the range is in user space, so the kernel doesn't hold any references
+        // to that memory; if the process still does, that's its own problem.
+        match unsafe { pt.unmap(page) } {
+            Ok((frame, flush)) => {
+                flush.flush();
+                // Safety: the frame was allocated by `mmap`, and it's no longer mapped.
+                unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+            }
+            // Unmapping pages that aren't mapped is not an error.
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => {
+                log::warn!("munmap: failed to unmap {:?}: {:?}", page, err);
+                return Err(Errno::EINVAL);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn syscall_munmap(addr: *mut c_void, size: c_size_t) -> isize {
+    munmap(VirtAddr::from_ptr(addr), size).map_or_else(|err| err as isize, |()| 0)
+}
@@ -20,6 +20,7 @@ mod fd;
 mod key;
 pub mod mmap;
 mod process;
+mod seal;
 mod stdio;
 
 #[cfg(feature = "initrd")]
@@ -47,8 +48,9 @@ use x86_64::{
 use self::switch_process::syscall_unstable_switch_proccess;
 use self::{
     fd::{syscall_fsync, syscall_read, syscall_write},
-    mmap::syscall_mmap,
+    mmap::{syscall_mmap, syscall_munmap},
     process::syscall_exit,
+    seal::{syscall_seal, syscall_unseal},
 };
 use crate::mm;
 
@@ -129,7 +131,12 @@ extern "sysv64" fn syscall_handler(
         Some(Syscall::Mmap) => {
             syscall_mmap(arg1 as *const c_void, arg2, arg3, arg4, arg5 as i32, arg6)
         }
+        Some(Syscall::Munmap) => syscall_munmap(arg1 as *mut c_void, arg2),
         Some(Syscall::Fsync) => syscall_fsync(arg1 as i32),
+        Some(Syscall::Seal) => syscall_seal(arg1 as *const c_void, arg2, arg3 as *mut c_void, arg4),
+        Some(Syscall::Unseal) => {
+            syscall_unseal(arg1 as *const c_void, arg2, arg3 as *mut c_void, arg4)
+        }
         #[cfg(feature = "initrd")]
         Some(Syscall::UnstableSwitchProcess) => {
             syscall_unstable_switch_proccess(arg1 as *mut c_void, arg2)
@@ -15,5 +15,10 @@
 //
 
 pub fn syscall_exit(status: i32) -> isize {
+    if status == 0 {
+        // A clean exit is how applications ask for the enclave to be shut down.
+        log::info!("User code exited, shutting down");
+        crate::shutdown::shutdown();
+    }
     panic!("User code terminated with status code: {}", status);
 }
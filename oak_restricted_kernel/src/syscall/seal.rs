@@ -0,0 +1,190 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Sealing of application data under a key derived from the application's
+//! DICE sealing key, so that the (untrusted) host can store the data for the
+//! application across restarts without learning or modifying it.
+//!
+//! The host still chooses which sealed data to hand back, if any: it can roll
+//! the data back to an older blob, or reset it by withholding the data, e.g. to
+//! restore a consumed budget. Applications can't tell the latter from a first
+//! launch.
+
+use core::{
+    ffi::{c_size_t, c_ssize_t, c_void},
+    slice,
+};
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, Key, KeyInit, Nonce, Tag};
+use hkdf::Hkdf;
+use oak_core::sync::OnceCell;
+use oak_restricted_kernel_dice::DerivedKey;
+use oak_restricted_kernel_interface::{syscalls::SEALED_DATA_OVERHEAD, Errno};
+use sha2::Sha256;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+static_assertions::const_assert_eq!(NONCE_SIZE + TAG_SIZE, SEALED_DATA_OVERHEAD);
+
+/// Domain separator for the sealing key, so that blobs can't be decrypted with
+/// the derived key that the application reads from `DERIVED_KEY_FD`.
+const SEALING_KEY_INFO: &[u8] = b"oak-restricted-kernel-seal";
+
+static SEALING_KEY: OnceCell<Aes256Gcm> = OnceCell::new();
+
+fn sealing_key(derived_key: &DerivedKey) -> Aes256Gcm {
+    let hkdf = Hkdf::<Sha256>::new(None, derived_key);
+    let mut key = [0u8; 32];
+    hkdf.expand(SEALING_KEY_INFO, &mut key).expect("invalid length for sealing key");
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    zeroize::Zeroize::zeroize(&mut key);
+    cipher
+}
+
+/// Makes the seal and unseal syscalls available, with a key derived from the
+/// derived key of the application.
+pub fn register(derived_key: &DerivedKey) {
+    if SEALING_KEY.set(sealing_key(derived_key)).is_err() {
+        panic!("sealing key already registered");
+    }
+}
+
+// Layout of a sealed blob: nonce || ciphertext || tag.
+fn seal(cipher: &Aes256Gcm, data: &[u8], blob: &mut [u8]) -> Result<usize, Errno> {
+    let blob_len = data.len().checked_add(SEALED_DATA_OVERHEAD).ok_or(Errno::EINVAL)?;
+    if blob.len() < blob_len {
+        return Err(Errno::EINVAL);
+    }
+    let (nonce, rest) = blob.split_at_mut(NONCE_SIZE);
+    let (ciphertext, rest) = rest.split_at_mut(data.len());
+    getrandom::getrandom(nonce).map_err(|_| Errno::EIO)?;
+    ciphertext.copy_from_slice(data);
+    let tag = cipher
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), &[], ciphertext)
+        .map_err(|_| Errno::EINVAL)?;
+    rest[..TAG_SIZE].copy_from_slice(&tag);
+    Ok(blob_len)
+}
+
+fn unseal(cipher: &Aes256Gcm, blob: &[u8], data: &mut [u8]) -> Result<usize, Errno> {
+    let data_len = blob.len().checked_sub(SEALED_DATA_OVERHEAD).ok_or(Errno::EINVAL)?;
+    if data.len() < data_len {
+        return Err(Errno::EINVAL);
+    }
+    let (nonce, rest) = blob.split_at(NONCE_SIZE);
+    let (ciphertext, tag) = rest.split_at(data_len);
+    let plaintext = &mut data[..data_len];
+    plaintext.copy_from_slice(ciphertext);
+    if cipher
+        .decrypt_in_place_detached(Nonce::from_slice(nonce), &[], plaintext, Tag::from_slice(tag))
+        .is_err()
+    {
+        // Don't leave the unauthenticated ciphertext in the buffer.
+        zeroize::Zeroize::zeroize(plaintext);
+        return Err(Errno::EINVAL);
+    }
+    Ok(data_len)
+}
+
+pub fn syscall_seal(
+    data: *const c_void,
+    data_len: c_size_t,
+    blob: *mut c_void,
+    blob_len: c_size_t,
+) -> c_ssize_t {
+    // We should validate that the pointers and sizes are valid, as these come from
+    // userspace and therefore are not to be trusted, but right now everything
+    // is in kernel space so there is nothing to check.
+    let data = unsafe { slice::from_raw_parts(data as *const u8, data_len) };
+    let blob = unsafe { slice::from_raw_parts_mut(blob as *mut u8, blob_len) };
+
+    match SEALING_KEY.get() {
+        Some(cipher) => {
+            seal(cipher, data, blob).map_or_else(|err| err as isize, |len| len as isize)
+        }
+        None => Errno::ENOSYS as isize,
+    }
+}
+
+pub fn syscall_unseal(
+    blob: *const c_void,
+    blob_len: c_size_t,
+    data: *mut c_void,
+    data_len: c_size_t,
+) -> c_ssize_t {
+    // See the comment in `syscall_seal` about validating the pointers.
+    let blob = unsafe { slice::from_raw_parts(blob as *const u8, blob_len) };
+    let data = unsafe { slice::from_raw_parts_mut(data as *mut u8, data_len) };
+
+    match SEALING_KEY.get() {
+        Some(cipher) => {
+            unseal(cipher, blob, data).map_or_else(|err| err as isize, |len| len as isize)
+        }
+        None => Errno::ENOSYS as isize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_unseal_round_trip() {
+        let cipher = sealing_key(&[7; 32]);
+        let data = b"remaining budget";
+        let mut blob = [0u8; 64];
+        let blob_len = seal(&cipher, data, &mut blob).unwrap();
+        assert_eq!(blob_len, data.len() + SEALED_DATA_OVERHEAD);
+
+        let mut unsealed = [0u8; 64];
+        let data_len = unseal(&cipher, &blob[..blob_len], &mut unsealed).unwrap();
+        assert_eq!(&unsealed[..data_len], data);
+    }
+
+    #[test]
+    fn unseal_fails_for_other_key_or_tampered_blob() {
+        let cipher = sealing_key(&[7; 32]);
+        let data = b"remaining budget";
+        let mut blob = [0u8; 64];
+        let blob_len = seal(&cipher, data, &mut blob).unwrap();
+        let mut unsealed = [0u8; 64];
+
+        let other_cipher = sealing_key(&[8; 32]);
+        assert_eq!(unseal(&other_cipher, &blob[..blob_len], &mut unsealed), Err(Errno::EINVAL));
+
+        blob[NONCE_SIZE] ^= 1;
+        assert_eq!(unseal(&cipher, &blob[..blob_len], &mut unsealed), Err(Errno::EINVAL));
+        assert_eq!(&unsealed[..data.len()], &[0; 16]);
+    }
+
+    #[test]
+    fn buffers_too_small() {
+        let cipher = sealing_key(&[7; 32]);
+        let data = b"remaining budget";
+        let mut blob = [0u8; 16 + SEALED_DATA_OVERHEAD - 1];
+        assert_eq!(seal(&cipher, data, &mut blob), Err(Errno::EINVAL));
+
+        let mut blob = [0u8; 16 + SEALED_DATA_OVERHEAD];
+        let blob_len = seal(&cipher, data, &mut blob).unwrap();
+        let mut unsealed = [0u8; 15];
+        assert_eq!(unseal(&cipher, &blob[..blob_len], &mut unsealed), Err(Errno::EINVAL));
+        assert_eq!(
+            unseal(&cipher, &blob[..SEALED_DATA_OVERHEAD - 1], &mut unsealed),
+            Err(Errno::EINVAL)
+        );
+    }
+}
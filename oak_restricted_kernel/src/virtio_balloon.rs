@@ -0,0 +1,210 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A driver for the virtio memory balloon, through which the launcher can
+//! reclaim memory from the guest and give it back later.
+//!
+//! The launcher sets the number of pages it wants the balloon to hold, and the
+//! kernel inflates the balloon by taking frames out of the frame allocator and
+//! handing them to the VMM, or deflates it by returning them to the frame
+//! allocator. We don't get interrupts from the device, so the balloon is only
+//! adjusted when the application reads from the communication channel, which
+//! is where it spends its time when idle.
+//!
+//! The balloon is inflated in 2 MiB frames, as that is the granularity of the
+//! frame allocator, so its size may be up to a frame short of the target.
+
+use alloc::vec::Vec;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+
+use log::{info, warn};
+use oak_sev_guest::msr::SevStatus;
+use spinning_top::Spinlock;
+use virtio_drivers::{
+    queue::VirtQueue,
+    transport::{mmio::MmioTransport, DeviceType, Transport},
+};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+use zerocopy::AsBytes;
+
+use crate::{
+    acpi::Acpi,
+    virtio_mmio::{find_device, OakHal},
+    FRAME_ALLOCATOR,
+};
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+const QUEUE_SIZE: usize = 8;
+
+/// The balloon counts in 4 KiB pages, regardless of the guest page size.
+const PAGES_PER_FRAME: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+bitflags::bitflags! {
+    /// We don't need any of the optional features, as we always tell the host
+    /// about the pages we take out of the balloon before using them.
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    struct BalloonFeatures: u64 {
+        const MUST_TELL_HOST = 1 << 0;
+    }
+}
+
+#[repr(C)]
+struct BalloonConfig {
+    /// Number of pages the host wants the balloon to hold.
+    num_pages: u32,
+    /// Number of pages the balloon holds.
+    actual: u32,
+}
+
+struct Balloon {
+    transport: MmioTransport,
+    config: NonNull<BalloonConfig>,
+    inflate: VirtQueue<OakHal, QUEUE_SIZE>,
+    deflate: VirtQueue<OakHal, QUEUE_SIZE>,
+    /// Frames that we have handed to the host.
+    frames: Vec<PhysFrame<Size2MiB>>,
+    /// Page frame numbers of the frame being inflated or deflated.
+    pfns: [u32; PAGES_PER_FRAME],
+}
+
+// Safety: the balloon is only accessed with the `BALLOON` lock held.
+unsafe impl Send for Balloon {}
+
+static BALLOON: Spinlock<Option<Balloon>> = Spinlock::new(None);
+
+impl Balloon {
+    fn new(mut transport: MmioTransport) -> virtio_drivers::Result<Self> {
+        transport.begin_init(BalloonFeatures::empty());
+        let config = transport.config_space::<BalloonConfig>()?;
+        let inflate = VirtQueue::new(&mut transport, INFLATE_QUEUE, false, false)?;
+        let deflate = VirtQueue::new(&mut transport, DEFLATE_QUEUE, false, false)?;
+        transport.finish_init();
+        Ok(Self {
+            transport,
+            config,
+            inflate,
+            deflate,
+            frames: Vec::new(),
+            pfns: [0; PAGES_PER_FRAME],
+        })
+    }
+
+    fn target_frames(&self) -> usize {
+        // Safety: the config space is valid for as long as the transport is.
+        let num_pages = unsafe { addr_of!((*self.config.as_ptr()).num_pages).read_volatile() };
+        num_pages as usize / PAGES_PER_FRAME
+    }
+
+    fn set_actual(&mut self) {
+        let actual = (self.frames.len() * PAGES_PER_FRAME) as u32;
+        // Safety: the config space is valid for as long as the transport is.
+        unsafe { addr_of_mut!((*self.config.as_ptr()).actual).write_volatile(actual) };
+    }
+
+    fn set_pfns(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first_pfn = (frame.start_address().as_u64() / Size4KiB::SIZE) as u32;
+        for (i, pfn) in self.pfns.iter_mut().enumerate() {
+            *pfn = first_pfn + i as u32;
+        }
+    }
+
+    /// Hands one more frame to the host. Returns false if there is no free
+    /// frame to give.
+    fn inflate_frame(&mut self) -> virtio_drivers::Result<bool> {
+        let frame: PhysFrame<Size2MiB> = match FRAME_ALLOCATOR.lock().allocate_frame() {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        self.set_pfns(frame);
+        if let Err(err) =
+            self.inflate.add_notify_wait_pop(&[self.pfns.as_bytes()], &mut [], &mut self.transport)
+        {
+            // Safety: the host didn't take the frame, so it's still ours.
+            unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+            return Err(err);
+        }
+        self.frames.push(frame);
+        Ok(true)
+    }
+
+    /// Takes the most recently inflated frame back from the host and returns it
+    /// to the frame allocator.
+    fn deflate_frame(&mut self) -> virtio_drivers::Result<()> {
+        let frame = match self.frames.last() {
+            Some(frame) => *frame,
+            None => return Ok(()),
+        };
+        self.set_pfns(frame);
+        self.deflate.add_notify_wait_pop(&[self.pfns.as_bytes()], &mut [], &mut self.transport)?;
+        self.frames.pop();
+        // Safety: the frame was allocated from the frame allocator when inflating, and
+        // the host has given it back to us.
+        unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+        Ok(())
+    }
+
+    fn adjust(&mut self) -> virtio_drivers::Result<()> {
+        let target = self.target_frames();
+        let initial = self.frames.len();
+        while self.frames.len() < target {
+            if !self.inflate_frame()? {
+                break;
+            }
+        }
+        while self.frames.len() > target {
+            self.deflate_frame()?;
+        }
+        if self.frames.len() != initial {
+            info!(
+                "Memory balloon holds {} MiB (target {} MiB)",
+                self.frames.len() * (Size2MiB::SIZE >> 20) as usize,
+                target * (Size2MiB::SIZE >> 20) as usize
+            );
+            self.set_actual();
+        }
+        Ok(())
+    }
+}
+
+/// Sets up the memory balloon, if the VMM provides one.
+pub fn init(acpi: &mut Acpi, sev_status: SevStatus) {
+    let transport = match find_device(acpi, DeviceType::MemoryBallooning) {
+        Some(transport) => transport,
+        None => return,
+    };
+    // Handing encrypted memory back to the host would require changing its page
+    // state first, which we don't support.
+    if !sev_status.is_empty() {
+        warn!("Ignoring the memory balloon, as it isn't supported with SEV");
+        return;
+    }
+    match Balloon::new(transport) {
+        Ok(balloon) => *BALLOON.lock() = Some(balloon),
+        Err(err) => warn!("Failed to initialize the memory balloon: {:?}", err),
+    }
+}
+
+/// Inflates or deflates the memory balloon to the size the host asked for.
+pub fn poll() {
+    if let Some(balloon) = BALLOON.lock().as_mut() {
+        if let Err(err) = balloon.adjust() {
+            warn!("Failed to adjust the memory balloon: {:?}", err);
+        }
+    }
+}
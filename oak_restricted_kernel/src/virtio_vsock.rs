@@ -0,0 +1,142 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A channel over a virtio-vsock stream socket, which moves data in packets of
+//! up to 64 KiB instead of a byte at a time like the virtio console.
+
+use anyhow::{anyhow, bail};
+use log::info;
+use oak_channel::{Read, Write};
+use spinning_top::Spinlock;
+use virtio_drivers::{
+    device::socket::{
+        SocketError, VirtIOSocket, VsockAddr, VsockConnectionManager, VsockEventType,
+    },
+    transport::{mmio::MmioTransport, DeviceType},
+    Error,
+};
+
+use crate::{
+    acpi::Acpi,
+    virtio_mmio::{find_device, OakHal},
+};
+
+// The port on which to listen for the connection of the launcher.
+const VSOCK_PORT: u32 = 1024;
+
+// The largest packet the host accepts, see `VIRTIO_VSOCK_MAX_PKT_BUF_SIZE` in
+// Linux.
+const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+struct Connection {
+    manager: VsockConnectionManager<OakHal, MmioTransport>,
+    peer: VsockAddr,
+}
+
+impl Connection {
+    // Waits for the next event of the connection, which the connection manager
+    // has handled already, e.g. by buffering received data.
+    fn wait(&mut self) -> anyhow::Result<()> {
+        let event = self
+            .manager
+            .wait_for_event()
+            .map_err(|err| anyhow!("Virtio vsock error: {:?}", err))?;
+        if event.source == self.peer
+            && matches!(event.event_type, VsockEventType::Disconnected { .. })
+        {
+            bail!("the launcher closed the vsock connection");
+        }
+        Ok(())
+    }
+}
+
+pub struct MmioVsockChannel {
+    inner: Spinlock<Connection>,
+}
+
+// Safety: for now, this is safe as we don't have threads in our kernel.
+// TODO(#3531): this will most likely break once we do add threads, though.
+unsafe impl Sync for MmioVsockChannel {}
+unsafe impl Send for MmioVsockChannel {}
+
+impl Read for MmioVsockChannel {
+    fn read_exact(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        let mut connection = self.inner.lock();
+        let peer = connection.peer;
+
+        let mut count = 0;
+        while count < data.len() {
+            let read = connection
+                .manager
+                .recv(peer, VSOCK_PORT, &mut data[count..])
+                .map_err(|err| anyhow!("Virtio vsock read error: {:?}", err))?;
+            if read == 0 {
+                connection.wait()?;
+            }
+            count += read;
+        }
+        // Let the launcher know it can send more, as it doesn't ask for credit.
+        connection
+            .manager
+            .update_credit(peer, VSOCK_PORT)
+            .map_err(|err| anyhow!("Virtio vsock credit update error: {:?}", err))
+    }
+}
+
+impl Write for MmioVsockChannel {
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut connection = self.inner.lock();
+        let peer = connection.peer;
+
+        for chunk in data.chunks(MAX_PAYLOAD_SIZE) {
+            loop {
+                match connection.manager.send(peer, VSOCK_PORT, chunk) {
+                    Ok(()) => break,
+                    // Wait until the launcher has read enough to make room for the chunk.
+                    Err(Error::SocketDeviceError(SocketError::InsufficientBufferSpaceInPeer)) => {
+                        connection.wait()?
+                    }
+                    Err(err) => bail!("Virtio vsock write error: {:?}", err),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn get_vsock_channel(acpi: &mut Acpi) -> MmioVsockChannel {
+    let transport = find_device(acpi, DeviceType::Socket).expect("No virtio vsock devices found");
+    let mut manager = VsockConnectionManager::new(
+        VirtIOSocket::<OakHal, _>::new(transport).expect("error initializing vsock"),
+    );
+    manager.listen(VSOCK_PORT);
+    info!("Waiting for a vsock connection on port {}", VSOCK_PORT);
+    // The connection manager accepts connections to ports it listens on.
+    let peer = loop {
+        let event = manager.wait_for_event().expect("error waiting for vsock connection");
+        if event.destination.port == VSOCK_PORT
+            && matches!(event.event_type, VsockEventType::ConnectionRequest)
+        {
+            break event.source;
+        }
+    };
+    MmioVsockChannel { inner: Spinlock::new(Connection { manager, peer }) }
+}
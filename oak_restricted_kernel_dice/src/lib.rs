@@ -23,7 +23,7 @@
 
 extern crate alloc;
 
-use alloc::vec;
+use alloc::{vec, vec::Vec};
 
 use coset::{cbor::Value, cwt::ClaimName, CborSerializable};
 use hkdf::Hkdf;
@@ -62,10 +62,12 @@ fn certificate_to_byte_array(cert: coset::CoseSign1) -> [u8; oak_dice::evidence:
     slice
 }
 
-/// Generates attestation evidence for the 'measurement' of the application.
+/// Generates attestation evidence for the 'measurement' of the application,
+/// and of its config if there is one.
 pub fn generate_dice_data(
     stage0_dice_data: oak_dice::evidence::Stage0DiceData,
     app_digest: &AppDigestSha2_256,
+    app_config_digest: Option<&AppDigestSha2_256>,
 ) -> oak_dice::evidence::RestrictedKernelDiceData {
     let (application_keys, application_private_keys): (
         oak_dice::evidence::ApplicationKeys,
@@ -102,10 +104,8 @@ pub fn generate_dice_data(
                     Value::Integer(FINAL_LAYER_CONFIG_MEASUREMENT_ID.into()),
                     Value::Map(vec![(
                         Value::Integer(SHA2_256_ID.into()),
-                        // There currently exists no application config for enclave
-                        // applications. Hence this field should
-                        // always be empty.
-                        Value::Bytes([0; 0].into()),
+                        // Empty if the application has no config.
+                        Value::Bytes(app_config_digest.map_or(Vec::new(), |digest| digest.into())),
                     )]),
                 ),
             ]),
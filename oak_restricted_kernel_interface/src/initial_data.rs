@@ -0,0 +1,294 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! The initial payload that the launcher sends over the communication channel
+//! before anything else, which holds the application to run.
+//!
+//! The payload is either a single ELF binary, or a bundle of named ELF
+//! binaries together with a selector naming the one to run. Bundles let the
+//! same image carry several versions of an application (for example, for A/B
+//! rollouts), with the host choosing between them at launch time. Only the
+//! selected binary is measured, so the attestation of an application is the
+//! same regardless of whether it was sent on its own or as part of a bundle.
+//!
+//! A bundle is encoded as follows, with all integers in little-endian order:
+//!
+//! ```text
+//! BUNDLE_MAGIC
+//! u16 selector length, selector
+//! u16 number of applications
+//! for each application:
+//!     u16 name length, name
+//!     u64 binary length, binary
+//! ```
+//!
+//! Either payload may be preceded by an application config, which the
+//! Restricted Kernel measures into the evidence of the application next to its
+//! binary:
+//!
+//! ```text
+//! CONFIG_MAGIC
+//! u64 config length, config
+//! single ELF binary or bundle
+//! ```
+
+use alloc::vec::Vec;
+
+use strum::Display;
+
+/// Prefix that tells a bundle apart from a single ELF binary, which starts
+/// with `\x7fELF`.
+pub const BUNDLE_MAGIC: &[u8; 8] = b"OAKAPPS\0";
+
+/// Prefix of an application config that precedes the application binary or
+/// bundle.
+pub const CONFIG_MAGIC: &[u8; 8] = b"OAKCONF\0";
+
+/// Errors returned when parsing the initial payload.
+#[derive(Debug, Display, Eq, PartialEq)]
+pub enum InitialDataError {
+    /// The bundle or the application config ended before all the fields were
+    /// read.
+    Truncated,
+    /// The bundle has trailing bytes after the last application.
+    TrailingData,
+    /// An application name or the selector isn't valid UTF-8.
+    InvalidName,
+    /// Two applications in the bundle have the same name.
+    DuplicateApplication,
+    /// The selector doesn't name an application in the bundle.
+    UnknownSelector,
+}
+
+/// An application binary, and the name it has in the bundle.
+#[derive(Debug, Eq, PartialEq)]
+pub struct NamedApplication<'a> {
+    pub name: &'a str,
+    pub binary: &'a [u8],
+}
+
+/// The parsed initial payload.
+#[derive(Debug, Eq, PartialEq)]
+pub enum InitialData<'a> {
+    /// A single application binary, as sent by launchers that don't use
+    /// bundles.
+    Application(&'a [u8]),
+    /// A bundle of applications, of which `selector` names the one to run.
+    Bundle { selector: &'a str, applications: Vec<NamedApplication<'a>> },
+}
+
+impl<'a> InitialData<'a> {
+    /// Parses the initial payload. Anything that doesn't start with
+    /// [`BUNDLE_MAGIC`] is treated as a single application binary.
+    pub fn parse(payload: &'a [u8]) -> Result<Self, InitialDataError> {
+        let mut reader = match payload.strip_prefix(BUNDLE_MAGIC.as_slice()) {
+            Some(bundle) => Reader(bundle),
+            None => return Ok(Self::Application(payload)),
+        };
+        let selector = reader.read_name()?;
+        let count = reader.read_u16()?;
+        let mut applications: Vec<NamedApplication> = Vec::with_capacity(count.into());
+        for _ in 0..count {
+            let name = reader.read_name()?;
+            let len =
+                usize::try_from(reader.read_u64()?).map_err(|_| InitialDataError::Truncated)?;
+            let binary = reader.read_bytes(len)?;
+            if applications.iter().any(|application| application.name == name) {
+                return Err(InitialDataError::DuplicateApplication);
+            }
+            applications.push(NamedApplication { name, binary });
+        }
+        if !reader.0.is_empty() {
+            return Err(InitialDataError::TrailingData);
+        }
+        if !applications.iter().any(|application| application.name == selector) {
+            return Err(InitialDataError::UnknownSelector);
+        }
+        Ok(Self::Bundle { selector, applications })
+    }
+
+    /// Returns the name of the application to run, if the payload is a bundle.
+    pub fn selector(&self) -> Option<&'a str> {
+        match self {
+            Self::Application(_) => None,
+            Self::Bundle { selector, .. } => Some(selector),
+        }
+    }
+
+    /// Returns the binary of the application to run.
+    pub fn selected_application(&self) -> &'a [u8] {
+        match self {
+            Self::Application(binary) => binary,
+            Self::Bundle { selector, applications } => {
+                applications
+                    .iter()
+                    .find(|application| application.name == *selector)
+                    .expect("selector was validated when parsing")
+                    .binary
+            }
+        }
+    }
+}
+
+/// Splits the application config off the initial payload, returning the
+/// config, which is empty if the payload has none, and the rest of the payload
+/// to pass to [`InitialData::parse`].
+pub fn split_application_config(payload: &[u8]) -> Result<(&[u8], &[u8]), InitialDataError> {
+    let mut reader = match payload.strip_prefix(CONFIG_MAGIC.as_slice()) {
+        Some(config) => Reader(config),
+        None => return Ok((&[], payload)),
+    };
+    let len = usize::try_from(reader.read_u64()?).map_err(|_| InitialDataError::Truncated)?;
+    let config = reader.read_bytes(len)?;
+    Ok((config, reader.0))
+}
+
+/// Prepends `config` to the initial payload, so that the Restricted Kernel
+/// measures it as the application config.
+pub fn encode_application_config(config: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut result = CONFIG_MAGIC.to_vec();
+    result.extend_from_slice(&(config.len() as u64).to_le_bytes());
+    result.extend_from_slice(config);
+    result.extend_from_slice(payload);
+    result
+}
+
+/// Encodes a bundle of applications, of which `selector` names the one to run.
+pub fn encode_bundle(selector: &str, applications: &[NamedApplication]) -> Vec<u8> {
+    let mut result = BUNDLE_MAGIC.to_vec();
+    write_name(&mut result, selector);
+    result.extend_from_slice(
+        &u16::try_from(applications.len()).expect("too many applications").to_le_bytes(),
+    );
+    for application in applications {
+        write_name(&mut result, application.name);
+        result.extend_from_slice(&(application.binary.len() as u64).to_le_bytes());
+        result.extend_from_slice(application.binary);
+    }
+    result
+}
+
+fn write_name(buffer: &mut Vec<u8>, name: &str) {
+    buffer.extend_from_slice(&u16::try_from(name.len()).expect("name too long").to_le_bytes());
+    buffer.extend_from_slice(name.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], InitialDataError> {
+        if self.0.len() < len {
+            return Err(InitialDataError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, InitialDataError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, InitialDataError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_name(&mut self) -> Result<&'a str, InitialDataError> {
+        let len = self.read_u16()?;
+        core::str::from_utf8(self.read_bytes(len.into())?)
+            .map_err(|_| InitialDataError::InvalidName)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APPLICATION_A: &[u8] = b"\x7fELF application a";
+    const APPLICATION_B: &[u8] = b"\x7fELF application b";
+
+    fn bundle(selector: &str) -> Vec<u8> {
+        encode_bundle(
+            selector,
+            &[
+                NamedApplication { name: "a", binary: APPLICATION_A },
+                NamedApplication { name: "b", binary: APPLICATION_B },
+            ],
+        )
+    }
+
+    #[test]
+    fn single_application() {
+        let initial_data = InitialData::parse(APPLICATION_A).unwrap();
+        assert_eq!(initial_data, InitialData::Application(APPLICATION_A));
+        assert_eq!(initial_data.selector(), None);
+        assert_eq!(initial_data.selected_application(), APPLICATION_A);
+    }
+
+    #[test]
+    fn bundle_selects_application() {
+        let payload = bundle("b");
+        let initial_data = InitialData::parse(&payload).unwrap();
+        assert_eq!(initial_data.selector(), Some("b"));
+        assert_eq!(initial_data.selected_application(), APPLICATION_B);
+    }
+
+    #[test]
+    fn invalid_bundles() {
+        assert_eq!(InitialData::parse(&bundle("c")), Err(InitialDataError::UnknownSelector));
+
+        let payload = bundle("a");
+        assert_eq!(
+            InitialData::parse(&payload[..payload.len() - 1]),
+            Err(InitialDataError::Truncated)
+        );
+
+        let mut payload = bundle("a");
+        payload.push(0);
+        assert_eq!(InitialData::parse(&payload), Err(InitialDataError::TrailingData));
+
+        let payload = encode_bundle(
+            "a",
+            &[
+                NamedApplication { name: "a", binary: APPLICATION_A },
+                NamedApplication { name: "a", binary: APPLICATION_B },
+            ],
+        );
+        assert_eq!(InitialData::parse(&payload), Err(InitialDataError::DuplicateApplication));
+    }
+
+    #[test]
+    fn application_config() {
+        assert_eq!(split_application_config(APPLICATION_A), Ok((&[][..], APPLICATION_A)));
+
+        let payload = encode_application_config(b"config", APPLICATION_A);
+        assert_eq!(split_application_config(&payload), Ok((&b"config"[..], APPLICATION_A)));
+
+        let bundle = bundle("b");
+        let payload = encode_application_config(b"config", &bundle);
+        let (config, rest) = split_application_config(&payload).unwrap();
+        assert_eq!(config, b"config");
+        assert_eq!(InitialData::parse(rest).unwrap().selected_application(), APPLICATION_B);
+
+        assert_eq!(
+            split_application_config(&payload[..CONFIG_MAGIC.len() + 10]),
+            Err(InitialDataError::Truncated)
+        );
+    }
+}
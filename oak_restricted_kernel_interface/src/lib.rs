@@ -17,7 +17,10 @@
 #![no_std]
 #![feature(c_size_t)]
 
+extern crate alloc;
+
 pub mod errno;
+pub mod initial_data;
 mod raw_syscall;
 pub mod syscall;
 pub mod syscalls;
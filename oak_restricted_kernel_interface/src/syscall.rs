@@ -110,6 +110,28 @@ pub fn mmap(
     }
 }
 
+#[no_mangle]
+pub extern "C" fn sys_munmap(addr: *mut c_void, size: c_size_t) -> isize {
+    unsafe { syscall!(Syscall::Munmap, addr, size) }
+}
+
+/// Removes a mapping previously created by `mmap`.
+///
+/// # Safety
+///
+/// The caller must guarantee that the memory in the range is no longer in use,
+/// as it will no longer be accessible after this call.
+pub unsafe fn munmap(addr: *mut c_void, size: usize) -> Result<(), Errno> {
+    let ret = sys_munmap(addr, size);
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from munmap syscall: {}", ret)))
+    } else {
+        Ok(())
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn sys_exit(status: c_int) {
     unsafe { syscall!(Syscall::Exit, status) };
@@ -130,6 +152,66 @@ pub fn unstable_switch_proccess(buf: &[u8]) -> ! {
     unreachable!();
 }
 
+#[no_mangle]
+pub extern "C" fn sys_seal(
+    data: *const c_void,
+    data_len: c_size_t,
+    blob: *mut c_void,
+    blob_len: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::Seal, data, data_len, blob, blob_len) }
+}
+
+/// Seals `data` into `blob`, returning the size of the sealed blob.
+///
+/// `blob` needs to be at least [`crate::syscalls::SEALED_DATA_OVERHEAD`] bytes
+/// larger than `data`.
+pub fn seal(data: &[u8], blob: &mut [u8]) -> Result<usize, Errno> {
+    let ret = sys_seal(
+        data.as_ptr() as *const c_void,
+        data.len(),
+        blob.as_mut_ptr() as *mut c_void,
+        blob.len(),
+    );
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from seal syscall: {}", ret)))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unseal(
+    blob: *const c_void,
+    blob_len: c_size_t,
+    data: *mut c_void,
+    data_len: c_size_t,
+) -> c_ssize_t {
+    unsafe { syscall!(Syscall::Unseal, blob, blob_len, data, data_len) }
+}
+
+/// Unseals `blob` into `data`, returning the size of the data.
+///
+/// `data` needs to be at least as large as `blob` minus
+/// [`crate::syscalls::SEALED_DATA_OVERHEAD`] bytes.
+pub fn unseal(blob: &[u8], data: &mut [u8]) -> Result<usize, Errno> {
+    let ret = sys_unseal(
+        blob.as_ptr() as *const c_void,
+        blob.len(),
+        data.as_mut_ptr() as *mut c_void,
+        data.len(),
+    );
+
+    if ret < 0 {
+        Err(Errno::from_repr(ret)
+            .unwrap_or_else(|| panic!("unexpected error from unseal syscall: {}", ret)))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
 // Note that these tests are not being executed against Restricted Kernel, but
 // rather the Linux kernel of the machine cargo is running on!
 #[cfg(test)]
@@ -174,6 +256,20 @@ mod tests {
         assert!(mem.is_ok());
     }
 
+    #[test]
+    fn test_munmap() {
+        let mem = mmap(
+            None,
+            4096,
+            MmapProtection::PROT_READ | MmapProtection::PROT_WRITE,
+            MmapFlags::MAP_ANONYMOUS | MmapFlags::MAP_PRIVATE,
+            -1,
+            0,
+        )
+        .unwrap();
+        assert!(unsafe { munmap(mem.as_mut_ptr() as *mut c_void, mem.len()) }.is_ok());
+    }
+
     #[test]
     fn test_mmap_error() {
         let mem = mmap(
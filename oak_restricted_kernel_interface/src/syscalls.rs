@@ -80,6 +80,18 @@ pub enum Syscall {
     ///   - We do not support PROT_NONE; PROT_READ is always implied.
     Mmap = 9,
 
+    /// Removes a mapping created by `Mmap`, returning the memory to the kernel.
+    /// Arguments:
+    ///   - arg0 (*mut c_void): start address of the range to unmap
+    ///   - arg1 (c_size_t): size of the range
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; 0, otherwise.
+    /// Oak Restricted Kernel considerations:
+    ///   - as with `Mmap`, we work on 2 MiB chunks: the address needs to be 2
+    ///     MiB-aligned, and size is rounded up to the next 2 MiB boundary.
+    ///   - pages in the range that are not mapped are ignored.
+    Munmap = 11,
+
     /// Terminates he calling process.
     /// Arguments:
     ///   - arg0 (c_int): error code
@@ -105,8 +117,48 @@ pub enum Syscall {
     /// Returns:
     ///   a value of <errno::Errno> on failure; 0, otherwise.
     UnstableSwitchProcess = UNSTABLE_SYSCALL_SPACE + 1,
+
+    /// Encrypts data so that only an instance of the same application on the
+    /// same platform can decrypt it, e.g. to keep state across restarts.
+    ///
+    /// The data is encrypted with AES-256-GCM under a key the kernel derives
+    /// from the sealing key of the application's DICE layer, so it is bound to
+    /// the measurements of the whole boot chain and the application.
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): pointer to the data to seal
+    ///   - arg1 (c_size_t): size of the data
+    ///   - arg2 (*mut c_void): pointer to the buffer for the sealed blob
+    ///   - arg3 (c_size_t): size of the buffer, which needs to be at least the
+    ///     size of the data plus <SEALED_DATA_OVERHEAD>
+    /// Returns:
+    ///   a value of <errno::Errno> on failure; otherwise, the size of the
+    /// sealed blob.
+    /// Oak Restricted Kernel considerations:
+    ///   Returns ENOSYS until the application's sealing key is known; with an
+    /// initial RAM disk, that is once the first application has written the
+    /// key of the next one.
+    Seal = 0x1000,
+
+    /// Decrypts a blob created by `Seal`.
+    ///
+    /// Arguments:
+    ///   - arg0 (*const c_void): pointer to the sealed blob
+    ///   - arg1 (c_size_t): size of the sealed blob
+    ///   - arg2 (*mut c_void): pointer to the buffer for the data
+    ///   - arg3 (c_size_t): size of the buffer, which needs to be at least the
+    ///     size of the blob minus <SEALED_DATA_OVERHEAD>
+    /// Returns:
+    ///   a value of <errno::Errno> on failure, including if the blob wasn't
+    /// sealed by the same application or was tampered with; otherwise, the
+    /// size of the data.
+    Unseal = 0x1001,
 }
 
+/// How much larger a sealed blob is than the data it seals: the 12-byte nonce
+/// and the 16-byte authentication tag of AES-256-GCM.
+pub const SEALED_DATA_OVERHEAD: usize = 12 + 16;
+
 bitflags! {
     #[repr(C)]
     pub struct MmapProtection: i32 {
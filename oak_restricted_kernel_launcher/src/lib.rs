@@ -20,13 +20,13 @@
 
 use oak_launcher_utils::{
     channel::{self},
+    error::LauncherError,
     launcher,
 };
 
 pub async fn create(
     params: launcher::Params,
-) -> Result<(Box<dyn launcher::GuestInstance>, channel::ConnectorHandle), Box<dyn std::error::Error>>
-{
+) -> Result<(Box<dyn launcher::GuestInstance>, channel::ConnectorHandle), LauncherError> {
     log::info!("creating guest instance");
     launcher::launch(params).await
 }
@@ -24,6 +24,7 @@ use oak_channel::basic_framed::receive_raw;
 #[cfg(feature = "exchange_evidence")]
 use oak_channel::basic_framed::send_raw;
 use oak_dice::evidence::Stage0DiceData;
+use oak_restricted_kernel_interface::initial_data::{split_application_config, InitialData};
 #[cfg(feature = "exchange_evidence")]
 use prost::Message;
 
@@ -38,7 +39,17 @@ impl AttestedApp {
         mut channel: C,
         stage0_dice_data: Stage0DiceData,
     ) -> Self {
-        let elf_binary = receive_raw::<C>(&mut channel).expect("failed to load");
+        let initial_data = receive_raw::<C>(&mut channel).expect("failed to load");
+        let (application_config, initial_data) =
+            split_application_config(&initial_data).expect("failed to parse application config");
+        let elf_binary = {
+            let initial_data =
+                InitialData::parse(initial_data).expect("failed to parse initial data");
+            if let Some(selector) = initial_data.selector() {
+                log::info!("Selected application {} from the bundle", selector);
+            }
+            initial_data.selected_application().to_vec()
+        };
         log::info!("Binary loaded, size: {}", elf_binary.len());
         let app_digest = oak_restricted_kernel_dice::measure_app_digest_sha2_256(&elf_binary);
         log::info!(
@@ -47,8 +58,13 @@ impl AttestedApp {
         );
         let derived_key =
             oak_restricted_kernel_dice::generate_derived_key(&stage0_dice_data, &app_digest);
-        let dice_data =
-            oak_restricted_kernel_dice::generate_dice_data(stage0_dice_data, &app_digest);
+        let app_config_digest = (!application_config.is_empty())
+            .then(|| oak_restricted_kernel_dice::measure_app_digest_sha2_256(application_config));
+        let dice_data = oak_restricted_kernel_dice::generate_dice_data(
+            stage0_dice_data,
+            &app_digest,
+            app_config_digest.as_ref(),
+        );
         #[cfg(feature = "exchange_evidence")]
         {
             let evidence = evidence_to_proto(dice_data.evidence.clone())
@@ -85,9 +85,22 @@ impl Write for FileDescriptorChannel {
 /// and responds to them using the provided [`micro_rpc::Transport`].
 pub fn start_blocking_server<T: micro_rpc::Transport<Error = !>>(
     channel: Box<dyn Channel>,
-    mut server: T,
+    server: T,
     stats: &mut dyn SampleStore,
 ) -> anyhow::Result<!> {
+    start_blocking_server_until(channel, server, stats, || false)?;
+    unreachable!("server stopped without being asked to");
+}
+
+/// Like [`start_blocking_server`], but returns once `should_stop` returns true
+/// after a response has been sent, e.g. because the request asked the server
+/// to shut down.
+pub fn start_blocking_server_until<T: micro_rpc::Transport<Error = !>>(
+    channel: Box<dyn Channel>,
+    mut server: T,
+    stats: &mut dyn SampleStore,
+    should_stop: impl Fn() -> bool,
+) -> anyhow::Result<()> {
     let channel_handle = &mut oak_channel::server::ServerChannelHandle::new(channel);
     loop {
         log::debug!("waiting for a request message");
@@ -111,5 +124,8 @@ pub fn start_blocking_server<T: micro_rpc::Transport<Error = !>>(
         };
         channel_handle.write_response(response_message)?;
         stats.record(timer.elapsed());
+        if should_stop() {
+            return Ok(());
+        }
     }
 }
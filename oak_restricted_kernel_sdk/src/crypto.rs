@@ -17,10 +17,16 @@
 //! Structs for signing and encryption using keys attested in the instance's
 //! attestation evidence.
 
+use alloc::{vec, vec::Vec};
+
 use oak_crypto::{
     encryption_key::{EncryptionKey, EncryptionKeyHandle},
     hpke::RecipientContext,
 };
+use oak_restricted_kernel_interface::{
+    syscall::{seal, unseal},
+    syscalls::SEALED_DATA_OVERHEAD,
+};
 use p256::ecdsa::SigningKey;
 
 /// [`EncryptionKeyHandle`] implementation that using the instance's evidence
@@ -78,3 +84,37 @@ impl Signer for InstanceSigner {
         Ok(<SigningKey as oak_crypto::signer::Signer>::sign(self.key, message))
     }
 }
+
+/// Exposes the ability to seal data, so that only instances of the same
+/// application can unseal it, for example after the enclave was restarted.
+pub trait Sealer {
+    /// Encrypts `data` under a key that is bound to the identity of the
+    /// application.
+    fn seal(&self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// Decrypts a blob created by [`Sealer::seal`], failing if it wasn't sealed
+    /// by an instance of the same application or was modified since.
+    fn unseal(&self, blob: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// [`Sealer`] implementation that uses the sealing key of the instance, which
+/// the Restricted Kernel derives from the application's DICE layer.
+#[derive(Clone, Default)]
+pub struct InstanceSealer;
+
+impl Sealer for InstanceSealer {
+    fn seal(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut blob = vec![0; data.len() + SEALED_DATA_OVERHEAD];
+        let len = seal(data, &mut blob).map_err(|err| anyhow::anyhow!("couldn't seal: {}", err))?;
+        blob.truncate(len);
+        Ok(blob)
+    }
+
+    fn unseal(&self, blob: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; blob.len().saturating_sub(SEALED_DATA_OVERHEAD)];
+        let len =
+            unseal(blob, &mut data).map_err(|err| anyhow::anyhow!("couldn't unseal: {}", err))?;
+        data.truncate(len);
+        Ok(data)
+    }
+}
@@ -17,6 +17,8 @@
 //! Mock attestation evidence and crypto logic. Useful for testing where an
 //! attestation rooted in a real TEE may not be available.
 
+use alloc::vec::Vec;
+
 use oak_crypto::{
     encryption_key::{EncryptionKey, EncryptionKeyHandle},
     hpke::RecipientContext,
@@ -26,7 +28,7 @@ use p256::ecdsa::SigningKey;
 
 use crate::{
     attestation::{DiceWrapper, EvidenceProvider},
-    crypto::Signer,
+    crypto::{Sealer, Signer},
 };
 
 lazy_static::lazy_static! {
@@ -48,6 +50,7 @@ fn get_mock_dice_data() -> RestrictedKernelDiceData {
     oak_restricted_kernel_dice::generate_dice_data(
         stage0_dice_data,
         &oak_restricted_kernel_dice::AppDigestSha2_256::default(),
+        None,
     )
 }
 
@@ -117,3 +120,24 @@ impl EvidenceProvider for MockEvidenceProvider {
         self.evidence
     }
 }
+
+/// [`Sealer`] implementation that doesn't encrypt anything, but only tags the
+/// data, so that unsealing data that wasn't sealed by it fails.
+#[derive(Clone, Default)]
+pub struct MockSealer;
+
+impl MockSealer {
+    const TAG: &'static [u8] = b"mock-sealed:";
+}
+
+impl Sealer for MockSealer {
+    fn seal(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok([Self::TAG, data].concat())
+    }
+
+    fn unseal(&self, blob: &[u8]) -> anyhow::Result<Vec<u8>> {
+        blob.strip_prefix(Self::TAG)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow::anyhow!("the blob wasn't sealed by the mock sealer"))
+    }
+}
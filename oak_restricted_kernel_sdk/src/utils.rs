@@ -65,6 +65,11 @@ impl log::Log for StderrLogger {
     }
 }
 
+/// Terminates the application, which shuts down the enclave.
+pub fn exit(status: i32) -> ! {
+    oak_restricted_kernel_interface::syscall::exit(status);
+}
+
 /// Provides a default implementation for [`alloc_error_handler`] attribute.
 ///
 /// This handler is declared implicitly when using the [`crate::entrypoint`]
@@ -24,9 +24,10 @@ use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 use crate::{
     cpuid::{CpuidInput, CpuidOutput},
+    instructions::PageSize,
     msr::{
-        register_ghcb_location, set_ghcb_address_and_exit, GhcbGpa, RegisterGhcbGpaError,
-        RegisterGhcbGpaRequest,
+        register_ghcb_location, set_ghcb_address_and_exit, GhcbGpa, PageAssignment,
+        RegisterGhcbGpaError, RegisterGhcbGpaRequest,
     },
     Translator,
 };
@@ -69,6 +70,27 @@ const SW_EXIT_CODE_MMIO_WRITE: u64 = 0x8000_0002;
 /// See table 6 in <https://www.amd.com/system/files/TechDocs/56421-guest-hypervisor-communication-block-standardization.pdf>.
 const SW_EXIT_CODE_AP_JUMP_TABLE: u64 = 0x8000_0005;
 
+/// The value of the sw_exit_code field when creating or destroying an AP under
+/// SEV-SNP.
+///
+/// See table 6 in <https://www.amd.com/system/files/TechDocs/56421-guest-hypervisor-communication-block-standardization.pdf>.
+const SW_EXIT_CODE_AP_CREATION: u64 = 0x8000_0013;
+
+/// The value of the sw_exit_code field when requesting page state changes.
+///
+/// See table 6 in <https://www.amd.com/system/files/TechDocs/56421-guest-hypervisor-communication-block-standardization.pdf>.
+const SW_EXIT_CODE_PSC: u64 = 0x8000_0010;
+
+/// The maximum number of entries in a single Page State Change request.
+///
+/// This is the number of entries that fit into the shared buffer after the
+/// 8-byte header.
+pub const PSC_MAX_ENTRIES: usize = 253;
+
+/// The value of the lower 32 bits of the sw_exit_info_1 field when requesting
+/// that an AP is started using the provided VMSA.
+const AP_CREATION_CREATE: u64 = 1;
+
 ///
 /// The value of the sw_exit_code field when doing a Guest Message request.
 ///
@@ -104,6 +126,54 @@ const IOIO_DATA_SIZE_32: u64 = 1 << 6;
 const BASE_VALID_BITMAP: ValidBitmap =
     ValidBitmap::SW_EXIT_CODE.union(ValidBitmap::SW_EXIT_INFO_1).union(ValidBitmap::SW_EXIT_INFO_2);
 
+/// The header of the Page State Change descriptor in the shared buffer.
+///
+/// See section 4.1.6 in <https://www.amd.com/content/dam/amd/en/documents/epyc-technical-docs/specifications/56421-guest-hypervisor-communication-block-standardization.pdf>.
+#[repr(C)]
+#[derive(Debug, Default, AsBytes, FromBytes, FromZeroes)]
+struct PageStateChangeHeader {
+    /// Index of the next entry to process.
+    cur_entry: u16,
+    /// Index of the last entry to process.
+    end_entry: u16,
+    reserved: u32,
+}
+
+/// A single entry of a Page State Change request.
+///
+/// The entry is laid out as follows:
+///
+/// * bits 11:0: the current page (in 4KiB units) of a 2MiB entry, updated by
+///   the hypervisor;
+/// * bits 51:12: the guest frame number;
+/// * bits 55:52: the requested page assignment;
+/// * bit 56: the page size (0 for 4KiB, 1 for 2MiB).
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, AsBytes, FromBytes, FromZeroes)]
+pub struct PageStateChangeEntry(u64);
+
+impl PageStateChangeEntry {
+    pub fn new(
+        gpa: PhysAddr,
+        assignment: PageAssignment,
+        page_size: PageSize,
+    ) -> Result<Self, &'static str> {
+        let alignment: u64 = match page_size {
+            PageSize::Page4KiB => 0x1000,
+            PageSize::Page2MiB => 0x20_0000,
+        };
+        if !gpa.is_aligned(alignment) {
+            return Err("page address is not aligned to the page size");
+        }
+        // Only 52 bits can be used for an address.
+        const ADDRESS_MAX: u64 = (1 << 52) - 1;
+        if gpa.as_u64() > ADDRESS_MAX {
+            return Err("page address is too high");
+        }
+        Ok(Self(gpa.as_u64() | (assignment as u64) << 52 | (page_size as u64) << 56))
+    }
+}
+
 /// The mask to use on MSR register values.
 ///
 /// RDMSR and WRMSR only use the 32-bit EAX and EDX registers, not 64-bit RAX
@@ -364,6 +434,30 @@ where
         Ok(PhysAddr::new(self.ghcb.as_ref().sw_exit_info_2))
     }
 
+    /// Starts an AP under SEV-SNP using the AP Creation protocol.
+    ///
+    /// The VMSA page must already have been marked as a VMSA in the RMP using
+    /// RMPADJUST. `sev_features` must match the SEV features of the VMSA.
+    ///
+    /// See Section 4.1.9 in <https://www.amd.com/content/dam/amd/en/documents/epyc-technical-docs/specifications/56421-guest-hypervisor-communication-block-standardization.pdf>.
+    pub fn create_ap(
+        &mut self,
+        apic_id: u32,
+        vmpl: u8,
+        vmsa: PhysAddr,
+        sev_features: u64,
+    ) -> Result<(), &'static str> {
+        let ghcb = self.ghcb.as_mut();
+
+        ghcb.sw_exit_code = SW_EXIT_CODE_AP_CREATION;
+        ghcb.sw_exit_info_1 = ((apic_id as u64) << 32) | ((vmpl as u64) << 16) | AP_CREATION_CREATE;
+        ghcb.sw_exit_info_2 = vmsa.as_u64();
+        ghcb.rax = sev_features;
+        ghcb.valid_bitmap = BASE_VALID_BITMAP.union(ValidBitmap::RAX);
+
+        self.do_vmg_exit()
+    }
+
     /// Read a 32-bit value from a MMIO memory address via the MMIO Access
     /// protocol.
     ///
@@ -559,6 +653,66 @@ where
         Ok(low | (high << 32))
     }
 
+    /// Changes the assignment of a batch of pages via the Page State Change
+    /// protocol.
+    ///
+    /// The hypervisor might only process some of the entries before resuming
+    /// the guest, so we keep exiting until all entries have been processed.
+    ///
+    /// See section 4.1.6 in <https://www.amd.com/content/dam/amd/en/documents/epyc-technical-docs/specifications/56421-guest-hypervisor-communication-block-standardization.pdf>.
+    pub fn page_state_change(
+        &mut self,
+        entries: &[PageStateChangeEntry],
+    ) -> Result<(), &'static str> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if entries.len() > PSC_MAX_ENTRIES {
+            return Err("too many page state change entries");
+        }
+        let end_entry = (entries.len() - 1) as u16;
+        let header_size = core::mem::size_of::<PageStateChangeHeader>();
+        let gpa_base = self.get_gpa().as_u64();
+
+        let ghcb = self.ghcb.as_mut();
+        ghcb.reset();
+        ghcb.shared_buffer[..header_size].copy_from_slice(
+            PageStateChangeHeader { cur_entry: 0, end_entry, reserved: 0 }.as_bytes(),
+        );
+        ghcb.shared_buffer[header_size..header_size + entries.as_bytes().len()]
+            .copy_from_slice(entries.as_bytes());
+
+        let mut cur_entry = 0;
+        while cur_entry <= end_entry {
+            let ghcb = self.ghcb.as_mut();
+            ghcb.sw_exit_code = SW_EXIT_CODE_PSC;
+            ghcb.sw_exit_info_1 = 0;
+            ghcb.sw_exit_info_2 = 0;
+            // Pointer to `shared_buffer` inside the GHCB.
+            ghcb.sw_scratch = gpa_base + (core::mem::offset_of!(Ghcb, shared_buffer) as u64);
+            ghcb.valid_bitmap = BASE_VALID_BITMAP | ValidBitmap::SW_SCRATCH;
+
+            self.do_vmg_exit()?;
+
+            let ghcb = self.ghcb.as_ref();
+            if ghcb.sw_exit_info_2 != 0 {
+                return Err("page state change request failed");
+            }
+            let header = PageStateChangeHeader::read_from_prefix(&ghcb.shared_buffer[..])
+                .ok_or("couldn't read page state change header")?;
+            if header.reserved != 0 {
+                return Err("reserved bits set in page state change header");
+            }
+            // Make sure the hypervisor is not going backwards or changing the size of
+            // the request; otherwise it could keep us looping forever.
+            if header.end_entry != end_entry || header.cur_entry < cur_entry {
+                return Err("invalid page state change header returned by hypervisor");
+            }
+            cur_entry = header.cur_entry;
+        }
+        Ok(())
+    }
+
     /// Sends a guest request message to the Platform Secure Processor via the
     /// Guest Message Protocol.
     ///
@@ -614,3 +768,51 @@ fn reset_slice(slice: &mut [u8]) {
         *byte = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_state_change_entry() {
+        let entry = PageStateChangeEntry::new(
+            PhysAddr::new(0x1234_5000),
+            PageAssignment::Shared,
+            PageSize::Page4KiB,
+        )
+        .unwrap();
+        assert_eq!(entry.0, 0x0020_0000_1234_5000);
+
+        let entry = PageStateChangeEntry::new(
+            PhysAddr::new(0x4000_0000),
+            PageAssignment::Private,
+            PageSize::Page2MiB,
+        )
+        .unwrap();
+        assert_eq!(entry.0, 0x0110_0000_4000_0000);
+
+        // 2MiB entries must be 2MiB-aligned.
+        assert!(PageStateChangeEntry::new(
+            PhysAddr::new(0x1000),
+            PageAssignment::Private,
+            PageSize::Page2MiB
+        )
+        .is_err());
+        assert!(PageStateChangeEntry::new(
+            PhysAddr::new(1 << 52),
+            PageAssignment::Private,
+            PageSize::Page4KiB
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_page_state_change_fits_in_shared_buffer() {
+        let ghcb = Ghcb::new();
+        assert!(
+            core::mem::size_of::<PageStateChangeHeader>()
+                + PSC_MAX_ENTRIES * core::mem::size_of::<PageStateChangeEntry>()
+                <= ghcb.shared_buffer.len()
+        );
+    }
+}
@@ -144,6 +144,12 @@ pub struct RmpPermission {
 
 static_assertions::assert_eq_size!(RmpPermission, u64);
 
+impl RmpPermission {
+    pub fn new(target_vmpl: u8, perm_mask: PermissionMask, vmsa: Vmsa) -> Self {
+        Self { target_vmpl, perm_mask, vmsa, _reserved_0: 0, _reserved_1: 0 }
+    }
+}
+
 impl From<RmpPermission> for u64 {
     fn from(permission: RmpPermission) -> u64 {
         // Safety: reinterpreting the struct as a u64 is safe because the types are
@@ -0,0 +1,63 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Calculates the SEV-SNP launch measurement of a VM booted with the Stage 0
+//! firmware.
+
+mod page;
+mod stage0;
+mod vmsa;
+
+use page::PageInfo;
+pub use stage0::{load_stage0, Stage0Info};
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+use crate::vmsa::{get_ap_vmsa, get_boot_vmsa, VMSA_ADDRESS};
+
+/// Calculates the launch digest of a VM with `vcpu_count` vCPUs that boots the
+/// given Stage 0 firmware ROM image.
+pub fn launch_digest(stage0: &Stage0Info, vcpu_count: usize, legacy_boot: bool) -> [u8; 48] {
+    let mut page_info = PageInfo::new();
+
+    // Add the Stage 0 firmware ROM image.
+    page_info.update_from_data(stage0.rom_bytes(), stage0.start_address);
+    if legacy_boot {
+        // Add the legacy boot shadow of the Stage 0 firmware ROM image.
+        page_info.update_from_data(stage0.legacy_shadow_bytes(), stage0.legacy_start_address);
+    }
+
+    for snp_page in stage0.get_snp_pages() {
+        for page_number in 0..snp_page.page_count {
+            page_info.update_from_snp_page(
+                snp_page.page_type.clone(),
+                snp_page.start_address + (page_number as u64) * Size4KiB::SIZE,
+            );
+        }
+    }
+
+    // The boot vCPU has the default VMSA configured.
+    page_info.update_from_vmsa(&get_boot_vmsa(), VMSA_ADDRESS);
+
+    // Subsequent vCPUs use the IP and CS segment specified in the SEV-ES reset
+    // block table in the firmware.
+    let sev_es_reset_block = stage0.get_sev_es_reset_block();
+    let ap_vmsa = get_ap_vmsa(&sev_es_reset_block);
+    for _ in 1..vcpu_count {
+        page_info.update_from_vmsa(&ap_vmsa, VMSA_ADDRESS);
+    }
+
+    page_info.digest_cur
+}
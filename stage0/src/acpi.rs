@@ -23,11 +23,15 @@ use core::{
     mem::{size_of, size_of_val, zeroed, MaybeUninit},
 };
 
-use sha2::{Digest, Sha256};
+use sha2::digest::Update;
 use strum::FromRepr;
 use zerocopy::AsBytes;
 
-use crate::{acpi_tables::Rsdp, fw_cfg::FwCfg};
+use crate::{
+    acpi_fallback,
+    acpi_tables::Rsdp,
+    fw_cfg::{FwCfg, FwCfgBackend},
+};
 
 // RSDP has to be within the first 1 KiB of EBDA, so we treat it separately. The
 // full size of EBDA is 128 KiB, but let's reserve the whole 1 KiB for the RSDP.
@@ -43,6 +47,7 @@ const TABLE_LOADER_FILE_NAME: &CStr =
     unsafe { CStr::from_bytes_with_nul_unchecked(b"etc/table-loader\0") };
 const RSDP_FILE_NAME_SUFFIX: &str = "acpi/rsdp";
 const ACPI_TABLES_FILE_NAME_SUFFIX: &str = "acpi/tables";
+const TPM_LOG_FILE_NAME_SUFFIX: &str = "tpm/log";
 
 const ROMFILE_LOADER_FILESZ: usize = 56;
 
@@ -57,6 +62,8 @@ fn get_file(name: &CStr) -> Result<&'static mut [u8], &'static str> {
         Ok(unsafe { RSDP.assume_init_mut().as_bytes_mut() })
     } else if name.ends_with(ACPI_TABLES_FILE_NAME_SUFFIX) {
         Ok(unsafe { EBDA.assume_init_mut() })
+    } else if name.ends_with(TPM_LOG_FILE_NAME_SUFFIX) {
+        crate::tpm::event_log_area().ok_or("TPM event log area not allocated")
     } else {
         Err("Unsupported file in table-loader")
     }
@@ -112,7 +119,7 @@ impl Allocate {
         Zone::from_repr(self.zone)
     }
 
-    fn invoke(&self, fwcfg: &mut FwCfg, acpi_digest: &mut Sha256) -> Result<(), &'static str> {
+    fn invoke(&self, fwcfg: &mut FwCfg, acpi_digest: &mut dyn Update) -> Result<(), &'static str> {
         let file = fwcfg.find(self.file()).unwrap();
         let name = self.file().to_str().map_err(|_| "invalid file name")?;
 
@@ -150,6 +157,16 @@ impl Allocate {
             fwcfg.read_file(&file, buf)?;
             acpi_digest.update(buf);
             Ok(())
+        } else if name.ends_with(TPM_LOG_FILE_NAME_SUFFIX) {
+            // The TPM2 table points to this area; it's filled in with the TCG event log
+            // once the PCRs have been extended.
+            let buf = crate::tpm::allocate_event_log_area(file.size())?;
+            if (buf.as_ptr() as u64) % self.align as u64 != 0 {
+                return Err("TPM event log area not aligned properly");
+            }
+            fwcfg.read_file(&file, buf)?;
+            acpi_digest.update(buf);
+            Ok(())
         } else {
             Err("Unsupported file in table-loader")
         }
@@ -465,7 +482,11 @@ enum Command<'a> {
 }
 
 impl Command<'_> {
-    pub fn invoke(&self, fwcfg: &mut FwCfg, acpi_digest: &mut Sha256) -> Result<(), &'static str> {
+    pub fn invoke(
+        &self,
+        fwcfg: &mut FwCfg,
+        acpi_digest: &mut dyn Update,
+    ) -> Result<(), &'static str> {
         match self {
             Command::Allocate(allocate) => allocate.invoke(fwcfg, acpi_digest),
             Command::AddPointer(add_pointer) => add_pointer.invoke(),
@@ -500,7 +521,7 @@ impl RomfileCommand {
         }
     }
 
-    fn invoke(&self, fwcfg: &mut FwCfg, acpi_digest: &mut Sha256) -> Result<(), &'static str> {
+    fn invoke(&self, fwcfg: &mut FwCfg, acpi_digest: &mut dyn Update) -> Result<(), &'static str> {
         if self.tag > CommandTag::VMM_SPECIFIC && self.tag().is_none() {
             log::warn!("ignoring proprietary ACPI linker command with tag {:#x}", self.tag);
             return Ok(());
@@ -522,7 +543,7 @@ impl RomfileCommand {
 /// Returns the address of the RSDP table.
 pub fn build_acpi_tables(
     fwcfg: &mut FwCfg,
-    acpi_digest: &mut Sha256,
+    acpi_digest: &mut dyn Update,
 ) -> Result<&'static Rsdp, &'static str> {
     let file =
         fwcfg.find(TABLE_LOADER_FILE_NAME).ok_or("Could not find 'etc/table-loader' in fw_cfg")?;
@@ -554,3 +575,54 @@ pub fn build_acpi_tables(
     rsdp.validate()?;
     Ok(rsdp)
 }
+
+/// Returns whether the VMM provides ACPI tables via `etc/table-loader`.
+pub fn has_table_loader<B: FwCfgBackend>(fwcfg: &mut FwCfg<B>) -> bool {
+    fwcfg.find(TABLE_LOADER_FILE_NAME).is_some()
+}
+
+/// Generates a minimal set of ACPI tables for VMMs that don't provide
+/// `etc/table-loader`.
+///
+/// Returns the address of the RSDP table.
+pub fn build_fallback_acpi_tables(
+    nb_cpus: u16,
+    acpi_digest: &mut dyn Update,
+) -> Result<&'static Rsdp, &'static str> {
+    // Safety: the EBDA is only used for ACPI tables, which we only generate once.
+    let ebda = unsafe { EBDA.write(zeroed()) };
+    let (xsdt_address, len) =
+        acpi_fallback::build_tables(ebda, ebda.as_ptr() as u64, acpi_fallback::apic_ids(nb_cpus))?;
+    acpi_digest.update(&ebda[..len]);
+
+    // Safety: we're the only user of the RSDP, and it's valid once written.
+    let rsdp = unsafe { RSDP.write(Rsdp::new(xsdt_address)) };
+    acpi_digest.update(rsdp.as_bytes());
+    rsdp.validate()?;
+    Ok(rsdp)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{acpi_tables::Madt, testing::FakeFwCfg};
+
+    #[test]
+    fn detect_table_loader() {
+        let mut fw_cfg = FakeFwCfg::default().with_file("etc/table-loader", &[0u8; 128]).build();
+
+        assert!(has_table_loader(&mut fw_cfg));
+        assert!(!has_table_loader(&mut FakeFwCfg::default().build()));
+    }
+
+    #[test]
+    fn parse_fallback_tables() {
+        let rsdp = build_fallback_acpi_tables(4, &mut Sha256::new()).unwrap();
+
+        let xsdt = rsdp.xsdt().unwrap().expect("no XSDT");
+        let madt = Madt::new(xsdt.get(Madt::SIGNATURE).expect("no MADT")).unwrap();
+        assert_eq!(madt.iter().count(), 4);
+    }
+}
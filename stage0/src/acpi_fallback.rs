@@ -0,0 +1,259 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Generator for a minimal set of ACPI tables, for VMMs that don't provide
+//! ACPI tables via the QEMU table loader.
+//!
+//! We generate an XSDT that points to a hardware-reduced FADT (with an empty
+//! DSDT) and a MADT that contains one local APIC entry for every CPU. As we
+//! can't query the VMM for the APIC IDs of the APs, we assume that the APs
+//! have consecutive APIC IDs following the APIC ID of the bootstrap processor.
+
+use alloc::{vec, vec::Vec};
+use core::{arch::x86_64::__cpuid, mem::size_of};
+
+use crate::acpi_tables::{LocalApicFlags, ProcessorLocalApic, ProcessorLocalX2Apic};
+
+/// Size of the common ACPI table header.
+const HEADER_SIZE: usize = 36;
+
+/// Offset of the length field in the table header.
+const LENGTH_OFFSET: usize = 4;
+
+/// Offset of the checksum field in the table header.
+const CHECKSUM_OFFSET: usize = 9;
+
+/// Size of the FADT as of ACPI 6.5.
+const FADT_SIZE: usize = 276;
+
+/// Offsets of the FADT fields we fill in.
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_IAPC_BOOT_ARCH_OFFSET: usize = 109;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_MINOR_VERSION_OFFSET: usize = 131;
+const FADT_X_DSDT_OFFSET: usize = 140;
+
+/// FADT flag indicating that the platform doesn't implement the ACPI fixed
+/// hardware.
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// IA-PC boot architecture flag indicating that there is no VGA hardware.
+const IAPC_BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+/// Default physical address of the local APIC.
+const LOCAL_APIC_ADDRESS: u32 = 0xFEE0_0000;
+
+/// APIC IDs above this value can't be represented in a Processor Local APIC
+/// structure.
+const MAX_XAPIC_ID: u32 = 0xFE;
+
+/// Tables are placed at 8-byte aligned offsets.
+const TABLE_ALIGNMENT: usize = 8;
+
+/// Creates a table with the common header. The length and checksum are filled
+/// in by `finish_table`.
+fn new_table(signature: &[u8; 4], revision: u8) -> Vec<u8> {
+    let mut table = vec![0u8; HEADER_SIZE];
+    table[0..4].copy_from_slice(signature);
+    table[8] = revision;
+    table[10..16].copy_from_slice(b"OAK   ");
+    table[16..24].copy_from_slice(b"STAGE0  ");
+    table[24..28].copy_from_slice(&1u32.to_le_bytes());
+    table[28..32].copy_from_slice(b"OAK ");
+    table[32..36].copy_from_slice(&1u32.to_le_bytes());
+    table
+}
+
+/// Sets the length and the checksum in the table header.
+fn finish_table(mut table: Vec<u8>) -> Vec<u8> {
+    let length = table.len() as u32;
+    table[LENGTH_OFFSET..LENGTH_OFFSET + size_of::<u32>()].copy_from_slice(&length.to_le_bytes());
+    table[CHECKSUM_OFFSET] = 0;
+    let checksum = table.iter().fold(0u8, |sum, &x| sum.wrapping_add(x));
+    table[CHECKSUM_OFFSET] = 0u8.wrapping_sub(checksum);
+    table
+}
+
+/// Creates an empty DSDT.
+fn dsdt() -> Vec<u8> {
+    finish_table(new_table(b"DSDT", 2))
+}
+
+/// Creates a hardware-reduced FADT that points to the DSDT.
+fn fadt(dsdt_address: u64) -> Vec<u8> {
+    let mut table = new_table(b"FACP", 6);
+    table.resize(FADT_SIZE, 0);
+    // The 32-bit DSDT field must be zero if the address doesn't fit.
+    if let Ok(dsdt_address) = u32::try_from(dsdt_address) {
+        table[FADT_DSDT_OFFSET..FADT_DSDT_OFFSET + size_of::<u32>()]
+            .copy_from_slice(&dsdt_address.to_le_bytes());
+    }
+    table[FADT_IAPC_BOOT_ARCH_OFFSET..FADT_IAPC_BOOT_ARCH_OFFSET + size_of::<u16>()]
+        .copy_from_slice(&IAPC_BOOT_ARCH_VGA_NOT_PRESENT.to_le_bytes());
+    table[FADT_FLAGS_OFFSET..FADT_FLAGS_OFFSET + size_of::<u32>()]
+        .copy_from_slice(&FADT_HW_REDUCED_ACPI.to_le_bytes());
+    table[FADT_MINOR_VERSION_OFFSET] = 5;
+    table[FADT_X_DSDT_OFFSET..FADT_X_DSDT_OFFSET + size_of::<u64>()]
+        .copy_from_slice(&dsdt_address.to_le_bytes());
+    finish_table(table)
+}
+
+/// Creates a MADT with an enabled local APIC entry for each of the APIC IDs.
+fn madt(apic_ids: impl Iterator<Item = u32>) -> Vec<u8> {
+    let mut table = new_table(b"APIC", 5);
+    table.extend_from_slice(&LOCAL_APIC_ADDRESS.to_le_bytes());
+    // No flags: we don't advertise a dual-8259 setup.
+    table.extend_from_slice(&0u32.to_le_bytes());
+    for (uid, apic_id) in apic_ids.enumerate() {
+        if apic_id <= MAX_XAPIC_ID && uid <= u8::MAX as usize {
+            table.extend_from_slice(&[ProcessorLocalApic::STRUCTURE_TYPE, 8, uid as u8]);
+            table.push(apic_id as u8);
+            table.extend_from_slice(&LocalApicFlags::ENABLED.bits().to_le_bytes());
+        } else {
+            table.extend_from_slice(&[ProcessorLocalX2Apic::STRUCTURE_TYPE, 16, 0, 0]);
+            table.extend_from_slice(&apic_id.to_le_bytes());
+            table.extend_from_slice(&LocalApicFlags::ENABLED.bits().to_le_bytes());
+            table.extend_from_slice(&(uid as u32).to_le_bytes());
+        }
+    }
+    finish_table(table)
+}
+
+/// Creates an XSDT that points to the given tables.
+fn xsdt(entries: &[u64]) -> Vec<u8> {
+    let mut table = new_table(b"XSDT", 1);
+    for entry in entries {
+        table.extend_from_slice(&entry.to_le_bytes());
+    }
+    finish_table(table)
+}
+
+/// Lays out tables one after the other in a buffer at physical address
+/// `base`.
+struct TableWriter<'a> {
+    buf: &'a mut [u8],
+    base: u64,
+    offset: usize,
+}
+
+impl<'a> TableWriter<'a> {
+    /// Copies a table into the buffer and returns its physical address.
+    fn append(&mut self, table: &[u8]) -> Result<u64, &'static str> {
+        let start = self.offset.next_multiple_of(TABLE_ALIGNMENT);
+        let end = start + table.len();
+        if end > self.buf.len() {
+            return Err("generated ACPI tables don't fit into the EBDA");
+        }
+        self.buf[start..end].copy_from_slice(table);
+        self.offset = end;
+        Ok(self.base + start as u64)
+    }
+}
+
+/// Generates the ACPI tables in `buf`, which is located at physical address
+/// `base`.
+///
+/// Returns the address of the XSDT and the number of bytes of `buf` that were
+/// used.
+pub fn build_tables(
+    buf: &mut [u8],
+    base: u64,
+    apic_ids: impl Iterator<Item = u32>,
+) -> Result<(u64, usize), &'static str> {
+    buf.fill(0);
+    let mut writer = TableWriter { buf, base, offset: 0 };
+    let dsdt_address = writer.append(&dsdt())?;
+    let fadt_address = writer.append(&fadt(dsdt_address))?;
+    let madt_address = writer.append(&madt(apic_ids))?;
+    let xsdt_address = writer.append(&xsdt(&[fadt_address, madt_address]))?;
+    Ok((xsdt_address, writer.offset))
+}
+
+/// Returns the number of logical processors reported by CPUID, for VMMs that
+/// don't report the CPU count via fw_cfg.
+pub fn cpu_count() -> u16 {
+    // Safety: the CPUs we support are new enough to support CPUID.
+    let result = unsafe { __cpuid(0x0000_0001) };
+    // EBX[23:16] is only valid if the HTT bit (EDX[28]) is set.
+    if result.edx & (1 << 28) != 0 {
+        (((result.ebx >> 16) & 0xFF) as u16).max(1)
+    } else {
+        1
+    }
+}
+
+/// Returns the APIC IDs of all CPUs, assuming that the APs have consecutive
+/// APIC IDs following the bootstrap processor.
+///
+/// Under SEV-ES and SEV-SNP the CPUID instruction is handled by the #VC
+/// handler.
+pub fn apic_ids(nb_cpus: u16) -> impl Iterator<Item = u32> {
+    // Safety: the CPUs we support are new enough to support CPUID.
+    let ebx = unsafe { __cpuid(0x0000_0001) }.ebx;
+    // The initial APIC ID is in EBX[31:24].
+    let bsp_apic_id = ebx >> 24;
+    (0..nb_cpus.max(1) as u32).map(move |cpu| bsp_apic_id + cpu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksum(table: &[u8]) -> u8 {
+        table.iter().fold(0u8, |sum, &x| sum.wrapping_add(x))
+    }
+
+    fn table_at(buf: &[u8], base: u64, address: u64) -> &[u8] {
+        let start = (address - base) as usize;
+        let length =
+            u32::from_le_bytes(buf[start + LENGTH_OFFSET..start + 8].try_into().unwrap()) as usize;
+        &buf[start..start + length]
+    }
+
+    #[test]
+    fn tables_have_valid_checksums() {
+        let mut buf = vec![0u8; 4096];
+        let base = 0x8_0000;
+
+        let (xsdt_address, _) = build_tables(&mut buf, base, 0..4).unwrap();
+
+        let xsdt = table_at(&buf, base, xsdt_address);
+        assert_eq!(&xsdt[0..4], b"XSDT");
+        assert_eq!(checksum(xsdt), 0);
+        for entry in xsdt[HEADER_SIZE..].chunks_exact(size_of::<u64>()) {
+            let table = table_at(&buf, base, u64::from_le_bytes(entry.try_into().unwrap()));
+            assert_eq!(checksum(table), 0);
+        }
+    }
+
+    #[test]
+    fn madt_uses_x2apic_entries_for_large_ids() {
+        let table = madt([0, 1, 0x100].into_iter());
+
+        // Header, local APIC address and flags, two local APIC structures and one
+        // local x2APIC structure.
+        assert_eq!(table.len(), HEADER_SIZE + 8 + 2 * 8 + 16);
+        assert_eq!(table[HEADER_SIZE + 8 + 2 * 8], ProcessorLocalX2Apic::STRUCTURE_TYPE);
+        assert_eq!(checksum(&table), 0);
+    }
+
+    #[test]
+    fn tables_too_large() {
+        let mut buf = vec![0u8; 128];
+
+        assert!(build_tables(&mut buf, 0, 0..4).is_err());
+    }
+}
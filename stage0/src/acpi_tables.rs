@@ -63,6 +63,25 @@ pub struct Rsdp {
 static_assertions::assert_eq_size!(Rsdp, [u8; 36usize]);
 
 impl Rsdp {
+    /// Creates an ACPI 2.0 RSDP that points to the XSDT at the given address.
+    pub fn new(xsdt_address: u64) -> Self {
+        let mut rsdp = Rsdp {
+            signature: *b"RSD PTR ",
+            checksum: 0,
+            oemid: *b"OAK   ",
+            revision: 2,
+            rsdt_address: 0,
+            length: size_of::<Rsdp>() as u32,
+            xsdt_address,
+            extended_checksum: 0,
+            _reserved: [0; 3],
+        };
+        let checksum = |buf: &[u8]| buf.iter().fold(0u8, |lhs, &rhs| lhs.wrapping_add(rhs));
+        rsdp.checksum = 0u8.wrapping_sub(checksum(&rsdp.as_bytes()[..20]));
+        rsdp.extended_checksum = 0u8.wrapping_sub(checksum(rsdp.as_bytes()));
+        rsdp
+    }
+
     pub fn validate(&self) -> Result<(), &'static str> {
         if &self.signature != b"RSD PTR " {
             return Err("Invalid RSDP signature");
@@ -24,6 +24,13 @@ use core::{
 use oak_linux_boot_params::{BootE820Entry, E820EntryType};
 use spinning_top::Spinlock;
 use x86_64::VirtAddr;
+use zeroize::Zeroize;
+
+/// Start of the short-term heap.
+const HEAP_START: u64 = 0x100000;
+
+/// Size of the short-term heap.
+const HEAP_SIZE: usize = 0x100000;
 
 struct Inner<const N: usize> {
     index: AtomicUsize,
@@ -96,8 +103,8 @@ unsafe impl<const N: usize> Allocator for BumpAllocator<N> {
 
 pub fn init_global_allocator(e820_table: &[BootE820Entry]) {
     // Create the heap between 1MiB and 2MiB.
-    let start = VirtAddr::new(0x100000);
-    let size = 0x100000usize;
+    let start = VirtAddr::new(HEAP_START);
+    let size = HEAP_SIZE;
     let end = start + size as u64;
 
     // Check that this range is backed by physical memory.
@@ -116,6 +123,17 @@ pub fn init_global_allocator(e820_table: &[BootE820Entry]) {
     }
 }
 
+/// Zeroes the memory backing the short-term heap.
+///
+/// # Safety
+///
+/// The caller must make sure that no allocations from the short-term heap are
+/// used, and no new allocations are made, afterwards.
+pub unsafe fn scrub_global_allocator() {
+    core::slice::from_raw_parts_mut(VirtAddr::new(HEAP_START).as_mut_ptr::<u8>(), HEAP_SIZE)
+        .zeroize();
+}
+
 #[cfg(test)]
 mod tests {
     use alloc::boxed::Box;
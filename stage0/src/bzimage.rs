@@ -0,0 +1,273 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Loader for kernels in the Linux bzImage format.
+//!
+//! See <https://www.kernel.org/doc/html/v6.7/arch/x86/boot.html> for the
+//! details of the boot protocol.
+
+use core::{mem::size_of, slice};
+
+use oak_linux_boot_params::{BootE820Entry, LoadFlags, SetupHeader, XLoadFlags};
+use x86_64::{PhysAddr, VirtAddr};
+use zerocopy::{AsBytes, FromZeroes};
+
+use crate::fw_cfg::{check_memory, check_non_overlapping, find_suitable_address};
+
+/// Offset of the setup header from the start of the bzImage file.
+const SETUP_HEADER_OFFSET: usize = 0x1F1;
+
+/// Offset of the byte that contains the length of the setup header (minus
+/// 0x202) from the start of the bzImage file.
+const SETUP_HEADER_LENGTH_OFFSET: usize = 0x201;
+
+/// Magic "HdrS" string at offset 0x202.
+const SETUP_HEADER_MAGIC: u32 = 0x53726448;
+
+/// Magic value at offset 0x1FE.
+const BOOT_FLAG_MAGIC: u16 = 0xAA55;
+
+/// The oldest boot protocol version we support. Version 2.12 introduced the
+/// 64-bit entry point flag in `xloadflags`.
+const MIN_PROTOCOL_VERSION: u16 = 0x020C;
+
+/// The boot protocol version that introduced the `pref_address` and
+/// `init_size` fields.
+const PREF_ADDRESS_PROTOCOL_VERSION: u16 = 0x020A;
+
+/// Where the protected-mode kernel is loaded if the kernel does not specify a
+/// preferred address.
+const DEFAULT_LOAD_ADDRESS: u64 = 0x100000;
+
+/// Offset of the 64-bit entry point from the start of the protected-mode
+/// kernel.
+const ENTRY_POINT_OFFSET: u64 = 0x200;
+
+/// A parsed bzImage kernel.
+pub struct BzImage<'a> {
+    /// The setup header of the kernel.
+    header: SetupHeader,
+    /// The protected-mode kernel that follows the real-mode setup code.
+    kernel: &'a [u8],
+}
+
+/// Information about a bzImage kernel after it has been loaded into memory.
+pub struct LoadedBzImage {
+    /// The setup header, updated with the fields that need to be filled in by
+    /// the boot loader.
+    pub header: SetupHeader,
+    /// The address where the protected-mode kernel was loaded.
+    pub start_address: VirtAddr,
+    /// The amount of memory the kernel needs, starting at `start_address`,
+    /// until it has set up its own memory map.
+    pub size: usize,
+    /// The 64-bit entry point of the kernel.
+    pub entry: VirtAddr,
+}
+
+impl<'a> BzImage<'a> {
+    /// Returns true if the buffer looks like it contains a bzImage kernel.
+    pub fn is_bzimage(buf: &[u8]) -> bool {
+        buf.len() > SETUP_HEADER_LENGTH_OFFSET + 1 + size_of::<u32>()
+            && buf[0x1FE..0x200] == BOOT_FLAG_MAGIC.to_le_bytes()
+            && buf[0x202..0x206] == SETUP_HEADER_MAGIC.to_le_bytes()
+    }
+
+    /// Parses the setup header of the bzImage kernel in `buf`.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, &'static str> {
+        if !Self::is_bzimage(buf) {
+            return Err("not a bzImage kernel");
+        }
+
+        // The length of the header is determined by the byte at offset 0x201. Older
+        // kernels have shorter headers, in which case the remaining fields stay zero.
+        let header_end = 0x202 + buf[SETUP_HEADER_LENGTH_OFFSET] as usize;
+        let header_end = header_end.min(SETUP_HEADER_OFFSET + size_of::<SetupHeader>());
+        if header_end > buf.len() {
+            return Err("bzImage setup header is truncated");
+        }
+        let mut header = SetupHeader::new_zeroed();
+        header.as_bytes_mut()[..header_end - SETUP_HEADER_OFFSET]
+            .copy_from_slice(&buf[SETUP_HEADER_OFFSET..header_end]);
+
+        let version = header.version;
+        if version < MIN_PROTOCOL_VERSION {
+            log::error!("bzImage boot protocol version: {:#06x}", version);
+            return Err("bzImage boot protocol version is too old");
+        }
+        if !header.load_flags().unwrap_or(LoadFlags::empty()).contains(LoadFlags::LOADED_HIGH) {
+            return Err("bzImage kernel can't be loaded high");
+        }
+        if !header.x_load_flags().unwrap_or(XLoadFlags::empty()).contains(XLoadFlags::XLF_KERNEL_64)
+        {
+            return Err("bzImage kernel doesn't have a 64-bit entry point");
+        }
+
+        // A value of zero means four sectors, for historical reasons. The setup code is
+        // preceded by the one-sector boot sector.
+        let setup_sects = match header.setup_sects {
+            0 => 4,
+            sects => sects as usize,
+        };
+        let setup_size = (setup_sects + 1) * 512;
+        if setup_size >= buf.len() {
+            return Err("bzImage kernel is truncated");
+        }
+
+        Ok(Self { header, kernel: &buf[setup_size..] })
+    }
+
+    /// The amount of memory the kernel needs to decompress itself and set up
+    /// its own memory map.
+    fn init_size(&self) -> usize {
+        let init_size = if self.header.version >= PREF_ADDRESS_PROTOCOL_VERSION {
+            self.header.init_size as usize
+        } else {
+            0
+        };
+        init_size.max(self.kernel.len())
+    }
+
+    /// The address where the kernel prefers to be loaded.
+    fn preferred_address(&self) -> PhysAddr {
+        let pref_address = self.header.pref_address;
+        if self.header.version >= PREF_ADDRESS_PROTOCOL_VERSION && pref_address != 0 {
+            PhysAddr::new(pref_address)
+        } else {
+            PhysAddr::new(DEFAULT_LOAD_ADDRESS)
+        }
+    }
+
+    /// Copies the protected-mode kernel into memory.
+    ///
+    /// The kernel is loaded at its preferred address if that memory is
+    /// available. If not, and the kernel is relocatable, it is loaded at the
+    /// highest suitable address that satisfies the kernel alignment
+    /// requirements.
+    pub fn load(self, e820_table: &[BootE820Entry]) -> Result<LoadedBzImage, &'static str> {
+        let size = self.init_size();
+        let source = VirtAddr::from_ptr(self.kernel.as_ptr());
+        let is_usable = |address: PhysAddr| {
+            let start = crate::phys_to_virt(address);
+            check_memory(start, size, e820_table)
+                .and_then(|_| check_non_overlapping(start, size, source, self.kernel.len()))
+        };
+
+        let preferred = self.preferred_address();
+        let address = match is_usable(preferred) {
+            Ok(()) => preferred,
+            Err(err) if self.header.relocatable_kernel != 0 => {
+                log::debug!(
+                    "Can't load kernel at preferred address {:#018x}: {}. Relocating.",
+                    preferred.as_u64(),
+                    err
+                );
+                let alignment = (self.header.kernel_alignment as u64).max(1);
+                if !alignment.is_power_of_two() {
+                    return Err("invalid bzImage kernel alignment");
+                }
+                // Reserve enough memory so that we can align the start address up.
+                let candidate = find_suitable_address(
+                    size + alignment as usize,
+                    preferred,
+                    PhysAddr::new(crate::TOP_OF_VIRTUAL_MEMORY),
+                    e820_table,
+                )?
+                .align_up(alignment);
+                is_usable(candidate)?;
+                candidate
+            }
+            Err(err) => return Err(err),
+        };
+
+        let start_address = crate::phys_to_virt(address);
+        // Safety: we've checked that the memory is backed by RAM, is mapped and does
+        // not overlap with the source buffer.
+        let target = unsafe { slice::from_raw_parts_mut::<u8>(start_address.as_mut_ptr(), size) };
+        target[..self.kernel.len()].copy_from_slice(self.kernel);
+        target[self.kernel.len()..].fill(0);
+
+        let mut header = self.header;
+        // We don't fit any of the registered boot loader types.
+        header.type_of_loader = 0xFF;
+        // The kernel needs to know where we put it if it was relocated. The 32-bit
+        // entry point is at the start of the protected-mode kernel.
+        header.code32_start = address.as_u64() as u32;
+
+        let entry = start_address + ENTRY_POINT_OFFSET;
+        log::debug!("bzImage kernel loaded at {:#018x}", start_address.as_u64());
+        log::debug!("bzImage kernel entry point {:#018x}", entry.as_u64());
+
+        Ok(LoadedBzImage { header, start_address, size, entry })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    fn create_image(version: u16, xloadflags: XLoadFlags) -> Vec<u8> {
+        let mut buf = vec![0u8; 0x2000];
+        let mut header = SetupHeader::new_zeroed();
+        header.setup_sects = 3;
+        header.boot_flag = BOOT_FLAG_MAGIC;
+        header.header = SETUP_HEADER_MAGIC;
+        header.version = version;
+        header.loadflags = LoadFlags::LOADED_HIGH.bits();
+        header.xloadflags = xloadflags.bits();
+        header.pref_address = 0x100_0000;
+        header.init_size = 0x4000;
+        buf[SETUP_HEADER_OFFSET..SETUP_HEADER_OFFSET + size_of::<SetupHeader>()]
+            .copy_from_slice(header.as_bytes());
+        buf[SETUP_HEADER_LENGTH_OFFSET] = 0x66;
+        buf
+    }
+
+    #[test]
+    fn parse_valid_image() {
+        let buf = create_image(0x020F, XLoadFlags::XLF_KERNEL_64);
+
+        let image = BzImage::parse(&buf).expect("couldn't parse bzImage");
+
+        assert_eq!(image.kernel.len(), 0x2000 - 4 * 512);
+        assert_eq!(image.preferred_address(), PhysAddr::new(0x100_0000));
+        assert_eq!(image.init_size(), 0x4000);
+    }
+
+    #[test]
+    fn parse_old_protocol() {
+        let buf = create_image(0x0206, XLoadFlags::XLF_KERNEL_64);
+
+        assert!(BzImage::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn parse_no_64_bit_entry() {
+        let buf = create_image(0x020F, XLoadFlags::empty());
+
+        assert!(BzImage::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn parse_not_bzimage() {
+        let buf = vec![0u8; 0x2000];
+
+        assert!(!BzImage::is_bzimage(&buf));
+        assert!(BzImage::parse(&buf).is_err());
+    }
+}
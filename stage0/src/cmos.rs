@@ -32,9 +32,9 @@ const CMOS_MEM_HIGHMEM_HIGH: u8 = 0x5d;
 
 const NMI_DISABLE_BIT: u8 = 0x80;
 
-pub struct Cmos {
-    index_port: PortWrapper<u8>,
-    data_port: PortWrapper<u8>,
+pub struct Cmos<W: PortWriter<u8> = PortWrapper<u8>, R: PortReader<u8> = PortWrapper<u8>> {
+    index_port: W,
+    data_port: R,
 }
 
 impl Cmos {
@@ -47,10 +47,22 @@ impl Cmos {
     /// actually available on those ports, otherwise the behaviour is
     /// undefined.
     pub unsafe fn new() -> Self {
-        Self {
-            index_port: io_port_factory().new_writer(CMOS_INDEX_PORT),
-            data_port: io_port_factory().new_reader(CMOS_DATA_PORT),
-        }
+        Self::with_ports(
+            io_port_factory().new_writer(CMOS_INDEX_PORT),
+            io_port_factory().new_reader(CMOS_DATA_PORT),
+        )
+    }
+}
+
+impl<W: PortWriter<u8>, R: PortReader<u8>> Cmos<W, R> {
+    /// Creates a new CMOS reader wrapper that uses the given index and data
+    /// ports.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as for `new()` apply to the ports.
+    pub unsafe fn with_ports(index_port: W, data_port: R) -> Self {
+        Self { index_port, data_port }
     }
 
     /// Returns the low RAM size (memory under the 4 GiB mark)
@@ -80,7 +92,7 @@ impl Cmos {
 
     fn read(&mut self, index: u8) -> Result<u8, &'static str> {
         // Safety: we've asked the caller to guarantee that these ports are exclusively
-        // available when calling new(), so accessing them is safe.
+        // available when calling new() or with_ports(), so accessing them is safe.
         unsafe {
             self.index_port.try_write(index | NMI_DISABLE_BIT)?;
             self.data_port.try_read()
@@ -0,0 +1,209 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Runtime configuration for stage0, read from the `opt/stage0/config` fw_cfg
+//! file.
+//!
+//! The file consists of `key=value` lines; empty lines and lines starting with
+//! `#` are ignored. The supported keys are:
+//!
+//! * `console`: the serial port used for logging, one of `com1` (the default),
+//!   `com2` or `none`.
+//! * `log_level`: the maximum log level, one of `off`, `error`, `warn`, `info`,
+//!   `debug` (the default) or `trace`.
+//! * `validation_progress`: whether to log the progress of SEV-SNP memory
+//!   validation, `true` or `false` (the default).
+//! * `defer_hotplug_validation`: whether to leave hotpluggable RAM for the
+//!   kernel to validate under SEV-SNP, `true` or `false` (the default).
+//! * `harden_handoff`: whether to unshare all memory and scrub the stage0 heap
+//!   and stack before jumping to the kernel under SEV-SNP, `true` or `false`
+//!   (the default).
+
+use core::{ffi::CStr, str::FromStr};
+
+use log::LevelFilter;
+
+use crate::fw_cfg::{FwCfg, FwCfgBackend};
+
+/// The fw_cfg file that contains the stage0 configuration.
+const CONFIG_FILE_PATH: &[u8] = b"opt/stage0/config\0";
+
+/// Maximum size of the configuration file we're willing to read.
+const MAX_CONFIG_SIZE: usize = 1024;
+
+/// The serial port stage0 logs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Console {
+    Com1,
+    Com2,
+    None,
+}
+
+impl Console {
+    /// Returns the base I/O port of the serial port, if any.
+    pub fn io_base(&self) -> Option<u16> {
+        match self {
+            Console::Com1 => Some(0x3f8),
+            Console::Com2 => Some(0x2f8),
+            Console::None => None,
+        }
+    }
+}
+
+/// Stage0 runtime configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub console: Console,
+    pub log_level: LevelFilter,
+    pub validation_progress: bool,
+    pub defer_hotplug_validation: bool,
+    pub harden_handoff: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            console: Console::Com1,
+            log_level: LevelFilter::Debug,
+            validation_progress: false,
+            defer_hotplug_validation: false,
+            harden_handoff: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parses the contents of the configuration file. Keys that are not
+    /// present keep their default values.
+    pub fn parse(contents: &str) -> Result<Self, &'static str> {
+        let mut config = Self::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or("invalid line in stage0 config")?;
+            let value = value.trim();
+            match key.trim() {
+                "console" => {
+                    config.console = match value {
+                        "com1" => Console::Com1,
+                        "com2" => Console::Com2,
+                        "none" => Console::None,
+                        _ => return Err("invalid console in stage0 config"),
+                    }
+                }
+                "log_level" => {
+                    config.log_level = LevelFilter::from_str(value)
+                        .map_err(|_| "invalid log level in stage0 config")?
+                }
+                "validation_progress" => {
+                    config.validation_progress = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err("invalid validation_progress in stage0 config"),
+                    }
+                }
+                "defer_hotplug_validation" => {
+                    config.defer_hotplug_validation = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err("invalid defer_hotplug_validation in stage0 config"),
+                    }
+                }
+                "harden_handoff" => {
+                    config.harden_handoff = match value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err("invalid harden_handoff in stage0 config"),
+                    }
+                }
+                _ => return Err("unknown key in stage0 config"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Reads the configuration from fw_cfg.
+///
+/// Returns the default configuration if the file is not present or invalid.
+pub fn load<B: FwCfgBackend>(fw_cfg: &mut FwCfg<B>) -> Config {
+    let path = CStr::from_bytes_with_nul(CONFIG_FILE_PATH).expect("invalid c-string");
+    let Some(file) = fw_cfg.find(path) else {
+        return Config::default();
+    };
+    let mut buf = [0u8; MAX_CONFIG_SIZE];
+    let config = fw_cfg
+        .read_file(&file, &mut buf)
+        .and_then(|len| core::str::from_utf8(&buf[..len]).map_err(|_| "stage0 config is not UTF-8"))
+        .and_then(|contents| Config::parse(contents.trim_end_matches('\0')));
+    match config {
+        Ok(config) => config,
+        Err(err) => {
+            log::warn!("ignoring stage0 config: {}", err);
+            Config::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeFwCfg;
+
+    #[test]
+    fn parse_empty_config() {
+        assert_eq!(Config::parse(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn parse_full_config() {
+        let config = Config::parse(
+            "# comment\nconsole = com2\nlog_level=warn\n\nvalidation_progress=true\n\
+             defer_hotplug_validation=true\nharden_handoff=true\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.console, Console::Com2);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert!(config.validation_progress);
+        assert!(config.defer_hotplug_validation);
+        assert!(config.harden_handoff);
+    }
+
+    #[test]
+    fn parse_invalid_config() {
+        assert!(Config::parse("console=com3").is_err());
+        assert!(Config::parse("log_level=loud").is_err());
+        assert!(Config::parse("colour=blue").is_err());
+        assert!(Config::parse("console").is_err());
+    }
+
+    #[test]
+    fn load_from_fw_cfg() {
+        let mut fw_cfg =
+            FakeFwCfg::default().with_file("opt/stage0/config", b"console=none\n").build();
+
+        assert_eq!(load(&mut fw_cfg).console, Console::None);
+    }
+
+    #[test]
+    fn load_without_config_file() {
+        let mut fw_cfg = FakeFwCfg::default().build();
+
+        assert_eq!(load(&mut fw_cfg), Config::default());
+    }
+}
@@ -23,7 +23,10 @@ use oak_sev_guest::{
 };
 use oak_sev_snp_attestation_report::AttestationReport;
 
-use crate::sev::send_guest_message_request;
+use crate::{
+    measurement::{sha2_384, SHA2_384_DIGEST_SIZE},
+    sev::send_guest_message_request,
+};
 
 type DerivedKey = [u8; 32];
 
@@ -60,6 +63,16 @@ pub fn get_attestation(
     }
 }
 
+/// Returns an attestation report that binds the measured boot event log.
+///
+/// The first 48 bytes of the report data contain the SHA2-384 digest of the
+/// event log; the remaining bytes are zero.
+pub fn get_event_log_attestation(event_log: &[u8]) -> Result<AttestationReport, &'static str> {
+    let mut report_data = [0u8; REPORT_DATA_SIZE];
+    report_data[..SHA2_384_DIGEST_SIZE].copy_from_slice(&sha2_384(event_log));
+    get_attestation(report_data)
+}
+
 /// Requests a derived key.
 ///
 /// The key is derived from the VCEK. The key derivation mixes in the VM launch
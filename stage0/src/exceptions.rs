@@ -0,0 +1,117 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Exception handlers that print diagnostics before terminating the VM.
+//!
+//! Without handlers any fault in stage0 triple-faults immediately, which gives
+//! no indication of what went wrong. The handlers log the faulting instruction,
+//! the error code and the register state over the logging channel, and then
+//! deliberately trigger a triple fault so that the VMM still sees the VM
+//! terminate.
+
+use core::ops::Deref;
+
+use log::error;
+use x86_64::{
+    instructions::{hlt, interrupts::int3},
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        DescriptorTablePointer,
+    },
+    VirtAddr,
+};
+
+/// Populates the IDT with the stage0 exception handlers.
+pub fn init_idt(idt: &mut InterruptDescriptorTable) {
+    idt.breakpoint.set_handler_fn(breakpoint_handler); // vector 3
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler); // vector 6
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler); // vector 13
+    idt.page_fault.set_handler_fn(page_fault_handler); // vector 14
+    crate::vc::init_idt(idt); // vector 29
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    // The panic handler triggers a breakpoint after logging the panic message, so
+    // there's no need to be too verbose here.
+    error!("EXCEPTION: BREAKPOINT");
+    error!("Instruction pointer: {:#018x}", stack_frame.deref().instruction_pointer.as_u64());
+    terminate();
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    error!("EXCEPTION: INVALID OPCODE");
+    log_register_state(&stack_frame);
+    terminate();
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    error!("EXCEPTION: GENERAL PROTECTION FAULT");
+    error!("Error code: {:#x}", error_code);
+    log_register_state(&stack_frame);
+    terminate();
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    error!("EXCEPTION: PAGE FAULT");
+    error!("Faulting address: {:#018x}", Cr2::read().as_u64());
+    error!("Error code: {:?}", error_code);
+    log_register_state(&stack_frame);
+    terminate();
+}
+
+/// Logs the interrupt stack frame and the control registers.
+fn log_register_state(stack_frame: &InterruptStackFrame) {
+    let stack_frame = stack_frame.deref();
+    error!(
+        "RIP: {:#018x} CS: {:#06x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment
+    );
+    error!(
+        "RSP: {:#018x} SS: {:#06x}",
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment
+    );
+    error!("RFLAGS: {:#018x}", stack_frame.cpu_flags);
+    error!("CR0: {:#018x}", Cr0::read_raw());
+    error!("CR3: {:#018x}", Cr3::read_raw().0.start_address().as_u64());
+    error!("CR4: {:#018x}", Cr4::read_raw());
+}
+
+/// Terminates the VM by triple-faulting.
+///
+/// We load an empty IDT and trigger a breakpoint; as there is no handler for
+/// the breakpoint (or the resulting double fault), the CPU shuts down.
+pub fn terminate() -> ! {
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    // Safety: we don't expect to handle any more interrupts, and the whole point
+    // is to make the next exception fatal.
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    int3();
+
+    loop {
+        hlt();
+    }
+}
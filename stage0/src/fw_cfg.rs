@@ -72,6 +72,7 @@ impl Default for DmaBuffer {
 enum FwCfgItems {
     Signature = 0x0000,
     Features = 0x0001,
+    NbCpus = 0x0005,
     KernelAddr = 0x0007,
     KernelSize = 0x0008,
     InitrdAddr = 0x000a,
@@ -137,10 +138,24 @@ impl core::fmt::Debug for DirEntry {
     }
 }
 
-/// Wrapper for the QEMU Firmware Configuration device.
+/// Low-level access to a fw_cfg device: selecting an item and reading its
+/// contents.
 ///
-/// See <https://www.qemu.org/docs/master/specs/fw_cfg.html> for more details.
-pub struct FwCfg {
+/// This allows the logic built on top of fw_cfg to be exercised against an
+/// in-memory device in unit tests.
+pub trait FwCfgBackend {
+    /// Selects the item to read and resets the read offset to the start of the
+    /// item.
+    fn write_selector(&mut self, selector: u16) -> Result<(), &'static str>;
+
+    /// Reads the next `buf.len()` bytes of the selected item.
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<(), &'static str>;
+}
+
+/// Backend that accesses the fw_cfg device via I/O ports.
+///
+/// If the device supports it, data is read using the DMA interface.
+pub struct PortBackend {
     selector: PortWrapper<u16>,
     data: PortWrapper<u8>,
     dma_high: PortWrapper<u32>,
@@ -150,6 +165,32 @@ pub struct FwCfg {
     dma_enabled: bool,
 }
 
+impl FwCfgBackend for PortBackend {
+    fn write_selector(&mut self, selector: u16) -> Result<(), &'static str> {
+        // Safety: we make sure the device is available when initializing FwCfg.
+        unsafe { self.selector.try_write(selector) }
+    }
+
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        if self.dma_enabled {
+            return self.read_buf_dma(buf);
+        }
+        for i in buf {
+            // Safety: We make sure that the device is available in `new()`, so reading from
+            // the port is safe.
+            *i = unsafe { self.data.try_read() }?;
+        }
+        Ok(())
+    }
+}
+
+/// Wrapper for the QEMU Firmware Configuration device.
+///
+/// See <https://www.qemu.org/docs/master/specs/fw_cfg.html> for more details.
+pub struct FwCfg<B: FwCfgBackend = PortBackend> {
+    backend: B,
+}
+
 impl FwCfg {
     /// # Safety
     ///
@@ -161,7 +202,7 @@ impl FwCfg {
     /// The caller has to guarantee that at least doing the probe will not cause
     /// any adverse effects.
     pub unsafe fn new(alloc: &'static BootAllocator) -> Result<Self, &'static str> {
-        let mut fwcfg = Self {
+        let mut fwcfg = Self::with_backend(PortBackend {
             selector: io_port_factory().new_writer(FWCFG_PORT_SELECTOR),
             data: io_port_factory().new_reader(FWCFG_PORT_DATA),
             dma_high: io_port_factory().new_writer(FWCFG_PORT_DMA),
@@ -171,13 +212,7 @@ impl FwCfg {
             dma_buf: Shared::new_in(DmaBuffer::default(), alloc),
             dma_access: Shared::new_in(FwCfgDmaAccess::default(), alloc),
             dma_enabled: false,
-        };
-
-        // Make sure the fw_cfg device is available. If the device is not available,
-        // writing and reading to I/O ports is undefined behaviour.
-        fwcfg.write_selector(FwCfgItems::Signature as u16)?;
-        let mut signature = [0u8; SIGNATURE.len()];
-        fwcfg.read(&mut signature)?;
+        })?;
 
         // Check whether DMA is enabled.
         let mut features = 0u8;
@@ -187,9 +222,26 @@ impl FwCfg {
         let features =
             Features::from_bits(features).ok_or("invalid fw_cfg device features received")?;
         if features.contains(Features::DMA) {
-            fwcfg.dma_enabled = true;
+            fwcfg.backend.dma_enabled = true;
         }
 
+        Ok(fwcfg)
+    }
+}
+
+impl<B: FwCfgBackend> FwCfg<B> {
+    /// Creates a wrapper for a fw_cfg device accessed via `backend`.
+    ///
+    /// Fails if the device doesn't have the expected signature.
+    pub fn with_backend(backend: B) -> Result<Self, &'static str> {
+        let mut fwcfg = Self { backend };
+
+        // Make sure the fw_cfg device is available. If the device is not available,
+        // writing and reading to I/O ports is undefined behaviour.
+        fwcfg.write_selector(FwCfgItems::Signature as u16)?;
+        let mut signature = [0u8; SIGNATURE.len()];
+        fwcfg.read(&mut signature)?;
+
         if signature == SIGNATURE { Ok(fwcfg) } else { Err("QEMU fw_cfg device not available") }
     }
 
@@ -269,11 +321,7 @@ impl FwCfg {
     pub fn read_file(&mut self, file: &DirEntry, buf: &mut [u8]) -> Result<usize, &'static str> {
         self.write_selector(file.selector())?;
         let len = min(buf.len(), file.size());
-        if self.dma_enabled {
-            self.read_buf_dma(&mut buf[..len])?;
-        } else {
-            self.read_buf(&mut buf[..len])?;
-        }
+        self.backend.read_buf(&mut buf[..len])?;
         Ok(len)
     }
 
@@ -283,13 +331,7 @@ impl FwCfg {
     pub fn read_file_vec(&mut self, file: &DirEntry) -> Result<Vec<u8>, &'static str> {
         self.write_selector(file.selector())?;
         let mut buf = vec![0; file.size()];
-
-        if self.dma_enabled {
-            self.read_buf_dma(&mut buf)?;
-        } else {
-            self.read_buf(&mut buf)?;
-        }
-
+        self.backend.read_buf(&mut buf)?;
         Ok(buf)
     }
 
@@ -356,6 +398,14 @@ impl FwCfg {
         }
     }
 
+    /// Reads the number of CPUs the VM was started with.
+    pub fn read_nb_cpus(&mut self) -> Result<u16, &'static str> {
+        let mut nb_cpus: u16 = 0;
+        self.write_selector(FwCfgItems::NbCpus as u16)?;
+        self.read(&mut nb_cpus)?;
+        Ok(nb_cpus)
+    }
+
     /// Reads the size of the setup information for the kernel.
     pub fn read_setup_size(&mut self) -> Result<u32, &'static str> {
         let mut setup_size: u32 = 0;
@@ -374,23 +424,15 @@ impl FwCfg {
     }
 
     fn write_selector(&mut self, selector: u16) -> Result<(), &'static str> {
-        // Safety: we make sure the device is available when initializing FwCfg.
-        unsafe { self.selector.try_write(selector) }
+        self.backend.write_selector(selector)
     }
 
     fn read<T: AsBytes + FromBytes>(&mut self, object: &mut T) -> Result<(), &'static str> {
-        self.read_buf(object.as_bytes_mut())
-    }
-
-    fn read_buf(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
-        for i in buf {
-            // Safety: We make sure that the device is available in `new()`, so reading from
-            // the port is safe.
-            *i = unsafe { self.data.try_read() }?;
-        }
-        Ok(())
+        self.backend.read_buf(object.as_bytes_mut())
     }
+}
 
+impl PortBackend {
     fn read_buf_dma(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
         let mut chunks_mut = buf.chunks_mut(Size4KiB::SIZE as usize);
         for chunk in chunks_mut.by_ref() {
@@ -454,16 +496,33 @@ bitflags! {
 pub fn find_suitable_dma_address(
     size: usize,
     e820_table: &[BootE820Entry],
+) -> Result<PhysAddr, &'static str> {
+    find_suitable_address(
+        size,
+        PhysAddr::zero(),
+        PhysAddr::new(crate::TOP_OF_VIRTUAL_MEMORY),
+        e820_table,
+    )
+}
+
+/// Finds the highest section of RAM that is big enough to hold a buffer of
+/// `size` bytes and falls within the range `[min, max)`.
+pub fn find_suitable_address(
+    size: usize,
+    min: PhysAddr,
+    max: PhysAddr,
+    e820_table: &[BootE820Entry],
 ) -> Result<PhysAddr, &'static str> {
     let padded_size = (size as u64).checked_next_multiple_of(Size4KiB::SIZE).unwrap();
+    let max = max.as_u64().min(crate::TOP_OF_VIRTUAL_MEMORY);
     e820_table
         .iter()
         .filter_map(|entry| {
             if entry.entry_type() != Some(E820EntryType::RAM) {
                 return None;
             }
-            let start = entry.addr() as u64;
-            let end = crate::TOP_OF_VIRTUAL_MEMORY.min(start + entry.size() as u64);
+            let start = (entry.addr() as u64).max(min.align_up(Size4KiB::SIZE).as_u64());
+            let end = max.min((entry.addr() + entry.size()) as u64);
             if padded_size.checked_add(start).unwrap() > end {
                 return None;
             }
@@ -472,7 +531,7 @@ pub fn find_suitable_dma_address(
             Some(PhysAddr::new(end.checked_sub(padded_size).unwrap()).align_down(Size4KiB::SIZE))
         })
         .max_by(PhysAddr::cmp)
-        .ok_or("no suitable memory available for dma buffer")
+        .ok_or("no suitable memory available for buffer")
 }
 
 /// Makes sure that a chunk of memory is valid
@@ -545,3 +604,93 @@ impl FwCfgDmaAccess {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeFwCfg;
+
+    #[test]
+    fn read_files() {
+        let mut fw_cfg = FakeFwCfg::default()
+            .with_file("etc/first", b"first")
+            .with_file("opt/second", b"second file")
+            .build();
+
+        let file = fw_cfg.find(CStr::from_bytes_with_nul(b"opt/second\0").unwrap()).unwrap();
+        assert_eq!(file.size(), 11);
+        assert_eq!(fw_cfg.read_file_vec(&file), Ok(b"second file".to_vec()));
+        // Reading into a smaller buffer only fills the buffer.
+        let mut buf = [0u8; 6];
+        assert_eq!(fw_cfg.read_file(&file, &mut buf), Ok(6));
+        assert_eq!(&buf, b"second");
+        assert!(fw_cfg.find(CStr::from_bytes_with_nul(b"etc/third\0").unwrap()).is_none());
+    }
+
+    #[test]
+    fn read_well_known_items() {
+        let mut fw_cfg = FakeFwCfg::default()
+            .with_item(FwCfgItems::NbCpus as u16, &4u16.to_le_bytes())
+            .with_item(FwCfgItems::CmdlineSize as u16, &12u32.to_le_bytes())
+            .build();
+
+        assert_eq!(fw_cfg.read_nb_cpus(), Ok(4));
+        assert_eq!(fw_cfg.get_cmdline_file().map(|file| file.size()), Some(12));
+        assert!(fw_cfg.get_kernel_file().is_none());
+    }
+
+    #[test]
+    fn missing_device() {
+        let fake = FakeFwCfg::default().with_item(FwCfgItems::Signature as u16, b"NONE");
+
+        assert!(FwCfg::with_backend(fake).is_err());
+    }
+
+    #[test]
+    fn find_suitable_address_highest() {
+        let e820_table = [
+            BootE820Entry::new(0, 0x8_0000, E820EntryType::RAM),
+            BootE820Entry::new(0x10_0000, 0x100_0000, E820EntryType::RAM),
+        ];
+
+        let address = find_suitable_address(
+            0x1800,
+            PhysAddr::zero(),
+            PhysAddr::new(crate::TOP_OF_VIRTUAL_MEMORY),
+            &e820_table,
+        );
+
+        assert_eq!(address, Ok(PhysAddr::new(0x110_0000 - 0x2000)));
+    }
+
+    #[test]
+    fn find_suitable_address_bounded() {
+        let e820_table = [
+            BootE820Entry::new(0, 0x8_0000, E820EntryType::RAM),
+            BootE820Entry::new(0x10_0000, 0x100_0000, E820EntryType::RAM),
+        ];
+
+        let address = find_suitable_address(
+            0x1000,
+            PhysAddr::new(0x1_0000),
+            PhysAddr::new(0x4_0000),
+            &e820_table,
+        );
+
+        assert_eq!(address, Ok(PhysAddr::new(0x3_F000)));
+    }
+
+    #[test]
+    fn find_suitable_address_too_large() {
+        let e820_table = [BootE820Entry::new(0x10_0000, 0x10_0000, E820EntryType::RAM)];
+
+        let address = find_suitable_address(
+            0x1000,
+            PhysAddr::new(0x1F_F800),
+            PhysAddr::new(crate::TOP_OF_VIRTUAL_MEMORY),
+            &e820_table,
+        );
+
+        assert!(address.is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Awareness of hotpluggable memory.
+//!
+//! QEMU describes the address ranges where memory can be hotplugged (DIMMs or
+//! virtio-mem devices) as memory affinity structures with the hot-pluggable
+//! flag set in the SRAT. As we need this information before memory validation,
+//! long before the ACPI tables are built, we look for the SRAT in the raw
+//! `etc/acpi/tables` fw_cfg file. The memory affinity structures contain
+//! absolute addresses, so the pointers that the table loader patches later
+//! don't matter.
+//!
+//! Hotpluggable memory that hasn't been plugged yet is not part of the E820
+//! table, and we don't add it: the kernel discovers it when it is plugged, and
+//! a reserved E820 entry would get in the way of that. Some VMMs do list
+//! hotpluggable memory as RAM in the E820 table, though. Under SEV-SNP we can
+//! leave those ranges for the kernel to validate, which is passed the ranges
+//! via setup data.
+
+use alloc::vec::Vec;
+use core::{alloc::Allocator, ffi::CStr, mem::zeroed, ops::Range};
+
+use oak_linux_boot_params::{BootE820Entry, E820EntryType};
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    PhysAddr,
+};
+
+use crate::fw_cfg::{FwCfg, FwCfgBackend};
+
+/// The fw_cfg file that contains the ACPI tables generated by QEMU.
+const ACPI_TABLES_FILE_PATH: &[u8] = b"etc/acpi/tables\0";
+
+/// Size of the common ACPI table header.
+const HEADER_SIZE: usize = 36;
+
+/// Offset of the first structure in the SRAT, after the header and 12 reserved
+/// bytes.
+const SRAT_STRUCTURES_OFFSET: usize = HEADER_SIZE + 12;
+
+/// Structure type of the SRAT Memory Affinity Structure.
+const MEMORY_AFFINITY_TYPE: u8 = 1;
+
+/// Length of the SRAT Memory Affinity Structure.
+const MEMORY_AFFINITY_LENGTH: usize = 40;
+
+/// Offsets of the Memory Affinity Structure fields we need.
+const MEMORY_AFFINITY_BASE_OFFSET: usize = 8;
+const MEMORY_AFFINITY_LENGTH_OFFSET: usize = 16;
+const MEMORY_AFFINITY_FLAGS_OFFSET: usize = 28;
+
+/// Memory affinity flag indicating that the structure is in use.
+const MEMORY_AFFINITY_ENABLED: u32 = 1 << 0;
+
+/// Memory affinity flag indicating that the memory can be hotplugged.
+const MEMORY_AFFINITY_HOT_PLUGGABLE: u32 = 1 << 1;
+
+/// Stage0 loads the kernel and the initial RAM disk below this address, so
+/// memory below it is always validated.
+const MIN_DEFERRED_ADDRESS: u64 = crate::TOP_OF_VIRTUAL_MEMORY;
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Finds a table in a blob of concatenated ACPI tables.
+///
+/// The walk stops at the first entry that doesn't look like a table, such as
+/// the zero padding at the end of the blob.
+fn find_table<'a>(blob: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut offset = 0;
+    while offset + 8 <= blob.len() {
+        let length = read_u32(blob, offset + 4) as usize;
+        if length < 8 || offset + length > blob.len() {
+            return None;
+        }
+        if &blob[offset..offset + 4] == signature {
+            return Some(&blob[offset..offset + length]);
+        }
+        offset += length;
+    }
+    None
+}
+
+/// Reads the ACPI tables provided by the VMM into `buf` and returns the SRAT,
+/// if there is one.
+pub fn find_srat<'a, B: FwCfgBackend>(
+    fw_cfg: &mut FwCfg<B>,
+    buf: &'a mut [u8],
+) -> Option<&'a [u8]> {
+    let path = CStr::from_bytes_with_nul(ACPI_TABLES_FILE_PATH).expect("invalid c-string");
+    let file = fw_cfg.find(path)?;
+    let len = fw_cfg.read_file(&file, buf).ok()?;
+    find_table(&buf[..len], b"SRAT")
+}
+
+/// Returns the enabled, hotpluggable memory ranges described in the SRAT.
+pub fn hotpluggable_ranges(srat: &[u8]) -> impl Iterator<Item = Range<u64>> + '_ {
+    let mut offset = SRAT_STRUCTURES_OFFSET;
+    core::iter::from_fn(move || {
+        while offset + 2 <= srat.len() {
+            let structure_type = srat[offset];
+            let length = srat[offset + 1] as usize;
+            if length < 2 || offset + length > srat.len() {
+                return None;
+            }
+            let structure = &srat[offset..offset + length];
+            offset += length;
+            if structure_type != MEMORY_AFFINITY_TYPE || length < MEMORY_AFFINITY_LENGTH {
+                continue;
+            }
+            let flags = read_u32(structure, MEMORY_AFFINITY_FLAGS_OFFSET);
+            let hot_pluggable = MEMORY_AFFINITY_ENABLED | MEMORY_AFFINITY_HOT_PLUGGABLE;
+            if flags & hot_pluggable != hot_pluggable {
+                continue;
+            }
+            let base = read_u64(structure, MEMORY_AFFINITY_BASE_OFFSET);
+            let size = read_u64(structure, MEMORY_AFFINITY_LENGTH_OFFSET);
+            return Some(base..base.saturating_add(size));
+        }
+        None
+    })
+}
+
+/// Splits the RAM in the E820 table into boot memory and hotpluggable memory.
+///
+/// Returns a copy of the E820 table without the hotpluggable RAM, and the
+/// hotpluggable RAM ranges that were removed. Only whole pages above
+/// `MIN_DEFERRED_ADDRESS` are treated as hotpluggable.
+pub fn split_boot_memory<A: Allocator + Copy>(
+    e820_table: &[BootE820Entry],
+    hotpluggable: &[Range<u64>],
+    alloc: A,
+) -> (Vec<BootE820Entry, A>, Vec<BootE820Entry, A>) {
+    let hotpluggable = hotpluggable
+        .iter()
+        .map(|range| {
+            let start =
+                PhysAddr::new(range.start.max(MIN_DEFERRED_ADDRESS)).align_up(Size4KiB::SIZE);
+            let end = PhysAddr::new(range.end).align_down(Size4KiB::SIZE);
+            start.as_u64()..end.as_u64()
+        })
+        .filter(|range| !range.is_empty());
+
+    let mut boot_memory = Vec::new_in(alloc);
+    let mut deferred = Vec::new_in(alloc);
+    for entry in e820_table {
+        if entry.entry_type() != Some(E820EntryType::RAM) {
+            boot_memory.push(*entry);
+            continue;
+        }
+        let mut start = entry.addr() as u64;
+        let end = (entry.addr() + entry.size()) as u64;
+        while start < end {
+            // Find the first hotpluggable range that overlaps what's left of the entry.
+            let Some(range) = hotpluggable
+                .clone()
+                .filter(|range| range.start < end && range.end > start)
+                .min_by_key(|range| range.start)
+            else {
+                boot_memory.push(ram(start, end));
+                break;
+            };
+            if range.start > start {
+                boot_memory.push(ram(start, range.start));
+            }
+            let deferred_end = range.end.min(end);
+            deferred.push(ram(range.start.max(start), deferred_end));
+            start = deferred_end;
+        }
+    }
+    (boot_memory, deferred)
+}
+
+fn ram(start: u64, end: u64) -> BootE820Entry {
+    BootE820Entry::new(start as usize, (end - start) as usize, E820EntryType::RAM)
+}
+
+/// Removes the hotpluggable RAM described in the SRAT from the E820 table.
+///
+/// Returns a copy of the E820 table with just the boot memory, and the
+/// hotpluggable RAM ranges that were removed.
+pub fn defer_hotpluggable_memory<A: Allocator + Copy, B: FwCfgBackend>(
+    fw_cfg: &mut FwCfg<B>,
+    e820_table: &[BootE820Entry],
+    alloc: A,
+) -> (Vec<BootE820Entry, A>, Vec<BootE820Entry, A>) {
+    // Safety: the EBDA is only used for the ACPI tables, which are built from
+    // scratch after memory validation, so we can use it as a scratch buffer.
+    let buf = unsafe { crate::acpi::EBDA.write(zeroed()) };
+    let mut hotpluggable = Vec::new_in(alloc);
+    if let Some(srat) = find_srat(fw_cfg, buf) {
+        hotpluggable.extend(hotpluggable_ranges(srat));
+    }
+    for range in &hotpluggable {
+        log::info!("hotpluggable memory [{:#018x}..{:#018x})", range.start, range.end);
+    }
+    split_boot_memory(e820_table, &hotpluggable, alloc)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{alloc::Global, vec, vec::Vec};
+
+    use super::*;
+    use crate::testing::FakeFwCfg;
+
+    const GIB: u64 = 1 << 30;
+
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut table = vec![0u8; HEADER_SIZE];
+        table[0..4].copy_from_slice(signature);
+        table.extend_from_slice(body);
+        let length = table.len() as u32;
+        table[4..8].copy_from_slice(&length.to_le_bytes());
+        table
+    }
+
+    fn memory_affinity(base: u64, size: u64, flags: u32) -> Vec<u8> {
+        let mut structure = vec![0u8; MEMORY_AFFINITY_LENGTH];
+        structure[0] = MEMORY_AFFINITY_TYPE;
+        structure[1] = MEMORY_AFFINITY_LENGTH as u8;
+        structure[MEMORY_AFFINITY_BASE_OFFSET..MEMORY_AFFINITY_BASE_OFFSET + 8]
+            .copy_from_slice(&base.to_le_bytes());
+        structure[MEMORY_AFFINITY_LENGTH_OFFSET..MEMORY_AFFINITY_LENGTH_OFFSET + 8]
+            .copy_from_slice(&size.to_le_bytes());
+        structure[MEMORY_AFFINITY_FLAGS_OFFSET..MEMORY_AFFINITY_FLAGS_OFFSET + 4]
+            .copy_from_slice(&flags.to_le_bytes());
+        structure
+    }
+
+    fn srat() -> Vec<u8> {
+        let mut body = vec![0u8; SRAT_STRUCTURES_OFFSET - HEADER_SIZE];
+        // A processor affinity structure, which we skip.
+        body.extend_from_slice(&[0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.extend(memory_affinity(0, 4 * GIB, MEMORY_AFFINITY_ENABLED));
+        body.extend(memory_affinity(
+            4 * GIB,
+            2 * GIB,
+            MEMORY_AFFINITY_ENABLED | MEMORY_AFFINITY_HOT_PLUGGABLE,
+        ));
+        // Disabled structures are ignored.
+        body.extend(memory_affinity(8 * GIB, GIB, MEMORY_AFFINITY_HOT_PLUGGABLE));
+        table(b"SRAT", &body)
+    }
+
+    #[test]
+    fn find_srat_in_acpi_tables() {
+        let mut blob = table(b"FACS", &[0u8; 28]);
+        blob.extend(table(b"DSDT", &[0u8; 100]));
+        blob.extend(srat());
+        blob.extend(table(b"APIC", &[0u8; 8]));
+        blob.resize(4096, 0);
+        let mut fw_cfg = FakeFwCfg::default().with_file("etc/acpi/tables", &blob).build();
+        let mut buf = vec![0u8; 8192];
+
+        let srat = find_srat(&mut fw_cfg, &mut buf).expect("no SRAT");
+
+        assert_eq!(hotpluggable_ranges(srat).collect::<Vec<_>>(), vec![4 * GIB..6 * GIB]);
+    }
+
+    #[test]
+    fn no_srat() {
+        let blob = table(b"DSDT", &[0u8; 100]);
+        let mut fw_cfg = FakeFwCfg::default().with_file("etc/acpi/tables", &blob).build();
+        let mut buf = vec![0u8; 8192];
+
+        assert!(find_srat(&mut fw_cfg, &mut buf).is_none());
+        assert!(find_srat(&mut FakeFwCfg::default().build(), &mut buf).is_none());
+    }
+
+    #[test]
+    fn split_hotpluggable_ram() {
+        let e820_table = [
+            ram(0, 0x9_F000),
+            BootE820Entry::new(0xF_0000, 0x1_0000, E820EntryType::RESERVED),
+            ram(0x10_0000, 4 * GIB),
+            ram(4 * GIB, 8 * GIB),
+        ];
+
+        let (boot_memory, deferred) =
+            split_boot_memory(&e820_table, &[6 * GIB..7 * GIB, 7 * GIB..10 * GIB], Global);
+
+        assert_eq!(
+            boot_memory,
+            vec![e820_table[0], e820_table[1], e820_table[2], ram(4 * GIB, 6 * GIB)]
+        );
+        assert_eq!(deferred, vec![ram(6 * GIB, 7 * GIB), ram(7 * GIB, 8 * GIB)]);
+    }
+
+    #[test]
+    fn low_memory_is_never_deferred() {
+        let e820_table = [ram(0x10_0000, 4 * GIB)];
+
+        let (boot_memory, deferred) = split_boot_memory(&e820_table, &[0..2 * GIB], Global);
+
+        assert_eq!(boot_memory, vec![ram(0x10_0000, MIN_DEFERRED_ADDRESS), ram(2 * GIB, 4 * GIB)]);
+        assert_eq!(deferred, vec![ram(MIN_DEFERRED_ADDRESS, 2 * GIB)]);
+    }
+}
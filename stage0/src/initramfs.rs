@@ -17,32 +17,48 @@
 use core::{ffi::CStr, slice};
 
 use oak_linux_boot_params::BootE820Entry;
+use x86_64::PhysAddr;
 
 use crate::{
-    fw_cfg::{check_non_overlapping, find_suitable_dma_address, FwCfg},
+    fw_cfg::{check_non_overlapping, find_suitable_address, FwCfg},
     kernel::KernelInfo,
 };
 
-/// The file path used by Stage0 to read the initial RAM disk from the fw_cfg
-/// device.
-const INITIAL_RAM_DISK_FILE_PATH: &[u8] = b"opt/stage0/initramfs\0";
+/// The file paths used by Stage0 to read the initial RAM disk from the fw_cfg
+/// device if it wasn't provided via the traditional selector, in order of
+/// preference.
+const INITIAL_RAM_DISK_FILE_PATHS: &[&[u8]] =
+    &[b"opt/stage0/initramfs\0", b"opt/initrd\0", b"etc/ramdisk\0"];
 
 /// Tries to load an initial RAM disk from the QEMU FW_CFG device.
 ///
+/// The RAM disk is placed as high as possible in memory, but above the end of
+/// the kernel and below `initrd_addr_max`, if the kernel specified it.
+///
 /// If it finds a RAM disk it returns the byte slice where it is loaded. If not
 /// it returns `None`.
 pub fn try_load_initial_ram_disk(
     fw_cfg: &mut FwCfg,
     e820_table: &[BootE820Entry],
     kernel_info: &KernelInfo,
+    initrd_addr_max: Option<u32>,
 ) -> Option<&'static [u8]> {
     let file = fw_cfg.get_initrd_file().or_else(|| {
-        let path = CStr::from_bytes_with_nul(INITIAL_RAM_DISK_FILE_PATH).expect("invalid c-string");
-        fw_cfg.find(path)
+        INITIAL_RAM_DISK_FILE_PATHS.iter().find_map(|path| {
+            let path = CStr::from_bytes_with_nul(path).expect("invalid c-string");
+            fw_cfg.find(path)
+        })
     })?;
     let size = file.size();
-    let initrd_address =
-        find_suitable_dma_address(size, e820_table).expect("no suitable DMA address available");
+    // `initrd_addr_max` is the address of the last byte the RAM disk may occupy.
+    let max_address = PhysAddr::new(
+        initrd_addr_max.map(|max| max as u64 + 1).unwrap_or(crate::TOP_OF_VIRTUAL_MEMORY),
+    );
+    // We use an identity mapping, so the end of the kernel in virtual memory is
+    // also its end in physical memory.
+    let kernel_end = PhysAddr::new(kernel_info.start_address.as_u64() + kernel_info.size as u64);
+    let initrd_address = find_suitable_address(size, kernel_end, max_address, e820_table)
+        .expect("no suitable address available for initial RAM disk");
 
     log::debug!("Initial RAM disk size {}", size);
     log::debug!("Initial RAM disk address {:#018x}", initrd_address.as_u64());
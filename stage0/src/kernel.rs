@@ -18,10 +18,14 @@ use alloc::{ffi::CString, string::String, vec};
 use core::{ffi::CStr, slice};
 
 use elf::{abi::PT_LOAD, endian::AnyEndian, segment::ProgramHeader, ElfBytes};
-use oak_linux_boot_params::BootE820Entry;
+use oak_linux_boot_params::{BootE820Entry, SetupHeader};
 use x86_64::{PhysAddr, VirtAddr};
 
-use crate::fw_cfg::{check_memory, check_non_overlapping, find_suitable_dma_address, FwCfg};
+use crate::{
+    bzimage::BzImage,
+    fw_cfg::{check_memory, check_non_overlapping, find_suitable_dma_address, FwCfg},
+    measurement::{Sha384Digest, SHA2_384_DIGEST_SIZE},
+};
 
 /// The default start location and entry point for the kernel if a kernel wasn't
 /// supplied via the QEMU fw_cfg device.
@@ -64,8 +68,16 @@ pub struct KernelInfo {
     pub entry: VirtAddr,
     /// The SHA2-256 digest of the raw kernel image.
     pub measurement: crate::Measurement,
+    /// The SHA2-384 digest of the raw kernel image.
+    pub sha2_384_digest: Sha384Digest,
     /// The type of kernel that we are booting.
     pub kernel_type: KernelType,
+    /// The setup header from the kernel image, if stage0 parsed a full bzImage
+    /// file itself.
+    pub setup_header: Option<SetupHeader>,
+    /// The 32-bit PVH entry point, if the kernel is an ELF binary that
+    /// advertises one.
+    pub pvh_entry: Option<PhysAddr>,
 }
 
 impl Default for KernelInfo {
@@ -75,7 +87,10 @@ impl Default for KernelInfo {
             size: DEFAULT_KERNEL_SIZE,
             entry: VirtAddr::new(DEFAULT_KERNEL_START),
             measurement: crate::Measurement::default(),
+            sha2_384_digest: [0; SHA2_384_DIGEST_SIZE],
             kernel_type: KernelType::Preloaded,
+            setup_header: None,
+            pvh_entry: None,
         }
     }
 }
@@ -115,9 +130,10 @@ pub fn try_load_cmdline(fw_cfg: &mut FwCfg) -> Option<String> {
 /// Tries to load a kernel image from the QEMU fw_cfg device.
 ///
 /// We assume that a kernel file provided via the traditional selector is a
-/// compressed kernel using the bzImage format. We assume that a kernel file
-/// provided via the custom filename of "opt/stage0/elf_kernel" is an
-/// uncompressed ELF file.
+/// compressed kernel using the bzImage format, with the setup data already
+/// split off by the VMM. A kernel file provided via the custom filename of
+/// "opt/stage0/elf_kernel" is either a full bzImage file (including the setup
+/// data), or an uncompressed ELF file.
 ///
 /// If it finds a kernel it returns the information about the kernel, otherwise
 /// `None`.
@@ -137,8 +153,9 @@ pub fn try_load_kernel_image(
     let dma_address = if bzimage {
         PhysAddr::new(DEFAULT_BZIMAGE_SATRT)
     } else {
-        // For an Elf kernel we copy the kernel image to a temporary location at the end
-        // of available mapped virtual memory where we can parse it.
+        // For an ELF kernel or a full bzImage file we copy the kernel image to a
+        // temporary location at the end of available mapped virtual memory
+        // where we can parse it.
         find_suitable_dma_address(size, e820_table).expect("no suitable DMA address available")
     };
     let start_address = crate::phys_to_virt(dma_address);
@@ -151,6 +168,7 @@ pub fn try_load_kernel_image(
     assert_eq!(actual_size, size, "kernel size did not match expected size");
 
     let measurement = crate::measure_byte_slice(buf);
+    let sha2_384_digest = crate::measurement::sha2_384(buf);
 
     if bzimage {
         // For a bzImage the 64-bit entry point is at offset 0x200 from the start of the
@@ -158,9 +176,43 @@ pub fn try_load_kernel_image(
         let entry = start_address + 0x200usize;
         log::debug!("Kernel entry point {:#018x}", entry.as_u64());
         let kernel_type = KernelType::BzImage;
-        Some(KernelInfo { start_address, size, entry, measurement, kernel_type })
+        Some(KernelInfo {
+            start_address,
+            size,
+            entry,
+            measurement,
+            sha2_384_digest,
+            kernel_type,
+            setup_header: None,
+            pvh_entry: None,
+        })
+    } else if BzImage::is_bzimage(buf) {
+        Some(load_bzimage_file(buf, e820_table, measurement, sha2_384_digest))
     } else {
-        Some(parse_elf_file(buf, e820_table, measurement))
+        Some(parse_elf_file(buf, e820_table, measurement, sha2_384_digest))
+    }
+}
+
+fn load_bzimage_file(
+    buf: &[u8],
+    e820_table: &[BootE820Entry],
+    measurement: crate::Measurement,
+    sha2_384_digest: Sha384Digest,
+) -> KernelInfo {
+    let image = BzImage::parse(buf)
+        .expect("couldn't parse bzImage kernel")
+        .load(e820_table)
+        .expect("couldn't load bzImage kernel");
+
+    KernelInfo {
+        start_address: image.start_address,
+        size: image.size,
+        entry: image.entry,
+        measurement,
+        sha2_384_digest,
+        kernel_type: KernelType::BzImage,
+        setup_header: Some(image.header),
+        pvh_entry: None,
     }
 }
 
@@ -168,6 +220,7 @@ fn parse_elf_file(
     buf: &[u8],
     e820_table: &[BootE820Entry],
     measurement: crate::Measurement,
+    sha2_384_digest: Sha384Digest,
 ) -> KernelInfo {
     let mut kernel_start = VirtAddr::new(crate::TOP_OF_VIRTUAL_MEMORY);
     let mut kernel_end = VirtAddr::new(0);
@@ -196,11 +249,24 @@ fn parse_elf_file(
     let kernel_size = (kernel_end - kernel_start) as usize;
     let entry = crate::phys_to_virt(PhysAddr::new(file.ehdr.e_entry));
     let kernel_type = KernelType::Elf;
+    let pvh_entry = crate::pvh::find_pvh_entry(buf);
     log::debug!("Kernel size {}", kernel_size);
     log::debug!("Kernel start address {:#018x}", kernel_start.as_u64());
     log::debug!("Kernel entry point {:#018x}", entry.as_u64());
+    if let Some(pvh_entry) = pvh_entry {
+        log::debug!("Kernel PVH entry point {:#018x}", pvh_entry.as_u64());
+    }
 
-    KernelInfo { start_address: kernel_start, size: kernel_size, entry, measurement, kernel_type }
+    KernelInfo {
+        start_address: kernel_start,
+        size: kernel_size,
+        entry,
+        measurement,
+        sha2_384_digest,
+        kernel_type,
+        setup_header: None,
+        pvh_entry,
+    }
 }
 
 /// Loads a segment from an ELF file into memory.
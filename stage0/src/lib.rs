@@ -15,26 +15,33 @@
 //
 
 #![no_std]
+#![feature(abi_x86_interrupt)]
 #![feature(int_roundings)]
 #![feature(allocator_api)]
+#![feature(naked_functions)]
 #![feature(slice_ptr_get)]
 
 extern crate alloc;
 
 use alloc::{boxed::Box, format};
-use core::{arch::asm, ffi::c_void, mem::MaybeUninit, panic::PanicInfo};
+use core::{
+    arch::asm,
+    ffi::c_void,
+    mem::{size_of, MaybeUninit},
+    panic::PanicInfo,
+};
 
 use linked_list_allocator::LockedHeap;
 use oak_core::sync::OnceCell;
 use oak_dice::evidence::{TeePlatform, DICE_DATA_CMDLINE_PARAM};
-use oak_linux_boot_params::{BootE820Entry, E820EntryType};
+use oak_linux_boot_params::{E820EntryType, XLoadFlags};
 use oak_sev_guest::{io::PortFactoryWrapper, msr::SevStatus};
 use sha2::{Digest, Sha256};
 use x86_64::{
     instructions::{hlt, interrupts::int3, segmentation::Segment},
     registers::segmentation::*,
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        gdt::{Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector},
         idt::InterruptDescriptorTable,
         paging::{PageSize, Size1GiB},
     },
@@ -45,20 +52,34 @@ use zerocopy::AsBytes;
 use crate::{kernel::KernelType, sev::GHCB_WRAPPER, smp::AP_JUMP_TABLE};
 
 mod acpi;
+mod acpi_fallback;
 mod acpi_tables;
 mod allocator;
 mod apic;
+mod bzimage;
 mod cmos;
+mod config;
 mod dice_attestation;
+mod exceptions;
 mod fw_cfg;
+mod hotplug;
 mod initramfs;
 mod kernel;
 mod logging;
+mod measurement;
 mod msr;
 pub mod paging;
+mod parallel_validation;
 mod pic;
+mod pvh;
 mod sev;
 mod smp;
+#[cfg(test)]
+mod testing;
+mod tpm;
+mod vc;
+mod verified_boot;
+mod virtio_console;
 mod zero_page;
 
 type Measurement = [u8; 32];
@@ -89,34 +110,57 @@ extern "C" {
     static BOOT_STACK_POINTER: c_void;
 }
 
-pub fn create_gdt(gdt: &mut GlobalDescriptorTable) -> (SegmentSelector, SegmentSelector) {
+/// Size of the boot stack, as defined in the linker script.
+const BOOT_STACK_SIZE: u64 = 32 * 1024;
+
+/// Creates the GDT entries stage0 needs.
+///
+/// Returns the selectors for the 64-bit code segment, the data segment and a
+/// flat 32-bit code segment. The latter is only used to drop back to protected
+/// mode when booting a kernel via the PVH entry point.
+pub fn create_gdt(
+    gdt: &mut GlobalDescriptorTable,
+) -> (SegmentSelector, SegmentSelector, SegmentSelector) {
     let cs = gdt.add_entry(Descriptor::kernel_code_segment());
     let ds = gdt.add_entry(Descriptor::kernel_data_segment());
-    (cs, ds)
+    let cs32 = gdt.add_entry(Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE32.bits()));
+    (cs, ds, cs32)
 }
 
-pub fn create_idt(_idt: &mut InterruptDescriptorTable) {}
+pub fn create_idt(idt: &mut InterruptDescriptorTable) {
+    exceptions::init_idt(idt);
+}
 
 /// Passes control to the operating system kernel. No more code from the BIOS
 /// will run.
 ///
+/// If `scrub_stack` is set, the boot stack is zeroed after switching back to
+/// its top, so that the kernel doesn't inherit anything stage0 left on it.
+///
 /// # Safety
 ///
 /// This assumes that the kernel entry point is valid.
 pub unsafe fn jump_to_kernel<A: core::alloc::Allocator>(
     entry_point: VirtAddr,
     zero_page: Box<zero_page::ZeroPage, &A>,
+    scrub_stack: bool,
 ) -> ! {
     asm!(
         // Boot stack pointer
-        "mov {1}, %rsp",
-        // Zero page address
-        "mov {2}, %rsi",
-        // ...and away we go!
-        "jmp *{0}",
-        in(reg) entry_point.as_u64(),
-        in(reg) &BOOT_STACK_POINTER as *const _ as u64,
-        in(reg) Box::leak(zero_page),
+        "mov %r9, %rsp",
+        // Zero the stack, if requested: RCX holds the number of bytes to clear
+        // below the stack pointer.
+        "mov %rsp, %rdi",
+        "sub %rcx, %rdi",
+        "shr $3, %rcx",
+        "xor %eax, %eax",
+        "rep stosq",
+        // ...and away we go! The zero page address is already in RSI.
+        "jmp *%r8",
+        in("r8") entry_point.as_u64(),
+        in("r9") &BOOT_STACK_POINTER as *const _ as u64,
+        in("rsi") Box::leak(zero_page),
+        in("rcx") if scrub_stack { BOOT_STACK_SIZE } else { 0 },
         options(noreturn, att_syntax)
     );
 }
@@ -176,12 +220,63 @@ pub fn rust64_start(encrypted: u64) -> ! {
     // IO ports.
     let mut fwcfg = unsafe { fw_cfg::FwCfg::new(&BOOT_ALLOC) }.expect("fw_cfg device not found!");
 
+    let config = config::load(&mut fwcfg);
+    logging::configure(&config);
+
+    if let Some(address) = virtio_console::find_console_address(&mut fwcfg) {
+        match logging::init_virtio_console(address) {
+            Ok(()) => log::info!("Logging to virtio console at {:#018x}", address.as_u64()),
+            Err(err) => log::warn!("Failed to initialize virtio console: {}", err),
+        }
+    }
+
     let mut zero_page = Box::new_in(zero_page::ZeroPage::new(), &BOOT_ALLOC);
 
     zero_page.fill_e820_table(&mut fwcfg);
 
     if sev_status().contains(SevStatus::SNP_ACTIVE) {
-        sev::validate_memory(zero_page.e820_table(), encrypted);
+        // If configured, we only validate the boot memory and leave hotpluggable RAM
+        // for the kernel to validate.
+        let (boot_memory, deferred) = if config.defer_hotplug_validation {
+            hotplug::defer_hotpluggable_memory(&mut fwcfg, zero_page.e820_table(), &BOOT_ALLOC)
+        } else {
+            hotplug::split_boot_memory(zero_page.e820_table(), &[], &BOOT_ALLOC)
+        };
+
+        // Make sure all RAM is assigned to the guest before we validate it. With
+        // the GHCB this only takes a handful of requests, even for large VMs.
+        sev::make_ram_private(&boot_memory).expect("couldn't assign RAM to the guest");
+
+        let nb_cpus = fwcfg.read_nb_cpus().unwrap_or(1);
+        if let Err(err) = parallel_validation::validate_memory(
+            &boot_memory,
+            encrypted,
+            nb_cpus,
+            config.validation_progress,
+        ) {
+            log::info!("Not validating memory in parallel: {}", err);
+            sev::validate_memory(&boot_memory, encrypted, config.validation_progress);
+        }
+
+        if !deferred.is_empty() {
+            for entry in &deferred {
+                log::info!(
+                    "Deferring validation of [{:#018x}..{:#018x}) to the kernel",
+                    entry.addr(),
+                    entry.addr() + entry.size()
+                );
+            }
+            let deferred = Box::leak(deferred.into_boxed_slice());
+            zero_page.reserve(&deferred[..]).expect("couldn't reserve unvalidated memory ranges");
+            let setup_data = Box::leak(Box::new_in(
+                oak_linux_boot_params::UnvalidatedMemorySetupData::new(
+                    deferred.as_ptr() as u64,
+                    deferred.len() as u32,
+                ),
+                &BOOT_ALLOC,
+            ));
+            zero_page.add_setup_data(&mut setup_data.header);
+        }
     }
 
     /* Set up the machine according to the 64-bit Linux boot protocol.
@@ -190,7 +285,7 @@ pub fn rust64_start(encrypted: u64) -> ! {
 
     let gdt = Box::leak(Box::new_in(GlobalDescriptorTable::new(), &BOOT_ALLOC));
 
-    let (cs, ds) = create_gdt(gdt);
+    let (cs, ds, cs32) = create_gdt(gdt);
     gdt.load();
     // Safety: we've set up the valid data structures in create_gdt, above.
     unsafe {
@@ -208,6 +303,7 @@ pub fn rust64_start(encrypted: u64) -> ! {
     idt.load();
 
     paging::map_additional_memory(encrypted);
+    paging::map_all_memory(zero_page.e820_table(), encrypted);
 
     // Initialize the short-term heap. Any allocations that rely on a global
     // allocator before this point will fail.
@@ -229,17 +325,39 @@ pub fn rust64_start(encrypted: u64) -> ! {
             ),
             &BOOT_ALLOC,
         ));
+        // The CC blob and the pages it points to are used by the kernel, so they must
+        // not be treated as usable RAM. As above, we only need the pointer value of
+        // SEV_SECRETS.
+        zero_page.reserve(cc_blob).expect("failed to reserve the CC blob");
+        zero_page
+            .reserve_region(
+                unsafe { SEV_SECRETS.as_ptr() } as usize,
+                size_of::<oak_sev_guest::secrets::SecretsPage>(),
+                E820EntryType::RESERVED,
+            )
+            .expect("failed to reserve the SEV secrets page");
+        zero_page
+            .reserve_region(
+                SEV_CPUID.as_ptr() as usize,
+                size_of::<oak_sev_guest::cpuid::CpuidPage>(),
+                E820EntryType::RESERVED,
+            )
+            .expect("failed to reserve the SEV CPUID page");
         let setup_data =
             Box::leak(Box::new_in(oak_linux_boot_params::CCSetupData::new(cc_blob), &BOOT_ALLOC));
 
-        zero_page.add_setup_data(setup_data);
+        zero_page.add_setup_data(&mut setup_data.header);
     }
 
     let cmdline = kernel::try_load_cmdline(&mut fwcfg).unwrap_or_default();
     let cmdline_sha2_256_digest = measure_byte_slice(cmdline.as_bytes());
+    let cmdline_sha2_384_digest = measurement::sha2_384(cmdline.as_bytes());
 
     let kernel_info =
         kernel::try_load_kernel_image(&mut fwcfg, zero_page.e820_table()).unwrap_or_default();
+    if let Some(setup_header) = kernel_info.setup_header.as_ref() {
+        zero_page.set_setup_header(setup_header);
+    }
     let mut entry = kernel_info.entry;
 
     // Attempt to parse 64 bytes at the suggested entry point as an ELF header. If
@@ -260,17 +378,34 @@ pub fn rust64_start(encrypted: u64) -> ! {
         entry = VirtAddr::new(header.e_entry);
     }
 
-    let mut acpi_digest = Sha256::default();
-    let rsdp = acpi::build_acpi_tables(&mut fwcfg, &mut acpi_digest).unwrap();
+    let mut acpi_digest = measurement::DualDigest::default();
+    let rsdp = if acpi::has_table_loader(&mut fwcfg) {
+        acpi::build_acpi_tables(&mut fwcfg, &mut acpi_digest).unwrap()
+    } else {
+        log::info!("No ACPI table-loader found in fw_cfg, generating minimal ACPI tables");
+        let nb_cpus = fwcfg.read_nb_cpus().unwrap_or_else(|_| acpi_fallback::cpu_count());
+        acpi::build_fallback_acpi_tables(nb_cpus, &mut acpi_digest).unwrap()
+    };
     zero_page.set_acpi_rsdp_addr(PhysAddr::new(rsdp as *const _ as u64));
-    let acpi_digest = acpi_digest.finalize();
-    let mut acpi_sha2_256_digest = Measurement::default();
-    acpi_sha2_256_digest[..].copy_from_slice(&acpi_digest[..]);
+    let (acpi_sha2_256_digest, acpi_sha2_384_digest) = acpi_digest.finalize();
 
     if let Err(err) = smp::bootstrap_aps(rsdp) {
         log::warn!("Failed to bootstrap APs: {}. APs may not be properly initialized.", err);
     }
 
+    // Under SEV-ES the APs are parked using the AP Reset Hold protocol instead of
+    // the wakeup mailbox, so there is nothing to report.
+    if !sev_status().contains(SevStatus::SEV_ES_ENABLED) {
+        let ap_info = Box::leak(Box::new_in(
+            oak_linux_boot_params::ApInfoSetupData::new(
+                smp::live_ap_count(),
+                smp::mailbox_address().as_u64(),
+            ),
+            &BOOT_ALLOC,
+        ));
+        zero_page.add_setup_data(&mut ap_info.header);
+    }
+
     // Register the AP Jump Table, if required.
     if sev_status().contains(SevStatus::SEV_ES_ENABLED) {
         // This assumes identity mapping. Which we have in stage0.
@@ -291,13 +426,56 @@ pub fn rust64_start(encrypted: u64) -> ! {
         }
     }
 
-    let ram_disk_sha2_256_digest =
-        initramfs::try_load_initial_ram_disk(&mut fwcfg, zero_page.e820_table(), &kernel_info)
-            .map(|ram_disk| {
-                zero_page.set_initial_ram_disk(ram_disk);
-                measure_byte_slice(ram_disk)
-            })
-            .unwrap_or_default();
+    let ram_disk = initramfs::try_load_initial_ram_disk(
+        &mut fwcfg,
+        zero_page.e820_table(),
+        &kernel_info,
+        zero_page.initrd_addr_max(),
+    );
+    if let Some(ram_disk) = ram_disk {
+        zero_page.set_initial_ram_disk(ram_disk);
+    }
+    let ram_disk_sha2_256_digest = ram_disk.map(measure_byte_slice).unwrap_or_default();
+    let ram_disk_sha2_384_digest = ram_disk.map(measurement::sha2_384);
+
+    // If verified boot is enabled, refuse to boot anything that wasn't signed by
+    // the platform owner.
+    let verifying_key =
+        verified_boot::verifying_key(&mut fwcfg).expect("invalid verified boot key");
+    if let Some(key) = verifying_key {
+        let components = verified_boot::SignedComponents {
+            kernel: kernel_info.sha2_384_digest,
+            cmdline: cmdline_sha2_384_digest,
+            initial_ram_disk: ram_disk_sha2_384_digest,
+        };
+        verified_boot::verify(&mut fwcfg, &key, &components)
+            .expect("verified boot failed, refusing to boot");
+        log::info!("Verified boot signature is valid");
+    }
+
+    // Extend the PCRs of the vTPM, if the VMM provides one. The event log area is
+    // referenced from the TPM2 ACPI table, so it needs to be reserved even if we
+    // fail to talk to the TPM.
+    let tpm_event_log_area = tpm::event_log_area();
+    if let Some(area) = tpm_event_log_area.as_ref() {
+        zero_page.reserve(&area[..]).expect("failed to reserve the TPM event log area");
+    }
+    if let Some(mut tpm) = tpm::Tpm::probe() {
+        let tpm_measurements = tpm::BootMeasurements {
+            firmware: tpm::firmware_digests(),
+            kernel: tpm::Digests {
+                sha2_256: kernel_info.measurement,
+                sha2_384: kernel_info.sha2_384_digest,
+            },
+            cmdline: &cmdline,
+            initial_ram_disk: ram_disk_sha2_384_digest
+                .map(|sha2_384| tpm::Digests { sha2_256: ram_disk_sha2_256_digest, sha2_384 }),
+        };
+        match tpm.measure_boot(&tpm_measurements, tpm_event_log_area) {
+            Ok(()) => log::info!("Extended TPM PCRs with the boot measurements"),
+            Err(err) => log::warn!("Failed to extend TPM PCRs: {}", err),
+        }
+    }
 
     let memory_map_sha2_256_digest = measure_byte_slice(zero_page.e820_table().as_bytes());
 
@@ -318,6 +496,54 @@ pub fn rust64_start(encrypted: u64) -> ! {
         memory_map_sha2_256_digest,
     };
 
+    // Record the SHA2-384 digests in the measured boot event log. The log has to
+    // outlive stage0, so we place it in reserved memory and tell the kernel where
+    // to find it via setup data.
+    let event_log = Box::leak(Box::new_in(measurement::EventLog::default(), &BOOT_ALLOC));
+    [
+        (measurement::EventType::Kernel, Some(kernel_info.sha2_384_digest)),
+        (measurement::EventType::Cmdline, Some(cmdline_sha2_384_digest)),
+        (measurement::EventType::AcpiTables, Some(acpi_sha2_384_digest)),
+        (measurement::EventType::InitialRamDisk, ram_disk_sha2_384_digest),
+    ]
+    .into_iter()
+    .filter_map(|(event_type, digest)| digest.map(|digest| (event_type, digest)))
+    .for_each(|(event_type, digest)| {
+        event_log.record(event_type, digest).expect("failed to record measurement")
+    });
+    zero_page.reserve(event_log.as_bytes()).expect("failed to reserve the event log");
+    let event_log_setup_data = Box::leak(Box::new_in(
+        oak_linux_boot_params::EventLogSetupData::new(
+            event_log.as_bytes().as_ptr() as u64,
+            event_log.as_bytes().len() as u32,
+        ),
+        &BOOT_ALLOC,
+    ));
+    zero_page.add_setup_data(&mut event_log_setup_data.header);
+
+    // Under SEV-SNP, request an attestation report that binds the event log, so
+    // that later stages have a baseline report without needing a guest message
+    // driver of their own.
+    if sev_status().contains(SevStatus::SNP_ACTIVE) {
+        match dice_attestation::get_event_log_attestation(event_log.as_bytes()) {
+            Ok(report) => {
+                let report = Box::leak(Box::new_in(report, &BOOT_ALLOC));
+                zero_page
+                    .reserve(report.as_bytes())
+                    .expect("failed to reserve the attestation report");
+                let report_setup_data = Box::leak(Box::new_in(
+                    oak_linux_boot_params::AttestationReportSetupData::new(
+                        report.as_bytes().as_ptr() as u64,
+                        report.as_bytes().len() as u32,
+                    ),
+                    &BOOT_ALLOC,
+                ));
+                zero_page.add_setup_data(&mut report_setup_data.header);
+            }
+            Err(err) => log::warn!("Failed to request early attestation report: {}", err),
+        }
+    }
+
     let tee_platform = if sev_status().contains(SevStatus::SNP_ACTIVE) {
         TeePlatform::AmdSevSnp
     } else {
@@ -334,11 +560,18 @@ pub fn rust64_start(encrypted: u64) -> ! {
         &crate::BOOT_ALLOC,
     ));
     // Reserve the memory containing the DICE data.
-    zero_page.insert_e820_entry(BootE820Entry::new(
-        dice_data.as_bytes().as_ptr() as usize,
-        dice_data.as_bytes().len(),
-        E820EntryType::RESERVED,
+    zero_page.reserve(dice_data.as_bytes()).expect("failed to reserve the DICE data");
+    // Tell the kernel where to find the DICE data. This works for all kernel types;
+    // the command-line parameter below is kept for kernels that don't look at the
+    // setup data.
+    let dice_setup_data = Box::leak(Box::new_in(
+        oak_linux_boot_params::DiceDataSetupData::new(
+            dice_data.as_bytes().as_ptr() as u64,
+            dice_data.as_bytes().len() as u32,
+        ),
+        &BOOT_ALLOC,
     ));
+    zero_page.add_setup_data(&mut dice_setup_data.header);
 
     // Append the DICE data address to the kernel command-line.
     let extra = format!("--{DICE_DATA_CMDLINE_PARAM}={dice_data:p}");
@@ -355,19 +588,77 @@ pub fn rust64_start(encrypted: u64) -> ! {
     };
     zero_page.set_cmdline(cmdline);
 
-    log::info!("jumping to kernel at {:#018x}", entry.as_u64());
+    // Kernels that advertise a PVH entry point are booted via PVH, unless SEV-ES
+    // or SEV-SNP is active: the PVH entry code can't handle #VC exceptions, and
+    // the SEV-SNP CC blob is only passed via the zero page.
+    let pvh = kernel_info
+        .pvh_entry
+        .filter(|_| !sev_status().contains(SevStatus::SEV_ES_ENABLED))
+        .map(|pvh_entry| (pvh_entry, pvh::create_start_info(&zero_page)));
+
+    // Hand over with 5-level paging enabled if both the CPU and the kernel support
+    // it. The PVH entry point runs with paging disabled, so it doesn't apply there.
+    // We can't switch under memory encryption, see `paging::enable_la57`.
+    let la57 = pvh.is_none()
+        && encrypted == 0
+        && zero_page.x_load_flags().contains(XLoadFlags::XLF_5LEVEL)
+        && paging::supports_la57();
+
+    if let Some((pvh_entry, _)) = pvh {
+        log::info!("jumping to kernel PVH entry point at {:#018x}", pvh_entry.as_u64());
+    } else {
+        log::info!(
+            "jumping to kernel at {:#018x} with {}-level paging",
+            entry.as_u64(),
+            if la57 { 5 } else { 4 }
+        );
+    }
 
     // Clean-ups we need to do just before we jump to the kernel proper: clean up
-    // the early GHCB and FW_CFG DMA buffers we used, and switch back to a
-    // hugepage for the first 2M of memory.
+    // the early GHCB, FW_CFG DMA and virtio console buffers we used, and switch
+    // back to a hugepage for the first 2M of memory.
     drop(fwcfg);
+    logging::deinit_virtio_console();
+    let harden = config.harden_handoff && sev_status().contains(SevStatus::SNP_ACTIVE);
+    if harden {
+        // This has to happen while we can still log, i.e. before the GHCB is gone.
+        match sev::unshare_remaining_pages(zero_page.e820_table()) {
+            Ok(summary) => log::info!(
+                "Handoff hardening: unshared {} leftover pages, {} pages verified as private",
+                summary.unshared,
+                summary.checked
+            ),
+            Err(err) => panic!("handoff hardening failed: {}", err),
+        }
+    }
     if sev_status().contains(SevStatus::SNP_ACTIVE) && GHCB_WRAPPER.get().is_some() {
         sev::deinit_ghcb();
     }
     paging::remap_first_huge_page(encrypted);
+    if harden {
+        // Safety: nothing from the short-term heap is used from here on.
+        unsafe {
+            allocator::scrub_global_allocator();
+        }
+    }
+    if la57 {
+        // Safety: cs and cs32 are the code segments from create_gdt, stage0 is
+        // identity-mapped below 4GiB and memory encryption is not active.
+        unsafe {
+            paging::enable_la57(cs, cs32);
+        }
+    }
+
+    if let Some((pvh_entry, start_info)) = pvh {
+        // Safety: the entry point was advertised by the kernel, cs32 is the 32-bit
+        // code segment from create_gdt and stage0 is identity-mapped.
+        unsafe {
+            pvh::jump_to_pvh_entry(pvh_entry, start_info, cs32);
+        }
+    }
 
     unsafe {
-        jump_to_kernel(entry, zero_page);
+        jump_to_kernel(entry, zero_page, harden);
     }
 }
 
@@ -376,8 +667,8 @@ pub fn rust64_start(encrypted: u64) -> ! {
 pub fn panic(info: &PanicInfo) -> ! {
     log::error!("{}", info);
 
-    // Trigger a breakpoint exception. As we don't have a #BP handler, this will
-    // triple fault and terminate the program.
+    // Trigger a breakpoint exception. The #BP handler (or, if the IDT isn't set up
+    // yet, the lack of one) will triple fault and terminate the program.
     int3();
 
     loop {
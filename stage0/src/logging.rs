@@ -18,15 +18,19 @@ use core::{fmt::Write, ops::DerefMut};
 
 use sev_serial::SerialPort;
 use spinning_top::Spinlock;
+use x86_64::PhysAddr;
 
-use crate::io_port_factory;
+use crate::{
+    config::{Config, Console},
+    io_port_factory,
+    virtio_console::VirtioConsole,
+};
 
 extern crate log;
 
-// Base I/O port for the first serial port in the system (colloquially known as
-// COM1)
-static SERIAL_BASE: u16 = 0x3f8;
 static SERIAL_PORT: Spinlock<Option<SerialPort>> = Spinlock::new(None);
+// Optional virtio console that receives a copy of all log messages.
+static VIRTIO_CONSOLE: Spinlock<Option<VirtioConsole>> = Spinlock::new(None);
 
 struct Logger {}
 
@@ -41,6 +45,11 @@ impl log::Log for Logger {
         if let Some(port) = lock.deref_mut() {
             writeln!(port, "stage0 {}: {}", record.level(), record.args()).unwrap();
         }
+        drop(lock);
+        if let Some(console) = VIRTIO_CONSOLE.lock().deref_mut() {
+            // Ignore errors, as there's no other way to report them.
+            let _ = writeln!(console, "stage0 {}: {}", record.level(), record.args());
+        }
     }
 
     fn flush(&self) {
@@ -51,14 +60,42 @@ impl log::Log for Logger {
 static LOGGER: Logger = Logger {};
 
 pub fn init_logging() {
-    // Our contract with the launcher requires the first serial port to be
-    // available, so assuming the loader adheres to it, this is safe.
-    let mut port = unsafe { SerialPort::new(SERIAL_BASE, io_port_factory()) };
-    port.init().expect("couldn't initialize logging serial port");
-    {
-        let mut lock = SERIAL_PORT.lock();
-        *lock.deref_mut() = Some(port);
-    }
+    let config = Config::default();
+    set_console(config.console);
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Debug);
+    log::set_max_level(config.log_level);
+}
+
+/// Applies the logging configuration read from fw_cfg.
+///
+/// Messages logged before this is called go to the default console.
+pub fn configure(config: &Config) {
+    set_console(config.console);
+    log::set_max_level(config.log_level);
+}
+
+fn set_console(console: Console) {
+    let port = console.io_base().map(|base| {
+        // Our contract with the launcher requires the first serial port to be
+        // available; any other port has been explicitly requested in the
+        // configuration, so assuming the VMM provides it, this is safe.
+        let mut port = unsafe { SerialPort::new(base, io_port_factory()) };
+        port.init().expect("couldn't initialize logging serial port");
+        port
+    });
+    *SERIAL_PORT.lock().deref_mut() = port;
+}
+
+/// Initializes the virtio console at the given virtio-mmio address and sends
+/// all further log messages to it in addition to the serial port.
+pub fn init_virtio_console(address: PhysAddr) -> Result<(), &'static str> {
+    let console = VirtioConsole::new(address)?;
+    *VIRTIO_CONSOLE.lock() = Some(console);
+    Ok(())
+}
+
+/// Stops logging to the virtio console and resets the device, so that the
+/// memory we shared with the hypervisor can be reclaimed.
+pub fn deinit_virtio_console() {
+    VIRTIO_CONSOLE.lock().take();
 }
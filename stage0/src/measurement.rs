@@ -0,0 +1,183 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Measured boot support.
+//!
+//! Stage0 records a SHA2-384 digest of every component it hands over to the
+//! next stage in an event log. The event log is placed in reserved guest
+//! memory and its location is passed to the kernel via a setup_data entry, so
+//! that the kernel can include the measurements in its attestation evidence.
+
+use sha2::{digest::Update, Digest, Sha256, Sha384};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// Size of a SHA2-384 digest, in bytes.
+pub const SHA2_384_DIGEST_SIZE: usize = 48;
+
+/// A SHA2-384 digest.
+pub type Sha384Digest = [u8; SHA2_384_DIGEST_SIZE];
+
+/// Magic value ("OAKL") at the start of the event log.
+pub const EVENT_LOG_MAGIC: u32 = 0x4C4B414F;
+
+/// Version of the event log layout.
+pub const EVENT_LOG_VERSION: u32 = 1;
+
+/// Maximum number of events that fit into the event log.
+pub const MAX_EVENTS: usize = 8;
+
+/// The components that stage0 measures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum EventType {
+    /// The raw kernel image, as it was read from the fw_cfg device.
+    Kernel = 1,
+    /// The kernel command-line, as provided by the VMM.
+    Cmdline = 2,
+    /// The initial RAM disk.
+    InitialRamDisk = 3,
+    /// The ACPI table-loader commands and the files they reference.
+    AcpiTables = 4,
+}
+
+/// A single entry in the event log.
+#[derive(Clone, Copy, Debug, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct Event {
+    /// The measured component, one of the values of `EventType`.
+    pub event_type: u32,
+    /// Reserved, must be zero.
+    pub reserved: u32,
+    /// The SHA2-384 digest of the component.
+    pub sha2_384_digest: Sha384Digest,
+}
+
+/// The measured boot event log.
+#[derive(AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct EventLog {
+    /// Must be `EVENT_LOG_MAGIC`.
+    pub magic: u32,
+    /// Must be `EVENT_LOG_VERSION`.
+    pub version: u32,
+    /// Number of valid entries in `events`.
+    pub count: u32,
+    /// Reserved, must be zero.
+    pub reserved: u32,
+    /// The recorded events; only the first `count` entries are valid.
+    pub events: [Event; MAX_EVENTS],
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        let mut log = Self::new_zeroed();
+        log.magic = EVENT_LOG_MAGIC;
+        log.version = EVENT_LOG_VERSION;
+        log
+    }
+}
+
+impl EventLog {
+    /// Appends a measurement to the event log.
+    pub fn record(
+        &mut self,
+        event_type: EventType,
+        sha2_384_digest: Sha384Digest,
+    ) -> Result<(), &'static str> {
+        let event = self.events.get_mut(self.count as usize).ok_or("event log is full")?;
+        *event = Event { event_type: event_type as u32, reserved: 0, sha2_384_digest };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the events that have been recorded so far.
+    pub fn events(&self) -> &[Event] {
+        &self.events[..self.count as usize]
+    }
+}
+
+/// Calculates the SHA2-384 digest of `source`.
+pub fn sha2_384(source: &[u8]) -> Sha384Digest {
+    let mut measurement = [0; SHA2_384_DIGEST_SIZE];
+    measurement[..].copy_from_slice(&Sha384::digest(source)[..]);
+    measurement
+}
+
+/// Calculates both the SHA2-256 and the SHA2-384 digest of data that is fed in
+/// piecemeal, such as the ACPI tables.
+#[derive(Default)]
+pub struct DualDigest {
+    sha2_256: Sha256,
+    sha2_384: Sha384,
+}
+
+impl Update for DualDigest {
+    fn update(&mut self, data: &[u8]) {
+        Update::update(&mut self.sha2_256, data);
+        Update::update(&mut self.sha2_384, data);
+    }
+}
+
+impl DualDigest {
+    pub fn finalize(self) -> (crate::Measurement, Sha384Digest) {
+        let mut sha2_256_digest = crate::Measurement::default();
+        sha2_256_digest[..].copy_from_slice(&self.sha2_256.finalize()[..]);
+        let mut sha2_384_digest = [0; SHA2_384_DIGEST_SIZE];
+        sha2_384_digest[..].copy_from_slice(&self.sha2_384.finalize()[..]);
+        (sha2_256_digest, sha2_384_digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_events() {
+        let mut log = EventLog::default();
+
+        log.record(EventType::Kernel, sha2_384(b"kernel")).unwrap();
+        log.record(EventType::Cmdline, sha2_384(b"")).unwrap();
+
+        assert_eq!(log.magic, EVENT_LOG_MAGIC);
+        assert_eq!(log.events().len(), 2);
+        assert_eq!(log.events()[0].event_type, EventType::Kernel as u32);
+        assert_eq!(log.events()[1].sha2_384_digest, sha2_384(b""));
+    }
+
+    #[test]
+    fn record_too_many_events() {
+        let mut log = EventLog::default();
+
+        for _ in 0..MAX_EVENTS {
+            log.record(EventType::AcpiTables, sha2_384(b"acpi")).unwrap();
+        }
+
+        assert!(log.record(EventType::AcpiTables, sha2_384(b"acpi")).is_err());
+    }
+
+    #[test]
+    fn dual_digest_matches_single_digests() {
+        let mut digest = DualDigest::default();
+        Update::update(&mut digest, b"hello ");
+        Update::update(&mut digest, b"world");
+
+        let (sha2_256_digest, sha2_384_digest) = digest.finalize();
+
+        assert_eq!(sha2_256_digest, crate::measure_byte_slice(b"hello world"));
+        assert_eq!(sha2_384_digest, sha2_384(b"hello world"));
+    }
+}
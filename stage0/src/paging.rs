@@ -15,16 +15,23 @@
 //
 
 use alloc::boxed::Box;
+use core::arch::{asm, x86_64::__cpuid_count};
 
 use oak_core::sync::OnceCell;
+use oak_linux_boot_params::{BootE820Entry, E820EntryType};
+use oak_sev_guest::cpuid::{CpuidInput, CpuidOutput};
 use spinning_top::Spinlock;
 use x86_64::{
     instructions::tlb::flush_all,
-    structures::paging::{page_table::PageTableFlags, PageSize, PageTable, Size2MiB, Size4KiB},
+    registers::segmentation::SegmentSelector,
+    structures::paging::{
+        page_table::PageTableFlags, PageSize, PageTable, Size1GiB, Size2MiB, Size4KiB,
+    },
     PhysAddr,
 };
+use zerocopy::FromZeroes;
 
-use crate::BOOT_ALLOC;
+use crate::{sev::GHCB_WRAPPER, BOOT_ALLOC};
 
 pub static mut PML4: PageTable = PageTable::new();
 pub static mut PDPT: PageTable = PageTable::new();
@@ -108,6 +115,132 @@ pub fn map_additional_memory(encrypted: u64) {
     flush_all();
 }
 
+/// Identity-maps all RAM above the first 1GiB listed in the E820 table, so that
+/// the kernel can touch it before it has set up its own page tables.
+///
+/// A 1GiB range that consists entirely of RAM is mapped using a single 1GiB
+/// page, if the CPU supports them. Otherwise, we use 2MiB pages and only map
+/// the parts of the range that contain RAM, so that we don't map the MMIO hole
+/// below 4GiB. We only map the first 512GiB, which is covered by `PDPT`.
+pub fn map_all_memory(e820_table: &[BootE820Entry], encrypted: u64) {
+    let gigabyte_pages = supports_1gib_pages();
+
+    {
+        let mut page_tables = PAGE_TABLE_REFS.get().expect("page tables not initiallized").lock();
+        let page_tables = &mut *page_tables;
+        map_ram(page_tables.pdpt, page_tables.pd_3, e820_table, encrypted, gigabyte_pages);
+    }
+
+    flush_all();
+}
+
+/// Fills in the page tables for `map_all_memory`; this doesn't touch the
+/// hardware, so that it can be tested on its own.
+fn map_ram(
+    pdpt: &mut PageTable,
+    pd_3: &mut PageTable,
+    e820_table: &[BootE820Entry],
+    encrypted: u64,
+    gigabyte_pages: bool,
+) {
+    let top_of_memory = e820_table
+        .iter()
+        .filter(|entry| entry.entry_type() == Some(E820EntryType::RAM))
+        .map(|entry| (entry.addr() + entry.size()) as u64)
+        .max()
+        .unwrap_or(0);
+    let gigabytes = top_of_memory.div_ceil(Size1GiB::SIZE).min(512) as usize;
+    // The first gigabyte has already been mapped by `map_additional_memory`.
+    for i in 1..gigabytes {
+        let start = (i as u64) * Size1GiB::SIZE;
+        if !contains_ram(e820_table, start, Size1GiB::SIZE, false) {
+            continue;
+        }
+
+        let pd = if i == 3 {
+            // The 3..4GiB range is already covered by `PD_3`, which maps the
+            // stage0 ROM image in its last entry.
+            &mut *pd_3
+        } else if pdpt[i].flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        } else if gigabyte_pages && contains_ram(e820_table, start, Size1GiB::SIZE, true) {
+            pdpt[i].set_addr(
+                PhysAddr::new(start | encrypted),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE,
+            );
+            continue;
+        } else {
+            let pd = Box::leak(Box::new_in(PageTable::new(), &BOOT_ALLOC));
+            pdpt[i].set_addr(
+                PhysAddr::new(pd as *const _ as u64 | encrypted),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+            pd
+        };
+
+        pd.iter_mut().enumerate().for_each(|(j, entry)| {
+            let address = start + (j as u64) * Size2MiB::SIZE;
+            if entry.flags().contains(PageTableFlags::PRESENT)
+                || !contains_ram(e820_table, address, Size2MiB::SIZE, false)
+            {
+                return;
+            }
+            entry.set_addr(
+                PhysAddr::new(address | encrypted),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE,
+            );
+        });
+    }
+}
+
+/// Checks whether the range `start..start + size` contains RAM.
+///
+/// If `fully` is set, the whole range must be covered by a single RAM entry in
+/// the E820 table; otherwise it is enough if any part of the range overlaps
+/// with RAM.
+fn contains_ram(e820_table: &[BootE820Entry], start: u64, size: u64, fully: bool) -> bool {
+    let end = start + size;
+    e820_table.iter().filter(|entry| entry.entry_type() == Some(E820EntryType::RAM)).any(|entry| {
+        let entry_start = entry.addr() as u64;
+        let entry_end = entry_start + entry.size() as u64;
+        if fully {
+            entry_start <= start && end <= entry_end
+        } else {
+            entry_start < end && start < entry_end
+        }
+    })
+}
+
+/// Checks whether the CPU supports 1GiB pages (CPUID Fn8000_0001 EDX bit 26).
+fn supports_1gib_pages() -> bool {
+    cpuid(0x8000_0001, 0).edx & (1 << 26) > 0
+}
+
+/// Checks whether the CPU supports 5-level paging (CPUID Fn0000_0007_x0 ECX bit
+/// 16).
+pub fn supports_la57() -> bool {
+    cpuid(0x0000_0007, 0).ecx & (1 << 16) > 0
+}
+
+/// Executes CPUID, via the GHCB if it has been set up.
+///
+/// Returns all zeros if the GHCB request fails.
+fn cpuid(leaf: u32, subleaf: u32) -> CpuidOutput {
+    if let Some(ghcb) = GHCB_WRAPPER.get() {
+        match ghcb.lock().get_cpuid(CpuidInput { eax: leaf, ecx: subleaf, xcr0: 0, xss: 0 }) {
+            Ok(result) => result,
+            Err(err) => {
+                log::warn!("Failed to read CPUID via the GHCB: {}", err);
+                CpuidOutput::new_zeroed()
+            }
+        }
+    } else {
+        // Safety: the CPUs we support are new enough to support CPUID.
+        let result = unsafe { __cpuid_count(leaf, subleaf) };
+        CpuidOutput { eax: result.eax, ebx: result.ebx, ecx: result.ecx, edx: result.edx }
+    }
+}
+
 // Remaps the first 2MiB of memory, which was previously mapped as 512 4KiB
 // pages, as a single 2MiB huge page again.
 pub fn remap_first_huge_page(encrypted: u64) {
@@ -126,3 +259,142 @@ pub fn remap_first_huge_page(encrypted: u64) {
 
     flush_all();
 }
+
+/// Switches the CPU to 5-level paging, using a new PML5 table whose first
+/// entry points to `PML4`, so that the existing identity mapping stays intact.
+///
+/// CR4.LA57 can't be changed while long mode is active, so we briefly drop to
+/// compatibility mode, disable paging, enable LA57 and load the PML5 table,
+/// and then re-enable paging and return to 64-bit mode.
+///
+/// # Safety
+///
+/// This assumes that `cs` and `cs32` refer to the flat 64-bit and 32-bit code
+/// segments, that the code, the stack and the page tables are identity-mapped
+/// below 4GiB, that interrupts are disabled, and that memory encryption is not
+/// active: the PML5 table is loaded from compatibility mode, where we can't set
+/// the encryption bit in CR3.
+pub unsafe fn enable_la57(cs: SegmentSelector, cs32: SegmentSelector) {
+    let pml5 = Box::leak(Box::new_in(PageTable::new(), &BOOT_ALLOC));
+    {
+        let page_tables = PAGE_TABLE_REFS.get().expect("page tables not initiallized").lock();
+        pml5[0].set_addr(
+            PhysAddr::new(&*page_tables.pml4 as *const _ as u64),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+    }
+
+    asm!(
+        // The upper halves of the general-purpose registers are undefined after
+        // leaving 64-bit mode, so save the callee-saved registers on the stack.
+        "push %rbx",
+        "push %rbp",
+        "push %r12",
+        "push %r13",
+        "push %r14",
+        "push %r15",
+        "lea 3f(%rip), %rdx",
+        // Far return into the 32-bit code segment.
+        "push %rax",
+        "lea 2f(%rip), %rax",
+        "push %rax",
+        "lretq",
+        ".code32",
+        "2:",
+        // Disable paging, which deactivates long mode but leaves EFER.LME set.
+        "mov %cr0, %eax",
+        "and $0x7FFFFFFF, %eax",
+        "mov %eax, %cr0",
+        // Enable LA57 and point CR3 to the PML5 table.
+        "mov %cr4, %eax",
+        "or $0x1000, %eax",
+        "mov %eax, %cr4",
+        "mov %edi, %cr3",
+        // Re-enable paging, which re-activates long mode.
+        "mov %cr0, %eax",
+        "or $0x80000000, %eax",
+        "mov %eax, %cr0",
+        // Far return back into the 64-bit code segment.
+        "push %esi",
+        "push %edx",
+        "lret",
+        ".code64",
+        "3:",
+        // The stack is below 4GiB; clear the upper half of the stack pointer.
+        "mov %esp, %esp",
+        "pop %r15",
+        "pop %r14",
+        "pop %r13",
+        "pop %r12",
+        "pop %rbp",
+        "pop %rbx",
+        in("rax") cs32.0 as u64,
+        in("rsi") cs.0 as u64,
+        in("rdi") pml5 as *const _ as u64,
+        clobber_abi("C"),
+        options(att_syntax)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn contains_ram_checks_overlap() {
+        let e820_table = vec![
+            BootE820Entry::new(0, 0x8000_0000, E820EntryType::RAM),
+            BootE820Entry::new(0x8000_0000, 0x8000_0000, E820EntryType::RESERVED),
+            BootE820Entry::new(0x1_0000_0000, 0x1_0000_0000, E820EntryType::RAM),
+        ];
+
+        assert!(contains_ram(&e820_table, Size1GiB::SIZE, Size1GiB::SIZE, true));
+        assert!(!contains_ram(&e820_table, 2 * Size1GiB::SIZE, Size1GiB::SIZE, false));
+        assert!(!contains_ram(&e820_table, 3 * Size1GiB::SIZE, Size1GiB::SIZE, false));
+        assert!(contains_ram(&e820_table, 4 * Size1GiB::SIZE, Size1GiB::SIZE, true));
+        assert!(contains_ram(&e820_table, 0x7FF0_0000, Size2MiB::SIZE, false));
+        assert!(!contains_ram(&e820_table, 0x7FF0_0000, Size2MiB::SIZE, true));
+    }
+
+    #[test]
+    fn map_ram_uses_gigabyte_pages() {
+        let e820_table = vec![
+            BootE820Entry::new(0, 0x8000_0000, E820EntryType::RAM),
+            BootE820Entry::new(0x1_0000_0000, 0x30_0000, E820EntryType::RAM),
+        ];
+        let mut pdpt = PageTable::new();
+        let mut pd_3 = PageTable::new();
+
+        map_ram(&mut pdpt, &mut pd_3, &e820_table, 0, true);
+
+        assert!(pdpt[1].flags().contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE));
+        assert_eq!(pdpt[1].addr(), PhysAddr::new(Size1GiB::SIZE));
+        // There is no RAM in the 2..4GiB range.
+        assert!(pdpt[2].is_unused());
+        assert!(pd_3.iter().all(|entry| entry.is_unused()));
+        // The partially populated gigabyte is mapped using 2MiB pages.
+        assert!(!pdpt[4].flags().contains(PageTableFlags::HUGE_PAGE));
+        // Safety: the PD was allocated by `map_ram` and is identity-mapped.
+        let pd = unsafe { &*(pdpt[4].addr().as_u64() as *const PageTable) };
+        assert_eq!(pd.iter().filter(|entry| !entry.is_unused()).count(), 2);
+        assert_eq!(pd[1].addr(), PhysAddr::new(0x1_0020_0000));
+    }
+
+    #[test]
+    fn map_ram_without_gigabyte_pages() {
+        let e820_table = vec![BootE820Entry::new(0, 0x8000_0000, E820EntryType::RAM)];
+        let mut pdpt = PageTable::new();
+        let mut pd_3 = PageTable::new();
+
+        map_ram(&mut pdpt, &mut pd_3, &e820_table, 0, false);
+
+        assert!(!pdpt[1].flags().contains(PageTableFlags::HUGE_PAGE));
+        // Safety: the PD was allocated by `map_ram` and is identity-mapped.
+        let pd = unsafe { &*(pdpt[1].addr().as_u64() as *const PageTable) };
+        assert!(pd.iter().all(|entry| {
+            entry.flags().contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE)
+        }));
+    }
+}
@@ -0,0 +1,357 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Parallel SEV-SNP memory validation.
+//!
+//! Calling PVALIDATE on all guest memory from the BSP is slow for large VMs.
+//! If the hypervisor supports the SEV-SNP AP Creation protocol, we start a
+//! small number of APs directly in 64-bit mode, using the same page tables as
+//! the BSP, and let them validate memory in parallel with the BSP.
+//!
+//! As the workers share the identity mapping of the BSP, all memory has to be
+//! mapped before the workers are started. This means that this path can't be
+//! combined with `sev::validate_memory`, which uses temporary mappings.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::x86_64::_mm_pause,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
+
+use oak_core::sync::OnceCell;
+use oak_linux_boot_params::{BootE820Entry, E820EntryType};
+use oak_sev_guest::{
+    instructions::{
+        pvalidate, rmpadjust, InstructionError, PageSize as SevPageSize, PermissionMask,
+        RmpPermission, Validation, Vmsa as VmsaFlag,
+    },
+    msr::{ap_reset_hold, get_hypervisor_feature_support, HypervisorFeatureSupportResponse},
+    vmsa::{SegmentRegister, Vmsa, VmsaPage},
+};
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS},
+        tables::sgdt,
+    },
+    registers::{
+        control::{Cr0, Cr3, Cr4},
+        model_specific::EferFlags,
+    },
+    structures::paging::{PageSize, Size2MiB, Size4KiB},
+};
+
+use crate::{
+    sev::{counters, GHCB_WRAPPER},
+    BootAllocator, BOOT_ALLOC,
+};
+
+/// Maximum number of APs that we use for validating memory.
+///
+/// Every worker needs a VMSA page and a stack from the boot allocator, so we
+/// keep this number small.
+const MAX_WORKERS: u32 = 4;
+
+/// Size of the stack for each worker.
+const WORKER_STACK_SIZE: usize = 4096;
+
+/// Size of the pieces of memory that the workers take off the queue.
+const CHUNK_SIZE: u64 = 64 * Size2MiB::SIZE;
+
+/// We already pvalidated the memory in the first 640KiB of RAM in the boot
+/// assembly code.
+const MIN_ADDRESS: u64 = 0xA0000;
+
+/// Memory ranges that still need to be validated.
+struct WorkQueue {
+    chunks: Vec<Range<u64>, &'static BootAllocator>,
+    /// Index of the next chunk to be validated.
+    next: AtomicUsize,
+    /// Number of workers (including the BSP) currently taking chunks off the
+    /// queue.
+    active: AtomicU32,
+    /// Set if any worker failed to validate a chunk.
+    failed: AtomicBool,
+}
+
+static WORK_QUEUE: OnceCell<WorkQueue> = OnceCell::new();
+
+#[repr(C, align(16))]
+struct WorkerStack([u8; WORKER_STACK_SIZE]);
+
+/// Validates all RAM in the E820 table using the BSP and up to `MAX_WORKERS`
+/// APs.
+///
+/// Returns an error, without having touched the page tables, if parallel
+/// validation is not possible; the caller is then expected to fall back to
+/// `sev::validate_memory`.
+pub fn validate_memory(
+    e820_table: &[BootE820Entry],
+    encrypted: u64,
+    nb_cpus: u16,
+    progress: bool,
+) -> Result<(), &'static str> {
+    if nb_cpus < 2 {
+        return Err("only one vCPU available");
+    }
+    if !get_hypervisor_feature_support()?.contains(HypervisorFeatureSupportResponse::AP_CREATION) {
+        return Err("hypervisor does not support AP creation");
+    }
+
+    log::info!("starting parallel SEV-SNP memory validation");
+
+    // The workers need an identity mapping of all the memory they validate.
+    crate::paging::map_additional_memory(encrypted);
+    crate::paging::map_all_memory(e820_table, encrypted);
+
+    let mut chunks = Vec::new_in(&BOOT_ALLOC);
+    for entry in e820_table {
+        if entry.entry_type() != Some(E820EntryType::RAM) {
+            continue;
+        }
+        let mut start = (entry.addr() as u64).max(MIN_ADDRESS);
+        let end = (entry.addr() + entry.size()) as u64;
+        while start < end {
+            let chunk_end = end.min((start / CHUNK_SIZE + 1) * CHUNK_SIZE);
+            chunks.push(start..chunk_end);
+            start = chunk_end;
+        }
+    }
+    if WORK_QUEUE
+        .set(WorkQueue {
+            chunks,
+            next: AtomicUsize::new(0),
+            active: AtomicU32::new(0),
+            failed: AtomicBool::new(false),
+        })
+        .is_err()
+    {
+        panic!("memory validation work queue already initialized");
+    }
+
+    // We assume that the BSP has APIC ID 0 and the APs use consecutive APIC IDs,
+    // which is what QEMU does by default.
+    let mut workers = 0;
+    for apic_id in 1..(nb_cpus as u32).min(MAX_WORKERS + 1) {
+        match start_worker(apic_id) {
+            Ok(()) => workers += 1,
+            Err(err) => log::warn!("failed to start validation worker {}: {}", apic_id, err),
+        }
+    }
+    log::debug!("started {} validation workers", workers);
+
+    // The BSP takes part in the validation as well. Once the queue is drained,
+    // wait for the workers that are still busy with their last chunk.
+    process_queue(progress);
+    let queue = WORK_QUEUE.get().unwrap();
+    while queue.active.load(Ordering::SeqCst) > 0 {
+        // Safety: SSE2 is supported in all 64-bit processors.
+        unsafe { _mm_pause() };
+    }
+    if queue.failed.load(Ordering::SeqCst) {
+        panic!("failed to validate memory");
+    }
+
+    log::info!("SEV-SNP memory validation complete.");
+    log::info!("  Validated using 2 MiB pages: {}", counters::VALIDATED_2M.load(Ordering::SeqCst));
+    log::info!("  Validated using 4 KiB pages: {}", counters::VALIDATED_4K.load(Ordering::SeqCst));
+    log::info!(
+        "  Valid state not updated: {}",
+        counters::ERROR_VALIDATION_STATUS_NOT_UPDATED.load(Ordering::SeqCst)
+    );
+    log::info!(
+        "  RMP page size mismatch errors (fallback to 4K): {}",
+        counters::ERROR_FAIL_SIZE_MISMATCH.load(Ordering::SeqCst)
+    );
+    Ok(())
+}
+
+/// Creates a VMSA for a worker and asks the hypervisor to start the AP with it.
+fn start_worker(apic_id: u32) -> Result<(), &'static str> {
+    let stack = Box::leak(Box::new_in(WorkerStack([0; WORKER_STACK_SIZE]), &BOOT_ALLOC));
+    // The System V ABI expects the stack to be misaligned by the return address
+    // on function entry.
+    let stack_top = stack.0.as_ptr_range().end as u64 - 8;
+
+    let vmsa = Box::leak(Box::new_in(
+        VmsaPage::new(worker_vmsa(worker_main as usize as u64, stack_top)),
+        &BOOT_ALLOC,
+    ));
+    let vmsa_address = vmsa as *const VmsaPage as usize;
+    // Due to an erratum, a 2MiB-aligned page can't be used as a VMSA.
+    if vmsa_address as u64 % Size2MiB::SIZE == 0 {
+        return Err("VMSA page is 2MiB-aligned");
+    }
+    rmpadjust(
+        vmsa_address,
+        SevPageSize::Page4KiB,
+        RmpPermission::new(1, PermissionMask::empty(), VmsaFlag::Yes),
+    )
+    .map_err(|_| "failed to mark page as VMSA")?;
+
+    GHCB_WRAPPER.get().ok_or("GHCB not initialized")?.lock().create_ap(
+        apic_id,
+        0,
+        x86_64::PhysAddr::new(vmsa_address as u64),
+        crate::sev_status().bits() >> 2,
+    )
+}
+
+/// Builds the VMSA for a worker that shares the long mode environment of the
+/// BSP.
+fn worker_vmsa(rip: u64, rsp: u64) -> Vmsa {
+    let gdt = sgdt();
+    let (pml4, cr3_flags) = Cr3::read_raw();
+    let data_segment = || SegmentRegister {
+        selector: DS::get_reg().0,
+        attributes: 0xc93, // (G|DB|P|S|W|A)
+        limit: 0xffffffff,
+        base: 0,
+    };
+    Vmsa {
+        cs: SegmentRegister {
+            selector: CS::get_reg().0,
+            attributes: 0x29b, // (L|P|S|CS|R|A)
+            limit: 0xffffffff,
+            base: 0,
+        },
+        ds: data_segment(),
+        es: data_segment(),
+        fs: data_segment(),
+        gs: data_segment(),
+        ss: data_segment(),
+        gdtr: SegmentRegister {
+            limit: gdt.limit as u32,
+            base: gdt.base.as_u64(),
+            ..Default::default()
+        },
+        idtr: SegmentRegister { limit: 0xffff, ..Default::default() },
+        ldtr: SegmentRegister {
+            limit: 0xffff,
+            attributes: 0x82, // (P|LDT)
+            ..Default::default()
+        },
+        tr: SegmentRegister {
+            limit: 0xffff,
+            attributes: 0x8b, // (P|"Busy 32-bit TSS")
+            ..Default::default()
+        },
+        dr6: 0xffff0ff0,
+        dr7: 0x0400,
+        cr0: Cr0::read_raw(),
+        cr3: pml4.start_address().as_u64() | cr3_flags as u64,
+        cr4: Cr4::read_raw(),
+        xcr0: 0x1,
+        efer: (EferFlags::LONG_MODE_ENABLE
+            | EferFlags::LONG_MODE_ACTIVE
+            | EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE)
+            .bits(),
+        g_pat: 0x0007040600070406,
+        rflags: 0x2,
+        rip,
+        rsp,
+        sev_features: crate::sev_status().bits() >> 2,
+        vmpl: 0,
+        ..Default::default()
+    }
+}
+
+/// Entry point for the workers.
+///
+/// The workers run without an IDT, so they must not do anything that could
+/// cause an exception (such as logging to the serial port).
+extern "C" fn worker_main() -> ! {
+    process_queue(false);
+
+    // Park the AP until the OS starts it again. If we get woken up by the INIT-SIPI
+    // sequence in `smp::bootstrap_aps`, count the AP as started, just like the AP
+    // bootstrap code does. The kernel is expected to use the AP Creation
+    // protocol to start the AP for real.
+    loop {
+        if let Ok(true) = ap_reset_hold() {
+            crate::smp::ap_checked_in();
+        }
+    }
+}
+
+/// Takes chunks off the work queue and validates them until the queue is empty.
+///
+/// Only the BSP may set `progress`, as the workers can't log.
+fn process_queue(progress: bool) {
+    let queue = WORK_QUEUE.get().expect("work queue not initialized");
+    queue.active.fetch_add(1, Ordering::SeqCst);
+    loop {
+        let index = queue.next.fetch_add(1, Ordering::SeqCst);
+        let Some(chunk) = queue.chunks.get(index) else {
+            break;
+        };
+        if progress {
+            log::info!("validating memory chunk {} of {}", index + 1, queue.chunks.len());
+        }
+        if validate_range(chunk.clone()).is_err() {
+            queue.failed.store(true, Ordering::SeqCst);
+        }
+    }
+    queue.active.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Validates an identity-mapped memory range, using 2MiB pages where possible.
+fn validate_range(range: Range<u64>) -> Result<(), InstructionError> {
+    let end = range.end & !(Size4KiB::SIZE - 1);
+    let mut address = range.start.next_multiple_of(Size4KiB::SIZE);
+    while address < end {
+        if address % Size2MiB::SIZE == 0 && address + Size2MiB::SIZE <= end {
+            match pvalidate(address as usize, SevPageSize::Page2MiB, Validation::Validated) {
+                Ok(()) => {
+                    counters::VALIDATED_2M.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(InstructionError::FailSizeMismatch) => {
+                    // 2MiB is no go, fail back to 4KiB pages.
+                    counters::ERROR_FAIL_SIZE_MISMATCH.fetch_add(1, Ordering::SeqCst);
+                    for page in (address..address + Size2MiB::SIZE).step_by(Size4KiB::SIZE as usize)
+                    {
+                        validate_4k(page)?;
+                    }
+                }
+                Err(InstructionError::ValidationStatusNotUpdated) => {
+                    counters::ERROR_VALIDATION_STATUS_NOT_UPDATED.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(err) => return Err(err),
+            }
+            address += Size2MiB::SIZE;
+        } else {
+            validate_4k(address)?;
+            address += Size4KiB::SIZE;
+        }
+    }
+    Ok(())
+}
+
+fn validate_4k(address: u64) -> Result<(), InstructionError> {
+    match pvalidate(address as usize, SevPageSize::Page4KiB, Validation::Validated) {
+        Ok(()) => {
+            counters::VALIDATED_4K.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        Err(InstructionError::ValidationStatusNotUpdated) => {
+            // We don't treat this as an error. It only happens if SEV-SNP is not enabled,
+            // or it is already validated.
+            counters::ERROR_VALIDATION_STATUS_NOT_UPDATED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,221 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Support for the Xen PVH boot protocol.
+//!
+//! Kernels that support PVH advertise a 32-bit entry point in an ELF note. The
+//! kernel is entered in 32-bit protected mode with paging disabled, and EBX
+//! pointing to a `hvm_start_info` structure that describes the memory map, the
+//! command line, the ACPI RSDP and any modules (such as the initial RAM disk).
+//!
+//! See <https://xenbits.xen.org/docs/unstable/misc/pvh.html> and
+//! <https://xenbits.xen.org/gitweb/?p=xen.git;a=blob;f=xen/include/public/arch-x86/hvm/start_info.h>.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{arch::asm, mem::size_of};
+
+use elf::{abi::PT_NOTE, endian::AnyEndian, note::Note, ElfBytes};
+use x86_64::{registers::segmentation::SegmentSelector, PhysAddr};
+use zerocopy::{AsBytes, FromZeroes};
+
+use crate::{zero_page::ZeroPage, BOOT_ALLOC};
+
+/// Type of the ELF note that contains the 32-bit PVH entry point.
+const XEN_ELFNOTE_PHYS32_ENTRY: u64 = 18;
+
+/// Name of the ELF note that contains the PVH entry point.
+const XEN_ELFNOTE_NAME: &str = "Xen";
+
+/// Magic value ("xEn3" with the 0x80 bit of the "E" set) of the start info.
+const HVM_START_MAGIC_VALUE: u32 = 0x336ec578;
+
+/// Version 1 of the start info adds the memory map.
+const HVM_START_VERSION: u32 = 1;
+
+/// The start info structure that is passed to the kernel in EBX.
+#[derive(AsBytes, FromZeroes)]
+#[repr(C)]
+pub struct HvmStartInfo {
+    /// Must be `HVM_START_MAGIC_VALUE`.
+    magic: u32,
+    /// Version of the structure.
+    version: u32,
+    /// SIF_xxx flags; we don't set any.
+    flags: u32,
+    /// Number of entries in the module list.
+    nr_modules: u32,
+    /// Physical address of the module list.
+    modlist_paddr: u64,
+    /// Physical address of the null-terminated command line.
+    cmdline_paddr: u64,
+    /// Physical address of the ACPI RSDP.
+    rsdp_paddr: u64,
+    /// Physical address of the memory map.
+    memmap_paddr: u64,
+    /// Number of entries in the memory map.
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+/// An entry in the module list.
+#[derive(AsBytes, FromZeroes)]
+#[repr(C)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+/// An entry in the memory map. The types match the E820 types.
+#[derive(AsBytes, FromZeroes)]
+#[repr(C)]
+struct HvmMemmapTableEntry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    reserved: u32,
+}
+
+static_assertions::assert_eq_size!(HvmStartInfo, [u8; 56]);
+static_assertions::assert_eq_size!(HvmModlistEntry, [u8; 32]);
+static_assertions::assert_eq_size!(HvmMemmapTableEntry, [u8; 24]);
+
+/// Looks for the PVH entry point note in an ELF kernel image.
+pub fn find_pvh_entry(buf: &[u8]) -> Option<PhysAddr> {
+    let file = ElfBytes::<AnyEndian>::minimal_parse(buf).ok()?;
+    file.segments()?
+        .iter()
+        .filter(|phdr| phdr.p_type == PT_NOTE)
+        .filter_map(|phdr| file.segment_data_as_notes(&phdr).ok())
+        .flatten()
+        .find_map(|note| match note {
+            Note::Unknown(note)
+                if note.n_type == XEN_ELFNOTE_PHYS32_ENTRY
+                    && note.name.trim_end_matches('\0') == XEN_ELFNOTE_NAME
+                    && note.desc.len() >= size_of::<u32>() =>
+            {
+                // The entry point is a 32-bit physical address, but it may be stored in a
+                // 64-bit field.
+                let entry = u32::from_le_bytes(note.desc[..size_of::<u32>()].try_into().unwrap());
+                Some(PhysAddr::new(entry as u64))
+            }
+            _ => None,
+        })
+}
+
+/// Builds the PVH start info from the information in the zero page.
+///
+/// All the data structures are allocated in the boot allocator, so that they
+/// outlive stage0.
+pub fn create_start_info(zero_page: &ZeroPage) -> &'static HvmStartInfo {
+    let mut memmap = Vec::with_capacity_in(zero_page.e820_table().len(), &BOOT_ALLOC);
+    memmap.extend(zero_page.e820_table().iter().map(|entry| HvmMemmapTableEntry {
+        addr: entry.addr() as u64,
+        size: entry.size() as u64,
+        type_: entry.entry_type().map(|type_| type_ as u32).unwrap_or(0),
+        reserved: 0,
+    }));
+    let memmap = memmap.leak();
+
+    let mut modules = Vec::new_in(&BOOT_ALLOC);
+    if let Some((address, size)) = zero_page.initial_ram_disk() {
+        modules.push(HvmModlistEntry {
+            paddr: address.as_u64(),
+            size: size as u64,
+            cmdline_paddr: 0,
+            reserved: 0,
+        });
+    }
+    let modules = modules.leak();
+
+    let start_info = Box::leak(Box::new_in(HvmStartInfo::new_zeroed(), &BOOT_ALLOC));
+    start_info.magic = HVM_START_MAGIC_VALUE;
+    start_info.version = HVM_START_VERSION;
+    start_info.nr_modules = modules.len() as u32;
+    start_info.modlist_paddr = if modules.is_empty() { 0 } else { modules.as_ptr() as u64 };
+    start_info.cmdline_paddr = zero_page.cmdline_addr().as_u64();
+    start_info.rsdp_paddr = zero_page.acpi_rsdp_addr().as_u64();
+    start_info.memmap_paddr = memmap.as_ptr() as u64;
+    start_info.memmap_entries = memmap.len() as u32;
+    start_info
+}
+
+/// Passes control to a kernel using the PVH entry point.
+///
+/// We switch from long mode to 32-bit protected mode by jumping to a 32-bit
+/// code segment, disabling paging and clearing EFER.LME. The data segments
+/// stage0 uses are flat 4GiB segments, so they can be used as-is.
+///
+/// # Safety
+///
+/// This assumes that the entry point is valid, that `code32_selector` refers to
+/// a flat 32-bit code segment, and that the code executing the switch is
+/// identity-mapped below 4GiB.
+pub unsafe fn jump_to_pvh_entry(
+    entry_point: PhysAddr,
+    start_info: &'static HvmStartInfo,
+    code32_selector: SegmentSelector,
+) -> ! {
+    asm!(
+        // Far return into the 32-bit code segment.
+        "push {0}",
+        "lea 2f(%rip), {0}",
+        "push {0}",
+        "lretq",
+        ".code32",
+        "2:",
+        // Disable paging, which also deactivates long mode.
+        "mov %cr0, %eax",
+        "and $0x7FFFFFFF, %eax",
+        "mov %eax, %cr0",
+        // Clear EFER.LME.
+        "mov $0xC0000080, %ecx",
+        "rdmsr",
+        "and $0xFFFFFEFF, %eax",
+        "wrmsr",
+        // PVH expects all CR4 bits to be cleared.
+        "xor %eax, %eax",
+        "mov %eax, %cr4",
+        // ...and away we go!
+        "jmp *%esi",
+        ".code64",
+        inout(reg) code32_selector.0 as u64 => _,
+        in("rsi") entry_point.as_u64(),
+        in("rbx") start_info as *const _ as u64,
+        options(noreturn, att_syntax)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_info_layout() {
+        // The offsets are defined in the Xen headers.
+        let start_info = HvmStartInfo::new_zeroed();
+        let base = &start_info as *const _ as usize;
+        assert_eq!(&start_info.modlist_paddr as *const _ as usize - base, 16);
+        assert_eq!(&start_info.memmap_paddr as *const _ as usize - base, 40);
+        assert_eq!(&start_info.memmap_entries as *const _ as usize - base, 48);
+    }
+
+    #[test]
+    fn no_pvh_note_in_garbage() {
+        assert_eq!(find_pvh_entry(&[0u8; 128]), None);
+    }
+}
@@ -27,7 +27,7 @@ use oak_linux_boot_params::{BootE820Entry, E820EntryType};
 pub use oak_sev_guest::ghcb::Ghcb;
 use oak_sev_guest::{
     crypto::GuestMessageEncryptor,
-    ghcb::GhcbProtocol,
+    ghcb::{GhcbProtocol, PageStateChangeEntry, PSC_MAX_ENTRIES},
     guest::{GuestMessage, Message},
     instructions::{pvalidate, InstructionError, PageSize as SevPageSize, Validation},
     msr::{
@@ -39,8 +39,9 @@ use spinning_top::Spinlock;
 use x86_64::{
     instructions::tlb,
     structures::paging::{
-        frame::PhysFrameRange, page::AddressNotAligned, Page, PageSize, PageTable, PageTableFlags,
-        PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+        frame::PhysFrameRange,
+        page::{AddressNotAligned, PageRange},
+        Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
@@ -51,6 +52,10 @@ use crate::{sev_status, BootAllocator};
 
 pub static GHCB_WRAPPER: OnceCell<Spinlock<GhcbProtocol<'static, Ghcb>>> = OnceCell::new();
 
+/// The pages in the first 2MiB of memory that are currently shared with the
+/// hypervisor, one bit per 4KiB page.
+static SHARED_PAGES: Spinlock<[u64; 8]> = Spinlock::new([0; 8]);
+
 /// Cryptographic helper to encrypt and decrypt messages for the GHCB guest
 /// message protocol.
 static GUEST_MESSAGE_ENCRYPTOR: Spinlock<Option<GuestMessageEncryptor>> = Spinlock::new(None);
@@ -79,13 +84,8 @@ unsafe impl<A: Allocator> Allocator for SharedAllocator<A> {
             layout.align_to(Size4KiB::SIZE as usize).map_err(|_| AllocError)?.pad_to_align();
         let allocation = self.inner.allocate(layout)?;
         if sev_status().contains(SevStatus::SEV_ENABLED) {
-            for offset in (0..allocation.len()).step_by(Size4KiB::SIZE as usize) {
-                // Safety: the allocation has succeeded and the offset won't exceed the size of
-                // the allocation.
-                share_page(Page::containing_address(VirtAddr::from_ptr(unsafe {
-                    allocation.as_non_null_ptr().as_ptr().add(offset)
-                })))
-            }
+            let start = Page::containing_address(VirtAddr::from_ptr(allocation.as_mut_ptr()));
+            share_pages(Page::range(start, start + (allocation.len() as u64 / Size4KiB::SIZE)));
         }
         Ok(allocation)
     }
@@ -97,13 +97,8 @@ unsafe impl<A: Allocator> Allocator for SharedAllocator<A> {
             .unwrap()
             .pad_to_align();
         if sev_status().contains(SevStatus::SEV_ENABLED) {
-            for offset in (0..layout.size()).step_by(Size4KiB::SIZE as usize) {
-                // Safety: the allocation has succeeded and the offset won't exceed the size of
-                // the allocation.
-                unshare_page(Page::containing_address(VirtAddr::from_ptr(unsafe {
-                    ptr.as_ptr().add(offset)
-                })))
-            }
+            let start = Page::containing_address(VirtAddr::from_ptr(ptr.as_ptr()));
+            unshare_pages(Page::range(start, start + (layout.size() as u64 / Size4KiB::SIZE)));
         }
         self.inner.deallocate(ptr, layout)
     }
@@ -185,57 +180,256 @@ pub fn deinit_ghcb() {
 
 /// Shares a single 4KiB page with the hypervisor.
 pub fn share_page(page: Page<Size4KiB>) {
-    let page_start = page.start_address().as_u64();
+    share_pages(Page::range(page, page + 1));
+}
+
+/// Shares a range of 4KiB pages with the hypervisor.
+pub fn share_pages(pages: PageRange<Size4KiB>) {
     // Only the first 2MiB is mapped as 4KiB pages, so make sure we fall in that
     // range.
-    assert!(page_start < Size2MiB::SIZE);
-    // Remove the ENCRYPTED bit from the entry that maps the page.
+    assert!(pages.end.start_address().as_u64() <= Size2MiB::SIZE);
+    // Remove the ENCRYPTED bit from the entries that map the pages.
     {
         let mut page_tables = crate::paging::PAGE_TABLE_REFS.get().unwrap().lock();
         let pt = &mut page_tables.pt_0;
-        pt[page.p1_index()].set_addr(
-            PhysAddr::new(page_start),
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-        );
+        for page in pages {
+            pt[page.p1_index()].set_addr(
+                PhysAddr::new(page.start_address().as_u64()),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+        }
     }
     tlb::flush_all();
+    track_shared_pages(pages, true);
 
     // SNP requires extra handling beyond just removing the encrypted bit.
     if sev_status().contains(SevStatus::SNP_ACTIVE) {
-        let request = SnpPageStateChangeRequest::new(page_start as usize, PageAssignment::Shared)
-            .expect("invalid address for page location");
-        change_snp_page_state(request).expect("couldn't change SNP state for page");
+        change_page_state(frames(pages), PageAssignment::Shared)
+            .expect("couldn't change SNP state for pages");
     }
 }
 
 /// Stops sharing a single 4KiB page with the hypervisor when running with AMD
 /// SEV-SNP enabled.
 pub fn unshare_page(page: Page<Size4KiB>) {
-    let page_start = page.start_address().as_u64();
+    unshare_pages(Page::range(page, page + 1));
+}
+
+/// Stops sharing a range of 4KiB pages with the hypervisor when running with
+/// AMD SEV-SNP enabled.
+pub fn unshare_pages(pages: PageRange<Size4KiB>) {
     // Only the first 2MiB is mapped as 4KiB pages, so make sure we fall in that
     // range.
-    assert!(page_start < Size2MiB::SIZE);
+    assert!(pages.end.start_address().as_u64() <= Size2MiB::SIZE);
     if sev_status().contains(SevStatus::SNP_ACTIVE) {
-        let request = SnpPageStateChangeRequest::new(page_start as usize, PageAssignment::Private)
-            .expect("invalid address for page location");
-        change_snp_page_state(request).expect("couldn't change SNP state for page");
+        change_page_state(frames(pages), PageAssignment::Private)
+            .expect("couldn't change SNP state for pages");
     }
-    // Mark the page as encrypted.
+    // Mark the pages as encrypted.
     {
         let mut page_tables = crate::paging::PAGE_TABLE_REFS.get().unwrap().lock();
         let pt = &mut page_tables.pt_0;
-        pt[page.p1_index()].set_addr(
-            PhysAddr::new(page_start | crate::ENCRYPTED.get().unwrap_or(&0)),
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-        );
+        for page in pages {
+            pt[page.p1_index()].set_addr(
+                PhysAddr::new(page.start_address().as_u64() | crate::ENCRYPTED.get().unwrap_or(&0)),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+        }
     }
     tlb::flush_all();
-    // We have to revalidate the page again after un-sharing it.
-    if let Err(err) = page.pvalidate(&counters::VALIDATED_4K) {
-        if err != InstructionError::ValidationStatusNotUpdated {
-            panic!("shared page revalidation failed");
+    track_shared_pages(pages, false);
+    // We have to revalidate the pages again after un-sharing them.
+    for page in pages {
+        if let Err(err) = page.pvalidate(&counters::VALIDATED_4K) {
+            if err != InstructionError::ValidationStatusNotUpdated {
+                panic!("shared page revalidation failed");
+            }
+        }
+    }
+}
+
+/// Records whether the pages are shared with the hypervisor.
+fn track_shared_pages(pages: PageRange<Size4KiB>, shared: bool) {
+    let mut shared_pages = SHARED_PAGES.lock();
+    for page in pages {
+        let index = (page.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        let bit = 1 << (index % 64);
+        if shared {
+            shared_pages[index / 64] |= bit;
+        } else {
+            shared_pages[index / 64] &= !bit;
+        }
+    }
+}
+
+/// Returns the pages that are currently shared with the hypervisor.
+fn shared_pages() -> impl Iterator<Item = Page<Size4KiB>> {
+    let shared_pages = *SHARED_PAGES.lock();
+    (0..shared_pages.len() * 64)
+        .filter(move |index| shared_pages[index / 64] & (1 << (index % 64)) != 0)
+        .map(|index| Page::containing_address(VirtAddr::new(index as u64 * Size4KiB::SIZE)))
+}
+
+/// The result of `unshare_remaining_pages`.
+pub struct HardeningSummary {
+    /// Number of pages that were still shared and were converted to private.
+    pub unshared: usize,
+    /// Number of pages whose validated state was checked.
+    pub checked: usize,
+}
+
+/// Converts all pages that are still shared with the hypervisor, apart from
+/// the GHCB, back to private, and checks that all RAM in the first 2MiB of
+/// memory (where stage0 shares pages) is private and validated.
+///
+/// PVALIDATE doesn't change a page that is already validated, and reports so;
+/// any page that it does validate was not in the state we expected in the RMP.
+/// This has to run before the first 2MiB are remapped as a huge page.
+pub fn unshare_remaining_pages(
+    e820_table: &[BootE820Entry],
+) -> Result<HardeningSummary, &'static str> {
+    let ghcb_page = GHCB_WRAPPER
+        .get()
+        .map(|ghcb| Page::containing_address(VirtAddr::new(ghcb.lock().get_gpa().as_u64())));
+
+    let mut unshared = 0;
+    for page in shared_pages().filter(|page| Some(*page) != ghcb_page) {
+        unshare_page(page);
+        unshared += 1;
+    }
+
+    let mut checked = 0;
+    for entry in e820_table {
+        if entry.entry_type() != Some(E820EntryType::RAM) {
+            continue;
+        }
+        let start = VirtAddr::new(entry.addr() as u64).align_up(Size4KiB::SIZE);
+        let end = VirtAddr::new(((entry.addr() + entry.size()) as u64).min(Size2MiB::SIZE))
+            .align_down(Size4KiB::SIZE);
+        if start >= end {
+            continue;
+        }
+        let pages =
+            Page::<Size4KiB>::range(Page::containing_address(start), Page::containing_address(end));
+        for page in pages.filter(|page| Some(*page) != ghcb_page) {
+            match page.pvalidate(&counters::VALIDATED_4K) {
+                Err(InstructionError::ValidationStatusNotUpdated) => checked += 1,
+                Ok(()) => return Err("found a page that was not validated"),
+                Err(_) => return Err("couldn't check the validated state of a page"),
+            }
         }
     }
+
+    Ok(HardeningSummary { unshared, checked })
+}
+
+/// Returns the physical frames backing a range of identity-mapped pages.
+fn frames(pages: PageRange<Size4KiB>) -> PhysFrameRange<Size4KiB> {
+    PhysFrame::range(
+        PhysFrame::containing_address(PhysAddr::new(pages.start.start_address().as_u64())),
+        PhysFrame::containing_address(PhysAddr::new(pages.end.start_address().as_u64())),
+    )
+}
+
+/// Changes the SNP page state of all pages in `range`.
+///
+/// If the GHCB is available we use the Page State Change protocol, which
+/// handles up to `PSC_MAX_ENTRIES` entries per VMEXIT and uses 2MiB entries
+/// where the range allows it. Otherwise we fall back to the MSR protocol,
+/// which needs a VMEXIT for every 4KiB page.
+pub fn change_page_state(
+    range: PhysFrameRange<Size4KiB>,
+    assignment: PageAssignment,
+) -> Result<(), &'static str> {
+    if let Some(ghcb) = GHCB_WRAPPER.get() {
+        let mut ghcb = ghcb.lock();
+        // The hypervisor writes the result of the request back to the GHCB, so the
+        // GHCB page itself has to be converted using the MSR protocol.
+        let ghcb_frame = PhysFrame::containing_address(ghcb.get_gpa());
+        if !(range.start..range.end).contains(&ghcb_frame) {
+            let mut entries = [PageStateChangeEntry::default(); PSC_MAX_ENTRIES];
+            let mut count = 0;
+            for entry in page_state_change_entries(range, assignment) {
+                entries[count] = entry?;
+                count += 1;
+                if count == PSC_MAX_ENTRIES {
+                    counters::PAGE_STATE_CHANGE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+                    ghcb.page_state_change(&entries)?;
+                    count = 0;
+                }
+            }
+            if count > 0 {
+                counters::PAGE_STATE_CHANGE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+                ghcb.page_state_change(&entries[..count])?;
+            }
+            return Ok(());
+        }
+    }
+
+    for frame in range {
+        let request =
+            SnpPageStateChangeRequest::new(frame.start_address().as_u64() as usize, assignment)?;
+        counters::PAGE_STATE_CHANGE_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        change_snp_page_state(request)?;
+    }
+    Ok(())
+}
+
+/// Splits `range` into Page State Change entries, using 2MiB entries for the
+/// 2MiB-aligned parts of the range and 4KiB entries for the rest.
+fn page_state_change_entries(
+    range: PhysFrameRange<Size4KiB>,
+    assignment: PageAssignment,
+) -> impl Iterator<Item = Result<PageStateChangeEntry, &'static str>> {
+    let end = range.end.start_address();
+    let mut next = range.start.start_address();
+    core::iter::from_fn(move || {
+        if next >= end {
+            return None;
+        }
+        let (page_size, size) = if next.is_aligned(Size2MiB::SIZE) && end - next >= Size2MiB::SIZE {
+            (SevPageSize::Page2MiB, Size2MiB::SIZE)
+        } else {
+            (SevPageSize::Page4KiB, Size4KiB::SIZE)
+        };
+        let entry = PageStateChangeEntry::new(next, assignment, page_size);
+        next += size;
+        Some(entry)
+    })
+}
+
+/// Assigns all memory ranges specified in the E820 table with type `RAM` to
+/// the guest, ahead of validating them.
+///
+/// The first 640KiB of RAM were already validated in the boot assembly code
+/// and contain pages that are shared with the hypervisor, so we leave them
+/// alone.
+pub fn make_ram_private(e820_table: &[BootE820Entry]) -> Result<(), &'static str> {
+    let min_addr = PhysAddr::new(0xA0000);
+    for entry in e820_table {
+        if entry.entry_type() != Some(E820EntryType::RAM) {
+            continue;
+        }
+        let start_address =
+            PhysAddr::new(entry.addr() as u64).align_up(Size4KiB::SIZE).max(min_addr);
+        let limit_address =
+            PhysAddr::new((entry.addr() + entry.size()) as u64).align_down(Size4KiB::SIZE);
+        if start_address >= limit_address {
+            continue;
+        }
+        change_page_state(
+            PhysFrame::range(
+                PhysFrame::containing_address(start_address),
+                PhysFrame::containing_address(limit_address),
+            ),
+            PageAssignment::Private,
+        )?;
+    }
+    log::info!(
+        "Assigned RAM to the guest; page state change requests so far: {}",
+        counters::PAGE_STATE_CHANGE_REQUESTS.load(Ordering::SeqCst)
+    );
+    Ok(())
 }
 
 // Page tables come in three sizes: for 1 GiB, 2 MiB and 4 KiB pages. However,
@@ -357,6 +551,10 @@ pub mod counters {
 
     /// Number of successful PVALIDATE invocations on 4 KiB pages.
     pub static VALIDATED_4K: AtomicUsize = AtomicUsize::new(0);
+
+    /// Number of page state change requests sent to the hypervisor, using
+    /// either the GHCB or the MSR protocol.
+    pub static PAGE_STATE_CHANGE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
 }
 
 trait Validatable4KiB {
@@ -456,7 +654,9 @@ impl Validatable2MiB for PhysFrameRange<Size2MiB> {
 
 /// Calls `PVALIDATE` on all memory ranges specified in the E820 table with type
 /// `RAM`.
-pub fn validate_memory(e820_table: &[BootE820Entry], encrypted: u64) {
+///
+/// If `progress` is set, every range is logged before it is validated.
+pub fn validate_memory(e820_table: &[BootE820Entry], encrypted: u64, progress: bool) {
     log::info!("starting SEV-SNP memory validation");
 
     let mut page_tables = crate::paging::PAGE_TABLE_REFS.get().unwrap().lock();
@@ -499,6 +699,13 @@ pub fn validate_memory(e820_table: &[BootE820Entry], encrypted: u64) {
 
         let start_address = PhysAddr::new(entry.addr() as u64);
         let limit_address = PhysAddr::new((entry.addr() + entry.size()) as u64);
+        if progress {
+            log::info!(
+                "validating memory [{:#018x}..{:#018x})",
+                start_address.as_u64(),
+                limit_address.as_u64()
+            );
+        }
 
         // If the memory boundaries align with 2 MiB, start with that.
         if start_address.is_aligned(Size2MiB::SIZE) && limit_address.is_aligned(Size2MiB::SIZE) {
@@ -578,3 +785,64 @@ pub fn send_guest_message_request<
     response_message.validate()?;
     encryptor.decrypt_message::<Response>(response_message.as_ref())
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn track_shared() {
+        let page = |address| Page::containing_address(VirtAddr::new(address));
+        track_shared_pages(Page::range(page(0x1000), page(0x3000)), true);
+        track_shared_pages(Page::range(page(0x1F_F000), page(0x20_0000)), true);
+        track_shared_pages(Page::range(page(0x2000), page(0x3000)), false);
+
+        assert_eq!(shared_pages().collect::<Vec<_>>(), vec![page(0x1000), page(0x1F_F000)]);
+    }
+
+    fn entries(start: u64, end: u64) -> Vec<PageStateChangeEntry> {
+        let range = PhysFrame::range(
+            PhysFrame::containing_address(PhysAddr::new(start)),
+            PhysFrame::containing_address(PhysAddr::new(end)),
+        );
+        page_state_change_entries(range, PageAssignment::Private).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn entry(gpa: u64, page_size: SevPageSize) -> PageStateChangeEntry {
+        PageStateChangeEntry::new(PhysAddr::new(gpa), PageAssignment::Private, page_size).unwrap()
+    }
+
+    #[test]
+    fn aligned_range_uses_2mib_entries() {
+        // 1GiB of memory needs 512 entries, which fit into three requests.
+        let entries = entries(Size1GiB::SIZE, 2 * Size1GiB::SIZE);
+
+        assert_eq!(entries.len(), 512);
+        assert_eq!(entries.len().div_ceil(PSC_MAX_ENTRIES), 3);
+        assert_eq!(entries[0], entry(Size1GiB::SIZE, SevPageSize::Page2MiB));
+        assert_eq!(entries[511], entry(2 * Size1GiB::SIZE - Size2MiB::SIZE, SevPageSize::Page2MiB));
+    }
+
+    #[test]
+    fn unaligned_range_uses_4kib_entries_at_the_edges() {
+        let start = Size2MiB::SIZE - Size4KiB::SIZE;
+        let end = 2 * Size2MiB::SIZE + Size4KiB::SIZE;
+
+        assert_eq!(
+            entries(start, end),
+            vec![
+                entry(start, SevPageSize::Page4KiB),
+                entry(Size2MiB::SIZE, SevPageSize::Page2MiB),
+                entry(2 * Size2MiB::SIZE, SevPageSize::Page4KiB),
+            ]
+        );
+    }
+
+    #[test]
+    fn small_range_uses_4kib_entries() {
+        assert_eq!(entries(0xA0000, 0xA2000).len(), 2);
+        assert!(entries(0xA0000, 0xA0000).is_empty());
+    }
+}
@@ -18,7 +18,7 @@ use core::{
     arch::x86_64::_mm_pause,
     ffi::c_void,
     mem::MaybeUninit,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering},
 };
 
 use oak_sev_guest::ap_jump_table::ApJumpTable;
@@ -46,6 +46,71 @@ static LIVE_AP_COUNT: AtomicU32 = AtomicU32::new(0);
 #[link_section = ".ap_bss"]
 pub static AP_JUMP_TABLE: MaybeUninit<ApJumpTable> = MaybeUninit::uninit();
 
+/// Wakeup mailbox for the APs that were started by stage0.
+///
+/// After checking in, every AP spins on this mailbox in real mode until the
+/// command field is set to `1` (Wakeup) and the APIC ID field matches its own
+/// local APIC ID. The AP then resets the command field to `0` (Noop) to
+/// acknowledge the command and jumps to the wakeup vector.
+///
+/// The layout follows the ACPI Multiprocessor Wakeup Mailbox Structure (see
+/// Section 5.2.12.19 in the ACPI specification, Version 6.5), but as the APs
+/// are still in real mode, the wakeup vector has to be in the first megabyte of
+/// memory. The OS is free to ignore the mailbox and use INIT-SIPI-SIPI instead.
+///
+/// The address of the mailbox and the number of APs are passed to the kernel
+/// using a setup data entry in the zero page.
+// The fields are only accessed by the AP bootstrap code and the OS.
+#[allow(dead_code)]
+#[repr(C, align(4096))]
+pub struct ApWakeupMailbox {
+    command: AtomicU16,
+    _reserved: u16,
+    apic_id: AtomicU32,
+    wakeup_vector: AtomicU64,
+    _reserved_for_os: [u8; 2032],
+    _reserved_for_firmware: [u8; 2048],
+}
+static_assertions::assert_eq_size!(ApWakeupMailbox, [u8; 4096]);
+
+impl ApWakeupMailbox {
+    const fn new() -> Self {
+        Self {
+            command: AtomicU16::new(0),
+            _reserved: 0,
+            apic_id: AtomicU32::new(0),
+            wakeup_vector: AtomicU64::new(0),
+            _reserved_for_os: [0; 2032],
+            _reserved_for_firmware: [0; 2048],
+        }
+    }
+}
+
+// Like `LIVE_AP_COUNT`, the mailbox is accessed from the AP bootstrap code in
+// real mode, so it has to be in the first 64K of memory.
+#[no_mangle]
+#[link_section = ".ap_bss"]
+static AP_MAILBOX: ApWakeupMailbox = ApWakeupMailbox::new();
+
+/// Returns the physical address of the AP wakeup mailbox.
+pub fn mailbox_address() -> PhysAddr {
+    // We use identity mapping in stage0.
+    PhysAddr::new(&AP_MAILBOX as *const _ as u64)
+}
+
+/// Returns the number of APs that have checked in so far.
+pub fn live_ap_count() -> u32 {
+    LIVE_AP_COUNT.load(Ordering::SeqCst)
+}
+
+/// Records that an AP has come online.
+///
+/// Used by APs that were not started via the AP bootstrap code, which does the
+/// equivalent in assembly.
+pub fn ap_checked_in() {
+    LIVE_AP_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
 pub fn start_ap(lapic: &mut Lapic, physical_apic_id: u32) -> Result<(), &'static str> {
     lapic.send_init_ipi(physical_apic_id)?;
     // TODO(#4235): wait 10 ms. The numbers chosen here are arbitrary and have no
@@ -79,7 +144,8 @@ pub fn start_ap(lapic: &mut Lapic, physical_apic_id: u32) -> Result<(), &'static
     Ok(())
 }
 
-// TODO(#4235): Bootstrap the APs.
+/// Starts all enabled APs listed in the MADT and waits until they have parked
+/// themselves in the wakeup mailbox.
 pub fn bootstrap_aps(rsdp: &Rsdp) -> Result<(), &'static str> {
     // If XSDT exists, then per ACPI spec we have to prefer that. If it doesn't, see
     // if we can use the old RSDT. (If we have neither XSDT or RSDT, the ACPI
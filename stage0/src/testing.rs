@@ -0,0 +1,146 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! In-memory implementations of the devices stage0 talks to, so that the logic
+//! built on top of them can be exercised in unit tests without booting a VM.
+
+use alloc::{collections::BTreeMap, rc::Rc, vec, vec::Vec};
+use core::cell::RefCell;
+
+use oak_sev_guest::io::{PortReader, PortWriter};
+
+use crate::fw_cfg::{FwCfg, FwCfgBackend};
+
+/// Selector of the fw_cfg file directory.
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// QEMU assigns selectors to files starting from this value.
+const FW_CFG_FILE_FIRST: u16 = 0x0020;
+
+/// Size of the name field in a fw_cfg file directory entry.
+const FW_CFG_MAX_FILE_PATH: usize = 56;
+
+/// An in-memory fw_cfg device that only supports the traditional interface.
+pub struct FakeFwCfg {
+    items: BTreeMap<u16, Vec<u8>>,
+    files: Vec<(&'static str, u16)>,
+    selected: u16,
+    offset: usize,
+}
+
+impl Default for FakeFwCfg {
+    fn default() -> Self {
+        let mut fake = Self { items: BTreeMap::new(), files: Vec::new(), selected: 0, offset: 0 };
+        fake.items.insert(0x0000, b"QEMU".to_vec());
+        fake.items.insert(0x0001, vec![0x01]);
+        fake.update_file_dir();
+        fake
+    }
+}
+
+impl FakeFwCfg {
+    /// Adds an item with a well-known selector.
+    pub fn with_item(mut self, selector: u16, data: &[u8]) -> Self {
+        self.items.insert(selector, data.to_vec());
+        self
+    }
+
+    /// Adds a file that can be looked up by name.
+    pub fn with_file(mut self, name: &'static str, data: &[u8]) -> Self {
+        assert!(name.len() < FW_CFG_MAX_FILE_PATH, "file name too long");
+        let selector = FW_CFG_FILE_FIRST + self.files.len() as u16;
+        self.files.push((name, selector));
+        self.items.insert(selector, data.to_vec());
+        self.update_file_dir();
+        self
+    }
+
+    /// Returns a fw_cfg wrapper backed by this device.
+    pub fn build(self) -> FwCfg<Self> {
+        FwCfg::with_backend(self).expect("invalid fake fw_cfg device")
+    }
+
+    fn update_file_dir(&mut self) {
+        let mut dir = Vec::new();
+        dir.extend_from_slice(&(self.files.len() as u32).to_be_bytes());
+        for (name, selector) in &self.files {
+            let mut path = [0u8; FW_CFG_MAX_FILE_PATH];
+            path[..name.len()].copy_from_slice(name.as_bytes());
+            dir.extend_from_slice(&(self.items[selector].len() as u32).to_be_bytes());
+            dir.extend_from_slice(&selector.to_be_bytes());
+            dir.extend_from_slice(&[0u8; 2]);
+            dir.extend_from_slice(&path);
+        }
+        self.items.insert(FW_CFG_FILE_DIR, dir);
+    }
+}
+
+impl FwCfgBackend for FakeFwCfg {
+    fn write_selector(&mut self, selector: u16) -> Result<(), &'static str> {
+        self.selected = selector;
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        // Like QEMU, reading past the end of an item (or reading an item that
+        // doesn't exist) returns zeros.
+        let item = self.items.get(&self.selected).map(Vec::as_slice).unwrap_or_default();
+        for byte in buf {
+            *byte = item.get(self.offset).copied().unwrap_or(0);
+            self.offset += 1;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Nvram {
+    registers: BTreeMap<u8, u8>,
+    index: u8,
+}
+
+/// An in-memory CMOS NVRAM.
+///
+/// The same value is used as both the index and the data port; clones share
+/// the NVRAM contents.
+#[derive(Clone, Default)]
+pub struct FakeNvram {
+    inner: Rc<RefCell<Nvram>>,
+}
+
+impl FakeNvram {
+    /// Sets the value of an NVRAM register.
+    pub fn with_register(self, index: u8, value: u8) -> Self {
+        self.inner.borrow_mut().registers.insert(index, value);
+        self
+    }
+}
+
+impl PortWriter<u8> for FakeNvram {
+    unsafe fn try_write(&mut self, value: u8) -> Result<(), &'static str> {
+        // The highest bit of the index port controls NMI delivery.
+        self.inner.borrow_mut().index = value & 0x7F;
+        Ok(())
+    }
+}
+
+impl PortReader<u8> for FakeNvram {
+    unsafe fn try_read(&mut self) -> Result<u8, &'static str> {
+        let nvram = self.inner.borrow();
+        Ok(nvram.registers.get(&nvram.index).copied().unwrap_or(0))
+    }
+}
@@ -0,0 +1,572 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Minimal TPM 2.0 driver using the Command Response Buffer (CRB) interface.
+//!
+//! If the VMM exposes a vTPM at the standard CRB address, stage0 extends the
+//! PCRs with the measurements of the boot components (following the PC Client
+//! conventions: PCR 0 for the firmware, PCR 4 for the kernel and the initial
+//! RAM disk, and PCR 8 for the kernel command-line) and records the events in
+//! a crypto-agile TCG event log, so that guests can use TPM-based attestation.
+//!
+//! The event log is written to the log area that the VMM advertises in the
+//! TPM2 ACPI table (`etc/tpm/log` in QEMU's table-loader).
+//!
+//! See the TCG PC Client Platform TPM Profile Specification for the CRB
+//! registers and the TCG PC Client Platform Firmware Profile Specification for
+//! the event log format.
+
+use alloc::vec::Vec;
+use core::{
+    ffi::c_void,
+    hint::spin_loop,
+    mem::{size_of, MaybeUninit},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+use x86_64::{
+    instructions::tlb::flush_all,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    measurement::{sha2_384, Sha384Digest},
+    paging::PAGE_TABLE_REFS,
+    sev::GHCB_WRAPPER,
+    Measurement,
+};
+
+/// Physical address of the locality 0 CRB registers.
+const TPM_CRB_BASE: u64 = 0xFED4_0000;
+
+/// Interface type of the CRB interface in `TPM_CRB_INTF_ID`.
+const INTERFACE_TYPE_CRB: u32 = 1;
+
+/// Maximum number of times we poll a register before giving up.
+const POLL_ITERATIONS: usize = 1_000_000;
+
+/// Size of the TPM event log area. QEMU reserves 64 KiB for the log.
+const EVENT_LOG_AREA_SIZE: usize = 64 * 1024;
+
+/// TPM2 command and response tags.
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+
+/// TPM2 command codes.
+const TPM_CC_STARTUP: u32 = 0x0000_0144;
+const TPM_CC_PCR_EXTEND: u32 = 0x0000_0182;
+
+/// TPM2 response codes.
+const TPM_RC_SUCCESS: u32 = 0x000;
+/// Returned by TPM2_Startup if the TPM has already been started.
+const TPM_RC_INITIALIZE: u32 = 0x100;
+
+/// Startup type that resets the TPM state.
+const TPM_SU_CLEAR: u16 = 0x0000;
+
+/// Handle of the password authorization session.
+const TPM_RS_PW: u32 = 0x4000_0009;
+
+/// TPM2 algorithm IDs.
+const TPM_ALG_SHA256: u16 = 0x000B;
+const TPM_ALG_SHA384: u16 = 0x000C;
+
+/// Size of a TPM2 command or response header.
+const HEADER_SIZE: usize = 10;
+
+/// Event types used in the event log.
+const EV_POST_CODE: u32 = 0x0000_0001;
+const EV_NO_ACTION: u32 = 0x0000_0003;
+const EV_IPL: u32 = 0x0000_000D;
+
+/// PCRs used for the measurements.
+const PCR_FIRMWARE: u32 = 0;
+const PCR_BOOT_LOADER: u32 = 4;
+const PCR_CMDLINE: u32 = 8;
+
+/// Offsets of the CRB registers, relative to `TPM_CRB_BASE`.
+#[derive(Clone, Copy)]
+#[repr(usize)]
+enum Register {
+    LocCtrl = 0x08,
+    LocSts = 0x0C,
+    InterfaceId = 0x30,
+    CtrlReq = 0x40,
+    CtrlSts = 0x44,
+    CtrlStart = 0x4C,
+    CmdSize = 0x58,
+    CmdLowAddr = 0x5C,
+    CmdHighAddr = 0x60,
+    RspSize = 0x64,
+    RspAddr = 0x68,
+}
+
+/// `TPM_LOC_CTRL`: request access to the locality.
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+/// `TPM_LOC_STS`: access to the locality has been granted.
+const LOC_STS_GRANTED: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_REQ`: transition to the Ready state.
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_REQ`: transition to the Idle state.
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+/// `TPM_CRB_CTRL_STS`: the TPM has encountered a fatal error.
+const CTRL_STS_FATAL_ERROR: u32 = 1 << 0;
+/// `TPM_CRB_CTRL_START`: start executing the command in the buffer.
+const CTRL_START: u32 = 1 << 0;
+
+#[repr(C, align(4096))]
+struct CrbRegisters {
+    registers: [u32; 1024],
+}
+static_assertions::assert_eq_size!(CrbRegisters, [u8; Size4KiB::SIZE as usize]);
+
+// Reserve a 4K chunk of memory that we can remap to the CRB registers.
+static mut TPM_CRB_AREA: MaybeUninit<CrbRegisters> = MaybeUninit::uninit();
+
+// Memory for the event log. The address is referenced from the TPM2 ACPI table,
+// so the log has to outlive stage0.
+static mut EVENT_LOG_AREA: MaybeUninit<[u8; EVENT_LOG_AREA_SIZE]> = MaybeUninit::uninit();
+
+/// Size of the event log area requested by the table-loader, or zero if the
+/// area hasn't been allocated.
+static EVENT_LOG_AREA_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates the memory for the TPM event log area requested in the ACPI
+/// table-loader.
+pub fn allocate_event_log_area(size: usize) -> Result<&'static mut [u8], &'static str> {
+    if size > EVENT_LOG_AREA_SIZE {
+        return Err("TPM event log area too large");
+    }
+    EVENT_LOG_AREA_LEN.store(size, Ordering::SeqCst);
+    // Safety: we do not have concurrent threads so accessing the static is safe.
+    let area = unsafe { EVENT_LOG_AREA.write([0; EVENT_LOG_AREA_SIZE]) };
+    Ok(&mut area[..size])
+}
+
+/// Returns the TPM event log area, if the ACPI table-loader has allocated it.
+pub fn event_log_area() -> Option<&'static mut [u8]> {
+    let size = EVENT_LOG_AREA_LEN.load(Ordering::SeqCst);
+    if size == 0 {
+        return None;
+    }
+    // Safety: the area has been initialized in `allocate_event_log_area`.
+    Some(unsafe { &mut EVENT_LOG_AREA.assume_init_mut()[..size] })
+}
+
+/// The SHA2-256 and SHA2-384 digests of a measured component.
+#[derive(Clone, Copy)]
+pub struct Digests {
+    pub sha2_256: Measurement,
+    pub sha2_384: Sha384Digest,
+}
+
+impl Digests {
+    pub fn new(source: &[u8]) -> Self {
+        let mut sha2_256 = Measurement::default();
+        sha2_256[..].copy_from_slice(&Sha256::digest(source)[..]);
+        Self { sha2_256, sha2_384: sha2_384(source) }
+    }
+
+    /// Serializes the digests as a `TPML_DIGEST_VALUES` structure.
+    fn extend_to(&self, buf: &mut Vec<u8>, to_bytes: fn(u16) -> [u8; 2], count: [u8; 4]) {
+        buf.extend_from_slice(&count);
+        buf.extend_from_slice(&to_bytes(TPM_ALG_SHA256));
+        buf.extend_from_slice(&self.sha2_256);
+        buf.extend_from_slice(&to_bytes(TPM_ALG_SHA384));
+        buf.extend_from_slice(&self.sha2_384);
+    }
+}
+
+extern "C" {
+    #[link_name = "bios_start"]
+    static BIOS_START: c_void;
+}
+
+/// Calculates the digests of the stage0 firmware image, which extends from
+/// `bios_start` up to the 4GiB boundary.
+pub fn firmware_digests() -> Digests {
+    const TOP: u64 = 0x1_0000_0000;
+    // Only the top 2MiB below 4GiB are mapped. Safety: we're only interested in
+    // the address of the symbol.
+    let start = (unsafe { &BIOS_START } as *const _ as u64).max(TOP - Size2MiB::SIZE);
+    // Safety: the firmware image is identity-mapped and is never modified.
+    let image = unsafe { core::slice::from_raw_parts(start as *const u8, (TOP - start) as usize) };
+    Digests::new(image)
+}
+
+/// The boot components that stage0 measures into the TPM.
+pub struct BootMeasurements<'a> {
+    /// The stage0 firmware image.
+    pub firmware: Digests,
+    pub kernel: Digests,
+    pub cmdline: &'a str,
+    pub initial_ram_disk: Option<Digests>,
+}
+
+/// Access to the CRB registers, either directly or via the GHCB MMIO protocol
+/// if we're running under SEV-ES or SEV-SNP.
+struct Mmio {
+    registers: &'static mut CrbRegisters,
+}
+
+impl Mmio {
+    fn new() -> Result<Self, &'static str> {
+        // Safety: we're not dereferencing the pointer, we just want to know where it
+        // landed in virtual memory.
+        let vaddr = VirtAddr::from_ptr(unsafe { TPM_CRB_AREA.as_ptr() });
+        if vaddr.as_u64() > Size2MiB::SIZE {
+            return Err("TPM_CRB_AREA virtual address does not land in the first page table");
+        }
+        let mut tables = PAGE_TABLE_REFS.get().unwrap().lock();
+        tables.pt_0[vaddr.p1_index()].set_addr(
+            PhysAddr::new(TPM_CRB_BASE),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE,
+        );
+        flush_all();
+        // Safety: we've mapped TPM_CRB_AREA to the CRB registers.
+        Ok(Self { registers: unsafe { TPM_CRB_AREA.assume_init_mut() } })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        if let Some(ghcb) = GHCB_WRAPPER.get() {
+            ghcb.lock()
+                .mmio_read_u32(PhysAddr::new(TPM_CRB_BASE + offset as u64))
+                .expect("couldn't read the TPM CRB register using the GHCB protocol")
+        } else {
+            // Safety: the register area is mapped to the device.
+            unsafe {
+                (&self.registers.registers[offset / size_of::<u32>()] as *const u32).read_volatile()
+            }
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        if let Some(ghcb) = GHCB_WRAPPER.get() {
+            ghcb.lock()
+                .mmio_write_u32(PhysAddr::new(TPM_CRB_BASE + offset as u64), value)
+                .expect("couldn't write the TPM CRB register using the GHCB protocol")
+        } else {
+            // Safety: the register area is mapped to the device.
+            unsafe {
+                (&mut self.registers.registers[offset / size_of::<u32>()] as *mut u32)
+                    .write_volatile(value)
+            }
+        }
+    }
+
+    fn read_register(&self, register: Register) -> u32 {
+        self.read(register as usize)
+    }
+
+    fn write_register(&mut self, register: Register, value: u32) {
+        self.write(register as usize, value)
+    }
+
+    /// Polls a register until `condition` holds for its value.
+    fn poll(
+        &self,
+        register: Register,
+        condition: impl Fn(u32) -> bool,
+    ) -> Result<u32, &'static str> {
+        for _ in 0..POLL_ITERATIONS {
+            let value = self.read_register(register);
+            if condition(value) {
+                return Ok(value);
+            }
+            spin_loop();
+        }
+        Err("timed out waiting for the TPM")
+    }
+}
+
+/// A TPM 2.0 device using the CRB interface at locality 0.
+pub struct Tpm {
+    mmio: Mmio,
+}
+
+impl Tpm {
+    /// Probes for a TPM at the standard CRB address.
+    ///
+    /// Returns `None` if there is no device, or if the device doesn't use the
+    /// CRB interface.
+    pub fn probe() -> Option<Self> {
+        let mmio = Mmio::new().ok()?;
+        let interface_id = mmio.read_register(Register::InterfaceId);
+        // Reads from unbacked MMIO return all ones.
+        if interface_id == u32::MAX || interface_id & 0xF != INTERFACE_TYPE_CRB {
+            return None;
+        }
+        Some(Self { mmio })
+    }
+
+    /// Starts the TPM and extends the PCRs with the boot measurements.
+    ///
+    /// If `log_area` is provided, a TCG event log describing the measurements
+    /// is written to it.
+    pub fn measure_boot(
+        &mut self,
+        measurements: &BootMeasurements,
+        log_area: Option<&mut [u8]>,
+    ) -> Result<(), &'static str> {
+        self.mmio.write_register(Register::LocCtrl, LOC_CTRL_REQUEST_ACCESS);
+        self.mmio.poll(Register::LocSts, |value| value & LOC_STS_GRANTED != 0)?;
+
+        match self.execute(&startup_command())? {
+            TPM_RC_SUCCESS | TPM_RC_INITIALIZE => {}
+            _ => return Err("TPM2_Startup failed"),
+        }
+
+        let mut event_log = log_area.map(EventLog::new).transpose()?;
+        let mut events: Vec<(u32, u32, Digests, &[u8])> = Vec::new();
+        events.push((PCR_FIRMWARE, EV_POST_CODE, measurements.firmware, b"POST CODE"));
+        events.push((PCR_BOOT_LOADER, EV_IPL, measurements.kernel, b"kernel"));
+        if let Some(initial_ram_disk) = measurements.initial_ram_disk {
+            events.push((PCR_BOOT_LOADER, EV_IPL, initial_ram_disk, b"initrd"));
+        }
+        events.push((
+            PCR_CMDLINE,
+            EV_IPL,
+            Digests::new(measurements.cmdline.as_bytes()),
+            measurements.cmdline.as_bytes(),
+        ));
+
+        for (pcr, event_type, digests, event_data) in events {
+            if self.execute(&pcr_extend_command(pcr, &digests))? != TPM_RC_SUCCESS {
+                return Err("TPM2_PCR_Extend failed");
+            }
+            if let Some(event_log) = event_log.as_mut() {
+                event_log.record(pcr, event_type, &digests, event_data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a command and returns the response code.
+    fn execute(&mut self, command: &[u8]) -> Result<u32, &'static str> {
+        self.mmio.write_register(Register::CtrlReq, CTRL_REQ_CMD_READY);
+        self.mmio.poll(Register::CtrlReq, |value| value & CTRL_REQ_CMD_READY == 0)?;
+        if self.mmio.read_register(Register::CtrlSts) & CTRL_STS_FATAL_ERROR != 0 {
+            return Err("TPM is in a fatal error state");
+        }
+
+        let cmd_address = (self.mmio.read_register(Register::CmdHighAddr) as u64) << 32
+            | self.mmio.read_register(Register::CmdLowAddr) as u64;
+        let cmd_offset = buffer_offset(cmd_address, self.mmio.read_register(Register::CmdSize))?;
+        if command.len() > self.mmio.read_register(Register::CmdSize) as usize {
+            return Err("TPM command too large for the command buffer");
+        }
+        for (i, chunk) in command.chunks(size_of::<u32>()).enumerate() {
+            let mut word = [0u8; size_of::<u32>()];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.mmio.write(cmd_offset + i * size_of::<u32>(), u32::from_le_bytes(word));
+        }
+
+        self.mmio.write_register(Register::CtrlStart, CTRL_START);
+        self.mmio.poll(Register::CtrlStart, |value| value & CTRL_START == 0)?;
+
+        let rsp_address = (self.mmio.read(Register::RspAddr as usize + size_of::<u32>()) as u64)
+            << 32
+            | self.mmio.read_register(Register::RspAddr) as u64;
+        let rsp_offset = buffer_offset(rsp_address, self.mmio.read_register(Register::RspSize))?;
+        let header: Vec<u8> = (0..3)
+            .flat_map(|i| self.mmio.read(rsp_offset + i * size_of::<u32>()).to_le_bytes())
+            .collect();
+        let response_code = response_code(&header)?;
+
+        self.mmio.write_register(Register::CtrlReq, CTRL_REQ_GO_IDLE);
+        Ok(response_code)
+    }
+}
+
+/// Returns the offset of a CRB buffer relative to `TPM_CRB_BASE`, ensuring that
+/// the buffer lies within the mapped register page.
+fn buffer_offset(address: u64, size: u32) -> Result<usize, &'static str> {
+    address
+        .checked_sub(TPM_CRB_BASE)
+        .filter(|offset| offset + size as u64 <= Size4KiB::SIZE)
+        .filter(|offset| offset % size_of::<u32>() as u64 == 0)
+        .map(|offset| offset as usize)
+        .ok_or("TPM CRB buffer is not within the CRB register page")
+}
+
+/// Builds a TPM2 command with the given tag, command code and parameters.
+fn command(tag: u16, command_code: u32, parameters: &[u8]) -> Vec<u8> {
+    let mut command = Vec::with_capacity(HEADER_SIZE + parameters.len());
+    command.extend_from_slice(&tag.to_be_bytes());
+    command.extend_from_slice(&((HEADER_SIZE + parameters.len()) as u32).to_be_bytes());
+    command.extend_from_slice(&command_code.to_be_bytes());
+    command.extend_from_slice(parameters);
+    command
+}
+
+/// Builds a TPM2_Startup(TPM_SU_CLEAR) command.
+fn startup_command() -> Vec<u8> {
+    command(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP, &TPM_SU_CLEAR.to_be_bytes())
+}
+
+/// Builds a TPM2_PCR_Extend command that extends the SHA2-256 and SHA2-384
+/// banks of a PCR, using an empty password authorization.
+fn pcr_extend_command(pcr: u32, digests: &Digests) -> Vec<u8> {
+    let mut parameters = Vec::new();
+    parameters.extend_from_slice(&pcr.to_be_bytes());
+    // Authorization area: TPM_RS_PW with an empty nonce, no attributes and an
+    // empty password.
+    let mut authorization = Vec::new();
+    authorization.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+    authorization.extend_from_slice(&0u16.to_be_bytes());
+    authorization.push(0);
+    authorization.extend_from_slice(&0u16.to_be_bytes());
+    parameters.extend_from_slice(&(authorization.len() as u32).to_be_bytes());
+    parameters.extend_from_slice(&authorization);
+    digests.extend_to(&mut parameters, u16::to_be_bytes, 2u32.to_be_bytes());
+    command(TPM_ST_SESSIONS, TPM_CC_PCR_EXTEND, &parameters)
+}
+
+/// Extracts the response code from a TPM2 response header.
+fn response_code(header: &[u8]) -> Result<u32, &'static str> {
+    if header.len() < HEADER_SIZE {
+        return Err("TPM response too short");
+    }
+    Ok(u32::from_be_bytes(header[6..10].try_into().unwrap()))
+}
+
+/// A crypto-agile TCG event log.
+///
+/// The log starts with a `TCG_PCClientPCREvent` structure describing the
+/// digest algorithms (the "Spec ID Event03" event), followed by a
+/// `TCG_PCR_EVENT2` structure for each measurement.
+struct EventLog<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> EventLog<'a> {
+    fn new(buf: &'a mut [u8]) -> Result<Self, &'static str> {
+        buf.fill(0);
+        let mut log = Self { buf, len: 0 };
+
+        let mut spec_id = Vec::new();
+        spec_id.extend_from_slice(b"Spec ID Event03\0");
+        // Platform class (client), spec version 2.0 errata 0, UINTN size of 64 bits.
+        spec_id.extend_from_slice(&0u32.to_le_bytes());
+        spec_id.extend_from_slice(&[0, 2, 0, 2]);
+        spec_id.extend_from_slice(&2u32.to_le_bytes());
+        for (algorithm, size) in [
+            (TPM_ALG_SHA256, size_of::<Measurement>()),
+            (TPM_ALG_SHA384, size_of::<Sha384Digest>()),
+        ] {
+            spec_id.extend_from_slice(&algorithm.to_le_bytes());
+            spec_id.extend_from_slice(&(size as u16).to_le_bytes());
+        }
+        // No vendor info.
+        spec_id.push(0);
+
+        let mut event = Vec::new();
+        event.extend_from_slice(&0u32.to_le_bytes());
+        event.extend_from_slice(&EV_NO_ACTION.to_le_bytes());
+        // The SHA1 digest field of the legacy event format is unused.
+        event.extend_from_slice(&[0; 20]);
+        event.extend_from_slice(&(spec_id.len() as u32).to_le_bytes());
+        event.extend_from_slice(&spec_id);
+        log.append(&event)?;
+        Ok(log)
+    }
+
+    fn record(
+        &mut self,
+        pcr: u32,
+        event_type: u32,
+        digests: &Digests,
+        event_data: &[u8],
+    ) -> Result<(), &'static str> {
+        let mut event = Vec::new();
+        event.extend_from_slice(&pcr.to_le_bytes());
+        event.extend_from_slice(&event_type.to_le_bytes());
+        digests.extend_to(&mut event, u16::to_le_bytes, 2u32.to_le_bytes());
+        event.extend_from_slice(&(event_data.len() as u32).to_le_bytes());
+        event.extend_from_slice(event_data);
+        self.append(&event)
+    }
+
+    fn append(&mut self, event: &[u8]) -> Result<(), &'static str> {
+        let end = self.len + event.len();
+        if end > self.buf.len() {
+            return Err("TPM event log is full");
+        }
+        self.buf[self.len..end].copy_from_slice(event);
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn startup_command_encoding() {
+        assert_eq!(
+            startup_command(),
+            [0x80, 0x01, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x01, 0x44, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn pcr_extend_command_encoding() {
+        let command = pcr_extend_command(8, &Digests::new(b"cmdline"));
+
+        // Header, PCR handle, authorization size and area, digest count and two
+        // tagged digests.
+        assert_eq!(command.len(), HEADER_SIZE + 4 + 4 + 9 + 4 + (2 + 32) + (2 + 48));
+        assert_eq!(u32::from_be_bytes(command[2..6].try_into().unwrap()) as usize, command.len());
+        assert_eq!(&command[10..14], &8u32.to_be_bytes());
+    }
+
+    #[test]
+    fn event_log_layout() {
+        let mut buf = vec![0xFFu8; 1024];
+        let mut log = EventLog::new(&mut buf).unwrap();
+        let spec_id_len = log.len;
+
+        log.record(PCR_BOOT_LOADER, EV_IPL, &Digests::new(b"kernel"), b"kernel").unwrap();
+
+        assert_eq!(&buf[32..48], b"Spec ID Event03\0");
+        let event = &buf[spec_id_len..];
+        assert_eq!(&event[0..4], &PCR_BOOT_LOADER.to_le_bytes());
+        assert_eq!(&event[8..12], &2u32.to_le_bytes());
+        assert_eq!(&event[12..14], &TPM_ALG_SHA256.to_le_bytes());
+        assert_eq!(&event[14 + 32 + 2 + 48..][..4], &6u32.to_le_bytes());
+    }
+
+    #[test]
+    fn event_log_full() {
+        let mut buf = vec![0u8; 128];
+        let mut log = EventLog::new(&mut buf).unwrap();
+
+        assert!(log.record(PCR_CMDLINE, EV_IPL, &Digests::new(b""), b"").is_err());
+    }
+
+    #[test]
+    fn buffer_must_be_in_register_page() {
+        assert_eq!(buffer_offset(TPM_CRB_BASE + 0x80, 0xF80), Ok(0x80));
+        assert!(buffer_offset(TPM_CRB_BASE + 0x80, 0x1000).is_err());
+        assert!(buffer_offset(0x1000, 0x80).is_err());
+    }
+}
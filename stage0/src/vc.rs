@@ -0,0 +1,312 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! #VC exception handler for SEV-ES and SEV-SNP.
+//!
+//! Under SEV-ES the CPU raises a VMM Communication Exception (#VC) for
+//! instructions that the hypervisor intercepts, such as CPUID, RDMSR and WRMSR.
+//! Instead of requiring every piece of code in stage0 to talk to the hypervisor
+//! via the GHCB explicitly, the handler decodes the intercepted instruction,
+//! emulates it and resumes execution after it:
+//!
+//! * CPUID is serviced from the SEV-SNP CPUID page if SEV-SNP is active, and
+//!   via the GHCB MSR protocol otherwise.
+//! * RDMSR and WRMSR are forwarded to the hypervisor via the GHCB.
+//!
+//! Anything else is logged and terminates the VM.
+//!
+//! We can't use the `x86-interrupt` calling convention, as we need to modify
+//! the general-purpose registers of the interrupted code. Stage0 is built
+//! without SSE, so we only need to save the general-purpose registers.
+
+use core::arch::asm;
+
+use log::error;
+use oak_sev_guest::{
+    cpuid::{CpuidInput, CpuidOutput, CpuidPage, CPUID_COUNT_MAX},
+    msr::{get_cpuid, CpuidRegister, CpuidRequest, SevStatus},
+};
+use x86_64::{structures::idt::InterruptDescriptorTable, VirtAddr};
+
+use crate::{sev::GHCB_WRAPPER, sev_status};
+
+/// The exit code for an intercepted CPUID instruction.
+const SVM_EXIT_CPUID: u64 = 0x72;
+
+/// The exit code for an intercepted RDMSR or WRMSR instruction.
+const SVM_EXIT_MSR: u64 = 0x7C;
+
+/// CPUID instruction opcode.
+const CPUID: [u8; 2] = [0x0F, 0xA2];
+
+/// RDMSR instruction opcode.
+const RDMSR: [u8; 2] = [0x0F, 0x32];
+
+/// WRMSR instruction opcode.
+const WRMSR: [u8; 2] = [0x0F, 0x30];
+
+/// CPUID leaves whose output depends on the sub-leaf in ECX. For all other
+/// leaves the value of ECX is ignored.
+const INDEXED_CPUID_LEAVES: &[u32] =
+    &[0x4, 0x7, 0xB, 0xD, 0xF, 0x10, 0x14, 0x8000_001D, 0x8000_0020, 0x8000_0026];
+
+/// The register state of the interrupted code, as saved by the #VC entry code.
+///
+/// The field order is the reverse of the order in which the registers are
+/// pushed, followed by the error code and the interrupt stack frame pushed by
+/// the CPU.
+#[repr(C)]
+#[derive(Debug)]
+pub struct VcStackFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// The error code, which contains the exit code of the intercepted
+    /// instruction.
+    pub error_code: u64,
+    /// Points to the instruction that caused the exception.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Installs the #VC handler in the IDT.
+pub fn init_idt(idt: &mut InterruptDescriptorTable) {
+    // Safety: the entry code follows the interrupt calling convention and returns
+    // via `iretq`.
+    unsafe {
+        idt.vmm_communication_exception.set_handler_addr(VirtAddr::new(vc_entry as usize as u64));
+    }
+}
+
+/// Entry point for the #VC exception.
+///
+/// Saves the general-purpose registers on the stack, calls `vc_handler` with a
+/// pointer to the saved state, restores the (potentially modified) registers
+/// and returns to the interrupted code.
+#[naked]
+unsafe extern "sysv64" fn vc_entry() -> ! {
+    asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // The first argument to the handler is a pointer to the saved state.
+        "mov rdi, rsp",
+        // The CPU aligns the stack to 16 bytes before pushing the 6-quadword interrupt
+        // frame; we've pushed 15 more quadwords, so we need to re-align the stack.
+        "sub rsp, 8",
+        "call {HANDLER}",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // Drop the error code.
+        "add rsp, 8",
+        "iretq",
+        HANDLER = sym vc_handler,
+        options(noreturn)
+    );
+}
+
+extern "sysv64" fn vc_handler(frame: &mut VcStackFrame) {
+    // Safety: RIP points to the instruction that caused the exception, which is
+    // mapped as we just tried to execute it. All the instructions we emulate are 2
+    // bytes long.
+    let opcode = unsafe { *(frame.rip as *const [u8; 2]) };
+
+    let result = match (frame.error_code, opcode) {
+        (SVM_EXIT_CPUID, CPUID) => handle_cpuid(frame),
+        (SVM_EXIT_MSR, RDMSR) => handle_rdmsr(frame),
+        (SVM_EXIT_MSR, WRMSR) => handle_wrmsr(frame),
+        _ => Err("unsupported #VC exception"),
+    };
+
+    match result {
+        Ok(()) => frame.rip += opcode.len() as u64,
+        Err(err) => {
+            error!("EXCEPTION: VMM COMMUNICATION EXCEPTION: {}", err);
+            error!("Exit code: {:#x}", frame.error_code);
+            error!("Instruction bytes: {:02x?}", opcode);
+            error!("{:#x?}", frame);
+            crate::exceptions::terminate();
+        }
+    }
+}
+
+fn handle_cpuid(frame: &mut VcStackFrame) -> Result<(), &'static str> {
+    let leaf = frame.rax as u32;
+    let sub_leaf = frame.rcx as u32;
+    let output = if sev_status().contains(SevStatus::SNP_ACTIVE) {
+        // Safety: the CPUID page is populated by the Secure Processor when SEV-SNP is
+        // active.
+        let cpuid_page = unsafe { crate::SEV_CPUID.assume_init_ref() };
+        lookup_cpuid(cpuid_page, leaf, sub_leaf)?
+    } else {
+        // The GHCB MSR protocol does not support sub-leaves or leaf 0xD.
+        if (INDEXED_CPUID_LEAVES.contains(&leaf) && sub_leaf != 0) || leaf == 0xD {
+            return Err("CPUID leaf not supported by the GHCB MSR protocol");
+        }
+        let register = |register| get_cpuid(CpuidRequest { leaf, register }).map(|r| r.value);
+        CpuidOutput {
+            eax: register(CpuidRegister::Eax)?,
+            ebx: register(CpuidRegister::Ebx)?,
+            ecx: register(CpuidRegister::Ecx)?,
+            edx: register(CpuidRegister::Edx)?,
+        }
+    };
+    frame.rax = output.eax as u64;
+    frame.rbx = output.ebx as u64;
+    frame.rcx = output.ecx as u64;
+    frame.rdx = output.edx as u64;
+    Ok(())
+}
+
+/// Looks up the result of CPUID in the SEV-SNP CPUID page.
+///
+/// Leaves beyond the maximum leaf of their range return all zeros, as they
+/// would on real hardware.
+fn lookup_cpuid(
+    cpuid_page: &CpuidPage,
+    leaf: u32,
+    sub_leaf: u32,
+) -> Result<CpuidOutput, &'static str> {
+    let sub_leaf = if INDEXED_CPUID_LEAVES.contains(&leaf) { sub_leaf } else { 0 };
+    // Stage0 doesn't enable XSAVE, so XCR0 has its reset value.
+    let xcr0 = if leaf == 0xD { 0x1 } else { 0 };
+    let target = CpuidInput { eax: leaf, ecx: sub_leaf, xcr0, xss: 0 };
+    let entries = &cpuid_page.cpuid_data[..(cpuid_page.count as usize).min(CPUID_COUNT_MAX)];
+    let find = |input: &CpuidInput| entries.iter().find(|entry| entry.input == *input);
+
+    if let Some(entry) = find(&target) {
+        return Ok(CpuidOutput {
+            eax: entry.output.eax,
+            ebx: entry.output.ebx,
+            ecx: entry.output.ecx,
+            edx: entry.output.edx,
+        });
+    }
+
+    // Leaf 0 of each range reports the maximum leaf in the range in EAX.
+    let range = CpuidInput { eax: leaf & 0xFFFF_0000, ecx: 0, xcr0: 0, xss: 0 };
+    match find(&range) {
+        Some(entry) if leaf > entry.output.eax => {
+            Ok(CpuidOutput { eax: 0, ebx: 0, ecx: 0, edx: 0 })
+        }
+        _ => Err("requested CPUID leaf not present in CPUID page"),
+    }
+}
+
+fn handle_rdmsr(frame: &mut VcStackFrame) -> Result<(), &'static str> {
+    let value = GHCB_WRAPPER
+        .get()
+        .ok_or("GHCB not initialized")?
+        .try_lock()
+        .ok_or("GHCB already in use")?
+        .msr_read(frame.rcx as u32)?;
+    frame.rax = value & 0xFFFF_FFFF;
+    frame.rdx = value >> 32;
+    Ok(())
+}
+
+fn handle_wrmsr(frame: &mut VcStackFrame) -> Result<(), &'static str> {
+    let value = (frame.rdx << 32) | (frame.rax & 0xFFFF_FFFF);
+    GHCB_WRAPPER
+        .get()
+        .ok_or("GHCB not initialized")?
+        .try_lock()
+        .ok_or("GHCB already in use")?
+        .msr_write(frame.rcx as u32, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use oak_sev_guest::cpuid::CpuidFunction;
+    use zerocopy::FromZeroes;
+
+    use super::*;
+
+    fn create_cpuid_page(entries: &[(u32, u32, u32)]) -> CpuidPage {
+        let mut page = CpuidPage::new_zeroed();
+        for (function, (eax, ecx, output)) in page.cpuid_data.iter_mut().zip(entries) {
+            *function = CpuidFunction::new_zeroed();
+            function.input.eax = *eax;
+            function.input.ecx = *ecx;
+            function.output.eax = *output;
+        }
+        page.count = entries.len() as u32;
+        page
+    }
+
+    #[test]
+    fn lookup_ignores_sub_leaf_for_non_indexed_leaves() {
+        let page = create_cpuid_page(&[(0x0, 0, 0x10), (0x1, 0, 0x42)]);
+
+        assert_eq!(lookup_cpuid(&page, 0x1, 0x1234).unwrap().eax, 0x42);
+    }
+
+    #[test]
+    fn lookup_uses_sub_leaf_for_indexed_leaves() {
+        let page = create_cpuid_page(&[(0x0, 0, 0x10), (0x7, 0, 0x1), (0x7, 1, 0x2)]);
+
+        assert_eq!(lookup_cpuid(&page, 0x7, 1).unwrap().eax, 0x2);
+    }
+
+    #[test]
+    fn lookup_out_of_range_leaf() {
+        let page = create_cpuid_page(&[(0x0, 0, 0x10), (0x8000_0000, 0, 0x8000_0008)]);
+
+        assert_eq!(lookup_cpuid(&page, 0x8000_0020, 0).unwrap().eax, 0);
+        assert!(lookup_cpuid(&page, 0x5, 0).is_err());
+    }
+}
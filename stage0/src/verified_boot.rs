@@ -0,0 +1,178 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Optional verified boot.
+//!
+//! If a verifying key is configured, stage0 refuses to boot unless the VMM
+//! provides a valid ECDSA P-256 signature over the kernel image, the kernel
+//! command-line and the initial RAM disk.
+//!
+//! The key can either be embedded into the firmware at build time (via the
+//! `OAK_STAGE0_VERIFIED_BOOT_KEY` environment variable, as a hex-encoded SEC1
+//! public key), in which case it is covered by the launch measurement, or be
+//! provided by the VMM in the `opt/stage0/verified_boot_key` fw_cfg file. An
+//! embedded key always takes precedence.
+//!
+//! The signature is read from the `opt/stage0/verified_boot_signature` fw_cfg
+//! file as a 64-byte fixed-size (r || s) ECDSA signature. The signed message is
+//! the concatenation of the SHA2-384 digests of the kernel image, the
+//! command-line and the initial RAM disk (all zeros if there is none).
+
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+
+use crate::{
+    fw_cfg::FwCfg,
+    measurement::{Sha384Digest, SHA2_384_DIGEST_SIZE},
+};
+
+/// Hex-encoded verifying key embedded at build time, if any.
+const EMBEDDED_KEY: Option<&str> = option_env!("OAK_STAGE0_VERIFIED_BOOT_KEY");
+
+/// The fw_cfg file that contains the SEC1-encoded verifying key.
+const KEY_FILE_PATH: &[u8] = b"opt/stage0/verified_boot_key\0";
+
+/// The fw_cfg file that contains the signature.
+const SIGNATURE_FILE_PATH: &[u8] = b"opt/stage0/verified_boot_signature\0";
+
+/// Upper bound for the size of the key and signature files; an uncompressed
+/// SEC1 P-256 key is 65 bytes and a fixed-size signature is 64 bytes.
+const MAX_FILE_SIZE: usize = 128;
+
+/// The digests of the components covered by the signature.
+pub struct SignedComponents {
+    pub kernel: Sha384Digest,
+    pub cmdline: Sha384Digest,
+    pub initial_ram_disk: Option<Sha384Digest>,
+}
+
+impl SignedComponents {
+    /// Returns the message that the signature is expected to cover.
+    fn message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(3 * SHA2_384_DIGEST_SIZE);
+        message.extend_from_slice(&self.kernel);
+        message.extend_from_slice(&self.cmdline);
+        message.extend_from_slice(&self.initial_ram_disk.unwrap_or([0; SHA2_384_DIGEST_SIZE]));
+        message
+    }
+}
+
+/// Returns the verifying key, or `None` if verified boot is not enabled.
+pub fn verifying_key(fw_cfg: &mut FwCfg) -> Result<Option<VerifyingKey>, &'static str> {
+    if let Some(key) = EMBEDDED_KEY {
+        let key = hex::decode(key.trim()).map_err(|_| "embedded verified boot key is not hex")?;
+        return parse_key(&key).map(Some);
+    }
+    match read_file(fw_cfg, KEY_FILE_PATH)? {
+        Some(key) => parse_key(&key).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Verifies the signature provided by the VMM over the boot components.
+pub fn verify(
+    fw_cfg: &mut FwCfg,
+    key: &VerifyingKey,
+    components: &SignedComponents,
+) -> Result<(), &'static str> {
+    let signature =
+        read_file(fw_cfg, SIGNATURE_FILE_PATH)?.ok_or("verified boot signature not found")?;
+    verify_signature(key, components, &signature)
+}
+
+fn verify_signature(
+    key: &VerifyingKey,
+    components: &SignedComponents,
+    signature: &[u8],
+) -> Result<(), &'static str> {
+    let signature =
+        Signature::from_slice(signature).map_err(|_| "invalid verified boot signature")?;
+    key.verify(&components.message(), &signature)
+        .map_err(|_| "verified boot signature verification failed")
+}
+
+fn parse_key(key: &[u8]) -> Result<VerifyingKey, &'static str> {
+    VerifyingKey::from_sec1_bytes(key).map_err(|_| "invalid verified boot key")
+}
+
+/// Reads a small fw_cfg file, returning `None` if the file doesn't exist.
+fn read_file(fw_cfg: &mut FwCfg, path: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+    let path = CStr::from_bytes_with_nul(path).expect("invalid c-string");
+    let Some(file) = fw_cfg.find(path) else {
+        return Ok(None);
+    };
+    if file.size() > MAX_FILE_SIZE {
+        return Err("verified boot file too large");
+    }
+    fw_cfg.read_file_vec(&file).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    use super::*;
+    use crate::measurement::sha2_384;
+
+    fn components() -> SignedComponents {
+        SignedComponents {
+            kernel: sha2_384(b"kernel"),
+            cmdline: sha2_384(b"console=ttyS0"),
+            initial_ram_disk: None,
+        }
+    }
+
+    fn sign(components: &SignedComponents) -> (VerifyingKey, Vec<u8>) {
+        let signing_key = SigningKey::from_slice(&[0x42; 32]).unwrap();
+        let signature: Signature = signing_key.sign(&components.message());
+        (*signing_key.verifying_key(), signature.to_bytes().to_vec())
+    }
+
+    #[test]
+    fn valid_signature() {
+        let (key, signature) = sign(&components());
+
+        assert_eq!(verify_signature(&key, &components(), &signature), Ok(()));
+    }
+
+    #[test]
+    fn signature_over_different_cmdline() {
+        let (key, signature) = sign(&components());
+        let mut components = components();
+        components.cmdline = sha2_384(b"console=ttyS0 init=/bin/sh");
+
+        assert!(verify_signature(&key, &components, &signature).is_err());
+    }
+
+    #[test]
+    fn signature_covers_initial_ram_disk() {
+        let (key, signature) = sign(&components());
+        let mut components = components();
+        components.initial_ram_disk = Some(sha2_384(b"initrd"));
+
+        assert!(verify_signature(&key, &components, &signature).is_err());
+    }
+
+    #[test]
+    fn parse_sec1_key() {
+        let (key, _) = sign(&components());
+
+        assert_eq!(parse_key(key.to_encoded_point(false).as_bytes()), Ok(key));
+        assert!(parse_key(&[0; 65]).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Minimal output-only driver for a virtio console device using the virtio-mmio
+//! transport.
+//!
+//! Only the modern (version 2) virtio-mmio interface is supported. The driver
+//! sets up the transmit queue of the first port and sends one buffer at a
+//! time, waiting for the device to consume it before returning.
+//!
+//! See <https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html> for
+//! the details of the transport and the device.
+
+use core::{
+    ffi::CStr,
+    fmt::Write,
+    mem::{size_of, MaybeUninit},
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, Ordering},
+};
+
+use bitflags::bitflags;
+use x86_64::{
+    instructions::tlb::flush_all,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    fw_cfg::FwCfg,
+    paging::PAGE_TABLE_REFS,
+    sev::{Shared, GHCB_WRAPPER},
+    BootAllocator, BOOT_ALLOC,
+};
+
+/// The fw_cfg file that contains the physical address of the virtio-mmio
+/// device, formatted as a hexadecimal string (e.g. "0xfeb00000").
+const VIRTIO_CONSOLE_FILE_PATH: &[u8] = b"opt/stage0/virtio_console\0";
+
+/// Magic value ("virt") at the start of the virtio-mmio register area.
+const VIRTIO_MMIO_MAGIC: u32 = 0x74726976;
+
+/// Version of the modern virtio-mmio interface.
+const VIRTIO_MMIO_VERSION: u32 = 2;
+
+/// Device ID of a virtio console.
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+/// VIRTIO_F_VERSION_1, in the upper 32 bits of the feature bits.
+const VIRTIO_F_VERSION_1_HIGH: u32 = 1 << 0;
+
+/// Index of the transmit queue of the first port.
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// Number of entries in the transmit queue.
+const QUEUE_SIZE: usize = 4;
+
+/// Size of the buffer we copy the output into.
+const BUFFER_SIZE: usize = 2048;
+
+/// How often we poll the used ring before giving up on the device.
+const MAX_POLLS: usize = 1_000_000;
+
+/// Offsets of the virtio-mmio registers.
+#[repr(usize)]
+#[derive(Clone, Copy)]
+enum Register {
+    MagicValue = 0x000,
+    Version = 0x004,
+    DeviceId = 0x008,
+    DeviceFeatures = 0x010,
+    DeviceFeaturesSel = 0x014,
+    DriverFeatures = 0x020,
+    DriverFeaturesSel = 0x024,
+    QueueSel = 0x030,
+    QueueNumMax = 0x034,
+    QueueNum = 0x038,
+    QueueReady = 0x044,
+    QueueNotify = 0x050,
+    Status = 0x070,
+    QueueDescLow = 0x080,
+    QueueDescHigh = 0x084,
+    QueueDriverLow = 0x090,
+    QueueDriverHigh = 0x094,
+    QueueDeviceLow = 0x0A0,
+    QueueDeviceHigh = 0x0A4,
+}
+
+bitflags! {
+    /// Bits in the device status register.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct DeviceStatus: u32 {
+        const ACKNOWLEDGE = 1;
+        const DRIVER = 2;
+        const DRIVER_OK = 4;
+        const FEATURES_OK = 8;
+    }
+}
+
+/// The 4KiB page that we map to the virtio-mmio register area.
+#[repr(C, align(4096))]
+struct MmioRegisters {
+    registers: [u32; 1024],
+}
+static_assertions::assert_eq_size!(MmioRegisters, [u8; Size4KiB::SIZE as usize]);
+
+// Reserve a 4K chunk of memory that we can remap to the device registers.
+static mut VIRTIO_MMIO_AREA: MaybeUninit<MmioRegisters> = MaybeUninit::uninit();
+
+/// Access to the virtio-mmio registers, either directly or via the GHCB MMIO
+/// protocol if we're running under SEV-ES or SEV-SNP.
+struct Mmio {
+    registers: &'static mut MmioRegisters,
+    /// Physical address of the device registers.
+    base: PhysAddr,
+    /// Offset of the device registers within the mapped page, as virtio-mmio
+    /// devices are not necessarily page-aligned.
+    page_offset: usize,
+}
+
+impl Mmio {
+    fn new(base: PhysAddr) -> Result<Self, &'static str> {
+        let page = base.align_down(Size4KiB::SIZE);
+        let page_offset = (base - page) as usize;
+        // Safety: we're not dereferencing the pointer, we just want to know where it
+        // landed in virtual memory.
+        let vaddr = VirtAddr::from_ptr(unsafe { VIRTIO_MMIO_AREA.as_ptr() });
+        if vaddr.as_u64() > Size2MiB::SIZE {
+            return Err("VIRTIO_MMIO_AREA virtual address does not land in the first page table");
+        }
+        let mut tables = PAGE_TABLE_REFS.get().unwrap().lock();
+        tables.pt_0[vaddr.p1_index()].set_addr(
+            page,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE,
+        );
+        flush_all();
+        // Safety: we've mapped VIRTIO_MMIO_AREA to the device registers.
+        Ok(Self { registers: unsafe { VIRTIO_MMIO_AREA.assume_init_mut() }, base, page_offset })
+    }
+
+    fn read(&self, register: Register) -> u32 {
+        let offset = register as usize;
+        if let Some(ghcb) = GHCB_WRAPPER.get() {
+            ghcb.lock()
+                .mmio_read_u32(self.base + offset)
+                .expect("couldn't read the virtio-mmio register using the GHCB protocol")
+        } else {
+            // Safety: the register area is mapped to the device.
+            unsafe {
+                (&self.registers.registers[(self.page_offset + offset) / size_of::<u32>()]
+                    as *const u32)
+                    .read_volatile()
+            }
+        }
+    }
+
+    fn write(&mut self, register: Register, value: u32) {
+        let offset = register as usize;
+        if let Some(ghcb) = GHCB_WRAPPER.get() {
+            ghcb.lock()
+                .mmio_write_u32(self.base + offset, value)
+                .expect("couldn't write the virtio-mmio register using the GHCB protocol")
+        } else {
+            // Safety: the register area is mapped to the device.
+            unsafe {
+                (&mut self.registers.registers[(self.page_offset + offset) / size_of::<u32>()]
+                    as *mut u32)
+                    .write_volatile(value)
+            }
+        }
+    }
+
+    fn write_u64(&mut self, low: Register, high: Register, value: u64) {
+        self.write(low, value as u32);
+        self.write(high, (value >> 32) as u32);
+    }
+}
+
+/// A descriptor in the descriptor table of a split virtqueue.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// The driver area (available ring) of a split virtqueue.
+#[repr(C)]
+#[derive(Default)]
+struct AvailableRing {
+    flags: u16,
+    index: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+/// An element in the used ring.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UsedElement {
+    id: u32,
+    length: u32,
+}
+
+/// The device area (used ring) of a split virtqueue.
+#[repr(C)]
+#[derive(Default)]
+struct UsedRing {
+    flags: u16,
+    index: u16,
+    ring: [UsedElement; QUEUE_SIZE],
+    available_event: u16,
+}
+
+/// The transmit queue and the buffer it points to. This needs to be shared with
+/// the hypervisor, so it must fit into a single page.
+#[repr(C, align(4096))]
+struct TransmitQueue {
+    descriptors: [Descriptor; QUEUE_SIZE],
+    available: AvailableRing,
+    used: UsedRing,
+    buffer: [u8; BUFFER_SIZE],
+}
+static_assertions::const_assert!(size_of::<TransmitQueue>() == Size4KiB::SIZE as usize);
+
+impl Default for TransmitQueue {
+    fn default() -> Self {
+        Self {
+            descriptors: Default::default(),
+            available: Default::default(),
+            used: Default::default(),
+            buffer: [0; BUFFER_SIZE],
+        }
+    }
+}
+
+/// A virtio console device.
+pub struct VirtioConsole {
+    mmio: Mmio,
+    queue: Shared<TransmitQueue, &'static BootAllocator>,
+}
+
+// Safety: the MMIO area and the queue are only ever accessed through the
+// `VirtioConsole`, which is protected by a lock in the logging module.
+unsafe impl Send for VirtioConsole {}
+
+impl VirtioConsole {
+    /// Initializes the virtio console device at the given virtio-mmio address.
+    pub fn new(base: PhysAddr) -> Result<Self, &'static str> {
+        let mut mmio = Mmio::new(base)?;
+        if mmio.read(Register::MagicValue) != VIRTIO_MMIO_MAGIC {
+            return Err("no virtio-mmio device found");
+        }
+        if mmio.read(Register::Version) != VIRTIO_MMIO_VERSION {
+            return Err("unsupported virtio-mmio version");
+        }
+        if mmio.read(Register::DeviceId) != VIRTIO_ID_CONSOLE {
+            return Err("virtio-mmio device is not a console");
+        }
+
+        // Reset the device and tell it that we know how to drive it.
+        mmio.write(Register::Status, 0);
+        let mut status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER;
+        mmio.write(Register::Status, status.bits());
+
+        // We don't need any device-specific features, but we require a modern device.
+        mmio.write(Register::DeviceFeaturesSel, 1);
+        if mmio.read(Register::DeviceFeatures) & VIRTIO_F_VERSION_1_HIGH == 0 {
+            return Err("virtio console device does not support VIRTIO_F_VERSION_1");
+        }
+        mmio.write(Register::DriverFeaturesSel, 1);
+        mmio.write(Register::DriverFeatures, VIRTIO_F_VERSION_1_HIGH);
+        mmio.write(Register::DriverFeaturesSel, 0);
+        mmio.write(Register::DriverFeatures, 0);
+        status |= DeviceStatus::FEATURES_OK;
+        mmio.write(Register::Status, status.bits());
+        if !DeviceStatus::from_bits_truncate(mmio.read(Register::Status))
+            .contains(DeviceStatus::FEATURES_OK)
+        {
+            return Err("virtio console device did not accept our features");
+        }
+
+        let queue = Shared::new_in(TransmitQueue::default(), &BOOT_ALLOC);
+        mmio.write(Register::QueueSel, TRANSMIT_QUEUE);
+        if mmio.read(Register::QueueReady) != 0 {
+            return Err("virtio console transmit queue is already in use");
+        }
+        if (mmio.read(Register::QueueNumMax) as usize) < QUEUE_SIZE {
+            return Err("virtio console transmit queue is too small");
+        }
+        mmio.write(Register::QueueNum, QUEUE_SIZE as u32);
+        // We use an identity mapping, so the virtual addresses are the physical
+        // addresses.
+        mmio.write_u64(
+            Register::QueueDescLow,
+            Register::QueueDescHigh,
+            queue.descriptors.as_ptr() as u64,
+        );
+        mmio.write_u64(
+            Register::QueueDriverLow,
+            Register::QueueDriverHigh,
+            &queue.available as *const AvailableRing as u64,
+        );
+        mmio.write_u64(
+            Register::QueueDeviceLow,
+            Register::QueueDeviceHigh,
+            &queue.used as *const UsedRing as u64,
+        );
+        mmio.write(Register::QueueReady, 1);
+
+        status |= DeviceStatus::DRIVER_OK;
+        mmio.write(Register::Status, status.bits());
+
+        Ok(Self { mmio, queue })
+    }
+
+    /// Sends a chunk of data that fits into the buffer to the device, and waits
+    /// until the device has consumed it.
+    fn send(&mut self, chunk: &[u8]) -> Result<(), &'static str> {
+        let queue = &mut *self.queue;
+        queue.buffer[..chunk.len()].copy_from_slice(chunk);
+        queue.descriptors[0] = Descriptor {
+            address: queue.buffer.as_ptr() as u64,
+            length: chunk.len() as u32,
+            flags: 0,
+            next: 0,
+        };
+
+        // Safety: the rings are valid memory that is shared with the device, so we use
+        // volatile accesses to make sure the compiler doesn't elide them.
+        unsafe {
+            let index = addr_of!(queue.available.index).read_volatile();
+            addr_of_mut!(queue.available.ring[index as usize % QUEUE_SIZE]).write_volatile(0);
+            fence(Ordering::SeqCst);
+            addr_of_mut!(queue.available.index).write_volatile(index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            self.mmio.write(Register::QueueNotify, TRANSMIT_QUEUE);
+
+            for _ in 0..MAX_POLLS {
+                if addr_of!(queue.used.index).read_volatile() == index.wrapping_add(1) {
+                    fence(Ordering::SeqCst);
+                    return Ok(());
+                }
+                core::hint::spin_loop();
+            }
+        }
+        Err("virtio console device did not consume the buffer")
+    }
+}
+
+impl Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for chunk in s.as_bytes().chunks(BUFFER_SIZE) {
+            self.send(chunk).map_err(|_| core::fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtioConsole {
+    fn drop(&mut self) {
+        // Reset the device so that it stops accessing the queue memory before we
+        // return it to the guest's private memory.
+        self.mmio.write(Register::Status, 0);
+    }
+}
+
+/// Reads the address of the virtio console device from the fw_cfg device, if
+/// one was configured.
+pub fn find_console_address(fw_cfg: &mut FwCfg) -> Option<PhysAddr> {
+    let path = CStr::from_bytes_with_nul(VIRTIO_CONSOLE_FILE_PATH).expect("invalid c-string");
+    let file = fw_cfg.find(path)?;
+    let mut buf = [0u8; 32];
+    let len = fw_cfg.read_file(&file, &mut buf).ok()?;
+    let address = parse_address(&buf[..len]);
+    if address.is_none() {
+        log::warn!("invalid virtio console address in fw_cfg");
+    }
+    address
+}
+
+/// Parses a physical address formatted as a hexadecimal string, with or
+/// without a "0x" prefix.
+fn parse_address(buf: &[u8]) -> Option<PhysAddr> {
+    let value =
+        core::str::from_utf8(buf).ok()?.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    let address = u64::from_str_radix(value, 16).ok()?;
+    PhysAddr::try_new(address).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_addresses() {
+        assert_eq!(parse_address(b"0xfeb00000"), Some(PhysAddr::new(0xfeb0_0000)));
+        assert_eq!(parse_address(b"FEB00200\n"), Some(PhysAddr::new(0xfeb0_0200)));
+        assert_eq!(parse_address(b"0xfeb00000\0"), Some(PhysAddr::new(0xfeb0_0000)));
+    }
+
+    #[test]
+    fn parse_invalid_addresses() {
+        assert_eq!(parse_address(b""), None);
+        assert_eq!(parse_address(b"console"), None);
+    }
+}
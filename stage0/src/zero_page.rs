@@ -15,15 +15,22 @@
 //
 
 use alloc::{ffi::CString, vec::Vec};
-use core::{ffi::CStr, mem::size_of, slice};
+use core::{
+    ffi::CStr,
+    mem::{size_of, size_of_val},
+    slice,
+};
 
-use oak_linux_boot_params::{BootE820Entry, BootParams, E820EntryType};
+use oak_linux_boot_params::{
+    BootE820Entry, BootParams, E820EntryType, SetupData, SetupHeader, XLoadFlags,
+};
+use oak_sev_guest::io::{PortReader, PortWriter};
 use x86_64::PhysAddr;
 use zerocopy::{AsBytes, FromBytes};
 
 use crate::{
     cmos::Cmos,
-    fw_cfg::{find_suitable_dma_address, FwCfg},
+    fw_cfg::{find_suitable_dma_address, FwCfg, FwCfgBackend},
     BOOT_ALLOC,
 };
 
@@ -111,12 +118,18 @@ impl ZeroPage {
         })
     }
 
+    /// Replaces the setup header with the header of a bzImage kernel that was
+    /// loaded by stage0.
+    pub fn set_setup_header(&mut self, hdr: &SetupHeader) {
+        self.inner.hdr = *hdr;
+    }
+
     /// Fills the E820 memory map (layout of the physical memory of the machine)
     /// in the zero page.
     ///
     /// We first try to read "etc/e820" via the QEMU fw_cfg interface, and if
     /// that is not available, fall back to querying RTC NVRAM.
-    pub fn fill_e820_table(&mut self, fw_cfg: &mut FwCfg) {
+    pub fn fill_e820_table<B: FwCfgBackend>(&mut self, fw_cfg: &mut FwCfg<B>) {
         // Try to load the E820 table from fw_cfg.
         // Safety: BootE820Entry has the same structure as what qemu uses, and we're
         // limiting ourselves to up to 128 entries.
@@ -141,7 +154,13 @@ impl ZeroPage {
                     panic!("QEMU_E820_RESERVATION_TABLE was not empty!");
                 }
 
-                build_e820_from_nvram(&mut self.inner.e820_table).expect("failed to read from CMOS")
+                // Safety: (a) fw_cfg is available, so we're running under QEMU(ish) and (b)
+                // there was no pre-built E820 table in fw_cfg; thus, we can reasonably
+                // expect CMOS to available, as that's what SeaBIOS would use in that
+                // situation to build the E820 table.
+                let mut cmos = unsafe { Cmos::new() };
+                build_e820_from_nvram(&mut cmos, &mut self.inner.e820_table)
+                    .expect("failed to read from CMOS")
             }
         };
 
@@ -286,6 +305,51 @@ impl ZeroPage {
         }
     }
 
+    /// Registers a region of memory that must not be handed to the operating
+    /// system as usable RAM, such as data that needs to outlive stage0.
+    ///
+    /// The region is carved out of any RAM entries it overlaps with and merged
+    /// with adjacent entries of the same type. `entry_type` must not be `RAM`,
+    /// and the region must not overlap with an entry of another non-RAM type,
+    /// as that would silently change how the operating system treats that
+    /// entry.
+    pub fn reserve_region(
+        &mut self,
+        addr: usize,
+        size: usize,
+        entry_type: E820EntryType,
+    ) -> Result<(), &'static str> {
+        if entry_type == E820EntryType::RAM || entry_type == E820EntryType::INVALID {
+            return Err("invalid entry type for a reserved region");
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        let end = addr.checked_add(size).ok_or("reserved region overflows")?;
+        if self.e820_table().iter().any(|entry| {
+            entry.addr() < end
+                && addr < entry.end()
+                && entry.entry_type() != Some(E820EntryType::RAM)
+                && entry.entry_type() != Some(entry_type)
+        }) {
+            return Err("reserved region overlaps with an entry of a different type");
+        }
+        self.insert_e820_entry(BootE820Entry::new(addr, size, entry_type));
+        Ok(())
+    }
+
+    /// Marks the memory backing `data` as reserved in the E820 table.
+    ///
+    /// This is a convenience wrapper around `reserve_region` for data that is
+    /// passed on to the kernel, e.g. structures leaked from the boot allocator.
+    pub fn reserve<T: ?Sized>(&mut self, data: &T) -> Result<(), &'static str> {
+        self.reserve_region(
+            data as *const T as *const u8 as usize,
+            size_of_val(data),
+            E820EntryType::RESERVED,
+        )
+    }
+
     fn validate_e820_table(&self) {
         // Check that the table is sorted.
         for i in 0..((self.inner.e820_entries - 1) as usize) {
@@ -316,6 +380,7 @@ impl ZeroPage {
         buf.resize(buf.capacity(), 0u8);
         buf.copy_from_slice(source);
         let buf = buf.leak();
+        self.reserve(buf).expect("failed to reserve the kernel command-line");
         self.inner.hdr.cmd_line_ptr = buf.as_ptr() as u32;
         // As per the Linux boot protocol `cmdline_size` does not include the trailing
         // \0.
@@ -327,11 +392,22 @@ impl ZeroPage {
     /// `setup_data` needs to be mutable because underneath the covers it's a
     /// C-style linked list, and we need to assign the pointer to the next
     /// value in the list to the `next` field in its header.
-    pub fn add_setup_data(&mut self, setup_data: &mut oak_linux_boot_params::CCSetupData) {
+    ///
+    /// The header needs to be followed by the data for the setup data entry in
+    /// memory, and has to outlive Stage 0.
+    pub fn add_setup_data(&mut self, header: &'static mut SetupData) {
         // Put our header as the first element in the linked list.
-        setup_data.header.next = self.inner.hdr.setup_data();
-        self.inner.hdr.setup_data =
-            &setup_data.header as *const oak_linux_boot_params::SetupData as u64;
+        header.next = self.inner.hdr.setup_data();
+        self.inner.hdr.setup_data = header as *const SetupData as u64;
+    }
+
+    /// Returns the highest address the kernel accepts for the initial RAM disk,
+    /// if the kernel specified it in its setup header.
+    pub fn initrd_addr_max(&self) -> Option<u32> {
+        match self.inner.hdr.initrd_addr_max {
+            0 => None,
+            max => Some(max),
+        }
     }
 
     /// Sets the address and size of the initial RAM disk.
@@ -344,6 +420,29 @@ impl ZeroPage {
         // maximum of 1GiB of RAM.
         self.inner.hdr.ramdisk_size = ram_disk.len() as u32;
     }
+
+    /// Returns the address and size of the initial RAM disk, if one was set.
+    pub fn initial_ram_disk(&self) -> Option<(PhysAddr, usize)> {
+        match self.inner.hdr.ramdisk_size {
+            0 => None,
+            size => Some((PhysAddr::new(self.inner.hdr.ramdisk_image as u64), size as usize)),
+        }
+    }
+
+    /// Returns the extended boot protocol flags of the kernel.
+    pub fn x_load_flags(&self) -> XLoadFlags {
+        self.inner.hdr.x_load_flags().unwrap_or(XLoadFlags::empty())
+    }
+
+    /// Returns the physical address of the null-terminated kernel command-line.
+    pub fn cmdline_addr(&self) -> PhysAddr {
+        PhysAddr::new(self.inner.hdr.cmd_line_ptr as u64)
+    }
+
+    /// Returns the physical address of the ACPI RSDP table.
+    pub fn acpi_rsdp_addr(&self) -> PhysAddr {
+        PhysAddr::new(self.inner.acpi_rsdp_addr)
+    }
 }
 
 /// Builds an E820 table by reading the low and high memory amount from CMOS.
@@ -351,12 +450,10 @@ impl ZeroPage {
 /// The code is largely based on what SeaBIOS is doing (see `qemu_preinit()` and
 /// `qemu_cfg_e820()` in <https://github.com/qemu/seabios/blob/b0d61ecef66eb05bd7a4eb7ada88ec5dab06dfee/src/fw/paravirt.c>),
 /// but <https://wiki.osdev.org/Detecting_Memory_%28x86%29> is also a good read on the topic.
-fn build_e820_from_nvram(e820_table: &mut [BootE820Entry]) -> Result<usize, &'static str> {
-    // Safety: (a) fw_cfg is available, so we're running under QEMU(ish) and (b)
-    // there was no pre-built E820 table in fw_cfg; thus, we can reasonably
-    // expect CMOS to available, as that's what SeaBIOS would use in that
-    // situation to build the E820 table.
-    let mut cmos = unsafe { Cmos::new() };
+fn build_e820_from_nvram<W: PortWriter<u8>, R: PortReader<u8>>(
+    cmos: &mut Cmos<W, R>,
+    e820_table: &mut [BootE820Entry],
+) -> Result<usize, &'static str> {
     let mut rs = cmos.low_ram_size()?;
     let high = cmos.high_ram_size()?;
 
@@ -389,6 +486,7 @@ fn build_e820_from_nvram(e820_table: &mut [BootE820Entry]) -> Result<usize, &'st
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{FakeFwCfg, FakeNvram};
 
     #[test]
     pub fn insert_e820_entry_empty_table() {
@@ -573,4 +671,74 @@ mod tests {
 
         assert_eq!(zero_page.e820_table(), &expected[..]);
     }
+
+    #[test]
+    pub fn reserve_region_carves_out_ram() {
+        let expected = [
+            BootE820Entry::new(0, 100, E820EntryType::RAM),
+            BootE820Entry::new(100, 150, E820EntryType::RESERVED),
+            BootE820Entry::new(250, 50, E820EntryType::RAM),
+        ];
+        let mut zero_page = ZeroPage::new();
+        zero_page.inner.append_e820_entry(BootE820Entry::new(0, 300, E820EntryType::RAM));
+
+        assert_eq!(zero_page.reserve_region(100, 100, E820EntryType::RESERVED), Ok(()));
+        assert_eq!(zero_page.reserve_region(200, 50, E820EntryType::RESERVED), Ok(()));
+
+        assert_eq!(zero_page.e820_table(), &expected[..]);
+    }
+
+    #[test]
+    pub fn reserve_region_rejects_conflicts() {
+        let expected = [
+            BootE820Entry::new(0, 100, E820EntryType::RAM),
+            BootE820Entry::new(100, 100, E820EntryType::ACPI),
+        ];
+        let mut zero_page = ZeroPage::new();
+        zero_page.inner.append_e820_entry(BootE820Entry::new(0, 100, E820EntryType::RAM));
+        zero_page.inner.append_e820_entry(BootE820Entry::new(100, 100, E820EntryType::ACPI));
+
+        assert!(zero_page.reserve_region(50, 100, E820EntryType::RESERVED).is_err());
+        assert!(zero_page.reserve_region(0, 50, E820EntryType::RAM).is_err());
+
+        assert_eq!(zero_page.e820_table(), &expected[..]);
+    }
+
+    #[test]
+    pub fn fill_e820_table_from_fw_cfg() {
+        let expected = [
+            BootE820Entry::new(0, 0x8_0000, E820EntryType::RAM),
+            BootE820Entry::new(0x8_0000, 0x2_0000, E820EntryType::ACPI),
+            BootE820Entry::new(0x10_0000, 0x7FF0_0000, E820EntryType::RAM),
+            BootE820Entry::new(0xFEFF_C000, 0x4000, E820EntryType::RESERVED),
+        ];
+        // QEMU doesn't necessarily provide the entries in order.
+        let e820 = [
+            BootE820Entry::new(0xFEFF_C000, 0x4000, E820EntryType::RESERVED),
+            BootE820Entry::new(0, 0x8000_0000, E820EntryType::RAM),
+        ];
+        let mut fw_cfg = FakeFwCfg::default().with_file("etc/e820", e820.as_bytes()).build();
+        let mut zero_page = ZeroPage::new();
+
+        zero_page.fill_e820_table(&mut fw_cfg);
+
+        assert_eq!(zero_page.e820_table(), &expected[..]);
+    }
+
+    #[test]
+    pub fn build_e820_from_cmos() {
+        let expected = [
+            BootE820Entry::new(0, 0x1100_0000, E820EntryType::RAM),
+            BootE820Entry::new(0xFFFB_C000, 0x4_4000, E820EntryType::RESERVED),
+            BootE820Entry::new(0x1_0000_0000, 0x1_0000_0000, E820EntryType::RAM),
+        ];
+        // 256MiB above 16MiB, and 4GiB above 4GiB.
+        let nvram = FakeNvram::default().with_register(0x35, 0x10).with_register(0x5d, 0x01);
+        // Safety: the fake NVRAM doesn't touch any hardware.
+        let mut cmos = unsafe { Cmos::with_ports(nvram.clone(), nvram) };
+        let mut e820_table = [BootE820Entry::new(0, 0, E820EntryType::INVALID); 3];
+
+        assert_eq!(build_e820_from_nvram(&mut cmos, &mut e820_table), Ok(3));
+        assert_eq!(e820_table, expected);
+    }
 }
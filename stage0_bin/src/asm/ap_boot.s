@@ -6,9 +6,40 @@
 ap_start:
     # Let the BSP know we're alive.
     lock incl (LIVE_AP_COUNT)
+    # Determine our local APIC ID so that we know when a mailbox command is addressed to us.
+    # Prefer the x2APIC ID (CPUID Fn0000_000B EDX) if that leaf is available, and fall back to the
+    # initial APIC ID (CPUID Fn0000_0001 EBX[31:24]) otherwise.
+    xor %eax, %eax          # EAX = 0x0 - Largest Standard Function Number
+    cpuid                   # EAX, EBX, ECX, EDX = CPUID(EAX)
+    cmp $0xB, %eax          # is the extended topology leaf available?
+    jb 1f                   # No. Use the initial APIC ID.
+    mov $0xB, %eax          # EAX = 0xB - Extended Topology Enumeration
+    xor %ecx, %ecx          # ECX = 0x0
+    cpuid
+    mov %edx, %esi          # ESI = x2APIC ID
+    jmp 2f
 1:
-    hlt
-    jmp 1b
+    mov $0x1, %eax          # EAX = 0x1 - Processor and Processor Feature Identifiers
+    cpuid
+    shr $24, %ebx           # EBX = EBX[31:24]
+    mov %ebx, %esi          # ESI = initial APIC ID
+2:
+    # Park in the wakeup mailbox until we get a Wakeup command addressed to us. See `ApWakeupMailbox`
+    # in stage0/src/smp.rs for a description of the protocol.
+    pause
+    cmpw $0x1, (AP_MAILBOX)         # is the command Wakeup?
+    jne 2b                          # No. Keep waiting.
+    cmpl %esi, (AP_MAILBOX+4)       # is the command addressed to us?
+    jne 2b                          # No. Keep waiting.
+    mov (AP_MAILBOX+8), %eax        # EAX = wakeup vector (needs to be in the first megabyte)
+    movw $0x0, (AP_MAILBOX)         # acknowledge the command by setting it back to Noop
+    # Convert the wakeup vector into a segment:offset pair and jump there.
+    mov %eax, %edx
+    shr $4, %edx            # DX = segment
+    and $0xF, %eax          # AX = offset
+    push %dx                # push CS
+    push %ax                # push IP
+    lret                    # pop IP, pop CS
 
 # Under SEV-ES, we need to use the AP Reset Hold and AP Jump Tables. We could munge all of it into
 # `ap_start` above, but it's simpler to keep it separate as if we ever run this code we know we're
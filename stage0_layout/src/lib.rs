@@ -0,0 +1,433 @@
+//
+// Copyright 2024 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Typed description of the memory layout of the Stage 0 firmware ROM image.
+//!
+//! The layout is defined by the linker script in `stage0_bin/layout.ld`. The
+//! fixed parts (where the image is loaded, where the GUID table ends and where
+//! the reset vector lives) are exposed as constants; the parts that depend on
+//! how the firmware was linked (the SEV metadata sections and the SEV-ES reset
+//! block) are parsed from the GUID table at the end of the image.
+//!
+//! This allows tooling on the host side to compute the expected SEV-SNP launch
+//! measurement of a firmware image without having to reimplement the parsing.
+//!
+//! See <https://github.com/qemu/qemu/blob/master/docs/specs/sev-guest-firmware.rst>
+//! for the GUID table format.
+
+use std::mem::size_of;
+
+use anyhow::{anyhow, bail, ensure, Context};
+use strum::FromRepr;
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    PhysAddr,
+};
+
+/// The address of the first byte after the end of the firmware image.
+///
+/// The firmware image gets loaded just below the 4GiB boundary.
+pub const FIRMWARE_TOP: PhysAddr = PhysAddr::new(0x1_0000_0000);
+
+/// The address of the first byte after the end of the legacy boot shadow
+/// firmware image.
+///
+/// To support legacy booting the last 128KiB of the firmware gets shadowed just
+/// below the end of 20-bit memory.
+pub const LEGACY_TOP: PhysAddr = PhysAddr::new(0x10_0000);
+
+/// The maximum size of the shadow firmware for legacy boot.
+pub const LEGACY_MAX_SIZE: usize = 128 * 1024;
+
+/// The reverse offset from the end of the firmware image to the reset vector.
+pub const RESET_VECTOR_OFFSET: usize = 0x10;
+
+/// The reverse offset from the end of the firmware image to the end of the GUID
+/// tables.
+pub const GUID_TABLE_END_OFFSET: usize = 0x20;
+
+/// The size of the header of an entry in the GUID table.
+///
+/// The header consists of a 16 byte GUID and a 16-bit length field.
+pub const GUID_TABLE_ENTRY_HEADER_SIZE: usize = size_of::<u128>() + size_of::<u16>();
+
+/// The size of the SEV metadata section header.
+pub const SEV_METADATA_HEADER_SIZE: usize = 16;
+
+/// The size of a SEV metadata section entry.
+pub const SEV_METADATA_ENTRY_SIZE: usize = 12;
+
+/// The footer GUID identifying the end of the GUID table.
+///
+/// This matches the footer GUID used in OVMF
+/// (96b582de-1fb2-45f7-baea-a366c55a082d).
+///
+/// See <https://github.com/tianocore/edk2/blob/fff6d81270b57ee786ea18ad74f43149b9f03494/OvmfPkg/ResetVector/Ia16/ResetVectorVtf0.asm>.
+pub const GUID_TABLE_FOOTER_GUID: u128 = u128::from_le_bytes([
+    0xDE, 0x82, 0xB5, 0x96, 0xB2, 0x1F, 0xF7, 0x45, 0xBA, 0xEA, 0xA3, 0x66, 0xC5, 0x5A, 0x08, 0x2D,
+]);
+
+/// The GUID identifying the SEV metadata GUID table entry.
+///
+/// This matches the SEV metadata GUID used in OVMF
+/// (dc886566-984a-4798-A75e-5585a7bf67cc).
+///
+/// See <https://github.com/tianocore/edk2/blob/fff6d81270b57ee786ea18ad74f43149b9f03494/OvmfPkg/ResetVector/Ia16/ResetVectorVtf0.asm>.
+pub const SEV_METADATA_GUID: u128 = u128::from_le_bytes([
+    0x66, 0x65, 0x88, 0xdc, 0x4a, 0x98, 0x98, 0x47, 0xA7, 0x5e, 0x55, 0x85, 0xa7, 0xbf, 0x67, 0xcc,
+]);
+
+/// The GUID identifying the SEV ES reset block GUID table entry.
+///
+/// This matches the SEV ES reset block GUID used in OVMF
+/// (00f771de-1a7e-4fcb-890e-68c77e2fb44e).
+///
+/// See <https://github.com/tianocore/edk2/blob/fff6d81270b57ee786ea18ad74f43149b9f03494/OvmfPkg/ResetVector/Ia16/ResetVectorVtf0.asm>.
+pub const SEV_ES_RESET_GUID: u128 = u128::from_le_bytes([
+    0xde, 0x71, 0xf7, 0x00, 0x7e, 0x1a, 0xcb, 0x4f, 0x89, 0x0e, 0x68, 0xc7, 0x7e, 0x2f, 0xb4, 0x4e,
+]);
+
+/// The expected first 4 bytes of the SEV metadata section header.
+const SEV_SECTION_SIGNATURE: &[u8] = b"ASEV";
+
+/// The version of SEV metadata sections we expect to encounter.
+const SEV_METADATA_VERSION: u32 = 1;
+
+/// The layout of a Stage 0 firmware ROM image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stage0Layout {
+    /// The size of the firmware ROM image.
+    pub rom_size: usize,
+    /// The start address of the firmware ROM in guest memory.
+    pub start_address: PhysAddr,
+    /// The start address of the legacy boot shadow of the firmware ROM in guest
+    /// memory.
+    pub legacy_start_address: PhysAddr,
+    /// The offset into the firmware ROM image from where the legacy boot shadow
+    /// starts.
+    pub legacy_offset: usize,
+    /// The address of the reset vector of the boot vCPU.
+    pub reset_vector: PhysAddr,
+    /// The entries of the GUID table, in the order they appear in the image.
+    pub guid_table: Vec<GuidTableEntry>,
+    /// The sections defined in the SEV metadata.
+    pub sev_metadata: Vec<SevMetadataSection>,
+    /// The reset block used by the non-boot vCPUs.
+    pub sev_es_reset_block: SevEsResetBlock,
+}
+
+impl Stage0Layout {
+    /// Parses the layout of the supplied firmware ROM image.
+    pub fn parse(rom: &[u8]) -> anyhow::Result<Self> {
+        let rom_size = rom.len();
+        ensure!(
+            rom_size > GUID_TABLE_END_OFFSET + GUID_TABLE_ENTRY_HEADER_SIZE,
+            "firmware ROM too small"
+        );
+        ensure!(rom_size as u64 <= FIRMWARE_TOP.as_u64(), "firmware ROM too large");
+        let legacy_size = rom_size.min(LEGACY_MAX_SIZE);
+        let guid_table = parse_guid_table(rom)?;
+
+        let sev_metadata_offset = find_u32_entry(rom, &guid_table, SEV_METADATA_GUID)
+            .context("couldn't find SEV metadata entry in GUID table")?;
+        let sev_metadata = parse_sev_metadata(rom, sev_metadata_offset as usize)?;
+
+        let sev_es_reset_block = find_u32_entry(rom, &guid_table, SEV_ES_RESET_GUID)
+            .context("couldn't find SEV-ES reset block entry in GUID table")?
+            .into();
+
+        Ok(Self {
+            rom_size,
+            start_address: FIRMWARE_TOP - rom_size as u64,
+            legacy_start_address: LEGACY_TOP - legacy_size as u64,
+            legacy_offset: rom_size - legacy_size,
+            reset_vector: FIRMWARE_TOP - RESET_VECTOR_OFFSET as u64,
+            guid_table,
+            sev_metadata,
+            sev_es_reset_block,
+        })
+    }
+
+    /// Finds the GUID table entry with the given GUID.
+    pub fn guid_table_entry(&self, guid: u128) -> Option<&GuidTableEntry> {
+        self.guid_table.iter().find(|entry| entry.guid == guid)
+    }
+
+    /// Returns the SEV metadata sections of the given type.
+    pub fn sev_metadata_sections(
+        &self,
+        section_type: SevMetadataSectionType,
+    ) -> impl Iterator<Item = &SevMetadataSection> {
+        self.sev_metadata.iter().filter(move |section| section.section_type == section_type)
+    }
+}
+
+/// An entry in the GUID table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuidTableEntry {
+    /// The GUID identifying the entry, as a little-endian u128.
+    pub guid: u128,
+    /// The offset of the entry data from the start of the firmware ROM image.
+    pub offset: usize,
+    /// The size of the entry data, excluding the header.
+    pub size: usize,
+}
+
+/// The section types used in the firmware SEV metadata.
+///
+/// See <https://github.com/tianocore/edk2/blob/fff6d81270b57ee786ea18ad74f43149b9f03494/OvmfPkg/ResetVector/X64/OvmfSevMetadata.asm>
+#[derive(Clone, Copy, Debug, FromRepr, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SevMetadataSectionType {
+    Unmeasured = 1,
+    Secrets = 2,
+    Cpuid = 3,
+}
+
+/// A range of guest memory described in the firmware SEV metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SevMetadataSection {
+    pub start_address: PhysAddr,
+    pub size: u64,
+    pub section_type: SevMetadataSectionType,
+}
+
+impl SevMetadataSection {
+    /// The number of 4KiB pages covered by the section.
+    pub fn page_count(&self) -> usize {
+        (self.size / Size4KiB::SIZE) as usize
+    }
+
+    /// The start addresses of the 4KiB pages covered by the section.
+    pub fn pages(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+        (0..self.size).step_by(Size4KiB::SIZE as usize).map(|offset| self.start_address + offset)
+    }
+
+    fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let start_address = PhysAddr::new(read_u32(bytes, 0)? as u64);
+        ensure!(
+            start_address.is_aligned(Size4KiB::SIZE),
+            "invalid SEV metadata entry base address"
+        );
+        let size = read_u32(bytes, 4)? as u64;
+        ensure!(size % Size4KiB::SIZE == 0, "invalid SEV metadata entry size");
+        let section_type = read_u32(bytes, 8)?;
+        let section_type = SevMetadataSectionType::from_repr(section_type)
+            .ok_or_else(|| anyhow!("invalid SEV metadata section type: {}", section_type))?;
+        Ok(Self { start_address, size, section_type })
+    }
+}
+
+/// The instruction pointer and code segment base that will be set when a
+/// non-boot vCPU is reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SevEsResetBlock {
+    pub rip: u64,
+    pub segment_base: u64,
+}
+
+impl From<u32> for SevEsResetBlock {
+    fn from(value: u32) -> Self {
+        // The instruction pointer is the two least significant bytes of the address.
+        let rip = (value & 0x0000ffff) as u64;
+        // The code segment base is the address with the two least significant bytes
+        // zeroed out.
+        let segment_base = (value & 0xffff0000) as u64;
+        Self { rip, segment_base }
+    }
+}
+
+/// Reads a little-endian u32 at the given offset.
+fn read_u32(bytes: &[u8], offset: usize) -> anyhow::Result<u32> {
+    bytes
+        .get(offset..offset + size_of::<u32>())
+        .map(|value| u32::from_le_bytes(value.try_into().unwrap()))
+        .context("unexpected end of data")
+}
+
+/// Reads the header of a GUID table entry (or the footer) that ends at `end`.
+///
+/// The header is stored after the entry data and consists of a 16-bit size
+/// (which includes the header itself) followed by the GUID.
+fn read_entry_header(rom: &[u8], end: usize) -> anyhow::Result<(u128, usize)> {
+    ensure!(end >= GUID_TABLE_ENTRY_HEADER_SIZE, "malformed GUID table entry");
+    let guid_start = end - size_of::<u128>();
+    let guid = u128::from_le_bytes(rom[guid_start..end].try_into().unwrap());
+    let size_start = guid_start - size_of::<u16>();
+    let size = u16::from_le_bytes(rom[size_start..guid_start].try_into().unwrap());
+    Ok((guid, size as usize))
+}
+
+/// Parses the GUID table, starting from the footer at the end of the image.
+fn parse_guid_table(rom: &[u8]) -> anyhow::Result<Vec<GuidTableEntry>> {
+    let table_end = rom.len() - GUID_TABLE_END_OFFSET;
+    let (footer_guid, table_size) = read_entry_header(rom, table_end)?;
+    ensure!(
+        footer_guid == GUID_TABLE_FOOTER_GUID,
+        "firmware image doesn't contain a valid GUID table"
+    );
+    ensure!(
+        table_size > GUID_TABLE_ENTRY_HEADER_SIZE && table_size < table_end,
+        "invalid GUID table size"
+    );
+    let table_start = table_end - table_size;
+    let mut entry_end = table_end - GUID_TABLE_ENTRY_HEADER_SIZE;
+    let mut entries = Vec::new();
+    while entry_end > table_start {
+        let (guid, size) = read_entry_header(rom, entry_end)?;
+        ensure!(
+            size >= GUID_TABLE_ENTRY_HEADER_SIZE && entry_end - table_start >= size,
+            "invalid GUID table entry size"
+        );
+        ensure!(entries.iter().all(|entry: &GuidTableEntry| entry.guid != guid), "duplicate GUID");
+        let offset = entry_end - size;
+        entries.push(GuidTableEntry { guid, offset, size: size - GUID_TABLE_ENTRY_HEADER_SIZE });
+        entry_end = offset;
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Reads the value of a GUID table entry that consists of a single u32.
+fn find_u32_entry(rom: &[u8], table: &[GuidTableEntry], guid: u128) -> anyhow::Result<u32> {
+    let entry = table.iter().find(|entry| entry.guid == guid).context("GUID not found")?;
+    ensure!(entry.size == size_of::<u32>(), "invalid GUID table entry length");
+    read_u32(rom, entry.offset)
+}
+
+/// Parses the SEV metadata, which starts `offset` bytes before the end of the
+/// image.
+///
+/// See <https://github.com/tianocore/edk2/blob/fff6d81270b57ee786ea18ad74f43149b9f03494/OvmfPkg/ResetVector/X64/OvmfSevMetadata.asm>
+fn parse_sev_metadata(rom: &[u8], offset: usize) -> anyhow::Result<Vec<SevMetadataSection>> {
+    ensure!(
+        offset <= rom.len() && offset >= SEV_METADATA_HEADER_SIZE,
+        "invalid SEV metadata offset"
+    );
+    let start = rom.len() - offset;
+    let header = &rom[start..start + SEV_METADATA_HEADER_SIZE];
+    if &header[..4] != SEV_SECTION_SIGNATURE {
+        bail!("invalid signature for SEV metadata section");
+    }
+    let length = read_u32(header, 4)? as usize;
+    ensure!(
+        read_u32(header, 8)? == SEV_METADATA_VERSION,
+        "invalid version for SEV metadata section"
+    );
+    let count = read_u32(header, 12)? as usize;
+    ensure!(
+        length == count * SEV_METADATA_ENTRY_SIZE + SEV_METADATA_HEADER_SIZE && length <= offset,
+        "invalid length or count in SEV metadata section"
+    );
+    rom[start + SEV_METADATA_HEADER_SIZE..start + length]
+        .chunks_exact(SEV_METADATA_ENTRY_SIZE)
+        .map(SevMetadataSection::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEV_ES_START: u32 = 0xFFFF_F000;
+
+    /// Builds a minimal firmware image with the same trailer as the one created
+    /// by `stage0_bin/layout.ld`.
+    fn rom(size: usize, sections: &[(u32, u32, SevMetadataSectionType)]) -> Vec<u8> {
+        let mut metadata = Vec::new();
+        metadata.extend_from_slice(SEV_SECTION_SIGNATURE);
+        let length = SEV_METADATA_HEADER_SIZE + sections.len() * SEV_METADATA_ENTRY_SIZE;
+        metadata.extend_from_slice(&(length as u32).to_le_bytes());
+        metadata.extend_from_slice(&SEV_METADATA_VERSION.to_le_bytes());
+        metadata.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        for (address, size, section_type) in sections {
+            metadata.extend_from_slice(&address.to_le_bytes());
+            metadata.extend_from_slice(&size.to_le_bytes());
+            metadata.extend_from_slice(&(*section_type as u32).to_le_bytes());
+        }
+
+        let entry_size = (size_of::<u32>() + GUID_TABLE_ENTRY_HEADER_SIZE) as u16;
+        let table_size = 2 * entry_size + GUID_TABLE_ENTRY_HEADER_SIZE as u16;
+        let trailer_size = metadata.len() + table_size as usize + GUID_TABLE_END_OFFSET;
+        let metadata_offset = trailer_size as u32;
+
+        let mut table = Vec::new();
+        table.extend_from_slice(&metadata_offset.to_le_bytes());
+        table.extend_from_slice(&entry_size.to_le_bytes());
+        table.extend_from_slice(&SEV_METADATA_GUID.to_le_bytes());
+        table.extend_from_slice(&SEV_ES_START.to_le_bytes());
+        table.extend_from_slice(&entry_size.to_le_bytes());
+        table.extend_from_slice(&SEV_ES_RESET_GUID.to_le_bytes());
+        table.extend_from_slice(&table_size.to_le_bytes());
+        table.extend_from_slice(&GUID_TABLE_FOOTER_GUID.to_le_bytes());
+
+        let mut rom = vec![0u8; size - trailer_size];
+        rom.extend_from_slice(&metadata);
+        rom.extend_from_slice(&table);
+        rom.resize(size, 0);
+        rom
+    }
+
+    #[test]
+    fn parse_layout() {
+        let sections = [
+            (0x7_8000, 0x8000, SevMetadataSectionType::Unmeasured),
+            (0x1000, 0x1000, SevMetadataSectionType::Secrets),
+            (0x2000, 0x1000, SevMetadataSectionType::Cpuid),
+        ];
+        let rom = rom(2 * 1024 * 1024, &sections);
+
+        let layout = Stage0Layout::parse(&rom).unwrap();
+
+        assert_eq!(layout.start_address, PhysAddr::new(0xFFE0_0000));
+        assert_eq!(layout.legacy_start_address, PhysAddr::new(0xE_0000));
+        assert_eq!(layout.legacy_offset, 2 * 1024 * 1024 - LEGACY_MAX_SIZE);
+        assert_eq!(layout.reset_vector, PhysAddr::new(0xFFFF_FFF0));
+        assert_eq!(layout.guid_table.len(), 2);
+        assert_eq!(layout.guid_table_entry(SEV_ES_RESET_GUID).unwrap().size, size_of::<u32>());
+        assert_eq!(layout.sev_metadata.len(), 3);
+        assert_eq!(layout.sev_metadata[0].page_count(), 8);
+        assert_eq!(
+            layout
+                .sev_metadata_sections(SevMetadataSectionType::Secrets)
+                .flat_map(SevMetadataSection::pages)
+                .collect::<Vec<_>>(),
+            vec![PhysAddr::new(0x1000)]
+        );
+        assert_eq!(
+            layout.sev_es_reset_block,
+            SevEsResetBlock { rip: 0xF000, segment_base: 0xFFFF_0000 }
+        );
+    }
+
+    #[test]
+    fn missing_guid_table() {
+        assert!(Stage0Layout::parse(&[0u8; 4096]).is_err());
+    }
+
+    #[test]
+    fn invalid_sev_metadata_section_type() {
+        let mut rom = rom(4096, &[(0x1000, 0x1000, SevMetadataSectionType::Secrets)]);
+        // The section type is the last field of the only metadata entry, just before
+        // the GUID table.
+        let table_size =
+            2 * (size_of::<u32>() + GUID_TABLE_ENTRY_HEADER_SIZE) + GUID_TABLE_ENTRY_HEADER_SIZE;
+        let type_offset = rom.len() - GUID_TABLE_END_OFFSET - table_size - size_of::<u32>();
+        rom[type_offset] = 0;
+
+        assert!(Stage0Layout::parse(&rom).is_err());
+    }
+}